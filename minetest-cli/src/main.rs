@@ -0,0 +1,266 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Result;
+use clap::Parser;
+use minetest_protocol::bot::commands::strip_escapes;
+use minetest_protocol::bot::script::BS;
+use minetest_protocol::bot::BotClient;
+use minetest_protocol::bot::ChatCommands;
+use minetest_protocol::services::client::Denied;
+use minetest_protocol::services::monitor::MonitorConfig;
+use minetest_protocol::services::monitor::MonitorEvent;
+use minetest_protocol::services::monitor::ServerMonitor;
+use minetest_protocol::wire::command::*;
+use minetest_protocol::wire::types::v3f;
+use minetest_protocol::wire::types::v3s16;
+use minetest_protocol::wire::types::ItemStackUpdate;
+use minetest_protocol::MinetestClient;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+
+/// mtcli - Interactive Minetest client for testing servers
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Server (address:port)
+    #[arg(short, long)]
+    server: SocketAddr,
+
+    /// Player name
+    #[arg(short, long)]
+    name: String,
+
+    /// Password (a new account is registered with it)
+    #[arg(short, long, default_value = "")]
+    password: String,
+
+    /// Print every command received from the server
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+
+    /// Stay idle and report server health (time drift, latency,
+    /// players and chat) instead of reading commands
+    #[arg(short, long, default_value_t = false)]
+    monitor: bool,
+}
+
+const HELP: &str = "\
+Commands:
+  say <message>             Send a chat message (or /command)
+  pos                       Show the player's position
+  goto <x> <y> <z>          Walk to a position
+  dig <x> <y> <z> [secs]    Dig a node (default 1 second)
+  place <x> <y> <z> <item>  Place an item from the hotbar against a node
+  node <x> <y> <z>          Show the name of a node
+  objects                   List the objects in range
+  inv [list]                Show the inventory
+  blocks near [radius]      Loaded map blocks around the player (default 2)
+  complete <text>           Complete a chat command or privilege
+  help                      This list
+  quit";
+
+/// The connection, and what has been learned from it
+struct Session {
+    bot: BotClient,
+    verbose: bool,
+}
+
+impl Session {
+    async fn recv(&mut self) -> Result<ToClientCommand> {
+        let command = self.bot.recv().await?;
+        if self.verbose {
+            println!("S->C {:?}", command);
+        }
+        Ok(command)
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // tokio::main makes rust-analyzer fragile,
+    // so put the code in a separate place.
+    real_main().await
+}
+
+async fn real_main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let client = MinetestClient::connect_and_login(args.server, &args.name, &args.password).await?;
+    println!("Logged in to {} as {}", args.server, args.name);
+    if args.monitor {
+        return monitor(client).await;
+    }
+    let mut session = Session {
+        bot: BotClient::new(client),
+        verbose: args.verbose,
+    };
+    for request in ChatCommands::requests() {
+        session.bot.send(request).await?;
+    }
+    println!("Type 'help' for a list of commands.");
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    break;
+                };
+                match run(&mut session, line.trim()).await {
+                    Ok(true) => (),
+                    Ok(false) => break,
+                    Err(err) => println!("Error: {}", err),
+                }
+            }
+            command = session.recv() => show(&command?)?,
+        }
+    }
+    Ok(())
+}
+
+async fn monitor(client: MinetestClient) -> Result<()> {
+    let mut monitor = ServerMonitor::new(client, MonitorConfig::default());
+    loop {
+        let event = monitor.next_event().await?;
+        let metrics = monitor.metrics();
+        match event {
+            MonitorEvent::Chat { sender, message } if sender.is_empty() => {
+                println!("chat: {}", message)
+            }
+            MonitorEvent::Chat { sender, message } => println!("chat: <{}> {}", sender, message),
+            MonitorEvent::PlayerJoined(name) => {
+                println!("joined: {} ({} online)", name, metrics.players_online)
+            }
+            MonitorEvent::PlayerLeft(name) => {
+                println!("left: {} ({} online)", name, metrics.players_online)
+            }
+            MonitorEvent::TimeDrift { ticks } => {
+                println!("drift: {} ticks ({} updates)", ticks, metrics.time_updates)
+            }
+            MonitorEvent::Latency(latency) => println!(
+                "latency: {:?} (max {:?}, lost {})",
+                latency,
+                metrics.max_latency.unwrap_or_default(),
+                metrics.lost_probes
+            ),
+            MonitorEvent::Denied(denied) => bail!(denied),
+        }
+    }
+}
+
+/// Prints what the user should see of a command from the server
+fn show(command: &ToClientCommand) -> Result<()> {
+    match command {
+        ToClientCommand::TCChatMessage(spec) => {
+            let message = strip_escapes(&spec.message);
+            if spec.sender.is_empty() {
+                println!("{}", message);
+            } else {
+                println!("<{}> {}", spec.sender, message);
+            }
+        }
+        _ => Denied::check(command)?,
+    }
+    Ok(())
+}
+
+/// Runs one REPL line. Returns false to quit.
+async fn run(session: &mut Session, line: &str) -> Result<bool> {
+    let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    match (verb, args.as_slice()) {
+        ("", _) => (),
+        ("say", _) if !rest.is_empty() => session.bot.chat(rest).await?,
+        ("pos", []) => {
+            let world = session.bot.world();
+            let p = world.position();
+            let block = world.player_block();
+            println!(
+                "({:.1}, {:.1}, {:.1}) in block ({}, {}, {})",
+                p.x, p.y, p.z, block.x, block.y, block.z
+            );
+        }
+        ("goto", [x, y, z]) => {
+            let target = v3f::new(x.parse()?, y.parse()?, z.parse()?);
+            session.bot.move_to(target).await?;
+        }
+        ("dig", [x, y, z, secs @ ..]) if secs.len() <= 1 => {
+            let pos = v3s16::new(x.parse()?, y.parse()?, z.parse()?);
+            let secs: f32 = secs.first().map_or(Ok(1.0), |s| s.parse())?;
+            session
+                .bot
+                .dig(&pos, Duration::try_from_secs_f32(secs)?)
+                .await?;
+        }
+        ("place", [x, y, z, item]) => {
+            let pos = v3s16::new(x.parse()?, y.parse()?, z.parse()?);
+            session.bot.place(&pos, item).await?;
+        }
+        ("node", [x, y, z]) => {
+            let pos = v3s16::new(x.parse()?, y.parse()?, z.parse()?);
+            match session.bot.world().node_name(&pos) {
+                Some(name) => println!("{}", name),
+                None => println!("Not loaded"),
+            }
+        }
+        ("objects", []) => {
+            for object in session.bot.world().objects() {
+                let p = &object.position;
+                let kind = if object.is_player { "player" } else { "entity" };
+                println!(
+                    "  {:5}  {} {} ({:.1}, {:.1}, {:.1}) hp {}",
+                    object.id,
+                    kind,
+                    object.name,
+                    p.x / BS,
+                    p.y / BS,
+                    p.z / BS,
+                    object.hp
+                );
+            }
+        }
+        ("inv", names) if names.len() <= 1 => {
+            for list in session.bot.world().inventory() {
+                if names.first().is_some_and(|name| *name != list.name) {
+                    continue;
+                }
+                println!("{} ({} slots)", list.name, list.items.len());
+                for (index, item) in list.items.iter().enumerate() {
+                    if let ItemStackUpdate::Item(stack) = item {
+                        println!("  {:3}  {} {}", index, stack.name, stack.count);
+                    }
+                }
+            }
+        }
+        ("blocks", ["near", radius @ ..]) if radius.len() <= 1 => {
+            let radius: i16 = radius.first().map_or(Ok(2), |r| r.parse())?;
+            let world = session.bot.world();
+            let center = world.player_block();
+            let mut near: Vec<_> = world
+                .blocks()
+                .filter(|b| {
+                    (b.x - center.x).abs() <= radius
+                        && (b.y - center.y).abs() <= radius
+                        && (b.z - center.z).abs() <= radius
+                })
+                .map(|b| (b.x, b.y, b.z))
+                .collect();
+            near.sort();
+            println!("{} blocks within {} of {:?}", near.len(), radius, center);
+            for (x, y, z) in near {
+                println!("  ({}, {}, {})", x, y, z);
+            }
+        }
+        ("complete", _) if !rest.is_empty() => {
+            for candidate in session.bot.world().commands().complete(rest) {
+                println!("{}", candidate);
+            }
+        }
+        ("help", []) => println!("{}", HELP),
+        ("quit" | "exit", []) => return Ok(false),
+        _ => println!("Unknown command. Type 'help' for a list."),
+    }
+    Ok(true)
+}
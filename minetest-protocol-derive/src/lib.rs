@@ -5,25 +5,34 @@ extern crate syn;
 use proc_macro2::Ident;
 use proc_macro2::Literal;
 use proc_macro2::TokenStream;
+use quote::format_ident;
 use quote::quote;
 use quote::quote_spanned;
 use quote::ToTokens;
 use syn::parse_macro_input;
 use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
+use syn::Attribute;
 use syn::Data;
 use syn::DeriveInput;
+use syn::Expr;
+use syn::ExprLit;
 use syn::Field;
 use syn::Generics;
 use syn::Index;
+use syn::Lit;
+use syn::LitInt;
+use syn::Meta;
+use syn::NestedMeta;
 use syn::Type;
 use syn::TypeParam;
+use syn::Variant;
 
-#[proc_macro_derive(MinetestSerialize, attributes(wrap))]
+#[proc_macro_derive(MinetestSerialize, attributes(wrap, proto, tag))]
 pub fn minetest_serialize(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let name = input.ident;
-    let serialize_body = make_serialize_body(&name, &input.data);
+    let serialize_body = make_serialize_body(&name, &input.attrs, &input.data);
 
     // The struct must include Serialize in the bounds of any type
     // that need to be serializable.
@@ -43,11 +52,11 @@ pub fn minetest_serialize(input: proc_macro::TokenStream) -> proc_macro::TokenSt
     proc_macro::TokenStream::from(expanded)
 }
 
-#[proc_macro_derive(MinetestDeserialize, attributes(wrap))]
+#[proc_macro_derive(MinetestDeserialize, attributes(wrap, proto, tag))]
 pub fn minetest_deserialize(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let name = input.ident;
-    let deserialize_body = make_deserialize_body(&name, &input.data);
+    let deserialize_body = make_deserialize_body(&name, &input.attrs, &input.data);
 
     // The struct must include Deserialize in the bounds of any type
     // that need to be serializable.
@@ -66,6 +75,89 @@ pub fn minetest_deserialize(input: proc_macro::TokenStream) -> proc_macro::Token
     proc_macro::TokenStream::from(expanded)
 }
 
+/// For an enum of `Name(Box<Spec>)` variants (the command enums), an
+/// `as_name()` accessor for each variant.
+#[proc_macro_derive(CommandAccessors)]
+pub fn command_accessors(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = input.ident;
+    let Data::Enum(body) = &input.data else {
+        return quote_spanned! {
+            name.span() => compile_error!("CommandAccessors only derives for enums");
+        }
+        .into();
+    };
+    let mut accessors = Vec::new();
+    for v in body.variants.iter() {
+        let syn::Fields::Unnamed(fields) = &v.fields else {
+            continue;
+        };
+        if fields.unnamed.len() != 1 {
+            continue;
+        }
+        let variant = &v.ident;
+        let spec = unboxed_type(&fields.unnamed[0].ty);
+        let as_name = format_ident!("as_{}", snake_case(&variant.to_string()));
+        let as_name_mut = format_ident!("{}_mut", as_name);
+        let doc = format!("The {} contents, if this is a {}", variant, variant);
+        accessors.push(quote! {
+            #[doc = #doc]
+            pub fn #as_name(&self) -> Option<&#spec> {
+                match self {
+                    #name::#variant(spec) => Some(spec),
+                    _ => None,
+                }
+            }
+
+            pub fn #as_name_mut(&mut self) -> Option<&mut #spec> {
+                match self {
+                    #name::#variant(spec) => Some(spec),
+                    _ => None,
+                }
+            }
+        });
+    }
+    let expanded = quote! {
+        impl #name {
+            #(#accessors)*
+        }
+    };
+    proc_macro::TokenStream::from(expanded)
+}
+
+/// T for Box<T>, otherwise the type itself
+fn unboxed_type(ty: &Type) -> Type {
+    if let Type::Path(path) = ty {
+        if let Some(last) = path.path.segments.last() {
+            if last.ident == "Box" {
+                if let syn::PathArguments::AngleBracketed(args) = &last.arguments {
+                    if let Some(syn::GenericArgument::Type(inner)) = args.args.first() {
+                        return inner.clone();
+                    }
+                }
+            }
+        }
+    }
+    ty.clone()
+}
+
+/// HudSetParam -> hud_set_param, TCChatMessage -> tc_chat_message
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_lower) {
+                out.push('_');
+            }
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
 fn get_wrapped_type(f: &Field) -> Type {
     let mut ty = f.ty.clone();
     for attr in f.attrs.iter() {
@@ -76,18 +168,103 @@ fn get_wrapped_type(f: &Field) -> Type {
     ty
 }
 
+/// A field marked #[proto(since = N)] is only on the wire from protocol
+/// version N. For older peers it is not serialized, and deserializes as
+/// Default::default(), or as `default = "expr"` if given.
+struct Since {
+    version: LitInt,
+    default: Option<Expr>,
+}
+
+fn get_since(f: &Field) -> Result<Option<Since>, syn::Error> {
+    let mut version = None;
+    let mut default = None;
+    for attr in f.attrs.iter() {
+        if !attr.path.is_ident("proto") {
+            continue;
+        }
+        let Meta::List(list) = attr.parse_meta()? else {
+            return Err(syn::Error::new(attr.span(), "expected #[proto(since = N)]"));
+        };
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("since") => {
+                    match &nv.lit {
+                        Lit::Int(lit) => version = Some(lit.clone()),
+                        lit => return Err(syn::Error::new(lit.span(), "expected a version")),
+                    }
+                }
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("default") => {
+                    match &nv.lit {
+                        Lit::Str(lit) => default = Some(lit.parse::<Expr>()?),
+                        lit => return Err(syn::Error::new(lit.span(), "expected a string")),
+                    }
+                }
+                other => return Err(syn::Error::new(other.span(), "unknown proto attribute")),
+            }
+        }
+    }
+    match (version, default) {
+        (Some(version), default) => Ok(Some(Since { version, default })),
+        (None, Some(default)) => Err(syn::Error::new(default.span(), "default needs since = N")),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Wraps the code that serializes field `f`
+fn gate_serialize(f: &Field, body: TokenStream) -> TokenStream {
+    match get_since(f) {
+        Ok(Some(Since { version, .. })) => quote_spanned! {f.span() =>
+            if ser.context().protocol_version >= #version {
+                #body
+            }
+        },
+        Ok(None) => body,
+        Err(err) => err.to_compile_error(),
+    }
+}
+
+/// Wraps the expression that deserializes field `f`
+fn gate_deserialize(f: &Field, body: TokenStream) -> TokenStream {
+    match get_since(f) {
+        Ok(Some(Since { version, default })) => {
+            let default = match default {
+                Some(expr) => quote! { #expr },
+                None => quote! { ::core::default::Default::default() },
+            };
+            quote_spanned! {f.span() =>
+                if deser.context().protocol_version >= #version {
+                    #body
+                } else {
+                    #default
+                }
+            }
+        }
+        Ok(None) => body,
+        Err(err) => err.to_compile_error(),
+    }
+}
+
 /// For struct, fields are serialized/deserialized in order.
-/// For enum, tags are assumed u8, consecutive, starting with 0.
-fn make_serialize_body(input_name: &Ident, data: &Data) -> TokenStream {
+/// For enum, a tag (see get_tag_type and get_tag) is followed by the
+/// variant's fields, all after the version byte if there is one (see
+/// get_version).
+fn make_serialize_body(input_name: &Ident, attrs: &[Attribute], data: &Data) -> TokenStream {
     match *data {
         syn::Data::Struct(ref data) => match data.fields {
             syn::Fields::Named(ref fields) => {
                 let recurse = fields.named.iter().map(|f| {
                     let name = &f.ident;
                     let ty = get_wrapped_type(f);
-                    quote_spanned! {f.span() =>
-                        <#ty as Serialize>::serialize(&value.#name, ser)?;
-                    }
+                    let field = name.as_ref().unwrap().to_string();
+                    // Name the field in errors, so a bad value can be found
+                    let body = quote_spanned! {f.span() =>
+                        ::anyhow::Context::with_context(
+                            <#ty as Serialize>::serialize(&value.#name, ser),
+                            || #field,
+                        )?;
+                    };
+                    gate_serialize(f, body)
                 });
                 quote! {
                     #(#recurse)*
@@ -97,9 +274,10 @@ fn make_serialize_body(input_name: &Ident, data: &Data) -> TokenStream {
                 let recurse = fields.unnamed.iter().enumerate().map(|(i, f)| {
                     let index = Index::from(i);
                     let ty = get_wrapped_type(f);
-                    quote_spanned! {f.span() =>
+                    let body = quote_spanned! {f.span() =>
                         <#ty as Serialize>::serialize(&value.#index, ser)?;
-                    }
+                    };
+                    gate_serialize(f, body)
                 });
                 quote! {
                     #(#recurse)*
@@ -110,36 +288,50 @@ fn make_serialize_body(input_name: &Ident, data: &Data) -> TokenStream {
             }
         },
         syn::Data::Enum(ref body) => {
+            let (tag_ty, tag_max) = match get_tag_type(attrs) {
+                Ok(tag_type) => tag_type,
+                Err(err) => return err.to_compile_error(),
+            };
+            let version = match get_version(attrs) {
+                Ok(Some(version)) => quote! {
+                    <u8 as Serialize>::serialize(&#version, ser)?;
+                },
+                Ok(None) => quote! {},
+                Err(err) => return err.to_compile_error(),
+            };
             let recurse = body.variants.iter().enumerate().map(|(i, v)| {
-                if !v.fields.is_empty() {
-                    quote_spanned! {v.span() =>
-                        compile_error!("Cannot handle fields yet");
-                    }
-                } else if v.discriminant.is_some() {
-                    quote_spanned! {v.span() =>
-                        compile_error!("Cannot handle discrimiant yet");
-                    }
-                } else {
-                    let id = &v.ident;
-                    let i = Literal::u8_unsuffixed(i as u8);
-                    quote_spanned! {v.span() =>
-                        #id => #i,
+                let id = &v.ident;
+                let tag = match get_tag(v, i, tag_max) {
+                    Ok(tag) => tag,
+                    Err(err) => return err.to_compile_error(),
+                };
+                let (pattern, fields) = variant_bindings(v);
+                let recurse = fields.iter().map(|(binding, f)| {
+                    let ty = get_wrapped_type(f);
+                    let body = quote_spanned! {f.span() =>
+                        <#ty as Serialize>::serialize(#binding, ser)?;
+                    };
+                    gate_serialize(f, body)
+                });
+                quote_spanned! {v.span() =>
+                    #input_name::#id #pattern => {
+                        <#tag_ty as Serialize>::serialize(&#tag, ser)?;
+                        #(#recurse)*
                     }
                 }
             });
             quote! {
-                    use #input_name::*;
-                    let tag = match value {
-                        #(#recurse)*
-                    };
-                    u8::serialize(&tag, ser)?;
+                #version
+                match value {
+                    #(#recurse)*
+                }
             }
         }
         syn::Data::Union(_) => unimplemented!(),
     }
 }
 
-fn make_deserialize_body(input_name: &Ident, data: &Data) -> TokenStream {
+fn make_deserialize_body(input_name: &Ident, attrs: &[Attribute], data: &Data) -> TokenStream {
     match *data {
         syn::Data::Struct(ref data) => {
             let inner = match data.fields {
@@ -147,9 +339,16 @@ fn make_deserialize_body(input_name: &Ident, data: &Data) -> TokenStream {
                     let recurse = fields.named.iter().map(|f| {
                         let name = &f.ident;
                         let ty = get_wrapped_type(f);
-                        quote_spanned! {f.span() =>
-                            #name: <#ty as Deserialize>::deserialize(deser)?,
-                        }
+                        let field = name.as_ref().unwrap().to_string();
+                        // Name the field and offset in errors (see DeserializeLocation)
+                        let body = quote_spanned! {f.span() =>
+                            match <#ty as Deserialize>::deserialize(deser) {
+                                Ok(value) => value,
+                                Err(err) => return Err(DeserializeField::wrap(err, #field, deser)),
+                            }
+                        };
+                        let value = gate_deserialize(f, body);
+                        quote! { #name: #value, }
                     });
                     quote! {
                         #(#recurse)*
@@ -159,9 +358,11 @@ fn make_deserialize_body(input_name: &Ident, data: &Data) -> TokenStream {
                     let recurse = fields.unnamed.iter().enumerate().map(|(i, f)| {
                         let index = Index::from(i);
                         let ty = get_wrapped_type(f);
-                        quote_spanned! {f.span() =>
-                            #index: <#ty as Deserialize>::deserialize(deser)?,
-                        }
+                        let body = quote_spanned! {f.span() =>
+                            <#ty as Deserialize>::deserialize(deser)?
+                        };
+                        let value = gate_deserialize(f, body);
+                        quote! { #index: #value, }
                     });
                     quote! {
                         #(#recurse)*
@@ -178,29 +379,47 @@ fn make_deserialize_body(input_name: &Ident, data: &Data) -> TokenStream {
             }
         }
         syn::Data::Enum(ref body) => {
-            let recurse = body.variants.iter().enumerate().map(|(i, v)| {
-                if !v.fields.is_empty() {
-                    quote_spanned! {v.span() =>
-                        compile_error!("Cannot handle fields yet");
-                    }
-                } else if v.discriminant.is_some() {
-                    quote_spanned! {v.span() =>
-                        compile_error!("Cannot handle discrimiant yet");
+            let (tag_ty, tag_max) = match get_tag_type(attrs) {
+                Ok(tag_type) => tag_type,
+                Err(err) => return err.to_compile_error(),
+            };
+            let input_name_str = Literal::string(&input_name.to_string());
+            let version = match get_version(attrs) {
+                Ok(Some(version)) => quote! {
+                    let version = <u8 as Deserialize>::deserialize(deser)?;
+                    if version != #version {
+                        bail!("Invalid {} version: {}", #input_name_str, version);
                     }
-                } else {
-                    let id = &v.ident;
-                    let i = Literal::u8_unsuffixed(i as u8);
-                    quote_spanned! {v.span() =>
-                        #i => #id,
-
+                },
+                Ok(None) => quote! {},
+                Err(err) => return err.to_compile_error(),
+            };
+            let recurse = body.variants.iter().enumerate().map(|(i, v)| {
+                let id = &v.ident;
+                let tag = match get_tag(v, i, tag_max) {
+                    Ok(tag) => tag,
+                    Err(err) => return err.to_compile_error(),
+                };
+                let (pattern, fields) = variant_bindings(v);
+                let recurse = fields.iter().map(|(binding, f)| {
+                    let ty = get_wrapped_type(f);
+                    let body = quote_spanned! {f.span() =>
+                        <#ty as Deserialize>::deserialize(deser)?
+                    };
+                    let value = gate_deserialize(f, body);
+                    quote! { let #binding = #value; }
+                });
+                quote_spanned! {v.span() =>
+                    #tag => {
+                        #(#recurse)*
+                        #input_name::#id #pattern
                     }
                 }
             });
 
-            let input_name_str = Literal::string(&input_name.to_string());
             quote! {
-                    use #input_name::*;
-                    let tag = u8::deserialize(deser)?;
+                    #version
+                    let tag = <#tag_ty as Deserialize>::deserialize(deser)?;
                     Ok(match tag {
                         #(#recurse)*
                         _ => bail!("Invalid {} tag: {}", #input_name_str, tag),
@@ -211,6 +430,121 @@ fn make_deserialize_body(input_name: &Ident, data: &Data) -> TokenStream {
     }
 }
 
+/// The enum's tag type: #[tag(u16)] or #[tag(u32)], or else u8.
+/// Returns it with its largest value.
+fn get_tag_type(attrs: &[Attribute]) -> Result<(Ident, u64), syn::Error> {
+    let mut tag_ty = format_ident!("u8");
+    for attr in attrs.iter() {
+        if attr.path.is_ident("tag") {
+            tag_ty = attr.parse_args::<Ident>()?;
+        }
+    }
+    let max = match tag_ty.to_string().as_str() {
+        "u8" => u8::MAX as u64,
+        "u16" => u16::MAX as u64,
+        "u32" => u32::MAX as u64,
+        _ => return Err(syn::Error::new(tag_ty.span(), "tag must be u8, u16 or u32")),
+    };
+    Ok((tag_ty, max))
+}
+
+/// The enum's #[proto(version = N)], if any: a u8 written before the tag,
+/// which must be N when deserializing.
+fn get_version(attrs: &[Attribute]) -> Result<Option<Literal>, syn::Error> {
+    let mut version = None;
+    for attr in attrs.iter() {
+        if !attr.path.is_ident("proto") {
+            continue;
+        }
+        let Meta::List(list) = attr.parse_meta()? else {
+            return Err(syn::Error::new(
+                attr.span(),
+                "expected #[proto(version = N)]",
+            ));
+        };
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("version") => {
+                    match &nv.lit {
+                        Lit::Int(lit) => {
+                            version = Some(Literal::u8_unsuffixed(lit.base10_parse()?))
+                        }
+                        lit => return Err(syn::Error::new(lit.span(), "expected a version")),
+                    }
+                }
+                other => return Err(syn::Error::new(other.span(), "unknown proto attribute")),
+            }
+        }
+    }
+    Ok(version)
+}
+
+/// The tag of an enum variant: #[proto(tag = N)], or an explicit
+/// discriminant, or else its index.
+fn get_tag(v: &Variant, index: usize, max: u64) -> Result<Literal, syn::Error> {
+    let mut tag: Option<LitInt> = None;
+    if let Some((
+        _,
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(lit), ..
+        }),
+    )) = &v.discriminant
+    {
+        tag = Some(lit.clone());
+    } else if let Some((_, expr)) = &v.discriminant {
+        return Err(syn::Error::new(
+            expr.span(),
+            "expected an integer discriminant",
+        ));
+    }
+    for attr in v.attrs.iter() {
+        if !attr.path.is_ident("proto") {
+            continue;
+        }
+        let Meta::List(list) = attr.parse_meta()? else {
+            return Err(syn::Error::new(attr.span(), "expected #[proto(tag = N)]"));
+        };
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("tag") => match &nv.lit {
+                    Lit::Int(lit) => tag = Some(lit.clone()),
+                    lit => return Err(syn::Error::new(lit.span(), "expected a tag")),
+                },
+                other => return Err(syn::Error::new(other.span(), "unknown proto attribute")),
+            }
+        }
+    }
+    let (tag, span) = match tag {
+        Some(lit) => (lit.base10_parse::<u64>()?, lit.span()),
+        None => (index as u64, v.span()),
+    };
+    if tag > max {
+        return Err(syn::Error::new(span, "tag too large for the tag type"));
+    }
+    Ok(Literal::u64_unsuffixed(tag))
+}
+
+/// The pattern binding every field of a variant (`(__f0, __f1)` or
+/// `{ a: __f0, b: __f1 }`), and the bindings with their fields.
+fn variant_bindings(v: &Variant) -> (TokenStream, Vec<(Ident, &Field)>) {
+    let fields: Vec<(Ident, &Field)> = v
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (format_ident!("__f{}", i), f))
+        .collect();
+    let bindings = fields.iter().map(|(binding, _)| binding);
+    let pattern = match v.fields {
+        syn::Fields::Named(_) => {
+            let names = fields.iter().map(|(_, f)| &f.ident);
+            quote! { { #(#names: #bindings),* } }
+        }
+        syn::Fields::Unnamed(_) => quote! { ( #(#bindings),* ) },
+        syn::Fields::Unit => quote! {},
+    };
+    (pattern, fields)
+}
+
 /// Converts <T: Trait, S: Trait2> into <T, S>
 fn strip_generic_bounds(input: &Generics) -> Generics {
     let input = input.clone();
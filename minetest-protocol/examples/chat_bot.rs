@@ -0,0 +1,168 @@
+//!
+//! Chat bridge bot
+//!
+//! Logs in (with SRP, registering the account if it is new), prints the
+//! server's chat, sends each line typed as a chat message, and answers a
+//! few commands from the other players:
+//!
+//! ```text
+//! !ping     pong
+//! !time     the time of day
+//! !where    where the bot is
+//! ```
+//!
+//! When the server shuts down or the connection is lost, it waits and logs
+//! in again. It stops at the end of its input, or if the server refuses
+//! the login for good (e.g. a wrong password).
+//!
+//! ```text
+//! cargo run --example chat_bot -- 127.0.0.1:30000 bridge secret
+//! ```
+//!
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use minetest_protocol::bot::commands::strip_escapes;
+use minetest_protocol::bot::BotClient;
+use minetest_protocol::services::client::Denied;
+use minetest_protocol::wire::command::TCChatMessageSpec;
+use minetest_protocol::wire::command::ToClientCommand;
+use minetest_protocol::MinetestClient;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+use tokio::io::Lines;
+use tokio::io::Stdin;
+
+const MIN_RETRY: Duration = Duration::from_secs(2);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// Why a session ended
+enum Ended {
+    InputClosed,
+    /// Worth logging in again
+    Disconnected {
+        reason: String,
+        logged_in: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, server, name, password] = &args[..] else {
+        bail!("Usage: chat_bot <address:port> <name> <password>");
+    };
+    let server: SocketAddr = server.parse().context("Bad server address")?;
+
+    let mut input = BufReader::new(tokio::io::stdin()).lines();
+    let mut retry = MIN_RETRY;
+    loop {
+        match session(server, name, password, &mut input).await? {
+            Ended::InputClosed => return Ok(()),
+            Ended::Disconnected { reason, logged_in } => {
+                if logged_in {
+                    retry = MIN_RETRY;
+                }
+                eprintln!("Disconnected ({}), logging in again in {:?}", reason, retry);
+                tokio::time::sleep(retry).await;
+                retry = (retry * 2).min(MAX_RETRY);
+            }
+        }
+    }
+}
+
+/// One connection, from login to disconnect. Fails if trying again
+/// wouldn't help.
+async fn session(
+    server: SocketAddr,
+    name: &str,
+    password: &str,
+    input: &mut Lines<BufReader<Stdin>>,
+) -> Result<Ended> {
+    let client = match MinetestClient::connect_and_login(server, name, password).await {
+        Ok(client) => client,
+        Err(err) => {
+            return match err.downcast_ref::<Denied>() {
+                Some(denied) if !denied.reconnect => Err(err),
+                _ => Ok(disconnected(err, false)),
+            };
+        }
+    };
+    println!("Logged in to {} as {}", server, name);
+    let mut bot = BotClient::new(client);
+
+    loop {
+        tokio::select! {
+            line = input.next_line() => {
+                let Some(line) = line? else {
+                    bot.into_client().disconnect().await?;
+                    return Ok(Ended::InputClosed);
+                };
+                if !line.trim().is_empty() {
+                    if let Err(err) = bot.chat(line.trim()).await {
+                        return Ok(disconnected(err, true));
+                    }
+                }
+            }
+            command = bot.recv() => {
+                let command = match command {
+                    Ok(command) => command,
+                    Err(err) => return Ok(disconnected(err, true)),
+                };
+                if let Some(denied) = Denied::from_toclient(&command) {
+                    return Ok(disconnected(denied, true));
+                }
+                if let ToClientCommand::TCChatMessage(spec) = &command {
+                    if let Err(err) = chat(&mut bot, name, spec).await {
+                        return Ok(disconnected(err, true));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn disconnected(reason: impl std::fmt::Display, logged_in: bool) -> Ended {
+    Ended::Disconnected {
+        reason: reason.to_string(),
+        logged_in,
+    }
+}
+
+/// Prints a chat message, and answers it if it's a command
+async fn chat(bot: &mut BotClient, name: &str, spec: &TCChatMessageSpec) -> Result<()> {
+    let message = strip_escapes(&spec.message);
+    println!("{}", message);
+    let (sender, text) = sender_and_text(&spec.sender, &message);
+    if sender.is_empty() || sender == name {
+        return Ok(());
+    }
+    let reply = match text.trim() {
+        "!ping" => "pong".to_string(),
+        "!time" => {
+            let ticks = bot.world().time_of_day();
+            format!("It is {:02}:{:02}", ticks / 1000, ticks % 1000 * 60 / 1000)
+        }
+        "!where" => {
+            let p = bot.world().position();
+            format!("I'm at ({:.0}, {:.0}, {:.0})", p.x, p.y, p.z)
+        }
+        _ => return Ok(()),
+    };
+    bot.chat(&format!("{}: {}", sender, reply)).await
+}
+
+/// Servers send player chat as "<name> text", with or without the sender
+/// filled in
+fn sender_and_text<'a>(sender: &'a str, message: &'a str) -> (&'a str, &'a str) {
+    if let Some((name, text)) = message
+        .strip_prefix('<')
+        .and_then(|rest| rest.split_once("> "))
+    {
+        return (name, text);
+    }
+    (sender, message)
+}
@@ -0,0 +1,141 @@
+//!
+//! Map snapshot tool
+//!
+//! Copies the nodes around spawn out of a world, into a schematic that
+//! core.place_schematic (or WorldEdit, after converting) can place
+//! elsewhere, and counts each kind of node:
+//!
+//! ```text
+//! cargo run --features sqlite --example world_dump -- ~/.minetest/worlds/test 16 spawn.mts
+//! ```
+//!
+//! This writes spawn.mts, 33 nodes on each side centered on spawn, and
+//! spawn.json with the number of each node in it:
+//!
+//! ```text
+//! {"air":30125,"default:dirt":2011,"default:dirt_with_grass":478, ...}
+//! ```
+//!
+//! Spawn is the origin, as for a server without static_spawnpoint; another
+//! center can follow, as "x,y,z". The world is opened read-only, with
+//! whichever backend its world.mt names (the sqlite3 backend needs the
+//! "sqlite" feature). Nodes in blocks not generated yet are left as air.
+//!
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use minetest_protocol::wire::types::v3s16;
+use minetest_protocol::wire::util::serialize_json_string;
+use minetest_protocol::world::open_world_map;
+use minetest_protocol::world::Schematic;
+use minetest_protocol::world::World;
+use minetest_protocol::world::WorldMt;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let (world_dir, radius, out, center) = match &args[..] {
+        [_, world_dir, radius, out] => (world_dir, radius, out, None),
+        [_, world_dir, radius, out, center] => (world_dir, radius, out, Some(center)),
+        _ => bail!("Usage: world_dump <world dir> <radius> <out.mts> [x,y,z]"),
+    };
+    let world_dir = Path::new(world_dir);
+    let radius: i16 = radius.parse().context("Bad radius")?;
+    if !(0..=100).contains(&radius) {
+        bail!("Radius must be from 0 to 100");
+    }
+    let center = match center {
+        Some(center) => parse_pos(center)?,
+        None => v3s16::new(0, 0, 0),
+    };
+    let out = PathBuf::from(out);
+
+    let world_mt = WorldMt::read(world_dir)?;
+    println!(
+        "World {:?} ({}), backend {}",
+        world_mt.world_name().unwrap_or("unnamed"),
+        world_mt.gameid().unwrap_or("unknown game"),
+        world_mt.backend()?
+    );
+    let world = World::new(open_world_map(world_dir, false)?);
+
+    let min = v3s16::new(center.x - radius, center.y - radius, center.z - radius);
+    let max = v3s16::new(center.x + radius, center.y + radius, center.z + radius);
+    let side = 2 * radius + 1;
+    let mut schematic = Schematic::new(v3s16::new(side, side, side));
+    let mut histogram: BTreeMap<String, u64> = BTreeMap::new();
+    let mut errors = 0;
+    for node in world.nodes_in_area(min.clone(), max.clone()) {
+        let node = match node {
+            Ok(node) => node,
+            Err(err) => {
+                eprintln!("Skipping a block: {}", err);
+                errors += 1;
+                continue;
+            }
+        };
+        let rel = |v: i16, min: i16| (v - min) as usize;
+        schematic.set_node(
+            rel(node.pos.x, min.x),
+            rel(node.pos.y, min.y),
+            rel(node.pos.z, min.z),
+            &node.name,
+            node.param2,
+        );
+        *histogram.entry(node.name.to_string()).or_default() += 1;
+    }
+
+    schematic.write(BufWriter::new(File::create(&out)?))?;
+    let json_path = out.with_extension("json");
+    std::fs::write(&json_path, histogram_json(&histogram)?)?;
+
+    let found: u64 = histogram.values().sum();
+    println!(
+        "({},{},{}) to ({},{},{}): {} nodes found, {} kinds, {} blocks skipped",
+        min.x,
+        min.y,
+        min.z,
+        max.x,
+        max.y,
+        max.z,
+        found,
+        histogram.len(),
+        errors
+    );
+    println!("Wrote {:?} and {:?}", out, json_path);
+    Ok(())
+}
+
+/// "x,y,z"
+fn parse_pos(text: &str) -> Result<v3s16> {
+    let c: Vec<i16> = text
+        .split(',')
+        .map(|c| c.trim().parse())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("Bad position {:?}", text))?;
+    let [x, y, z] = c[..] else {
+        bail!("Bad position {:?}", text);
+    };
+    Ok(v3s16::new(x, y, z))
+}
+
+fn histogram_json(histogram: &BTreeMap<String, u64>) -> Result<Vec<u8>> {
+    let mut out = b"{".to_vec();
+    for (i, (name, count)) in histogram.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        serialize_json_string(name.as_bytes(), |chunk| {
+            out.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        out.extend(format!(":{}", count).into_bytes());
+    }
+    out.extend_from_slice(b"}\n");
+    Ok(out)
+}
@@ -0,0 +1,208 @@
+//!
+//! BotClient
+//!
+//! A MinetestClient with a ClientWorld kept up to date, and the things a
+//! player does as single calls:
+//!
+//! ```text
+//! let mut bot = BotClient::connect(addr, "bot", "secret").await?;
+//! bot.chat("hello").await?;
+//! bot.move_to(v3f::new(10.0, 5.0, -3.0)).await?;
+//! if bot.world().node_name(&pos) == Some("default:tree") {
+//!     bot.dig(&pos, Duration::from_secs(3)).await?;
+//! }
+//! bot.place(&pos, "default:torch").await?;
+//! ```
+//!
+//! Actions are sent at the pace a real client would send them (see
+//! InputScript). Commands received meanwhile update the world right away,
+//! and are kept for the next `recv`. While idle, `recv` is what updates the
+//! world: a bot should keep calling it. The blocks evicted to stay within
+//! the world's block limit are reported in TOSERVER_DELETEDBLOCKS, sent at
+//! the start of the next `recv` or `send`. That keeps `recv` cancel safe, so
+//! it can be used in `tokio::select!`.
+//!
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::services::client::MinetestClient;
+use crate::wire::command::DeletedblocksSpec;
+use crate::wire::command::TSChatMessageSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::v3f;
+use crate::wire::types::v3s16;
+
+use super::commands::ChatCommands;
+use super::script::Action;
+use super::script::InputScript;
+use super::world::ClientWorld;
+
+pub struct BotClient {
+    client: MinetestClient,
+    world: ClientWorld,
+    // Walking speed and send interval for actions
+    script: InputScript,
+    // Received while performing actions, already applied to the world
+    received: VecDeque<ToClientCommand>,
+    // Evicted from the world, not yet reported to the server
+    deleted: VecDeque<DeletedblocksSpec>,
+}
+
+impl BotClient {
+    /// Connects and logs in (see MinetestClient::login), and asks for the
+    /// chat commands (see ChatCommands)
+    pub async fn connect(
+        connect_to: SocketAddr,
+        player_name: &str,
+        password: &str,
+    ) -> Result<Self> {
+        let client = MinetestClient::connect_and_login(connect_to, player_name, password).await?;
+        let mut bot = Self::new(client);
+        for request in ChatCommands::requests() {
+            bot.send(request).await?;
+        }
+        Ok(bot)
+    }
+
+    /// A bot on a client that is already logged in
+    pub fn new(client: MinetestClient) -> Self {
+        Self {
+            client,
+            world: ClientWorld::new(),
+            script: InputScript::new(),
+            received: VecDeque::new(),
+            deleted: VecDeque::new(),
+        }
+    }
+
+    pub fn world(&self) -> &ClientWorld {
+        &self.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut ClientWorld {
+        &mut self.world
+    }
+
+    /// Nodes per second
+    pub fn set_walk_speed(&mut self, walk_speed: f32) {
+        self.script.walk_speed = walk_speed;
+    }
+
+    pub fn into_client(self) -> MinetestClient {
+        self.client
+    }
+
+    /// If this fails, the client has disconnected.
+    pub async fn send(&mut self, command: ToServerCommand) -> Result<()> {
+        self.send_deleted().await?;
+        self.world.observe_toserver(&command);
+        self.client.send(command).await
+    }
+
+    /// The next command from the server, after updating the world with it.
+    /// If this fails, the client has disconnected. Cancel safe.
+    pub async fn recv(&mut self) -> Result<ToClientCommand> {
+        self.send_deleted().await?;
+        if let Some(command) = self.received.pop_front() {
+            return Ok(command);
+        }
+        let command = self.client.recv().await?;
+        self.observe(&command);
+        Ok(command)
+    }
+
+    fn observe(&mut self, command: &ToClientCommand) {
+        self.world.observe_toclient(command);
+        if command.as_blockdata().is_some() {
+            self.deleted.extend(self.world.evict_blocks());
+        }
+    }
+
+    // A spec is only dropped once sent, so a cancelled call leaves the rest
+    // for the next one
+    async fn send_deleted(&mut self) -> Result<()> {
+        while let Some(spec) = self.deleted.front() {
+            self.client.send(spec.clone().into()).await?;
+            self.deleted.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Sends a chat message (or a /command)
+    pub async fn chat(&mut self, message: &str) -> Result<()> {
+        let spec = TSChatMessageSpec {
+            message: message.to_string(),
+        };
+        self.send(spec.into()).await
+    }
+
+    /// Walks in a straight line to `target` (the feet, in nodes)
+    pub async fn move_to(&mut self, target: v3f) -> Result<()> {
+        self.perform(&[Action::MoveTo(target)]).await
+    }
+
+    /// Digs the node at `pos` with the wielded item, taking `duration`
+    /// (the server checks it against the tool's dig time)
+    pub async fn dig(&mut self, pos: &v3s16, duration: Duration) -> Result<()> {
+        self.perform(&[Action::LookAt(center(pos)), Action::Dig(duration)])
+            .await
+    }
+
+    /// Places `item` against the node at `pos`, on the face towards the
+    /// player, like right-clicking it. The item must be in the hotbar.
+    pub async fn place(&mut self, pos: &v3s16, item: &str) -> Result<()> {
+        let Some(slot) = self
+            .world
+            .hotbar()
+            .items()
+            .iter()
+            .position(|stack| stack.as_ref().is_some_and(|stack| stack.name == item))
+        else {
+            bail!("{} is not in the hotbar", item);
+        };
+        self.perform(&[
+            Action::Select(slot as u16),
+            Action::LookAt(center(pos)),
+            Action::Place,
+        ])
+        .await
+    }
+
+    /// Compiles `actions` from the player's position, and sends them in time.
+    /// Commands received meanwhile are applied to the world, and returned
+    /// by `recv` afterwards.
+    pub async fn perform(&mut self, actions: &[Action]) -> Result<()> {
+        let script = InputScript {
+            actions: actions.to_vec(),
+            ..self.script.clone()
+        };
+        let steps = script.compile(self.world.player())?;
+        let start = tokio::time::Instant::now();
+        for step in steps {
+            let at = tokio::time::sleep_until(start + step.at);
+            tokio::pin!(at);
+            loop {
+                tokio::select! {
+                    _ = &mut at => break,
+                    command = self.client.recv() => {
+                        let command = command?;
+                        self.observe(&command);
+                        self.received.push_back(command);
+                    }
+                }
+            }
+            self.send(step.command).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Nodes are centered on integer positions
+fn center(pos: &v3s16) -> v3f {
+    v3f::new(pos.x as f32, pos.y as f32, pos.z as f32)
+}
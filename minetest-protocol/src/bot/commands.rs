@@ -0,0 +1,369 @@
+//!
+//! Chat commands
+//!
+//! The protocol has no command for listing chat commands. Clients learn
+//! them from the builtin /help: real clients get the __builtin:help_cmds
+//! formspec, and the chat reply is sent to the server admin (and by older
+//! servers, to everyone). Privileges come with TOCLIENT_PRIVILEGES, and
+//! their descriptions from the __builtin:help_privs formspec (/help privs).
+//!
+//! ChatCommands collects all of these, so tools can offer completion.
+//!
+use std::collections::BTreeMap;
+
+use crate::wire::command::TSChatMessageSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+
+pub const HELP_CMDS_FORMNAME: &str = "__builtin:help_cmds";
+pub const HELP_PRIVS_FORMNAME: &str = "__builtin:help_privs";
+
+// Row colors used by the builtin help formspecs
+const COLOR_ALLOWED: &str = "#0F0";
+const COLOR_HEADER: &str = "#FFF";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ChatCommand {
+    pub name: String,
+    /// Parameter summary, e.g. "<name> <privilege>". Empty if not known.
+    pub params: String,
+    /// Whether the player has the privileges to run it, if known
+    pub allowed: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ChatCommands {
+    commands: BTreeMap<String, ChatCommand>,
+    // Granted to this player
+    privileges: Vec<String>,
+    // All registered privileges, with descriptions
+    known_privileges: BTreeMap<String, String>,
+}
+
+impl ChatCommands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The chat messages that make the server describe its commands and
+    /// privileges.
+    pub fn requests() -> Vec<ToServerCommand> {
+        ["/help all", "/help privs"]
+            .into_iter()
+            .map(|message| {
+                TSChatMessageSpec {
+                    message: message.to_string(),
+                }
+                .into()
+            })
+            .collect()
+    }
+
+    /// Known commands, by name
+    pub fn commands(&self) -> impl Iterator<Item = &ChatCommand> {
+        self.commands.values()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ChatCommand> {
+        self.commands.get(name)
+    }
+
+    /// Privileges granted to this player
+    pub fn privileges(&self) -> &[String] {
+        &self.privileges
+    }
+
+    pub fn has_privilege(&self, name: &str) -> bool {
+        self.privileges.iter().any(|p| p == name)
+    }
+
+    /// Every privilege registered on the server, with its description
+    pub fn known_privileges(&self) -> &BTreeMap<String, String> {
+        &self.known_privileges
+    }
+
+    /// Completions for a partially typed chat line.
+    ///
+    /// "/gr" completes to command names ("/grant", "/grantme"), and the
+    /// last word after "/grant", "/grantme", "/revoke" and "/revokeme" to
+    /// privilege names. Candidates are whole lines, sorted.
+    pub fn complete(&self, line: &str) -> Vec<String> {
+        let Some(rest) = line.strip_prefix('/') else {
+            return Vec::new();
+        };
+        let Some((command, args)) = rest.split_once(' ') else {
+            return self
+                .commands
+                .keys()
+                .filter(|name| name.starts_with(rest))
+                .map(|name| format!("/{}", name))
+                .collect();
+        };
+        if !matches!(command, "grant" | "grantme" | "revoke" | "revokeme") {
+            return Vec::new();
+        }
+        let (done, word) = match args.rfind([' ', ',']) {
+            Some(index) => args.split_at(index + 1),
+            None => ("", args),
+        };
+        let mut privileges: Vec<&str> = self.known_privileges.keys().map(|p| p.as_str()).collect();
+        if privileges.is_empty() {
+            privileges = self.privileges.iter().map(|p| p.as_str()).collect();
+            privileges.sort();
+        }
+        privileges
+            .into_iter()
+            .filter(|p| p.starts_with(word))
+            .map(|p| format!("/{} {}{}", command, done, p))
+            .collect()
+    }
+
+    pub fn observe_toclient(&mut self, command: &ToClientCommand) {
+        match command {
+            ToClientCommand::Privileges(spec) => {
+                self.privileges = spec.privileges.clone();
+            }
+            ToClientCommand::ShowFormspec(spec) => match spec.form_name.as_str() {
+                HELP_CMDS_FORMNAME => self.parse_help_cmds(&spec.form_spec),
+                HELP_PRIVS_FORMNAME => self.parse_help_privs(&spec.form_spec),
+                _ => (),
+            },
+            ToClientCommand::TCChatMessage(spec) => {
+                self.parse_help_reply(&strip_escapes(&spec.message));
+            }
+            _ => (),
+        }
+    }
+
+    // tablecolumns[color;tree;text;text]: color, depth, command, params.
+    // Depth 0 rows are the header and the mod names.
+    fn parse_help_cmds(&mut self, formspec: &str) {
+        let Some(cells) = table_cells(formspec) else {
+            return;
+        };
+        for row in cells.chunks_exact(4) {
+            if row[1] != "1" {
+                continue;
+            }
+            self.commands.insert(
+                row[2].clone(),
+                ChatCommand {
+                    name: row[2].clone(),
+                    params: row[3].clone(),
+                    allowed: Some(row[0].eq_ignore_ascii_case(COLOR_ALLOWED)),
+                },
+            );
+        }
+    }
+
+    // tablecolumns[color;text;text]: color, privilege, description
+    fn parse_help_privs(&mut self, formspec: &str) {
+        let Some(cells) = table_cells(formspec) else {
+            return;
+        };
+        for row in cells.chunks_exact(3) {
+            if row[0].eq_ignore_ascii_case(COLOR_HEADER) {
+                continue;
+            }
+            self.known_privileges.insert(row[1].clone(), row[2].clone());
+        }
+    }
+
+    // The chat form of /help ("Available commands: a b c") and
+    // /help all ("Available commands:" then "/name params: description"
+    // lines).
+    fn parse_help_reply(&mut self, message: &str) {
+        let Some(rest) = message.strip_prefix("Available commands:") else {
+            return;
+        };
+        if let Some(lines) = rest.strip_prefix('\n') {
+            for line in lines.lines() {
+                let Some(line) = line.strip_prefix('/') else {
+                    continue;
+                };
+                let usage = line.split_once(": ").map_or(line, |(usage, _)| usage);
+                let (name, params) = usage.split_once(' ').unwrap_or((usage, ""));
+                let entry = self.entry(name);
+                entry.params = params.to_string();
+            }
+        } else {
+            let names = rest.lines().next().unwrap_or("");
+            for name in names.split_whitespace() {
+                self.entry(name);
+            }
+        }
+    }
+
+    fn entry(&mut self, name: &str) -> &mut ChatCommand {
+        self.commands
+            .entry(name.to_string())
+            .or_insert_with(|| ChatCommand {
+                name: name.to_string(),
+                ..Default::default()
+            })
+    }
+}
+
+/// Removes color and translation escape sequences from a message,
+/// like minetest.strip_escapes.
+pub fn strip_escapes(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        // Either "\x1b(...)" or a single character code, like "\x1bE"
+        if chars.next() == Some('(') {
+            for c in chars.by_ref() {
+                if c == ')' {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// The cells of the first table[] element, unescaped
+fn table_cells(formspec: &str) -> Option<Vec<String>> {
+    let elements = split_escaped(formspec, ']');
+    let table = elements
+        .iter()
+        .find_map(|e| e.trim_start().strip_prefix("table["))?;
+    // table[<X>,<Y>;<W>,<H>;<name>;<cell 1>,<cell 2>,...;<selected idx>
+    let parts = split_escaped(table, ';');
+    let cells = parts.get(3)?;
+    Some(
+        split_escaped(cells, ',')
+            .iter()
+            .map(|c| unescape(c))
+            .collect(),
+    )
+}
+
+/// Splits on unescaped `sep`, leaving escapes in place
+fn split_escaped(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == sep {
+            parts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(c) = chars.next() {
+                out.push(c);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::PrivilegesSpec;
+    use crate::wire::command::ShowFormspecSpec;
+    use crate::wire::command::TCChatMessageSpec;
+
+    fn formspec(form_name: &str, form_spec: &str) -> ToClientCommand {
+        ShowFormspecSpec {
+            form_spec: form_spec.to_string(),
+            form_name: form_name.to_string(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn help_formspecs() {
+        let mut commands = ChatCommands::new();
+        commands.observe_toclient(&formspec(
+            HELP_CMDS_FORMNAME,
+            "size[13,6.5]label[0,-0.1;Available commands: (see also: /help <cmd>)]\
+             tablecolumns[color;tree;text;text]\
+             table[0,0.5;12.8,4.8;list;#FFF,0,Command,Parameters,\
+             #0AF,0,builtin,,\
+             #0F0,1,grant,<name> (<privilege> \\[\\, <privilege2> \\[...\\]\\] | all),\
+             #ACACAC,1,shutdown,\\[<delay_in_seconds> | -1\\] \\[-r\\] \\[<message>\\];0]\
+             textarea[0.3,5.2;13.05,1.5;;;Info]",
+        ));
+        let grant = commands.get("grant").unwrap();
+        assert_eq!(
+            grant.params,
+            "<name> (<privilege> [, <privilege2> [...]] | all)"
+        );
+        assert_eq!(grant.allowed, Some(true));
+        assert_eq!(commands.get("shutdown").unwrap().allowed, Some(false));
+        assert_eq!(commands.commands().count(), 2);
+
+        commands.observe_toclient(&formspec(
+            HELP_PRIVS_FORMNAME,
+            "size[13,7.5]label[0,-0.1;Available privileges:]\
+             tablecolumns[color;text,width=12;text]\
+             table[0,0.5;12.8,6;list_privs;#FFF,Privilege,Description,\
+             #0F0,interact,Can interact with things and modify the world,\
+             #ACACAC,privs,Can modify privileges;1]",
+        ));
+        commands.observe_toclient(
+            &PrivilegesSpec {
+                privileges: vec!["interact".to_string(), "shout".to_string()],
+            }
+            .into(),
+        );
+        assert!(commands.has_privilege("shout"));
+        assert_eq!(commands.known_privileges().len(), 2);
+
+        assert_eq!(commands.complete("/gr"), vec!["/grant"]);
+        assert_eq!(commands.complete("/s"), vec!["/shutdown"]);
+        assert_eq!(
+            commands.complete("/grant bob interact,p"),
+            vec!["/grant bob interact,privs"]
+        );
+        assert!(commands.complete("hello").is_empty());
+    }
+
+    #[test]
+    fn help_chat_reply() {
+        let chat = |message: &str| -> ToClientCommand {
+            TCChatMessageSpec {
+                version: 1,
+                message_type: 0,
+                sender: String::new(),
+                message: message.to_string(),
+                timestamp: 0,
+            }
+            .into()
+        };
+        let mut commands = ChatCommands::new();
+        commands.observe_toclient(&chat(
+            "\x1b(T@__builtin)Available commands: \x1bFadmin days\x1bE\x1bE\n\
+             Use '/help <cmd>' to get more information.",
+        ));
+        assert_eq!(commands.complete("/"), vec!["/admin", "/days"]);
+        commands.observe_toclient(&chat(
+            "Available commands:\n/admin: Show the name of the server owner\n\
+             /kick <name> [<reason>]: Kick a player",
+        ));
+        assert_eq!(commands.get("kick").unwrap().params, "<name> [<reason>]");
+        assert_eq!(commands.get("admin").unwrap().params, "");
+        assert_eq!(commands.get("admin").unwrap().allowed, None);
+    }
+}
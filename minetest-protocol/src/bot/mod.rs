@@ -0,0 +1,18 @@
+//!
+//! Client side model
+//!
+//! Building blocks for bots and tools that act as a client: the state a
+//! real client derives from the commands a server sends it, and scripted
+//! input to send back. BotClient puts them together on a connection.
+//!
+pub mod client;
+pub mod commands;
+pub mod script;
+pub mod time;
+pub mod world;
+
+pub use client::BotClient;
+pub use commands::ChatCommands;
+pub use script::InputScript;
+pub use time::TimeOfDay;
+pub use world::ClientWorld;
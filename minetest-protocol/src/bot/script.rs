@@ -0,0 +1,437 @@
+//!
+//! Input scripts
+//!
+//! A list of simple actions for a bot (walk somewhere, look at a node, dig
+//! it or place against it, wait), compiled into the timed TOSERVER_PLAYERPOS and
+//! TOSERVER_INTERACT commands a real client would send while doing them.
+//!
+//! Scripts can also be written as text, one action per line. Positions are
+//! in nodes, times in seconds.
+//!
+//! ```text
+//! # Walk to the tree and take a log
+//! move 10 5 -3
+//! look 11 6 -3
+//! select 1
+//! dig 0.75
+//! look 11 5 -3
+//! place
+//! wait 2
+//! ```
+//!
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::services::client::MinetestClient;
+use crate::wire::command::InteractSpec;
+use crate::wire::command::PlayeritemSpec;
+use crate::wire::command::PlayerposSpec;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::v3f;
+use crate::wire::types::v3s16;
+use crate::wire::types::InteractAction;
+use crate::wire::types::PlayerPos;
+use crate::wire::types::PointedThing;
+
+/// Engine units per node. Player positions on the wire are in these.
+pub const BS: f32 = 10.0;
+
+/// Eye height above the player's feet, in nodes
+pub const EYE_HEIGHT: f32 = 1.625;
+
+// PlayerPos::keys_pressed bits
+pub const KEY_FORWARD: u32 = 1 << 0;
+pub const KEY_BACKWARD: u32 = 1 << 1;
+pub const KEY_LEFT: u32 = 1 << 2;
+pub const KEY_RIGHT: u32 = 1 << 3;
+pub const KEY_JUMP: u32 = 1 << 4;
+pub const KEY_AUX1: u32 = 1 << 5;
+pub const KEY_SNEAK: u32 = 1 << 6;
+pub const KEY_DIG: u32 = 1 << 7;
+pub const KEY_PLACE: u32 = 1 << 8;
+pub const KEY_ZOOM: u32 = 1 << 9;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Walk in a straight line to a position (of the feet, in nodes)
+    MoveTo(v3f),
+    /// Turn to look at a point (in nodes). The node containing it becomes
+    /// the pointed node.
+    LookAt(v3f),
+    /// Select a hotbar slot (0 based)
+    Select(u16),
+    /// Dig the pointed node, taking this long
+    Dig(Duration),
+    /// Place the selected item against the pointed node, on the face
+    /// towards the player
+    Place,
+    Wait(Duration),
+}
+
+/// A command, and when to send it (from the start of the script)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptStep {
+    pub at: Duration,
+    pub command: ToServerCommand,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputScript {
+    pub actions: Vec<Action>,
+    /// Nodes per second. The engine's default movement_speed_walk is 4.
+    pub walk_speed: f32,
+    /// How often to send TOSERVER_PLAYERPOS while moving
+    pub send_interval: Duration,
+}
+
+impl Default for InputScript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputScript {
+    pub fn new() -> Self {
+        Self {
+            actions: Vec::new(),
+            walk_speed: 4.0,
+            send_interval: Duration::from_millis(100),
+        }
+    }
+
+    pub fn push(&mut self, action: Action) -> &mut Self {
+        self.actions.push(action);
+        self
+    }
+
+    /// Parse the text form (see the module docs)
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut script = Self::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let verb = words.next().unwrap_or("");
+            let args: Vec<f32> = match words.map(str::parse).collect() {
+                Ok(args) => args,
+                Err(_) => bail!("Line {}: bad number in {:?}", index + 1, line),
+            };
+            let seconds = |secs: f32| match Duration::try_from_secs_f32(secs) {
+                Ok(duration) => Ok(duration),
+                Err(err) => Err(anyhow!("Line {}: {} in {:?}", index + 1, err, line)),
+            };
+            let action = match (verb, args.as_slice()) {
+                ("move", &[x, y, z]) => Action::MoveTo(v3f::new(x, y, z)),
+                ("look", &[x, y, z]) => Action::LookAt(v3f::new(x, y, z)),
+                ("select", &[slot]) if slot >= 0.0 && slot.fract() == 0.0 => {
+                    Action::Select(slot as u16)
+                }
+                ("dig", &[secs]) => Action::Dig(seconds(secs)?),
+                ("place", &[]) => Action::Place,
+                ("wait", &[secs]) => Action::Wait(seconds(secs)?),
+                _ => bail!("Line {}: can't understand {:?}", index + 1, line),
+            };
+            script.actions.push(action);
+        }
+        Ok(script)
+    }
+
+    /// The commands to send, starting from `start` (as the server last
+    /// placed the player, in engine units). Fails if a dig or place comes
+    /// before anything has been looked at.
+    pub fn compile(&self, start: &PlayerPos) -> Result<Vec<ScriptStep>> {
+        let mut state = start.clone();
+        let mut now = Duration::ZERO;
+        let mut pointed: Option<v3s16> = None;
+        let mut item_index: u16 = 0;
+        let mut steps = Vec::new();
+        let playerpos = |state: &PlayerPos| -> ToServerCommand {
+            PlayerposSpec {
+                player_pos: state.clone(),
+            }
+            .into()
+        };
+        for action in &self.actions {
+            match action {
+                Action::MoveTo(target) => {
+                    let from = nodes(&state.position);
+                    let delta = sub(target, &from);
+                    let distance = length(&delta);
+                    if distance > 0.0 && self.walk_speed > 0.0 {
+                        let duration = distance / self.walk_speed;
+                        // Bounds the step times below as well
+                        let walk_time = Duration::try_from_secs_f32(duration)?;
+                        let factor = self.walk_speed * BS / distance;
+                        state.speed =
+                            v3f::new(delta.x * factor, delta.y * factor, delta.z * factor);
+                        state.yaw = yaw_towards(&delta);
+                        state.keys_pressed |= KEY_FORWARD;
+                        let interval = self.send_interval.as_secs_f32().max(0.001);
+                        let mut count = 1;
+                        while count as f32 * interval < duration {
+                            let elapsed = count as f32 * interval;
+                            let t = elapsed / duration;
+                            state.position = engine(&v3f::new(
+                                from.x + delta.x * t,
+                                from.y + delta.y * t,
+                                from.z + delta.z * t,
+                            ));
+                            steps.push(ScriptStep {
+                                at: now + Duration::from_secs_f32(elapsed),
+                                command: playerpos(&state),
+                            });
+                            count += 1;
+                        }
+                        now += walk_time;
+                    }
+                    state.position = engine(target);
+                    state.speed = v3f::new(0.0, 0.0, 0.0);
+                    state.keys_pressed &= !KEY_FORWARD;
+                    steps.push(ScriptStep {
+                        at: now,
+                        command: playerpos(&state),
+                    });
+                }
+                Action::LookAt(point) => {
+                    let eye = nodes(&state.position);
+                    let eye = v3f::new(eye.x, eye.y + EYE_HEIGHT, eye.z);
+                    let delta = sub(point, &eye);
+                    if length(&delta) > 0.0 {
+                        state.yaw = yaw_towards(&delta);
+                        state.pitch = pitch_towards(&delta);
+                    }
+                    pointed = Some(node_at(point));
+                    steps.push(ScriptStep {
+                        at: now,
+                        command: playerpos(&state),
+                    });
+                }
+                Action::Select(slot) => {
+                    item_index = *slot;
+                    steps.push(ScriptStep {
+                        at: now,
+                        command: PlayeritemSpec { item: *slot }.into(),
+                    });
+                }
+                Action::Dig(duration) => {
+                    let Some(under) = pointed.clone() else {
+                        bail!("Dig before looking at a node");
+                    };
+                    let pointed_thing = PointedThing::Node {
+                        above_surface: facing_neighbour(&under, &nodes(&state.position)),
+                        under_surface: under,
+                    };
+                    let interact = |state: &PlayerPos, action| -> ToServerCommand {
+                        InteractSpec {
+                            action,
+                            item_index,
+                            pointed_thing: pointed_thing.clone(),
+                            player_pos: state.clone(),
+                        }
+                        .into()
+                    };
+                    state.keys_pressed |= KEY_DIG;
+                    steps.push(ScriptStep {
+                        at: now,
+                        command: playerpos(&state),
+                    });
+                    steps.push(ScriptStep {
+                        at: now,
+                        command: interact(&state, InteractAction::StartDigging),
+                    });
+                    now += *duration;
+                    steps.push(ScriptStep {
+                        at: now,
+                        command: interact(&state, InteractAction::DiggingCompleted),
+                    });
+                    state.keys_pressed &= !KEY_DIG;
+                    steps.push(ScriptStep {
+                        at: now,
+                        command: playerpos(&state),
+                    });
+                }
+                Action::Place => {
+                    let Some(under) = pointed.clone() else {
+                        bail!("Place before looking at a node");
+                    };
+                    steps.push(ScriptStep {
+                        at: now,
+                        command: InteractSpec {
+                            action: InteractAction::Place,
+                            item_index,
+                            pointed_thing: PointedThing::Node {
+                                above_surface: facing_neighbour(&under, &nodes(&state.position)),
+                                under_surface: under,
+                            },
+                            player_pos: state.clone(),
+                        }
+                        .into(),
+                    });
+                }
+                Action::Wait(duration) => now += *duration,
+            }
+        }
+        Ok(steps)
+    }
+}
+
+/// Send compiled steps to the server at their times, starting now
+pub async fn play(client: &mut MinetestClient, steps: &[ScriptStep]) -> Result<()> {
+    let start = tokio::time::Instant::now();
+    for step in steps {
+        tokio::time::sleep_until(start + step.at).await;
+        client.send(step.command.clone()).await?;
+    }
+    Ok(())
+}
+
+fn nodes(position: &v3f) -> v3f {
+    v3f::new(position.x / BS, position.y / BS, position.z / BS)
+}
+
+fn engine(position: &v3f) -> v3f {
+    v3f::new(position.x * BS, position.y * BS, position.z * BS)
+}
+
+fn sub(a: &v3f, b: &v3f) -> v3f {
+    v3f::new(a.x - b.x, a.y - b.y, a.z - b.z)
+}
+
+fn length(v: &v3f) -> f32 {
+    (v.x * v.x + v.y * v.y + v.z * v.z).sqrt()
+}
+
+/// Degrees. 0 looks towards +Z, 90 towards -X.
+fn yaw_towards(delta: &v3f) -> f32 {
+    (-delta.x).atan2(delta.z).to_degrees().rem_euclid(360.0)
+}
+
+/// Degrees. Positive looks down.
+fn pitch_towards(delta: &v3f) -> f32 {
+    (-delta.y)
+        .atan2((delta.x * delta.x + delta.z * delta.z).sqrt())
+        .to_degrees()
+}
+
+/// Nodes are centered on integer positions
+fn node_at(point: &v3f) -> v3s16 {
+    v3s16::new(
+        point.x.round() as i16,
+        point.y.round() as i16,
+        point.z.round() as i16,
+    )
+}
+
+/// The neighbour of `node` on the face towards the eye, like the node a
+/// client would place against
+fn facing_neighbour(node: &v3s16, feet: &v3f) -> v3s16 {
+    let dx = feet.x - node.x as f32;
+    let dy = feet.y + EYE_HEIGHT - node.y as f32;
+    let dz = feet.z - node.z as f32;
+    let step = |d: f32| if d < 0.0 { -1 } else { 1 };
+    if dx.abs() >= dy.abs() && dx.abs() >= dz.abs() {
+        v3s16::new(node.x + step(dx), node.y, node.z)
+    } else if dy.abs() >= dz.abs() {
+        v3s16::new(node.x, node.y + step(dy), node.z)
+    } else {
+        v3s16::new(node.x, node.y, node.z + step(dz))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> PlayerPos {
+        PlayerPos {
+            position: v3f::new(0.0, 0.0, 0.0),
+            speed: v3f::new(0.0, 0.0, 0.0),
+            pitch: 0.0,
+            yaw: 0.0,
+            keys_pressed: 0,
+            fov: 1.25,
+            wanted_range: 12,
+        }
+    }
+
+    #[test]
+    fn walk_look_and_dig() {
+        let script = InputScript::parse(
+            "
+            # comment
+            move 2 0 0
+            look 3 1 0
+            select 2
+            dig 0.5
+            wait 1
+            ",
+        )
+        .unwrap();
+        let steps = script.compile(&start()).unwrap();
+
+        // 2 nodes at 4 nodes/s: every 100ms for half a second
+        let moving: Vec<&ScriptStep> = steps
+            .iter()
+            .take_while(|step| step.at < Duration::from_millis(500))
+            .collect();
+        assert_eq!(moving.len(), 4);
+        let ToServerCommand::Playerpos(spec) = &moving[0].command else {
+            panic!("expected playerpos");
+        };
+        assert!((spec.player_pos.position.x - 4.0).abs() < 0.01);
+        assert!((spec.player_pos.speed.x - 40.0).abs() < 0.01);
+        assert_eq!(spec.player_pos.yaw, 270.0);
+        assert_eq!(spec.player_pos.keys_pressed, KEY_FORWARD);
+
+        let digging: Vec<(Duration, &InteractSpec)> = steps
+            .iter()
+            .filter_map(|step| match &step.command {
+                ToServerCommand::Interact(spec) => Some((step.at, &**spec)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(digging.len(), 2);
+        assert_eq!(digging[0].0, Duration::from_millis(500));
+        assert_eq!(digging[0].1.action, InteractAction::StartDigging);
+        assert_eq!(digging[1].0, Duration::from_millis(1000));
+        assert_eq!(digging[1].1.action, InteractAction::DiggingCompleted);
+        assert_eq!(digging[0].1.item_index, 2);
+        assert_eq!(
+            digging[0].1.pointed_thing,
+            PointedThing::Node {
+                under_surface: v3s16::new(3, 1, 0),
+                above_surface: v3s16::new(2, 1, 0),
+            }
+        );
+        assert_eq!(digging[0].1.player_pos.keys_pressed, KEY_DIG);
+        let last = steps.last().unwrap();
+        let ToServerCommand::Playerpos(spec) = &last.command else {
+            panic!("expected playerpos");
+        };
+        assert_eq!(spec.player_pos.keys_pressed, 0);
+        assert!((spec.player_pos.position.x - 20.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn bad_scripts() {
+        assert!(InputScript::parse("jump").is_err());
+        assert!(InputScript::parse("move 1 2").is_err());
+        assert!(InputScript::parse("wait soon").is_err());
+        for bad in ["wait -1", "wait inf", "wait NaN", "dig 1e30"] {
+            let err = InputScript::parse(bad).unwrap_err();
+            assert!(err.to_string().starts_with("Line 1: "), "{}", err);
+        }
+        let script = InputScript::parse("dig 1").unwrap();
+        assert!(script.compile(&start()).is_err());
+        assert!(InputScript::parse("place 1").is_err());
+        let script = InputScript::parse("place").unwrap();
+        assert!(script.compile(&start()).is_err());
+        let script = InputScript::parse("move 1e30 0 0").unwrap();
+        assert!(script.compile(&start()).is_err());
+    }
+}
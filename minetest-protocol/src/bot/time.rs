@@ -0,0 +1,132 @@
+//!
+//! Time of day
+//!
+//! The server sends TOCLIENT_TIME_OF_DAY every few seconds. In between,
+//! the client advances the time itself using time_speed (the ratio of game
+//! time to real time, 72 by default), so day/night changes smoothly.
+//!
+//! Servers too old to send time_speed get it estimated from the change
+//! between updates, like the engine's client does.
+//!
+use std::time::Instant;
+
+use crate::wire::command::TimeOfDaySpec;
+
+/// Time of day ticks per game day
+pub const TICKS_PER_DAY: u32 = 24000;
+
+const SECONDS_PER_DAY: f32 = 24.0 * 3600.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeOfDay {
+    // The last update, as a fraction of a day in [0, 1)
+    base: f32,
+    base_time: Option<Instant>,
+    speed: f32,
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeOfDay {
+    pub fn new() -> Self {
+        Self {
+            base: 0.0,
+            base_time: None,
+            speed: 0.0,
+        }
+    }
+
+    /// True once an update has been received
+    pub fn is_set(&self) -> bool {
+        self.base_time.is_some()
+    }
+
+    /// Game seconds per real second
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn update(&mut self, spec: &TimeOfDaySpec, now: Instant) {
+        let value = (spec.time_of_day as u32 % TICKS_PER_DAY) as f32 / TICKS_PER_DAY as f32;
+        match spec.time_speed {
+            Some(speed) => self.speed = speed,
+            None => {
+                if let Some(base_time) = self.base_time {
+                    let elapsed = now.saturating_duration_since(base_time).as_secs_f32();
+                    let mut diff = value - self.base;
+                    // Wrapped past midnight
+                    if value < 0.2 && self.base > 0.8 {
+                        diff += 1.0;
+                    }
+                    if elapsed > 0.0 {
+                        self.speed = SECONDS_PER_DAY * diff / elapsed;
+                    }
+                }
+            }
+        }
+        self.base = value;
+        self.base_time = Some(now);
+    }
+
+    /// The time of day at `now`, as a fraction of a day in [0, 1).
+    /// 0 is midnight, 0.5 is noon.
+    pub fn fraction_at(&self, now: Instant) -> f32 {
+        let Some(base_time) = self.base_time else {
+            return self.base;
+        };
+        let elapsed = now.saturating_duration_since(base_time).as_secs_f32();
+        (self.base + elapsed * self.speed / SECONDS_PER_DAY).rem_euclid(1.0)
+    }
+
+    /// The time of day at `now`, in ticks [0, 24000)
+    pub fn ticks_at(&self, now: Instant) -> u32 {
+        (self.fraction_at(now) * TICKS_PER_DAY as f32).round() as u32 % TICKS_PER_DAY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn advances_between_updates() {
+        let start = Instant::now();
+        let mut time = TimeOfDay::new();
+        time.update(
+            &TimeOfDaySpec {
+                time_of_day: 6000,
+                time_speed: Some(72.0),
+            },
+            start,
+        );
+        assert_eq!(time.ticks_at(start), 6000);
+        // 72x: 20 real minutes per game day, 50 real seconds per 1000 ticks
+        assert_eq!(time.ticks_at(start + Duration::from_secs(50)), 7000);
+        // Wraps at midnight
+        assert_eq!(time.ticks_at(start + Duration::from_secs(1000)), 2000);
+    }
+
+    #[test]
+    fn estimates_speed_for_old_servers() {
+        let start = Instant::now();
+        let mut time = TimeOfDay::new();
+        let update = |time: &mut TimeOfDay, ticks, secs| {
+            let spec = TimeOfDaySpec {
+                time_of_day: ticks,
+                time_speed: None,
+            };
+            time.update(&spec, start + Duration::from_secs(secs));
+        };
+        update(&mut time, 23500, 0);
+        assert_eq!(time.speed(), 0.0);
+        update(&mut time, 500, 50);
+        assert!((time.speed() - 72.0).abs() < 0.01);
+        let later = start + Duration::from_secs(100);
+        assert!((time.ticks_at(later) as i64 - 1500).abs() <= 1);
+    }
+}
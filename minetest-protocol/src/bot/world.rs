@@ -0,0 +1,667 @@
+//!
+//! ClientWorld
+//!
+//! The world as seen by a client. Feed it every command received from the
+//! server with `observe_toclient`, and every command sent with
+//! `observe_toserver`.
+//!
+//! It keeps the nodes of the map blocks received (named with the node
+//! definitions), the active objects in range, the player's inventory and
+//! the HUD.
+//!
+//! Like the engine's client (client_mapblock_limit), it keeps at most
+//! `block_limit` blocks. `evict_blocks` drops the ones farthest from the
+//! player, and returns the TOSERVER_DELETEDBLOCKS to send, so the server
+//! knows to send them again when the player comes back:
+//!
+//! ```text
+//! world.observe_toclient(&command);
+//! for spec in world.evict_blocks() {
+//!     client.send(spec.into()).await?;
+//! }
+//! ```
+//!
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::game::hotbar::Hotbar;
+use crate::game::minimap::MinimapTracker;
+use crate::wire::command::DeletedblocksSpec;
+use crate::wire::command::HudaddSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::v3f;
+use crate::wire::types::v3s16;
+use crate::wire::types::ActiveObjectCommand;
+use crate::wire::types::AddedObject;
+use crate::wire::types::HudStat;
+use crate::wire::types::Inventory;
+use crate::wire::types::InventoryEntry;
+use crate::wire::types::InventoryList;
+use crate::wire::types::ItemStackUpdate;
+use crate::wire::types::MapNode;
+use crate::wire::types::MapNodesBulk;
+use crate::wire::types::PlayerPos;
+
+use super::commands::ChatCommands;
+use super::script::BS;
+use super::time::TimeOfDay;
+
+/// Nodes per map block edge
+pub const MAP_BLOCKSIZE: i16 = 16;
+
+/// The engine's default client_mapblock_limit
+pub const DEFAULT_BLOCK_LIMIT: usize = 7500;
+
+/// Blocks in one TOSERVER_DELETEDBLOCKS (the count is a u8)
+const MAX_DELETED_PER_COMMAND: usize = 255;
+
+/// An active object (entity or player) in range
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientObject {
+    pub id: u16,
+    /// The player's name, empty for entities
+    pub name: String,
+    pub is_player: bool,
+    /// In engine units
+    pub position: v3f,
+    pub hp: u16,
+}
+
+impl ClientObject {
+    fn new(added: &AddedObject) -> Self {
+        let init = &added.init_data;
+        let mut object = Self {
+            id: added.id,
+            name: init.name.clone(),
+            is_player: init.is_player,
+            position: init.position,
+            hp: init.hp,
+        };
+        for message in &init.messages {
+            object.apply(message);
+        }
+        object
+    }
+
+    fn apply(&mut self, command: &ActiveObjectCommand) {
+        match command {
+            ActiveObjectCommand::UpdatePosition(update) => self.position = update.position,
+            ActiveObjectCommand::Punched(punched) => self.hp = punched.hp,
+            _ => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientWorld {
+    time: TimeOfDay,
+    minimap: MinimapTracker,
+    commands: ChatCommands,
+    player: PlayerPos,
+    inventory: Vec<InventoryList>,
+    hotbar: Hotbar,
+    // Map blocks received (and not deleted)
+    blocks: HashMap<v3s16, Box<MapNodesBulk>>,
+    block_limit: usize,
+    // Content id to node name, from the node definitions
+    node_names: HashMap<u16, String>,
+    objects: BTreeMap<u16, ClientObject>,
+    hud: BTreeMap<u32, HudaddSpec>,
+}
+
+impl Default for ClientWorld {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientWorld {
+    pub fn new() -> Self {
+        Self {
+            time: TimeOfDay::new(),
+            minimap: MinimapTracker::default(),
+            commands: ChatCommands::new(),
+            player: PlayerPos {
+                position: v3f::new(0.0, 0.0, 0.0),
+                speed: v3f::new(0.0, 0.0, 0.0),
+                pitch: 0.0,
+                yaw: 0.0,
+                keys_pressed: 0,
+                fov: 1.25,
+                wanted_range: 12,
+            },
+            inventory: Vec::new(),
+            hotbar: Hotbar::new(),
+            blocks: HashMap::new(),
+            block_limit: DEFAULT_BLOCK_LIMIT,
+            node_names: HashMap::new(),
+            objects: BTreeMap::new(),
+            hud: BTreeMap::new(),
+        }
+    }
+
+    pub fn observe_toclient(&mut self, command: &ToClientCommand) {
+        self.observe_toclient_at(command, Instant::now())
+    }
+
+    /// Like `observe_toclient`, for a command received at `now`
+    pub fn observe_toclient_at(&mut self, command: &ToClientCommand, now: Instant) {
+        match command {
+            ToClientCommand::TimeOfDay(spec) => self.time.update(spec, now),
+            ToClientCommand::AuthAccept(spec) => self.player.position = spec.player_pos,
+            ToClientCommand::MovePlayer(spec) => {
+                self.player.position = spec.pos;
+                self.player.pitch = spec.pitch;
+                self.player.yaw = spec.yaw;
+            }
+            ToClientCommand::Inventory(spec) => {
+                apply_inventory(&mut self.inventory, &spec.inventory)
+            }
+            ToClientCommand::Blockdata(spec) => {
+                self.blocks
+                    .insert(spec.pos.clone(), Box::new(spec.block.nodes.clone()));
+            }
+            ToClientCommand::Addnode(spec) => self.set_node(&spec.pos, spec.node),
+            ToClientCommand::Removenode(spec) => self.set_node(&spec.pos, MapNode::AIR),
+            ToClientCommand::Nodedef(spec) => {
+                self.node_names = spec
+                    .node_def
+                    .content_features
+                    .iter()
+                    .map(|(id, features)| (*id, features.name.clone()))
+                    .collect();
+            }
+            ToClientCommand::ActiveObjectRemoveAdd(spec) => {
+                for id in &spec.removed_object_ids {
+                    self.objects.remove(id);
+                }
+                for added in &spec.added_objects {
+                    self.objects.insert(added.id, ClientObject::new(added));
+                }
+            }
+            ToClientCommand::ActiveObjectMessages(spec) => {
+                for message in &spec.objects {
+                    if let Some(object) = self.objects.get_mut(&message.id) {
+                        object.apply(&message.data);
+                    }
+                }
+            }
+            ToClientCommand::Hudadd(spec) => {
+                self.hud.insert(spec.server_id, (**spec).clone());
+            }
+            ToClientCommand::Hudchange(spec) => {
+                if let Some(element) = self.hud.get_mut(&spec.server_id) {
+                    apply_hud_stat(element, &spec.stat);
+                }
+            }
+            ToClientCommand::Hudrm(spec) => {
+                self.hud.remove(&spec.server_id);
+            }
+            _ => (),
+        }
+        self.minimap.observe_toclient(command);
+        self.commands.observe_toclient(command);
+        self.hotbar.observe_toclient(command);
+    }
+
+    /// Keeps the player's state in step with what was sent to the server
+    pub fn observe_toserver(&mut self, command: &ToServerCommand) {
+        match command {
+            ToServerCommand::Playerpos(spec) => self.player = spec.player_pos.clone(),
+            ToServerCommand::Interact(spec) => self.player = spec.player_pos.clone(),
+            ToServerCommand::Deletedblocks(spec) => {
+                for pos in &spec.blocks {
+                    self.blocks.remove(pos);
+                }
+            }
+            _ => (),
+        }
+        self.hotbar.observe_toserver(command);
+    }
+
+    /// The current time of day in ticks [0, 24000), advanced smoothly
+    /// between updates from the server.
+    pub fn time_of_day(&self) -> u32 {
+        self.time.ticks_at(Instant::now())
+    }
+
+    /// The current time of day as a fraction of a day [0, 1)
+    pub fn time_of_day_f(&self) -> f32 {
+        self.time.fraction_at(Instant::now())
+    }
+
+    pub fn time(&self) -> &TimeOfDay {
+        &self.time
+    }
+
+    pub fn minimap(&self) -> &MinimapTracker {
+        &self.minimap
+    }
+
+    pub fn minimap_mut(&mut self) -> &mut MinimapTracker {
+        &mut self.minimap
+    }
+
+    /// Chat commands and privileges, for completion
+    pub fn commands(&self) -> &ChatCommands {
+        &self.commands
+    }
+
+    /// The player's last known state, in engine units
+    /// (an InputScript can start from it)
+    pub fn player(&self) -> &PlayerPos {
+        &self.player
+    }
+
+    /// The player's feet, in nodes
+    pub fn position(&self) -> v3f {
+        let p = &self.player.position;
+        v3f::new(p.x / BS, p.y / BS, p.z / BS)
+    }
+
+    /// The player's own inventory lists ("main", "craft", ...)
+    pub fn inventory(&self) -> &[InventoryList] {
+        &self.inventory
+    }
+
+    pub fn inventory_list(&self, name: &str) -> Option<&InventoryList> {
+        self.inventory.iter().find(|list| list.name == name)
+    }
+
+    /// The hotbar, and the wielded item
+    pub fn hotbar(&self) -> &Hotbar {
+        &self.hotbar
+    }
+
+    pub fn has_block(&self, pos: &v3s16) -> bool {
+        self.blocks.contains_key(pos)
+    }
+
+    /// Positions of the map blocks received, in no particular order
+    pub fn blocks(&self) -> impl Iterator<Item = &v3s16> {
+        self.blocks.keys()
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn block_limit(&self) -> usize {
+        self.block_limit
+    }
+
+    /// Takes effect at the next `evict_blocks`
+    pub fn set_block_limit(&mut self, block_limit: usize) {
+        self.block_limit = block_limit;
+    }
+
+    /// Drops the blocks farthest from the player until at most
+    /// `block_limit` are left. The server still thinks the client has
+    /// them, so the commands returned must be sent.
+    pub fn evict_blocks(&mut self) -> Vec<DeletedblocksSpec> {
+        let excess = self.blocks.len().saturating_sub(self.block_limit);
+        if excess == 0 {
+            return Vec::new();
+        }
+        let center = self.player_block();
+        let distance = |pos: &v3s16| {
+            let d = |a: i16, b: i16| (a as i32 - b as i32).pow(2);
+            d(pos.x, center.x) + d(pos.y, center.y) + d(pos.z, center.z)
+        };
+        let mut blocks: Vec<v3s16> = self.blocks.keys().cloned().collect();
+        blocks.select_nth_unstable_by_key(excess - 1, |pos| std::cmp::Reverse(distance(pos)));
+        blocks.truncate(excess);
+        for pos in &blocks {
+            self.blocks.remove(pos);
+        }
+        blocks
+            .chunks(MAX_DELETED_PER_COMMAND)
+            .map(|chunk| DeletedblocksSpec {
+                blocks: chunk.to_vec(),
+            })
+            .collect()
+    }
+
+    /// The block containing the player
+    pub fn player_block(&self) -> v3s16 {
+        let p = self.position();
+        let block = |v: f32| (v.round() as i32).div_euclid(MAP_BLOCKSIZE as i32) as i16;
+        v3s16::new(block(p.x), block(p.y), block(p.z))
+    }
+
+    /// The node at `pos` (in nodes), if its block was received
+    pub fn node(&self, pos: &v3s16) -> Option<MapNode> {
+        let (block, index) = split_node_pos(pos);
+        self.blocks.get(&block).map(|nodes| nodes.nodes[index])
+    }
+
+    /// The name of the node at `pos`, once the node definitions are known
+    pub fn node_name(&self, pos: &v3s16) -> Option<&str> {
+        let node = self.node(pos)?;
+        self.node_names.get(&node.param0).map(|name| name.as_str())
+    }
+
+    /// Active objects in range, by id
+    pub fn objects(&self) -> impl Iterator<Item = &ClientObject> {
+        self.objects.values()
+    }
+
+    pub fn object(&self, id: u16) -> Option<&ClientObject> {
+        self.objects.get(&id)
+    }
+
+    /// HUD elements, by server id, with their changes applied
+    pub fn hud(&self) -> &BTreeMap<u32, HudaddSpec> {
+        &self.hud
+    }
+
+    fn set_node(&mut self, pos: &v3s16, node: MapNode) {
+        let (block, index) = split_node_pos(pos);
+        if let Some(nodes) = self.blocks.get_mut(&block) {
+            nodes.nodes[index] = node;
+        }
+    }
+}
+
+/// The block containing a node, and the node's index in it
+fn split_node_pos(pos: &v3s16) -> (v3s16, usize) {
+    let block = |v: i16| v.div_euclid(MAP_BLOCKSIZE);
+    let rel = |v: i16| v.rem_euclid(MAP_BLOCKSIZE) as usize;
+    (
+        v3s16::new(block(pos.x), block(pos.y), block(pos.z)),
+        rel(pos.z) * 256 + rel(pos.y) * 16 + rel(pos.x),
+    )
+}
+
+fn apply_hud_stat(element: &mut HudaddSpec, stat: &HudStat) {
+    match stat {
+        HudStat::Pos(v) => element.pos = v.clone(),
+        HudStat::Name(v) => element.name = v.clone(),
+        HudStat::Scale(v) => element.scale = v.clone(),
+        HudStat::Text(v) => element.text = v.clone(),
+        HudStat::Number(v) => element.number = *v,
+        HudStat::Item(v) => element.item = *v,
+        HudStat::Dir(v) => element.dir = *v,
+        HudStat::Align(v) => element.align = v.clone(),
+        HudStat::Offset(v) => element.offset = v.clone(),
+        HudStat::WorldPos(v) => element.world_pos = Some(*v),
+        HudStat::Size(v) => element.size = Some(v.clone()),
+        HudStat::ZIndex(v) => element.z_index = Some(*v as i16),
+        HudStat::Text2(v) => element.text2 = Some(v.clone()),
+        HudStat::Style(v) => element.style = Some(*v),
+    }
+}
+
+/// Applies an inventory update, like Inventory::deSerialize in the engine.
+/// Lists neither updated nor kept are removed, and Keep items keep the old
+/// stack in that slot.
+pub fn apply_inventory(lists: &mut Vec<InventoryList>, update: &Inventory) {
+    let mut old = std::mem::take(lists);
+    for entry in &update.entries {
+        match entry {
+            InventoryEntry::KeepList(name) => {
+                if let Some(index) = old.iter().position(|list| &list.name == name) {
+                    lists.push(old.swap_remove(index));
+                }
+            }
+            InventoryEntry::Update(list) => {
+                let previous = old.iter().find(|l| l.name == list.name);
+                let items = list
+                    .items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| match item {
+                        ItemStackUpdate::Keep => previous
+                            .and_then(|p| p.items.get(i).cloned())
+                            .unwrap_or(ItemStackUpdate::Empty),
+                        item => item.clone(),
+                    })
+                    .collect();
+                lists.push(InventoryList {
+                    name: list.name.clone(),
+                    width: list.width,
+                    items,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::ActiveObjectMessagesSpec;
+    use crate::wire::command::ActiveObjectRemoveAddSpec;
+    use crate::wire::command::AddnodeSpec;
+    use crate::wire::command::BlockdataSpec;
+    use crate::wire::command::DeletedblocksSpec;
+    use crate::wire::command::HudchangeSpec;
+    use crate::wire::command::HudrmSpec;
+    use crate::wire::command::InventorySpec;
+    use crate::wire::command::MovePlayerSpec;
+    use crate::wire::command::RemovenodeSpec;
+    use crate::wire::types::v2f;
+    use crate::wire::types::AOCPunched;
+    use crate::wire::types::ActiveObjectMessage;
+    use crate::wire::types::GenericInitData;
+    use crate::wire::types::ItemStack;
+    use crate::wire::types::ItemStackMetadata;
+    use crate::wire::types::MapBlockBuf;
+
+    fn stack(name: &str, count: u16) -> ItemStackUpdate {
+        ItemStackUpdate::Item(ItemStack {
+            name: name.to_string(),
+            count,
+            wear: 0,
+            metadata: ItemStackMetadata {
+                string_vars: vec![],
+            },
+        })
+    }
+
+    fn list(name: &str, items: Vec<ItemStackUpdate>) -> InventoryEntry {
+        InventoryEntry::Update(InventoryList {
+            name: name.to_string(),
+            width: 0,
+            items,
+        })
+    }
+
+    #[test]
+    fn tracks_player_and_inventory() {
+        let mut world = ClientWorld::new();
+        world.observe_toclient(
+            &MovePlayerSpec {
+                pos: v3f::new(105.0, 20.0, -40.0),
+                pitch: 0.0,
+                yaw: 90.0,
+            }
+            .into(),
+        );
+        assert_eq!(world.position(), v3f::new(10.5, 2.0, -4.0));
+        assert_eq!(world.player_block(), v3s16::new(0, 0, -1));
+
+        let inventory = |entries| -> ToClientCommand {
+            InventorySpec {
+                inventory: Inventory { entries },
+            }
+            .into()
+        };
+        world.observe_toclient(&inventory(vec![
+            list(
+                "main",
+                vec![stack("default:dirt", 5), ItemStackUpdate::Empty],
+            ),
+            list("craft", vec![ItemStackUpdate::Empty]),
+        ]));
+        world.observe_toclient(&inventory(vec![list(
+            "main",
+            vec![ItemStackUpdate::Keep, stack("default:stone", 1)],
+        )]));
+        assert_eq!(world.inventory().len(), 1);
+        let main = world.inventory_list("main").unwrap();
+        assert_eq!(main.items[0], stack("default:dirt", 5));
+        assert_eq!(main.items[1], stack("default:stone", 1));
+    }
+
+    #[test]
+    fn tracks_blocks() {
+        let mut world = ClientWorld::new();
+        let pos = v3s16::new(1, -2, 3);
+        let block = MapBlockBuf::new().to_map_block();
+        world.observe_toclient(
+            &BlockdataSpec {
+                pos: pos.clone(),
+                block,
+                network_specific_version: 2,
+            }
+            .into(),
+        );
+        assert!(world.has_block(&pos));
+        world.observe_toserver(
+            &DeletedblocksSpec {
+                blocks: vec![pos.clone()],
+            }
+            .into(),
+        );
+        assert!(!world.has_block(&pos));
+    }
+
+    #[test]
+    fn evicts_far_blocks() {
+        let mut world = ClientWorld::new();
+        world.set_block_limit(2);
+        for x in [3, -1, 0] {
+            world.observe_toclient(
+                &BlockdataSpec {
+                    pos: v3s16::new(x, 0, 0),
+                    block: MapBlockBuf::new().to_map_block(),
+                    network_specific_version: 2,
+                }
+                .into(),
+            );
+        }
+        assert_eq!(world.block_count(), 3);
+        assert_eq!(
+            world.evict_blocks(),
+            vec![DeletedblocksSpec {
+                blocks: vec![v3s16::new(3, 0, 0)],
+            }]
+        );
+        assert_eq!(world.block_count(), 2);
+        assert!(world.evict_blocks().is_empty());
+
+        world.set_block_limit(0);
+        let deleted = world.evict_blocks();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].blocks.len(), 2);
+        assert_eq!(world.block_count(), 0);
+    }
+
+    #[test]
+    fn tracks_nodes_objects_and_hud() {
+        let mut world = ClientWorld::new();
+        world.observe_toclient(
+            &BlockdataSpec {
+                pos: v3s16::new(0, -1, 0),
+                block: MapBlockBuf::new().to_map_block(),
+                network_specific_version: 2,
+            }
+            .into(),
+        );
+        let pos = v3s16::new(1, -2, 3);
+        let stone = MapNode {
+            param0: 5,
+            param1: 0,
+            param2: 0,
+        };
+        world.observe_toclient(
+            &AddnodeSpec {
+                pos: pos.clone(),
+                node: stone,
+                keep_metadata: false,
+            }
+            .into(),
+        );
+        assert_eq!(world.node(&pos), Some(stone));
+        // Not loaded
+        assert_eq!(world.node(&v3s16::new(1, 2, 3)), None);
+        assert_eq!(world.node_name(&pos), None);
+        world.observe_toclient(&RemovenodeSpec { pos: pos.clone() }.into());
+        assert!(world.node(&pos).unwrap().is_air());
+
+        let added = AddedObject {
+            id: 9,
+            typ: 101,
+            init_data: GenericInitData {
+                version: 1,
+                name: "alice".to_string(),
+                is_player: true,
+                id: 9,
+                position: v3f::new(10.0, 0.0, 0.0),
+                rotation: v3f::new(0.0, 0.0, 0.0),
+                hp: 20,
+                messages: vec![],
+            },
+        };
+        world.observe_toclient(
+            &ActiveObjectRemoveAddSpec {
+                removed_object_ids: vec![],
+                added_objects: vec![added],
+            }
+            .into(),
+        );
+        world.observe_toclient(
+            &ActiveObjectMessagesSpec {
+                objects: vec![ActiveObjectMessage {
+                    id: 9,
+                    data: ActiveObjectCommand::Punched(AOCPunched { hp: 15 }),
+                }],
+            }
+            .into(),
+        );
+        let alice = world.object(9).unwrap();
+        assert!(alice.is_player);
+        assert_eq!((alice.name.as_str(), alice.hp), ("alice", 15));
+        world.observe_toclient(
+            &ActiveObjectRemoveAddSpec {
+                removed_object_ids: vec![9],
+                added_objects: vec![],
+            }
+            .into(),
+        );
+        assert_eq!(world.objects().count(), 0);
+
+        let element = HudaddSpec {
+            server_id: 3,
+            typ: 1,
+            pos: v2f::new(0.5, 0.5),
+            name: "score".to_string(),
+            scale: v2f::new(1.0, 1.0),
+            text: "0".to_string(),
+            number: 0xffffff,
+            item: 0,
+            dir: 0,
+            align: v2f::new(0.0, 0.0),
+            offset: v2f::new(0.0, 0.0),
+            world_pos: None,
+            size: None,
+            z_index: None,
+            text2: None,
+            style: None,
+        };
+        world.observe_toclient(&element.into());
+        world.observe_toclient(
+            &HudchangeSpec {
+                server_id: 3,
+                stat: HudStat::Text("42".to_string()),
+            }
+            .into(),
+        );
+        assert_eq!(world.hud()[&3].text, "42");
+        world.observe_toclient(&HudrmSpec { server_id: 3 }.into());
+        assert!(world.hud().is_empty());
+    }
+}
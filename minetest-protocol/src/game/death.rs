@@ -0,0 +1,326 @@
+//!
+//! Death and respawn
+//!
+//! PlayerVitals reports a death with a plain Deathscreen. DeathFlow takes
+//! the commands produced by PlayerVitals and turns a death into the full
+//! flow the server wants:
+//!
+//! * the death screen, optionally pointing the camera at the killer, or a
+//!   respawn formspec instead of the client's built-in screen,
+//! * the inventory kept, dropped (returned to the caller to spawn in the
+//!   world) or cleared,
+//! * on respawn, full vitals and a MovePlayer to the respawn position.
+//!
+use crate::wire::command::DeathscreenSpec;
+use crate::wire::command::InventorySpec;
+use crate::wire::command::MovePlayerSpec;
+use crate::wire::command::ShowFormspecSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::v3f;
+use crate::wire::types::Inventory;
+use crate::wire::types::InventoryEntry;
+use crate::wire::types::ItemStack;
+use crate::wire::types::ItemStackUpdate;
+
+use super::vitals::PlayerVitals;
+
+/// The form name and button of the engine's builtin death formspec
+pub const DEATH_FORM_NAME: &str = "__builtin:death";
+pub const RESPAWN_BUTTON: &str = "btn_respawn";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespawnUi {
+    /// The client's own death screen (TOCLIENT_DEATHSCREEN).
+    /// The client answers with TOSERVER_RESPAWN.
+    Deathscreen,
+    /// A formspec with a RESPAWN_BUTTON. It is shown again if the player
+    /// closes it without respawning.
+    Formspec {
+        form_name: String,
+        form_spec: String,
+    },
+}
+
+impl RespawnUi {
+    /// The formspec the engine's builtin death handler shows
+    pub fn builtin_formspec() -> Self {
+        RespawnUi::Formspec {
+            form_name: DEATH_FORM_NAME.to_string(),
+            form_spec: format!(
+                "size[11,5.5]bgcolor[#320000b4;true]label[4.85,1.35;You died]button_exit[4,3;3,0.5;{};Respawn]",
+                RESPAWN_BUTTON
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InventoryPolicy {
+    Keep,
+    /// Empty the lists, returning the items so they can be dropped
+    Drop,
+    /// Empty the lists, destroying the items
+    Clear,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DeathOutcome {
+    pub commands: Vec<ToClientCommand>,
+    /// Set when the player died in this step
+    pub died: bool,
+    /// Items to spawn in the world (InventoryPolicy::Drop)
+    pub dropped: Vec<ItemStack>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeathFlow {
+    pub respawn_ui: RespawnUi,
+    pub inventory_policy: InventoryPolicy,
+    /// The lists affected by the inventory policy
+    pub lists: Vec<String>,
+    pub respawn_pos: v3f,
+    pub respawn_pitch: f32,
+    pub respawn_yaw: f32,
+}
+
+impl DeathFlow {
+    pub fn new(respawn_pos: v3f) -> Self {
+        Self {
+            respawn_ui: RespawnUi::Deathscreen,
+            inventory_policy: InventoryPolicy::Keep,
+            lists: vec!["main".to_string(), "craft".to_string()],
+            respawn_pos,
+            respawn_pitch: 0.0,
+            respawn_yaw: 0.0,
+        }
+    }
+
+    /// Pass the commands returned by PlayerVitals through this. On death,
+    /// the Deathscreen is replaced by the configured UI, and the inventory
+    /// policy is applied to `inventory`. `camera_target` is where the
+    /// death screen points the camera (e.g. the killer).
+    pub fn process(
+        &self,
+        commands: Vec<ToClientCommand>,
+        inventory: &mut Inventory,
+        camera_target: Option<v3f>,
+    ) -> DeathOutcome {
+        let mut outcome = DeathOutcome::default();
+        for command in commands {
+            if let ToClientCommand::Deathscreen(_) = command {
+                outcome.died = true;
+                outcome.commands.push(self.death_ui(camera_target));
+                if let Some(update) = self.apply_policy(inventory, &mut outcome.dropped) {
+                    outcome.commands.push(update);
+                }
+            } else {
+                outcome.commands.push(command);
+            }
+        }
+        outcome
+    }
+
+    fn death_ui(&self, camera_target: Option<v3f>) -> ToClientCommand {
+        match &self.respawn_ui {
+            RespawnUi::Deathscreen => DeathscreenSpec {
+                set_camera_point_target: camera_target.is_some(),
+                camera_point_target: camera_target.unwrap_or(v3f::new(0.0, 0.0, 0.0)),
+            }
+            .into(),
+            RespawnUi::Formspec {
+                form_name,
+                form_spec,
+            } => show_formspec(form_name, form_spec),
+        }
+    }
+
+    fn apply_policy(
+        &self,
+        inventory: &mut Inventory,
+        dropped: &mut Vec<ItemStack>,
+    ) -> Option<ToClientCommand> {
+        if self.inventory_policy == InventoryPolicy::Keep {
+            return None;
+        }
+        let mut changed = false;
+        for entry in inventory.entries.iter_mut() {
+            let InventoryEntry::Update(list) = entry else {
+                continue;
+            };
+            if !self.lists.contains(&list.name) {
+                continue;
+            }
+            for item in list.items.iter_mut() {
+                if let ItemStackUpdate::Item(stack) =
+                    std::mem::replace(item, ItemStackUpdate::Empty)
+                {
+                    changed = true;
+                    if self.inventory_policy == InventoryPolicy::Drop {
+                        dropped.push(stack);
+                    }
+                }
+            }
+        }
+        changed.then(|| {
+            InventorySpec {
+                inventory: inventory.clone(),
+            }
+            .into()
+        })
+    }
+
+    /// Handle respawn requests from the client: TOSERVER_RESPAWN, or the
+    /// respawn formspec. Returns the commands to send.
+    pub fn handle_toserver(
+        &self,
+        vitals: &mut PlayerVitals,
+        command: &ToServerCommand,
+    ) -> Vec<ToClientCommand> {
+        if !vitals.is_dead() {
+            return Vec::new();
+        }
+        match (&self.respawn_ui, command) {
+            (RespawnUi::Deathscreen, ToServerCommand::Respawn(_)) => self.respawn(vitals),
+            (
+                RespawnUi::Formspec {
+                    form_name,
+                    form_spec,
+                },
+                ToServerCommand::InventoryFields(spec),
+            ) if spec.client_formspec_name == *form_name => {
+                if spec.fields.iter().any(|(name, _)| name == RESPAWN_BUTTON) {
+                    let mut out = self.respawn(vitals);
+                    // Make sure the form is closed
+                    out.push(show_formspec(form_name, ""));
+                    out
+                } else {
+                    // Closed some other way, the player is still dead
+                    vec![show_formspec(form_name, form_spec)]
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn respawn(&self, vitals: &mut PlayerVitals) -> Vec<ToClientCommand> {
+        let mut out = vitals.respawn();
+        out.push(
+            MovePlayerSpec {
+                pos: self.respawn_pos,
+                pitch: self.respawn_pitch,
+                yaw: self.respawn_yaw,
+            }
+            .into(),
+        );
+        out
+    }
+}
+
+fn show_formspec(form_name: &str, form_spec: &str) -> ToClientCommand {
+    ShowFormspecSpec {
+        form_spec: form_spec.to_string(),
+        form_name: form_name.to_string(),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::vitals::HpChangeReason;
+    use crate::wire::command::InventoryFieldsSpec;
+    use crate::wire::command::RespawnSpec;
+    use crate::wire::types::InventoryList;
+    use crate::wire::types::ItemStackMetadata;
+
+    fn inventory() -> Inventory {
+        let stack = ItemStack {
+            name: "default:dirt".to_string(),
+            count: 5,
+            wear: 0,
+            metadata: ItemStackMetadata {
+                string_vars: Vec::new(),
+            },
+        };
+        Inventory {
+            entries: vec![InventoryEntry::Update(InventoryList {
+                name: "main".to_string(),
+                width: 0,
+                items: vec![ItemStackUpdate::Item(stack), ItemStackUpdate::Empty],
+            })],
+        }
+    }
+
+    #[test]
+    fn deathscreen_flow() {
+        let mut flow = DeathFlow::new(v3f::new(0.0, 10.0, 0.0));
+        flow.inventory_policy = InventoryPolicy::Drop;
+        let mut vitals = PlayerVitals::default();
+        let mut inv = inventory();
+
+        let killer = v3f::new(1.0, 2.0, 3.0);
+        let out = vitals.damage(100, HpChangeReason::Punch);
+        let outcome = flow.process(out, &mut inv, Some(killer));
+        assert!(outcome.died);
+        assert_eq!(outcome.dropped.len(), 1);
+        assert_eq!(outcome.commands.len(), 3);
+        match &outcome.commands[1] {
+            ToClientCommand::Deathscreen(spec) => {
+                assert!(spec.set_camera_point_target);
+                assert_eq!(spec.camera_point_target, killer);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(outcome.commands[2], ToClientCommand::Inventory(_)));
+        assert_eq!(inv, {
+            let mut empty = inventory();
+            let InventoryEntry::Update(list) = &mut empty.entries[0] else {
+                unreachable!()
+            };
+            list.items[0] = ItemStackUpdate::Empty;
+            empty
+        });
+
+        let out = flow.handle_toserver(&mut vitals, &RespawnSpec {}.into());
+        assert!(!vitals.is_dead());
+        assert!(matches!(out.last(), Some(ToClientCommand::MovePlayer(_))));
+    }
+
+    #[test]
+    fn formspec_flow() {
+        let mut flow = DeathFlow::new(v3f::new(0.0, 0.0, 0.0));
+        flow.respawn_ui = RespawnUi::builtin_formspec();
+        let mut vitals = PlayerVitals::default();
+        let mut inv = inventory();
+
+        let out = vitals.damage(100, HpChangeReason::Punch);
+        let outcome = flow.process(out, &mut inv, None);
+        assert!(matches!(
+            outcome.commands[1],
+            ToClientCommand::ShowFormspec(_)
+        ));
+        // Keep is the default
+        assert_eq!(inv, inventory());
+
+        // The client's Respawn is not accepted in place of the form
+        assert!(flow
+            .handle_toserver(&mut vitals, &RespawnSpec {}.into())
+            .is_empty());
+
+        let fields = |name: &str| -> ToServerCommand {
+            InventoryFieldsSpec {
+                client_formspec_name: DEATH_FORM_NAME.to_string(),
+                fields: vec![(name.to_string(), String::new())],
+            }
+            .into()
+        };
+        let out = flow.handle_toserver(&mut vitals, &fields("quit"));
+        assert!(matches!(out[..], [ToClientCommand::ShowFormspec(_)]));
+        assert!(vitals.is_dead());
+
+        let out = flow.handle_toserver(&mut vitals, &fields(RESPAWN_BUTTON));
+        assert!(!vitals.is_dead());
+        assert!(matches!(out.last(), Some(ToClientCommand::ShowFormspec(_))));
+    }
+}
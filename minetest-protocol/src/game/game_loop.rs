@@ -0,0 +1,292 @@
+//!
+//! Fixed timestep server loop
+//!
+//! The engine steps its server every dedicated_server_step (0.09s by
+//! default): vitals, node timers, entities and the map sender all advance
+//! by the same dtime, in the same order. GameLoop keeps that schedule, and
+//! times each phase:
+//!
+//! ```text
+//! let mut game_loop = GameLoop::new(LoopConfig::default());
+//! loop {
+//!     for step in game_loop.tick().await {
+//!         let vitals_out = game_loop.time("vitals", || vitals.step(step.dtime, head, &body));
+//!         let blocks = game_loop.time("map", || map_sender.poll(step.now));
+//!         ...
+//!     }
+//! }
+//! ```
+//!
+//! When a step runs late (the previous one took too long, or the task
+//! wasn't scheduled), the CatchUp policy decides what happens to the time
+//! that was missed.
+//!
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The engine's default dedicated_server_step
+pub const DEFAULT_STEP: Duration = Duration::from_millis(90);
+
+/// What to do with steps that were missed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatchUp {
+    /// Run the missed steps back to back, each with the usual dtime, but
+    /// no more than `max_steps` at once. The rest are skipped.
+    Burst { max_steps: u32 },
+    /// Run one step with a longer dtime, up to `max_dtime`, like the
+    /// engine does. The rest is skipped.
+    Stretch { max_dtime: Duration },
+    /// Skip them, and carry on from now
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopConfig {
+    pub step: Duration,
+    pub catch_up: CatchUp,
+}
+
+impl Default for LoopConfig {
+    fn default() -> Self {
+        Self {
+            step: DEFAULT_STEP,
+            catch_up: CatchUp::Stretch {
+                max_dtime: Duration::from_millis(500),
+            },
+        }
+    }
+}
+
+/// One step to run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// Seconds since the previous step
+    pub dtime: f32,
+    /// When the step was due
+    pub now: Instant,
+}
+
+/// Time spent in one phase of the step
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PhaseStats {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl PhaseStats {
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => self.total / count as u32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LoopStats {
+    pub steps: u64,
+    /// Steps that ran after the next one was due
+    pub late_steps: u64,
+    /// Time left out by the CatchUp policy
+    pub skipped: Duration,
+    /// By the name given to GameLoop::time
+    pub phases: BTreeMap<&'static str, PhaseStats>,
+}
+
+pub struct GameLoop {
+    config: LoopConfig,
+    /// When the next step is due
+    next: Option<Instant>,
+    stats: LoopStats,
+}
+
+impl GameLoop {
+    pub fn new(config: LoopConfig) -> Self {
+        Self {
+            config,
+            next: None,
+            stats: LoopStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &LoopConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &LoopStats {
+        &self.stats
+    }
+
+    /// When the next step is due. The first one is due right away.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        self.next
+    }
+
+    /// Waits for the next step, and returns the steps to run (see due)
+    pub async fn tick(&mut self) -> Vec<Step> {
+        if let Some(next) = self.next {
+            tokio::time::sleep_until(next.into()).await;
+        }
+        self.due(Instant::now())
+    }
+
+    /// The steps to run at `now`, oldest first. Empty if the next one isn't
+    /// due yet.
+    pub fn due(&mut self, now: Instant) -> Vec<Step> {
+        let step = self.config.step;
+        let Some(next) = self.next else {
+            self.next = Some(now + step);
+            self.stats.steps += 1;
+            return vec![Step {
+                dtime: step.as_secs_f32(),
+                now,
+            }];
+        };
+        if now < next {
+            return Vec::new();
+        }
+        // The steps due since `next`, the one at `next` included
+        let behind = (now - next).as_nanos() / step.as_nanos().max(1);
+        let missed = behind.min(u32::MAX as u128) as u32;
+        let mut steps = Vec::new();
+        match self.config.catch_up {
+            CatchUp::Burst { max_steps } => {
+                let run = (missed + 1).min(max_steps.max(1));
+                for i in 0..run {
+                    steps.push(Step {
+                        dtime: step.as_secs_f32(),
+                        now: next + step * i,
+                    });
+                }
+                self.stats.skipped += step * (missed + 1 - run);
+            }
+            CatchUp::Stretch { max_dtime } => {
+                let elapsed = step + step * missed;
+                let dtime = elapsed.min(max_dtime.max(step));
+                steps.push(Step {
+                    dtime: dtime.as_secs_f32(),
+                    now: next + step * missed,
+                });
+                self.stats.skipped += elapsed - dtime;
+            }
+            CatchUp::Skip => {
+                steps.push(Step {
+                    dtime: step.as_secs_f32(),
+                    now: next + step * missed,
+                });
+                self.stats.skipped += step * missed;
+            }
+        }
+        if missed > 0 {
+            self.stats.late_steps += 1;
+        }
+        self.stats.steps += steps.len() as u64;
+        self.next = Some(next + step * (missed + 1));
+        steps
+    }
+
+    /// Runs `phase`, and adds the time it took to the stats for `name`
+    pub fn time<R>(&mut self, name: &'static str, phase: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = phase();
+        self.record(name, start.elapsed());
+        result
+    }
+
+    /// Adds `took` to the stats for `name`
+    pub fn record(&mut self, name: &'static str, took: Duration) {
+        let stats = self.stats.phases.entry(name).or_default();
+        stats.count += 1;
+        stats.total += took;
+        stats.max = stats.max.max(took);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_loop(catch_up: CatchUp) -> (GameLoop, Instant) {
+        let config = LoopConfig {
+            step: Duration::from_millis(100),
+            catch_up,
+        };
+        (GameLoop::new(config), Instant::now())
+    }
+
+    fn dtimes(steps: &[Step]) -> Vec<u64> {
+        steps
+            .iter()
+            .map(|step| (step.dtime * 1000.0).round() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn on_time() {
+        let (mut game_loop, start) = new_loop(CatchUp::Skip);
+        assert_eq!(dtimes(&game_loop.due(start)), vec![100]);
+        assert!(game_loop.due(start + Duration::from_millis(50)).is_empty());
+        let steps = game_loop.due(start + Duration::from_millis(130));
+        assert_eq!(dtimes(&steps), vec![100]);
+        assert_eq!(steps[0].now, start + Duration::from_millis(100));
+        assert_eq!(
+            game_loop.next_wakeup(),
+            Some(start + Duration::from_millis(200))
+        );
+        assert_eq!(game_loop.stats().steps, 2);
+        assert_eq!(game_loop.stats().late_steps, 0);
+    }
+
+    #[test]
+    fn catching_up() {
+        // 450ms late: the steps at 100, 200, 300, 400 and 500 are due
+        let late = Duration::from_millis(550);
+
+        let (mut game_loop, start) = new_loop(CatchUp::Burst { max_steps: 3 });
+        game_loop.due(start);
+        assert_eq!(dtimes(&game_loop.due(start + late)), vec![100, 100, 100]);
+        assert_eq!(game_loop.stats().skipped, Duration::from_millis(200));
+
+        let (mut game_loop, start) = new_loop(CatchUp::Stretch {
+            max_dtime: Duration::from_millis(400),
+        });
+        game_loop.due(start);
+        assert_eq!(dtimes(&game_loop.due(start + late)), vec![400]);
+        assert_eq!(game_loop.stats().skipped, Duration::from_millis(100));
+
+        let (mut game_loop, start) = new_loop(CatchUp::Skip);
+        game_loop.due(start);
+        assert_eq!(dtimes(&game_loop.due(start + late)), vec![100]);
+        assert_eq!(game_loop.stats().skipped, Duration::from_millis(400));
+        assert_eq!(game_loop.stats().late_steps, 1);
+        // Back on the original schedule
+        assert_eq!(
+            game_loop.next_wakeup(),
+            Some(start + Duration::from_millis(600))
+        );
+    }
+
+    #[test]
+    fn phase_stats() {
+        let (mut game_loop, _) = new_loop(CatchUp::Skip);
+        assert_eq!(game_loop.time("vitals", || 5), 5);
+        game_loop.record("map", Duration::from_millis(4));
+        game_loop.record("map", Duration::from_millis(2));
+        let map = game_loop.stats().phases["map"];
+        assert_eq!(map.count, 2);
+        assert_eq!(map.max, Duration::from_millis(4));
+        assert_eq!(map.mean(), Duration::from_millis(3));
+        assert_eq!(game_loop.stats().phases["vitals"].count, 1);
+    }
+
+    #[tokio::test]
+    async fn ticks() {
+        let (mut game_loop, _) = new_loop(CatchUp::Skip);
+        assert_eq!(game_loop.tick().await.len(), 1);
+        let start = Instant::now();
+        assert_eq!(game_loop.tick().await.len(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(99));
+    }
+}
@@ -0,0 +1,262 @@
+//!
+//! Hotbar and wielded item
+//!
+//! The hotbar is the first `item_count` slots of the player's "main"
+//! inventory list. The client chooses the wielded slot (Playeritem, and the
+//! item_index in every Interact), and the server sets the hotbar size and
+//! images (HudSetParam) and sends the inventory (Inventory).
+//!
+//! Observe both directions of a connection with a Hotbar to know which
+//! ItemStack the player wields when handling an Interact.
+//!
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::wire::command::HudSetParamSpec;
+use crate::wire::command::PlayeritemSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::HudSetParam;
+use crate::wire::types::Inventory;
+use crate::wire::types::InventoryEntry;
+use crate::wire::types::ItemStack;
+use crate::wire::types::ItemStackUpdate;
+
+/// The list the hotbar shows
+pub const WIELD_LIST: &str = "main";
+
+/// HUD_HOTBAR_ITEMCOUNT_DEFAULT
+pub const HOTBAR_ITEMCOUNT_DEFAULT: u16 = 8;
+
+/// HUD_HOTBAR_ITEMCOUNT_MAX
+pub const HOTBAR_ITEMCOUNT_MAX: u16 = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hotbar {
+    item_count: u16,
+    image: String,
+    selected_image: String,
+    wield_index: u16,
+    // The WIELD_LIST, None when the server hasn't sent it (or removed it)
+    items: Option<Vec<Option<ItemStack>>>,
+}
+
+impl Default for Hotbar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hotbar {
+    pub fn new() -> Self {
+        Self {
+            item_count: HOTBAR_ITEMCOUNT_DEFAULT,
+            image: String::new(),
+            selected_image: String::new(),
+            wield_index: 0,
+            items: None,
+        }
+    }
+
+    pub fn item_count(&self) -> u16 {
+        self.item_count
+    }
+
+    pub fn image(&self) -> &str {
+        &self.image
+    }
+
+    pub fn selected_image(&self) -> &str {
+        &self.selected_image
+    }
+
+    /// The wielded slot. Like the engine, the slot chosen by the client
+    /// is clamped to the hotbar size.
+    pub fn wield_index(&self) -> u16 {
+        self.wield_index.min(self.item_count - 1)
+    }
+
+    /// The hotbar slots, as far as they are known.
+    pub fn items(&self) -> &[Option<ItemStack>] {
+        match &self.items {
+            Some(items) => &items[..items.len().min(self.item_count as usize)],
+            None => &[],
+        }
+    }
+
+    /// The wielded ItemStack, or None for an empty hand.
+    pub fn wielded(&self) -> Option<&ItemStack> {
+        self.items
+            .as_ref()?
+            .get(self.wield_index() as usize)?
+            .as_ref()
+    }
+
+    /// Server side: change the hotbar size, returning the command to send.
+    pub fn set_item_count(&mut self, count: u16) -> Result<ToClientCommand> {
+        if count == 0 || count > HOTBAR_ITEMCOUNT_MAX {
+            bail!("Hotbar item count out of range: {}", count);
+        }
+        self.item_count = count;
+        Ok(hud_set_param(HudSetParam::SetHotBarItemCount(count as i32)))
+    }
+
+    /// Server side: change the hotbar background image.
+    pub fn set_image(&mut self, image: &str) -> ToClientCommand {
+        self.image = image.to_string();
+        hud_set_param(HudSetParam::SetHotBarImage(self.image.clone()))
+    }
+
+    /// Server side: change the image drawn around the selected slot.
+    pub fn set_selected_image(&mut self, image: &str) -> ToClientCommand {
+        self.selected_image = image.to_string();
+        hud_set_param(HudSetParam::SetHotBarSelectedImage(
+            self.selected_image.clone(),
+        ))
+    }
+
+    /// Client side: select a slot, returning the command to send.
+    pub fn select(&mut self, index: u16) -> ToServerCommand {
+        self.wield_index = index;
+        PlayeritemSpec { item: index }.into()
+    }
+
+    /// Update from a command sent to the client.
+    pub fn observe_toclient(&mut self, command: &ToClientCommand) {
+        match command {
+            ToClientCommand::HudSetParam(spec) => match &spec.value {
+                HudSetParam::SetHotBarItemCount(count) => {
+                    // The client ignores counts out of range
+                    if *count > 0 && *count <= HOTBAR_ITEMCOUNT_MAX as i32 {
+                        self.item_count = *count as u16;
+                    }
+                }
+                HudSetParam::SetHotBarImage(image) => self.image = image.clone(),
+                HudSetParam::SetHotBarSelectedImage(image) => self.selected_image = image.clone(),
+            },
+            ToClientCommand::Inventory(spec) => self.apply_inventory(&spec.inventory),
+            _ => (),
+        }
+    }
+
+    /// Update from a command sent to the server.
+    pub fn observe_toserver(&mut self, command: &ToServerCommand) {
+        match command {
+            ToServerCommand::Playeritem(spec) => self.wield_index = spec.item,
+            // Interacting also selects the slot it was done with
+            ToServerCommand::Interact(spec) => self.wield_index = spec.item_index,
+            _ => (),
+        }
+    }
+
+    /// An Inventory replaces the lists it contains, keeps the lists it
+    /// names with KeepList, and removes every other list.
+    fn apply_inventory(&mut self, inventory: &Inventory) {
+        let mut items = None;
+        for entry in &inventory.entries {
+            match entry {
+                InventoryEntry::KeepList(name) if name == WIELD_LIST => {
+                    items = self.items.take();
+                }
+                InventoryEntry::Update(list) if list.name == WIELD_LIST => {
+                    let old = self.items.take().unwrap_or_default();
+                    let new = list
+                        .items
+                        .iter()
+                        .enumerate()
+                        .map(|(i, update)| match update {
+                            ItemStackUpdate::Empty => None,
+                            ItemStackUpdate::Keep => old.get(i).cloned().flatten(),
+                            ItemStackUpdate::Item(stack) => Some(stack.clone()),
+                        })
+                        .collect();
+                    items = Some(new);
+                }
+                _ => (),
+            }
+        }
+        self.items = items;
+    }
+}
+
+fn hud_set_param(value: HudSetParam) -> ToClientCommand {
+    HudSetParamSpec { value }.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::InventorySpec;
+    use crate::wire::types::InventoryList;
+    use crate::wire::types::ItemStackMetadata;
+
+    fn stack(name: &str) -> ItemStack {
+        ItemStack {
+            name: name.to_string(),
+            count: 1,
+            wear: 0,
+            metadata: ItemStackMetadata {
+                string_vars: Vec::new(),
+            },
+        }
+    }
+
+    fn inventory(entries: Vec<InventoryEntry>) -> ToClientCommand {
+        InventorySpec {
+            inventory: Inventory { entries },
+        }
+        .into()
+    }
+
+    fn main_list(items: Vec<ItemStackUpdate>) -> InventoryEntry {
+        InventoryEntry::Update(InventoryList {
+            name: WIELD_LIST.to_string(),
+            width: 0,
+            items,
+        })
+    }
+
+    #[test]
+    fn tracks_wielded_item() {
+        let mut server = Hotbar::new();
+        let mut client = Hotbar::new();
+        assert_eq!(client.wielded(), None);
+
+        let update = inventory(vec![main_list(vec![
+            ItemStackUpdate::Item(stack("default:pick_steel")),
+            ItemStackUpdate::Empty,
+            ItemStackUpdate::Item(stack("default:torch")),
+        ])]);
+        server.observe_toclient(&update);
+        client.observe_toclient(&update);
+
+        let select = client.select(2);
+        server.observe_toserver(&select);
+        assert_eq!(server.wielded(), Some(&stack("default:torch")));
+        assert_eq!(server, client);
+
+        // Keep leaves the slot alone, KeepList the whole list
+        server.observe_toclient(&inventory(vec![main_list(vec![
+            ItemStackUpdate::Empty,
+            ItemStackUpdate::Empty,
+            ItemStackUpdate::Keep,
+        ])]));
+        assert_eq!(server.wielded(), Some(&stack("default:torch")));
+        server.observe_toclient(&inventory(vec![InventoryEntry::KeepList(
+            WIELD_LIST.to_string(),
+        )]));
+        assert_eq!(server.wielded(), Some(&stack("default:torch")));
+
+        // Shrinking the hotbar clamps the wield index
+        let resize = server.set_item_count(1).unwrap();
+        client.observe_toclient(&resize);
+        assert_eq!(client.wield_index(), 0);
+        assert_eq!(client.wielded(), Some(&stack("default:pick_steel")));
+        assert_eq!(client.items().len(), 1);
+        assert!(server.set_item_count(0).is_err());
+
+        // Lists not mentioned are removed
+        client.observe_toclient(&inventory(Vec::new()));
+        assert_eq!(client.wielded(), None);
+    }
+}
@@ -0,0 +1,334 @@
+//!
+//! Map block sending, paced per client
+//!
+//! The client acknowledges every TOCLIENT_BLOCKDATA it receives with
+//! TOSERVER_GOTBLOCKS. The engine limits the blocks a client has in transit
+//! (max_simultaneous_block_sends_per_client), so a laggy client is simply
+//! sent less. MapSender does the same, and also adapts a blocks per second
+//! rate:
+//!
+//! - Each ack measures how long the block took to be acknowledged. The
+//!   rate grows while this is below `target_lag`, and is cut when it is
+//!   above, or when a block is never acknowledged.
+//! - While the map channel is congested (see peer::congestion), nothing is
+//!   sent and the rate is cut once.
+//!
+//! Blocks queued for the same position replace each other, so a changing
+//! block is only sent once it is its turn.
+//!
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::peer::congestion::CongestionEvent;
+use crate::peer::peer::ChannelNum;
+use crate::wire::command::BlockdataSpec;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::v3s16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapSenderConfig {
+    /// Blocks per second
+    pub start_rate: f32,
+    pub min_rate: f32,
+    pub max_rate: f32,
+    /// Blocks sent but not acknowledged (like the engine's default)
+    pub max_in_flight: usize,
+    /// Ack lag above which the rate is cut
+    pub target_lag: Duration,
+    /// A block not acknowledged in this long is counted as lost
+    pub ack_timeout: Duration,
+}
+
+impl Default for MapSenderConfig {
+    fn default() -> Self {
+        Self {
+            start_rate: 40.0,
+            min_rate: 2.0,
+            max_rate: 400.0,
+            max_in_flight: 40,
+            target_lag: Duration::from_millis(500),
+            ack_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The channel Blockdata is sent on
+const MAP_CHANNEL: ChannelNum = 2;
+
+/// Added to the rate for each quick ack, in blocks per second
+const RATE_INCREASE: f32 = 1.0;
+/// The rate is multiplied by this when cut
+const RATE_DECREASE: f32 = 0.75;
+/// Weight of a new lag sample in the smoothed lag
+const LAG_GAIN: f64 = 0.125;
+
+#[derive(Debug, Clone)]
+pub struct MapSender {
+    config: MapSenderConfig,
+    rate: f32,
+    // Blocks that may be sent, with the fraction of one not yet used
+    allowance: f32,
+    last_update: Option<Instant>,
+    queue: VecDeque<BlockdataSpec>,
+    in_flight: HashMap<v3s16, Instant>,
+    lag: Option<Duration>,
+    congested: bool,
+    // Don't cut the rate for every late ack of the same burst
+    last_cut: Option<Instant>,
+    lost: u64,
+}
+
+impl MapSender {
+    pub fn new(config: MapSenderConfig) -> Self {
+        Self {
+            rate: config.start_rate,
+            config,
+            allowance: 1.0,
+            last_update: None,
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            lag: None,
+            congested: false,
+            last_cut: None,
+            lost: 0,
+        }
+    }
+
+    /// Current rate, in blocks per second
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    /// Smoothed ack lag
+    pub fn lag(&self) -> Option<Duration> {
+        self.lag
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Blocks never acknowledged
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    pub fn is_congested(&self) -> bool {
+        self.congested
+    }
+
+    /// Queues a block, replacing any queued block at the same position
+    pub fn push(&mut self, block: BlockdataSpec) {
+        match self.queue.iter_mut().find(|queued| queued.pos == block.pos) {
+            Some(queued) => *queued = block,
+            None => self.queue.push_back(block),
+        }
+    }
+
+    /// Commands from the client. Gotblocks acknowledges blocks sent.
+    pub fn observe_toserver(&mut self, command: &ToServerCommand, now: Instant) {
+        if let Some(spec) = command.as_gotblocks() {
+            for pos in &spec.blocks {
+                if let Some(sent) = self.in_flight.remove(pos) {
+                    self.sample_lag(now.saturating_duration_since(sent), now);
+                }
+            }
+        }
+    }
+
+    /// Congestion events from the connection (only the map's channel counts)
+    pub fn observe_congestion(&mut self, event: &CongestionEvent, now: Instant) {
+        match *event {
+            CongestionEvent::Congested { channel, .. }
+                if channel == MAP_CHANNEL && !self.congested =>
+            {
+                self.congested = true;
+                self.cut(now);
+            }
+            CongestionEvent::Recovered { channel, .. } if channel == MAP_CHANNEL => {
+                self.congested = false;
+            }
+            _ => (),
+        }
+    }
+
+    /// The blocks to send now. Call again at `next_wakeup`.
+    pub fn poll(&mut self, now: Instant) -> Vec<BlockdataSpec> {
+        self.expire(now);
+        if let Some(last) = self.last_update {
+            let elapsed = now.saturating_duration_since(last).as_secs_f32();
+            // Allow a short burst, but don't save up for later
+            let burst = (self.rate / 10.0).max(1.0);
+            self.allowance = (self.allowance + elapsed * self.rate).min(burst);
+        }
+        self.last_update = Some(now);
+
+        let mut out = Vec::new();
+        while self.allowance >= 1.0 && self.can_send() {
+            let Some(block) = self.queue.pop_front() else {
+                break;
+            };
+            self.allowance -= 1.0;
+            self.in_flight.insert(block.pos.clone(), now);
+            out.push(block);
+        }
+        out
+    }
+
+    /// When poll may have blocks to send, if ever without an ack or event
+    pub fn next_wakeup(&self, now: Instant) -> Option<Instant> {
+        if self.queue.is_empty() || self.congested {
+            return None;
+        }
+        // Blocks in flight time out eventually
+        if self.in_flight.len() >= self.config.max_in_flight {
+            return self
+                .in_flight
+                .values()
+                .min()
+                .map(|&sent| sent + self.config.ack_timeout);
+        }
+        let wait = ((1.0 - self.allowance) / self.rate).max(0.0);
+        Some(now + Duration::from_secs_f32(wait))
+    }
+
+    fn can_send(&self) -> bool {
+        !self.congested && self.in_flight.len() < self.config.max_in_flight
+    }
+
+    fn sample_lag(&mut self, lag: Duration, now: Instant) {
+        let smoothed = match self.lag {
+            Some(old) => old.mul_f64(1.0 - LAG_GAIN) + lag.mul_f64(LAG_GAIN),
+            None => lag,
+        };
+        self.lag = Some(smoothed);
+        if smoothed > self.config.target_lag {
+            self.cut(now);
+        } else if !self.congested {
+            self.rate = (self.rate + RATE_INCREASE).min(self.config.max_rate);
+        }
+    }
+
+    /// Cuts the rate, at most once per target_lag
+    fn cut(&mut self, now: Instant) {
+        if let Some(last) = self.last_cut {
+            if now.saturating_duration_since(last) < self.config.target_lag {
+                return;
+            }
+        }
+        self.last_cut = Some(now);
+        self.rate = (self.rate * RATE_DECREASE).max(self.config.min_rate);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.config.ack_timeout;
+        let before = self.in_flight.len();
+        self.in_flight
+            .retain(|_, sent| now.saturating_duration_since(*sent) < timeout);
+        let expired = before - self.in_flight.len();
+        if expired > 0 {
+            self.lost += expired as u64;
+            self.cut(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::congestion::QueueKind;
+    use crate::wire::command::GotblocksSpec;
+    use crate::wire::types::MapBlockBuf;
+
+    fn block(x: i16) -> BlockdataSpec {
+        BlockdataSpec {
+            pos: v3s16::new(x, 0, 0),
+            block: MapBlockBuf::new().to_map_block(),
+            network_specific_version: 2,
+        }
+    }
+
+    fn gotblocks(blocks: &[BlockdataSpec]) -> ToServerCommand {
+        GotblocksSpec {
+            blocks: blocks.iter().map(|b| b.pos.clone()).collect(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn adapts_to_ack_lag() {
+        let start = Instant::now();
+        let mut sender = MapSender::new(MapSenderConfig::default());
+        for x in 0..1000 {
+            sender.push(block(x));
+        }
+        // Replaces the queued block
+        sender.push(block(0));
+        assert_eq!(sender.queued(), 1000);
+
+        let first = sender.poll(start);
+        assert_eq!(first.len(), 1);
+        // 40 blocks per second, in bursts of up to 4
+        let later = start + Duration::from_millis(100);
+        let sent = sender.poll(later);
+        assert_eq!(sent.len(), 4);
+        assert_eq!(sender.in_flight(), 5);
+
+        // Quick acks speed it up
+        sender.observe_toserver(&gotblocks(&first), start + Duration::from_millis(50));
+        sender.observe_toserver(&gotblocks(&sent), later + Duration::from_millis(50));
+        assert_eq!(sender.rate(), 45.0);
+        assert_eq!(sender.in_flight(), 0);
+
+        // A slow one cuts the rate
+        let now = later + Duration::from_millis(200);
+        let sent = sender.poll(now);
+        sender.observe_toserver(&gotblocks(&sent), now + Duration::from_secs(5));
+        assert!(sender.lag().unwrap() > Duration::from_millis(500));
+        assert!(sender.rate() < 45.0);
+    }
+
+    #[test]
+    fn pauses_while_congested() {
+        let start = Instant::now();
+        let config = MapSenderConfig {
+            max_in_flight: 2,
+            ..Default::default()
+        };
+        let mut sender = MapSender::new(config);
+        for x in 0..10 {
+            sender.push(block(x));
+        }
+        let congested = CongestionEvent::Congested {
+            channel: 2,
+            kind: QueueKind::Unacked,
+            depth: 1000,
+        };
+        sender.observe_congestion(&congested, start);
+        assert!(sender.poll(start).is_empty());
+        assert_eq!(sender.next_wakeup(start), None);
+        assert_eq!(sender.rate(), 30.0);
+
+        let recovered = CongestionEvent::Recovered {
+            channel: 2,
+            kind: QueueKind::Unacked,
+            depth: 10,
+        };
+        sender.observe_congestion(&recovered, start);
+        let now = start + Duration::from_secs(1);
+        // Limited by the blocks in flight
+        assert_eq!(sender.poll(now).len(), 2);
+        let wakeup = sender.next_wakeup(now).unwrap();
+        assert_eq!(wakeup, now + Duration::from_secs(10));
+        // Never acknowledged, so the next two go out at a lower rate
+        assert_eq!(sender.poll(wakeup).len(), 2);
+        assert_eq!(sender.lost(), 2);
+        assert_eq!(sender.rate(), 22.5);
+    }
+}
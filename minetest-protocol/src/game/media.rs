@@ -0,0 +1,490 @@
+//!
+//! Media (textures, sounds, models)
+//!
+//! At join, the server announces every media file by name and SHA1
+//! (TOCLIENT_ANNOUNCE_MEDIA). The client loads what it has cached, and
+//! requests the rest (TOSERVER_REQUEST_MEDIA), which the server sends in
+//! bunches (TOCLIENT_MEDIA).
+//!
+//! The announcement always lists every file: the client only loads
+//! announced media, even when it has the rest cached. What can be saved on
+//! reconnect is the sending. MediaHistory remembers, per player, the
+//! version of each file the client has been sent. `diff` reports what
+//! changed since, and `serve_for` doesn't send the same version of a file
+//! twice in one session, so a client re-requesting everything only
+//! receives it once.
+//!
+//! Files added or changed while clients are connected can be sent with
+//! TOCLIENT_MEDIA_PUSH (`push`). With the "watch" feature, media_watch
+//! finds such changes on disk.
+//!
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::wire::command::AnnounceMediaSpec;
+use crate::wire::command::MediaPushSpec;
+use crate::wire::command::MediaSpec;
+use crate::wire::command::RequestMediaSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::types::MediaAnnouncement;
+use crate::wire::types::MediaFileData;
+
+/// Files are packed into Media commands of about this size (like the engine)
+pub const BYTES_PER_BUNCH: usize = 5000;
+
+/// Extensions the engine loads as media
+pub const MEDIA_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "bmp", "tga", "ogg", "x", "b3d", "obj", "gltf", "glb", "tr", "txt",
+];
+
+pub type Sha1 = [u8; 20];
+
+pub fn is_media_file(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) => {
+            !stem.is_empty() && MEDIA_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+        }
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaChangeKind {
+    Added,
+    Changed,
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaChange {
+    pub name: String,
+    pub kind: MediaChangeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaFile {
+    pub name: String,
+    pub data: Arc<[u8]>,
+    pub sha1: Sha1,
+}
+
+impl MediaFile {
+    pub fn new(name: &str, data: Vec<u8>) -> Self {
+        let sha1 = sha1_smol::Sha1::from(&data).digest().bytes();
+        Self {
+            name: name.to_string(),
+            data: data.into(),
+            sha1,
+        }
+    }
+
+    pub fn sha1_base64(&self) -> String {
+        base64(&self.sha1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MediaServer {
+    files: BTreeMap<String, MediaFile>,
+    /// Space separated HTTP media server URLs, announced to clients
+    pub remote_servers: String,
+}
+
+impl MediaServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a file
+    pub fn add_file(&mut self, name: &str, data: Vec<u8>) -> &MediaFile {
+        self.files
+            .insert(name.to_string(), MediaFile::new(name, data));
+        &self.files[name]
+    }
+
+    pub fn remove_file(&mut self, name: &str) -> Option<MediaFile> {
+        self.files.remove(name)
+    }
+
+    pub fn file(&self, name: &str) -> Option<&MediaFile> {
+        self.files.get(name)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &MediaFile> {
+        self.files.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Add every media file under `dir` (recursively). Media names are
+    /// file names, so files with the same name replace each other.
+    /// Returns the number of files added.
+    pub fn load_dir(&mut self, dir: &Path) -> io::Result<usize> {
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                count += self.load_dir(&path)?;
+            } else if let Some(name) = media_name(&path) {
+                self.add_file(&name, std::fs::read(&path)?);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Re-read the file at `path` after it changed on disk.
+    /// Returns None if it isn't media, or is unchanged.
+    pub fn refresh_path(&mut self, path: &Path) -> io::Result<Option<MediaChange>> {
+        let Some(name) = media_name(path) else {
+            return Ok(None);
+        };
+        let kind = if path.is_file() {
+            let file = MediaFile::new(&name, std::fs::read(path)?);
+            let kind = match self.files.get(&name) {
+                Some(old) if old.sha1 == file.sha1 => return Ok(None),
+                Some(_) => MediaChangeKind::Changed,
+                None => MediaChangeKind::Added,
+            };
+            self.files.insert(name.clone(), file);
+            kind
+        } else if self.files.remove(&name).is_some() {
+            MediaChangeKind::Removed
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(MediaChange { name, kind }))
+    }
+
+    /// Offer a file to a connected client (protocol 40+). The client loads
+    /// it from its cache, or requests it, then answers with HaveMedia.
+    /// `cached` lets the client keep the file in its media cache.
+    /// MediaPushTracker allocates the tokens.
+    pub fn push(&self, name: &str, token: u32, cached: bool) -> Option<ToClientCommand> {
+        let file = self.files.get(name)?;
+        Some(
+            MediaPushSpec {
+                raw_hash: file.sha1.to_vec(),
+                filename: file.name.clone(),
+                cached,
+                token,
+            }
+            .into(),
+        )
+    }
+
+    pub fn announce(&self) -> ToClientCommand {
+        AnnounceMediaSpec {
+            files: self
+                .files
+                .values()
+                .map(|file| MediaAnnouncement {
+                    name: file.name.clone(),
+                    sha1_base64: file.sha1_base64(),
+                })
+                .collect(),
+            remote_servers: self.remote_servers.clone(),
+        }
+        .into()
+    }
+
+    /// The Media commands answering a request. Unknown files are skipped.
+    pub fn serve(&self, request: &RequestMediaSpec) -> Vec<ToClientCommand> {
+        let files: Vec<&MediaFile> = request
+            .files
+            .iter()
+            .filter_map(|name| self.files.get(name))
+            .collect();
+        bunch(&files)
+    }
+
+    /// Like `serve`, but skips files already sent to this client, in this
+    /// version, during the current session. Records what was sent.
+    pub fn serve_for(
+        &self,
+        history: &mut MediaHistory,
+        request: &RequestMediaSpec,
+    ) -> Vec<ToClientCommand> {
+        let mut files = Vec::new();
+        for name in &request.files {
+            let Some(file) = self.files.get(name) else {
+                continue;
+            };
+            if history.sent_this_session(file) {
+                continue;
+            }
+            history.record(file);
+            files.push(file);
+        }
+        bunch(&files)
+    }
+
+    /// What changed since the client last received media
+    pub fn diff(&self, history: &MediaHistory) -> MediaDiff {
+        let mut diff = MediaDiff::default();
+        for file in self.files.values() {
+            match history.sent.get(&file.name) {
+                Some(sha1) if *sha1 == file.sha1 => diff.unchanged.push(file.name.clone()),
+                Some(_) => diff.changed.push(file.name.clone()),
+                None => diff.added.push(file.name.clone()),
+            }
+        }
+        for name in history.sent.keys() {
+            if !self.files.contains_key(name) {
+                diff.removed.push(name.clone());
+            }
+        }
+        diff.removed.sort();
+        diff
+    }
+}
+
+fn media_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    is_media_file(name).then(|| name.to_string())
+}
+
+fn bunch(files: &[&MediaFile]) -> Vec<ToClientCommand> {
+    let mut bunches: Vec<Vec<MediaFileData>> = Vec::new();
+    let mut size = BYTES_PER_BUNCH;
+    for file in files {
+        if size >= BYTES_PER_BUNCH {
+            bunches.push(Vec::new());
+            size = 0;
+        }
+        size += file.data.len();
+        bunches.last_mut().unwrap().push(MediaFileData {
+            name: file.name.clone(),
+            data: file.data.to_vec(),
+        });
+    }
+    let num_bunches = bunches.len() as u16;
+    bunches
+        .into_iter()
+        .enumerate()
+        .map(|(index, files)| {
+            MediaSpec {
+                num_bunches,
+                bunch_index: index as u16,
+                files,
+            }
+            .into()
+        })
+        .collect()
+}
+
+/// Files in each category are sorted by name
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MediaDiff {
+    pub unchanged: Vec<String>,
+    pub changed: Vec<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl MediaDiff {
+    /// Files the client will need to download (if not cached otherwise)
+    pub fn needed(&self) -> impl Iterator<Item = &String> {
+        self.changed.iter().chain(self.added.iter())
+    }
+}
+
+/// The media one client has been sent, kept by the server across sessions
+/// (e.g. keyed by player name).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MediaHistory {
+    sent: HashMap<String, Sha1>,
+    this_session: HashSet<String>,
+}
+
+impl MediaHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call when the client (re)connects
+    pub fn start_session(&mut self) {
+        self.this_session.clear();
+    }
+
+    /// The version of `name` the client was last sent
+    pub fn sent(&self, name: &str) -> Option<&Sha1> {
+        self.sent.get(name)
+    }
+
+    pub fn record(&mut self, file: &MediaFile) {
+        self.sent.insert(file.name.clone(), file.sha1);
+        self.this_session.insert(file.name.clone());
+    }
+
+    fn sent_this_session(&self, file: &MediaFile) -> bool {
+        self.this_session.contains(&file.name) && self.sent.get(&file.name) == Some(&file.sha1)
+    }
+}
+
+/// Standard base64, with padding
+pub(crate) fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Inverse of base64. None if `text` isn't standard base64.
+pub(crate) fn unbase64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut n: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        n = n << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(names: &[&str]) -> RequestMediaSpec {
+        RequestMediaSpec {
+            files: names.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn served(commands: &[ToClientCommand]) -> Vec<String> {
+        let mut names = Vec::new();
+        for command in commands {
+            let ToClientCommand::Media(spec) = command else {
+                panic!("expected Media");
+            };
+            names.extend(spec.files.iter().map(|f| f.name.clone()));
+        }
+        names
+    }
+
+    #[test]
+    fn announce_and_serve() {
+        let mut media = MediaServer::new();
+        let file = media.add_file("a.png", b"abc".to_vec());
+        assert_eq!(file.sha1_base64(), "qZk+NkcGgWq6PiVxeFDCbJzQ2J0=");
+        media.add_file("b.ogg", vec![0; BYTES_PER_BUNCH]);
+        media.add_file("c.obj", vec![1; 10]);
+
+        let ToClientCommand::AnnounceMedia(spec) = media.announce() else {
+            panic!();
+        };
+        assert_eq!(spec.files.len(), 3);
+
+        let out = media.serve(&request(&["b.ogg", "missing.png", "c.obj", "a.png"]));
+        // b.ogg fills a bunch by itself
+        assert_eq!(out.len(), 2);
+        assert_eq!(served(&out), vec!["b.ogg", "c.obj", "a.png"]);
+    }
+
+    #[test]
+    fn reconnect_diff() {
+        let mut media = MediaServer::new();
+        media.add_file("a.png", b"a".to_vec());
+        media.add_file("b.png", b"b".to_vec());
+        media.add_file("gone.png", b"x".to_vec());
+        let mut history = MediaHistory::new();
+        history.start_session();
+        let out = media.serve_for(&mut history, &request(&["a.png", "b.png", "gone.png"]));
+        assert_eq!(served(&out).len(), 3);
+        // Re-requesting in the same session sends nothing
+        assert!(media
+            .serve_for(&mut history, &request(&["a.png", "b.png"]))
+            .is_empty());
+
+        media.add_file("b.png", b"b2".to_vec());
+        media.add_file("c.png", b"c".to_vec());
+        media.remove_file("gone.png");
+        let diff = media.diff(&history);
+        assert_eq!(diff.unchanged, vec!["a.png"]);
+        assert_eq!(diff.changed, vec!["b.png"]);
+        assert_eq!(diff.added, vec!["c.png"]);
+        assert_eq!(diff.removed, vec!["gone.png"]);
+
+        // A new session serves whatever the client is missing
+        history.start_session();
+        let out = media.serve_for(&mut history, &request(&["a.png", "b.png"]));
+        assert_eq!(served(&out), vec!["a.png", "b.png"]);
+    }
+
+    #[test]
+    fn load_and_refresh() {
+        let dir = std::env::temp_dir().join(format!("mt-media-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("textures")).unwrap();
+        let texture = dir.join("textures").join("a.png");
+        std::fs::write(&texture, b"one").unwrap();
+        std::fs::write(dir.join("README"), b"not media").unwrap();
+
+        let mut media = MediaServer::new();
+        assert_eq!(media.load_dir(&dir).unwrap(), 1);
+        assert_eq!(media.refresh_path(&texture).unwrap(), None);
+
+        std::fs::write(&texture, b"two").unwrap();
+        let change = media.refresh_path(&texture).unwrap().unwrap();
+        assert_eq!(change.kind, MediaChangeKind::Changed);
+        let Some(ToClientCommand::MediaPush(spec)) = media.push("a.png", 7, true) else {
+            panic!();
+        };
+        assert_eq!(spec.raw_hash, media.file("a.png").unwrap().sha1.to_vec());
+
+        std::fs::remove_file(&texture).unwrap();
+        let change = media.refresh_path(&texture).unwrap().unwrap();
+        assert_eq!(change.kind, MediaChangeKind::Removed);
+        assert!(media.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        for data in [&b""[..], b"f", b"fo", b"foo", b"\xff\x00\x80\x7f"] {
+            assert_eq!(unbase64(&base64(data)).as_deref(), Some(data));
+        }
+        assert_eq!(unbase64("Zm9v!"), None);
+    }
+}
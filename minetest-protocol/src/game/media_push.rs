@@ -0,0 +1,223 @@
+//!
+//! Dynamic media delivery
+//!
+//! A TOCLIENT_MEDIA_PUSH carries a token. Once the client has the file
+//! (from its cache, or by requesting it), it answers with TOSERVER_HAVE_MEDIA
+//! listing the tokens it is done with. MediaPushTracker allocates tokens for
+//! one client, tracks the outstanding ones, and re-sends pushes that go
+//! unanswered, so callers deal in file names and delivery events instead.
+//!
+//! Ephemeral files are pushed with the cached flag clear: the client uses
+//! them for this session only, and doesn't store them in its media cache.
+//!
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::wire::command::HaveMediaSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+
+use super::media::MediaServer;
+
+/// How long to wait for HaveMedia before pushing again
+pub const PUSH_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Pushes before giving up
+pub const PUSH_MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingPush {
+    pub token: u32,
+    pub filename: String,
+    pub ephemeral: bool,
+    pub attempts: u32,
+    pub last_sent: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MediaPushEvent {
+    /// The client has the file
+    Delivered { token: u32, filename: String },
+    /// No answer after PUSH_MAX_ATTEMPTS, or the file was removed
+    Failed { token: u32, filename: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaPushTracker {
+    next_token: u32,
+    pending: BTreeMap<u32, PendingPush>,
+    pub retry_after: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for MediaPushTracker {
+    fn default() -> Self {
+        Self {
+            next_token: 1,
+            pending: BTreeMap::new(),
+            retry_after: PUSH_RETRY_AFTER,
+            max_attempts: PUSH_MAX_ATTEMPTS,
+        }
+    }
+}
+
+impl MediaPushTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tokens waiting for HaveMedia
+    pub fn outstanding(&self) -> impl Iterator<Item = &PendingPush> {
+        self.pending.values()
+    }
+
+    pub fn is_pending(&self, token: u32) -> bool {
+        self.pending.contains_key(&token)
+    }
+
+    /// Push `name` from `media`. Returns the token and the command, or
+    /// None if there is no such file.
+    pub fn push(
+        &mut self,
+        media: &MediaServer,
+        name: &str,
+        ephemeral: bool,
+        now: Instant,
+    ) -> Option<(u32, ToClientCommand)> {
+        let token = self.next_token;
+        let command = media.push(name, token, !ephemeral)?;
+        // 0 is never used
+        self.next_token = self.next_token.checked_add(1).unwrap_or(1);
+        self.pending.insert(
+            token,
+            PendingPush {
+                token,
+                filename: name.to_string(),
+                ephemeral,
+                attempts: 1,
+                last_sent: now,
+            },
+        );
+        Some((token, command))
+    }
+
+    /// Unknown or repeated tokens are ignored.
+    pub fn handle_have_media(&mut self, spec: &HaveMediaSpec) -> Vec<MediaPushEvent> {
+        spec.tokens
+            .iter()
+            .filter_map(|token| self.pending.remove(token))
+            .map(|push| MediaPushEvent::Delivered {
+                token: push.token,
+                filename: push.filename,
+            })
+            .collect()
+    }
+
+    pub fn handle_toserver(&mut self, command: &ToServerCommand) -> Vec<MediaPushEvent> {
+        match command {
+            ToServerCommand::HaveMedia(spec) => self.handle_have_media(spec),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Re-send unanswered pushes, and give up on those out of attempts.
+    /// Re-sent pushes keep their token, but carry the file's current hash.
+    pub fn step(
+        &mut self,
+        media: &MediaServer,
+        now: Instant,
+    ) -> (Vec<ToClientCommand>, Vec<MediaPushEvent>) {
+        let mut commands = Vec::new();
+        let mut failed = Vec::new();
+        for push in self.pending.values_mut() {
+            if now.saturating_duration_since(push.last_sent) < self.retry_after {
+                continue;
+            }
+            let command = if push.attempts < self.max_attempts {
+                media.push(&push.filename, push.token, !push.ephemeral)
+            } else {
+                None
+            };
+            match command {
+                Some(command) => {
+                    push.attempts += 1;
+                    push.last_sent = now;
+                    commands.push(command);
+                }
+                None => failed.push(push.token),
+            }
+        }
+        let events = failed
+            .into_iter()
+            .filter_map(|token| self.pending.remove(&token))
+            .map(|push| MediaPushEvent::Failed {
+                token: push.token,
+                filename: push.filename,
+            })
+            .collect();
+        (commands, events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_lifecycle() {
+        let mut media = MediaServer::new();
+        media.add_file("a.png", b"a".to_vec());
+        media.add_file("b.png", b"b".to_vec());
+        let mut tracker = MediaPushTracker::new();
+        let start = Instant::now();
+
+        assert!(tracker.push(&media, "missing.png", false, start).is_none());
+        let (a, command) = tracker.push(&media, "a.png", false, start).unwrap();
+        let ToClientCommand::MediaPush(spec) = command else {
+            panic!();
+        };
+        assert!(spec.cached);
+        let (b, command) = tracker.push(&media, "b.png", true, start).unwrap();
+        let ToClientCommand::MediaPush(spec) = command else {
+            panic!();
+        };
+        assert!(!spec.cached);
+        assert_ne!(a, b);
+
+        let have: ToServerCommand = HaveMediaSpec {
+            tokens: vec![a, 99],
+        }
+        .into();
+        assert_eq!(
+            tracker.handle_toserver(&have),
+            vec![MediaPushEvent::Delivered {
+                token: a,
+                filename: "a.png".to_string()
+            }]
+        );
+        assert!(tracker.handle_toserver(&have).is_empty());
+
+        // b goes unanswered
+        let (commands, events) = tracker.step(&media, start + Duration::from_secs(1));
+        assert!(commands.is_empty() && events.is_empty());
+        let mut now = start;
+        for _ in 1..PUSH_MAX_ATTEMPTS {
+            now += PUSH_RETRY_AFTER;
+            let (commands, events) = tracker.step(&media, now);
+            assert_eq!(commands.len(), 1);
+            assert!(events.is_empty());
+        }
+        now += PUSH_RETRY_AFTER;
+        let (commands, events) = tracker.step(&media, now);
+        assert!(commands.is_empty());
+        assert_eq!(
+            events,
+            vec![MediaPushEvent::Failed {
+                token: b,
+                filename: "b.png".to_string()
+            }]
+        );
+        assert_eq!(tracker.outstanding().count(), 0);
+    }
+}
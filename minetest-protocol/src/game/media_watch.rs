@@ -0,0 +1,80 @@
+//!
+//! Live media reload (feature "watch")
+//!
+//! MediaWatcher watches media directories and applies changes on disk to a
+//! MediaServer. Added and changed files can then be pushed to connected
+//! clients, so textures, models and sounds can be iterated on without
+//! restarting:
+//!
+//! ```text
+//! for change in watcher.poll(&mut media) {
+//!     if change.kind != MediaChangeKind::Removed {
+//!         // for each client: tracker.push(&media, &change.name, false, now)
+//!     }
+//! }
+//! ```
+//!
+//! Clients keep removed files until they reconnect.
+//!
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+
+use super::media::MediaChange;
+use super::media::MediaServer;
+
+pub struct MediaWatcher {
+    watcher: RecommendedWatcher,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+}
+
+impl MediaWatcher {
+    pub fn new() -> notify::Result<Self> {
+        let (tx, events) = mpsc::channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })?;
+        Ok(Self { watcher, events })
+    }
+
+    /// Watch `dir` and everything under it
+    pub fn watch(&mut self, dir: &Path) -> notify::Result<()> {
+        self.watcher.watch(dir, RecursiveMode::Recursive)
+    }
+
+    pub fn unwatch(&mut self, dir: &Path) -> notify::Result<()> {
+        self.watcher.unwatch(dir)
+    }
+
+    /// Apply the changes seen since the last poll to `media`, returning
+    /// them. Doesn't block. Files that can't be read are reported and
+    /// skipped (they are usually still being written, and will change again).
+    pub fn poll(&mut self, media: &mut MediaServer) -> Vec<MediaChange> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Ok(event) => {
+                    for path in event.paths {
+                        if !paths.contains(&path) {
+                            paths.push(path);
+                        }
+                    }
+                }
+                Err(err) => eprintln!("Media watcher error: {:?}", err),
+            }
+        }
+        let mut changes = Vec::new();
+        for path in paths {
+            match media.refresh_path(&path) {
+                Ok(Some(change)) => changes.push(change),
+                Ok(None) => (),
+                Err(err) => eprintln!("Cannot reload {:?}: {}", path, err),
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,146 @@
+//!
+//! Minimap modes
+//!
+//! The server chooses the modes the client can cycle through with
+//! TOCLIENT_MINIMAP_MODES, and the one selected initially. The selection
+//! afterwards is purely client side, and followed here by MinimapTracker.
+//!
+use crate::wire::command::ToClientCommand;
+use crate::wire::types::HudFlags;
+use crate::wire::types::MinimapMode;
+use crate::wire::types::MinimapModeList;
+
+pub use crate::wire::minimap::MinimapModesBuilder;
+pub use crate::wire::minimap::MinimapType;
+
+/// Client side view of the minimap
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinimapTracker {
+    modes: MinimapModeList,
+    current: usize,
+    // From HudSetFlags
+    minimap_visible: bool,
+    radar_visible: bool,
+}
+
+impl Default for MinimapTracker {
+    fn default() -> Self {
+        Self {
+            modes: MinimapModeList::engine_default(),
+            current: 0,
+            minimap_visible: true,
+            radar_visible: true,
+        }
+    }
+}
+
+impl MinimapTracker {
+    pub fn modes(&self) -> &MinimapModeList {
+        &self.modes
+    }
+
+    /// The current mode. Modes disabled by the HUD flags show as Off.
+    pub fn current(&self) -> Option<&MinimapMode> {
+        let mode = self.modes.vec.get(self.current)?;
+        if self.allowed(mode) {
+            Some(mode)
+        } else {
+            None
+        }
+    }
+
+    pub fn current_type(&self) -> MinimapType {
+        self.current()
+            .and_then(|mode| mode.minimap_type())
+            .unwrap_or(MinimapType::Off)
+    }
+
+    fn allowed(&self, mode: &MinimapMode) -> bool {
+        match mode.minimap_type() {
+            Some(MinimapType::Radar) => self.minimap_visible && self.radar_visible,
+            Some(MinimapType::Off) | None => true,
+            _ => self.minimap_visible,
+        }
+    }
+
+    /// Cycle to the next allowed mode, like the client's minimap key.
+    pub fn cycle(&mut self) -> Option<&MinimapMode> {
+        let count = self.modes.vec.len();
+        for step in 1..=count {
+            let index = (self.current + step) % count;
+            if self.allowed(&self.modes.vec[index]) {
+                self.current = index;
+                break;
+            }
+        }
+        self.current()
+    }
+
+    pub fn set_hud_flags(&mut self, flags: &HudFlags) {
+        self.minimap_visible = flags.minimap_visible;
+        self.radar_visible = flags.minimap_radar_visible;
+    }
+
+    pub fn observe_toclient(&mut self, command: &ToClientCommand) {
+        match command {
+            ToClientCommand::MinimapModes(spec) => {
+                self.modes = if spec.modes.vec.is_empty() {
+                    MinimapModeList::engine_default()
+                } else {
+                    spec.modes.clone()
+                };
+                self.current = (self.modes.mode as usize).min(self.modes.vec.len() - 1);
+            }
+            ToClientCommand::HudSetFlags(spec) => {
+                // Flags in the mask are cleared, then flags are set
+                let mut old = HudFlags::from_u32(0);
+                old.minimap_visible = self.minimap_visible;
+                old.minimap_radar_visible = self.radar_visible;
+                let new = (old.to_u32() & !spec.mask.to_u32()) | spec.flags.to_u32();
+                self.set_hud_flags(&HudFlags::from_u32(new));
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::HudSetFlagsSpec;
+
+    #[test]
+    fn tracker_cycles() {
+        let mut tracker = MinimapTracker::default();
+        tracker.observe_toclient(
+            &MinimapModeList::builder()
+                .off("")
+                .surface("", 128)
+                .radar("", 128)
+                .select(1)
+                .build_command()
+                .unwrap(),
+        );
+        assert_eq!(tracker.current_type(), MinimapType::Surface);
+        tracker.cycle();
+        assert_eq!(tracker.current_type(), MinimapType::Radar);
+        tracker.cycle();
+        assert_eq!(tracker.current_type(), MinimapType::Off);
+
+        // Radar is skipped when the server hides it
+        let hide = |mask: u32| -> ToClientCommand {
+            HudSetFlagsSpec {
+                flags: HudFlags::from_u32(0),
+                mask: HudFlags::from_u32(mask),
+            }
+            .into()
+        };
+        tracker.observe_toclient(&hide(1 << 6));
+        tracker.cycle();
+        tracker.cycle();
+        assert_eq!(tracker.current_type(), MinimapType::Off);
+        tracker.observe_toclient(&hide(1 << 5));
+        tracker.cycle();
+        assert_eq!(tracker.current_type(), MinimapType::Off);
+    }
+}
@@ -0,0 +1,32 @@
+//!
+//! Game state helpers
+//!
+//! The wire module describes individual commands. The helpers here track
+//! state that is spread over several commands (for one connection), so that
+//! servers and tools can keep their view consistent with the client's.
+//!
+pub mod death;
+pub mod game_loop;
+pub mod hotbar;
+pub mod map_sender;
+pub mod media;
+pub mod media_push;
+#[cfg(feature = "watch")]
+pub mod media_watch;
+pub mod minimap;
+pub mod nodemeta;
+pub mod player_list;
+pub mod privileges;
+pub mod vitals;
+
+pub use death::DeathFlow;
+pub use game_loop::GameLoop;
+pub use hotbar::Hotbar;
+pub use map_sender::MapSender;
+pub use media::MediaServer;
+pub use media_push::MediaPushTracker;
+pub use minimap::MinimapTracker;
+pub use nodemeta::NodemetaSubscriptions;
+pub use player_list::PlayerList;
+pub use privileges::PrivilegeGate;
+pub use vitals::PlayerVitals;
@@ -0,0 +1,220 @@
+//!
+//! Node metadata changes, only to the clients that have the block
+//!
+//! Every chest opened or furnace ticking makes a TOCLIENT_NODEMETA_CHANGED.
+//! A client can only use it for a block it has, so the engine sends each
+//! change only to the clients that have the block. NodemetaSubscriptions
+//! does the same: it follows the blocks each client has (from
+//! TOSERVER_GOTBLOCKS and TOSERVER_DELETEDBLOCKS), and splits a change
+//! among the clients that have its blocks:
+//!
+//! ```text
+//! subscriptions.observe_toserver(client, &command);   // everything from each client
+//!
+//! for (client, command) in subscriptions.fan_out(&changed) {
+//!     clients[&client].send(command).await?;
+//! }
+//! subscriptions.remove_client(&client);               // when it leaves
+//! ```
+//!
+//! A client that has none of the blocks gets nothing.
+//!
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+use crate::wire::command::NodemetaChangedSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::v3s16;
+use crate::wire::types::AbsNodeMetadataList;
+
+/// The blocks one client has
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LoadedBlocks {
+    blocks: HashSet<v3s16>,
+}
+
+impl LoadedBlocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn contains(&self, block: &v3s16) -> bool {
+        self.blocks.contains(block)
+    }
+
+    /// Keeps track of the blocks the client acknowledged, and dropped
+    pub fn observe_toserver(&mut self, command: &ToServerCommand) {
+        match command {
+            ToServerCommand::Gotblocks(spec) => self.blocks.extend(spec.blocks.iter().cloned()),
+            ToServerCommand::Deletedblocks(spec) => {
+                for block in &spec.blocks {
+                    self.blocks.remove(block);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// The part of `changed` in blocks the client has. None if there is
+    /// nothing left.
+    pub fn filter(&self, changed: &NodemetaChangedSpec) -> Option<NodemetaChangedSpec> {
+        let metadata: Vec<_> = changed
+            .list
+            .metadata
+            .iter()
+            .filter(|(pos, _)| self.contains(&pos.block()))
+            .cloned()
+            .collect();
+        if metadata.is_empty() {
+            return None;
+        }
+        Some(NodemetaChangedSpec {
+            list: AbsNodeMetadataList { metadata },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NodemetaStats {
+    /// Changes to one node, sent to one client
+    pub sent: u64,
+    /// Changes to one node, not sent to one client without the block
+    pub filtered: u64,
+}
+
+/// The blocks every client has, by address
+#[derive(Debug, Clone, Default)]
+pub struct NodemetaSubscriptions {
+    clients: HashMap<SocketAddr, LoadedBlocks>,
+    stats: NodemetaStats,
+}
+
+impl NodemetaSubscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> NodemetaStats {
+        self.stats
+    }
+
+    pub fn client(&self, client: &SocketAddr) -> Option<&LoadedBlocks> {
+        self.clients.get(client)
+    }
+
+    /// A client is added by the first command it sends
+    pub fn observe_toserver(&mut self, client: SocketAddr, command: &ToServerCommand) {
+        self.clients
+            .entry(client)
+            .or_default()
+            .observe_toserver(command);
+    }
+
+    pub fn remove_client(&mut self, client: &SocketAddr) {
+        self.clients.remove(client);
+    }
+
+    /// `changed`, split among the clients that have its blocks
+    pub fn fan_out(&mut self, changed: &NodemetaChangedSpec) -> Vec<(SocketAddr, ToClientCommand)> {
+        let total = changed.list.metadata.len() as u64;
+        let mut out = Vec::new();
+        for (client, blocks) in &self.clients {
+            match blocks.filter(changed) {
+                Some(spec) => {
+                    let sent = spec.list.metadata.len() as u64;
+                    self.stats.sent += sent;
+                    self.stats.filtered += total - sent;
+                    out.push((*client, spec.into()));
+                }
+                None => self.stats.filtered += total,
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::DeletedblocksSpec;
+    use crate::wire::command::GotblocksSpec;
+    use crate::wire::types::AbsBlockPos;
+    use crate::wire::types::Inventory;
+    use crate::wire::types::NodeMetadata;
+
+    fn changed(nodes: &[(i16, i16, i16)]) -> NodemetaChangedSpec {
+        NodemetaChangedSpec {
+            list: AbsNodeMetadataList {
+                metadata: nodes
+                    .iter()
+                    .map(|&(x, y, z)| {
+                        let meta = NodeMetadata {
+                            stringvars: Vec::new(),
+                            inventory: Inventory {
+                                entries: Vec::new(),
+                            },
+                        };
+                        (AbsBlockPos::new(v3s16::new(x, y, z)), meta)
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn gotblocks(blocks: &[(i16, i16, i16)]) -> ToServerCommand {
+        GotblocksSpec {
+            blocks: blocks
+                .iter()
+                .map(|&(x, y, z)| v3s16::new(x, y, z))
+                .collect(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn only_loaded_blocks() {
+        let alice: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let bob: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut subscriptions = NodemetaSubscriptions::new();
+        subscriptions.observe_toserver(alice, &gotblocks(&[(0, 0, 0), (-1, 0, 0)]));
+        subscriptions.observe_toserver(bob, &gotblocks(&[(5, 5, 5)]));
+
+        // Nodes (3,4,5) and (-1,0,0) are in blocks (0,0,0) and (-1,0,0)
+        let out = subscriptions.fan_out(&changed(&[(3, 4, 5), (-1, 0, 0), (100, 0, 0)]));
+        assert_eq!(out.len(), 1);
+        let (client, ToClientCommand::NodemetaChanged(spec)) = &out[0] else {
+            panic!("expected NodemetaChanged");
+        };
+        assert_eq!(*client, alice);
+        assert_eq!(spec.list.metadata.len(), 2);
+        assert_eq!(
+            subscriptions.stats(),
+            NodemetaStats {
+                sent: 2,
+                filtered: 4,
+            }
+        );
+
+        subscriptions.observe_toserver(
+            alice,
+            &DeletedblocksSpec {
+                blocks: vec![v3s16::new(0, 0, 0), v3s16::new(-1, 0, 0)],
+            }
+            .into(),
+        );
+        assert!(subscriptions.client(&alice).unwrap().is_empty());
+        assert!(subscriptions.fan_out(&changed(&[(3, 4, 5)])).is_empty());
+        subscriptions.remove_client(&alice);
+        assert!(subscriptions.client(&alice).is_none());
+    }
+}
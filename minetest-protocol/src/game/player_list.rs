@@ -0,0 +1,125 @@
+//!
+//! Online player list
+//!
+//! Clients keep a list of online players (used for chat nick completion,
+//! and by client-side mods), maintained by TOCLIENT_UPDATE_PLAYER_LIST.
+//! A joining client gets the whole list (Init), and every other client is
+//! told about joins (Add) and leaves (Remove).
+//!
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::UpdatePlayerListSpec;
+use crate::wire::types::PlayerListModifier;
+
+/// The commands to send when a player joins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    /// The full list, for the joining player
+    pub to_player: ToClientCommand,
+    /// The new name, for everyone else
+    pub to_others: ToClientCommand,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PlayerList {
+    // In join order
+    players: Vec<String>,
+}
+
+impl PlayerList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    pub fn is_online(&self, name: &str) -> bool {
+        self.players.iter().any(|p| p == name)
+    }
+
+    /// Online players, in join order
+    pub fn players(&self) -> &[String] {
+        &self.players
+    }
+
+    pub fn join(&mut self, name: &str) -> Result<Join> {
+        if self.is_online(name) {
+            bail!("Player {:?} is already online", name);
+        }
+        self.players.push(name.to_string());
+        Ok(Join {
+            to_player: self.init(),
+            to_others: update(PlayerListModifier::Add, vec![name.to_string()]),
+        })
+    }
+
+    /// Returns the command to send to the remaining players,
+    /// or None if the player wasn't online.
+    pub fn leave(&mut self, name: &str) -> Option<ToClientCommand> {
+        let index = self.players.iter().position(|p| p == name)?;
+        let name = self.players.remove(index);
+        Some(update(PlayerListModifier::Remove, vec![name]))
+    }
+
+    /// The full list, as sent to a joining player
+    pub fn init(&self) -> ToClientCommand {
+        update(PlayerListModifier::Init, self.players.clone())
+    }
+
+    /// Client side: apply an update from the server.
+    pub fn observe_toclient(&mut self, command: &ToClientCommand) {
+        let ToClientCommand::UpdatePlayerList(spec) = command else {
+            return;
+        };
+        match spec.typ {
+            PlayerListModifier::Init => self.players = spec.players.clone(),
+            PlayerListModifier::Add => {
+                for name in &spec.players {
+                    if !self.is_online(name) {
+                        self.players.push(name.clone());
+                    }
+                }
+            }
+            PlayerListModifier::Remove => self.players.retain(|p| !spec.players.contains(p)),
+        }
+    }
+}
+
+fn update(typ: PlayerListModifier, players: Vec<String>) -> ToClientCommand {
+    UpdatePlayerListSpec { typ, players }.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_and_leave() {
+        let mut server = PlayerList::new();
+        let mut alice = PlayerList::new();
+        let mut bob = PlayerList::new();
+
+        let join = server.join("alice").unwrap();
+        alice.observe_toclient(&join.to_player);
+        let join = server.join("bob").unwrap();
+        bob.observe_toclient(&join.to_player);
+        alice.observe_toclient(&join.to_others);
+        assert!(server.join("bob").is_err());
+        assert_eq!(alice, server);
+        assert_eq!(bob, server);
+
+        let leave = server.leave("alice").unwrap();
+        bob.observe_toclient(&leave);
+        assert_eq!(bob.players(), &["bob".to_string()]);
+        assert!(!server.is_online("alice"));
+        assert_eq!(server.leave("alice"), None);
+    }
+}
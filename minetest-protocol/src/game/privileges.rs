@@ -0,0 +1,307 @@
+//!
+//! Privilege checks for client commands
+//!
+//! The server tells a client its privileges with TOCLIENT_PRIVILEGES. A
+//! PrivilegeGate keeps track of them (from the commands sent to the
+//! client), and checks every command from the client against a
+//! PrivilegePolicy before the server acts on it:
+//!
+//! ```text
+//! gate.observe_toclient(&command);   // everything sent to the client
+//!
+//! let command = conn.recv().await?;
+//! match gate.check(&command) {
+//!     Verdict::Allow => handle(command),
+//!     Verdict::Drop => (),
+//!     Verdict::Reject(reply) => conn.send(reply).await?,
+//! }
+//! ```
+//!
+//! The default policy is the engine's and builtin's: Interact and
+//! InventoryAction need interact (dropped without), chat needs shout, and
+//! chat commands such as /give need theirs (both refused with a chat
+//! message). Chat commands not in the policy are left to the server.
+//!
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::wire::command::CommandProperties;
+use crate::wire::command::TCChatMessageSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+
+/// What happens to a command sent without the privileges for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// Ignored, like the engine does with Interact
+    Drop,
+    /// Ignored, and the player is told why in chat
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivilegeRule {
+    /// All of them are needed
+    pub privileges: Vec<String>,
+    pub refusal: Refusal,
+}
+
+impl PrivilegeRule {
+    pub fn new(privileges: &[&str], refusal: Refusal) -> Self {
+        Self {
+            privileges: privileges.iter().map(|p| p.to_string()).collect(),
+            refusal,
+        }
+    }
+}
+
+/// The privileges needed, by command
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivilegePolicy {
+    /// By command name, e.g. "Interact". For TSChatMessage, this only
+    /// applies to chat that isn't a chat command.
+    pub commands: BTreeMap<&'static str, PrivilegeRule>,
+    /// By chat command, without the '/', e.g. "give"
+    pub chat_commands: BTreeMap<String, PrivilegeRule>,
+}
+
+impl PrivilegePolicy {
+    /// Nothing needs privileges
+    pub fn new() -> Self {
+        Self {
+            commands: BTreeMap::new(),
+            chat_commands: BTreeMap::new(),
+        }
+    }
+
+    /// Fails for a command that isn't sent to the server
+    pub fn require(&mut self, command: &str, rule: PrivilegeRule) -> Result<()> {
+        let Some(name) = ToServerCommand::COMMAND_NAMES
+            .iter()
+            .find(|name| **name == command)
+        else {
+            bail!("Unknown ToServer command {:?}", command);
+        };
+        self.commands.insert(name, rule);
+        Ok(())
+    }
+
+    /// `chat_command` without the '/'
+    pub fn require_chat_command(&mut self, chat_command: &str, rule: PrivilegeRule) {
+        self.chat_commands.insert(chat_command.to_string(), rule);
+    }
+}
+
+impl Default for PrivilegePolicy {
+    fn default() -> Self {
+        use Refusal::*;
+        let mut policy = Self::new();
+        for (command, privileges, refusal) in [
+            ("Interact", &["interact"][..], Drop),
+            ("InventoryAction", &["interact"], Drop),
+            ("TSChatMessage", &["shout"], Reject),
+        ] {
+            policy
+                .require(command, PrivilegeRule::new(privileges, refusal))
+                .expect("A ToServer command");
+        }
+        // From builtin/game/chat.lua
+        for (chat_command, privileges) in [
+            ("give", &["give"][..]),
+            ("giveme", &["give"]),
+            ("spawnentity", &["give", "interact"]),
+            ("pulverize", &["interact"]),
+            ("teleport", &["teleport"]),
+            ("kick", &["kick"]),
+            ("ban", &["ban"]),
+            ("unban", &["ban"]),
+            ("setpassword", &["password"]),
+            ("clearpassword", &["password"]),
+            ("shutdown", &["server"]),
+            ("clearobjects", &["server"]),
+        ] {
+            policy.require_chat_command(chat_command, PrivilegeRule::new(privileges, Reject));
+        }
+        policy
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    /// Dropped, and this is sent to the client
+    Reject(ToClientCommand),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PrivilegeGate {
+    policy: PrivilegePolicy,
+    privileges: BTreeSet<String>,
+}
+
+impl PrivilegeGate {
+    /// No privileges until the server sends them
+    pub fn new(policy: PrivilegePolicy) -> Self {
+        Self {
+            policy,
+            privileges: BTreeSet::new(),
+        }
+    }
+
+    pub fn policy_mut(&mut self) -> &mut PrivilegePolicy {
+        &mut self.policy
+    }
+
+    pub fn privileges(&self) -> &BTreeSet<String> {
+        &self.privileges
+    }
+
+    pub fn has_privilege(&self, name: &str) -> bool {
+        self.privileges.contains(name)
+    }
+
+    /// For a server that doesn't send them through the gate
+    pub fn set_privileges<I: IntoIterator<Item = String>>(&mut self, privileges: I) {
+        self.privileges = privileges.into_iter().collect();
+    }
+
+    /// Keeps track of the privileges sent to the client
+    pub fn observe_toclient(&mut self, command: &ToClientCommand) {
+        if let ToClientCommand::Privileges(spec) = command {
+            self.set_privileges(spec.privileges.iter().cloned());
+        }
+    }
+
+    /// Whether the player may send `command`
+    pub fn check(&self, command: &ToServerCommand) -> Verdict {
+        if let ToServerCommand::TSChatMessage(spec) = command {
+            if let Some(line) = spec.message.strip_prefix('/') {
+                let name = line.split_whitespace().next().unwrap_or("");
+                return match self.policy.chat_commands.get(name) {
+                    Some(rule) => self.verdict(rule, |missing| {
+                        format!(
+                            "You don't have permission to run this command \
+                             (missing privileges: {}).",
+                            missing
+                        )
+                    }),
+                    None => Verdict::Allow,
+                };
+            }
+        }
+        match self.policy.commands.get(command.command_name()) {
+            Some(rule) => self.verdict(rule, |missing| match command {
+                ToServerCommand::TSChatMessage(_) => {
+                    "-!- You don't have permission to shout.".to_string()
+                }
+                _ => format!(
+                    "You don't have permission to do that (missing privileges: {}).",
+                    missing
+                ),
+            }),
+            None => Verdict::Allow,
+        }
+    }
+
+    fn verdict(&self, rule: &PrivilegeRule, message: impl FnOnce(&str) -> String) -> Verdict {
+        let missing: Vec<&str> = rule
+            .privileges
+            .iter()
+            .filter(|privilege| !self.has_privilege(privilege))
+            .map(String::as_str)
+            .collect();
+        if missing.is_empty() {
+            return Verdict::Allow;
+        }
+        match rule.refusal {
+            Refusal::Drop => Verdict::Drop,
+            Refusal::Reject => Verdict::Reject(
+                TCChatMessageSpec {
+                    version: 1,
+                    // Raw, from no one
+                    message_type: 0,
+                    sender: String::new(),
+                    message: message(&missing.join(", ")),
+                    timestamp: 0,
+                }
+                .into(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::DamageSpec;
+    use crate::wire::command::PrivilegesSpec;
+    use crate::wire::command::TSChatMessageSpec;
+
+    fn chat(message: &str) -> ToServerCommand {
+        TSChatMessageSpec {
+            message: message.to_string(),
+        }
+        .into()
+    }
+
+    fn rejection(verdict: Verdict) -> String {
+        match verdict {
+            Verdict::Reject(ToClientCommand::TCChatMessage(spec)) => spec.message,
+            verdict => panic!("expected a rejection, not {:?}", verdict),
+        }
+    }
+
+    #[test]
+    fn default_policy() {
+        let mut gate = PrivilegeGate::new(PrivilegePolicy::default());
+        assert_eq!(
+            rejection(gate.check(&chat("hello"))),
+            "-!- You don't have permission to shout."
+        );
+        // Chat commands don't need shout
+        assert_eq!(gate.check(&chat("/help")), Verdict::Allow);
+        assert_eq!(
+            rejection(gate.check(&chat("/spawnentity mobs:sheep"))),
+            "You don't have permission to run this command \
+             (missing privileges: give, interact)."
+        );
+
+        gate.observe_toclient(
+            &PrivilegesSpec {
+                privileges: vec!["shout".into(), "give".into()],
+            }
+            .into(),
+        );
+        assert_eq!(gate.check(&chat("hello")), Verdict::Allow);
+        assert_eq!(gate.check(&chat("/giveme default:dirt")), Verdict::Allow);
+        assert_eq!(
+            rejection(gate.check(&chat("/spawnentity mobs:sheep"))),
+            "You don't have permission to run this command \
+             (missing privileges: interact)."
+        );
+    }
+
+    #[test]
+    fn custom_policy() {
+        let mut policy = PrivilegePolicy::new();
+        policy
+            .require("Damage", PrivilegeRule::new(&["fall"], Refusal::Drop))
+            .unwrap();
+        policy
+            .require("Interact", PrivilegeRule::new(&["build"], Refusal::Reject))
+            .unwrap();
+        assert!(policy
+            .require("Blockdata", PrivilegeRule::new(&["x"], Refusal::Drop))
+            .is_err());
+        let mut gate = PrivilegeGate::new(policy);
+        let damage = DamageSpec { damage: 3 }.into();
+        assert_eq!(gate.check(&damage), Verdict::Drop);
+        assert_eq!(gate.check(&chat("hello")), Verdict::Allow);
+        gate.set_privileges(["fall".to_string()]);
+        assert_eq!(gate.check(&damage), Verdict::Allow);
+    }
+}
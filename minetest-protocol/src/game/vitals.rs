@@ -0,0 +1,338 @@
+//!
+//! Player HP and breath
+//!
+//! PlayerVitals mirrors the survival logic of the engine's PlayerSAO:
+//! hp and breath are clamped to their maximums, breath is lost every 2 seconds
+//! with the head in a drowning node (then hp, once breath runs out), and
+//! regained every 0.5 seconds otherwise. Nodes with damage_per_second hurt
+//! (or heal) once a second.
+//!
+//! Every change returns the commands to send to the client. Client
+//! commands (Damage, Respawn) are handled with `handle_toserver`.
+//!
+use crate::wire::command::BreathSpec;
+use crate::wire::command::DeathscreenSpec;
+use crate::wire::command::HpSpec;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::v3f;
+use crate::wire::types::ContentFeatures;
+
+/// PLAYER_MAX_HP_DEFAULT
+pub const PLAYER_MAX_HP_DEFAULT: u16 = 20;
+
+/// PLAYER_MAX_BREATH_DEFAULT
+pub const PLAYER_MAX_BREATH_DEFAULT: u16 = 10;
+
+const DROWNING_INTERVAL: f32 = 2.0;
+const BREATHING_INTERVAL: f32 = 0.5;
+const NODE_HURT_INTERVAL: f32 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HpChangeReason {
+    /// Set directly by the server
+    SetHp,
+    /// Reported by the client (TOSERVER_DAMAGE)
+    Fall,
+    Drowning,
+    NodeDamage,
+    Punch,
+    Respawn,
+}
+
+/// The properties of a node that matter to vitals.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NodeHazard {
+    pub drowning: u8,
+    /// Negative values heal
+    pub damage_per_second: i32,
+    /// CONTENT_IGNORE (not loaded). Breath is not regained in ignore.
+    pub ignore: bool,
+}
+
+impl From<&ContentFeatures> for NodeHazard {
+    fn from(features: &ContentFeatures) -> Self {
+        Self {
+            drowning: features.drowning,
+            // Sent as u32, but signed in the engine
+            damage_per_second: features.damage_per_second as i32,
+            ignore: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerVitals {
+    hp: u16,
+    hp_max: u16,
+    breath: u16,
+    breath_max: u16,
+    immortal: bool,
+    drowning_timer: f32,
+    breathing_timer: f32,
+    hurt_timer: f32,
+}
+
+impl Default for PlayerVitals {
+    fn default() -> Self {
+        Self::new(PLAYER_MAX_HP_DEFAULT, PLAYER_MAX_BREATH_DEFAULT)
+    }
+}
+
+impl PlayerVitals {
+    /// Starts alive with full hp and breath.
+    pub fn new(hp_max: u16, breath_max: u16) -> Self {
+        Self {
+            hp: hp_max,
+            hp_max,
+            breath: breath_max,
+            breath_max,
+            immortal: false,
+            drowning_timer: 0.0,
+            breathing_timer: 0.0,
+            hurt_timer: 0.0,
+        }
+    }
+
+    pub fn hp(&self) -> u16 {
+        self.hp
+    }
+
+    pub fn hp_max(&self) -> u16 {
+        self.hp_max
+    }
+
+    pub fn breath(&self) -> u16 {
+        self.breath
+    }
+
+    pub fn breath_max(&self) -> u16 {
+        self.breath_max
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.hp == 0
+    }
+
+    pub fn is_immortal(&self) -> bool {
+        self.immortal
+    }
+
+    /// Immortal players take no damage and don't lose breath
+    /// (the "immortal" armor group).
+    pub fn set_immortal(&mut self, immortal: bool) {
+        self.immortal = immortal;
+    }
+
+    /// The commands that send the complete state, e.g. after joining.
+    pub fn sync(&self) -> Vec<ToClientCommand> {
+        let mut out = vec![hp_command(self.hp, false), breath_command(self.breath)];
+        if self.is_dead() {
+            out.push(deathscreen_command());
+        }
+        out
+    }
+
+    /// Change the maximums. Current values are clamped to them.
+    pub fn set_max(&mut self, hp_max: u16, breath_max: u16) -> Vec<ToClientCommand> {
+        self.hp_max = hp_max;
+        self.breath_max = breath_max;
+        let mut out = self.set_hp(self.hp, HpChangeReason::SetHp);
+        out.extend(self.set_breath(self.breath));
+        out
+    }
+
+    pub fn set_hp(&mut self, hp: u16, reason: HpChangeReason) -> Vec<ToClientCommand> {
+        let hp = hp.min(self.hp_max);
+        if hp < self.hp && self.immortal && reason != HpChangeReason::SetHp {
+            return Vec::new();
+        }
+        if hp == self.hp {
+            return Vec::new();
+        }
+        let was_alive = !self.is_dead();
+        let damage_effect = hp < self.hp;
+        self.hp = hp;
+        let mut out = vec![hp_command(hp, damage_effect)];
+        if was_alive && self.is_dead() {
+            out.push(deathscreen_command());
+        }
+        out
+    }
+
+    /// Apply damage (negative heals). Dead players are not healed.
+    pub fn damage(&mut self, amount: i32, reason: HpChangeReason) -> Vec<ToClientCommand> {
+        if self.is_dead() {
+            return Vec::new();
+        }
+        let hp = (self.hp as i32 - amount).clamp(0, u16::MAX as i32) as u16;
+        self.set_hp(hp, reason)
+    }
+
+    pub fn set_breath(&mut self, breath: u16) -> Vec<ToClientCommand> {
+        let breath = breath.min(self.breath_max);
+        if breath == self.breath {
+            return Vec::new();
+        }
+        self.breath = breath;
+        vec![breath_command(breath)]
+    }
+
+    /// Advance the timers by `dtime` seconds. `head` is the node at eye
+    /// height, `body` the nodes the player occupies.
+    pub fn step(
+        &mut self,
+        dtime: f32,
+        head: NodeHazard,
+        body: &[NodeHazard],
+    ) -> Vec<ToClientCommand> {
+        let mut out = Vec::new();
+        if interval(&mut self.drowning_timer, dtime, DROWNING_INTERVAL)
+            && head.drowning > 0
+            && !self.is_dead()
+            && !self.immortal
+        {
+            if self.breath > 0 {
+                out.extend(self.set_breath(self.breath - 1));
+            }
+            if self.breath == 0 {
+                out.extend(self.damage(head.drowning as i32, HpChangeReason::Drowning));
+            }
+        }
+        if interval(&mut self.breathing_timer, dtime, BREATHING_INTERVAL)
+            && head.drowning == 0
+            && !head.ignore
+            && !self.is_dead()
+            && !self.immortal
+            && self.breath < self.breath_max
+        {
+            out.extend(self.set_breath(self.breath + 1));
+        }
+        if interval(&mut self.hurt_timer, dtime, NODE_HURT_INTERVAL) {
+            // The most harmful node wins, or the most healing if none harm
+            let worst = body
+                .iter()
+                .map(|n| n.damage_per_second)
+                .max_by_key(|d| (*d > 0, d.abs()));
+            if let Some(damage) = worst.filter(|d| *d != 0) {
+                out.extend(self.damage(damage, HpChangeReason::NodeDamage));
+            }
+        }
+        out
+    }
+
+    /// Bring a dead player back with full hp and breath.
+    pub fn respawn(&mut self) -> Vec<ToClientCommand> {
+        if !self.is_dead() {
+            return Vec::new();
+        }
+        let mut out = self.set_hp(self.hp_max, HpChangeReason::Respawn);
+        out.extend(self.set_breath(self.breath_max));
+        out
+    }
+
+    /// Handle vitals related client commands (Damage, Respawn).
+    pub fn handle_toserver(&mut self, command: &ToServerCommand) -> Vec<ToClientCommand> {
+        match command {
+            ToServerCommand::Damage(spec) => self.damage(spec.damage as i32, HpChangeReason::Fall),
+            ToServerCommand::Respawn(_) => self.respawn(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Like the engine's IntervalLimiter
+fn interval(timer: &mut f32, dtime: f32, wanted: f32) -> bool {
+    *timer += dtime;
+    if *timer < wanted {
+        return false;
+    }
+    *timer -= wanted;
+    true
+}
+
+fn hp_command(hp: u16, damage_effect: bool) -> ToClientCommand {
+    HpSpec {
+        hp,
+        damage_effect: Some(damage_effect),
+    }
+    .into()
+}
+
+fn breath_command(breath: u16) -> ToClientCommand {
+    BreathSpec { breath }.into()
+}
+
+fn deathscreen_command() -> ToClientCommand {
+    DeathscreenSpec {
+        set_camera_point_target: false,
+        camera_point_target: v3f::new(0.0, 0.0, 0.0),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::DamageSpec;
+    use crate::wire::command::RespawnSpec;
+
+    fn water() -> NodeHazard {
+        NodeHazard {
+            drowning: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn drowning_and_respawn() {
+        let mut vitals = PlayerVitals::new(3, 2);
+        let air = NodeHazard::default();
+
+        // Lose a breath every 2 seconds, then hp
+        assert!(vitals.step(1.0, water(), &[]).is_empty());
+        assert_eq!(vitals.step(1.0, water(), &[]), vec![breath_command(1)]);
+        vitals.step(2.0, water(), &[]);
+        assert_eq!(vitals.breath(), 0);
+        assert_eq!(vitals.hp(), 2);
+
+        // Catch a breath
+        vitals.step(0.5, air, &[]);
+        assert_eq!(vitals.breath(), 1);
+
+        // Fall damage kills
+        let out = vitals.handle_toserver(&DamageSpec { damage: 5 }.into());
+        assert_eq!(out, vec![hp_command(0, true), deathscreen_command()]);
+        assert!(vitals.is_dead());
+        assert!(vitals.damage(1, HpChangeReason::Punch).is_empty());
+        assert!(vitals.damage(-1, HpChangeReason::NodeDamage).is_empty());
+
+        let out = vitals.handle_toserver(&RespawnSpec {}.into());
+        assert_eq!(out, vec![hp_command(3, false), breath_command(2)]);
+        assert!(vitals.handle_toserver(&RespawnSpec {}.into()).is_empty());
+    }
+
+    #[test]
+    fn node_damage_and_clamping() {
+        let mut vitals = PlayerVitals::default();
+        let lava = NodeHazard {
+            damage_per_second: 4,
+            ..Default::default()
+        };
+        let healing = NodeHazard {
+            damage_per_second: -8,
+            ..Default::default()
+        };
+        vitals.step(1.0, NodeHazard::default(), &[healing, lava]);
+        assert_eq!(vitals.hp(), 16);
+        vitals.step(1.0, NodeHazard::default(), &[healing]);
+        assert_eq!(vitals.hp(), PLAYER_MAX_HP_DEFAULT);
+
+        vitals.set_immortal(true);
+        assert!(vitals.damage(100, HpChangeReason::Punch).is_empty());
+        vitals.set_immortal(false);
+
+        let out = vitals.set_max(10, 5);
+        assert_eq!(out, vec![hp_command(10, true), breath_command(5)]);
+    }
+}
@@ -1,10 +1,17 @@
+pub mod bot;
+pub mod game;
 pub mod peer;
+pub mod recording;
+pub mod replay;
 pub mod services;
-pub mod wire;
+pub mod world;
+
+/// The wire format layer lives in the minetest-wire crate
+pub use minetest_wire as wire;
 
 pub use services::client::MinetestClient;
 pub use services::conn::MinetestConnection;
+pub use services::server::AuthServer;
 pub use services::server::MinetestServer;
-pub use wire::audit::audit_on;
 pub use wire::command::CommandRef;
 pub use wire::types::CommandDirection;
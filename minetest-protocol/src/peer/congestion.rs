@@ -0,0 +1,246 @@
+//!
+//! Congestion watermarks
+//!
+//! Each channel has two queues on the way out: reliable packets sent but
+//! not yet acked (bounded by the reliable window), and packets waiting for
+//! room in the window. A client that can't keep up shows up as both
+//! growing without bound, since nothing stops the server from queueing.
+//!
+//! A Watermarks pair gives each depth some hysteresis: crossing `high`
+//! reports the channel congested, and it is only reported recovered once
+//! the depth falls back to `low`. A server can then slow down the map it
+//! sends to that client, instead of queueing more.
+//!
+use super::peer::ChannelNum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Congested when the depth reaches this
+    pub high: usize,
+    /// Recovered when the depth falls back to this
+    pub low: usize,
+}
+
+impl Watermarks {
+    pub const fn new(high: usize, low: usize) -> Self {
+        Self { high, low }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionConfig {
+    /// Reliable packets sent but not acked
+    pub unacked: Watermarks,
+    /// Packets waiting to be sent
+    pub queued: Watermarks,
+}
+
+impl Default for CongestionConfig {
+    fn default() -> Self {
+        // The reliable window is 1024 packets, and a packet is at most
+        // about 500 bytes, so 4096 queued is about 2 MB.
+        Self {
+            unacked: Watermarks::new(768, 256),
+            queued: Watermarks::new(4096, 512),
+        }
+    }
+}
+
+/// The depth a watermark applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QueueKind {
+    Unacked,
+    Queued,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionEvent {
+    /// `depth` reached the high watermark
+    Congested {
+        channel: ChannelNum,
+        kind: QueueKind,
+        depth: usize,
+    },
+    /// `depth` fell back to the low watermark
+    Recovered {
+        channel: ChannelNum,
+        kind: QueueKind,
+        depth: usize,
+    },
+}
+
+/// A snapshot of one channel's outgoing queues
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChannelLoad {
+    pub unacked: usize,
+    pub queued: usize,
+    /// The reliable window size, in packets
+    pub window: usize,
+}
+
+impl ChannelLoad {
+    /// Fraction of the reliable window in use [0, 1]
+    pub fn utilization(&self) -> f32 {
+        if self.window == 0 {
+            0.0
+        } else {
+            (self.unacked as f32 / self.window as f32).min(1.0)
+        }
+    }
+}
+
+/// Turns a series of depths into Congested/Recovered transitions
+#[derive(Debug, Clone, Default)]
+pub struct WatermarkTracker {
+    congested: bool,
+}
+
+impl WatermarkTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_congested(&self) -> bool {
+        self.congested
+    }
+
+    /// Returns Some(true) when `depth` makes the queue congested,
+    /// Some(false) when it recovers, and None otherwise.
+    pub fn update(&mut self, marks: &Watermarks, depth: usize) -> Option<bool> {
+        if !self.congested && depth >= marks.high {
+            self.congested = true;
+            Some(true)
+        } else if self.congested && depth <= marks.low {
+            self.congested = false;
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// The trackers for every channel
+#[derive(Debug, Clone)]
+pub struct CongestionMonitor {
+    config: CongestionConfig,
+    // (unacked, queued) per channel
+    trackers: Vec<(WatermarkTracker, WatermarkTracker)>,
+}
+
+impl CongestionMonitor {
+    pub fn new(config: CongestionConfig, channels: usize) -> Self {
+        Self {
+            config,
+            trackers: vec![Default::default(); channels],
+        }
+    }
+
+    pub fn config(&self) -> &CongestionConfig {
+        &self.config
+    }
+
+    /// New watermarks apply from the next update
+    pub fn set_config(&mut self, config: CongestionConfig) {
+        self.config = config;
+    }
+
+    pub fn is_congested(&self) -> bool {
+        self.trackers
+            .iter()
+            .any(|(unacked, queued)| unacked.is_congested() || queued.is_congested())
+    }
+
+    /// Checks the loads (indexed by channel), appending any transitions
+    pub fn update(&mut self, loads: &[ChannelLoad], events: &mut Vec<CongestionEvent>) {
+        for (num, (load, (unacked, queued))) in loads.iter().zip(&mut self.trackers).enumerate() {
+            let channel = num as ChannelNum;
+            let checks = [
+                (
+                    QueueKind::Unacked,
+                    unacked,
+                    &self.config.unacked,
+                    load.unacked,
+                ),
+                (QueueKind::Queued, queued, &self.config.queued, load.queued),
+            ];
+            for (kind, tracker, marks, depth) in checks {
+                match tracker.update(marks, depth) {
+                    Some(true) => events.push(CongestionEvent::Congested {
+                        channel,
+                        kind,
+                        depth,
+                    }),
+                    Some(false) => events.push(CongestionEvent::Recovered {
+                        channel,
+                        kind,
+                        depth,
+                    }),
+                    None => (),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hysteresis() {
+        let config = CongestionConfig {
+            unacked: Watermarks::new(10, 2),
+            queued: Watermarks::new(100, 50),
+        };
+        let mut monitor = CongestionMonitor::new(config, 3);
+        let load = |unacked, queued| ChannelLoad {
+            unacked,
+            queued,
+            window: 20,
+        };
+        let mut events = Vec::new();
+        monitor.update(&[load(5, 0), load(10, 0), load(0, 0)], &mut events);
+        assert_eq!(
+            events,
+            vec![CongestionEvent::Congested {
+                channel: 1,
+                kind: QueueKind::Unacked,
+                depth: 10
+            }]
+        );
+        assert!(monitor.is_congested());
+
+        // Between the watermarks, nothing changes
+        events.clear();
+        monitor.update(&[load(5, 0), load(5, 120), load(0, 0)], &mut events);
+        monitor.update(&[load(5, 0), load(3, 60), load(0, 0)], &mut events);
+        assert_eq!(
+            events,
+            vec![CongestionEvent::Congested {
+                channel: 1,
+                kind: QueueKind::Queued,
+                depth: 120
+            }]
+        );
+
+        events.clear();
+        monitor.update(&[load(5, 0), load(2, 50), load(0, 0)], &mut events);
+        assert_eq!(
+            events,
+            vec![
+                CongestionEvent::Recovered {
+                    channel: 1,
+                    kind: QueueKind::Unacked,
+                    depth: 2
+                },
+                CongestionEvent::Recovered {
+                    channel: 1,
+                    kind: QueueKind::Queued,
+                    depth: 50
+                },
+            ]
+        );
+        assert!(!monitor.is_congested());
+        assert_eq!(load(5, 0).utilization(), 0.25);
+    }
+}
@@ -0,0 +1,259 @@
+//!
+//! Channel and reliability overrides
+//!
+//! Each command type has a channel and reliability (see
+//! CommandProperties::default_channel and default_reliability), from the
+//! table in wire::command. DeliveryOverrides replaces them per command
+//! type on one connection, at runtime, for experiments:
+//!
+//! ```text
+//! let mut overrides = DeliveryOverrides::new();
+//! overrides.set("ActiveObjectMessages", Delivery::unreliable())?;
+//! overrides.set("Blockdata", Delivery::on_channel(1))?;
+//! peer.set_delivery_overrides(overrides);
+//! ```
+//!
+//! Or from a string (e.g. a config file), in the same order:
+//!
+//! ```text
+//! ActiveObjectMessages=unreliable Blockdata=1 Media=0:reliable
+//! ```
+//!
+//! The other side doesn't care which channel or reliability a command
+//! comes with, but an unreliable command may be lost. Making a command
+//! the game relies on unreliable (e.g. Init) will break things.
+//!
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use super::peer::ChannelNum;
+use crate::wire::command::CommandProperties;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+
+/// How one command type is sent. None keeps the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Delivery {
+    pub channel: Option<ChannelNum>,
+    pub reliable: Option<bool>,
+}
+
+impl Delivery {
+    pub fn reliable() -> Self {
+        Self {
+            reliable: Some(true),
+            ..Default::default()
+        }
+    }
+
+    pub fn unreliable() -> Self {
+        Self {
+            reliable: Some(false),
+            ..Default::default()
+        }
+    }
+
+    pub fn on_channel(channel: ChannelNum) -> Self {
+        Self {
+            channel: Some(channel),
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.channel, self.reliable) {
+            (Some(channel), Some(reliable)) => write!(f, "{}:{}", channel, reliability(reliable)),
+            (Some(channel), None) => write!(f, "{}", channel),
+            (None, Some(reliable)) => write!(f, "{}", reliability(reliable)),
+            (None, None) => write!(f, "default"),
+        }
+    }
+}
+
+fn reliability(reliable: bool) -> &'static str {
+    if reliable {
+        "reliable"
+    } else {
+        "unreliable"
+    }
+}
+
+/// Delivery by command name, for one connection
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeliveryOverrides {
+    by_name: BTreeMap<&'static str, Delivery>,
+}
+
+impl DeliveryOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Overrides the delivery of the command `name` (e.g. "Blockdata").
+    /// Fails for an unknown command or a channel above 2.
+    pub fn set(&mut self, name: &str, delivery: Delivery) -> Result<()> {
+        let Some(name) = command_name(name) else {
+            bail!("Unknown command {:?}", name);
+        };
+        if let Some(channel) = delivery.channel {
+            if channel > 2 {
+                bail!("Invalid channel {} for {} (0 to 2)", channel, name);
+            }
+        }
+        if delivery == Delivery::default() {
+            self.by_name.remove(name);
+        } else {
+            self.by_name.insert(name, delivery);
+        }
+        Ok(())
+    }
+
+    /// Back to the default for the command `name`
+    pub fn clear(&mut self, name: &str) {
+        self.by_name.remove(name);
+    }
+
+    pub fn get(&self, name: &str) -> Delivery {
+        self.by_name.get(name).copied().unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// The channel and reliability `command` is sent with
+    pub fn resolve<Cmd: CommandProperties>(&self, command: &Cmd) -> (ChannelNum, bool) {
+        let delivery = self.get(command.command_name());
+        (
+            delivery
+                .channel
+                .unwrap_or_else(|| command.default_channel()),
+            delivery
+                .reliable
+                .unwrap_or_else(|| command.default_reliability()),
+        )
+    }
+}
+
+/// The command's name as the command tables have it
+fn command_name(name: &str) -> Option<&'static str> {
+    ToClientCommand::COMMAND_NAMES
+        .iter()
+        .chain(ToServerCommand::COMMAND_NAMES)
+        .find(|known| **known == name)
+        .copied()
+}
+
+impl FromStr for DeliveryOverrides {
+    type Err = anyhow::Error;
+
+    /// Space separated name=delivery, where delivery is a channel, reliable
+    /// or unreliable, or both as channel:reliability
+    fn from_str(text: &str) -> Result<Self> {
+        let mut overrides = Self::new();
+        for item in text.split_whitespace() {
+            let Some((name, value)) = item.split_once('=') else {
+                bail!("Expected name=delivery, not {:?}", item);
+            };
+            let mut delivery = Delivery::default();
+            for part in value.split(':') {
+                match part {
+                    "reliable" => delivery.reliable = Some(true),
+                    "unreliable" => delivery.reliable = Some(false),
+                    "default" => (),
+                    channel => match channel.parse() {
+                        Ok(channel) => delivery.channel = Some(channel),
+                        Err(_) => bail!("Invalid delivery {:?} for {}", value, name),
+                    },
+                }
+            }
+            overrides.set(name, delivery)?;
+        }
+        Ok(overrides)
+    }
+}
+
+impl fmt::Display for DeliveryOverrides {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, delivery)) in self.by_name.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}={}", name, delivery)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::socket::MinetestSocket;
+    use crate::services::transport::LoopbackNetwork;
+    use crate::wire::command::ActiveObjectMessagesSpec;
+    use crate::wire::command::BreathSpec;
+    use crate::wire::command::Command;
+    use crate::wire::command::TSChatMessageSpec;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn overrides() {
+        let mut overrides: DeliveryOverrides =
+            "ActiveObjectMessages=unreliable Breath=2:unreliable"
+                .parse()
+                .unwrap();
+        let messages: ToClientCommand = ActiveObjectMessagesSpec { objects: vec![] }.into();
+        let breath: ToClientCommand = BreathSpec { breath: 5 }.into();
+        assert_eq!(overrides.resolve(&messages), (0, false));
+        assert_eq!(overrides.resolve(&breath), (2, false));
+        assert_eq!(
+            overrides.to_string(),
+            "ActiveObjectMessages=unreliable Breath=2:unreliable"
+        );
+
+        overrides.set("Breath", Delivery::default()).unwrap();
+        assert_eq!(overrides.resolve(&breath), (0, true));
+        assert!(overrides.set("Breath", Delivery::on_channel(3)).is_err());
+        assert!(overrides
+            .set("NoSuchCommand", Delivery::reliable())
+            .is_err());
+        assert!("Breath=sometimes".parse::<DeliveryOverrides>().is_err());
+    }
+
+    #[tokio::test]
+    async fn sent_as_overridden() {
+        let network = LoopbackNetwork::new();
+        let server_addr: SocketAddr = "10.0.0.1:30000".parse().unwrap();
+        let client_addr: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut server = MinetestSocket::with_transport(network.bind(server_addr), true).unwrap();
+        let mut client = MinetestSocket::with_transport(network.bind(client_addr), false).unwrap();
+        let mut to_server = client.add_peer(server_addr).await;
+        let chat = TSChatMessageSpec {
+            message: "hi".into(),
+        };
+        to_server.send(chat.into()).await.unwrap();
+        let mut to_client = server.accept().await.unwrap();
+        to_client.recv().await.unwrap();
+
+        to_client.set_delivery_overrides("Breath=2:unreliable".parse().unwrap());
+        to_client
+            .send(Command::ToClient(BreathSpec { breath: 5 }.into()))
+            .await
+            .unwrap();
+        let Command::ToClient(ToClientCommand::Breath(spec)) = to_server.recv().await.unwrap()
+        else {
+            panic!("expected Breath");
+        };
+        assert_eq!(spec.breath, 5);
+        let stats = to_server.stats();
+        assert_eq!(stats.channels[2].packets_received, 1);
+        // Unreliable, so not acked
+        assert_eq!(stats.channels[2].acks_sent, 0);
+    }
+}
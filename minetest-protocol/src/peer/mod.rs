@@ -1,7 +1,12 @@
 mod channel;
+pub mod congestion;
+pub mod delivery;
+#[allow(clippy::module_inception)]
 pub mod peer;
+pub mod quarantine;
 mod reliable_receiver;
 mod reliable_sender;
-mod split_receiver;
+pub(crate) mod split_receiver;
 mod split_sender;
+pub mod stats;
 mod util;
@@ -15,15 +15,23 @@ use anyhow::Result;
 use rand::rngs::StdRng;
 use rand::Rng;
 use rand::SeedableRng;
-use tokio::sync::mpsc::unbounded_channel;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
 
 use crate::wire::command::Command;
 use crate::wire::command::CommandProperties;
 use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::compat::CompatIssue;
+use crate::wire::compat::CompatLinter;
+use crate::wire::compat::CompatMode;
+use crate::wire::deser::ChainedBuffer;
 use crate::wire::deser::Deserialize;
 use crate::wire::deser::Deserializer;
+use crate::wire::packet::negotiate_protocol_version;
 use crate::wire::packet::AckBody;
 use crate::wire::packet::ControlBody;
 use crate::wire::packet::InnerBody;
@@ -32,24 +40,58 @@ use crate::wire::packet::PacketBody;
 use crate::wire::packet::PeerId;
 use crate::wire::packet::ReliableBody;
 use crate::wire::packet::SetPeerIdBody;
+use crate::wire::packet::MAX_ORIGINAL_BODY_SIZE;
+use crate::wire::packet::PACKET_HEADER_SIZE;
+use crate::wire::packet::SER_FMT_HIGHEST_WRITE;
 use crate::wire::ser::Serialize;
 use crate::wire::ser::VecSerializer;
 use crate::wire::types::ProtocolContext;
-
+use crate::wire::util::ZLIB_DEFAULT_LEVEL;
+
+use super::congestion::ChannelLoad;
+use super::congestion::CongestionConfig;
+use super::congestion::CongestionEvent;
+use super::congestion::CongestionMonitor;
+use super::delivery::DeliveryOverrides;
+use super::quarantine::ParseFailure;
+use super::quarantine::ParsedUnit;
 use super::reliable_receiver::ReliableReceiver;
 use super::reliable_sender::ReliableSender;
 use super::split_receiver::SplitReceiver;
 use super::split_sender::SplitSender;
+use super::stats::ChannelStats;
+use super::stats::ConnectionStats;
 
 use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
 // How long to accept peer_id == 0 from a client after sending set_peer_id
 const INEXISTENT_PEER_ID_GRACE: Duration = Duration::from_secs(20);
 
+// Channel capacities. Peer::send waits while the runner is TO_RUNNER_CAPACITY
+// commands behind. Past the receive capacities, unreliable commands and
+// datagrams are dropped, and reliable ones disconnect the peer.
+const TO_RUNNER_CAPACITY: usize = 256;
+const TO_CONTROLLER_CAPACITY: usize = 1024;
+const FROM_SOCKET_CAPACITY: usize = 1024;
+const CONGESTION_EVENTS_CAPACITY: usize = 64;
+const SEND_WARNINGS_CAPACITY: usize = 64;
+
+// The runner stops taking commands from the controller while this many
+// packets wait to be sent (all channels), which is what makes Peer::send
+// wait for a slow peer
+const MAX_QUEUED_PACKETS: usize = 8192;
+
+// How long Peer::close waits for the peer to ack what was sent before it
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum PeerError {
     #[error("Peer sent disconnect packet")]
     PeerSentDisconnect,
@@ -59,6 +101,89 @@ pub enum PeerError {
     ControllerClosed,
     #[error("Internal Peer error")]
     InternalPeerError,
+    /// Nothing was received from the peer for KeepaliveConfig::timeout
+    #[error("Peer timed out")]
+    Timeout,
+    /// A reliable packet or command arrived with the receive queue full:
+    /// the controller isn't keeping up with the peer
+    #[error("Receive queue overflow")]
+    ReceiveOverflow,
+    /// Refused by UnreliableSplitPolicy::Reject. The connection is unaffected.
+    #[error("Unreliable command {command} is too large for one packet ({size} bytes)")]
+    UnreliableTooLarge { command: &'static str, size: usize },
+}
+
+impl PeerError {
+    /// The PeerError an error is about, if any
+    pub fn find(err: &anyhow::Error) -> Option<&PeerError> {
+        err.chain().find_map(|cause| cause.downcast_ref())
+    }
+
+    /// The peer hung up on purpose, rather than something going wrong
+    pub fn is_peer_disconnect(&self) -> bool {
+        matches!(self, PeerError::PeerSentDisconnect)
+    }
+
+    /// The socket itself failed, rather than the peer or what it sent
+    pub fn is_transport_failure(&self) -> bool {
+        matches!(self, PeerError::SocketClosed)
+    }
+}
+
+/// Something Peer::send changed about a command it still sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendWarning {
+    /// UnreliableSplitPolicy::ForceReliable sent an unreliable command
+    /// reliably, because it needed split packets
+    ForcedReliable { command: &'static str, size: usize },
+    /// CompatMode::Warn sent something the peer's protocol version won't
+    /// understand
+    Incompatible(CompatIssue),
+}
+
+impl std::fmt::Display for SendWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendWarning::ForcedReliable { command, size } => write!(
+                f,
+                "Sent unreliable {} reliably, {} bytes is too large for one packet",
+                command, size
+            ),
+            SendWarning::Incompatible(issue) => write!(f, "Peer can't understand {}", issue),
+        }
+    }
+}
+
+/// What to do with an unreliable command too large for a single packet.
+/// Split packets are only reassembled once every chunk has arrived, so
+/// sending one unreliably loses the whole command if any chunk drops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnreliableSplitPolicy {
+    /// Send it reliably instead, with a SendWarning
+    #[default]
+    ForceReliable,
+    /// Fail the send with PeerError::UnreliableTooLarge
+    Reject,
+    /// Send it as unreliable split packets anyway
+    Allow,
+}
+
+/// Like the engine, a Ping is sent on channel 0 every `ping_interval`, so
+/// an idle connection still hears from us, and a peer that sends nothing
+/// (not even pings or acks) for `timeout` is disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    pub ping_interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+        }
+    }
 }
 
 pub type ChannelNum = u8;
@@ -68,9 +193,26 @@ pub type FullSeqNum = u64;
 pub struct Peer {
     remote_addr: SocketAddr,
     remote_is_server: bool,
-    /// TODO(paradust): Add backpressure
-    send: UnboundedSender<Command>,
-    recv: UnboundedReceiver<Result<Command>>,
+    send: Sender<(Command, ChannelNum, bool, MeasuredSize)>,
+    recv: Receiver<Result<Command>>,
+    settings: watch::Sender<PeerSettings>,
+    split_policy: UnreliableSplitPolicy,
+    compat_mode: CompatMode,
+    delivery: DeliveryOverrides,
+    // Follows the runner's send context, to check commands before queueing
+    send_context: watch::Receiver<ProtocolContext>,
+    load: watch::Receiver<[ChannelLoad; 3]>,
+    stats: watch::Receiver<ConnectionStats>,
+    congestion: Receiver<CongestionEvent>,
+    warnings_tx: Sender<SendWarning>,
+    warnings: Receiver<SendWarning>,
+}
+
+/// A command's serialized size, and the context it was serialized in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MeasuredSize {
+    context: ProtocolContext,
+    size: usize,
 }
 
 impl Peer {
@@ -82,13 +224,194 @@ impl Peer {
         self.remote_is_server
     }
 
+    /// The context commands are currently serialized in
+    pub fn send_context(&self) -> ProtocolContext {
+        *self.send_context.borrow()
+    }
+
+    pub fn split_policy(&self) -> UnreliableSplitPolicy {
+        self.split_policy
+    }
+
+    pub fn set_split_policy(&mut self, policy: UnreliableSplitPolicy) {
+        self.split_policy = policy;
+    }
+
+    pub fn compat_mode(&self) -> CompatMode {
+        self.compat_mode
+    }
+
+    /// What to do with commands the peer's protocol version can't understand
+    pub fn set_compat_mode(&mut self, mode: CompatMode) {
+        self.compat_mode = mode;
+    }
+
+    pub fn delivery_overrides(&self) -> &DeliveryOverrides {
+        &self.delivery
+    }
+
+    /// Channel and reliability by command type, instead of the defaults,
+    /// for the commands sent from now on (see peer::delivery)
+    pub fn set_delivery_overrides(&mut self, overrides: DeliveryOverrides) {
+        self.delivery = overrides;
+    }
+
+    /// zlib level for the commands sent from now on, and those still
+    /// queued (see ProtocolContext::zlib_level). Nodedef and Itemdef are
+    /// compressed for every client that joins, so servers may want a faster
+    /// level. If this fails, the peer has disconnected.
+    pub fn set_zlib_level(&self, level: u8) -> Result<()> {
+        self.update_settings(|settings| settings.zlib_level = level)
+    }
+
+    /// Audits the commands received from now on (see wire::audit). Off by
+    /// default. If this fails, the peer has disconnected.
+    pub fn set_audit(&self, audit: bool) -> Result<()> {
+        self.update_settings(|settings| settings.audit = audit)
+    }
+
+    /// How full each channel's outgoing queues are, as of the last time
+    /// the runner sent packets
+    pub fn channel_load(&self) -> [ChannelLoad; 3] {
+        *self.load.borrow()
+    }
+
+    /// Traffic counters, as of the runner's last wakeup
+    pub fn stats(&self) -> ConnectionStats {
+        *self.stats.borrow()
+    }
+
+    /// A handle on the peer's state that can be used from another task
+    pub fn monitor(&self) -> PeerMonitor {
+        PeerMonitor {
+            remote_addr: self.remote_addr,
+            remote_is_server: self.remote_is_server,
+            send_context: self.send_context.clone(),
+            load: self.load.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// See PeerMonitor::debug_dump
+    pub fn debug_dump(&self) -> String {
+        self.monitor().debug_dump()
+    }
+
+    /// Watermarks for congestion events. If this fails, the peer has
+    /// disconnected.
+    pub fn set_congestion_config(&self, config: CongestionConfig) -> Result<()> {
+        self.update_settings(|settings| settings.congestion = config)
+    }
+
+    /// Ping interval and inactivity timeout. If this fails, the peer has
+    /// disconnected.
+    pub fn set_keepalive(&self, config: KeepaliveConfig) -> Result<()> {
+        self.update_settings(|settings| settings.keepalive = config)
+    }
+
+    fn update_settings(&self, update: impl FnOnce(&mut PeerSettings)) -> Result<()> {
+        if self.settings.is_closed() {
+            bail!(PeerError::InternalPeerError);
+        }
+        self.settings.send_modify(update);
+        Ok(())
+    }
+
+    /// Congestion events since the last call, oldest first
+    pub fn congestion_events(&mut self) -> Vec<CongestionEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = self.congestion.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    /// Warnings from send since the last call, oldest first. Only the
+    /// first SEND_WARNINGS_CAPACITY are kept.
+    pub fn send_warnings(&mut self) -> Vec<SendWarning> {
+        let mut warnings = Vec::new();
+        while let Ok(warning) = self.warnings.try_recv() {
+            warnings.push(warning);
+        }
+        warnings
+    }
+
     /// Send command to peer
-    /// If this fails, the peer has disconnected.
+    /// Waits while the runner is too far behind, e.g. while the peer
+    /// doesn't ack fast enough.
+    /// If the command can't be serialized (CommandSerializeError), is too
+    /// new for the peer in CompatMode::Deny (CompatError), or is refused by
+    /// the split policy (PeerError::UnreliableTooLarge), it is not sent and
+    /// the connection is unaffected.
+    /// Otherwise, if this fails, the peer has disconnected.
     pub async fn send(&self, command: Command) -> Result<()> {
-        self.send.send(command)?;
+        let context = self.send_context();
+        let size = command.check_serialize(context)?;
+        self.send_measured(command, context, size).await
+    }
+
+    /// Like send, for a command checked already in this crate: `size` is
+    /// what check_serialize returned for it in `context`, normally
+    /// send_context(). It isn't serialized again until it goes out.
+    pub(crate) async fn send_measured(
+        &self,
+        command: Command,
+        context: ProtocolContext,
+        size: usize,
+    ) -> Result<()> {
+        let issues =
+            CompatLinter::new(context.protocol_version, self.compat_mode).check(&command)?;
+        for issue in issues {
+            let _ = self.warnings_tx.try_send(SendWarning::Incompatible(issue));
+        }
+        let (channel, mut reliable) = self.delivery.resolve(&command);
+        if !reliable && size > MAX_ORIGINAL_BODY_SIZE {
+            match self.split_policy {
+                UnreliableSplitPolicy::ForceReliable => {
+                    let _ = self.warnings_tx.try_send(SendWarning::ForcedReliable {
+                        command: command.command_name(),
+                        size,
+                    });
+                    reliable = true;
+                }
+                UnreliableSplitPolicy::Reject => bail!(PeerError::UnreliableTooLarge {
+                    command: command.command_name(),
+                    size,
+                }),
+                UnreliableSplitPolicy::Allow => (),
+            }
+        }
+        let measured = MeasuredSize { context, size };
+        self.send
+            .send((command, channel, reliable, measured))
+            .await?;
         Ok(())
     }
 
+    /// Close the connection. Commands already sent are still delivered
+    /// (waiting up to 5 seconds for the acks), then a Disconnect is sent.
+    /// Commands received meanwhile are dropped. Resolves once the runner
+    /// has shut down, and fails if the peer had disconnected already.
+    pub async fn close(self) -> Result<()> {
+        let Peer {
+            send,
+            mut recv,
+            settings,
+            ..
+        } = self;
+        // Set before the runner can see the controller go away
+        settings.send_modify(|settings| settings.closing = true);
+        drop(send);
+        // The runner drops its senders when it's done
+        let mut result = Ok(());
+        while let Some(received) = recv.recv().await {
+            if let Err(err) = received {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
     /// Receive command from the peer
     /// Returns (channel, reliable flag, Command)
     /// If this fails, the peer is disconnected.
@@ -100,63 +423,208 @@ impl Peer {
     }
 }
 
+/// The state of a peer as of the runner's last wakeup, see Peer::monitor
+#[derive(Clone)]
+pub struct PeerMonitor {
+    remote_addr: SocketAddr,
+    remote_is_server: bool,
+    send_context: watch::Receiver<ProtocolContext>,
+    load: watch::Receiver<[ChannelLoad; 3]>,
+    stats: watch::Receiver<ConnectionStats>,
+}
+
+impl PeerMonitor {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        *self.stats.borrow()
+    }
+
+    pub fn channel_load(&self) -> [ChannelLoad; 3] {
+        *self.load.borrow()
+    }
+
+    /// False once the runner has shut down
+    pub fn is_running(&self) -> bool {
+        self.stats.has_changed().is_ok()
+    }
+
+    /// The connection state in a few lines of text: the negotiated
+    /// context, then the counters, seqnums and queues of each channel.
+    /// For bug reports about hangs.
+    pub fn debug_dump(&self) -> String {
+        let context = *self.send_context.borrow();
+        let stats = self.stats();
+        let load = self.channel_load();
+        let mut dump = format!(
+            "{} {}: protocol {}, ser_fmt {}, {}\n",
+            if self.remote_is_server {
+                "server"
+            } else {
+                "client"
+            },
+            self.remote_addr,
+            context.protocol_version,
+            context.ser_fmt,
+            if self.is_running() {
+                "running"
+            } else {
+                "shut down"
+            },
+        );
+        for (num, (channel, load)) in stats.channels.iter().zip(load).enumerate() {
+            dump += &format!(
+                "  channel {}: sent {} ({} bytes), received {} ({} bytes), \
+                 {} retransmits, acks {} sent {} received, \
+                 seqnums next send {} oldest unacked {:?} next receive {}, \
+                 unacked {}/{} queued {}, rtt {:?}\n",
+                num,
+                channel.packets_sent,
+                channel.bytes_sent,
+                channel.packets_received,
+                channel.bytes_received,
+                channel.retransmits,
+                channel.acks_sent,
+                channel.acks_received,
+                channel.next_send_seqnum,
+                channel.oldest_unacked_seqnum,
+                channel.next_receive_seqnum,
+                load.unacked,
+                load.window,
+                load.queued,
+                channel.rtt,
+            );
+        }
+        dump
+    }
+}
+
+/// Set by the controller at any time. The runner applies the latest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PeerSettings {
+    zlib_level: u8,
+    audit: bool,
+    congestion: CongestionConfig,
+    keepalive: KeepaliveConfig,
+    closing: bool,
+}
+
 // This is owned by the MinetestSocket
 pub struct PeerIO {
-    relay: UnboundedSender<SocketToPeer>,
+    relay: Sender<SocketToPeer>,
+    // Tells the runner why relay closed
+    overflowed: Arc<AtomicBool>,
 }
 
 pub fn new_peer(
     remote_addr: SocketAddr,
     remote_is_server: bool,
-    peer_to_socket: UnboundedSender<PeerToSocket>,
+    peer_to_socket: Sender<PeerToSocket>,
 ) -> (Peer, PeerIO) {
-    let (peer_send_tx, peer_send_rx) = unbounded_channel();
-    let (peer_recv_tx, peer_recv_rx) = unbounded_channel();
-    let (relay_tx, relay_rx) = unbounded_channel();
+    let (peer_send_tx, peer_send_rx) = channel(TO_RUNNER_CAPACITY);
+    let (peer_recv_tx, peer_recv_rx) = channel(TO_CONTROLLER_CAPACITY);
+    let (relay_tx, relay_rx) = channel(FROM_SOCKET_CAPACITY);
+    let send_context = ProtocolContext::latest_for_send(remote_is_server);
+    let (send_context_tx, send_context_rx) = watch::channel(send_context);
+    let (load_tx, load_rx) = watch::channel([ChannelLoad::default(); 3]);
+    let (stats_tx, stats_rx) = watch::channel(ConnectionStats::default());
+    let (congestion_tx, congestion_rx) = channel(CONGESTION_EVENTS_CAPACITY);
+    let (warnings_tx, warnings_rx) = channel(SEND_WARNINGS_CAPACITY);
+    let (settings_tx, settings_rx) = watch::channel(PeerSettings {
+        zlib_level: ZLIB_DEFAULT_LEVEL,
+        audit: false,
+        congestion: CongestionConfig::default(),
+        keepalive: KeepaliveConfig::default(),
+        closing: false,
+    });
+    let overflowed = Arc::new(AtomicBool::new(false));
 
     let socket_peer = Peer {
         remote_addr,
         remote_is_server,
         send: peer_send_tx,
         recv: peer_recv_rx,
+        settings: settings_tx,
+        split_policy: UnreliableSplitPolicy::default(),
+        compat_mode: CompatMode::default(),
+        delivery: DeliveryOverrides::new(),
+        send_context: send_context_rx,
+        load: load_rx,
+        stats: stats_rx,
+        congestion: congestion_rx,
+        warnings_tx,
+        warnings: warnings_rx,
+    };
+    let socket_peer_io = PeerIO {
+        relay: relay_tx,
+        overflowed: overflowed.clone(),
     };
-    let socket_peer_io = PeerIO { relay: relay_tx };
     let socket_peer_runner = PeerRunner {
         remote_addr,
         remote_is_server,
         recv_context: ProtocolContext::latest_for_receive(remote_is_server),
-        send_context: ProtocolContext::latest_for_send(remote_is_server),
+        send_context,
+        send_context_tx,
         connect_time: Instant::now(),
         remote_peer_id: 0,
         local_peer_id: 0,
         from_socket: relay_rx,
+        overflowed,
         from_controller: peer_send_rx,
+        settings: settings_rx,
         to_controller: peer_recv_tx.clone(),
         to_socket: peer_to_socket,
         channels: vec![
-            Channel::new(remote_is_server, peer_recv_tx.clone()),
-            Channel::new(remote_is_server, peer_recv_tx.clone()),
-            Channel::new(remote_is_server, peer_recv_tx.clone()),
+            Channel::new(remote_addr, remote_is_server, peer_recv_tx.clone()),
+            Channel::new(remote_addr, remote_is_server, peer_recv_tx.clone()),
+            Channel::new(remote_addr, remote_is_server, peer_recv_tx.clone()),
         ],
+        congestion: CongestionMonitor::new(CongestionConfig::default(), 3),
+        load_tx,
+        stats_tx,
+        congestion_tx,
         rng: StdRng::from_entropy(),
         now: Instant::now(),
         last_received: Instant::now(),
+        keepalive: KeepaliveConfig::default(),
+        last_ping: Instant::now(),
+        close_deadline: None,
     };
     tokio::spawn(async move { socket_peer_runner.run().await });
     (socket_peer, socket_peer_io)
 }
 
 impl PeerIO {
-    /// Parse the packet and send it to the runner
+    /// Send the packet to the runner, to parse
     /// Called by the MinetestSocket when a packet arrives for us
     ///
-    pub fn send(&mut self, data: &[u8]) {
-        // TODO: Add backpressure
-        let _ = self.relay.send(SocketToPeer::Received(data.to_vec()));
+    /// If the runner is too far behind, an unreliable packet is dropped,
+    /// and a reliable one disconnects the peer (PeerError::ReceiveOverflow):
+    /// the peer would only resend it. Returns false if the peer is gone,
+    /// and the PeerIO should be dropped.
+    pub fn send(&mut self, data: &[u8]) -> bool {
+        match self.relay.try_send(SocketToPeer::Received(data.to_vec())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) if !is_reliable_datagram(data) => true,
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.store(true, Ordering::SeqCst);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
     }
 }
 
+/// Peeks the packet type, which follows the packet header
+fn is_reliable_datagram(data: &[u8]) -> bool {
+    // See PacketBody::deserialize
+    data.get(PACKET_HEADER_SIZE) == Some(&3)
+}
+
 struct Channel {
+    remote_addr: SocketAddr,
     unreliable_out: VecDeque<InnerBody>,
 
     reliable_in: ReliableReceiver,
@@ -165,20 +633,27 @@ struct Channel {
     split_in: SplitReceiver,
     split_out: SplitSender,
 
-    to_controller: UnboundedSender<Result<Command>>,
+    stats: ChannelStats,
+    to_controller: Sender<Result<Command>>,
     now: Instant,
     recv_context: ProtocolContext,
     send_context: ProtocolContext,
 }
 
 impl Channel {
-    pub fn new(remote_is_server: bool, to_controller: UnboundedSender<Result<Command>>) -> Self {
+    pub fn new(
+        remote_addr: SocketAddr,
+        remote_is_server: bool,
+        to_controller: Sender<Result<Command>>,
+    ) -> Self {
         Self {
+            remote_addr,
             unreliable_out: VecDeque::new(),
             reliable_in: ReliableReceiver::new(),
             reliable_out: ReliableSender::new(),
             split_in: SplitReceiver::new(),
             split_out: SplitSender::new(),
+            stats: ChannelStats::default(),
             to_controller,
             now: Instant::now(),
             recv_context: ProtocolContext::latest_for_receive(remote_is_server),
@@ -204,7 +679,7 @@ impl Channel {
     pub async fn process(&mut self, body: PacketBody) -> anyhow::Result<()> {
         match body {
             PacketBody::Reliable(rb) => self.process_reliable(rb).await?,
-            PacketBody::Inner(ib) => self.process_inner(ib).await?,
+            PacketBody::Inner(ib) => self.process_inner(false, ib).await?,
         }
         Ok(())
     }
@@ -212,20 +687,34 @@ impl Channel {
     pub async fn process_reliable(&mut self, body: ReliableBody) -> anyhow::Result<()> {
         self.reliable_in.push(body);
         while let Some(inner) = self.reliable_in.pop() {
-            self.process_inner(inner).await?;
+            self.process_inner(true, inner).await?;
         }
         Ok(())
     }
 
-    pub async fn process_inner(&mut self, body: InnerBody) -> anyhow::Result<()> {
+    pub async fn process_inner(&mut self, reliable: bool, body: InnerBody) -> anyhow::Result<()> {
         match body {
             InnerBody::Control(body) => self.process_control(body),
-            InnerBody::Original(body) => self.process_command(body.command).await,
+            InnerBody::Original(body) => self.process_command(reliable, body.command).await?,
             InnerBody::Split(body) => {
-                if let Some(payload) = self.split_in.push(self.now, body)? {
-                    let mut buf = Deserializer::new(self.recv_context, &payload);
-                    let command = Command::deserialize(&mut buf)?;
-                    self.process_command(command).await;
+                if let Some(chunks) = self.split_in.push(self.now, reliable, body)? {
+                    self.stats.split_reassemblies += 1;
+                    // Parse in place, without concatenating the chunks
+                    let chain = ChainedBuffer::new(chunks);
+                    let command = {
+                        let mut buf = Deserializer::new_chained(self.recv_context, &chain);
+                        match Command::deserialize(&mut buf) {
+                            Ok(command) => command,
+                            Err(error) => bail!(ParseFailure::new(
+                                ParsedUnit::SplitCommand,
+                                self.remote_addr,
+                                self.recv_context,
+                                &chain.concat(),
+                                error,
+                            )),
+                        }
+                    };
+                    self.process_command(reliable, command).await?;
                 }
             }
         }
@@ -233,25 +722,36 @@ impl Channel {
     }
 
     pub fn process_control(&mut self, body: ControlBody) {
-        match body {
-            ControlBody::Ack(ack) => {
-                self.reliable_out.process_ack(ack);
+        // Everything but acks is handled one level up
+        if let ControlBody::Ack(ack) = body {
+            self.stats.acks_received += 1;
+            if let Some(rtt) = self.reliable_out.process_ack(ack, self.now) {
+                self.stats.add_rtt_sample(rtt);
             }
-            // Everything else is handled one level up
-            _ => (),
         }
     }
 
-    pub async fn process_command(&mut self, command: Command) {
-        match self.to_controller.send(Ok(command)) {
-            Ok(_) => (),
-            Err(e) => panic!("Unexpected command channel shutdown: {:?}", e),
+    /// Never waits for the controller: it may be waiting for us to take
+    /// what it sends. An unreliable command is dropped if it can't keep up.
+    pub async fn process_command(&mut self, reliable: bool, command: Command) -> Result<()> {
+        match self.to_controller.try_send(Ok(command)) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) if !reliable => Ok(()),
+            Err(TrySendError::Full(_)) => bail!(PeerError::ReceiveOverflow),
+            Err(TrySendError::Closed(_)) => bail!(PeerError::ControllerClosed),
         }
     }
 
-    /// Send command to remote
-    pub fn send(&mut self, reliable: bool, command: Command) -> anyhow::Result<()> {
-        let bodies = self.split_out.push(self.send_context, command)?;
+    /// Send command to remote. The size is reused if it was measured in
+    /// this channel's context.
+    pub fn send(
+        &mut self,
+        reliable: bool,
+        command: Command,
+        measured: MeasuredSize,
+    ) -> anyhow::Result<()> {
+        let size = (measured.context == self.send_context).then_some(measured.size);
+        let bodies = self.split_out.push(self.send_context, command, size)?;
         for body in bodies.into_iter() {
             self.send_inner(reliable, body);
         }
@@ -268,13 +768,11 @@ impl Channel {
 
     /// Check if the channel has anything ready to send.
     pub fn next_send(&mut self, now: Instant) -> Option<PacketBody> {
-        match self.unreliable_out.pop_front() {
-            Some(body) => return Some(PacketBody::Inner(body)),
-            None => (),
+        if let Some(body) = self.unreliable_out.pop_front() {
+            return Some(PacketBody::Inner(body));
         };
-        match self.reliable_out.pop(now) {
-            Some(body) => return Some(body),
-            None => (),
+        if let Some(body) = self.reliable_out.pop(now) {
+            return Some(body);
         }
         None
     }
@@ -283,6 +781,26 @@ impl Channel {
     pub fn next_timeout(&mut self) -> Option<Instant> {
         self.reliable_out.next_timeout()
     }
+
+    /// Unreliable packets are never left queued after next_send()
+    pub fn load(&self) -> ChannelLoad {
+        ChannelLoad {
+            unacked: self.reliable_out.unacked(),
+            queued: self.reliable_out.queued() + self.unreliable_out.len(),
+            window: self.reliable_out.window_size() as usize,
+        }
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            retransmits: self.reliable_out.retransmits(),
+            next_send_seqnum: self.reliable_out.next_seqnum(),
+            oldest_unacked_seqnum: self.reliable_out.oldest_unacked_seqnum(),
+            next_receive_seqnum: self.reliable_in.next_seqnum(),
+            window: self.reliable_out.window_size(),
+            ..self.stats
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -305,14 +823,17 @@ pub struct PeerRunner {
     connect_time: Instant,
     recv_context: ProtocolContext,
     send_context: ProtocolContext,
+    send_context_tx: watch::Sender<ProtocolContext>,
 
-    // TODO(paradust): These should have a limited size, and close connection on overflow.
-    from_socket: UnboundedReceiver<SocketToPeer>,
-    to_socket: UnboundedSender<PeerToSocket>,
+    from_socket: Receiver<SocketToPeer>,
+    // Set by PeerIO when it gives up on us
+    overflowed: Arc<AtomicBool>,
+    to_socket: Sender<PeerToSocket>,
 
-    // TODO(paradust): These should have backpressure
-    from_controller: UnboundedReceiver<Command>,
-    to_controller: UnboundedSender<Result<Command>>,
+    // With the reliable flag to send it with
+    from_controller: Receiver<(Command, ChannelNum, bool, MeasuredSize)>,
+    to_controller: Sender<Result<Command>>,
+    settings: watch::Receiver<PeerSettings>,
 
     // This is the peer id in the Minetest protocol
     // Minetest's server uses these to keep track of clients, but we use the remote_addr.
@@ -323,12 +844,21 @@ pub struct PeerRunner {
     rng: StdRng,
 
     channels: Vec<Channel>,
+    congestion: CongestionMonitor,
+    load_tx: watch::Sender<[ChannelLoad; 3]>,
+    stats_tx: watch::Sender<ConnectionStats>,
+    congestion_tx: Sender<CongestionEvent>,
 
     // Updated once per wakeup, to limit number of repeated syscalls
     now: Instant,
 
     // Time last packet was received. Used to timeout connection.
     last_received: Instant,
+    keepalive: KeepaliveConfig,
+    last_ping: Instant,
+
+    // Set once the controller closed the connection (see Peer::close)
+    close_deadline: Option<Instant>,
 }
 
 impl PeerRunner {
@@ -348,41 +878,56 @@ impl PeerRunner {
 
     pub async fn send_raw(&mut self, channel: u8, body: PacketBody) -> Result<()> {
         let raw = self.serialize_for_send(channel, body)?;
+        self.count_sent(channel, &raw);
         self.to_socket
-            .send(PeerToSocket::Send(self.remote_addr, raw))?;
+            .send(PeerToSocket::Send(self.remote_addr, raw))
+            .await?;
         Ok(())
     }
 
     pub async fn send_raw_priority(&mut self, channel: u8, body: PacketBody) -> Result<()> {
         let raw = self.serialize_for_send(channel, body)?;
+        self.count_sent(channel, &raw);
         self.to_socket
-            .send(PeerToSocket::SendImmediate(self.remote_addr, raw))?;
+            .send(PeerToSocket::SendImmediate(self.remote_addr, raw))
+            .await?;
         Ok(())
     }
 
+    fn count_sent(&mut self, channel: u8, raw: &[u8]) {
+        let stats = &mut self.channels[channel as usize].stats;
+        stats.packets_sent += 1;
+        stats.bytes_sent += raw.len() as u64;
+    }
+
     pub async fn run(mut self) {
-        if let Err(err) = self.run_inner().await {
-            // Top-level error handling for a peer.
-            // If an error gets to this point, the peer is toast.
-            // Send a disconnect packet, and a remove peer request to the socket
-            // These channels might already be dead, so ignore any errors.
-            let disconnected_cleanly: bool = if let Some(e) = err.downcast_ref::<PeerError>() {
-                matches!(e, PeerError::PeerSentDisconnect)
-            } else {
-                false
-            };
-            if !disconnected_cleanly {
-                // Send a disconnect packet
-                let _ = self
-                    .send_raw(0, (ControlBody::Disconnect).into_inner().into_unreliable())
-                    .await;
-            }
+        // Top-level error handling for a peer.
+        // run_inner only returns Ok once the controller closed the
+        // connection. If an error gets to this point, the peer is toast.
+        // Either way, send a disconnect packet, and a remove peer request to
+        // the socket. These channels might already be dead, so ignore any errors.
+        let result = self.run_inner().await;
+        let disconnected_cleanly: bool = match &result {
+            Err(err) => matches!(
+                err.downcast_ref::<PeerError>(),
+                Some(PeerError::PeerSentDisconnect)
+            ),
+            Ok(()) => false,
+        };
+        if !disconnected_cleanly {
+            // Send a disconnect packet
             let _ = self
-                .to_socket
-                .send(PeerToSocket::PeerIsDisconnected(self.remote_addr));
-
-            // Tell the controller why we died
-            let _ = self.to_controller.send(Err(err));
+                .send_raw(0, (ControlBody::Disconnect).into_inner().into_unreliable())
+                .await;
+        }
+        let _ = self
+            .to_socket
+            .send(PeerToSocket::PeerIsDisconnected(self.remote_addr))
+            .await;
+
+        if let Err(err) = result {
+            // Tell the controller why we died, once it has room
+            let _ = self.to_controller.send(Err(err)).await;
         }
     }
 
@@ -408,11 +953,25 @@ impl PeerRunner {
                     next_wakeup = std::cmp::min(next_wakeup, timeout);
                 }
             }
+            next_wakeup = next_wakeup
+                .min(self.last_ping + self.keepalive.ping_interval)
+                .min(self.last_received + self.keepalive.timeout);
+            self.check_load();
+            self.publish_stats();
+            if let Some(deadline) = self.close_deadline {
+                if self.now >= deadline || self.is_flushed() {
+                    return Ok(());
+                }
+                next_wakeup = next_wakeup.min(deadline);
+            }
+            let may_queue =
+                self.queued_packets() < MAX_QUEUED_PACKETS && self.close_deadline.is_none();
 
             // rust-analyzer chokes on code inside select!, so keep it to a minimum.
             tokio::select! {
+                changed = self.settings.changed() => self.handle_settings(changed)?,
                 msg = self.from_socket.recv() => self.handle_from_socket(msg).await?,
-                command = self.from_controller.recv() => self.handle_from_controller(command).await?,
+                command = self.from_controller.recv(), if may_queue => self.handle_from_controller(command).await?,
                 _ = tokio::time::sleep_until(next_wakeup.into()) => self.handle_timeout().await?,
             }
         }
@@ -422,28 +981,68 @@ impl PeerRunner {
         self.update_now();
         let msg = match msg {
             Some(msg) => msg,
+            None if self.overflowed.load(Ordering::SeqCst) => bail!(PeerError::ReceiveOverflow),
             None => bail!(PeerError::SocketClosed),
         };
         match msg {
             SocketToPeer::Received(buf) => {
-                let mut deser = Deserializer::new(self.recv_context, &buf);
-                let pkt = Packet::deserialize(&mut deser)?;
+                let pkt = {
+                    let mut deser = Deserializer::new(self.recv_context, &buf);
+                    match Packet::deserialize(&mut deser) {
+                        Ok(pkt) => pkt,
+                        Err(error) => bail!(ParseFailure::new(
+                            ParsedUnit::Packet,
+                            self.remote_addr,
+                            self.recv_context,
+                            &buf,
+                            error,
+                        )),
+                    }
+                };
                 self.last_received = self.now;
+                let stats = &mut self.channels[pkt.channel as usize].stats;
+                stats.packets_received += 1;
+                stats.bytes_received += buf.len() as u64;
                 self.process_packet(pkt).await?;
             }
         };
         Ok(())
     }
 
-    async fn handle_from_controller(&mut self, command: Option<Command>) -> anyhow::Result<()> {
+    async fn handle_from_controller(
+        &mut self,
+        msg: Option<(Command, ChannelNum, bool, MeasuredSize)>,
+    ) -> anyhow::Result<()> {
         self.update_now();
-        let command = match command {
-            Some(command) => command,
+        let (command, channel, reliable, measured) = match msg {
+            Some(msg) => msg,
+            None if self.settings.borrow().closing => {
+                self.close_deadline = Some(self.now + CLOSE_TIMEOUT);
+                return Ok(());
+            }
             None => bail!(PeerError::ControllerClosed),
         };
         self.sniff_hello(&command);
 
-        self.send_command(command).await?;
+        self.send_command(command, channel, reliable, measured)
+            .await?;
+        Ok(())
+    }
+
+    fn handle_settings(&mut self, changed: Result<(), watch::error::RecvError>) -> Result<()> {
+        if changed.is_err() {
+            bail!(PeerError::ControllerClosed);
+        }
+        let settings = *self.settings.borrow_and_update();
+        if settings.zlib_level != self.send_context.zlib_level
+            || settings.audit != self.recv_context.audit
+        {
+            self.send_context.zlib_level = settings.zlib_level;
+            self.recv_context.audit = settings.audit;
+            self.publish_context();
+        }
+        self.congestion.set_config(settings.congestion);
+        self.keepalive = settings.keepalive;
         Ok(())
     }
 
@@ -469,17 +1068,17 @@ impl PeerRunner {
             if pkt.sender_peer_id == 0 {
                 if self.now > self.connect_time + INEXISTENT_PEER_ID_GRACE {
                     // Malformed, ignore.
-                    println!("Ignoring peer_id 0 packet");
+                    eprintln!("Ignoring peer_id 0 packet");
                     return Ok(());
                 }
             } else if pkt.sender_peer_id != self.remote_peer_id {
                 // Malformed. Ignore
-                println!("Invalid peer_id on packet");
+                eprintln!("Invalid peer_id on packet");
                 return Ok(());
             }
         } else {
             if pkt.sender_peer_id != 1 {
-                println!("Server sending from wrong peer id");
+                eprintln!("Server sending from wrong peer id");
                 return Ok(());
             }
         }
@@ -524,8 +1123,21 @@ impl PeerRunner {
         self.channels[pkt.channel as usize].process(pkt.body).await
     }
 
+    /// The client's Init switches the server side to the highest mutually
+    /// supported version, and the server's Hello (sent or received) to
+    /// the version it announces.
     fn sniff_hello(&mut self, command: &Command) {
         match command {
+            Command::ToServer(ToServerCommand::Init(spec)) if !self.remote_is_server => {
+                let negotiated = negotiate_protocol_version(
+                    spec.min_net_proto_version,
+                    spec.max_net_proto_version,
+                );
+                if let Some(protocol_version) = negotiated {
+                    let ser_fmt = spec.serialization_ver_max.min(SER_FMT_HIGHEST_WRITE);
+                    self.update_context(ser_fmt, protocol_version);
+                }
+            }
             Command::ToClient(ToClientCommand::Hello(spec)) => {
                 self.update_context(spec.serialization_ver, spec.proto_ver);
             }
@@ -538,28 +1150,403 @@ impl PeerRunner {
         self.recv_context.ser_fmt = ser_fmt;
         self.send_context.protocol_version = protocol_version;
         self.send_context.ser_fmt = ser_fmt;
+        self.publish_context();
+    }
+
+    fn publish_context(&mut self) {
+        let _ = self.send_context_tx.send(self.send_context);
         for num in 0..=2 {
             self.channels[num].update_context(&self.recv_context, &self.send_context);
         }
     }
 
+    fn queued_packets(&self) -> usize {
+        self.channels
+            .iter()
+            .map(|channel| channel.load().queued)
+            .sum()
+    }
+
+    /// Nothing left to send, or waiting for an ack
+    fn is_flushed(&self) -> bool {
+        self.channels.iter().all(|channel| {
+            let load = channel.load();
+            load.queued == 0 && load.unacked == 0
+        })
+    }
+
+    /// Publishes the channel loads, and any watermarks they crossed
+    fn check_load(&mut self) {
+        let loads = [
+            self.channels[0].load(),
+            self.channels[1].load(),
+            self.channels[2].load(),
+        ];
+        self.load_tx.send_if_modified(|current| {
+            let modified = *current != loads;
+            *current = loads;
+            modified
+        });
+        let mut events = Vec::new();
+        self.congestion.update(&loads, &mut events);
+        for event in events {
+            // Nobody may be listening. Events are dropped if they aren't
+            // taken.
+            let _ = self.congestion_tx.try_send(event);
+        }
+    }
+
+    fn publish_stats(&mut self) {
+        let stats = ConnectionStats {
+            channels: [
+                self.channels[0].stats(),
+                self.channels[1].stats(),
+                self.channels[2].stats(),
+            ],
+        };
+        self.stats_tx.send_if_modified(|current| {
+            let modified = *current != stats;
+            *current = stats;
+            modified
+        });
+    }
+
     /// If this is a reliable packet, send an ack right away
     /// using a higher-priority out-of-band channel.
     async fn send_ack(&mut self, channel: u8, rb: &ReliableBody) -> anyhow::Result<()> {
         let ack = AckBody::new(rb.seqnum).into_inner().into_unreliable();
         self.send_raw_priority(channel, ack).await?;
+        self.channels[channel as usize].stats.acks_sent += 1;
         Ok(())
     }
 
     /// Send command to remote
-    async fn send_command(&mut self, command: Command) -> anyhow::Result<()> {
-        let channel = command.default_channel();
-        let reliable = command.default_reliability();
+    async fn send_command(
+        &mut self,
+        command: Command,
+        channel: ChannelNum,
+        reliable: bool,
+        measured: MeasuredSize,
+    ) -> anyhow::Result<()> {
         assert!((0..=2).contains(&channel));
-        self.channels[channel as usize].send(reliable, command)
+        self.channels[channel as usize].send(reliable, command, measured)
     }
 
+    /// Resends are handled by the channels (next_send). This times out a
+    /// silent peer, and sends pings. Pings are reliable, so the peer acks
+    /// them, which keeps our side of the connection alive too.
     async fn process_timeouts(&mut self) -> anyhow::Result<()> {
+        if self.now >= self.last_received + self.keepalive.timeout {
+            bail!(PeerError::Timeout);
+        }
+        if self.now >= self.last_ping + self.keepalive.ping_interval {
+            self.last_ping = self.now;
+            self.channels[0].send_inner(true, ControlBody::Ping.into_inner());
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::CommandSerializeError;
+    use crate::wire::command::InitSpec;
+    use crate::wire::packet::OriginalBody;
+    use crate::wire::packet::LATEST_PROTOCOL_VERSION;
+    use crate::wire::util::ZLIB_DEFAULT_LEVEL;
+
+    fn init(name_len: usize) -> Command {
+        Command::ToServer(
+            InitSpec {
+                serialization_ver_max: 29,
+                supp_compr_modes: 0,
+                min_net_proto_version: 37,
+                max_net_proto_version: 41,
+                player_name: "x".repeat(name_len),
+            }
+            .into(),
+        )
+    }
+
+    #[tokio::test]
+    async fn unreliable_split_policy() {
+        let (to_socket, mut from_peer) = channel(1024);
+        let (mut peer, _io) = new_peer("127.0.0.1:30000".parse().unwrap(), true, to_socket);
+
+        peer.set_split_policy(UnreliableSplitPolicy::Reject);
+        let err = peer.send(init(1000)).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PeerError>(),
+            Some(PeerError::UnreliableTooLarge {
+                command: "Init",
+                ..
+            })
+        ));
+        let err = peer.send(init(70000)).await.unwrap_err();
+        assert!(err.downcast_ref::<CommandSerializeError>().is_some());
+
+        // The default sends it split and reliable
+        peer.set_split_policy(UnreliableSplitPolicy::default());
+        peer.send(init(1000)).await.unwrap();
+        let context = ProtocolContext::latest_for_receive(false);
+        let mut chunks = 0;
+        while chunks < 2 {
+            let Some(PeerToSocket::Send(_, raw)) = from_peer.recv().await else {
+                panic!("expected a packet");
+            };
+            let pkt = Packet::deserialize(&mut Deserializer::new(context, &raw)).unwrap();
+            assert!(pkt.as_reliable().is_some());
+            chunks += 1;
+        }
+        assert_eq!(
+            peer.send_warnings(),
+            vec![SendWarning::ForcedReliable {
+                command: "Init",
+                size: init(1000).check_serialize(peer.send_context()).unwrap(),
+            }]
+        );
+        assert!(peer.send_warnings().is_empty());
+    }
+
+    #[tokio::test]
+    async fn negotiates_from_init() {
+        let (to_socket, _from_peer) = channel(1024);
+        let (mut peer, mut io) = new_peer("127.0.0.1:30000".parse().unwrap(), false, to_socket);
+        // A client newer than this crate
+        let init: Command = Command::ToServer(
+            InitSpec {
+                serialization_ver_max: 29,
+                supp_compr_modes: 0,
+                min_net_proto_version: 37,
+                max_net_proto_version: 99,
+                player_name: "x".to_string(),
+            }
+            .into(),
+        );
+        let body = InnerBody::Original(OriginalBody { command: init });
+        let pkt = Packet::new(0, 0, body.into_unreliable());
+        let mut ser = VecSerializer::new(ProtocolContext::latest_for_send(true), 512);
+        Packet::serialize(&pkt, &mut ser).unwrap();
+        io.send(&ser.take());
+        assert!(matches!(
+            peer.recv().await.unwrap(),
+            Command::ToServer(ToServerCommand::Init(_))
+        ));
+        assert_eq!(
+            peer.send_context().protocol_version,
+            LATEST_PROTOCOL_VERSION
+        );
+        assert_eq!(negotiate_protocol_version(37, 42), Some(42));
+        assert_eq!(negotiate_protocol_version(20, 36), None);
+        assert_eq!(negotiate_protocol_version(99, 100), None);
+    }
+
+    #[tokio::test]
+    async fn congestion_events() {
+        use super::super::congestion::QueueKind;
+        use super::super::congestion::Watermarks;
+        use crate::wire::command::TSChatMessageSpec;
+        use crate::wire::packet::SEQNUM_INITIAL;
+
+        let (to_socket, _from_peer) = channel(1024);
+        let (mut peer, mut io) = new_peer("127.0.0.1:30000".parse().unwrap(), true, to_socket);
+        peer.set_congestion_config(CongestionConfig {
+            unacked: Watermarks::new(4, 1),
+            ..Default::default()
+        })
+        .unwrap();
+        for _ in 0..4 {
+            let chat = TSChatMessageSpec {
+                message: "hi".to_string(),
+            };
+            peer.send(Command::ToServer(chat.into())).await.unwrap();
+        }
+        while peer.channel_load()[0].unacked < 4 {
+            peer.load.changed().await.unwrap();
+        }
+        assert_eq!(
+            peer.congestion_events(),
+            vec![CongestionEvent::Congested {
+                channel: 0,
+                kind: QueueKind::Unacked,
+                depth: 4
+            }]
+        );
+
+        // The server acks all of them
+        for seqnum in SEQNUM_INITIAL..SEQNUM_INITIAL + 4 {
+            let pkt = Packet::new(1, 0, AckBody::new(seqnum).into_inner().into_unreliable());
+            let mut ser = VecSerializer::new(ProtocolContext::latest_for_send(false), 512);
+            Packet::serialize(&pkt, &mut ser).unwrap();
+            io.send(&ser.take());
+        }
+        while peer.channel_load()[0].unacked > 0 {
+            peer.load.changed().await.unwrap();
+        }
+        assert!(matches!(
+            peer.congestion_events()[..],
+            [CongestionEvent::Recovered {
+                channel: 0,
+                kind: QueueKind::Unacked,
+                ..
+            }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn keepalive_and_timeout() {
+        let (to_socket, mut from_peer) = channel(1024);
+        let (mut peer, _io) = new_peer("127.0.0.1:30000".parse().unwrap(), true, to_socket);
+        peer.set_keepalive(KeepaliveConfig {
+            ping_interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(200),
+        })
+        .unwrap();
+        let context = ProtocolContext::latest_for_receive(false);
+        loop {
+            let Some(PeerToSocket::Send(_, raw)) = from_peer.recv().await else {
+                panic!("expected a packet");
+            };
+            let pkt = Packet::deserialize(&mut Deserializer::new(context, &raw)).unwrap();
+            if pkt.as_control() == Some(&ControlBody::Ping) {
+                assert!(pkt.as_reliable().is_some());
+                break;
+            }
+        }
+        // The server never answers
+        let err = peer.recv().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PeerError>(),
+            Some(PeerError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        use crate::wire::command::TSChatMessageSpec;
+        use crate::wire::packet::SEQNUM_INITIAL;
+
+        let (to_socket, mut from_peer) = channel(1024);
+        let (mut peer, mut io) = new_peer("127.0.0.1:30000".parse().unwrap(), true, to_socket);
+        let chat = TSChatMessageSpec {
+            message: "hi".to_string(),
+        };
+        peer.send(Command::ToServer(chat.into())).await.unwrap();
+        let Some(PeerToSocket::Send(_, chat)) = from_peer.recv().await else {
+            panic!("expected the chat message");
+        };
+
+        // The server acks it, and pings
+        for body in [
+            AckBody::new(SEQNUM_INITIAL).into_inner().into_unreliable(),
+            ControlBody::Ping.into_inner().into_reliable(SEQNUM_INITIAL),
+        ] {
+            let mut ser = VecSerializer::new(ProtocolContext::latest_for_send(false), 512);
+            Packet::serialize(&Packet::new(1, 0, body), &mut ser).unwrap();
+            io.send(&ser.take());
+        }
+        while peer.stats().channels[0].next_receive_seqnum == SEQNUM_INITIAL {
+            peer.stats.changed().await.unwrap();
+        }
+        let stats = peer.stats().channels[0];
+        // The chat message, and an 11 byte ack
+        assert_eq!(
+            (stats.packets_sent, stats.bytes_sent),
+            (2, chat.len() as u64 + 11)
+        );
+        assert_eq!(
+            (stats.packets_received, stats.acks_received, stats.acks_sent),
+            (2, 1, 1)
+        );
+        assert_eq!(stats.next_send_seqnum, SEQNUM_INITIAL + 1);
+        assert_eq!(stats.oldest_unacked_seqnum, None);
+        assert_eq!(stats.retransmits, 0);
+        assert!(stats.rtt.is_some());
+        assert_eq!(peer.stats().total().packets_sent, 2);
+    }
+
+    #[tokio::test]
+    async fn close_flushes() {
+        use crate::wire::command::TSChatMessageSpec;
+        use crate::wire::packet::SEQNUM_INITIAL;
+
+        let (to_socket, mut from_peer) = channel(1024);
+        let (peer, mut io) = new_peer("127.0.0.1:30000".parse().unwrap(), true, to_socket);
+        let chat = TSChatMessageSpec {
+            message: "bye".to_string(),
+        };
+        peer.send(Command::ToServer(chat.into())).await.unwrap();
+        let close = tokio::spawn(peer.close());
+        let context = ProtocolContext::latest_for_receive(false);
+        let mut next_packet = || {
+            let msg = from_peer.try_recv();
+            msg.map(|msg| match msg {
+                PeerToSocket::Send(_, raw) => {
+                    Some(Packet::deserialize(&mut Deserializer::new(context, &raw)).unwrap())
+                }
+                _ => None,
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let chat = next_packet().unwrap().unwrap();
+        assert!(chat.as_reliable().is_some());
+        // Waiting for the ack
+        assert!(next_packet().is_err());
+        assert!(!close.is_finished());
+
+        let ack = Packet::new(
+            1,
+            0,
+            AckBody::new(SEQNUM_INITIAL).into_inner().into_unreliable(),
+        );
+        let mut ser = VecSerializer::new(ProtocolContext::latest_for_send(false), 512);
+        Packet::serialize(&ack, &mut ser).unwrap();
+        io.send(&ser.take());
+        close.await.unwrap().unwrap();
+        let disconnect = next_packet().unwrap().unwrap();
+        assert_eq!(disconnect.as_control(), Some(&ControlBody::Disconnect));
+        assert!(matches!(
+            from_peer.try_recv(),
+            Ok(PeerToSocket::PeerIsDisconnected(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn receive_overflow() {
+        let (to_socket, _from_peer) = channel(1024);
+        let (mut peer, mut io) = new_peer("127.0.0.1:30000".parse().unwrap(), false, to_socket);
+        let datagram = |body: PacketBody| {
+            let pkt = Packet::new(0, 0, body);
+            let mut ser = VecSerializer::new(ProtocolContext::latest_for_send(true), 512);
+            Packet::serialize(&pkt, &mut ser).unwrap();
+            ser.take()
+        };
+        let ping = datagram(ControlBody::Ping.into_inner().into_unreliable());
+        let reliable_ping = datagram(ControlBody::Ping.into_inner().into_reliable(65500));
+        assert!(is_reliable_datagram(&reliable_ping));
+        // The runner doesn't get to run meanwhile
+        for _ in 0..FROM_SOCKET_CAPACITY {
+            assert!(io.send(&ping));
+        }
+        // Dropped
+        assert!(io.send(&ping));
+        assert!(!io.send(&reliable_ping));
+        drop(io);
+        let err = peer.recv().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PeerError>(),
+            Some(PeerError::ReceiveOverflow)
+        ));
+    }
+
+    #[tokio::test]
+    async fn zlib_level() {
+        let (to_socket, _from_peer) = channel(1024);
+        let (mut peer, _io) = new_peer("127.0.0.1:30000".parse().unwrap(), false, to_socket);
+        assert_eq!(peer.send_context().zlib_level, ZLIB_DEFAULT_LEVEL);
+        peer.set_zlib_level(1).unwrap();
+        peer.send_context.changed().await.unwrap();
+        assert_eq!(peer.send_context().zlib_level, 1);
+    }
+}
@@ -0,0 +1,154 @@
+//!
+//! Data that failed to parse
+//!
+//! When a packet, or a command put back together from split packets,
+//! fails to deserialize, the peer disconnects and the controller's recv
+//! fails with a ParseFailure. It has the bytes (up to MAX_CAPTURED_BYTES)
+//! and the protocol context they were parsed in, which is what it takes to
+//! reproduce the bug:
+//!
+//! ```text
+//! if let Some(failure) = err.downcast_ref::<ParseFailure>() {
+//!     let path = failure.quarantine(Path::new("quarantine"))?;
+//!     println!("Bad data saved to {:?}", path);
+//! }
+//! ```
+//!
+//! A quarantined failure is two files: the bytes (.bin), and what is known
+//! about them (.txt).
+//!
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::wire::types::ProtocolContext;
+
+/// Bytes kept of a packet or command that didn't parse
+pub const MAX_CAPTURED_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParsedUnit {
+    /// A whole datagram
+    Packet,
+    /// A command reassembled from split packets
+    SplitCommand,
+}
+
+impl ParsedUnit {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParsedUnit::Packet => "packet",
+            ParsedUnit::SplitCommand => "split command",
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Cannot parse {} from {remote_addr} ({len} bytes, protocol {}, ser_fmt {}): {error:#}", .unit.name(), .context.protocol_version, .context.ser_fmt)]
+pub struct ParseFailure {
+    pub unit: ParsedUnit,
+    pub remote_addr: SocketAddr,
+    /// What the bytes were parsed in
+    pub context: ProtocolContext,
+    /// The first MAX_CAPTURED_BYTES bytes
+    pub data: Vec<u8>,
+    /// The full length
+    pub len: usize,
+    pub error: anyhow::Error,
+}
+
+impl ParseFailure {
+    pub fn new(
+        unit: ParsedUnit,
+        remote_addr: SocketAddr,
+        context: ProtocolContext,
+        data: &[u8],
+        error: anyhow::Error,
+    ) -> Self {
+        Self {
+            unit,
+            remote_addr,
+            context,
+            data: data[..data.len().min(MAX_CAPTURED_BYTES)].to_vec(),
+            len: data.len(),
+            error,
+        }
+    }
+
+    /// True if data is only the start of what was received
+    pub fn is_truncated(&self) -> bool {
+        self.data.len() < self.len
+    }
+
+    /// Writes the failure into `dir` (made if missing), as a .bin with the
+    /// bytes and a .txt describing them. Returns the path of the .bin.
+    pub fn quarantine(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let unit = match self.unit {
+            ParsedUnit::Packet => "packet",
+            ParsedUnit::SplitCommand => "command",
+        };
+        let addr = self.remote_addr.to_string().replace([':', '[', ']'], "_");
+        let stem = format!("{}-{}-{}", millis, addr, unit);
+        let bin = dir.join(format!("{}.bin", stem));
+        std::fs::write(&bin, &self.data)?;
+        std::fs::write(dir.join(format!("{}.txt", stem)), self.describe())?;
+        Ok(bin)
+    }
+
+    /// The .txt of quarantine
+    pub fn describe(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "{}", self);
+        let _ = writeln!(text);
+        let _ = writeln!(text, "unit: {}", self.unit.name());
+        let _ = writeln!(text, "remote: {}", self.remote_addr);
+        let _ = writeln!(text, "context: {:?}", self.context);
+        let _ = writeln!(text, "length: {}", self.len);
+        if self.is_truncated() {
+            let _ = writeln!(text, "captured: first {} bytes", self.data.len());
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::Command;
+    use crate::wire::deser::Deserialize;
+    use crate::wire::deser::Deserializer;
+
+    #[test]
+    fn quarantine_files() {
+        let context = ProtocolContext::latest_for_receive(false);
+        let data = vec![0xff; MAX_CAPTURED_BYTES + 10];
+        let error = Command::deserialize(&mut Deserializer::new(context, &data)).unwrap_err();
+        let failure = ParseFailure::new(
+            ParsedUnit::SplitCommand,
+            "[::1]:30000".parse().unwrap(),
+            context,
+            &data,
+            error,
+        );
+        assert!(failure.is_truncated());
+        assert!(failure
+            .to_string()
+            .starts_with("Cannot parse split command from [::1]:30000 (65546 bytes, protocol"));
+
+        let dir = std::env::temp_dir().join(format!("mt-quarantine-{}", std::process::id()));
+        let bin = failure.quarantine(&dir).unwrap();
+        assert!(bin.to_str().unwrap().ends_with("-___1__30000-command.bin"));
+        assert_eq!(std::fs::read(&bin).unwrap().len(), MAX_CAPTURED_BYTES);
+        let txt = std::fs::read_to_string(bin.with_extension("txt")).unwrap();
+        assert!(txt.contains("\ncaptured: first 65536 bytes\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
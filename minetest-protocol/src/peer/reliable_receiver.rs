@@ -34,6 +34,11 @@ impl ReliableReceiver {
         }
     }
 
+    /// The seqnum expected next from remote
+    pub fn next_seqnum(&self) -> u16 {
+        self.next_seqnum as u16
+    }
+
     // Pull a single body to be processed, from the reliable stream.
     // These are guaranteed to be in the same order as they were sent.
     // This should be called until exhaustion, after a push.
@@ -34,6 +34,11 @@ pub struct ReliableSender {
     // seq num -> packet
     buffer: BTreeMap<u64, PacketBody>,
 
+    // When packets in the buffer were sent, while they've only been sent
+    // once. An ack for a resent packet can't tell which send it answers.
+    sent_at: BTreeMap<u64, Instant>,
+    retransmits: u64,
+
     // TODO(paradust): Use a better data structure for this
     timeouts: BTreeSet<(Instant, u64)>,
     resend_timeout: Duration,
@@ -45,21 +50,27 @@ impl ReliableSender {
             next_seqnum: SEQNUM_INITIAL as u64,
             window_size: START_RELIABLE_WINDOW_SIZE,
             buffer: BTreeMap::new(),
+            sent_at: BTreeMap::new(),
+            retransmits: 0,
             timeouts: BTreeSet::new(),
             resend_timeout: Duration::from_millis(RESEND_TIMEOUT_START_MS),
             queued: VecDeque::new(),
         }
     }
 
-    pub fn process_ack(&mut self, ack: AckBody) {
+    /// Returns the round trip time, if the acked packet was only sent once
+    pub fn process_ack(&mut self, ack: AckBody, now: Instant) -> Option<Duration> {
         let unacked_base = match self.oldest_unacked() {
             Some(unacked_base) => unacked_base,
             None => {
-                return;
+                return None;
             }
         };
         let seqnum = rel_to_abs(unacked_base, ack.seqnum);
         self.buffer.remove(&seqnum);
+        self.sent_at
+            .remove(&seqnum)
+            .map(|sent_at| now.saturating_duration_since(sent_at))
     }
 
     /// Push a packet for reliable send.
@@ -70,6 +81,34 @@ impl ReliableSender {
         self.queued.push_back((seqnum, body));
     }
 
+    /// Packets sent but not yet acked
+    pub fn unacked(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Packets waiting for room in the window
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn window_size(&self) -> u16 {
+        self.window_size
+    }
+
+    /// Packets sent again, for lack of an ack
+    pub fn retransmits(&self) -> u64 {
+        self.retransmits
+    }
+
+    /// The seqnum the next pushed packet gets
+    pub fn next_seqnum(&self) -> u16 {
+        self.next_seqnum as u16
+    }
+
+    pub fn oldest_unacked_seqnum(&self) -> Option<u16> {
+        self.oldest_unacked().map(|seqnum| seqnum as u16)
+    }
+
     fn oldest_unacked(&self) -> Option<u64> {
         self.buffer.first_key_value().map(|(seqnum, _)| *seqnum)
     }
@@ -82,10 +121,9 @@ impl ReliableSender {
     }
 
     pub fn next_timeout(&self) -> Option<Instant> {
-        match self.timeouts.first() {
-            Some((when, _)) => Some(*when + RESEND_RESOLUTION),
-            None => None,
-        }
+        self.timeouts
+            .first()
+            .map(|(when, _)| *when + RESEND_RESOLUTION)
     }
 
     /// Pop a single packet for immediate transmission.
@@ -116,6 +154,7 @@ impl ReliableSender {
         match self.queued.pop_front() {
             Some((seqnum, b)) => {
                 self.buffer.insert(seqnum, PacketBody::clone(&b));
+                self.sent_at.insert(seqnum, now);
                 self.timeouts.insert((now + self.resend_timeout, seqnum));
                 Some(b)
             }
@@ -138,6 +177,8 @@ impl ReliableSender {
                     } else if expire_time <= now {
                         // Ready to resend
                         let body = self.buffer.get(&seqnum).unwrap().clone();
+                        self.sent_at.remove(&seqnum);
+                        self.retransmits += 1;
                         // Schedule future resend
                         self.timeouts.insert((now + self.resend_timeout, seqnum));
                         return Some(body);
@@ -239,7 +280,7 @@ mod tests {
                 }
 
                 // Transmission window should never exceed MAX_RELIABLE_WINDOW_SIZE
-                if let Some(oldest_unacked_index) = sent_but_unacked.first().map(|v| *v) {
+                if let Some(oldest_unacked_index) = sent_but_unacked.first().copied() {
                     assert!(
                         recovered_index >= oldest_unacked_index,
                         "Resending already acknowledged packet"
@@ -263,7 +304,7 @@ mod tests {
 
             // Send the acks
             for seqnum in send_ack_now.into_iter() {
-                r.process_ack(AckBody { seqnum });
+                r.process_ack(AckBody { seqnum }, now);
             }
 
             // If we're given a timeout, simulate sleeping until the timeout 50% of the time.
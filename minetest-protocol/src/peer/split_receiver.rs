@@ -24,7 +24,6 @@ impl IncomingBuffer {
 
     /// Push a new split packet into the split receiver
     /// If a command has become ready as a result, true is returned.
-    #[must_use]
     fn push(&mut self, now: Instant, body: SplitBody) -> anyhow::Result<bool> {
         if body.chunk_count != self.chunk_count {
             bail!("Split packet corrupt: chunk_count mismatch");
@@ -37,20 +36,19 @@ impl IncomingBuffer {
         }
     }
 
-    fn take(self) -> anyhow::Result<Vec<u8>> {
+    /// The chunks of the command, in order
+    fn take(self) -> anyhow::Result<Vec<Vec<u8>>> {
         assert!(self.chunks.len() == self.chunk_count as usize);
-        let total_size: usize = self.chunks.iter().map(|v| v.1.len()).sum();
-        let mut buf = Vec::with_capacity(total_size);
-        for (_, chunk) in self.chunks.iter() {
-            buf.extend_from_slice(&chunk);
-        }
-        assert!(buf.len() == total_size);
-        Ok(buf)
+        Ok(self.chunks.into_values().collect())
     }
 }
 
+/// Reliable and unreliable split packets are reassembled separately,
+/// keyed by (reliable, seqnum). The sender numbers both from one counter,
+/// but an unreliable command that lost a chunk is never completed, and
+/// must not collide with a later command once the u16 seqnum wraps.
 pub struct SplitReceiver {
-    pending: HashMap<u16, IncomingBuffer>,
+    pending: HashMap<(bool, u16), IncomingBuffer>,
 }
 
 impl SplitReceiver {
@@ -61,20 +59,102 @@ impl SplitReceiver {
     }
 
     /// Push a split packet for reconstruction
-    /// Returns the finished command if it is ready
-    #[must_use]
-    pub fn push(&mut self, now: Instant, body: SplitBody) -> anyhow::Result<Option<Vec<u8>>> {
-        let seqnum = body.seqnum;
+    /// Returns the chunks of the finished command if it is ready.
+    /// They are not concatenated, use a ChainedBuffer to parse them.
+    pub fn push(
+        &mut self,
+        now: Instant,
+        reliable: bool,
+        body: SplitBody,
+    ) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+        // Incomplete unreliable commands are abandoned after a timeout,
+        // like the engine does.
+        self.pending
+            .retain(|(reliable, _), buf| *reliable || buf.timeout > now);
+
+        let key = (reliable, body.seqnum);
+        if !reliable {
+            // Left over from a command with a lost chunk
+            if let Some(buf) = self.pending.get(&key) {
+                if buf.chunk_count != body.chunk_count {
+                    self.pending.remove(&key);
+                }
+            }
+        }
         let should_take = self
             .pending
-            .entry(seqnum)
+            .entry(key)
             .or_insert_with(|| IncomingBuffer::new(now, body.chunk_count))
             .push(now, body)?;
 
         if should_take {
-            Ok(Some(self.pending.remove(&seqnum).unwrap().take()?))
+            Ok(Some(self.pending.remove(&key).unwrap().take()?))
         } else {
             Ok(None)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(seqnum: u16, chunk_count: u16, chunk_num: u16) -> SplitBody {
+        SplitBody {
+            seqnum,
+            chunk_count,
+            chunk_num,
+            chunk_data: vec![chunk_num as u8],
+        }
+    }
+
+    #[test]
+    fn reliable_and_unreliable_dont_collide() {
+        let now = Instant::now();
+        let mut recv = SplitReceiver::new();
+        assert!(recv.push(now, false, chunk(7, 2, 0)).unwrap().is_none());
+        assert!(recv.push(now, true, chunk(7, 3, 0)).unwrap().is_none());
+        assert!(recv.push(now, true, chunk(7, 3, 1)).unwrap().is_none());
+        assert_eq!(
+            recv.push(now, false, chunk(7, 2, 1)).unwrap(),
+            Some(vec![vec![0], vec![1]])
+        );
+        assert!(recv.push(now, true, chunk(7, 3, 2)).unwrap().is_some());
+        assert!(recv.pending.is_empty());
+    }
+
+    #[test]
+    fn stale_unreliable_after_wraparound() {
+        let now = Instant::now();
+        let mut recv = SplitReceiver::new();
+        // A chunk is lost, and 65536 split commands later the seqnum is reused
+        let seqnum = 65500;
+        assert!(recv
+            .push(now, false, chunk(seqnum, 2, 0))
+            .unwrap()
+            .is_none());
+        assert!(recv
+            .push(now, false, chunk(seqnum, 3, 0))
+            .unwrap()
+            .is_none());
+        assert!(recv
+            .push(now, false, chunk(seqnum, 3, 1))
+            .unwrap()
+            .is_none());
+        assert!(recv
+            .push(now, false, chunk(seqnum, 3, 2))
+            .unwrap()
+            .is_some());
+
+        // Or times out
+        assert!(recv.push(now, false, chunk(1, 2, 0)).unwrap().is_none());
+        assert!(recv.push(now, true, chunk(2, 2, 0)).unwrap().is_none());
+        let later = now + SPLIT_TIMEOUT + Duration::from_secs(1);
+        assert!(recv.push(later, false, chunk(1, 2, 1)).unwrap().is_none());
+        assert!(recv.push(later, true, chunk(2, 2, 1)).unwrap().is_some());
+
+        // Reliable chunks can't be lost, so a mismatch is still an error
+        assert!(recv.push(now, true, chunk(3, 2, 0)).unwrap().is_none());
+        assert!(recv.push(now, true, chunk(3, 3, 1)).is_err());
+    }
+}
@@ -5,11 +5,13 @@ use crate::wire::packet::SplitBody;
 use crate::wire::packet::MAX_ORIGINAL_BODY_SIZE;
 use crate::wire::packet::MAX_SPLIT_BODY_SIZE;
 use crate::wire::packet::SEQNUM_INITIAL;
-use crate::wire::ser::MockSerializer;
 use crate::wire::ser::Serialize;
 use crate::wire::ser::VecSerializer;
 use crate::wire::types::ProtocolContext;
 
+/// Split seqnums come from a single counter per channel, for reliable and
+/// unreliable commands alike. The engine's receiver keys split packets by
+/// seqnum only, so separate counters would collide there.
 pub struct SplitSender {
     next_seqnum: u64,
 }
@@ -23,16 +25,16 @@ impl SplitSender {
 
     /// Push a Command for transmission
     /// This will possibly split it into 1 or more packets.
-    #[must_use]
+    /// `size` is its serialized size in `context`, if known already.
     pub fn push(
         &mut self,
         context: ProtocolContext,
         command: Command,
+        size: Option<usize>,
     ) -> anyhow::Result<Vec<InnerBody>> {
-        let total_size = {
-            let mut ser = MockSerializer::new(context);
-            Command::serialize(&command, &mut ser)?;
-            ser.len()
+        let total_size = match size {
+            Some(size) => size,
+            None => command.check_serialize(context)?,
         };
         let mut result = Vec::new();
         // Packets should serialize to at most 512 bytes
@@ -47,7 +49,7 @@ impl SplitSender {
             assert!(data.len() == total_size);
             let mut index: usize = 0;
             let mut offset: usize = 0;
-            let total_chunks: usize = (total_size + MAX_SPLIT_BODY_SIZE - 1) / MAX_SPLIT_BODY_SIZE;
+            let total_chunks: usize = total_size.div_ceil(MAX_SPLIT_BODY_SIZE);
             while offset < total_size {
                 let end = std::cmp::min(offset + MAX_SPLIT_BODY_SIZE, total_size);
                 result.push(InnerBody::Split(SplitBody {
@@ -65,3 +67,39 @@ impl SplitSender {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::ShowFormspecSpec;
+
+    fn seqnums(bodies: &[InnerBody]) -> Vec<u16> {
+        bodies
+            .iter()
+            .map(|body| match body {
+                InnerBody::Split(split) => split.seqnum,
+                _ => panic!("expected split"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seqnum_wraps() {
+        let context = ProtocolContext::latest_for_send(false);
+        let command = Command::ToClient(
+            ShowFormspecSpec {
+                form_spec: "x".repeat(2000),
+                form_name: String::new(),
+            }
+            .into(),
+        );
+        let mut sender = SplitSender::new();
+        sender.next_seqnum = u16::MAX as u64;
+        let first = sender.push(context, command.clone(), None).unwrap();
+        assert_eq!(seqnums(&first), vec![u16::MAX; first.len()]);
+        let size = command.check_serialize(context).unwrap();
+        let second = sender.push(context, command, Some(size)).unwrap();
+        assert_eq!(seqnums(&second), vec![0; second.len()]);
+        assert!(second.len() > 1);
+    }
+}
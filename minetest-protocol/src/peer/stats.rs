@@ -0,0 +1,114 @@
+//!
+//! Connection statistics
+//!
+//! Counters kept by the peer runner, published as a ConnectionStats
+//! snapshot every time it wakes up (see Peer::stats):
+//!
+//! ```text
+//! let stats = conn.stats();
+//! println!("rtt {:?}, {} retransmits", stats.rtt(), stats.total().retransmits);
+//! ```
+//!
+//! Packets and bytes are whole datagrams, headers included. Acks are
+//! counted as packets too.
+//!
+use std::time::Duration;
+
+/// Weight of a new round trip sample in the smoothed RTT, like TCP's
+const RTT_GAIN: f64 = 0.125;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ChannelStats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Reliable packets sent again, for lack of an ack in time
+    pub retransmits: u64,
+    pub acks_sent: u64,
+    /// Includes duplicates, and acks of resent packets
+    pub acks_received: u64,
+    /// Commands put back together from split packets
+    pub split_reassemblies: u64,
+    /// The seqnum the next reliable packet is sent with
+    pub next_send_seqnum: u16,
+    /// The oldest reliable packet not acked yet, if any
+    pub oldest_unacked_seqnum: Option<u16>,
+    /// The reliable seqnum expected next from the peer
+    pub next_receive_seqnum: u16,
+    /// Reliable packets that may be unacked at once
+    pub window: u16,
+    /// Smoothed round trip time, from the acks of packets sent only once.
+    /// None until one was acked.
+    pub rtt: Option<Duration>,
+}
+
+impl ChannelStats {
+    /// Folds a round trip sample into `rtt`
+    pub(super) fn add_rtt_sample(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt.mul_f64(1.0 - RTT_GAIN) + sample.mul_f64(RTT_GAIN),
+            None => sample,
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ConnectionStats {
+    pub channels: [ChannelStats; 3],
+}
+
+impl ConnectionStats {
+    /// The counters summed over the channels. The seqnums and the window
+    /// are left at zero, and the RTT is rtt().
+    pub fn total(&self) -> ChannelStats {
+        let mut total = ChannelStats::default();
+        for channel in &self.channels {
+            total.packets_sent += channel.packets_sent;
+            total.bytes_sent += channel.bytes_sent;
+            total.packets_received += channel.packets_received;
+            total.bytes_received += channel.bytes_received;
+            total.retransmits += channel.retransmits;
+            total.acks_sent += channel.acks_sent;
+            total.acks_received += channel.acks_received;
+            total.split_reassemblies += channel.split_reassemblies;
+        }
+        total.rtt = self.rtt();
+        total
+    }
+
+    /// The mean of the channels' RTTs
+    pub fn rtt(&self) -> Option<Duration> {
+        let rtts: Vec<Duration> = self
+            .channels
+            .iter()
+            .filter_map(|channel| channel.rtt)
+            .collect();
+        if rtts.is_empty() {
+            None
+        } else {
+            Some(rtts.iter().sum::<Duration>() / rtts.len() as u32)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_and_rtt() {
+        let mut stats = ConnectionStats::default();
+        assert_eq!(stats.rtt(), None);
+        stats.channels[0].packets_sent = 3;
+        stats.channels[2].packets_sent = 4;
+        stats.channels[1].retransmits = 1;
+        stats.channels[0].add_rtt_sample(Duration::from_millis(100));
+        stats.channels[0].add_rtt_sample(Duration::from_millis(180));
+        assert_eq!(stats.channels[0].rtt, Some(Duration::from_millis(110)));
+        stats.channels[1].add_rtt_sample(Duration::from_millis(50));
+        let total = stats.total();
+        assert_eq!((total.packets_sent, total.retransmits), (7, 1));
+        assert_eq!(total.rtt, Some(Duration::from_millis(80)));
+    }
+}
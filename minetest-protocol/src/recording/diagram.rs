@@ -0,0 +1,260 @@
+//!
+//! Sequence diagrams of recordings
+//!
+//! Turns a recorded session into a Mermaid or PlantUML sequence diagram,
+//! with the command names and when they were seen:
+//!
+//! ```text
+//! sequenceDiagram
+//!     participant Client
+//!     participant Proxy
+//!     participant Server
+//!     Client->>Proxy: Init (+0.0 ms)
+//!     Proxy->>Server: Init
+//!     Server->>Proxy: Hello (+12.5 ms)
+//!     Proxy->>Client: Hello
+//!     Server->>Proxy: Blockdata ×214 (+310.2 ms … +2.41 s)
+//!     Proxy->>Client: Blockdata ×214
+//! ```
+//!
+//! Times are relative to the start of the recording, when the recorder
+//! (e.g. mtshark) received the command. A run of the same command in the
+//! same direction is collapsed into one arrow, or there would be thousands
+//! of Blockdata arrows.
+//!
+use std::fmt::Write as _;
+use std::io::Read;
+use std::io::Write;
+
+use super::format::RecordingReader;
+use crate::wire::command::CommandProperties;
+use crate::wire::types::CommandDirection;
+use anyhow::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiagramFormat {
+    Mermaid,
+    PlantUml,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagramOptions {
+    pub format: DiagramFormat,
+    /// Show the recorder as a Proxy between client and server, which
+    /// every command passes through
+    pub proxy: bool,
+    /// Collapse runs of the same command (see the module docs)
+    pub collapse: bool,
+}
+
+impl Default for DiagramOptions {
+    fn default() -> Self {
+        Self {
+            format: DiagramFormat::Mermaid,
+            proxy: true,
+            collapse: true,
+        }
+    }
+}
+
+/// Commands of one arrow
+struct Run {
+    dir: CommandDirection,
+    name: &'static str,
+    count: usize,
+    first_us: u64,
+    last_us: u64,
+}
+
+/// Writes the diagram of a recording. Returns the number of arrows between
+/// client and server (one per command, or per run when collapsing).
+pub fn sequence_diagram<R: Read, W: Write>(
+    input: R,
+    mut output: W,
+    options: &DiagramOptions,
+) -> Result<usize> {
+    let mut reader = RecordingReader::new(input)?;
+    let mut diagram = String::new();
+    let participants: &[&str] = if options.proxy {
+        &["Client", "Proxy", "Server"]
+    } else {
+        &["Client", "Server"]
+    };
+    match options.format {
+        DiagramFormat::Mermaid => diagram.push_str("sequenceDiagram\n"),
+        DiagramFormat::PlantUml => diagram.push_str("@startuml\n"),
+    }
+    for participant in participants {
+        writeln!(diagram, "    participant {}", participant)?;
+    }
+
+    let mut arrows = 0;
+    let mut run: Option<Run> = None;
+    while let Some((timestamp_us, command)) = reader.next_command()? {
+        let dir = command.direction();
+        let name = command.command_name();
+        if let Some(run) = &mut run {
+            if options.collapse && run.dir == dir && run.name == name {
+                run.count += 1;
+                run.last_us = timestamp_us;
+                continue;
+            }
+        }
+        if let Some(done) = run.take() {
+            write_run(&mut diagram, &done, options)?;
+            arrows += 1;
+        }
+        run = Some(Run {
+            dir,
+            name,
+            count: 1,
+            first_us: timestamp_us,
+            last_us: timestamp_us,
+        });
+    }
+    if let Some(done) = run {
+        write_run(&mut diagram, &done, options)?;
+        arrows += 1;
+    }
+
+    if options.format == DiagramFormat::PlantUml {
+        diagram.push_str("@enduml\n");
+    }
+    output.write_all(diagram.as_bytes())?;
+    output.flush()?;
+    Ok(arrows)
+}
+
+fn write_run(diagram: &mut String, run: &Run, options: &DiagramOptions) -> Result<()> {
+    let (from, to) = match run.dir {
+        CommandDirection::ToServer => ("Client", "Server"),
+        CommandDirection::ToClient => ("Server", "Client"),
+    };
+    let label = if run.count == 1 {
+        run.name.to_string()
+    } else {
+        format!("{} ×{}", run.name, run.count)
+    };
+    let time = if run.count == 1 {
+        format!("+{}", format_time(run.first_us))
+    } else {
+        format!(
+            "+{} … +{}",
+            format_time(run.first_us),
+            format_time(run.last_us)
+        )
+    };
+    let timed = format!("{} ({})", label, time);
+    if options.proxy {
+        write_arrow(diagram, options.format, from, "Proxy", &timed)?;
+        write_arrow(diagram, options.format, "Proxy", to, &label)?;
+    } else {
+        write_arrow(diagram, options.format, from, to, &timed)?;
+    }
+    Ok(())
+}
+
+fn write_arrow(
+    diagram: &mut String,
+    format: DiagramFormat,
+    from: &str,
+    to: &str,
+    label: &str,
+) -> Result<()> {
+    match format {
+        DiagramFormat::Mermaid => writeln!(diagram, "    {}->>{}: {}", from, to, label)?,
+        DiagramFormat::PlantUml => writeln!(diagram, "    {} -> {} : {}", from, to, label)?,
+    }
+    Ok(())
+}
+
+/// Milliseconds under 10 seconds, seconds after that
+fn format_time(us: u64) -> String {
+    if us < 10_000_000 {
+        format!("{:.1} ms", us as f64 / 1000.0)
+    } else {
+        format!("{:.2} s", us as f64 / 1_000_000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::RecordingWriter;
+    use crate::wire::command::Command;
+    use crate::wire::command::TCChatMessageSpec;
+    use crate::wire::command::TSChatMessageSpec;
+    use crate::wire::command::TimeOfDaySpec;
+    use crate::wire::packet::LATEST_PROTOCOL_VERSION;
+    use crate::wire::packet::SER_FMT_HIGHEST_WRITE;
+
+    #[test]
+    fn mermaid_and_plantuml() {
+        let mut writer =
+            RecordingWriter::new(Vec::new(), LATEST_PROTOCOL_VERSION, SER_FMT_HIGHEST_WRITE)
+                .unwrap();
+        let chat = Command::ToServer(
+            TSChatMessageSpec {
+                message: "hi".to_string(),
+            }
+            .into(),
+        );
+        writer.write_command_at(0, &chat).unwrap();
+        for i in 0..3 {
+            let time = Command::ToClient(
+                TimeOfDaySpec {
+                    time_of_day: i,
+                    time_speed: Some(72.0),
+                }
+                .into(),
+            );
+            writer
+                .write_command_at(1500 + i as u64 * 1000, &time)
+                .unwrap();
+        }
+        let reply = Command::ToClient(
+            TCChatMessageSpec {
+                version: 1,
+                message_type: 0,
+                sender: String::new(),
+                message: "hello".to_string(),
+                timestamp: 0,
+            }
+            .into(),
+        );
+        writer.write_command_at(12_345_678, &reply).unwrap();
+        let recording = writer.into_inner();
+
+        let mut out = Vec::new();
+        let arrows =
+            sequence_diagram(&recording[..], &mut out, &DiagramOptions::default()).unwrap();
+        assert_eq!(arrows, 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "sequenceDiagram
+    participant Client
+    participant Proxy
+    participant Server
+    Client->>Proxy: TSChatMessage (+0.0 ms)
+    Proxy->>Server: TSChatMessage
+    Server->>Proxy: TimeOfDay ×3 (+1.5 ms … +3.5 ms)
+    Proxy->>Client: TimeOfDay ×3
+    Server->>Proxy: TCChatMessage (+12.35 s)
+    Proxy->>Client: TCChatMessage
+"
+        );
+
+        let options = DiagramOptions {
+            format: DiagramFormat::PlantUml,
+            proxy: false,
+            collapse: false,
+        };
+        let mut out = Vec::new();
+        let arrows = sequence_diagram(&recording[..], &mut out, &options).unwrap();
+        assert_eq!(arrows, 5);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("@startuml\n    participant Client\n    participant Server\n"));
+        assert!(out.contains("    Server -> Client : TimeOfDay (+2.5 ms)\n"));
+        assert!(out.ends_with("@enduml\n"));
+    }
+}
@@ -0,0 +1,588 @@
+//!
+//! Recording container format
+//!
+//! All integers are big-endian, like the rest of the Minetest protocol.
+//!
+//! ```text
+//! magic             8 bytes    b"MTRECORD"
+//! format_major      u16
+//! format_minor      u16
+//! header_len        u32        length of the header body that follows
+//! header body
+//!   crate_version     u16 length + UTF-8
+//!   protocol_version  u16
+//!   ser_fmt           u8
+//!   start_time_us     u64        microseconds since the unix epoch
+//!
+//! zero or more records
+//!   kind              u8
+//!   length            u32        length of the payload
+//!   payload
+//! ```
+//!
+//! Record kinds:
+//!
+//! ```text
+//! 1  Command   timestamp_us u64, direction u8 (0=ToClient, 1=ToServer),
+//!              wire_len u32, wire bytes
+//! 2  Context   timestamp_us u64, protocol_version u16, ser_fmt u8
+//! ```
+//!
+//! Record timestamps are microseconds since `start_time_us`.
+//!
+//! Compatibility rules:
+//!
+//! * Commands are stored in their Minetest wire encoding, never in a
+//!   Rust-specific form. They stay decodable for as long as this crate
+//!   supports the protocol version they were recorded with, no matter how
+//!   the Rust types evolve.
+//! * A reader accepts any recording with the same `format_major`. A newer
+//!   `format_minor` may append fields to the header body, append fields to
+//!   the payload of existing record kinds, or introduce new record kinds.
+//!   Readers ignore trailing header/payload bytes they don't understand, and
+//!   surface unknown record kinds as `Record::Unknown`.
+//! * Any other change requires bumping `format_major`; readers reject
+//!   recordings with a major version they don't know.
+//!
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::wire::command::serialize_commandref;
+use crate::wire::command::Command;
+use crate::wire::command::CommandRef;
+use crate::wire::deser::Deserialize;
+use crate::wire::deser::Deserializer;
+use crate::wire::ser::VecSerializer;
+use crate::wire::types::CommandDirection;
+use crate::wire::types::ProtocolContext;
+use crate::wire::types::TextFormatPolicy;
+use crate::wire::util::ZLIB_DEFAULT_LEVEL;
+use anyhow::bail;
+use anyhow::Result;
+
+pub const RECORDING_MAGIC: &[u8; 8] = b"MTRECORD";
+pub const RECORDING_FORMAT_MAJOR: u16 = 1;
+pub const RECORDING_FORMAT_MINOR: u16 = 0;
+
+pub const RECORD_KIND_COMMAND: u8 = 1;
+pub const RECORD_KIND_CONTEXT: u8 = 2;
+
+/// Upper bound on a single header or record, to avoid allocating
+/// absurd amounts of memory when reading a corrupt file.
+const MAX_RECORD_SIZE: u32 = 256 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecordingError {
+    #[error("Not a recording (bad magic)")]
+    BadMagic,
+    #[error("Unsupported recording format {0}.{1}")]
+    UnsupportedVersion(u16, u16),
+    #[error("Recording is truncated")]
+    Truncated,
+    #[error("Invalid direction in command record: {0}")]
+    InvalidDirection(u8),
+    #[error("Record too large: {0} bytes")]
+    RecordTooLarge(u32),
+    #[error("Malformed record: {0}")]
+    Malformed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingHeader {
+    pub format_major: u16,
+    pub format_minor: u16,
+    /// Version of minetest-protocol that made the recording
+    pub crate_version: String,
+    /// Protocol version at the start of the recording.
+    /// Context records may change it later on.
+    pub protocol_version: u16,
+    pub ser_fmt: u8,
+    pub start_time_us: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandRecord {
+    pub timestamp_us: u64,
+    pub dir: CommandDirection,
+    /// The command in wire format (starting with the u16 command id)
+    pub data: Vec<u8>,
+}
+
+impl CommandRecord {
+    pub fn decode(&self, protocol_version: u16, ser_fmt: u8) -> Result<Command> {
+        let context = ProtocolContext {
+            dir: self.dir,
+            protocol_version,
+            ser_fmt,
+            text_format: TextFormatPolicy::default(),
+            zlib_level: ZLIB_DEFAULT_LEVEL,
+            audit: false,
+        };
+        let mut deser = Deserializer::new(context, &self.data);
+        Command::deserialize(&mut deser)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Command(CommandRecord),
+    Context {
+        timestamp_us: u64,
+        protocol_version: u16,
+        ser_fmt: u8,
+    },
+    /// A record kind introduced by a newer minor version of the format.
+    Unknown {
+        kind: u8,
+        payload: Vec<u8>,
+    },
+}
+
+fn dir_to_u8(dir: CommandDirection) -> u8 {
+    match dir {
+        CommandDirection::ToClient => 0,
+        CommandDirection::ToServer => 1,
+    }
+}
+
+fn dir_from_u8(value: u8) -> Result<CommandDirection> {
+    match value {
+        0 => Ok(CommandDirection::ToClient),
+        1 => Ok(CommandDirection::ToServer),
+        _ => bail!(RecordingError::InvalidDirection(value)),
+    }
+}
+
+pub struct RecordingWriter<W: Write> {
+    out: W,
+    started: Instant,
+    protocol_version: u16,
+    ser_fmt: u8,
+}
+
+impl<W: Write> RecordingWriter<W> {
+    /// Start a new recording. The header is written immediately.
+    pub fn new(mut out: W, protocol_version: u16, ser_fmt: u8) -> Result<Self> {
+        let start_time_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        let crate_version = env!("CARGO_PKG_VERSION");
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&u16::try_from(crate_version.len())?.to_be_bytes());
+        body.extend_from_slice(crate_version.as_bytes());
+        body.extend_from_slice(&protocol_version.to_be_bytes());
+        body.push(ser_fmt);
+        body.extend_from_slice(&start_time_us.to_be_bytes());
+
+        out.write_all(RECORDING_MAGIC)?;
+        out.write_all(&RECORDING_FORMAT_MAJOR.to_be_bytes())?;
+        out.write_all(&RECORDING_FORMAT_MINOR.to_be_bytes())?;
+        out.write_all(&u32::try_from(body.len())?.to_be_bytes())?;
+        out.write_all(&body)?;
+        Ok(Self {
+            out,
+            started: Instant::now(),
+            protocol_version,
+            ser_fmt,
+        })
+    }
+
+    /// Microseconds since the recording started
+    pub fn elapsed_us(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    fn write_record(&mut self, kind: u8, payload: &[u8]) -> Result<()> {
+        let length = u32::try_from(payload.len())?;
+        if length > MAX_RECORD_SIZE {
+            bail!(RecordingError::RecordTooLarge(length));
+        }
+        self.out.write_all(&[kind])?;
+        self.out.write_all(&length.to_be_bytes())?;
+        self.out.write_all(payload)?;
+        Ok(())
+    }
+
+    /// Record a command, timestamped now.
+    pub fn write_command<Cmd: CommandRef>(&mut self, command: &Cmd) -> Result<()> {
+        let timestamp_us = self.elapsed_us();
+        self.write_command_at(timestamp_us, command)
+    }
+
+    pub fn write_command_at<Cmd: CommandRef>(
+        &mut self,
+        timestamp_us: u64,
+        command: &Cmd,
+    ) -> Result<()> {
+        let context = ProtocolContext {
+            dir: command.direction(),
+            protocol_version: self.protocol_version,
+            ser_fmt: self.ser_fmt,
+            text_format: TextFormatPolicy::default(),
+            zlib_level: ZLIB_DEFAULT_LEVEL,
+            audit: false,
+        };
+        let mut ser = VecSerializer::new(context, 1024);
+        serialize_commandref(command, &mut ser)?;
+        self.write_raw(timestamp_us, context.dir, &ser.take())
+    }
+
+    /// Record a command that is already in wire format.
+    pub fn write_raw(
+        &mut self,
+        timestamp_us: u64,
+        dir: CommandDirection,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut payload = Vec::with_capacity(13 + data.len());
+        payload.extend_from_slice(&timestamp_us.to_be_bytes());
+        payload.push(dir_to_u8(dir));
+        payload.extend_from_slice(&u32::try_from(data.len())?.to_be_bytes());
+        payload.extend_from_slice(data);
+        self.write_record(RECORD_KIND_COMMAND, &payload)
+    }
+
+    /// Change the protocol context used for subsequent commands.
+    /// Nothing is written if the context did not change.
+    pub fn set_context(&mut self, protocol_version: u16, ser_fmt: u8) -> Result<()> {
+        let timestamp_us = self.elapsed_us();
+        self.set_context_at(timestamp_us, protocol_version, ser_fmt)
+    }
+
+    pub fn set_context_at(
+        &mut self,
+        timestamp_us: u64,
+        protocol_version: u16,
+        ser_fmt: u8,
+    ) -> Result<()> {
+        if protocol_version == self.protocol_version && ser_fmt == self.ser_fmt {
+            return Ok(());
+        }
+        self.protocol_version = protocol_version;
+        self.ser_fmt = ser_fmt;
+        let mut payload = Vec::with_capacity(11);
+        payload.extend_from_slice(&timestamp_us.to_be_bytes());
+        payload.extend_from_slice(&protocol_version.to_be_bytes());
+        payload.push(ser_fmt);
+        self.write_record(RECORD_KIND_CONTEXT, &payload)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Read exactly buf.len() bytes.
+/// Returns false if the stream was already at EOF (nothing read).
+fn read_exact_or_eof<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => bail!(RecordingError::Truncated),
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(true)
+}
+
+fn read_exact<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<()> {
+    if buf.is_empty() || read_exact_or_eof(input, buf)? {
+        Ok(())
+    } else {
+        bail!(RecordingError::Truncated)
+    }
+}
+
+fn read_sized<R: Read>(input: &mut R, length: u32) -> Result<Vec<u8>> {
+    if length > MAX_RECORD_SIZE {
+        bail!(RecordingError::RecordTooLarge(length));
+    }
+    let mut buf = vec![0u8; length as usize];
+    read_exact(input, &mut buf)?;
+    Ok(buf)
+}
+
+/// Cursor over a header body or record payload.
+/// Fields appended by newer minor versions are left unread.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if count > self.0.len() {
+            bail!(RecordingError::Malformed("field runs past end".to_string()));
+        }
+        let ret;
+        (ret, self.0) = self.0.split_at(count);
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+pub struct RecordingReader<R: Read> {
+    input: R,
+    header: RecordingHeader,
+    protocol_version: u16,
+    ser_fmt: u8,
+}
+
+impl<R: Read> RecordingReader<R> {
+    /// Open a recording, reading and validating its header.
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        if !read_exact_or_eof(&mut input, &mut magic)? || &magic != RECORDING_MAGIC {
+            bail!(RecordingError::BadMagic);
+        }
+        let mut fixed = [0u8; 8];
+        read_exact(&mut input, &mut fixed)?;
+        let format_major = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let format_minor = u16::from_be_bytes([fixed[2], fixed[3]]);
+        if format_major != RECORDING_FORMAT_MAJOR {
+            bail!(RecordingError::UnsupportedVersion(
+                format_major,
+                format_minor
+            ));
+        }
+        let header_len = u32::from_be_bytes(fixed[4..8].try_into().unwrap());
+        let body = read_sized(&mut input, header_len)?;
+        let mut fields = Fields(&body);
+        let version_len = fields.u16()? as usize;
+        let crate_version = std::str::from_utf8(fields.take(version_len)?)?.to_string();
+        let protocol_version = fields.u16()?;
+        let ser_fmt = fields.u8()?;
+        let start_time_us = fields.u64()?;
+        let header = RecordingHeader {
+            format_major,
+            format_minor,
+            crate_version,
+            protocol_version,
+            ser_fmt,
+            start_time_us,
+        };
+        Ok(Self {
+            input,
+            header,
+            protocol_version,
+            ser_fmt,
+        })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    /// The (protocol_version, ser_fmt) in effect at the current position
+    pub fn context(&self) -> (u16, u8) {
+        (self.protocol_version, self.ser_fmt)
+    }
+
+    /// Read the next record, or None at the end of the recording.
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut kind = [0u8; 1];
+        if !read_exact_or_eof(&mut self.input, &mut kind)? {
+            return Ok(None);
+        }
+        let kind = kind[0];
+        let mut length = [0u8; 4];
+        read_exact(&mut self.input, &mut length)?;
+        let payload = read_sized(&mut self.input, u32::from_be_bytes(length))?;
+        let record = match kind {
+            RECORD_KIND_COMMAND => {
+                if payload.len() < 13 {
+                    bail!(RecordingError::Malformed(
+                        "short command record".to_string()
+                    ));
+                }
+                let mut fields = Fields(&payload);
+                let timestamp_us = fields.u64()?;
+                let dir = dir_from_u8(fields.u8()?)?;
+                let wire_len = fields.u32()? as usize;
+                Record::Command(CommandRecord {
+                    timestamp_us,
+                    dir,
+                    data: fields.take(wire_len)?.to_vec(),
+                })
+            }
+            RECORD_KIND_CONTEXT => {
+                let mut fields = Fields(&payload);
+                let timestamp_us = fields.u64()?;
+                let protocol_version = fields.u16()?;
+                let ser_fmt = fields.u8()?;
+                self.protocol_version = protocol_version;
+                self.ser_fmt = ser_fmt;
+                Record::Context {
+                    timestamp_us,
+                    protocol_version,
+                    ser_fmt,
+                }
+            }
+            kind => Record::Unknown { kind, payload },
+        };
+        Ok(Some(record))
+    }
+
+    /// Read and decode the next command, skipping other records.
+    /// Returns (timestamp_us, command), or None at the end of the recording.
+    pub fn next_command(&mut self) -> Result<Option<(u64, Command)>> {
+        while let Some(record) = self.next_record()? {
+            if let Record::Command(record) = record {
+                let command = record.decode(self.protocol_version, self.ser_fmt)?;
+                return Ok(Some((record.timestamp_us, command)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::TSChatMessageSpec;
+    use crate::wire::command::TimeOfDaySpec;
+    use crate::wire::packet::LATEST_PROTOCOL_VERSION;
+    use crate::wire::packet::SER_FMT_HIGHEST_WRITE;
+
+    fn sample_commands() -> Vec<Command> {
+        vec![
+            Command::ToServer(
+                TSChatMessageSpec {
+                    message: "hello".to_string(),
+                }
+                .into(),
+            ),
+            Command::ToClient(
+                TimeOfDaySpec {
+                    time_of_day: 1234,
+                    time_speed: Some(72.0),
+                }
+                .into(),
+            ),
+        ]
+    }
+
+    fn record_sample() -> Vec<u8> {
+        let mut writer =
+            RecordingWriter::new(Vec::new(), LATEST_PROTOCOL_VERSION, SER_FMT_HIGHEST_WRITE)
+                .unwrap();
+        for (i, command) in sample_commands().iter().enumerate() {
+            writer.write_command_at(i as u64 * 1000, command).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = record_sample();
+        let mut reader = RecordingReader::new(data.as_slice()).unwrap();
+        assert_eq!(reader.header().format_major, RECORDING_FORMAT_MAJOR);
+        assert_eq!(reader.header().crate_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(reader.header().protocol_version, LATEST_PROTOCOL_VERSION);
+        let mut commands = Vec::new();
+        while let Some((ts, command)) = reader.next_command().unwrap() {
+            assert_eq!(ts, commands.len() as u64 * 1000);
+            commands.push(command);
+        }
+        assert_eq!(commands, sample_commands());
+    }
+
+    #[test]
+    fn newer_minor_version_is_readable() {
+        let data = record_sample();
+        // Rebuild the file as a hypothetical 1.7 writer would produce it:
+        // an extra header field, an extra field on each command record,
+        // and a record of unknown kind.
+        let header_len = u32::from_be_bytes(data[12..16].try_into().unwrap()) as usize;
+        let mut newer = Vec::new();
+        newer.extend_from_slice(&data[..10]);
+        newer.extend_from_slice(&7u16.to_be_bytes());
+        newer.extend_from_slice(&(header_len as u32 + 3).to_be_bytes());
+        newer.extend_from_slice(&data[16..16 + header_len]);
+        newer.extend_from_slice(b"new");
+        newer.push(200);
+        newer.extend_from_slice(&2u32.to_be_bytes());
+        newer.extend_from_slice(&[1, 2]);
+        let mut records = &data[16 + header_len..];
+        while !records.is_empty() {
+            let length = u32::from_be_bytes(records[1..5].try_into().unwrap()) as usize;
+            let (record, rest) = records.split_at(5 + length);
+            assert_eq!(record[0], RECORD_KIND_COMMAND);
+            newer.push(record[0]);
+            newer.extend_from_slice(&(length as u32 + 2).to_be_bytes());
+            newer.extend_from_slice(&record[5..]);
+            newer.extend_from_slice(b"xt");
+            records = rest;
+        }
+
+        let mut reader = RecordingReader::new(newer.as_slice()).unwrap();
+        assert_eq!(reader.header().format_minor, 7);
+        assert_eq!(
+            reader.next_record().unwrap(),
+            Some(Record::Unknown {
+                kind: 200,
+                payload: vec![1, 2]
+            })
+        );
+        // The wire bytes end where wire_len says, not at the appended field
+        let mut original = RecordingReader::new(data.as_slice()).unwrap();
+        let mut count = 0;
+        while let Some(record) = reader.next_record().unwrap() {
+            assert_eq!(Some(record), original.next_record().unwrap());
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let data = record_sample();
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        assert!(RecordingReader::new(bad_magic.as_slice()).is_err());
+
+        let mut newer_major = data.clone();
+        newer_major[8..10].copy_from_slice(&(RECORDING_FORMAT_MAJOR + 1).to_be_bytes());
+        let err = RecordingReader::new(newer_major.as_slice()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RecordingError>(),
+            Some(RecordingError::UnsupportedVersion(_, _))
+        ));
+
+        let truncated = &data[..data.len() - 1];
+        let mut reader = RecordingReader::new(truncated).unwrap();
+        assert!(reader.next_command().unwrap().is_some());
+        let err = reader.next_command().err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RecordingError>(),
+            Some(RecordingError::Truncated)
+        ));
+    }
+}
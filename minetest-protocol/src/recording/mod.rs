@@ -0,0 +1,30 @@
+//!
+//! Recorded command streams
+//!
+//! A recording is a sequence of Commands captured from a live connection
+//! (for example by mtshark), stored in a stable container format so that
+//! it can be loaded again by later versions of this crate.
+//!
+//! Recordings can be redacted for sharing (redact), and drawn as sequence
+//! diagrams (diagram). For the raw packets, there are packet captures
+//! (pcap). Either can be replayed against a server (see replay).
+//!
+pub mod diagram;
+pub mod format;
+pub mod pcap;
+pub mod redact;
+
+pub use diagram::sequence_diagram;
+pub use diagram::DiagramFormat;
+pub use diagram::DiagramOptions;
+pub use format::CommandRecord;
+pub use format::Record;
+pub use format::RecordingHeader;
+pub use format::RecordingReader;
+pub use format::RecordingWriter;
+pub use pcap::CapturedDatagram;
+pub use pcap::PcapTap;
+pub use pcap::PcapngReader;
+pub use pcap::PcapngWriter;
+pub use redact::NameRedaction;
+pub use redact::Redactor;
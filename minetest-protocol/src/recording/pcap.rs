@@ -0,0 +1,537 @@
+//!
+//! Packet captures
+//!
+//! Writes datagrams to a pcapng file, for Wireshark. Unlike a recording,
+//! this keeps the packets as they were on the wire: acks, resends, split
+//! packets and all. A UDP and IP header is made up for each datagram, so
+//! the capture opens like one taken off a network interface:
+//!
+//! ```text
+//! let tap = Arc::new(PcapTap::new(PcapngWriter::new(File::create("session.pcapng")?)?));
+//! let socket = UdpSocket::bind(bind_addr).await?;
+//! let server = MinetestServer::with_transport(TappedTransport::new(socket, tap))?;
+//! ```
+//!
+//! Timestamps are when the datagram was handed to or taken from the
+//! socket, in microseconds. A socket bound to 0.0.0.0 shows up as such.
+//!
+//! PcapngReader reads the UDP datagrams back, from these captures or from
+//! ones taken off an interface (raw IP or Ethernet, at the default
+//! microsecond resolution), e.g. to replay them (see replay).
+//!
+use anyhow::bail;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::services::transport::DatagramTap;
+use crate::services::transport::TapDirection;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const BYTE_ORDER_MAGIC_SWAPPED: u32 = 0x4D3C2B1A;
+
+/// Packets start with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+/// Upper bound on a block, against corrupt files
+const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
+const IPPROTO_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+pub struct PcapngWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Writes the file header, with a single interface
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut header = Vec::new();
+        header.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        header.extend(1u16.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        // Section length: unknown
+        header.extend((-1i64).to_le_bytes());
+        write_block(&mut out, BLOCK_SECTION_HEADER, &header)?;
+
+        let mut interface = Vec::new();
+        interface.extend(LINKTYPE_RAW.to_le_bytes());
+        interface.extend(0u16.to_le_bytes());
+        // Snap length: none
+        interface.extend(0u32.to_le_bytes());
+        write_block(&mut out, BLOCK_INTERFACE_DESCRIPTION, &interface)?;
+        Ok(Self { out })
+    }
+
+    /// Writes a UDP datagram from `src` to `dst`
+    pub fn write_datagram(
+        &mut self,
+        at: SystemTime,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let packet = ip_packet(src, dst, payload);
+        let micros = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut body = Vec::with_capacity(20 + packet.len() + 3);
+        body.extend(0u32.to_le_bytes());
+        body.extend(((micros >> 32) as u32).to_le_bytes());
+        body.extend((micros as u32).to_le_bytes());
+        body.extend((packet.len() as u32).to_le_bytes());
+        body.extend((packet.len() as u32).to_le_bytes());
+        body.extend(&packet);
+        write_block(&mut self.out, BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// The body is padded to 4 bytes
+fn write_block<W: Write>(out: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total = (12 + body.len() + padding) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&[0; 3][..padding])?;
+    out.write_all(&total.to_le_bytes())
+}
+
+/// An IPv4 packet if both ends are IPv4, IPv6 otherwise
+fn ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(40 + udp_len as usize);
+    let pseudo_header = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = vec![0x45, 0];
+            header.extend((20 + udp_len).to_be_bytes());
+            // Id, don't fragment
+            header.extend([0, 0, 0x40, 0, TTL, IPPROTO_UDP, 0, 0]);
+            header.extend(src_ip.octets());
+            header.extend(dst_ip.octets());
+            let checksum = internet_checksum(&[&header]);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend(&header);
+
+            let mut pseudo = Vec::new();
+            pseudo.extend(src_ip.octets());
+            pseudo.extend(dst_ip.octets());
+            pseudo.extend([0, IPPROTO_UDP]);
+            pseudo.extend(udp_len.to_be_bytes());
+            pseudo
+        }
+        (src_ip, dst_ip) => {
+            let (src_ip, dst_ip) = (to_ipv6(src_ip), to_ipv6(dst_ip));
+            packet.extend([0x60, 0, 0, 0]);
+            packet.extend(udp_len.to_be_bytes());
+            packet.extend([IPPROTO_UDP, TTL]);
+            packet.extend(src_ip.octets());
+            packet.extend(dst_ip.octets());
+
+            let mut pseudo = Vec::new();
+            pseudo.extend(src_ip.octets());
+            pseudo.extend(dst_ip.octets());
+            pseudo.extend((udp_len as u32).to_be_bytes());
+            pseudo.extend([0, 0, 0, IPPROTO_UDP]);
+            pseudo
+        }
+    };
+    let mut udp = Vec::with_capacity(8);
+    udp.extend(src.port().to_be_bytes());
+    udp.extend(dst.port().to_be_bytes());
+    udp.extend(udp_len.to_be_bytes());
+    let checksum = match internet_checksum(&[&pseudo_header, &udp, &[0, 0], payload]) {
+        // All ones stands for zero, which means "no checksum"
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp.extend(checksum.to_be_bytes());
+    packet.extend(&udp);
+    packet.extend(payload);
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// RFC 1071, over the parts as if concatenated (all but the last must be
+/// of even length)
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let word = match *pair {
+                [hi, lo] => u16::from_be_bytes([hi, lo]),
+                [hi] => u16::from_be_bytes([hi, 0]),
+                _ => unreachable!(),
+            };
+            sum += word as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A DatagramTap writing a pcapng file. Capturing stops at the first write
+/// error.
+pub struct PcapTap<W: Write> {
+    writer: Mutex<Option<PcapngWriter<W>>>,
+}
+
+impl<W: Write> PcapTap<W> {
+    pub fn new(writer: PcapngWriter<W>) -> Self {
+        Self {
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> DatagramTap for PcapTap<W> {
+    fn datagram(&self, dir: TapDirection, local: SocketAddr, remote: SocketAddr, data: &[u8]) {
+        let (src, dst) = match dir {
+            TapDirection::Sent => (local, remote),
+            TapDirection::Received => (remote, local),
+        };
+        let mut writer = self.writer.lock().unwrap();
+        let Some(pcap) = writer.as_mut() else {
+            return;
+        };
+        let result = pcap
+            .write_datagram(SystemTime::now(), src, dst, data)
+            .and_then(|()| pcap.flush());
+        if let Err(err) = result {
+            eprintln!("Packet capture stopped: {}", err);
+            *writer = None;
+        }
+    }
+}
+
+/// A UDP datagram read from a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedDatagram {
+    pub at: SystemTime,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// Reads the UDP datagrams of a pcapng file. Everything else (other
+/// protocols, IP fragments, unknown blocks) is skipped.
+pub struct PcapngReader<R: Read> {
+    input: R,
+    big_endian: bool,
+    /// Link type of each interface of the current section
+    link_types: Vec<u16>,
+}
+
+impl<R: Read> PcapngReader<R> {
+    /// Checks that `input` starts like a pcapng file
+    pub fn new(mut input: R) -> anyhow::Result<Self> {
+        let mut block_type = [0u8; 4];
+        input.read_exact(&mut block_type)?;
+        if u32::from_le_bytes(block_type) != BLOCK_SECTION_HEADER {
+            bail!("Not a pcapng file");
+        }
+        let mut reader = Self {
+            input,
+            big_endian: false,
+            link_types: Vec::new(),
+        };
+        reader.read_section_header()?;
+        Ok(reader)
+    }
+
+    /// The next UDP datagram, or None at the end of the file
+    pub fn next_datagram(&mut self) -> anyhow::Result<Option<CapturedDatagram>> {
+        loop {
+            let mut block_type = [0u8; 4];
+            match self.input.read_exact(&mut block_type) {
+                Ok(()) => (),
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+            // Same in either byte order
+            if u32::from_le_bytes(block_type) == BLOCK_SECTION_HEADER {
+                self.read_section_header()?;
+                continue;
+            }
+            let block_type = self.u32(block_type);
+            let body = self.read_body()?;
+            match block_type {
+                BLOCK_INTERFACE_DESCRIPTION => {
+                    if body.len() < 2 {
+                        bail!("Short interface block");
+                    }
+                    self.link_types
+                        .push(self.u16(body[..2].try_into().unwrap()));
+                }
+                BLOCK_ENHANCED_PACKET => {
+                    if let Some(datagram) = self.enhanced_packet(&body)? {
+                        return Ok(Some(datagram));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// After the block type: the length, byte order magic, and the rest
+    fn read_section_header(&mut self) -> anyhow::Result<()> {
+        let mut head = [0u8; 8];
+        self.input.read_exact(&mut head)?;
+        self.big_endian = match u32::from_le_bytes(head[4..].try_into().unwrap()) {
+            BYTE_ORDER_MAGIC => false,
+            BYTE_ORDER_MAGIC_SWAPPED => true,
+            _ => bail!("Bad pcapng byte order magic"),
+        };
+        let total = self.u32(head[..4].try_into().unwrap());
+        if !(16..=MAX_BLOCK_SIZE).contains(&total) || !total.is_multiple_of(4) {
+            bail!("Bad pcapng block length {}", total);
+        }
+        let mut rest = vec![0u8; total as usize - 12];
+        self.input.read_exact(&mut rest)?;
+        self.link_types.clear();
+        Ok(())
+    }
+
+    /// After the block type: the body, without the lengths around it
+    fn read_body(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut length = [0u8; 4];
+        self.input.read_exact(&mut length)?;
+        let total = self.u32(length);
+        if !(12..=MAX_BLOCK_SIZE).contains(&total) || !total.is_multiple_of(4) {
+            bail!("Bad pcapng block length {}", total);
+        }
+        let mut body = vec![0u8; total as usize - 8];
+        self.input.read_exact(&mut body)?;
+        body.truncate(total as usize - 12);
+        Ok(body)
+    }
+
+    fn enhanced_packet(&self, body: &[u8]) -> anyhow::Result<Option<CapturedDatagram>> {
+        if body.len() < 20 {
+            bail!("Short packet block");
+        }
+        let field = |pos: usize| self.u32(body[pos..pos + 4].try_into().unwrap());
+        let Some(&link_type) = self.link_types.get(field(0) as usize) else {
+            bail!("Packet on an undeclared interface");
+        };
+        let micros = ((field(4) as u64) << 32) | field(8) as u64;
+        let captured = field(12) as usize;
+        let Some(packet) = body.get(20..20 + captured) else {
+            bail!("Short packet block");
+        };
+        let Some((src, dst, payload)) = udp_datagram(link_type, packet) else {
+            return Ok(None);
+        };
+        Ok(Some(CapturedDatagram {
+            at: UNIX_EPOCH + Duration::from_micros(micros),
+            src,
+            dst,
+            payload: payload.to_vec(),
+        }))
+    }
+
+    fn u16(&self, bytes: [u8; 2]) -> u16 {
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32(&self, bytes: [u8; 4]) -> u32 {
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+/// The addresses and payload of a UDP packet, None if it is something else
+fn udp_datagram(link_type: u16, packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let ip = match link_type {
+        LINKTYPE_RAW => packet,
+        LINKTYPE_ETHERNET => {
+            let mut pos = 12;
+            let mut ethertype = u16::from_be_bytes(packet.get(pos..pos + 2)?.try_into().ok()?);
+            if ethertype == ETHERTYPE_VLAN {
+                pos += 4;
+                ethertype = u16::from_be_bytes(packet.get(pos..pos + 2)?.try_into().ok()?);
+            }
+            match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => packet.get(pos + 2..)?,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let (src_ip, dst_ip, udp): (IpAddr, IpAddr, &[u8]) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let flags_offset = u16::from_be_bytes(ip.get(6..8)?.try_into().ok()?);
+            // Fragments, other than a whole packet
+            if flags_offset & 0x3fff != 0 || *ip.get(9)? != IPPROTO_UDP {
+                return None;
+            }
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            let total_len = u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?) as usize;
+            let udp = ip.get(header_len..total_len.min(ip.len()))?;
+            (Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into(), udp)
+        }
+        6 => {
+            // Extension headers aren't followed
+            if *ip.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (
+                Ipv6Addr::from(src).into(),
+                Ipv6Addr::from(dst).into(),
+                ip.get(40..)?,
+            )
+        }
+        _ => return None,
+    };
+    let port = |pos: usize| Some(u16::from_be_bytes(udp.get(pos..pos + 2)?.try_into().ok()?));
+    let (src_port, dst_port) = (port(0)?, port(2)?);
+    let udp_len = port(4)? as usize;
+    let payload = udp.get(8..udp_len)?;
+    Some((
+        SocketAddr::new(unmap(src_ip), src_port),
+        SocketAddr::new(unmap(dst_ip), dst_port),
+        payload,
+    ))
+}
+
+/// IPv4 addresses that the writer mapped into IPv6 are IPv4 again
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pcapng_blocks() {
+        let mut pcap = PcapngWriter::new(Vec::new()).unwrap();
+        let at = UNIX_EPOCH + Duration::from_micros(0x1_0000_0002);
+        let client: SocketAddr = "192.168.1.2:40000".parse().unwrap();
+        let server: SocketAddr = "192.168.1.1:30000".parse().unwrap();
+        pcap.write_datagram(at, client, server, b"hello").unwrap();
+        let out = pcap.into_inner();
+
+        let u32_at = |pos: usize| u32::from_le_bytes(out[pos..pos + 4].try_into().unwrap());
+        // Section header, interface, packet
+        assert_eq!(u32_at(0), BLOCK_SECTION_HEADER);
+        assert_eq!(u32_at(4), 28);
+        assert_eq!(u32_at(28), BLOCK_INTERFACE_DESCRIPTION);
+        assert_eq!(u32_at(32), 20);
+        let block = 48;
+        assert_eq!(u32_at(block), BLOCK_ENHANCED_PACKET);
+        // 20 IPv4 + 8 UDP + 5 payload, padded
+        assert_eq!(u32_at(block + 4), 32 + 36);
+        assert_eq!((u32_at(block + 12), u32_at(block + 16)), (1, 2));
+        assert_eq!(u32_at(block + 20), 33);
+        assert_eq!(out.len(), block + 32 + 36);
+
+        let packet = &out[block + 28..block + 28 + 33];
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(internet_checksum(&[&packet[..20]]), 0);
+        assert_eq!(&packet[20..26], &[0x9c, 0x40, 0x75, 0x30, 0, 13]);
+        assert_eq!(&packet[28..], b"hello");
+    }
+
+    #[test]
+    fn ipv6_checksum() {
+        let src: SocketAddr = "[::1]:30000".parse().unwrap();
+        let dst: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let packet = ip_packet(src, dst, b"odd");
+        assert_eq!(packet.len(), 40 + 8 + 3);
+        assert_eq!(packet[0] >> 4, 6);
+        // A UDP checksum over the pseudo header comes out as zero
+        let mut pseudo = Vec::new();
+        pseudo.extend(&packet[8..40]);
+        pseudo.extend(11u32.to_be_bytes());
+        pseudo.extend([0, 0, 0, IPPROTO_UDP]);
+        assert_eq!(internet_checksum(&[&pseudo, &packet[40..]]), 0);
+    }
+
+    #[test]
+    fn read_back() {
+        let mut pcap = PcapngWriter::new(Vec::new()).unwrap();
+        let at = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let client: SocketAddr = "192.168.1.2:40000".parse().unwrap();
+        let server: SocketAddr = "192.168.1.1:30000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:30000".parse().unwrap();
+        pcap.write_datagram(at, client, server, b"hello").unwrap();
+        pcap.write_datagram(at, v6, client, b"odd").unwrap();
+        pcap.write_datagram(at, server, client, b"").unwrap();
+
+        let out = pcap.into_inner();
+        let mut reader = PcapngReader::new(&out[..]).unwrap();
+        let mut next = || reader.next_datagram().unwrap();
+        let first = next().unwrap();
+        assert_eq!(
+            first,
+            CapturedDatagram {
+                at,
+                src: client,
+                dst: server,
+                payload: b"hello".to_vec(),
+            }
+        );
+        // Mapped to IPv6 on the way in, IPv4 again on the way out
+        let second = next().unwrap();
+        assert_eq!((second.src, second.dst), (v6, client));
+        assert_eq!(second.payload, b"odd");
+        assert!(next().unwrap().payload.is_empty());
+        assert!(next().is_none());
+
+        assert!(PcapngReader::new(&b"MTRECORD"[..]).is_err());
+    }
+}
@@ -0,0 +1,413 @@
+//!
+//! Redaction of captured commands
+//!
+//! Captures are useful in bug reports, but they contain player names,
+//! SRP authentication material, chat, and network addresses. The Redactor
+//! rewrites commands so that they can be shared:
+//!
+//! * Player names are replaced by a pseudonym derived from a salted hash,
+//!   so the same player keeps the same (valid) name throughout a capture,
+//!   or stripped to a fixed placeholder. This includes player objects and
+//!   object nametags, which are usually a player's name.
+//! * SRP salts, verifiers and exchange bytes are zeroed, keeping their length.
+//! * Chat and modchannel message contents are replaced.
+//! * Socket addresses are hashed the same way as names.
+//!
+//! Command framing is unchanged, so redacted captures still decode and
+//! replay the same way as the originals.
+//!
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+
+use super::format::Record;
+use super::format::RecordingReader;
+use super::format::RecordingWriter;
+use crate::wire::command::Command;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::types::ActiveObjectCommand;
+use anyhow::Result;
+use sha2::Digest;
+use sha2::Sha256;
+
+pub const REDACTED_NAME: &str = "redacted";
+pub const REDACTED_TEXT: &str = "[redacted]";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameRedaction {
+    /// Replace each name with a stable pseudonym, e.g. "p_3fa81c02"
+    Hash,
+    /// Replace every name with REDACTED_NAME
+    Strip,
+}
+
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    salt: u64,
+    names: NameRedaction,
+}
+
+impl Redactor {
+    /// A redactor with a random salt, so pseudonyms cannot be
+    /// correlated across captures.
+    pub fn new(names: NameRedaction) -> Self {
+        Self::with_salt(rand::random(), names)
+    }
+
+    /// A redactor with a fixed salt. Pseudonyms are stable across
+    /// captures redacted with the same salt.
+    pub fn with_salt(salt: u64, names: NameRedaction) -> Self {
+        Self { salt, names }
+    }
+
+    /// Sha256 of the salt and `value`, which unlike std's hashers is the
+    /// same on every build
+    fn digest(&self, value: &[u8]) -> u32 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.to_be_bytes());
+        hasher.update(value);
+        let hash = hasher.finalize();
+        u32::from_be_bytes(hash[..4].try_into().unwrap())
+    }
+
+    /// The replacement for a player name. The result is a valid player name.
+    pub fn name(&self, name: &str) -> String {
+        if name.is_empty() {
+            return String::new();
+        }
+        match self.names {
+            NameRedaction::Hash => format!("p_{:08x}", self.digest(name.as_bytes())),
+            NameRedaction::Strip => REDACTED_NAME.to_string(),
+        }
+    }
+
+    pub fn addr(&self, addr: &SocketAddr) -> String {
+        format!("addr_{:08x}", self.digest(addr.to_string().as_bytes()))
+    }
+
+    fn name_in_place(&self, name: &mut String) {
+        *name = self.name(name);
+    }
+
+    fn text_in_place(&self, text: &mut String) {
+        if !text.is_empty() {
+            *text = REDACTED_TEXT.to_string();
+        }
+    }
+
+    fn bytes_in_place(&self, bytes: &mut [u8]) {
+        bytes.fill(0);
+    }
+
+    pub fn redact(&self, command: &mut Command) {
+        match command {
+            Command::ToServer(c) => self.redact_toserver(c),
+            Command::ToClient(c) => self.redact_toclient(c),
+        }
+    }
+
+    pub fn redact_toserver(&self, command: &mut ToServerCommand) {
+        use ToServerCommand::*;
+        match command {
+            Init(spec) => self.name_in_place(&mut spec.player_name),
+            TSChatMessage(spec) => self.text_in_place(&mut spec.message),
+            TSModchannelMsg(spec) => self.text_in_place(&mut spec.channel_msg),
+            FirstSrp(spec) => {
+                self.bytes_in_place(&mut spec.salt);
+                self.bytes_in_place(&mut spec.verification_key);
+            }
+            SrpBytesA(spec) => self.bytes_in_place(&mut spec.bytes_a),
+            SrpBytesM(spec) => self.bytes_in_place(&mut spec.bytes_m),
+            _ => (),
+        }
+    }
+
+    pub fn redact_toclient(&self, command: &mut ToClientCommand) {
+        use ToClientCommand::*;
+        match command {
+            Hello(spec) => self.name_in_place(&mut spec.username_legacy),
+            TCChatMessage(spec) => {
+                self.name_in_place(&mut spec.sender);
+                self.text_in_place(&mut spec.message);
+            }
+            TCModchannelMsg(spec) => {
+                self.name_in_place(&mut spec.sender);
+                self.text_in_place(&mut spec.channel_msg);
+            }
+            UpdatePlayerList(spec) => {
+                for player in spec.players.iter_mut() {
+                    self.name_in_place(player);
+                }
+            }
+            ActiveObjectRemoveAdd(spec) => {
+                for object in spec.added_objects.iter_mut() {
+                    let init_data = &mut object.init_data;
+                    if init_data.is_player {
+                        self.name_in_place(&mut init_data.name);
+                    }
+                    for message in init_data.messages.iter_mut() {
+                        self.redact_object_command(message);
+                    }
+                }
+            }
+            ActiveObjectMessages(spec) => {
+                for object in spec.objects.iter_mut() {
+                    self.redact_object_command(&mut object.data);
+                }
+            }
+            SrpBytesSB(spec) => {
+                self.bytes_in_place(&mut spec.s);
+                self.bytes_in_place(&mut spec.b);
+            }
+            _ => (),
+        }
+    }
+
+    fn redact_object_command(&self, command: &mut ActiveObjectCommand) {
+        if let ActiveObjectCommand::SetProperties(props) = command {
+            self.name_in_place(&mut props.newprops.nametag);
+        }
+    }
+
+    /// Copy a recording, redacting every command in it.
+    ///
+    /// Records of unknown kinds are dropped, since there is no way to
+    /// know what they contain. Returns the number of commands copied.
+    pub fn redact_recording<R: Read, W: Write>(&self, input: R, output: W) -> Result<usize> {
+        let mut reader = RecordingReader::new(input)?;
+        let header = reader.header().clone();
+        let mut writer = RecordingWriter::new(output, header.protocol_version, header.ser_fmt)?;
+        let mut count = 0;
+        while let Some(record) = reader.next_record()? {
+            match record {
+                Record::Command(record) => {
+                    let (protocol_version, ser_fmt) = reader.context();
+                    let mut command = record.decode(protocol_version, ser_fmt)?;
+                    self.redact(&mut command);
+                    writer.write_command_at(record.timestamp_us, &command)?;
+                    count += 1;
+                }
+                Record::Context {
+                    timestamp_us,
+                    protocol_version,
+                    ser_fmt,
+                } => writer.set_context_at(timestamp_us, protocol_version, ser_fmt)?,
+                Record::Unknown { .. } => (),
+            }
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::ActiveObjectMessagesSpec;
+    use crate::wire::command::ActiveObjectRemoveAddSpec;
+    use crate::wire::command::InitSpec;
+    use crate::wire::command::SrpBytesASpec;
+    use crate::wire::command::TCChatMessageSpec;
+    use crate::wire::packet::LATEST_PROTOCOL_VERSION;
+    use crate::wire::packet::SER_FMT_HIGHEST_WRITE;
+    use crate::wire::types::aabb3f;
+    use crate::wire::types::v2s16;
+    use crate::wire::types::v3f;
+    use crate::wire::types::AOCSetProperties;
+    use crate::wire::types::ActiveObjectMessage;
+    use crate::wire::types::AddedObject;
+    use crate::wire::types::GenericInitData;
+    use crate::wire::types::ObjectProperties;
+    use crate::wire::types::SColor;
+
+    #[test]
+    fn pseudonyms_are_stable_and_valid() {
+        let redactor = Redactor::with_salt(42, NameRedaction::Hash);
+        let a = redactor.name("singleplayer");
+        // Pinned: the same salt gives the same pseudonyms on every build
+        assert_eq!(a, "p_00c788df");
+        assert_eq!(a, redactor.name("singleplayer"));
+        assert_ne!(a, redactor.name("someone_else"));
+        assert!(a.len() <= 20);
+        assert!(a
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'));
+        assert_ne!(
+            a,
+            Redactor::with_salt(43, NameRedaction::Hash).name("singleplayer")
+        );
+    }
+
+    #[test]
+    fn redacts_recording() {
+        let chat: ToClientCommand = TCChatMessageSpec {
+            version: 1,
+            message_type: 1,
+            sender: "alice".to_string(),
+            message: "my password is hunter2".to_string(),
+            timestamp: 0,
+        }
+        .into();
+        let init: ToServerCommand = InitSpec {
+            serialization_ver_max: 29,
+            supp_compr_modes: 0,
+            min_net_proto_version: 37,
+            max_net_proto_version: 41,
+            player_name: "alice".to_string(),
+        }
+        .into();
+        let srp: ToServerCommand = SrpBytesASpec {
+            bytes_a: vec![0xAB; 256],
+            based_on: 1,
+        }
+        .into();
+
+        let mut writer =
+            RecordingWriter::new(Vec::new(), LATEST_PROTOCOL_VERSION, SER_FMT_HIGHEST_WRITE)
+                .unwrap();
+        writer.write_command(&init).unwrap();
+        writer.write_command(&srp).unwrap();
+        writer.write_command(&chat).unwrap();
+        let original = writer.into_inner();
+
+        let redactor = Redactor::with_salt(1, NameRedaction::Hash);
+        let mut redacted = Vec::new();
+        let count = redactor
+            .redact_recording(original.as_slice(), &mut redacted)
+            .unwrap();
+        assert_eq!(count, 3);
+        assert!(!redacted.windows(5).any(|w| w == b"alice"));
+        assert!(!redacted.windows(4).any(|w| w == [0xAB; 4]));
+
+        let pseudonym = redactor.name("alice");
+        let mut reader = RecordingReader::new(redacted.as_slice()).unwrap();
+        match reader.next_command().unwrap().unwrap().1 {
+            Command::ToServer(ToServerCommand::Init(spec)) => {
+                assert_eq!(spec.player_name, pseudonym)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match reader.next_command().unwrap().unwrap().1 {
+            Command::ToServer(ToServerCommand::SrpBytesA(spec)) => {
+                assert_eq!(spec.bytes_a, vec![0; 256])
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match reader.next_command().unwrap().unwrap().1 {
+            Command::ToClient(ToClientCommand::TCChatMessage(spec)) => {
+                assert_eq!(spec.sender, pseudonym);
+                assert_eq!(spec.message, REDACTED_TEXT);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    fn player_props(nametag: &str) -> ObjectProperties {
+        let box_ = aabb3f {
+            min_edge: v3f::new(-0.3, 0.0, -0.3),
+            max_edge: v3f::new(0.3, 1.7, 0.3),
+        };
+        ObjectProperties {
+            version: 4,
+            hp_max: 20,
+            physical: false,
+            _unused: 0,
+            collision_box: box_.clone(),
+            selection_box: box_,
+            pointable: true,
+            visual: "mesh".to_string(),
+            visual_size: v3f::new(1.0, 1.0, 1.0),
+            textures: vec!["character.png".to_string()],
+            spritediv: v2s16::new(1, 1),
+            initial_sprite_basepos: v2s16::new(0, 0),
+            is_visible: true,
+            makes_footstep_sound: true,
+            automatic_rotate: 0.0,
+            mesh: "character.b3d".to_string(),
+            colors: Vec::new(),
+            collide_with_objects: true,
+            stepheight: 0.6,
+            automatic_face_movement_dir: false,
+            automatic_face_movement_dir_offset: 0.0,
+            backface_culling: false,
+            nametag: nametag.to_string(),
+            nametag_color: SColor::new(255, 255, 255, 255),
+            automatic_face_movement_max_rotation_per_sec: -1.0,
+            infotext: String::new(),
+            wield_item: String::new(),
+            glow: 0,
+            breath_max: 10,
+            eye_height: 1.625,
+            zoom_fov: 0.0,
+            use_texture_alpha: false,
+            damage_texture_modifier: None,
+            shaded: None,
+            show_on_minimap: None,
+            nametag_bgcolor: None,
+            rotate_selectionbox: None,
+        }
+    }
+
+    #[test]
+    fn redacts_player_objects() {
+        let set_props = |nametag: &str| {
+            ActiveObjectCommand::SetProperties(Box::new(AOCSetProperties {
+                newprops: player_props(nametag),
+            }))
+        };
+        let added: ToClientCommand = ActiveObjectRemoveAddSpec {
+            removed_object_ids: Vec::new(),
+            added_objects: vec![AddedObject {
+                id: 5,
+                typ: 7,
+                init_data: GenericInitData {
+                    version: 1,
+                    name: "alice".to_string(),
+                    is_player: true,
+                    id: 5,
+                    position: v3f::new(0.0, 0.0, 0.0),
+                    rotation: v3f::new(0.0, 0.0, 0.0),
+                    hp: 20,
+                    messages: vec![set_props("alice")],
+                },
+            }],
+        }
+        .into();
+        let message: ToClientCommand = ActiveObjectMessagesSpec {
+            objects: vec![ActiveObjectMessage {
+                id: 5,
+                data: set_props("alice"),
+            }],
+        }
+        .into();
+
+        let redactor = Redactor::with_salt(1, NameRedaction::Strip);
+        for mut command in [added, message] {
+            redactor.redact_toclient(&mut command);
+            assert!(!format!("{:?}", command).contains("alice"));
+        }
+
+        // Entities keep their name: it's their type, e.g. "mobs:sheep"
+        let mut entity: ToClientCommand = ActiveObjectRemoveAddSpec {
+            removed_object_ids: Vec::new(),
+            added_objects: vec![AddedObject {
+                id: 6,
+                typ: 7,
+                init_data: GenericInitData {
+                    version: 1,
+                    name: "mobs:sheep".to_string(),
+                    is_player: false,
+                    id: 6,
+                    position: v3f::new(0.0, 0.0, 0.0),
+                    rotation: v3f::new(0.0, 0.0, 0.0),
+                    hp: 8,
+                    messages: Vec::new(),
+                },
+            }],
+        }
+        .into();
+        redactor.redact_toclient(&mut entity);
+        assert!(format!("{:?}", entity).contains("mobs:sheep"));
+    }
+}
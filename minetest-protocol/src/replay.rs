@@ -0,0 +1,597 @@
+//!
+//! Replaying captured sessions
+//!
+//! Sends what a client sent in a captured session to a live server again,
+//! with the original timing, or faster or slower. A server bug that a
+//! client triggered once can then be triggered at will:
+//!
+//! ```text
+//! let capture = Capture::load(&std::fs::read("conn-1.mtrec")?)?;
+//! let options = ReplayOptions {
+//!     speed: 2.0,
+//!     login: Some(("replay".into(), "secret".into())),
+//!     ..Default::default()
+//! };
+//! let stats = replay(server_addr, &capture.steps, &options).await?;
+//! ```
+//!
+//! A capture can be:
+//!
+//! ```text
+//! a recording      mtshark --record
+//! a packet capture mtshark --pcap, or any pcapng with the client's
+//!                  traffic. The client is whoever sent the first datagram.
+//! JSON lines       mtshark --output jsonl -vv (with the serde feature).
+//!                  The first session in it.
+//! ```
+//!
+//! The recorded login can't be sent again as is: SRP is a fresh exchange
+//! every time. With ReplayOptions::login, the replay logs in by itself
+//! (see MinetestClient::login), and leaves the recorded handshake out.
+//! Without it, every command goes out as recorded, which only gets past
+//! the handshake of a server that doesn't check.
+//!
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::bot::script::ScriptStep;
+use crate::peer::split_receiver::SplitReceiver;
+use crate::recording::format::RECORDING_MAGIC;
+use crate::recording::CapturedDatagram;
+use crate::recording::PcapngReader;
+use crate::recording::Record;
+use crate::recording::RecordingReader;
+use crate::services::client::MinetestClient;
+use crate::wire::command::Command;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+use crate::wire::deser::ChainedBuffer;
+use crate::wire::deser::Deserialize;
+use crate::wire::deser::Deserializer;
+use crate::wire::packet::InnerBody;
+use crate::wire::packet::Packet;
+use crate::wire::packet::PacketBody;
+use crate::wire::types::CommandDirection;
+use crate::wire::types::ProtocolContext;
+
+/// What ReplayOptions::default waits for the server after the last command
+pub const DEFAULT_LINGER: Duration = Duration::from_secs(2);
+
+/// The client's side of a captured session
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Capture {
+    /// The commands, and when they were sent. The first one is at zero.
+    pub steps: Vec<ScriptStep>,
+    /// What couldn't be decoded, and why
+    pub skipped: Vec<String>,
+}
+
+impl Capture {
+    /// Any of the formats above, told apart by their first bytes
+    pub fn load(data: &[u8]) -> Result<Self> {
+        if data.starts_with(RECORDING_MAGIC) {
+            Self::from_recording(data)
+        } else if data.starts_with(&[0x0A, 0x0D, 0x0D, 0x0A]) {
+            Self::from_pcapng(data)
+        } else {
+            Self::from_text(data)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn from_text(data: &[u8]) -> Result<Self> {
+        Self::from_jsonl(std::str::from_utf8(data)?)
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn from_text(_data: &[u8]) -> Result<Self> {
+        bail!("Not a recording or a pcapng file (JSON needs the serde feature)")
+    }
+
+    pub fn from_recording(data: &[u8]) -> Result<Self> {
+        let mut reader = RecordingReader::new(data)?;
+        let mut capture = Capture::default();
+        while let Some(record) = reader.next_record()? {
+            let Record::Command(record) = record else {
+                continue;
+            };
+            if record.dir != CommandDirection::ToServer {
+                continue;
+            }
+            let at = Duration::from_micros(record.timestamp_us);
+            let (protocol_version, ser_fmt) = reader.context();
+            match record.decode(protocol_version, ser_fmt) {
+                Ok(Command::ToServer(command)) => capture.push(at, command),
+                Ok(Command::ToClient(_)) => (),
+                Err(err) => capture.skip(at, err),
+            }
+        }
+        Ok(capture.started())
+    }
+
+    pub fn from_pcapng(data: &[u8]) -> Result<Self> {
+        let mut reader = PcapngReader::new(data)?;
+        let Some(first) = reader.next_datagram()? else {
+            return Ok(Capture::default());
+        };
+        let mut decoder = PcapDecoder::new(&first);
+        let mut capture = Capture::default();
+        let mut next = Some(first);
+        while let Some(datagram) = next {
+            decoder.datagram(&datagram, &mut capture);
+            next = reader.next_datagram()?;
+        }
+        Ok(capture.started())
+    }
+
+    /// The C->S commands of the first session, which must have contents
+    /// (-vv)
+    #[cfg(feature = "serde")]
+    pub fn from_jsonl(text: &str) -> Result<Self> {
+        let mut capture = Capture::default();
+        let mut session = None;
+        for line in text.lines().filter(|line| line.starts_with('{')) {
+            let line: serde_json::Value = serde_json::from_str(line)?;
+            if line["dir"] != "C->S" {
+                continue;
+            }
+            if *session.get_or_insert_with(|| line["session"].clone()) != line["session"] {
+                continue;
+            }
+            let at = Duration::try_from_secs_f64(line["timestamp"].as_f64().unwrap_or(0.0))?;
+            if line.get("command").is_none() {
+                bail!("No command contents in the JSON, capture with -vv");
+            }
+            match serde_json::from_value(line["command"].clone()) {
+                Ok(command) => capture.push(at, command),
+                Err(err) => capture.skip(at, err.into()),
+            }
+        }
+        Ok(capture.started())
+    }
+
+    fn push(&mut self, at: Duration, command: ToServerCommand) {
+        self.steps.push(ScriptStep { at, command });
+    }
+
+    fn skip(&mut self, at: Duration, err: anyhow::Error) {
+        self.skipped
+            .push(format!("at {:.3}s: {:#}", at.as_secs_f64(), err));
+    }
+
+    /// With the times from the first command
+    fn started(mut self) -> Self {
+        if let Some(start) = self.steps.first().map(|step| step.at) {
+            for step in &mut self.steps {
+                step.at = step.at.saturating_sub(start);
+            }
+        }
+        self
+    }
+}
+
+/// The client's commands from the datagrams of a capture
+struct PcapDecoder {
+    client: SocketAddr,
+    server: SocketAddr,
+    start: SystemTime,
+    /// Set from the server's Hello
+    context: ProtocolContext,
+    splits: SplitReceiver,
+    /// Reliable (channel, seqnum) seen, to leave out resends
+    seen: HashSet<(u8, u16)>,
+}
+
+impl PcapDecoder {
+    fn new(first: &CapturedDatagram) -> Self {
+        Self {
+            client: first.src,
+            server: first.dst,
+            start: first.at,
+            context: ProtocolContext::latest_for_receive(false),
+            splits: SplitReceiver::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    fn datagram(&mut self, datagram: &CapturedDatagram, capture: &mut Capture) {
+        let at = datagram.at.duration_since(self.start).unwrap_or_default();
+        if (datagram.src, datagram.dst) == (self.server, self.client) {
+            self.server_datagram(&datagram.payload);
+        } else if (datagram.src, datagram.dst) == (self.client, self.server) {
+            if let Err(err) = self.client_datagram(at, &datagram.payload, capture) {
+                capture.skip(at, err);
+            }
+        }
+    }
+
+    /// Only the Hello matters, for the protocol version
+    fn server_datagram(&mut self, payload: &[u8]) {
+        let context = ProtocolContext::latest_for_receive(true);
+        let Ok(packet) = Packet::deserialize(&mut Deserializer::new(context, payload)) else {
+            return;
+        };
+        if let Some(Command::ToClient(ToClientCommand::Hello(spec))) = packet.inner().command() {
+            self.context.protocol_version = spec.proto_ver;
+            self.context.ser_fmt = spec.serialization_ver;
+        }
+    }
+
+    fn client_datagram(
+        &mut self,
+        at: Duration,
+        payload: &[u8],
+        capture: &mut Capture,
+    ) -> Result<()> {
+        let packet = Packet::deserialize(&mut Deserializer::new(self.context, payload))?;
+        let (reliable, inner) = match packet.body {
+            PacketBody::Reliable(body) => {
+                if !self.seen.insert((packet.channel, body.seqnum)) {
+                    return Ok(());
+                }
+                // Half the seqnums away, so they can come again
+                self.seen
+                    .remove(&(packet.channel, body.seqnum.wrapping_add(0x8000)));
+                (true, body.inner)
+            }
+            PacketBody::Inner(inner) => (false, inner),
+        };
+        let command = match inner {
+            InnerBody::Control(_) => return Ok(()),
+            InnerBody::Original(body) => body.command,
+            InnerBody::Split(body) => {
+                let Some(chunks) = self.splits.push(Instant::now(), reliable, body)? else {
+                    return Ok(());
+                };
+                let chain = ChainedBuffer::new(chunks);
+                Command::deserialize(&mut Deserializer::new_chained(self.context, &chain))?
+            }
+        };
+        if let Command::ToServer(command) = command {
+            capture.push(at, command);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayOptions {
+    /// 2.0 replays twice as fast, f64::INFINITY without waiting at all
+    pub speed: f64,
+    /// (player name, password) to log in with, instead of the recorded
+    /// handshake
+    pub login: Option<(String, String)>,
+    /// How long to wait for the server after the last command, to see
+    /// whether it hangs up
+    pub linger: Duration,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            login: None,
+            linger: DEFAULT_LINGER,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ReplayStats {
+    pub sent: usize,
+    pub received: usize,
+}
+
+/// The steps to send, for `options`: without the recorded handshake when
+/// logging in, and with the times scaled
+pub fn replay_steps(steps: &[ScriptStep], options: &ReplayOptions) -> Vec<ScriptStep> {
+    let kept: Vec<&ScriptStep> = steps
+        .iter()
+        .filter(|step| options.login.is_none() || !is_handshake(&step.command))
+        .collect();
+    let start = kept.first().map(|step| step.at).unwrap_or_default();
+    kept.into_iter()
+        .map(|step| ScriptStep {
+            at: (step.at - start).div_f64(options.speed),
+            command: step.command.clone(),
+        })
+        .collect()
+}
+
+fn is_handshake(command: &ToServerCommand) -> bool {
+    matches!(
+        command,
+        ToServerCommand::Init(_)
+            | ToServerCommand::FirstSrp(_)
+            | ToServerCommand::SrpBytesA(_)
+            | ToServerCommand::SrpBytesM(_)
+            | ToServerCommand::Init2(_)
+            | ToServerCommand::ClientReady(_)
+    )
+}
+
+/// Connects to `server` and replays (see replay_client)
+pub async fn replay(
+    server: SocketAddr,
+    steps: &[ScriptStep],
+    options: &ReplayOptions,
+) -> Result<ReplayStats> {
+    let client = MinetestClient::connect(server).await?;
+    replay_client(client, steps, options).await
+}
+
+/// Sends the steps (see replay_steps) at their times, taking whatever the
+/// server sends meanwhile. Fails if the server hangs up before the end.
+pub async fn replay_client(
+    mut client: MinetestClient,
+    steps: &[ScriptStep],
+    options: &ReplayOptions,
+) -> Result<ReplayStats> {
+    let steps = replay_steps(steps, options);
+    if let Some((player_name, password)) = &options.login {
+        client.login(player_name, password).await?;
+    }
+    let mut stats = ReplayStats::default();
+    let result = replay_on(&mut client, &steps, options.linger, &mut stats).await;
+    if let Err(err) = result {
+        bail!(err.context(format!(
+            "Replay stopped after {} of {} commands",
+            stats.sent,
+            steps.len()
+        )));
+    }
+    client.disconnect().await?;
+    Ok(stats)
+}
+
+/// recv is cancel safe, so the server is listened to while waiting
+async fn replay_on(
+    client: &mut MinetestClient,
+    steps: &[ScriptStep],
+    linger: Duration,
+    stats: &mut ReplayStats,
+) -> Result<()> {
+    let start = tokio::time::Instant::now();
+    for step in steps {
+        let due = start + step.at;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(due) => break,
+                result = client.recv() => {
+                    result?;
+                    stats.received += 1;
+                }
+            }
+        }
+        client.send(step.command.clone()).await?;
+        stats.sent += 1;
+    }
+    let end = tokio::time::Instant::now() + linger;
+    while let Ok(result) = tokio::time::timeout_at(end, client.recv()).await {
+        result?;
+        stats.received += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::pcap::PcapngWriter;
+    use crate::recording::RecordingWriter;
+    use crate::services::transport::LoopbackNetwork;
+    use crate::wire::command::HelloSpec;
+    use crate::wire::command::Init2Spec;
+    use crate::wire::command::TSChatMessageSpec;
+    use crate::wire::command::TimeOfDaySpec;
+    use crate::wire::packet::OriginalBody;
+    use crate::wire::packet::ReliableBody;
+    use crate::wire::packet::SplitBody;
+    use crate::wire::packet::LATEST_PROTOCOL_VERSION;
+    use crate::wire::packet::SER_FMT_HIGHEST_WRITE;
+    use crate::wire::ser::Serialize;
+    use crate::wire::ser::VecSerializer;
+    use crate::wire::types::AuthMechsBitset;
+    use crate::MinetestServer;
+    use std::time::UNIX_EPOCH;
+
+    fn chat(message: &str) -> ToServerCommand {
+        TSChatMessageSpec {
+            message: message.to_string(),
+        }
+        .into()
+    }
+
+    fn step(millis: u64, command: ToServerCommand) -> ScriptStep {
+        ScriptStep {
+            at: Duration::from_millis(millis),
+            command,
+        }
+    }
+
+    #[test]
+    fn recording_capture() {
+        let mut writer =
+            RecordingWriter::new(Vec::new(), LATEST_PROTOCOL_VERSION, SER_FMT_HIGHEST_WRITE)
+                .unwrap();
+        let time = TimeOfDaySpec {
+            time_of_day: 6000,
+            time_speed: Some(72.0),
+        };
+        writer
+            .write_command_at(500, &Command::ToClient(time.into()))
+            .unwrap();
+        writer
+            .write_command_at(1000, &Command::ToServer(chat("a")))
+            .unwrap();
+        writer
+            .write_command_at(3000, &Command::ToServer(chat("b")))
+            .unwrap();
+        let capture = Capture::load(&writer.into_inner()).unwrap();
+        assert_eq!(capture.steps, vec![step(0, chat("a")), step(2, chat("b"))]);
+        assert!(capture.skipped.is_empty());
+    }
+
+    fn datagram(context: ProtocolContext, seqnum: u16, inner: InnerBody) -> Vec<u8> {
+        let body = PacketBody::Reliable(ReliableBody { seqnum, inner });
+        let mut ser = VecSerializer::new(context, 64);
+        Packet::serialize(&Packet::new(2, 0, body), &mut ser).unwrap();
+        ser.take()
+    }
+
+    fn original(command: Command) -> InnerBody {
+        InnerBody::Original(OriginalBody { command })
+    }
+
+    #[test]
+    fn pcapng_capture() {
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.1:30000".parse().unwrap();
+        let to_server = ProtocolContext::latest_for_send(true);
+        let to_client = ProtocolContext::latest_for_send(false);
+        let hello = HelloSpec {
+            serialization_ver: SER_FMT_HIGHEST_WRITE,
+            compression_mode: 0,
+            proto_ver: 40,
+            auth_mechs: AuthMechsBitset {
+                legacy_password: false,
+                srp: true,
+                first_srp: false,
+            },
+            username_legacy: String::new(),
+        };
+        let mut ser = VecSerializer::new(to_server, 64);
+        Command::serialize(&Command::ToServer(chat("split")), &mut ser).unwrap();
+        let whole = ser.take();
+        let chunk = |chunk_num: u16, data: &[u8]| {
+            InnerBody::Split(SplitBody {
+                seqnum: 7,
+                chunk_count: 2,
+                chunk_num,
+                chunk_data: data.to_vec(),
+            })
+        };
+
+        let mut pcap = PcapngWriter::new(Vec::new()).unwrap();
+        let mut send = |millis: u64, src, dst, data: Vec<u8>| {
+            let at = UNIX_EPOCH + Duration::from_millis(millis);
+            pcap.write_datagram(at, src, dst, &data).unwrap();
+        };
+        let init2 = original(Command::ToServer(Init2Spec { lang: None }.into()));
+        send(0, client, server, datagram(to_server, 65500, init2.clone()));
+        let hello = original(Command::ToClient(hello.into()));
+        send(10, server, client, datagram(to_client, 65500, hello));
+        // A resend
+        send(20, client, server, datagram(to_server, 65500, init2));
+        send(
+            30,
+            client,
+            server,
+            datagram(to_server, 65501, chunk(1, &whole[3..])),
+        );
+        send(
+            40,
+            client,
+            server,
+            datagram(to_server, 65502, chunk(0, &whole[..3])),
+        );
+        send(50, client, server, b"garbage".to_vec());
+
+        let capture = Capture::load(&pcap.into_inner()).unwrap();
+        assert_eq!(
+            capture.steps,
+            vec![
+                step(0, Init2Spec { lang: None }.into()),
+                step(40, chat("split")),
+            ]
+        );
+        assert_eq!(capture.skipped.len(), 1);
+        assert!(capture.skipped[0].starts_with("at 0.050s: "));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn jsonl_capture() {
+        let line = |session: u64, timestamp: f64, dir: &str, command: &ToServerCommand| {
+            serde_json::json!({
+                "timestamp": timestamp,
+                "session": session,
+                "dir": dir,
+                "name": "TSChatMessage",
+                "command": command,
+            })
+            .to_string()
+        };
+        let text = [
+            "[P1] New client connected from 127.0.0.1:34997".to_string(),
+            line(1, 100.5, "C->S", &chat("a")),
+            line(2, 100.75, "C->S", &chat("other session")),
+            line(1, 101.0, "S->C", &chat("wrong way")),
+            line(1, 102.5, "C->S", &chat("b")),
+        ]
+        .join("\n");
+        let capture = Capture::load(text.as_bytes()).unwrap();
+        assert_eq!(
+            capture.steps,
+            vec![step(0, chat("a")), step(2000, chat("b"))]
+        );
+    }
+
+    #[test]
+    fn steps_for_options() {
+        let steps = vec![
+            step(0, Init2Spec { lang: None }.into()),
+            step(1000, chat("a")),
+            step(3000, chat("b")),
+        ];
+        let options = ReplayOptions {
+            speed: 2.0,
+            login: Some(("replay".into(), "secret".into())),
+            ..Default::default()
+        };
+        assert_eq!(
+            replay_steps(&steps, &options),
+            vec![step(0, chat("a")), step(1000, chat("b"))]
+        );
+        let options = ReplayOptions {
+            speed: f64::INFINITY,
+            ..Default::default()
+        };
+        assert!(replay_steps(&steps, &options)
+            .iter()
+            .all(|step| step.at.is_zero()));
+    }
+
+    #[tokio::test]
+    async fn replay_to_server() {
+        let network = LoopbackNetwork::new();
+        let server_addr: SocketAddr = "10.0.0.1:30000".parse().unwrap();
+        let client_addr: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut server = MinetestServer::with_transport(network.bind(server_addr)).unwrap();
+        let client = MinetestClient::connect_with_transport(network.bind(client_addr), server_addr)
+            .await
+            .unwrap();
+        let steps = vec![step(0, chat("a")), step(50, chat("b"))];
+        let options = ReplayOptions {
+            linger: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let replayed = tokio::spawn(async move { replay_client(client, &steps, &options).await });
+
+        let mut conn = server.accept().await;
+        for message in ["a", "b"] {
+            let Ok(ToServerCommand::TSChatMessage(spec)) = conn.recv().await else {
+                panic!("expected TSChatMessage");
+            };
+            assert_eq!(spec.message, message);
+        }
+        let stats = replayed.await.unwrap().unwrap();
+        assert_eq!((stats.sent, stats.received), (2, 0));
+    }
+}
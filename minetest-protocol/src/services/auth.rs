@@ -0,0 +1,521 @@
+//!
+//! The server side of the login handshake
+//!
+//! ```text
+//! C->S  Init
+//! S->C  Hello                 (first_srp, srp or legacy_password)
+//! C->S  FirstSrp              (new account: registered, and accepted)
+//!    or SrpBytesA
+//! S->C  SrpBytesSB
+//! C->S  SrpBytesM             (checked against the account's verifier)
+//! S->C  AuthAccept
+//! ```
+//!
+//! Accounts come from an AuthHandler. MemoryAuthHandler keeps them in
+//! memory; a server with its own store (e.g. auth.sqlite) implements the
+//! trait. An AuthServer runs the handshake for every connection, and only
+//! hands over the ones that logged in:
+//!
+//! ```text
+//! let mut server = AuthServer::new(addr, MemoryAuthHandler::new(), AuthConfig::default());
+//! let client = server.accept().await;
+//! println!("{} logged in", client.player_name);
+//! ```
+//!
+//! Accounts from before SRP (Credentials::Legacy) log in with SRP too,
+//! using a verifier made from their password hash for each login.
+//!
+//! A refused login is sent TOCLIENT_ACCESS_DENIED, with the same codes the
+//! engine uses. Player names are checked with AuthConfig::player_names (see
+//! validate).
+//!
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use super::conn::MinetestConnection;
+use super::srp;
+use super::srp::SrpServer;
+use super::validate::NameRules;
+use crate::wire::command::*;
+use crate::wire::packet::negotiate_protocol_version;
+use crate::wire::packet::SER_FMT_HIGHEST_WRITE;
+use crate::wire::types::v3f;
+use crate::wire::types::AccessDeniedCode;
+use crate::wire::types::AuthMechsBitset;
+
+/// How an account's password is checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Srp {
+        salt: Vec<u8>,
+        verifier: Vec<u8>,
+    },
+    /// srp::legacy_password, for accounts from before SRP
+    Legacy(String),
+}
+
+impl Credentials {
+    /// New SRP credentials, with a random salt
+    pub fn from_password(player_name: &str, password: &str) -> Self {
+        let (salt, verifier) = srp::salted_verifier(player_name, password);
+        Credentials::Srp { salt, verifier }
+    }
+}
+
+/// Where accounts are kept. Called from the connections' tasks.
+pub trait AuthHandler: Send + Sync + 'static {
+    /// None for a player without an account
+    fn credentials(&self, player_name: &str) -> Result<Option<Credentials>>;
+
+    /// A new account, on the player's first login. Fails if the player
+    /// already has one (registered since the handshake began, say), which
+    /// refuses the login with the error as the reason.
+    fn register(&self, player_name: &str, credentials: Credentials) -> Result<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryAuthHandler {
+    accounts: Mutex<HashMap<String, Credentials>>,
+}
+
+impl MemoryAuthHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, player_name: &str, credentials: Credentials) {
+        self.accounts().insert(player_name.to_string(), credentials);
+    }
+
+    pub fn set_password(&self, player_name: &str, password: &str) {
+        self.insert(
+            player_name,
+            Credentials::from_password(player_name, password),
+        );
+    }
+
+    pub fn remove(&self, player_name: &str) -> Option<Credentials> {
+        self.accounts().remove(player_name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn accounts(&self) -> std::sync::MutexGuard<'_, HashMap<String, Credentials>> {
+        self.accounts.lock().unwrap()
+    }
+}
+
+impl AuthHandler for MemoryAuthHandler {
+    fn credentials(&self, player_name: &str) -> Result<Option<Credentials>> {
+        Ok(self.accounts().get(player_name).cloned())
+    }
+
+    fn register(&self, player_name: &str, credentials: Credentials) -> Result<()> {
+        let mut accounts = self.accounts();
+        if accounts.contains_key(player_name) {
+            bail!("Player {} already exists", player_name);
+        }
+        accounts.insert(player_name.to_string(), credentials);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthConfig {
+    /// Refuse new accounts and legacy accounts without a password
+    pub disallow_empty_password: bool,
+    /// The whole handshake, from Init to AuthAccept
+    pub timeout: Duration,
+    /// Sent in AuthAccept
+    pub map_seed: u64,
+    /// Sent in AuthAccept, in seconds (the engine's dedicated_server_step)
+    pub send_interval: f32,
+    /// Checked on Init
+    pub player_names: NameRules,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            disallow_empty_password: false,
+            timeout: Duration::from_secs(30),
+            map_seed: 0,
+            send_interval: 0.09,
+            player_names: NameRules::default(),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum AuthError {
+    /// Sent to the client as TOCLIENT_ACCESS_DENIED
+    #[error("Login refused: {}", .0.to_str())]
+    Refused(AccessDeniedCode),
+    #[error("Login timed out")]
+    Timeout,
+}
+
+/// A connection that logged in. The client waits for Init2 to be answered
+/// next (definitions, media, ...).
+pub struct AuthenticatedConnection {
+    pub conn: MinetestConnection,
+    pub player_name: String,
+    /// The account was registered by this login
+    pub new_account: bool,
+}
+
+/// Runs the handshake on `conn`. A refused login is sent AccessDenied, and
+/// fails with AuthError::Refused.
+pub async fn authenticate(
+    mut conn: MinetestConnection,
+    handler: &dyn AuthHandler,
+    config: &AuthConfig,
+) -> Result<AuthenticatedConnection> {
+    let mut handshake = Handshake::new(config.clone());
+    let run = async {
+        loop {
+            let command = conn.recv().await?;
+            match handshake.handle(handler, &command) {
+                Ok(Some(reply)) => conn.send(reply).await?,
+                Ok(None) => (),
+                Err(code) => {
+                    conn.send_access_denied(code.clone()).await?;
+                    return Err(AuthError::Refused(code).into());
+                }
+            }
+            if handshake.is_accepted() {
+                return anyhow::Ok(());
+            }
+        }
+    };
+    match tokio::time::timeout(config.timeout, run).await {
+        Ok(result) => result?,
+        Err(_) => return Err(AuthError::Timeout.into()),
+    }
+    Ok(AuthenticatedConnection {
+        conn,
+        player_name: handshake.player_name,
+        new_account: handshake.new_account,
+    })
+}
+
+enum State {
+    WaitInit,
+    WaitAuth(Option<Credentials>),
+    WaitProof {
+        server: SrpServer,
+        bytes_a: Vec<u8>,
+    },
+    Accepted,
+    /// Every command after a refusal gets the same refusal
+    Refused(AccessDeniedCode),
+}
+
+/// The handshake without the connection: each command from the client in,
+/// a reply (or a refusal) out.
+pub struct Handshake {
+    config: AuthConfig,
+    state: State,
+    player_name: String,
+    new_account: bool,
+}
+
+impl Handshake {
+    pub fn new(config: AuthConfig) -> Self {
+        Self {
+            config,
+            state: State::WaitInit,
+            player_name: String::new(),
+            new_account: false,
+        }
+    }
+
+    /// From Init
+    pub fn player_name(&self) -> &str {
+        &self.player_name
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        matches!(self.state, State::Accepted)
+    }
+
+    /// The reply to `command`, if any. Commands that don't belong to the
+    /// handshake are ignored, like the engine does. After a refusal, the
+    /// handshake is over: anything else the client sends is refused the
+    /// same way.
+    pub fn handle(
+        &mut self,
+        handler: &dyn AuthHandler,
+        command: &ToServerCommand,
+    ) -> Result<Option<ToClientCommand>, AccessDeniedCode> {
+        let state = std::mem::replace(&mut self.state, State::WaitInit);
+        match self.step(handler, state, command) {
+            Ok((state, reply)) => {
+                self.state = state;
+                Ok(reply)
+            }
+            Err(code) => {
+                self.state = State::Refused(code.clone());
+                Err(code)
+            }
+        }
+    }
+
+    fn step(
+        &mut self,
+        handler: &dyn AuthHandler,
+        state: State,
+        command: &ToServerCommand,
+    ) -> Result<(State, Option<ToClientCommand>), AccessDeniedCode> {
+        use AccessDeniedCode::*;
+        Ok(match (state, command) {
+            (State::Refused(code), _) => return Err(code),
+            (State::WaitInit, ToServerCommand::Init(spec)) => {
+                let Some(protocol_version) = negotiate_protocol_version(
+                    spec.min_net_proto_version,
+                    spec.max_net_proto_version,
+                ) else {
+                    return Err(WrongVersion);
+                };
+                self.config.player_names.check(&spec.player_name)?;
+                self.player_name = spec.player_name.clone();
+                let credentials = handler
+                    .credentials(&self.player_name)
+                    .map_err(|_| ServerFail)?;
+                let auth_mechs = AuthMechsBitset {
+                    legacy_password: matches!(credentials, Some(Credentials::Legacy(_))),
+                    srp: matches!(credentials, Some(Credentials::Srp { .. })),
+                    first_srp: credentials.is_none(),
+                };
+                let hello = HelloSpec {
+                    serialization_ver: spec.serialization_ver_max.min(SER_FMT_HIGHEST_WRITE),
+                    compression_mode: 0,
+                    proto_ver: protocol_version,
+                    auth_mechs,
+                    username_legacy: self.player_name.clone(),
+                };
+                (State::WaitAuth(credentials), Some(hello.into()))
+            }
+            (State::WaitAuth(None), ToServerCommand::FirstSrp(spec)) => {
+                if spec.is_empty && self.config.disallow_empty_password {
+                    return Err(EmptyPassword);
+                }
+                // Hello offered FirstSrp, but someone may have registered
+                // the name since
+                let existing = handler
+                    .credentials(&self.player_name)
+                    .map_err(|_| ServerFail)?;
+                if existing.is_some() {
+                    return Err(UnexpectedData);
+                }
+                let credentials = Credentials::Srp {
+                    salt: spec.salt.clone(),
+                    verifier: spec.verification_key.clone(),
+                };
+                handler
+                    .register(&self.player_name, credentials)
+                    .map_err(|err| CustomString(err.to_string()))?;
+                self.new_account = true;
+                (State::Accepted, Some(self.accept()))
+            }
+            (State::WaitAuth(Some(credentials)), ToServerCommand::SrpBytesA(spec)) => {
+                let (salt, verifier) = match (credentials, spec.based_on) {
+                    (Credentials::Srp { salt, verifier }, 1) => (salt, verifier),
+                    (Credentials::Legacy(hash), 0) => {
+                        if hash.is_empty() && self.config.disallow_empty_password {
+                            return Err(EmptyPassword);
+                        }
+                        srp::salted_verifier(&self.player_name, &hash)
+                    }
+                    _ => return Err(UnexpectedData),
+                };
+                let server = SrpServer::new(&self.player_name, &salt, &verifier);
+                let reply = SrpBytesSBSpec {
+                    s: salt,
+                    b: server.bytes_b(),
+                };
+                let state = State::WaitProof {
+                    server,
+                    bytes_a: spec.bytes_a.clone(),
+                };
+                (state, Some(reply.into()))
+            }
+            (State::WaitProof { server, bytes_a }, ToServerCommand::SrpBytesM(spec)) => {
+                match server.verify(&bytes_a, &spec.bytes_m) {
+                    Ok(true) => (State::Accepted, Some(self.accept())),
+                    Ok(false) => return Err(WrongPassword),
+                    Err(_) => return Err(UnexpectedData),
+                }
+            }
+            (state, _) => (state, None),
+        })
+    }
+
+    fn accept(&self) -> ToClientCommand {
+        AuthAcceptSpec {
+            // The application moves the player once it is in the game
+            player_pos: v3f::new(0.0, 0.0, 0.0),
+            map_seed: self.config.map_seed,
+            recommended_send_interval: self.config.send_interval,
+            // SRP, for /setpassword
+            sudo_auth_methods: 2,
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::srp::SrpClient;
+    use crate::wire::packet::LATEST_PROTOCOL_VERSION;
+
+    fn init(player_name: &str) -> ToServerCommand {
+        InitSpec {
+            serialization_ver_max: 29,
+            supp_compr_modes: 0,
+            min_net_proto_version: 37,
+            max_net_proto_version: LATEST_PROTOCOL_VERSION,
+            player_name: player_name.to_string(),
+        }
+        .into()
+    }
+
+    /// Logs in with SRP, returning the refusal if any
+    fn srp_login(
+        handler: &dyn AuthHandler,
+        player_name: &str,
+        password: &str,
+        based_on: u8,
+    ) -> Result<(), AccessDeniedCode> {
+        let mut handshake = Handshake::new(AuthConfig::default());
+        handshake.handle(handler, &init(player_name))?;
+        let client = SrpClient::new(player_name, password);
+        let bytes_a = SrpBytesASpec {
+            bytes_a: client.bytes_a(),
+            based_on,
+        };
+        let Some(ToClientCommand::SrpBytesSB(spec)) = handshake.handle(handler, &bytes_a.into())?
+        else {
+            panic!("No SrpBytesSB");
+        };
+        let bytes_m = client.process_challenge(&spec.s, &spec.b).unwrap();
+        let reply = handshake.handle(handler, &SrpBytesMSpec { bytes_m }.into())?;
+        assert!(matches!(reply, Some(ToClientCommand::AuthAccept(_))));
+        assert!(handshake.is_accepted());
+        Ok(())
+    }
+
+    #[test]
+    fn register_then_login() {
+        let handler = MemoryAuthHandler::new();
+        let mut handshake = Handshake::new(AuthConfig::default());
+        let Some(ToClientCommand::Hello(hello)) = handshake.handle(&handler, &init("bot")).unwrap()
+        else {
+            panic!("No Hello");
+        };
+        assert!(hello.auth_mechs.first_srp && !hello.auth_mechs.srp);
+        assert_eq!(hello.proto_ver, LATEST_PROTOCOL_VERSION);
+
+        // Not part of the handshake
+        let ignored = handshake.handle(&handler, &Init2Spec { lang: None }.into());
+        assert!(ignored.unwrap().is_none());
+
+        let (salt, verification_key) = srp::salted_verifier("bot", "secret");
+        let first = FirstSrpSpec {
+            salt,
+            verification_key,
+            is_empty: false,
+        };
+        let reply = handshake.handle(&handler, &first.into()).unwrap();
+        assert!(matches!(reply, Some(ToClientCommand::AuthAccept(_))));
+        assert_eq!(handler.len(), 1);
+
+        assert_eq!(srp_login(&handler, "bot", "secret", 1), Ok(()));
+        assert_eq!(
+            srp_login(&handler, "bot", "guess", 1),
+            Err(AccessDeniedCode::WrongPassword)
+        );
+        assert_eq!(
+            srp_login(&handler, "bot", "secret", 0),
+            Err(AccessDeniedCode::UnexpectedData)
+        );
+    }
+
+    #[test]
+    fn legacy_and_refusals() {
+        let handler = MemoryAuthHandler::new();
+        let hash = srp::legacy_password("old", "secret");
+        handler.insert("old", Credentials::Legacy(hash.clone()));
+        // The client uses the hash as the password
+        assert_eq!(srp_login(&handler, "old", &hash, 0), Ok(()));
+
+        let refusal = |name: &str| {
+            let mut handshake = Handshake::new(AuthConfig::default());
+            handshake.handle(&handler, &init(name)).unwrap_err()
+        };
+        assert_eq!(refusal("bad name"), AccessDeniedCode::WrongCharsInName);
+        assert_eq!(refusal(""), AccessDeniedCode::WrongName);
+        assert_eq!(refusal("singleplayer"), AccessDeniedCode::WrongName);
+
+        // A refusal ends the handshake, even for a good Init
+        let mut handshake = Handshake::new(AuthConfig::default());
+        handshake.handle(&handler, &init("bad name")).unwrap_err();
+        assert_eq!(
+            handshake.handle(&handler, &init("good")).unwrap_err(),
+            AccessDeniedCode::WrongCharsInName
+        );
+
+        let config = AuthConfig {
+            disallow_empty_password: true,
+            ..Default::default()
+        };
+        let mut handshake = Handshake::new(config);
+        handshake.handle(&handler, &init("new")).unwrap();
+        let (salt, verification_key) = srp::salted_verifier("new", "");
+        let first = FirstSrpSpec {
+            salt,
+            verification_key,
+            is_empty: true,
+        };
+        assert_eq!(
+            handshake.handle(&handler, &first.into()).unwrap_err(),
+            AccessDeniedCode::EmptyPassword
+        );
+    }
+
+    #[test]
+    fn registered_during_handshake() {
+        let handler = MemoryAuthHandler::new();
+        let mut handshake = Handshake::new(AuthConfig::default());
+        handshake.handle(&handler, &init("sam")).unwrap();
+
+        // Someone else takes the name before FirstSrp arrives
+        handler.set_password("sam", "theirs");
+        let (salt, verification_key) = srp::salted_verifier("sam", "mine");
+        let first = FirstSrpSpec {
+            salt,
+            verification_key,
+            is_empty: false,
+        };
+        assert_eq!(
+            handshake.handle(&handler, &first.into()).unwrap_err(),
+            AccessDeniedCode::UnexpectedData
+        );
+        assert_eq!(srp_login(&handler, "sam", "theirs", 1), Ok(()));
+
+        let credentials = Credentials::from_password("sam", "mine");
+        assert!(handler.register("sam", credentials).is_err());
+        assert_eq!(srp_login(&handler, "sam", "theirs", 1), Ok(()));
+    }
+}
@@ -0,0 +1,375 @@
+//!
+//! Per-connection bandwidth accounting
+//!
+//! BandwidthMeter counts the commands and bytes sent and received on a
+//! connection, broken down by CommandClass, and enforces quotas over a
+//! sliding time window (e.g. at most 50 MB of media per client per minute).
+//!
+//! Bytes are the serialized size of the command, without packet headers.
+//! See wire::overhead for those.
+//!
+//! When a quota is exceeded, its QuotaAction applies, unless a hook has been
+//! installed, in which case the hook decides.
+//!
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::wire::command::Command;
+use crate::wire::command::CommandProperties;
+use crate::wire::command::ToClientCommand;
+use crate::wire::command::ToServerCommand;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CommandClass {
+    /// Media announcements, requests and files
+    Media,
+    /// Item and node definitions
+    Definitions,
+    /// Map blocks and node changes
+    Map,
+    /// Active objects
+    Objects,
+    Inventory,
+    Chat,
+    /// Everything else
+    Other,
+}
+
+impl CommandClass {
+    pub fn of(command: &Command) -> Self {
+        match command {
+            Command::ToClient(command) => Self::of_toclient(command),
+            Command::ToServer(command) => Self::of_toserver(command),
+        }
+    }
+
+    pub fn of_toclient(command: &ToClientCommand) -> Self {
+        use ToClientCommand::*;
+        match command {
+            AnnounceMedia(_) | Media(_) | MediaPush(_) => CommandClass::Media,
+            Itemdef(_) | Nodedef(_) => CommandClass::Definitions,
+            Blockdata(_) | Addnode(_) | Removenode(_) | NodemetaChanged(_) => CommandClass::Map,
+            ActiveObjectRemoveAdd(_) | ActiveObjectMessages(_) => CommandClass::Objects,
+            Inventory(_) | DetachedInventory(_) | InventoryFormspec(_) => CommandClass::Inventory,
+            TCChatMessage(_) => CommandClass::Chat,
+            _ => CommandClass::Other,
+        }
+    }
+
+    pub fn of_toserver(command: &ToServerCommand) -> Self {
+        use ToServerCommand::*;
+        match command {
+            RequestMedia(_) | HaveMedia(_) => CommandClass::Media,
+            Gotblocks(_) | Deletedblocks(_) | NodemetaFields(_) => CommandClass::Map,
+            InventoryAction(_) | InventoryFields(_) => CommandClass::Inventory,
+            TSChatMessage(_) => CommandClass::Chat,
+            _ => CommandClass::Other,
+        }
+    }
+}
+
+impl fmt::Display for CommandClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flow {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ClassStats {
+    pub commands: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BandwidthStats {
+    pub sent: BTreeMap<CommandClass, ClassStats>,
+    pub received: BTreeMap<CommandClass, ClassStats>,
+}
+
+impl BandwidthStats {
+    pub fn flow(&self, flow: Flow) -> &BTreeMap<CommandClass, ClassStats> {
+        match flow {
+            Flow::Sent => &self.sent,
+            Flow::Received => &self.received,
+        }
+    }
+
+    pub fn total(&self, flow: Flow) -> ClassStats {
+        let mut total = ClassStats::default();
+        for stats in self.flow(flow).values() {
+            total.commands += stats.commands;
+            total.bytes += stats.bytes;
+        }
+        total
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Let the command through anyway
+    Allow,
+    /// Don't send (or don't deliver) the command
+    Drop,
+    /// Close the connection
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quota {
+    pub class: CommandClass,
+    pub flow: Flow,
+    pub max_bytes: u64,
+    pub window: Duration,
+    pub action: QuotaAction,
+}
+
+impl Quota {
+    /// Limit the media sent to a client, e.g. to stop a client that keeps
+    /// re-requesting everything.
+    pub fn media_sent(max_bytes: u64, window: Duration) -> Self {
+        Self {
+            class: CommandClass::Media,
+            flow: Flow::Sent,
+            max_bytes,
+            window,
+            action: QuotaAction::Drop,
+        }
+    }
+}
+
+/// Passed to the hook when a quota would be exceeded
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaExceeded {
+    pub quota: Quota,
+    pub command_name: &'static str,
+    /// Bytes already used in the window
+    pub used: u64,
+    /// Size of the command that would exceed the quota
+    pub bytes: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} quota exceeded by {} ({} + {} bytes > {} per {:?})",
+            self.quota.class,
+            self.command_name,
+            self.used,
+            self.bytes,
+            self.quota.max_bytes,
+            self.quota.window
+        )
+    }
+}
+
+pub type QuotaHook = Box<dyn FnMut(&QuotaExceeded) -> QuotaAction + Send + Sync>;
+
+struct QuotaState {
+    quota: Quota,
+    // (time, bytes) within the window, oldest first
+    events: VecDeque<(Instant, u64)>,
+    used: u64,
+}
+
+impl QuotaState {
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(time, bytes)) = self.events.front() {
+            if now.saturating_duration_since(time) < self.quota.window {
+                break;
+            }
+            self.events.pop_front();
+            self.used -= bytes;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BandwidthMeter {
+    stats: BandwidthStats,
+    quotas: Vec<QuotaState>,
+    hook: Option<QuotaHook>,
+}
+
+impl BandwidthMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> &BandwidthStats {
+        &self.stats
+    }
+
+    pub fn add_quota(&mut self, quota: Quota) {
+        self.quotas.push(QuotaState {
+            quota,
+            events: VecDeque::new(),
+            used: 0,
+        });
+    }
+
+    /// Removes every quota, e.g. to replace them with new limits
+    pub fn clear_quotas(&mut self) {
+        self.quotas.clear();
+    }
+
+    /// Decide what to do when a quota is exceeded, instead of the
+    /// quota's own action.
+    pub fn set_hook(&mut self, hook: QuotaHook) {
+        self.hook = Some(hook);
+    }
+
+    /// Account for a command of `bytes` bytes, and check the quotas.
+    /// Commands that aren't allowed aren't counted.
+    pub fn record(
+        &mut self,
+        flow: Flow,
+        command: &Command,
+        bytes: usize,
+        now: Instant,
+    ) -> QuotaAction {
+        let class = CommandClass::of(command);
+        let bytes = bytes as u64;
+        let mut action = QuotaAction::Allow;
+        for state in self.quotas.iter_mut() {
+            if state.quota.class != class || state.quota.flow != flow {
+                continue;
+            }
+            state.expire(now);
+            if state.used + bytes <= state.quota.max_bytes {
+                continue;
+            }
+            let exceeded = QuotaExceeded {
+                quota: state.quota.clone(),
+                command_name: command.command_name(),
+                used: state.used,
+                bytes,
+            };
+            let verdict = match self.hook.as_mut() {
+                Some(hook) => hook(&exceeded),
+                None => state.quota.action,
+            };
+            action = worst(action, verdict);
+        }
+        if action != QuotaAction::Allow {
+            return action;
+        }
+        for state in self.quotas.iter_mut() {
+            if state.quota.class == class && state.quota.flow == flow {
+                state.events.push_back((now, bytes));
+                state.used += bytes;
+            }
+        }
+        let stats = match flow {
+            Flow::Sent => &mut self.stats.sent,
+            Flow::Received => &mut self.stats.received,
+        };
+        let entry = stats.entry(class).or_default();
+        entry.commands += 1;
+        entry.bytes += bytes;
+        QuotaAction::Allow
+    }
+}
+
+fn worst(a: QuotaAction, b: QuotaAction) -> QuotaAction {
+    use QuotaAction::*;
+    match (a, b) {
+        (Disconnect, _) | (_, Disconnect) => Disconnect,
+        (Drop, _) | (_, Drop) => Drop,
+        _ => Allow,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::MediaSpec;
+    use crate::wire::command::RequestMediaSpec;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    fn media() -> Command {
+        Command::ToClient(
+            MediaSpec {
+                num_bunches: 1,
+                bunch_index: 0,
+                files: Vec::new(),
+            }
+            .into(),
+        )
+    }
+
+    #[test]
+    fn media_quota() {
+        let start = Instant::now();
+        let mut meter = BandwidthMeter::new();
+        meter.add_quota(Quota::media_sent(1000, Duration::from_secs(60)));
+
+        assert_eq!(
+            meter.record(Flow::Sent, &media(), 600, start),
+            QuotaAction::Allow
+        );
+        assert_eq!(
+            meter.record(Flow::Sent, &media(), 600, start),
+            QuotaAction::Drop
+        );
+        // Only counts sent media
+        let request = Command::ToServer(RequestMediaSpec { files: Vec::new() }.into());
+        assert_eq!(
+            meter.record(Flow::Received, &request, 5000, start),
+            QuotaAction::Allow
+        );
+        // The window slides
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            meter.record(Flow::Sent, &media(), 600, later),
+            QuotaAction::Allow
+        );
+
+        let sent = meter.stats().sent[&CommandClass::Media];
+        assert_eq!(
+            sent,
+            ClassStats {
+                commands: 2,
+                bytes: 1200
+            }
+        );
+        assert_eq!(meter.stats().total(Flow::Received).bytes, 5000);
+    }
+
+    #[test]
+    fn hook_decides() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let mut meter = BandwidthMeter::new();
+        meter.add_quota(Quota::media_sent(100, Duration::from_secs(60)));
+        let counter = calls.clone();
+        meter.set_hook(Box::new(move |exceeded| {
+            assert_eq!(exceeded.command_name, "Media");
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                QuotaAction::Allow
+            } else {
+                QuotaAction::Disconnect
+            }
+        }));
+        let now = Instant::now();
+        assert_eq!(
+            meter.record(Flow::Sent, &media(), 200, now),
+            QuotaAction::Allow
+        );
+        assert_eq!(
+            meter.record(Flow::Sent, &media(), 200, now),
+            QuotaAction::Disconnect
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,13 +1,37 @@
+use std::collections::VecDeque;
 use std::net::SocketAddr;
+use std::sync::Mutex;
 
 use anyhow::bail;
 
+use super::conn::post_mortem;
 use super::socket::MinetestSocket;
+use super::srp;
+use super::srp::SrpClient;
+use super::transport::DatagramTransport;
+use super::watchdog::RecentCommands;
+use crate::peer::delivery::DeliveryOverrides;
 use crate::peer::peer::Peer;
+use crate::peer::peer::SendWarning;
+use crate::peer::peer::UnreliableSplitPolicy;
+use crate::peer::stats::ConnectionStats;
 use crate::wire::command::*;
+use crate::wire::compat::CompatMode;
+use crate::wire::packet::LATEST_PROTOCOL_VERSION;
+use crate::wire::packet::SER_FMT_HIGHEST_READ;
+use crate::wire::types::AccessDeniedCode;
+use crate::wire::types::CommandDirection;
+use crate::wire::types::ProtocolContext;
+
+/// Oldest protocol version offered in Init
+const MIN_PROTOCOL_VERSION: u16 = 37;
 
 pub struct MinetestClient {
     remote_peer: Peer,
+    /// Received during login, not yet returned by recv
+    pending: VecDeque<ToClientCommand>,
+    /// See keep_recent
+    recent: Option<Mutex<RecentCommands>>,
 }
 
 impl MinetestClient {
@@ -17,25 +41,342 @@ impl MinetestClient {
         } else {
             "[::]:0".parse()?
         };
-        let mut socket = MinetestSocket::new(bind_addr, false).await?;
+        let socket = MinetestSocket::new(bind_addr, false).await?;
+        Ok(Self::with_socket(socket, connect_to).await)
+    }
 
+    /// Connects over `transport` instead of UDP (see transport)
+    pub async fn connect_with_transport<T: DatagramTransport>(
+        transport: T,
+        connect_to: SocketAddr,
+    ) -> anyhow::Result<Self> {
+        let socket = MinetestSocket::with_transport(transport, false)?;
+        Ok(Self::with_socket(socket, connect_to).await)
+    }
+
+    async fn with_socket(mut socket: MinetestSocket, connect_to: SocketAddr) -> Self {
         // Send a null packet to server.
         // It should answer back, establishing a peer ids.
         let remote_peer = socket.add_peer(connect_to).await;
 
-        Ok(Self { remote_peer })
+        Self {
+            remote_peer,
+            pending: VecDeque::new(),
+            recent: None,
+        }
+    }
+
+    /// Connects and logs in. See login.
+    pub async fn connect_and_login(
+        connect_to: SocketAddr,
+        player_name: &str,
+        password: &str,
+    ) -> anyhow::Result<Self> {
+        let mut client = Self::connect(connect_to).await?;
+        client.login(player_name, password).await?;
+        Ok(client)
+    }
+
+    /// The login handshake, with SRP. A new account is registered with
+    /// `password`.
+    ///
+    /// ```text
+    /// C->S  Init
+    /// S->C  Hello                 (auth mechanisms the account can use)
+    /// C->S  FirstSrp              (new account: register a verifier)
+    ///    or SrpBytesA
+    /// S->C  SrpBytesSB
+    /// C->S  SrpBytesM
+    /// S->C  AuthAccept
+    /// C->S  Init2
+    /// S->C  definitions, AnnounceMedia, ...
+    /// C->S  ClientReady
+    /// ```
+    ///
+    /// Media is never requested. Everything the server sent meanwhile is
+    /// still returned by recv, so nothing (like the definitions) is missed.
+    /// Fails with a Denied if the server refuses the login.
+    pub async fn login(&mut self, player_name: &str, password: &str) -> anyhow::Result<()> {
+        self.send(
+            InitSpec {
+                serialization_ver_max: SER_FMT_HIGHEST_READ,
+                supp_compr_modes: 0,
+                min_net_proto_version: MIN_PROTOCOL_VERSION,
+                max_net_proto_version: LATEST_PROTOCOL_VERSION,
+                player_name: player_name.to_string(),
+            }
+            .into(),
+        )
+        .await?;
+
+        let mut srp_client: Option<SrpClient> = None;
+        loop {
+            let command = self.recv_remote().await?;
+            Denied::check(&command)?;
+            match &command {
+                ToClientCommand::Hello(spec) => {
+                    if spec.auth_mechs.first_srp {
+                        let (salt, verification_key) = srp::salted_verifier(player_name, password);
+                        self.send(
+                            FirstSrpSpec {
+                                salt,
+                                verification_key,
+                                is_empty: password.is_empty(),
+                            }
+                            .into(),
+                        )
+                        .await?;
+                    } else if spec.auth_mechs.srp {
+                        let client = SrpClient::new(player_name, password);
+                        self.send(
+                            SrpBytesASpec {
+                                bytes_a: client.bytes_a(),
+                                based_on: 1,
+                            }
+                            .into(),
+                        )
+                        .await?;
+                        srp_client = Some(client);
+                    } else if spec.auth_mechs.legacy_password {
+                        let legacy = srp::legacy_password(player_name, password);
+                        let client = SrpClient::new(player_name, &legacy);
+                        self.send(
+                            SrpBytesASpec {
+                                bytes_a: client.bytes_a(),
+                                based_on: 0,
+                            }
+                            .into(),
+                        )
+                        .await?;
+                        srp_client = Some(client);
+                    } else {
+                        bail!("Unsupported auth mechanisms: {:?}", spec.auth_mechs);
+                    }
+                }
+                ToClientCommand::SrpBytesSB(spec) => {
+                    let Some(client) = &srp_client else {
+                        bail!("SrpBytesSB before SrpBytesA");
+                    };
+                    let bytes_m = client.process_challenge(&spec.s, &spec.b)?;
+                    self.send(SrpBytesMSpec { bytes_m }.into()).await?;
+                }
+                ToClientCommand::AuthAccept(_) => {
+                    self.send(Init2Spec { lang: None }.into()).await?;
+                }
+                ToClientCommand::AnnounceMedia(_) => {
+                    self.send(
+                        ClientReadySpec {
+                            major_ver: 5,
+                            minor_ver: 8,
+                            patch_ver: 0,
+                            reserved: 0,
+                            full_ver: format!("minetest-rs {}", env!("CARGO_PKG_VERSION")),
+                            formspec_ver: Some(6),
+                        }
+                        .into(),
+                    )
+                    .await?;
+                    self.pending.push_back(command);
+                    return Ok(());
+                }
+                _ => (),
+            }
+            self.pending.push_back(command);
+        }
     }
 
     /// If this fails, the client has disconnected.
     pub async fn recv(&mut self) -> anyhow::Result<ToClientCommand> {
-        match self.remote_peer.recv().await? {
-            Command::ToClient(cmd) => Ok(cmd),
-            Command::ToServer(_) => bail!("Invalid packet direction"),
+        match self.pending.pop_front() {
+            Some(command) => Ok(command),
+            None => self.recv_remote().await,
         }
     }
 
     /// If this fails, the client has disconnected.
     pub async fn send(&mut self, command: ToServerCommand) -> anyhow::Result<()> {
-        self.remote_peer.send(Command::ToServer(command)).await
+        let command = Command::ToServer(command);
+        let context = self.remote_peer.send_context();
+        match self.remember(&command, context)? {
+            Some(size) => self.remote_peer.send_measured(command, context, size).await,
+            None => self.remote_peer.send(command).await,
+        }
+    }
+
+    /// See Peer::stats
+    pub fn stats(&self) -> ConnectionStats {
+        self.remote_peer.stats()
+    }
+
+    /// See Peer::set_audit
+    pub fn set_audit(&self, audit: bool) -> anyhow::Result<()> {
+        self.remote_peer.set_audit(audit)
+    }
+
+    /// See Peer::set_delivery_overrides
+    pub fn set_delivery_overrides(&mut self, overrides: DeliveryOverrides) {
+        self.remote_peer.set_delivery_overrides(overrides)
+    }
+
+    pub fn delivery_overrides(&self) -> &DeliveryOverrides {
+        self.remote_peer.delivery_overrides()
+    }
+
+    pub fn split_policy(&self) -> UnreliableSplitPolicy {
+        self.remote_peer.split_policy()
+    }
+
+    /// See Peer::set_split_policy
+    pub fn set_split_policy(&mut self, policy: UnreliableSplitPolicy) {
+        self.remote_peer.set_split_policy(policy)
+    }
+
+    pub fn compat_mode(&self) -> CompatMode {
+        self.remote_peer.compat_mode()
+    }
+
+    /// See Peer::set_compat_mode
+    pub fn set_compat_mode(&mut self, mode: CompatMode) {
+        self.remote_peer.set_compat_mode(mode)
+    }
+
+    /// See Peer::send_warnings
+    pub fn send_warnings(&mut self) -> Vec<SendWarning> {
+        self.remote_peer.send_warnings()
+    }
+
+    /// See PeerMonitor::debug_dump
+    pub fn debug_dump(&self) -> String {
+        self.remote_peer.debug_dump()
+    }
+
+    /// See MinetestConnection::keep_recent
+    pub fn keep_recent(&mut self, recent: RecentCommands) {
+        self.recent = Some(Mutex::new(recent));
+    }
+
+    pub fn recent_commands(&self) -> Option<RecentCommands> {
+        self.recent
+            .as_ref()
+            .map(|recent| recent.lock().unwrap().clone())
+    }
+
+    /// See MinetestConnection::post_mortem
+    pub fn post_mortem(&self) -> String {
+        post_mortem(self.debug_dump(), self.recent.as_ref())
+    }
+
+    /// Only serializes the command when keeping recent ones, returning
+    /// its size then
+    fn remember(
+        &self,
+        command: &Command,
+        context: ProtocolContext,
+    ) -> anyhow::Result<Option<usize>> {
+        let Some(recent) = &self.recent else {
+            return Ok(None);
+        };
+        let size = command.check_serialize(context)?;
+        recent.lock().unwrap().push(command, size);
+        Ok(Some(size))
+    }
+
+    /// Disconnects, once the commands already sent are delivered (see
+    /// Peer::close)
+    pub async fn disconnect(self) -> anyhow::Result<()> {
+        self.remote_peer.close().await
+    }
+
+    async fn recv_remote(&mut self) -> anyhow::Result<ToClientCommand> {
+        let command = self.remote_peer.recv().await?;
+        let context = ProtocolContext {
+            dir: CommandDirection::ToClient,
+            ..self.remote_peer.send_context()
+        };
+        self.remember(&command, context)?;
+        match command {
+            Command::ToClient(cmd) => Ok(cmd),
+            Command::ToServer(_) => bail!("Invalid packet direction"),
+        }
+    }
+}
+
+/// The server refused the login, or dropped the connection.
+///
+/// Servers send TOCLIENT_ACCESS_DENIED with a code, and very old ones
+/// TOCLIENT_ACCESS_DENIED_LEGACY with only a reason. Both become a Denied,
+/// so applications handle rejection one way.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("Access denied: {message}")]
+pub struct Denied {
+    /// AccessDeniedCode::CustomString with the reason, for the legacy command
+    pub code: AccessDeniedCode,
+    /// What the engine's client would show
+    pub message: String,
+    /// The server asked the client to reconnect (e.g. it is restarting)
+    pub reconnect: bool,
+}
+
+impl Denied {
+    /// The Denied for either deny command, None for anything else
+    pub fn from_toclient(command: &ToClientCommand) -> Option<Self> {
+        let code = match command {
+            ToClientCommand::AccessDenied(spec) => spec.code.clone(),
+            ToClientCommand::AccessDeniedLegacy(spec) => {
+                AccessDeniedCode::CustomString(spec.reason.clone())
+            }
+            _ => return None,
+        };
+        Some(Self {
+            message: code.to_str().to_string(),
+            reconnect: code.should_reconnect(),
+            code,
+        })
+    }
+
+    /// Fails with the Denied if `command` is a deny command
+    pub fn check(command: &ToClientCommand) -> Result<(), Self> {
+        match Self::from_toclient(command) {
+            Some(denied) => Err(denied),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_deny_paths() {
+        let denied = Denied::from_toclient(
+            &AccessDeniedSpec {
+                code: AccessDeniedCode::Shutdown(String::new(), true),
+            }
+            .into(),
+        )
+        .unwrap();
+        assert_eq!(denied.message, "Server shutting down");
+        assert!(denied.reconnect);
+
+        let legacy: ToClientCommand = AccessDeniedLegacySpec {
+            reason: "Wrong password".to_string(),
+        }
+        .into();
+        let denied = Denied::check(&legacy).unwrap_err();
+        assert_eq!(
+            denied.code,
+            AccessDeniedCode::CustomString("Wrong password".to_string())
+        );
+        assert_eq!(denied.to_string(), "Access denied: Wrong password");
+        assert!(!denied.reconnect);
+
+        assert!(Denied::check(
+            &AccessDeniedSpec {
+                code: AccessDeniedCode::WrongPassword
+            }
+            .into()
+        )
+        .is_err());
     }
 }
@@ -0,0 +1,311 @@
+//!
+//! Protocol compatibility reports
+//!
+//! Which commands the clients out there (and their servers) actually send,
+//! and what this crate fails to parse, by client version. It shows which
+//! protocol gaps matter most, e.g. a command added in a newer release that
+//! its clients already send.
+//!
+//! Nothing is collected unless asked for. A CompatTally counts what goes
+//! through one connection, and is added to a CompatReport when the
+//! connection ends:
+//!
+//! ```text
+//! let mut tally = CompatTally::new();
+//! tally.command(&command);        // every command, both ways
+//! tally.failure(&err);            // if the connection fails
+//! report.add(tally);
+//! ```
+//!
+//! A report is anonymous: only counts, by command name, client release
+//! (major.minor.patch) and protocol version. No names, addresses or
+//! command contents. With the serde feature, it serializes (e.g. to JSON)
+//! to be shared, and deserializes to add to it later:
+//!
+//! ```text
+//! {
+//!   "versions": {
+//!     "5.9.0, protocol 45": {
+//!       "sessions": 3,
+//!       "to_server": { "Init": 3, "Interact": 412, ... },
+//!       "to_client": { "Blockdata": 9120, ... },
+//!       "unknown_commands": { "ToClient 0x64": 2 },
+//!       "parse_failures": { "packet: Invalid skybox type: 4": 1 }
+//!     }
+//!   }
+//! }
+//! ```
+//!
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::peer::quarantine::ParseFailure;
+use crate::wire::command::CommandRef;
+use crate::wire::command::ToServerCommand;
+use crate::wire::deser::DeserializeError;
+
+/// Longest parse failure message kept
+const MAX_MESSAGE_LEN: usize = 80;
+
+/// What a client runs, as far as it told
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ClientVersion {
+    /// major.minor.patch, from ClientReady
+    pub release: Option<(u8, u8, u8)>,
+    /// The highest protocol version it supports, from Init
+    pub max_protocol: Option<u16>,
+}
+
+impl fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.release {
+            Some((major, minor, patch)) => write!(f, "{}.{}.{}", major, minor, patch)?,
+            None => write!(f, "unknown")?,
+        }
+        if let Some(protocol) = self.max_protocol {
+            write!(f, ", protocol {}", protocol)?;
+        }
+        Ok(())
+    }
+}
+
+/// The counts for one client version
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct VersionCounts {
+    pub sessions: u64,
+    /// Commands by name
+    pub to_server: BTreeMap<String, u64>,
+    pub to_client: BTreeMap<String, u64>,
+    /// Command ids this crate doesn't know, as "ToClient 0x64"
+    pub unknown_commands: BTreeMap<String, u64>,
+    /// Other packets and commands that failed to parse, by error
+    pub parse_failures: BTreeMap<String, u64>,
+}
+
+impl VersionCounts {
+    pub fn merge(&mut self, other: &VersionCounts) {
+        self.sessions += other.sessions;
+        for (mine, theirs) in [
+            (&mut self.to_server, &other.to_server),
+            (&mut self.to_client, &other.to_client),
+            (&mut self.unknown_commands, &other.unknown_commands),
+            (&mut self.parse_failures, &other.parse_failures),
+        ] {
+            for (key, count) in theirs {
+                *mine.entry(key.clone()).or_default() += count;
+            }
+        }
+    }
+}
+
+/// What went through one connection
+#[derive(Debug, Clone, Default)]
+pub struct CompatTally {
+    version: ClientVersion,
+    counts: VersionCounts,
+}
+
+impl CompatTally {
+    pub fn new() -> Self {
+        Self {
+            version: ClientVersion::default(),
+            counts: VersionCounts {
+                sessions: 1,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn version(&self) -> ClientVersion {
+        self.version
+    }
+
+    pub fn counts(&self) -> &VersionCounts {
+        &self.counts
+    }
+
+    /// Counts a command received, from either side. The client's version
+    /// is taken from its Init and ClientReady.
+    pub fn command<Cmd: CommandRef>(&mut self, command: &Cmd) {
+        let name = command.command_name();
+        if let Some(command) = command.toserver_ref() {
+            match command {
+                ToServerCommand::Init(spec) => {
+                    self.version.max_protocol = Some(spec.max_net_proto_version)
+                }
+                ToServerCommand::ClientReady(spec) => {
+                    self.version.release = Some((spec.major_ver, spec.minor_ver, spec.patch_ver))
+                }
+                _ => (),
+            }
+            count(&mut self.counts.to_server, name);
+        } else {
+            count(&mut self.counts.to_client, name);
+        }
+    }
+
+    /// Counts a connection error, if it is a ParseFailure
+    pub fn failure(&mut self, err: &anyhow::Error) {
+        let Some(failure) = err.downcast_ref::<ParseFailure>() else {
+            return;
+        };
+        match DeserializeError::find(&failure.error).and_then(DeserializeError::bad_packet_id) {
+            Some((dir, id)) => {
+                count(
+                    &mut self.counts.unknown_commands,
+                    &format!("{:?} 0x{:02x}", dir, id),
+                );
+            }
+            None => {
+                let message = anonymize(&failure.error.root_cause().to_string());
+                count(
+                    &mut self.counts.parse_failures,
+                    &format!("{}: {}", failure.unit.name(), message),
+                );
+            }
+        }
+    }
+}
+
+fn count(counts: &mut BTreeMap<String, u64>, key: &str) {
+    match counts.get_mut(key) {
+        Some(count) => *count += 1,
+        None => {
+            counts.insert(key.to_string(), 1);
+        }
+    }
+}
+
+/// An error message without the strings it quotes (which could be chat,
+/// names, ...), and not too long
+fn anonymize(message: &str) -> String {
+    let mut out = String::new();
+    let mut quoted = false;
+    for c in message.chars() {
+        if c == '"' {
+            if !quoted {
+                out.push_str("\"...\"");
+            }
+            quoted = !quoted;
+        } else if !quoted {
+            out.push(c);
+        }
+    }
+    if out.chars().count() > MAX_MESSAGE_LEN {
+        out = out.chars().take(MAX_MESSAGE_LEN).collect::<String>() + "...";
+    }
+    out
+}
+
+/// Counts from any number of connections, by client version
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct CompatReport {
+    /// By ClientVersion, as text
+    pub versions: BTreeMap<String, VersionCounts>,
+}
+
+impl CompatReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, tally: CompatTally) {
+        self.versions
+            .entry(tally.version.to_string())
+            .or_default()
+            .merge(&tally.counts);
+    }
+
+    pub fn merge(&mut self, other: &CompatReport) {
+        for (version, counts) in &other.versions {
+            self.versions
+                .entry(version.clone())
+                .or_default()
+                .merge(counts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::quarantine::ParsedUnit;
+    use crate::wire::command::BreathSpec;
+    use crate::wire::command::ClientReadySpec;
+    use crate::wire::command::Command;
+    use crate::wire::command::InitSpec;
+    use crate::wire::command::ToClientCommand;
+    use crate::wire::deser::Deserialize;
+    use crate::wire::deser::Deserializer;
+    use crate::wire::types::ProtocolContext;
+
+    fn failure(data: &[u8]) -> anyhow::Error {
+        let context = ProtocolContext::latest_for_receive(false);
+        let error = Command::deserialize(&mut Deserializer::new(context, data)).unwrap_err();
+        let addr = "127.0.0.1:30000".parse().unwrap();
+        ParseFailure::new(ParsedUnit::SplitCommand, addr, context, data, error).into()
+    }
+
+    #[test]
+    fn tally_by_version() {
+        let mut report = CompatReport::new();
+        for _ in 0..2 {
+            let mut tally = CompatTally::new();
+            tally.command(&ToServerCommand::Init(Box::new(InitSpec {
+                serialization_ver_max: 29,
+                supp_compr_modes: 0,
+                min_net_proto_version: 37,
+                max_net_proto_version: 46,
+                player_name: "alice".into(),
+            })));
+            tally.command(&ToClientCommand::Breath(Box::new(BreathSpec {
+                breath: 10,
+            })));
+            tally.command(&ToServerCommand::ClientReady(Box::new(ClientReadySpec {
+                major_ver: 5,
+                minor_ver: 10,
+                patch_ver: 0,
+                reserved: 0,
+                full_ver: "5.10.0-dev".into(),
+                formspec_ver: Some(8),
+            })));
+            tally.failure(&failure(&[0x00, 0x64]));
+            report.add(tally);
+        }
+        // Never got far enough to say
+        let mut tally = CompatTally::new();
+        tally.failure(&failure(&[0x00, 0x02, 0xff]));
+        tally.failure(&anyhow::anyhow!("not a parse failure"));
+        report.add(tally);
+
+        let counts = &report.versions["5.10.0, protocol 46"];
+        assert_eq!(counts.sessions, 2);
+        assert_eq!(counts.to_server["Init"], 2);
+        assert_eq!(counts.to_server["ClientReady"], 2);
+        assert_eq!(counts.to_client["Breath"], 2);
+        assert_eq!(counts.unknown_commands["ToServer 0x64"], 2);
+        let unknown = &report.versions["unknown"];
+        assert_eq!(unknown.sessions, 1);
+        assert_eq!(unknown.parse_failures.len(), 1);
+        let key = unknown.parse_failures.keys().next().unwrap();
+        assert!(key.starts_with("split command: "));
+
+        let mut merged = report.clone();
+        merged.merge(&report);
+        assert_eq!(merged.versions["5.10.0, protocol 46"].sessions, 4);
+        assert_eq!(merged.versions["unknown"].parse_failures[key], 2);
+    }
+
+    #[test]
+    fn anonymized_messages() {
+        assert_eq!(
+            anonymize("Unknown InventoryLocation: \"player:alice\""),
+            "Unknown InventoryLocation: \"...\""
+        );
+        assert_eq!(anonymize(&"x".repeat(100)).len(), MAX_MESSAGE_LEN + 3);
+    }
+}
@@ -3,48 +3,283 @@
 //!
 //!
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
+use super::bandwidth::BandwidthMeter;
+use super::bandwidth::Flow;
+use super::bandwidth::QuotaAction;
+use super::watchdog::RecentCommands;
+use super::watchdog::StallDetector;
+use super::watchdog::DEFAULT_RECENT_COMMANDS;
+use crate::peer::congestion::ChannelLoad;
+use crate::peer::congestion::CongestionConfig;
+use crate::peer::congestion::CongestionEvent;
+use crate::peer::delivery::DeliveryOverrides;
+use crate::peer::peer::KeepaliveConfig;
 use crate::peer::peer::Peer;
+use crate::peer::peer::SendWarning;
+use crate::peer::peer::UnreliableSplitPolicy;
+use crate::peer::stats::ConnectionStats;
 use crate::wire::command::*;
+use crate::wire::compat::CompatMode;
 use crate::wire::types::*;
 use anyhow::bail;
 use anyhow::Result;
 
+/// How often watch_for_stalls looks at the stats
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// This is owned by the driver
 pub struct MinetestConnection {
     peer: Peer,
+    meter: BandwidthMeter,
+    /// See keep_recent
+    recent: Option<Arc<Mutex<RecentCommands>>>,
 }
 
 impl MinetestConnection {
     pub fn new(peer: Peer) -> Self {
-        Self { peer: peer }
+        Self {
+            peer,
+            meter: BandwidthMeter::new(),
+            recent: None,
+        }
+    }
+
+    /// Bandwidth used by this connection, and its quotas
+    pub fn meter(&self) -> &BandwidthMeter {
+        &self.meter
+    }
+
+    pub fn meter_mut(&mut self) -> &mut BandwidthMeter {
+        &mut self.meter
     }
 
     pub fn remote_addr(&self) -> SocketAddr {
         self.peer.remote_addr()
     }
 
+    /// The protocol version commands are sent in. Once the client's Init
+    /// has been received, this is the highest version both sides support
+    /// (see wire::packet::negotiate_protocol_version), the one to announce
+    /// in Hello.
+    pub fn protocol_version(&self) -> u16 {
+        self.peer.send_context().protocol_version
+    }
+
+    /// The context commands are sent in. See protocol_version.
+    pub fn send_context(&self) -> ProtocolContext {
+        self.peer.send_context()
+    }
+
+    /// See Peer::set_zlib_level
+    pub fn set_zlib_level(&self, level: u8) -> Result<()> {
+        self.peer.set_zlib_level(level)
+    }
+
+    /// See Peer::channel_load
+    pub fn channel_load(&self) -> [ChannelLoad; 3] {
+        self.peer.channel_load()
+    }
+
+    /// See Peer::stats
+    pub fn stats(&self) -> ConnectionStats {
+        self.peer.stats()
+    }
+
+    /// See PeerMonitor::debug_dump
+    pub fn debug_dump(&self) -> String {
+        self.peer.debug_dump()
+    }
+
+    /// Keeps the last commands sent and received in `recent` (see
+    /// services::watchdog), for post_mortem. Replaces the ones kept so far.
+    pub fn keep_recent(&mut self, recent: RecentCommands) {
+        self.recent = Some(Arc::new(Mutex::new(recent)));
+    }
+
+    /// The last commands, with keep_recent
+    pub fn recent_commands(&self) -> Option<RecentCommands> {
+        self.recent
+            .as_ref()
+            .map(|recent| recent.lock().unwrap().clone())
+    }
+
+    /// What to print when the connection fails: debug_dump, and the last
+    /// commands with keep_recent
+    pub fn post_mortem(&self) -> String {
+        post_mortem(self.debug_dump(), self.recent.as_deref())
+    }
+
+    /// Looks for stalls (see services::watchdog) in a task of its own,
+    /// until the connection is closed. A stall is printed along with the
+    /// post_mortem. This keeps the last DEFAULT_RECENT_COMMANDS commands,
+    /// unless keep_recent was called first.
+    pub fn watch_for_stalls(&mut self, timeout: Duration) {
+        if self.recent.is_none() {
+            self.keep_recent(RecentCommands::new(DEFAULT_RECENT_COMMANDS));
+        }
+        let recent = self.recent.clone();
+        let monitor = self.peer.monitor();
+        tokio::spawn(async move {
+            let mut detector = StallDetector::new(timeout);
+            let mut interval = tokio::time::interval(STALL_CHECK_INTERVAL);
+            while monitor.is_running() {
+                interval.tick().await;
+                if let Some(stall) = detector.check(&monitor.stats(), Instant::now()) {
+                    eprintln!(
+                        "Stall on connection from {}: {}\n{}",
+                        monitor.remote_addr(),
+                        stall,
+                        post_mortem(monitor.debug_dump(), recent.as_deref())
+                    );
+                }
+            }
+        });
+    }
+
+    fn remember(&self, command: &Command, size: usize) {
+        if let Some(recent) = &self.recent {
+            recent.lock().unwrap().push(command, size);
+        }
+    }
+
+    /// See Peer::set_audit
+    pub fn set_audit(&self, audit: bool) -> Result<()> {
+        self.peer.set_audit(audit)
+    }
+
+    /// See Peer::set_delivery_overrides
+    pub fn set_delivery_overrides(&mut self, overrides: DeliveryOverrides) {
+        self.peer.set_delivery_overrides(overrides)
+    }
+
+    pub fn delivery_overrides(&self) -> &DeliveryOverrides {
+        self.peer.delivery_overrides()
+    }
+
+    /// See Peer::set_congestion_config
+    pub fn set_congestion_config(&self, config: CongestionConfig) -> Result<()> {
+        self.peer.set_congestion_config(config)
+    }
+
+    /// See Peer::set_keepalive
+    pub fn set_keepalive(&self, config: KeepaliveConfig) -> Result<()> {
+        self.peer.set_keepalive(config)
+    }
+
+    /// Congestion events since the last call, oldest first. A server
+    /// should send less of the map to this client while congested.
+    pub fn congestion_events(&mut self) -> Vec<CongestionEvent> {
+        self.peer.congestion_events()
+    }
+
+    pub fn split_policy(&self) -> UnreliableSplitPolicy {
+        self.peer.split_policy()
+    }
+
+    /// See Peer::set_split_policy
+    pub fn set_split_policy(&mut self, policy: UnreliableSplitPolicy) {
+        self.peer.set_split_policy(policy)
+    }
+
+    pub fn compat_mode(&self) -> CompatMode {
+        self.peer.compat_mode()
+    }
+
+    /// See Peer::set_compat_mode
+    pub fn set_compat_mode(&mut self, mode: CompatMode) {
+        self.peer.set_compat_mode(mode)
+    }
+
+    /// See Peer::send_warnings
+    pub fn send_warnings(&mut self) -> Vec<SendWarning> {
+        self.peer.send_warnings()
+    }
+
     /// Send a command to the client
-    pub async fn send(&self, command: ToClientCommand) -> Result<()> {
-        self.peer.send(Command::ToClient(command)).await
+    /// Commands dropped by a quota are not sent, and a quota asking
+    /// for a disconnect fails with BandwidthError::QuotaDisconnect.
+    pub async fn send(&mut self, command: ToClientCommand) -> Result<()> {
+        let command = Command::ToClient(command);
+        let context = self.peer.send_context();
+        let size = command.check_serialize(context)?;
+        match self
+            .meter
+            .record(Flow::Sent, &command, size, Instant::now())
+        {
+            QuotaAction::Allow => {
+                self.remember(&command, size);
+                self.peer.send_measured(command, context, size).await
+            }
+            QuotaAction::Drop => Ok(()),
+            QuotaAction::Disconnect => {
+                bail!(BandwidthError::QuotaDisconnect(command.command_name()))
+            }
+        }
     }
 
-    pub async fn send_access_denied(&self, code: AccessDeniedCode) -> Result<()> {
+    pub async fn send_access_denied(&mut self, code: AccessDeniedCode) -> Result<()> {
         self.send(AccessDeniedSpec { code }.into()).await
     }
 
+    /// Tells the client why with an AccessDenied (e.g. AccessDeniedCode::Shutdown),
+    /// and closes the connection once it's delivered (see Peer::close)
+    pub async fn close(mut self, code: AccessDeniedCode) -> Result<()> {
+        let sent = self.send_access_denied(code).await;
+        let closed = self.peer.close().await;
+        sent.and(closed)
+    }
+
     /// Await a command from the peer
     /// Returns (channel, reliable flag, Command)
     /// Returns None when the peer is disconnected
+    /// Commands dropped by a quota are skipped.
     pub async fn recv(&mut self) -> Result<ToServerCommand> {
-        match self.peer.recv().await? {
-            Command::ToServer(command) => Ok(command),
-            Command::ToClient(_) => {
+        loop {
+            let command = self.peer.recv().await?;
+            if let Command::ToClient(_) = command {
                 bail!("Received wrong direction command from SocketPeer")
             }
+            let context = ProtocolContext {
+                dir: CommandDirection::ToServer,
+                ..self.peer.send_context()
+            };
+            let size = command.check_serialize(context)?;
+            match self
+                .meter
+                .record(Flow::Received, &command, size, Instant::now())
+            {
+                QuotaAction::Allow => (),
+                QuotaAction::Drop => continue,
+                QuotaAction::Disconnect => {
+                    bail!(BandwidthError::QuotaDisconnect(command.command_name()))
+                }
+            }
+            self.remember(&command, size);
+            if let Command::ToServer(command) = command {
+                return Ok(command);
+            }
         }
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum BandwidthError {
+    #[error("Bandwidth quota exceeded by {0}, disconnecting")]
+    QuotaDisconnect(&'static str),
+}
+
 /// This is owned by the MinetestServer
 pub struct MinetestConnectionRecord {}
+
+/// A debug_dump, followed by the recent commands if kept
+pub(crate) fn post_mortem(dump: String, recent: Option<&Mutex<RecentCommands>>) -> String {
+    match recent {
+        Some(recent) => format!("{}Recent commands:\n{}", dump, recent.lock().unwrap()),
+        None => dump,
+    }
+}
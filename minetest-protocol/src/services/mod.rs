@@ -1,4 +1,12 @@
+pub mod auth;
+pub mod bandwidth;
 pub mod client;
+pub mod compat_report;
 pub mod conn;
+pub mod monitor;
 pub mod server;
 pub mod socket;
+pub mod srp;
+pub mod transport;
+pub mod validate;
+pub mod watchdog;
@@ -0,0 +1,353 @@
+//!
+//! Server monitoring
+//!
+//! ServerMonitor watches a C++ server from an idle, logged in client
+//! (use a dedicated account), and turns what it hears into health events
+//! and metrics:
+//!
+//! - Time of day drift. Between TOCLIENT_TIME_OF_DAY updates the game time
+//!   is predicted from time_speed. A server that lags advances time more
+//!   slowly than predicted, so each update shows how far it fell behind.
+//! - Latency, from a chat command probe ("/admin" by default) answered by
+//!   the server's Lua, so it includes time spent waiting for a server step.
+//! - Players joining and leaving, and chat.
+//!
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+
+use crate::bot::commands::strip_escapes;
+use crate::bot::time::TimeOfDay;
+use crate::bot::time::TICKS_PER_DAY;
+use crate::game::player_list::PlayerList;
+use crate::wire::command::TSChatMessageSpec;
+use crate::wire::command::ToClientCommand;
+
+use super::client::Denied;
+use super::client::MinetestClient;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorConfig {
+    /// How often to measure latency. None disables the probe.
+    pub probe_interval: Option<Duration>,
+    /// Chat command sent to measure latency
+    pub probe_command: String,
+    /// Start of the reply to the probe, with escapes stripped
+    pub probe_replies: Vec<String>,
+    /// Smallest drift reported as an event, in ticks.
+    /// At the default time_speed (72), one tick is 50ms of real time.
+    pub drift_threshold: u32,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval: Some(Duration::from_secs(30)),
+            probe_command: "/admin".to_string(),
+            probe_replies: vec![
+                "The administrator of this server is".to_string(),
+                "There's no administrator named in the config file.".to_string(),
+            ],
+            drift_threshold: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonitorEvent {
+    Chat {
+        sender: String,
+        message: String,
+    },
+    PlayerJoined(String),
+    PlayerLeft(String),
+    /// Game time fell behind (negative) or ran ahead of the prediction
+    TimeDrift {
+        ticks: i32,
+    },
+    Latency(Duration),
+    /// The server disconnected the monitor
+    Denied(Denied),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MonitorMetrics {
+    pub commands_received: u64,
+    pub chat_messages: u64,
+    pub players_online: usize,
+    /// Drift at the last time of day update, in ticks
+    pub last_drift: Option<i32>,
+    /// Sum of |drift| over all updates, in ticks
+    pub total_drift: u64,
+    pub time_updates: u64,
+    pub last_latency: Option<Duration>,
+    pub max_latency: Option<Duration>,
+    /// Probes sent that got no reply before the next one
+    pub lost_probes: u64,
+}
+
+pub struct ServerMonitor {
+    client: MinetestClient,
+    state: MonitorState,
+}
+
+impl ServerMonitor {
+    /// Takes over a client that has already logged in
+    pub fn new(client: MinetestClient, config: MonitorConfig) -> Self {
+        Self {
+            client,
+            state: MonitorState::new(config),
+        }
+    }
+
+    pub fn metrics(&self) -> &MonitorMetrics {
+        &self.state.metrics
+    }
+
+    pub fn players(&self) -> &PlayerList {
+        &self.state.players
+    }
+
+    /// Waits for the next event, sending latency probes meanwhile.
+    /// Fails when the connection is lost.
+    pub async fn next_event(&mut self) -> Result<MonitorEvent> {
+        loop {
+            if let Some(event) = self.state.pending.pop_front() {
+                return Ok(event);
+            }
+            let next_probe = self.state.next_probe();
+            tokio::select! {
+                command = self.client.recv() => {
+                    self.state.observe(&command?, Instant::now());
+                }
+                _ = sleep_until(next_probe) => {
+                    let probe = self.state.start_probe(Instant::now());
+                    self.client.send(probe.into()).await?;
+                }
+            }
+        }
+    }
+}
+
+async fn sleep_until(when: Option<Instant>) {
+    match when {
+        Some(when) => tokio::time::sleep_until(when.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Everything but the connection, so it can be driven by hand
+pub struct MonitorState {
+    config: MonitorConfig,
+    metrics: MonitorMetrics,
+    players: PlayerList,
+    time: TimeOfDay,
+    probe_sent: Option<Instant>,
+    last_probe: Option<Instant>,
+    pending: VecDeque<MonitorEvent>,
+}
+
+impl MonitorState {
+    pub fn new(config: MonitorConfig) -> Self {
+        Self {
+            config,
+            metrics: MonitorMetrics::default(),
+            players: PlayerList::new(),
+            time: TimeOfDay::new(),
+            probe_sent: None,
+            last_probe: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn metrics(&self) -> &MonitorMetrics {
+        &self.metrics
+    }
+
+    /// Events produced so far, oldest first
+    pub fn take_events(&mut self) -> Vec<MonitorEvent> {
+        self.pending.drain(..).collect()
+    }
+
+    /// When the next probe is due, if probing
+    pub fn next_probe(&self) -> Option<Instant> {
+        let interval = self.config.probe_interval?;
+        match self.last_probe {
+            Some(last) => Some(last + interval),
+            None => Some(Instant::now()),
+        }
+    }
+
+    /// The chat message to send as a probe, sent at `now`
+    pub fn start_probe(&mut self, now: Instant) -> TSChatMessageSpec {
+        if self.probe_sent.is_some() {
+            self.metrics.lost_probes += 1;
+        }
+        self.probe_sent = Some(now);
+        self.last_probe = Some(now);
+        TSChatMessageSpec {
+            message: self.config.probe_command.clone(),
+        }
+    }
+
+    pub fn observe(&mut self, command: &ToClientCommand, now: Instant) {
+        self.metrics.commands_received += 1;
+        match command {
+            ToClientCommand::TimeOfDay(spec) => {
+                if self.time.is_set() {
+                    let predicted = self.time.ticks_at(now) as i32;
+                    let day = TICKS_PER_DAY as i32;
+                    let actual = spec.time_of_day as i32 % day;
+                    // Shortest way around the clock
+                    let drift = (actual - predicted + day / 2).rem_euclid(day) - day / 2;
+                    self.metrics.last_drift = Some(drift);
+                    self.metrics.total_drift += drift.unsigned_abs() as u64;
+                    self.metrics.time_updates += 1;
+                    if drift.unsigned_abs() >= self.config.drift_threshold {
+                        self.push(MonitorEvent::TimeDrift { ticks: drift });
+                    }
+                }
+                self.time.update(spec, now);
+            }
+            ToClientCommand::UpdatePlayerList(_) => {
+                let before = self.players.players().to_vec();
+                self.players.observe_toclient(command);
+                let after = self.players.players().to_vec();
+                for name in &after {
+                    if !before.contains(name) {
+                        self.push(MonitorEvent::PlayerJoined(name.clone()));
+                    }
+                }
+                for name in before {
+                    if !after.contains(&name) {
+                        self.push(MonitorEvent::PlayerLeft(name));
+                    }
+                }
+                self.metrics.players_online = self.players.len();
+            }
+            ToClientCommand::TCChatMessage(spec) => {
+                let message = strip_escapes(&spec.message);
+                if let Some(sent) = self.probe_sent {
+                    let is_reply = spec.sender.is_empty()
+                        && self
+                            .config
+                            .probe_replies
+                            .iter()
+                            .any(|reply| message.starts_with(reply.as_str()));
+                    if is_reply {
+                        let latency = now.saturating_duration_since(sent);
+                        self.probe_sent = None;
+                        self.metrics.last_latency = Some(latency);
+                        self.metrics.max_latency = self.metrics.max_latency.max(Some(latency));
+                        self.push(MonitorEvent::Latency(latency));
+                        return;
+                    }
+                }
+                self.metrics.chat_messages += 1;
+                self.push(MonitorEvent::Chat {
+                    sender: spec.sender.clone(),
+                    message,
+                });
+            }
+            ToClientCommand::AccessDenied(_) | ToClientCommand::AccessDeniedLegacy(_) => {
+                if let Some(denied) = Denied::from_toclient(command) {
+                    self.push(MonitorEvent::Denied(denied));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn push(&mut self, event: MonitorEvent) {
+        self.pending.push_back(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::TCChatMessageSpec;
+    use crate::wire::command::TimeOfDaySpec;
+    use crate::wire::command::UpdatePlayerListSpec;
+    use crate::wire::types::PlayerListModifier;
+
+    fn time(ticks: u16) -> ToClientCommand {
+        TimeOfDaySpec {
+            time_of_day: ticks,
+            time_speed: Some(72.0),
+        }
+        .into()
+    }
+
+    fn chat(message: &str) -> ToClientCommand {
+        TCChatMessageSpec {
+            version: 1,
+            message_type: 1,
+            sender: String::new(),
+            message: message.to_string(),
+            timestamp: 0,
+        }
+        .into()
+    }
+
+    #[test]
+    fn time_drift() {
+        let start = Instant::now();
+        let mut state = MonitorState::new(MonitorConfig::default());
+        state.observe(&time(23900), start);
+        // 50 seconds is 1000 ticks at 72x. The server only managed 900,
+        // across midnight.
+        state.observe(&time(800), start + Duration::from_secs(50));
+        assert_eq!(state.metrics().last_drift, Some(-100));
+        assert_eq!(
+            state.take_events(),
+            vec![MonitorEvent::TimeDrift { ticks: -100 }]
+        );
+        state.observe(&time(1805), start + Duration::from_secs(100));
+        assert_eq!(state.metrics().last_drift, Some(5));
+        assert!(state.take_events().is_empty());
+    }
+
+    #[test]
+    fn players_chat_and_latency() {
+        let start = Instant::now();
+        let mut state = MonitorState::new(MonitorConfig::default());
+        let players = |typ, names: &[&str]| -> ToClientCommand {
+            UpdatePlayerListSpec {
+                typ,
+                players: names.iter().map(|n| n.to_string()).collect(),
+            }
+            .into()
+        };
+        state.observe(
+            &players(PlayerListModifier::Init, &["alice", "monitor"]),
+            start,
+        );
+        state.observe(&players(PlayerListModifier::Remove, &["alice"]), start);
+
+        let probe = state.start_probe(start);
+        assert_eq!(probe.message, "/admin");
+        state.observe(&chat("*** bob joined the game."), start);
+        state.observe(
+            &chat("\x1b(T@__builtin)The administrator of this server is \x1bFsam\x1bE.\x1bE"),
+            start + Duration::from_millis(80),
+        );
+        assert_eq!(
+            state.take_events(),
+            vec![
+                MonitorEvent::PlayerJoined("alice".to_string()),
+                MonitorEvent::PlayerJoined("monitor".to_string()),
+                MonitorEvent::PlayerLeft("alice".to_string()),
+                MonitorEvent::Chat {
+                    sender: String::new(),
+                    message: "*** bob joined the game.".to_string()
+                },
+                MonitorEvent::Latency(Duration::from_millis(80)),
+            ]
+        );
+        assert_eq!(state.metrics().players_online, 1);
+        assert_eq!(state.metrics().chat_messages, 1);
+    }
+}
@@ -3,15 +3,24 @@
 //! and a MinetestConnection is just a wrapper around a SocketPeer.
 //!
 //! In the future it may provide its own abstraction above the Minetest Commands.
+//!
+//! An AuthServer logs clients in itself (see auth), and hands over
+//! AuthenticatedConnections instead.
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::mpsc::UnboundedSender;
 
+use super::auth::authenticate;
+use super::auth::AuthConfig;
+use super::auth::AuthHandler;
+use super::auth::AuthenticatedConnection;
 use super::conn::MinetestConnection;
 use super::socket::MinetestSocket;
+use super::transport::DatagramTransport;
 
 pub struct MinetestServer {
     accept_rx: UnboundedReceiver<MinetestConnection>,
@@ -20,50 +29,136 @@ pub struct MinetestServer {
 impl MinetestServer {
     pub fn new(bind_addr: SocketAddr) -> Self {
         let (accept_tx, accept_rx) = unbounded_channel();
-        let runner = MinetestServerRunner {
-            bind_addr: bind_addr,
-            accept_tx: accept_tx,
-        };
-        tokio::spawn(async move {
-            runner.run().await;
-        });
-        Self {
-            accept_rx: accept_rx,
-        }
+        start(bind_addr, None, Accepted::Connection(accept_tx));
+        Self { accept_rx }
+    }
+
+    /// A server on `transport` instead of UDP (see transport)
+    pub fn with_transport<T: DatagramTransport>(transport: T) -> std::io::Result<Self> {
+        let socket = MinetestSocket::with_transport(transport, true)?;
+        let (accept_tx, accept_rx) = unbounded_channel();
+        start(
+            socket.local_addr(),
+            Some(socket),
+            Accepted::Connection(accept_tx),
+        );
+        Ok(Self { accept_rx })
     }
 
+    /// The next connection
     pub async fn accept(&mut self) -> MinetestConnection {
         self.accept_rx.recv().await.unwrap()
     }
 }
 
+/// A server that runs the login handshake for every connection, and only
+/// hands over the clients that logged in
+pub struct AuthServer {
+    authenticated_rx: UnboundedReceiver<AuthenticatedConnection>,
+}
+
+impl AuthServer {
+    /// With accounts from `handler`
+    pub fn new(bind_addr: SocketAddr, handler: impl AuthHandler, config: AuthConfig) -> Self {
+        let (authenticated_tx, authenticated_rx) = unbounded_channel();
+        let accepted = Accepted::Authenticated(authenticated_tx, Arc::new(handler), config);
+        start(bind_addr, None, accepted);
+        Self { authenticated_rx }
+    }
+
+    /// The next client that logged in
+    pub async fn accept(&mut self) -> AuthenticatedConnection {
+        self.authenticated_rx.recv().await.unwrap()
+    }
+}
+
+/// Where the runner sends new connections
+enum Accepted {
+    Connection(UnboundedSender<MinetestConnection>),
+    /// After authenticating them
+    Authenticated(
+        UnboundedSender<AuthenticatedConnection>,
+        Arc<dyn AuthHandler>,
+        AuthConfig,
+    ),
+}
+
+/// `socket` is bound to `bind_addr` already, if given
+fn start(bind_addr: SocketAddr, socket: Option<MinetestSocket>, accepted: Accepted) {
+    let runner = MinetestServerRunner {
+        bind_addr,
+        socket,
+        accepted,
+    };
+    tokio::spawn(async move {
+        runner.run().await;
+    });
+}
+
 struct MinetestServerRunner {
     bind_addr: SocketAddr,
-    accept_tx: UnboundedSender<MinetestConnection>,
+    socket: Option<MinetestSocket>,
+    accepted: Accepted,
 }
 
 impl MinetestServerRunner {
-    async fn run(self) {
-        println!("MinetestServer starting on {}", self.bind_addr.to_string());
-        let mut socket = loop {
-            match MinetestSocket::new(self.bind_addr, true).await {
-                Ok(socket) => break socket,
-                Err(err) => {
-                    println!("MinetestServer: bind failed: {}", err);
-                    println!("Retrying in 5 seconds");
-                    tokio::time::sleep(Duration::from_millis(5000)).await;
-                }
-            };
+    async fn run(mut self) {
+        eprintln!("MinetestServer starting on {}", self.bind_addr);
+        let mut socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => self.bind().await,
         };
-        println!("MinetestServer started");
+        eprintln!("MinetestServer started");
         loop {
             let t = socket.accept().await.unwrap();
-            println!("MinetestServer accepted connection");
+            eprintln!("MinetestServer accepted connection");
             let conn = MinetestConnection::new(t);
-            match self.accept_tx.send(conn) {
-                Ok(_) => (),
-                Err(_) => println!("Unexpected send fail in MinetestServer"),
+            match &self.accepted {
+                Accepted::Connection(accept_tx) => {
+                    if accept_tx.send(conn).is_err() {
+                        eprintln!("Unexpected send fail in MinetestServer");
+                    }
+                }
+                Accepted::Authenticated(authenticated_tx, handler, config) => {
+                    spawn_authenticate(
+                        conn,
+                        authenticated_tx.clone(),
+                        handler.clone(),
+                        config.clone(),
+                    );
+                }
             }
         }
     }
+
+    async fn bind(&self) -> MinetestSocket {
+        loop {
+            match MinetestSocket::new(self.bind_addr, true).await {
+                Ok(socket) => return socket,
+                Err(err) => {
+                    eprintln!("MinetestServer: bind failed: {}", err);
+                    eprintln!("Retrying in 5 seconds");
+                    tokio::time::sleep(Duration::from_millis(5000)).await;
+                }
+            };
+        }
+    }
+}
+
+fn spawn_authenticate(
+    conn: MinetestConnection,
+    authenticated_tx: UnboundedSender<AuthenticatedConnection>,
+    handler: Arc<dyn AuthHandler>,
+    config: AuthConfig,
+) {
+    tokio::spawn(async move {
+        let remote_addr = conn.remote_addr();
+        match authenticate(conn, &*handler, &config).await {
+            Ok(client) => {
+                eprintln!("MinetestServer: {} logged in", client.player_name);
+                let _ = authenticated_tx.send(client);
+            }
+            Err(err) => eprintln!("MinetestServer: login from {} failed: {}", remote_addr, err),
+        }
+    });
 }
@@ -3,12 +3,10 @@ use std::collections::VecDeque;
 use std::io::Error;
 use std::net::SocketAddr;
 
-use tokio::io::Interest;
-use tokio::io::Ready;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc::unbounded_channel;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
 
 use crate::peer::peer::PeerToSocket;
 
@@ -16,21 +14,34 @@ use crate::peer::peer::new_peer;
 use crate::peer::peer::Peer;
 use crate::peer::peer::PeerIO;
 
+use super::transport::DatagramTransport;
+
 const MAX_DATAGRAM_SIZE: usize = 65536;
 
+// Datagrams from the peer runners. Past MAX_OUTGOING datagrams waiting for
+// the socket, the runners wait.
+const PEER_TO_SOCKET_CAPACITY: usize = 1024;
+const MAX_OUTGOING: usize = 4096;
+
+// New peers not yet accepted. Past this, datagrams from new addresses are
+// ignored.
+const ACCEPT_CAPACITY: usize = 64;
+
 ///
 /// MinetestSocket
 ///
-/// Handles the raw UDP socket, protocol validation, separating packets by peer,
-/// reliable packet send, and split packets.
+/// Handles the raw UDP socket (or another DatagramTransport), protocol
+/// validation, separating packets by peer, reliable packet send, and split
+/// packets.
 ///
 /// The actual contents of the communication, including authentication/handshaking,
 /// are not handled at this layer.
 ///
 pub struct MinetestSocket {
-    accept_rx: UnboundedReceiver<Peer>,
-    knock_tx: UnboundedSender<SocketAddr>,
+    accept_rx: Receiver<Peer>,
+    knock_tx: Sender<SocketAddr>,
     for_server: bool,
+    local_addr: SocketAddr,
 }
 
 impl MinetestSocket {
@@ -39,16 +50,26 @@ impl MinetestSocket {
     /// To select a random bind port, use 0.0.0.0:0 or [::]:0
     pub async fn new(bind_addr: SocketAddr, for_server: bool) -> Result<Self, Error> {
         let socket = UdpSocket::bind(bind_addr).await?;
-        let (peer_tx, peer_rx) = unbounded_channel();
-        let (accept_tx, accept_rx) = unbounded_channel();
-        let (knock_tx, knock_rx) = unbounded_channel();
+        Self::with_transport(socket, for_server)
+    }
+
+    /// Create a new MinetestSocket over `transport` instead of UDP
+    pub fn with_transport<T: DatagramTransport>(
+        transport: T,
+        for_server: bool,
+    ) -> Result<Self, Error> {
+        let local_addr = transport.local_addr()?;
+        let (peer_tx, peer_rx) = channel(PEER_TO_SOCKET_CAPACITY);
+        let (accept_tx, accept_rx) = channel(ACCEPT_CAPACITY);
+        let (knock_tx, knock_rx) = channel(1);
         let minetest_socket = Self {
             accept_rx,
             knock_tx,
             for_server,
+            local_addr,
         };
         let minetest_socket_runner = MinetestSocketRunner {
-            socket,
+            transport,
             peers: HashMap::new(),
             peer_tx,
             peer_rx,
@@ -61,6 +82,10 @@ impl MinetestSocket {
         Ok(minetest_socket)
     }
 
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
     /// Returns None when the server has shutdown.
     pub async fn accept(&mut self) -> Option<Peer> {
         self.accept_rx.recv().await
@@ -73,7 +98,7 @@ impl MinetestSocket {
     // they will be discarded.
     pub async fn add_peer(&mut self, remote: SocketAddr) -> Peer {
         assert!(!self.for_server);
-        self.knock_tx.send(remote).unwrap();
+        self.knock_tx.send(remote).await.unwrap();
 
         // Wait for the peer
         loop {
@@ -86,24 +111,24 @@ impl MinetestSocket {
     }
 }
 
-pub struct MinetestSocketRunner {
-    socket: UdpSocket,
+pub struct MinetestSocketRunner<T: DatagramTransport> {
+    transport: T,
     peers: HashMap<SocketAddr, PeerIO>,
-    peer_tx: UnboundedSender<PeerToSocket>,
-    peer_rx: UnboundedReceiver<PeerToSocket>,
+    peer_tx: Sender<PeerToSocket>,
+    peer_rx: Receiver<PeerToSocket>,
     outgoing: VecDeque<(SocketAddr, Vec<u8>)>,
-    accept_tx: UnboundedSender<Peer>,
-    knock_rx: UnboundedReceiver<SocketAddr>,
+    accept_tx: Sender<Peer>,
+    knock_rx: Receiver<SocketAddr>,
     for_server: bool,
 }
 
-impl MinetestSocketRunner {
+impl<T: DatagramTransport> MinetestSocketRunner<T> {
     pub async fn run(mut self) {
         // Top-level error handler
         match self.run_inner().await {
             Ok(_) => (),
             Err(err) => {
-                println!("MinetestSocket abnormal exit: {:?}", err);
+                eprintln!("MinetestSocket abnormal exit: {:?}", err);
             }
         }
     }
@@ -113,14 +138,12 @@ impl MinetestSocketRunner {
         let mut buf: Vec<u8> = vec![0u8; MAX_DATAGRAM_SIZE];
 
         loop {
-            let mut r = Interest::READABLE;
-            if !self.outgoing.is_empty() {
-                r = r | Interest::WRITABLE;
-            }
+            let may_queue = self.outgoing.len() < MAX_OUTGOING;
             // rust-analyzer chokes on code inside select!, so keep it to a minimum.
             tokio::select! {
-                t = self.socket.ready(r) => self.handle_socket_io(t, &mut buf).await?,
-                msg = self.peer_rx.recv() => self.handle_peer_message(msg),
+                t = self.transport.recv_from(&mut buf) => self.handle_received(t, &buf),
+                t = send_next(&self.transport, self.outgoing.back()) => self.handle_sent(t),
+                msg = self.peer_rx.recv(), if may_queue => self.handle_peer_message(msg),
                 t = self.knock_rx.recv(), if !knock_closed => {
                     match t {
                         Some(t) => {
@@ -135,35 +158,27 @@ impl MinetestSocketRunner {
         }
     }
 
-    async fn handle_socket_io(
-        &mut self,
-        t: tokio::io::Result<Ready>,
-        buf: &mut [u8],
-    ) -> anyhow::Result<()> {
-        let t = t.expect("socket.ready should not error");
-        if t.is_readable() {
-            match self.socket.try_recv_from(buf) {
-                Ok((n, remote_addr)) => {
-                    if let Some(peer) = self.get_peer(remote_addr, self.for_server) {
-                        // TODO: If the peer receive channel is full, generate a disconnect message.
-                        peer.send(&buf[..n]);
+    fn handle_received(&mut self, t: Result<(usize, SocketAddr), Error>, buf: &[u8]) {
+        match t {
+            Ok((n, remote_addr)) => {
+                if let Some(peer) = self.get_peer(remote_addr, self.for_server) {
+                    // The runner finds out it was dropped, and disconnects
+                    if !peer.send(&buf[..n]) {
+                        self.remove_peer(remote_addr);
                     }
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
-                Err(e) => panic!("Unexpected socket error: {:?}", e),
-            };
+            }
+            Err(e) => panic!("Unexpected socket error: {:?}", e),
         }
-        if t.is_writable() && !self.outgoing.is_empty() {
-            let (addr, data) = self.outgoing.pop_back().unwrap();
-            match self.socket.try_send_to(&data, addr) {
-                Ok(_) => (),
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    self.outgoing.push_back((addr, data));
-                }
-                Err(e) => panic!("Unexpected socket error: {:?}", e),
+    }
+
+    fn handle_sent(&mut self, t: Result<usize, Error>) {
+        match t {
+            Ok(_) => {
+                self.outgoing.pop_back();
             }
+            Err(e) => panic!("Unexpected socket error: {:?}", e),
         }
-        Ok(())
     }
 
     fn handle_peer_message(&mut self, msg: Option<PeerToSocket>) {
@@ -186,13 +201,28 @@ impl MinetestSocketRunner {
     }
 
     fn insert_peer(&mut self, remote_addr: SocketAddr) {
+        // Only start a peer that can be accepted
+        let Ok(permit) = self.accept_tx.try_reserve() else {
+            return;
+        };
         let (peer, peerio) = new_peer(remote_addr, !self.for_server, self.peer_tx.clone());
         self.peers.insert(remote_addr, peerio);
-        let ok = self.accept_tx.send(peer).is_ok();
-        assert!(ok);
+        permit.send(peer);
     }
 
     fn remove_peer(&mut self, remote_addr: SocketAddr) {
         self.peers.remove(&remote_addr);
     }
 }
+
+/// Sends the datagram with the highest priority (the last), or never
+/// finishes if there is none
+async fn send_next<T: DatagramTransport>(
+    transport: &T,
+    next: Option<&(SocketAddr, Vec<u8>)>,
+) -> Result<usize, Error> {
+    match next {
+        Some((addr, data)) => transport.send_to(data, *addr).await,
+        None => std::future::pending().await,
+    }
+}
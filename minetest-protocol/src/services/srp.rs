@@ -0,0 +1,253 @@
+//!
+//! SRP-6a, as Minetest uses it
+//!
+//! SHA-256 and the RFC 5054 2048-bit group. k and u hash their inputs
+//! padded to the length of N. The verifier is made with the lowercased
+//! player name, the proof (M) with the name as typed.
+//!
+//! MinetestClient::login and services::auth do the whole handshake, for
+//! the client and the server.
+//!
+use anyhow::bail;
+use anyhow::Result;
+use num_bigint::BigUint;
+use rand::RngCore;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::game::media::base64;
+
+const N_HEX: &str = "\
+AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050A37329CBB4A099ED8193E075\
+7767A13DD52312AB4B03310DCD7F48A9DA04FD50E8083969EDB767B0CF6095179A163AB3661A05FBD5FAAAE8\
+2918A9962F0B93B855F97993EC975EEAA80D740ADBF4FF747359D041D5C33EA71D281E446B14773BCA97B43A\
+23FB801676BD207A436C6481F1D2B9078717461A5B9D32E688F87748544523B524B0D57D5EA77A2775D2ECFA\
+032CFBDBF52FB3786160279004E57AE6AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8\
+E9DBFBB694B5C803D89F7AE435DE236D525F54759B65E372FCD68EF20FA7111F9E4AFF73";
+
+const G: u32 = 2;
+
+fn group() -> (BigUint, BigUint) {
+    (
+        BigUint::parse_bytes(N_HEX.as_bytes(), 16).unwrap(),
+        BigUint::from(G),
+    )
+}
+
+/// Sha256 of the concatenation of `parts`
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// `n` big endian, left padded to `len` bytes
+fn padded(n: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut out = vec![0; len.saturating_sub(bytes.len())];
+    out.extend_from_slice(&bytes);
+    out
+}
+
+fn calculate_x(salt: &[u8], username: &str, password: &[u8]) -> BigUint {
+    let inner = hash(&[username.as_bytes(), b":", password]);
+    BigUint::from_bytes_be(&hash(&[salt, &inner]))
+}
+
+/// k = H(N, g)
+fn calculate_k(n: &BigUint, g: &BigUint) -> BigUint {
+    let len = n.to_bytes_be().len();
+    BigUint::from_bytes_be(&hash(&[&n.to_bytes_be(), &padded(g, len)]))
+}
+
+/// u = H(A, B)
+fn calculate_u(n: &BigUint, big_a: &BigUint, big_b: &BigUint) -> BigUint {
+    let len = n.to_bytes_be().len();
+    BigUint::from_bytes_be(&hash(&[&padded(big_a, len), &padded(big_b, len)]))
+}
+
+/// M = H(H(N) xor H(g), H(I), s, A, B, K)
+fn calculate_m(
+    player_name: &str,
+    salt: &[u8],
+    big_a: &BigUint,
+    big_b: &BigUint,
+    s: &BigUint,
+) -> Vec<u8> {
+    let (n, g) = group();
+    let session_key = hash(&[&s.to_bytes_be()]);
+    let h_n = hash(&[&n.to_bytes_be()]);
+    let h_g = hash(&[&g.to_bytes_be()]);
+    let h_xor: Vec<u8> = h_n.iter().zip(&h_g).map(|(a, b)| a ^ b).collect();
+    let h_i = hash(&[player_name.as_bytes()]);
+    hash(&[
+        &h_xor,
+        &h_i,
+        salt,
+        &big_a.to_bytes_be(),
+        &big_b.to_bytes_be(),
+        &session_key,
+    ])
+}
+
+fn random_exponent() -> BigUint {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BigUint::from_bytes_be(&bytes)
+}
+
+/// A new random salt and the matching verifier, for TOSERVER_FIRST_SRP
+pub fn salted_verifier(player_name: &str, password: &str) -> (Vec<u8>, Vec<u8>) {
+    let (n, g) = group();
+    let mut salt = vec![0; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let x = calculate_x(&salt, &player_name.to_lowercase(), password.as_bytes());
+    let v = g.modpow(&x, &n);
+    (salt, v.to_bytes_be())
+}
+
+/// The password hash of accounts from before SRP: base64 SHA-1 of the name
+/// and password, or empty for no password. These accounts log in with SRP
+/// using it as the password (AuthMechsBitset::legacy_password).
+pub fn legacy_password(player_name: &str, password: &str) -> String {
+    if password.is_empty() {
+        return String::new();
+    }
+    let digest = sha1_smol::Sha1::from(format!("{}{}", player_name, password)).digest();
+    base64(&digest.bytes())
+}
+
+pub struct SrpClient {
+    player_name: String,
+    password: String,
+    a: BigUint,
+    big_a: BigUint,
+}
+
+impl SrpClient {
+    pub fn new(player_name: &str, password: &str) -> Self {
+        let (n, g) = group();
+        let a = random_exponent();
+        let big_a = g.modpow(&a, &n);
+        Self {
+            player_name: player_name.to_string(),
+            password: password.to_string(),
+            a,
+            big_a,
+        }
+    }
+
+    /// A, for TOSERVER_SRP_BYTES_A
+    pub fn bytes_a(&self) -> Vec<u8> {
+        self.big_a.to_bytes_be()
+    }
+
+    /// The proof M for TOSERVER_SRP_BYTES_M, from the salt and B in
+    /// TOCLIENT_SRP_BYTES_S_B
+    pub fn process_challenge(&self, salt: &[u8], bytes_b: &[u8]) -> Result<Vec<u8>> {
+        let (n, g) = group();
+        let b = BigUint::from_bytes_be(bytes_b);
+        if (&b % &n) == BigUint::default() {
+            bail!("SRP: server sent an invalid B");
+        }
+        let u = calculate_u(&n, &self.big_a, &b);
+        if u == BigUint::default() {
+            bail!("SRP: u is zero");
+        }
+        let k = calculate_k(&n, &g);
+        let x = calculate_x(
+            salt,
+            &self.player_name.to_lowercase(),
+            self.password.as_bytes(),
+        );
+
+        // S = (B - k * g^x) ^ (a + u * x) mod N
+        let kgx = (k * g.modpow(&x, &n)) % &n;
+        let base = ((&b % &n) + &n - kgx) % &n;
+        let s = base.modpow(&(&self.a + u * x), &n);
+        Ok(calculate_m(&self.player_name, salt, &self.big_a, &b, &s))
+    }
+}
+
+/// The server side, for an account with a salt and verifier
+pub struct SrpServer {
+    player_name: String,
+    salt: Vec<u8>,
+    v: BigUint,
+    b: BigUint,
+    big_b: BigUint,
+}
+
+impl SrpServer {
+    pub fn new(player_name: &str, salt: &[u8], verifier: &[u8]) -> Self {
+        let (n, g) = group();
+        let v = BigUint::from_bytes_be(verifier);
+        let b = random_exponent();
+        // B = k * v + g^b mod N
+        let big_b = (calculate_k(&n, &g) * &v + g.modpow(&b, &n)) % &n;
+        Self {
+            player_name: player_name.to_string(),
+            salt: salt.to_vec(),
+            v,
+            b,
+            big_b,
+        }
+    }
+
+    /// The salt, for TOCLIENT_SRP_BYTES_S_B
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// B, for TOCLIENT_SRP_BYTES_S_B
+    pub fn bytes_b(&self) -> Vec<u8> {
+        self.big_b.to_bytes_be()
+    }
+
+    /// Whether M (TOSERVER_SRP_BYTES_M) proves the client knows the
+    /// password. Fails if A is invalid.
+    pub fn verify(&self, bytes_a: &[u8], bytes_m: &[u8]) -> Result<bool> {
+        let (n, _) = group();
+        let a = BigUint::from_bytes_be(bytes_a);
+        if (&a % &n) == BigUint::default() {
+            bail!("SRP: client sent an invalid A");
+        }
+        let u = calculate_u(&n, &a, &self.big_b);
+        // S = (A * v^u) ^ b mod N
+        let s = (&a * self.v.modpow(&u, &n)).modpow(&self.b, &n);
+        let expected = calculate_m(&self.player_name, &self.salt, &a, &self.big_b, &s);
+        Ok(expected == bytes_m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_and_server_agree() {
+        let (salt, verifier) = salted_verifier("Bot", "secret");
+        let server = SrpServer::new("Bot", &salt, &verifier);
+        let client = SrpClient::new("Bot", "secret");
+        let m = client
+            .process_challenge(server.salt(), &server.bytes_b())
+            .unwrap();
+        assert!(server.verify(&client.bytes_a(), &m).unwrap());
+
+        let wrong = SrpClient::new("Bot", "guess");
+        let m = wrong
+            .process_challenge(server.salt(), &server.bytes_b())
+            .unwrap();
+        assert!(!server.verify(&wrong.bytes_a(), &m).unwrap());
+        assert!(client.process_challenge(&salt, &[0]).is_err());
+        assert!(server.verify(&[0], &m).is_err());
+
+        assert_eq!(legacy_password("bot", ""), "");
+        assert_eq!(
+            legacy_password("bot", "secret"),
+            "rJ1ROyVyltIda+nPusHBr1rFm2o="
+        );
+    }
+}
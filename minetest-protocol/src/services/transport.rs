@@ -0,0 +1,353 @@
+//!
+//! Datagram transports
+//!
+//! A MinetestSocket sends and receives raw datagrams through a
+//! DatagramTransport. Normally that is a UdpSocket, but anything that
+//! carries addressed datagrams will do: an in-memory network for tests
+//! (LoopbackNetwork), UNIX domain sockets, or a bridge to web clients.
+//!
+//! ```text
+//! let network = LoopbackNetwork::new();
+//! network.set_conditions(LinkConditions { loss: 0.1, ..Default::default() });
+//! let mut server = MinetestServer::with_transport(network.bind(server_addr))?;
+//! let client = MinetestClient::connect_with_transport(network.bind(client_addr), server_addr).await?;
+//! ```
+//!
+//! Like UDP, a transport may lose, duplicate or reorder datagrams. The peer
+//! layer takes care of that.
+//!
+//! A TappedTransport shows every datagram that goes through another
+//! transport to a DatagramTap, e.g. to write a packet capture (see
+//! recording::pcap).
+//!
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Both futures must be cancel safe: the socket runner selects on them, and
+/// drops whichever didn't finish. A dropped send_to must not have sent, and
+/// a dropped recv_from must not have consumed a datagram.
+pub trait DatagramTransport: Send + Sync + 'static {
+    /// Sends one datagram. Returns the number of bytes sent.
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Receives one datagram into `buf`. Returns its size and sender.
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl DatagramTransport for UdpSocket {
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        UdpSocket::send_to(self, buf, target)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TapDirection {
+    Sent,
+    Received,
+}
+
+/// Sees the datagrams of a TappedTransport. Called as they are sent and
+/// received, so it must not block for long.
+pub trait DatagramTap: Send + Sync + 'static {
+    fn datagram(&self, dir: TapDirection, local: SocketAddr, remote: SocketAddr, data: &[u8]);
+}
+
+pub struct TappedTransport<T> {
+    inner: T,
+    tap: Arc<dyn DatagramTap>,
+}
+
+impl<T: DatagramTransport> TappedTransport<T> {
+    pub fn new(inner: T, tap: Arc<dyn DatagramTap>) -> Self {
+        Self { inner, tap }
+    }
+}
+
+impl<T: DatagramTransport> DatagramTransport for TappedTransport<T> {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let sent = self.inner.send_to(buf, target).await?;
+        if let Ok(local) = self.inner.local_addr() {
+            self.tap
+                .datagram(TapDirection::Sent, local, target, &buf[..sent]);
+        }
+        Ok(sent)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, from) = self.inner.recv_from(buf).await?;
+        if let Ok(local) = self.inner.local_addr() {
+            self.tap
+                .datagram(TapDirection::Received, local, from, &buf[..size]);
+        }
+        Ok((size, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Extra delay of a reordered datagram, so later ones overtake it
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+/// What a LoopbackNetwork does to datagrams on their way. The default is
+/// a perfect link.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkConditions {
+    /// Chance that a datagram is lost [0, 1]
+    pub loss: f64,
+    /// Chance that a datagram is held back, and arrives after the ones sent
+    /// right after it [0, 1]
+    pub reorder: f64,
+    /// Delay of every datagram
+    pub latency: Duration,
+    /// Extra random delay of each datagram, up to this
+    pub jitter: Duration,
+}
+
+struct LoopbackState {
+    bound: HashMap<SocketAddr, UnboundedSender<Datagram>>,
+    conditions: LinkConditions,
+    rng: StdRng,
+}
+
+/// Addresses bound in one LoopbackNetwork can reach each other, under its
+/// LinkConditions. Datagrams sent to an address nobody bound are dropped.
+///
+/// Losses, reordering and jitter come from a seeded RNG, so a test sees
+/// the same ones every run (as long as it sends the same datagrams).
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    state: Arc<Mutex<LoopbackState>>,
+}
+
+impl Default for LoopbackNetwork {
+    fn default() -> Self {
+        Self::with_seed(0)
+    }
+}
+
+impl LoopbackNetwork {
+    /// A perfect network
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(LoopbackState {
+                bound: HashMap::new(),
+                conditions: LinkConditions::default(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Applies to the datagrams sent from now on
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.state.lock().unwrap().conditions = conditions;
+    }
+
+    /// Binds `addr`, replacing any transport already bound to it
+    pub fn bind(&self, addr: SocketAddr) -> LoopbackTransport {
+        let (tx, rx) = unbounded_channel();
+        self.state.lock().unwrap().bound.insert(addr, tx);
+        LoopbackTransport {
+            network: self.clone(),
+            addr,
+            rx: tokio::sync::Mutex::new(rx),
+        }
+    }
+
+    fn deliver(&self, data: &[u8], from: SocketAddr, to: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        let conditions = state.conditions;
+        let Some(tx) = state.bound.get(&to).cloned() else {
+            return;
+        };
+        let rng = &mut state.rng;
+        if rng.gen_bool(conditions.loss.clamp(0.0, 1.0)) {
+            return;
+        }
+        let mut delay = conditions.latency;
+        if !conditions.jitter.is_zero() {
+            delay += conditions.jitter.mul_f64(rng.gen());
+        }
+        if rng.gen_bool(conditions.reorder.clamp(0.0, 1.0)) {
+            delay += REORDER_DELAY;
+        }
+        let datagram = (data.to_vec(), from);
+        if delay.is_zero() {
+            let _ = tx.send(datagram);
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = tx.send(datagram);
+            });
+        }
+    }
+}
+
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    addr: SocketAddr,
+    rx: tokio::sync::Mutex<UnboundedReceiver<Datagram>>,
+}
+
+impl DatagramTransport for LoopbackTransport {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        // Only once polled, so an unpolled send_to sends nothing
+        self.network.deliver(buf, self.addr, target);
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let Some((data, from)) = self.rx.lock().await.recv().await else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        Ok((size, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::socket::MinetestSocket;
+    use crate::wire::command::Command;
+    use crate::wire::command::InitSpec;
+    use crate::wire::command::TCChatMessageSpec;
+    use crate::wire::command::TSChatMessageSpec;
+    use crate::wire::command::ToClientCommand;
+    use crate::wire::command::ToServerCommand;
+    use crate::MinetestClient;
+    use crate::MinetestServer;
+
+    #[tokio::test]
+    async fn loopback_sockets() {
+        let network = LoopbackNetwork::new();
+        let server_addr: SocketAddr = "10.0.0.1:30000".parse().unwrap();
+        let client_addr: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut server = MinetestSocket::with_transport(network.bind(server_addr), true).unwrap();
+        let mut client = MinetestSocket::with_transport(network.bind(client_addr), false).unwrap();
+        assert_eq!(server.local_addr(), server_addr);
+
+        let to_server = client.add_peer(server_addr).await;
+        let init = Command::ToServer(
+            InitSpec {
+                serialization_ver_max: 29,
+                supp_compr_modes: 0,
+                min_net_proto_version: 37,
+                max_net_proto_version: 41,
+                player_name: "x".repeat(2000),
+            }
+            .into(),
+        );
+        to_server.send(init).await.unwrap();
+
+        let mut to_client = server.accept().await.unwrap();
+        assert_eq!(to_client.remote_addr(), client_addr);
+        // Split, sent reliably, and put back together
+        let Command::ToServer(ToServerCommand::Init(spec)) = to_client.recv().await.unwrap() else {
+            panic!("expected Init");
+        };
+        assert_eq!(spec.player_name.len(), 2000);
+    }
+
+    #[tokio::test]
+    async fn bad_network() {
+        let network = LoopbackNetwork::with_seed(3);
+        network.set_conditions(LinkConditions {
+            loss: 0.1,
+            reorder: 0.2,
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+        });
+        let server_addr: SocketAddr = "10.0.0.1:30000".parse().unwrap();
+        let client_addr: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut server = MinetestServer::with_transport(network.bind(server_addr)).unwrap();
+        let mut client =
+            MinetestClient::connect_with_transport(network.bind(client_addr), server_addr)
+                .await
+                .unwrap();
+
+        let exchange = async {
+            for i in 0..20 {
+                let message = format!("{}", i);
+                client
+                    .send(TSChatMessageSpec { message }.into())
+                    .await
+                    .unwrap();
+            }
+            let mut conn = server.accept().await;
+            for i in 0..20 {
+                let Ok(ToServerCommand::TSChatMessage(spec)) = conn.recv().await else {
+                    panic!("expected TSChatMessage");
+                };
+                assert_eq!(spec.message, format!("{}", i));
+            }
+            // Split in many packets
+            let long = "x".repeat(20000);
+            let chat = TCChatMessageSpec {
+                version: 1,
+                message_type: 1,
+                sender: String::new(),
+                message: long.clone(),
+                timestamp: 0,
+            };
+            conn.send(chat.into()).await.unwrap();
+            let Ok(ToClientCommand::TCChatMessage(spec)) = client.recv().await else {
+                panic!("expected TCChatMessage");
+            };
+            assert_eq!(spec.message, long);
+        };
+        tokio::time::timeout(Duration::from_secs(60), exchange)
+            .await
+            .unwrap();
+    }
+}
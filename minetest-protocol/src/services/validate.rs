@@ -0,0 +1,161 @@
+//!
+//! Player name and chat validation
+//!
+//! The same rules the engine applies before a player joins, and before a
+//! chat message is sent to everyone:
+//!
+//! ```text
+//! let rules = NameRules::default();
+//! rules.check("alice")?;                  // Err(WrongName) or Err(WrongCharsInName)
+//!
+//! match sanitize_chat(&spec.message, MAX_CHAT_MESSAGE_LEN) {
+//!     Ok(line) => broadcast(format!("<{}> {}", name, line)),
+//!     Err(err) => reply(err.to_string()),  // only to the sender
+//! }
+//! ```
+//!
+//! The auth handshake checks names with the NameRules in its AuthConfig.
+//!
+use crate::wire::types::AccessDeniedCode;
+
+/// The engine's PLAYERNAME_SIZE, less the terminator
+pub const MAX_PLAYER_NAME_LEN: usize = 19;
+
+/// The engine's default chat_message_max_size, in characters
+pub const MAX_CHAT_MESSAGE_LEN: usize = 2000;
+
+/// The engine's PLAYERNAME_ALLOWED_CHARS: letters, digits, '-' and '_'
+pub fn is_player_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// Which player names may join
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameRules {
+    /// Refused whatever the case. The engine refuses "singleplayer",
+    /// except in singleplayer.
+    pub disallowed: Vec<String>,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        Self {
+            disallowed: vec!["singleplayer".into()],
+        }
+    }
+}
+
+impl NameRules {
+    /// No disallowed names, like singleplayer
+    pub fn singleplayer() -> Self {
+        Self {
+            disallowed: Vec::new(),
+        }
+    }
+
+    /// In the engine's order: length, characters, then disallowed names
+    pub fn check(&self, player_name: &str) -> Result<(), AccessDeniedCode> {
+        if player_name.is_empty() || player_name.len() > MAX_PLAYER_NAME_LEN {
+            return Err(AccessDeniedCode::WrongName);
+        }
+        if !player_name.chars().all(is_player_name_char) {
+            return Err(AccessDeniedCode::WrongCharsInName);
+        }
+        if self
+            .disallowed
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(player_name))
+        {
+            return Err(AccessDeniedCode::WrongName);
+        }
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ChatError {
+    /// The engine's reply, sent only to the sender
+    #[error("Your message exceed the maximum chat message limit set on the server. It was refused. Send a shorter message")]
+    TooLong,
+    /// Nothing left to send
+    #[error("Empty chat message")]
+    Empty,
+}
+
+/// A chat message from a client, fit to send to everyone: without control
+/// characters (so it can't fake a line from someone else with a newline)
+/// or color escapes, and without surrounding whitespace. Messages over
+/// `max_len` characters are refused, like the engine does.
+pub fn sanitize_chat(message: &str, max_len: usize) -> Result<String, ChatError> {
+    if message.chars().count() > max_len {
+        return Err(ChatError::TooLong);
+    }
+    let mut out = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            skip_escape(&mut chars);
+        } else if !c.is_control() {
+            out.push(c);
+        }
+    }
+    let out = out.trim();
+    if out.is_empty() {
+        return Err(ChatError::Empty);
+    }
+    Ok(out.to_string())
+}
+
+/// After ESC, an enriched text escape is "(...)" or a single character
+fn skip_escape(chars: &mut std::str::Chars<'_>) {
+    if chars.next() == Some('(') {
+        for c in chars.by_ref() {
+            if c == ')' {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_names() {
+        let rules = NameRules::default();
+        assert_eq!(rules.check("alice_2-b"), Ok(()));
+        assert_eq!(rules.check(&"a".repeat(19)), Ok(()));
+        assert_eq!(
+            rules.check(&"a".repeat(20)),
+            Err(AccessDeniedCode::WrongName)
+        );
+        assert_eq!(rules.check(""), Err(AccessDeniedCode::WrongName));
+        assert_eq!(
+            rules.check("bad name"),
+            Err(AccessDeniedCode::WrongCharsInName)
+        );
+        assert_eq!(rules.check("café"), Err(AccessDeniedCode::WrongCharsInName));
+        assert_eq!(
+            rules.check("SinglePlayer"),
+            Err(AccessDeniedCode::WrongName)
+        );
+        assert_eq!(NameRules::singleplayer().check("singleplayer"), Ok(()));
+    }
+
+    #[test]
+    fn chat() {
+        assert_eq!(sanitize_chat("  hi there ", 11).unwrap(), "hi there");
+        assert_eq!(
+            sanitize_chat("hi\n<admin> you are banned", 100).unwrap(),
+            "hi<admin> you are banned"
+        );
+        assert_eq!(
+            sanitize_chat("\x1b(c@#ff0000)red\x1bE and plain", 100).unwrap(),
+            "red and plain"
+        );
+        assert_eq!(sanitize_chat("héllo", 5).unwrap(), "héllo");
+        assert_eq!(sanitize_chat("héllo!", 5), Err(ChatError::TooLong));
+        assert_eq!(sanitize_chat("\x07\t ", 100), Err(ChatError::Empty));
+    }
+}
@@ -0,0 +1,283 @@
+//!
+//! Stall detection
+//!
+//! A connection stalls when a reliable packet never gets acked: the sender
+//! keeps resending it, and nothing behind it gets through (the client sits
+//! at "Connecting to server..." forever). StallDetector looks at
+//! ConnectionStats snapshots for a channel whose oldest unacked packet
+//! stays the same for too long:
+//!
+//! ```text
+//! let mut detector = StallDetector::new(DEFAULT_STALL_TIMEOUT);
+//! if let Some(stall) = detector.check(&conn.stats(), Instant::now()) {
+//!     println!("{}\n{}", stall, conn.post_mortem());
+//! }
+//! ```
+//!
+//! MinetestConnection::watch_for_stalls does this in a task of its own.
+//!
+//! RecentCommands keeps the last commands of a connection, to show what led
+//! up to a stall or a disconnect (see MinetestConnection::keep_recent):
+//!
+//! ```text
+//! +12.031s C->S Init 21 bytes
+//! +12.044s S->C Hello 39 bytes
+//! +12.045s C->S SrpBytesA 276 bytes
+//! ```
+//!
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::peer::stats::ConnectionStats;
+use crate::wire::command::CommandRef;
+use crate::wire::types::CommandDirection;
+
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Commands RecentCommands keeps by default
+pub const DEFAULT_RECENT_COMMANDS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stall {
+    pub channel: u8,
+    /// The reliable packet that isn't acked
+    pub seqnum: u16,
+    /// How long it has been the oldest unacked one
+    pub duration: Duration,
+    /// Resends on the channel meanwhile
+    pub retransmits: u64,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "channel {} stalled: reliable {} unacked for {:.1?} ({} retransmits)",
+            self.channel, self.seqnum, self.duration, self.retransmits
+        )
+    }
+}
+
+/// Where a channel was at when its oldest unacked packet last changed
+#[derive(Debug, Clone, Copy, Default)]
+struct Progress {
+    oldest_unacked: Option<u16>,
+    since: Option<Instant>,
+    retransmits: u64,
+    reported: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct StallDetector {
+    timeout: Duration,
+    channels: [Progress; 3],
+}
+
+impl StallDetector {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            channels: Default::default(),
+        }
+    }
+
+    /// Call with fresh stats, e.g. every second. Returns a stall once, when
+    /// a channel has gone `timeout` without its oldest unacked packet being
+    /// acked. After that, the channel is reported again only if it gets
+    /// going and stalls anew.
+    pub fn check(&mut self, stats: &ConnectionStats, now: Instant) -> Option<Stall> {
+        let mut stall = None;
+        for (num, (progress, channel)) in self.channels.iter_mut().zip(&stats.channels).enumerate()
+        {
+            let since = match progress.since {
+                Some(since) if progress.oldest_unacked == channel.oldest_unacked_seqnum => since,
+                _ => {
+                    *progress = Progress {
+                        oldest_unacked: channel.oldest_unacked_seqnum,
+                        since: Some(now),
+                        retransmits: channel.retransmits,
+                        reported: false,
+                    };
+                    continue;
+                }
+            };
+            let Some(seqnum) = progress.oldest_unacked else {
+                continue;
+            };
+            let duration = now.saturating_duration_since(since);
+            if duration < self.timeout || progress.reported {
+                continue;
+            }
+            progress.reported = true;
+            stall = stall.or(Some(Stall {
+                channel: num as u8,
+                seqnum,
+                duration,
+                retransmits: channel.retransmits - progress.retransmits,
+            }));
+        }
+        stall
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentCommand {
+    /// Since the RecentCommands was made
+    pub at: Duration,
+    pub dir: CommandDirection,
+    pub name: &'static str,
+    /// Serialized size
+    pub size: usize,
+    /// The command's Debug output, with RecentCommands::with_bodies
+    pub body: Option<String>,
+}
+
+/// A ring buffer of the last commands seen
+#[derive(Debug, Clone)]
+pub struct RecentCommands {
+    capacity: usize,
+    bodies: bool,
+    start: Instant,
+    commands: VecDeque<RecentCommand>,
+}
+
+impl RecentCommands {
+    /// Keeps the name and size of the last `capacity` commands
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            bodies: false,
+            start: Instant::now(),
+            commands: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Keeps whole commands too. That formats every command, and keeps
+    /// private data (chat, passwords) around, so it's for debugging.
+    pub fn with_bodies(mut self) -> Self {
+        self.bodies = true;
+        self
+    }
+
+    pub fn push<Cmd: CommandRef>(&mut self, command: &Cmd, size: usize) {
+        self.push_at(command, size, Instant::now());
+    }
+
+    pub fn push_at<Cmd: CommandRef>(&mut self, command: &Cmd, size: usize, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.commands.len() == self.capacity {
+            self.commands.pop_front();
+        }
+        self.commands.push_back(RecentCommand {
+            at: now.saturating_duration_since(self.start),
+            dir: command.direction(),
+            name: command.command_name(),
+            size,
+            body: self.bodies.then(|| format!("{:?}", command)),
+        });
+    }
+
+    /// Oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &RecentCommand> {
+        self.commands.iter()
+    }
+}
+
+/// One command per line, oldest first
+impl fmt::Display for RecentCommands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for command in &self.commands {
+            let dir = match command.dir {
+                CommandDirection::ToClient => "S->C",
+                CommandDirection::ToServer => "C->S",
+            };
+            write!(
+                f,
+                "+{:.3}s {} {} {} bytes",
+                command.at.as_secs_f64(),
+                dir,
+                command.name,
+                command.size
+            )?;
+            match &command.body {
+                Some(body) => writeln!(f, ": {}", body)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::command::Command;
+    use crate::wire::command::TSChatMessageSpec;
+
+    #[test]
+    fn detects_stalls_once() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut detector = StallDetector::new(Duration::from_secs(10));
+        let mut stats = ConnectionStats::default();
+        stats.channels[1].oldest_unacked_seqnum = Some(65500);
+        stats.channels[1].retransmits = 3;
+        assert_eq!(detector.check(&stats, at(0)), None);
+
+        // Acks coming in
+        stats.channels[1].oldest_unacked_seqnum = Some(65510);
+        assert_eq!(detector.check(&stats, at(8)), None);
+        stats.channels[1].retransmits = 9;
+        assert_eq!(detector.check(&stats, at(16)), None);
+        let stall = detector.check(&stats, at(18)).unwrap();
+        assert_eq!(
+            stall,
+            Stall {
+                channel: 1,
+                seqnum: 65510,
+                duration: Duration::from_secs(10),
+                retransmits: 6,
+            }
+        );
+        assert_eq!(detector.check(&stats, at(30)), None);
+
+        // Going again, then idle: nothing unacked is no stall
+        stats.channels[1].oldest_unacked_seqnum = None;
+        assert_eq!(detector.check(&stats, at(31)), None);
+        assert_eq!(detector.check(&stats, at(60)), None);
+    }
+
+    #[test]
+    fn recent_commands() {
+        let chat = |message: &str| {
+            Command::ToServer(
+                TSChatMessageSpec {
+                    message: message.to_string(),
+                }
+                .into(),
+            )
+        };
+        let mut recent = RecentCommands::new(2);
+        for (size, message) in ["one", "two", "three"].into_iter().enumerate() {
+            recent.push(&chat(message), size);
+        }
+        let commands: Vec<&RecentCommand> = recent.iter().collect();
+        assert_eq!(commands.len(), 2);
+        assert_eq!((commands[0].name, commands[0].size), ("TSChatMessage", 1));
+        assert_eq!(commands[1].body, None);
+        assert!(recent
+            .to_string()
+            .lines()
+            .all(|line| line.contains(" C->S TSChatMessage ")));
+
+        let mut recent = RecentCommands::new(2).with_bodies();
+        recent.push(&chat("hello"), 12);
+        let dump = recent.to_string();
+        assert!(dump.contains(" C->S TSChatMessage 12 bytes: "));
+        assert!(dump.contains("\"hello\""));
+    }
+}
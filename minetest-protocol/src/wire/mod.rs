@@ -1,7 +0,0 @@
-pub mod audit;
-pub mod command;
-pub mod deser;
-pub mod packet;
-pub mod ser;
-pub mod types;
-pub mod util;
@@ -0,0 +1,145 @@
+//!
+//! Unloading unused blocks
+//!
+//! Like the engine's server_unload_unused_data_timeout: a block that
+//! nothing has used for `timeout` is written back and dropped from memory,
+//! so a long-running server doesn't keep every block a player ever walked
+//! through.
+//!
+//! A block is touched when it is read or written, and while a player is near
+//! it (touch_area, every step, for each player):
+//!
+//! ```text
+//! let mut activity = BlockActivity::new(Duration::from_secs(29));
+//! activity.touch_area(&player_block, 2, now);
+//! ...
+//! activity.unload_idle(&mut cache, now)?;
+//! ```
+//!
+//! When a block is next saved, its disk timestamp should be set to the game
+//! time, so the time it spent unloaded can be caught up on when it is
+//! loaded again (see DiskMapBlock::unloaded_for).
+//!
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+
+use crate::wire::types::v3s16;
+
+use super::cache::BlockCache;
+use super::database::MapDatabase;
+
+#[derive(Debug, Clone)]
+pub struct BlockActivity {
+    timeout: Duration,
+    last_used: HashMap<v3s16, Instant>,
+}
+
+impl BlockActivity {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_used: HashMap::new(),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Blocks tracked, i.e. loaded
+    pub fn len(&self) -> usize {
+        self.last_used.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_used.is_empty()
+    }
+
+    pub fn last_used(&self, pos: &v3s16) -> Option<Instant> {
+        self.last_used.get(pos).copied()
+    }
+
+    pub fn touch(&mut self, pos: &v3s16, now: Instant) {
+        self.last_used.insert(pos.clone(), now);
+    }
+
+    /// Touches every loaded block within `radius` blocks of `center`
+    /// (a cube). Blocks not loaded are left alone.
+    pub fn touch_area(&mut self, center: &v3s16, radius: i16, now: Instant) {
+        let near = |a: i16, b: i16| (a as i32 - b as i32).abs() <= radius as i32;
+        for (pos, last_used) in self.last_used.iter_mut() {
+            if near(pos.x, center.x) && near(pos.y, center.y) && near(pos.z, center.z) {
+                *last_used = now;
+            }
+        }
+    }
+
+    /// Stops tracking a block, e.g. one that was deleted
+    pub fn forget(&mut self, pos: &v3s16) {
+        self.last_used.remove(pos);
+    }
+
+    /// Blocks unused for `timeout`, least recently used first
+    pub fn idle(&self, now: Instant) -> Vec<v3s16> {
+        let mut idle: Vec<(Instant, v3s16)> = self
+            .last_used
+            .iter()
+            .filter(|(_, &last_used)| now.saturating_duration_since(last_used) >= self.timeout)
+            .map(|(pos, &last_used)| (last_used, pos.clone()))
+            .collect();
+        idle.sort_by_key(|(last_used, pos)| (*last_used, pos.x, pos.y, pos.z));
+        idle.into_iter().map(|(_, pos)| pos).collect()
+    }
+
+    /// Writes back and drops the idle blocks from `cache`. Returns them.
+    /// If writing one fails, it stays loaded, and the error is returned.
+    pub fn unload_idle<D: MapDatabase>(
+        &mut self,
+        cache: &mut BlockCache<D>,
+        now: Instant,
+    ) -> Result<Vec<v3s16>> {
+        let idle = self.idle(now);
+        for pos in &idle {
+            cache.unload(pos)?;
+            self.forget(pos);
+        }
+        Ok(idle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::MemoryMapDatabase;
+
+    fn pos(x: i16) -> v3s16 {
+        v3s16::new(x, 0, 0)
+    }
+
+    #[test]
+    fn unloads_idle_blocks() {
+        let start = Instant::now();
+        let mut cache = BlockCache::new(MemoryMapDatabase::new(), 100);
+        let mut activity = BlockActivity::new(Duration::from_secs(29));
+        for x in 0..4 {
+            cache.set_block(&pos(x), &[x as u8]).unwrap();
+            activity.touch(&pos(x), start);
+        }
+        // A player stands near 0 and 1
+        let later = start + Duration::from_secs(20);
+        activity.touch_area(&pos(0), 1, later);
+        assert!(activity.idle(later).is_empty());
+
+        let now = start + Duration::from_secs(30);
+        assert_eq!(activity.idle(now), vec![pos(2), pos(3)]);
+        assert_eq!(activity.unload_idle(&mut cache, now).unwrap().len(), 2);
+        assert_eq!(activity.len(), 2);
+        assert_eq!(cache.len(), 2);
+        // Flushed before being dropped
+        assert_eq!(cache.database().get_block(&pos(3)).unwrap(), Some(vec![3]));
+        assert_eq!(cache.database().get_block(&pos(0)).unwrap(), None);
+    }
+}
@@ -0,0 +1,537 @@
+//!
+//! World archives
+//!
+//! A whole world (map, world.mt, auth, players, mod storage...) in one
+//! compressed file, independent of the map backend it came from. Blocks
+//! are stored by position, so an archive of a LevelDB world imports into
+//! SQLite like any other.
+//!
+//! ```text
+//! "MTWORLD\0" u16 version
+//! records, each:
+//!     u8 kind, u32 length, zstd(payload), sha1(kind, payload)
+//! kind 1 (file):   u16 name length, name ('/' separated), data
+//! kind 2 (blocks): u32 count, count x (s16 x, y, z, u32 length, data)
+//! kind 0 (end):    u64 files, u64 blocks, sha1 of every record's sha1
+//! ```
+//!
+//! Every record is checked as it is read, and an archive without its end
+//! record is rejected as truncated.
+//!
+use std::io::Read;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Result;
+use sha1_smol::Sha1;
+
+use crate::wire::types::v3s16;
+use crate::wire::util::zstd_compress;
+use crate::wire::util::zstd_decompress;
+
+use super::database::MapDatabase;
+
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"MTWORLD\0";
+pub const ARCHIVE_VERSION: u16 = 1;
+
+/// Blocks are compressed in batches of about this many bytes
+pub const ARCHIVE_BATCH_SIZE: usize = 4 << 20;
+
+const RECORD_END: u8 = 0;
+const RECORD_FILE: u8 = 1;
+const RECORD_BLOCKS: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArchiveEntry {
+    /// A file in the world directory, by its relative path
+    File {
+        name: String,
+        data: Vec<u8>,
+    },
+    Blocks(Vec<(v3s16, Vec<u8>)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ArchiveSummary {
+    pub files: u64,
+    pub blocks: u64,
+}
+
+fn record_hash(kind: u8, payload: &[u8]) -> [u8; 20] {
+    let mut hash = Sha1::new();
+    hash.update(&[kind]);
+    hash.update(payload);
+    hash.digest().bytes()
+}
+
+pub struct ArchiveWriter<W: Write> {
+    out: W,
+    batch: Vec<u8>,
+    batch_count: u32,
+    summary: ArchiveSummary,
+    digest: Sha1,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(ARCHIVE_MAGIC)?;
+        out.write_all(&ARCHIVE_VERSION.to_be_bytes())?;
+        Ok(Self {
+            out,
+            batch: Vec::new(),
+            batch_count: 0,
+            summary: ArchiveSummary::default(),
+            digest: Sha1::new(),
+        })
+    }
+
+    /// `name` is relative to the world directory, with '/' separators
+    pub fn add_file(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let Ok(len) = u16::try_from(name.len()) else {
+            bail!("File name too long: {}", name);
+        };
+        let mut payload = Vec::with_capacity(2 + name.len() + data.len());
+        payload.extend_from_slice(&len.to_be_bytes());
+        payload.extend_from_slice(name.as_bytes());
+        payload.extend_from_slice(data);
+        self.write_record(RECORD_FILE, &payload)?;
+        self.summary.files += 1;
+        Ok(())
+    }
+
+    pub fn add_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()> {
+        let Ok(len) = u32::try_from(data.len()) else {
+            bail!("Block {:?} too large", pos);
+        };
+        for v in [pos.x, pos.y, pos.z] {
+            self.batch.extend_from_slice(&v.to_be_bytes());
+        }
+        self.batch.extend_from_slice(&len.to_be_bytes());
+        self.batch.extend_from_slice(data);
+        self.batch_count += 1;
+        self.summary.blocks += 1;
+        if self.batch.len() >= ARCHIVE_BATCH_SIZE {
+            self.flush_blocks()?;
+        }
+        Ok(())
+    }
+
+    fn flush_blocks(&mut self) -> Result<()> {
+        if self.batch_count == 0 {
+            return Ok(());
+        }
+        let mut payload = Vec::with_capacity(4 + self.batch.len());
+        payload.extend_from_slice(&self.batch_count.to_be_bytes());
+        payload.append(&mut self.batch);
+        self.batch_count = 0;
+        self.write_record(RECORD_BLOCKS, &payload)
+    }
+
+    fn write_record(&mut self, kind: u8, payload: &[u8]) -> Result<()> {
+        let mut compressed = Vec::new();
+        zstd_compress(payload, |chunk| {
+            compressed.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        let Ok(len) = u32::try_from(compressed.len()) else {
+            bail!("Archive record too large");
+        };
+        let hash = record_hash(kind, payload);
+        self.out.write_all(&[kind])?;
+        self.out.write_all(&len.to_be_bytes())?;
+        self.out.write_all(&compressed)?;
+        self.out.write_all(&hash)?;
+        self.digest.update(&hash);
+        Ok(())
+    }
+
+    /// Write the end record. Returns the output, and what was written.
+    pub fn finish(mut self) -> Result<(W, ArchiveSummary)> {
+        self.flush_blocks()?;
+        self.out.write_all(&[RECORD_END])?;
+        self.out.write_all(&self.summary.files.to_be_bytes())?;
+        self.out.write_all(&self.summary.blocks.to_be_bytes())?;
+        self.out.write_all(&self.digest.digest().bytes())?;
+        self.out.flush()?;
+        Ok((self.out, self.summary))
+    }
+}
+
+pub struct ArchiveReader<R: Read> {
+    input: R,
+    summary: ArchiveSummary,
+    digest: Sha1,
+    records: u64,
+    done: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut header = [0u8; 10];
+        input.read_exact(&mut header)?;
+        if &header[..8] != ARCHIVE_MAGIC {
+            bail!("Not a world archive");
+        }
+        let version = u16::from_be_bytes([header[8], header[9]]);
+        if version != ARCHIVE_VERSION {
+            bail!("Unsupported world archive version {}", version);
+        }
+        Ok(Self {
+            input,
+            summary: ArchiveSummary::default(),
+            digest: Sha1::new(),
+            records: 0,
+            done: false,
+        })
+    }
+
+    fn read_n<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        match self.input.read_exact(&mut buf) {
+            Ok(()) => Ok(buf),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                bail!("World archive is truncated")
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The next entry, or None after the (verified) end of the archive
+    pub fn read_entry(&mut self) -> Result<Option<ArchiveEntry>> {
+        if self.done {
+            return Ok(None);
+        }
+        let [kind] = self.read_n::<1>()?;
+        if kind == RECORD_END {
+            let files = u64::from_be_bytes(self.read_n()?);
+            let blocks = u64::from_be_bytes(self.read_n()?);
+            let digest: [u8; 20] = self.read_n()?;
+            if digest != self.digest.digest().bytes() {
+                bail!("World archive is corrupt (bad checksum)");
+            }
+            if (ArchiveSummary { files, blocks }) != self.summary {
+                bail!("World archive is corrupt (entries missing)");
+            }
+            self.done = true;
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(self.read_n()?) as usize;
+        let mut compressed = vec![0u8; len];
+        if let Err(err) = self.input.read_exact(&mut compressed) {
+            if err.kind() == std::io::ErrorKind::UnexpectedEof {
+                bail!("World archive is truncated");
+            }
+            return Err(err.into());
+        }
+        let hash: [u8; 20] = self.read_n()?;
+        let mut payload = Vec::new();
+        let decompressed = zstd_decompress(&compressed, |chunk| {
+            payload.extend_from_slice(chunk);
+            Ok(())
+        });
+        if decompressed.is_err() || record_hash(kind, &payload) != hash {
+            bail!("World archive record {} is corrupt", self.records);
+        }
+        self.digest.update(&hash);
+        self.records += 1;
+        let entry = match kind {
+            RECORD_FILE => parse_file(&payload)?,
+            RECORD_BLOCKS => parse_blocks(&payload)?,
+            _ => bail!("Unknown world archive record kind {}", kind),
+        };
+        match &entry {
+            ArchiveEntry::File { .. } => self.summary.files += 1,
+            ArchiveEntry::Blocks(blocks) => self.summary.blocks += blocks.len() as u64,
+        }
+        Ok(Some(entry))
+    }
+
+    /// What has been read so far (everything, once read_entry returns None)
+    pub fn summary(&self) -> ArchiveSummary {
+        self.summary
+    }
+}
+
+fn parse_file(payload: &[u8]) -> Result<ArchiveEntry> {
+    let Some((len, rest)) = payload.split_first_chunk::<2>() else {
+        bail!("Bad file record");
+    };
+    let len = u16::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        bail!("Bad file record");
+    }
+    let (name, data) = rest.split_at(len);
+    Ok(ArchiveEntry::File {
+        name: String::from_utf8(name.to_vec())?,
+        data: data.to_vec(),
+    })
+}
+
+fn parse_blocks(payload: &[u8]) -> Result<ArchiveEntry> {
+    let Some((count, mut rest)) = payload.split_first_chunk::<4>() else {
+        bail!("Bad blocks record");
+    };
+    let count = u32::from_be_bytes(*count);
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        let Some((header, tail)) = rest.split_first_chunk::<10>() else {
+            bail!("Bad blocks record");
+        };
+        let v = |i: usize| i16::from_be_bytes([header[i], header[i + 1]]);
+        let pos = v3s16::new(v(0), v(2), v(4));
+        let len = u32::from_be_bytes([header[6], header[7], header[8], header[9]]) as usize;
+        if tail.len() < len {
+            bail!("Bad blocks record");
+        }
+        let (data, tail) = tail.split_at(len);
+        blocks.push((pos, data.to_vec()));
+        rest = tail;
+    }
+    Ok(ArchiveEntry::Blocks(blocks))
+}
+
+/// Map files of every backend. The map is archived as blocks instead.
+fn is_map_file(name: &str) -> bool {
+    name == "map.sqlite" || name.starts_with("map.sqlite-") || name == "map.db"
+}
+
+fn collect_files(dir: &Path, prefix: &str, out: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let Some(name) = entry.file_name().to_str().map(|name| name.to_string()) else {
+            bail!("Non UTF-8 file name in world: {:?}", entry.path());
+        };
+        if prefix.is_empty() && is_map_file(&name) {
+            continue;
+        }
+        let relative = format!("{}{}", prefix, name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), &format!("{}/", relative), out)?;
+        } else if file_type.is_file() {
+            out.push((relative, entry.path()));
+        }
+    }
+    Ok(())
+}
+
+/// Archive the world in directory `dir`, whose map is `db`.
+///
+/// Other files are copied as they are. Stop the server first: a database
+/// being written to (auth.sqlite, players.sqlite) may be copied half written.
+pub fn export_world<D, W>(dir: &Path, db: &D, out: W) -> Result<(W, ArchiveSummary)>
+where
+    D: MapDatabase + ?Sized,
+    W: Write,
+{
+    let mut archive = ArchiveWriter::new(out)?;
+    let mut files = Vec::new();
+    collect_files(dir, "", &mut files)?;
+    for (name, path) in files {
+        archive.add_file(&name, &std::fs::read(path)?)?;
+    }
+    for pos in db.list_blocks()? {
+        if let Some(data) = db.get_block(&pos)? {
+            archive.add_block(&pos, &data)?;
+        }
+    }
+    archive.finish()
+}
+
+/// Where an archived file goes under `dir`. Names that would escape it
+/// are rejected.
+pub fn archive_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let relative = Path::new(name);
+    if name.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("Bad file name in world archive: {:?}", name);
+    }
+    Ok(dir.join(relative))
+}
+
+/// Point world.mt at the sqlite3 map backend (the one import writes)
+pub fn set_sqlite_backend(world_mt: &str) -> String {
+    let mut out = String::new();
+    let mut found = false;
+    for line in world_mt.lines() {
+        let key = line.split_once('=').map(|(key, _)| key.trim());
+        if key == Some("backend") {
+            found = true;
+            out.push_str("backend = sqlite3\n");
+        } else {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    if !found {
+        out.push_str("backend = sqlite3\n");
+    }
+    out
+}
+
+/// Unpack an archive into `dir` (which must be empty or not exist), with
+/// the map in a new map.sqlite
+#[cfg(feature = "sqlite")]
+pub fn import_world<R: Read>(input: R, dir: &Path) -> Result<ArchiveSummary> {
+    use super::sqlite::SqliteMapDatabase;
+
+    if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+        bail!("{:?} is not empty", dir);
+    }
+    std::fs::create_dir_all(dir)?;
+    let mut archive = ArchiveReader::new(input)?;
+    let db = SqliteMapDatabase::create(&dir.join("map.sqlite"))?;
+    let mut has_world_mt = false;
+    while let Some(entry) = archive.read_entry()? {
+        match entry {
+            ArchiveEntry::File { name, data } => {
+                let path = archive_path(dir, &name)?;
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                if name == "world.mt" {
+                    has_world_mt = true;
+                    let text = String::from_utf8_lossy(&data);
+                    std::fs::write(path, set_sqlite_backend(&text))?;
+                } else {
+                    std::fs::write(path, data)?;
+                }
+            }
+            ArchiveEntry::Blocks(blocks) => db.set_blocks(&blocks)?,
+        }
+    }
+    if !has_world_mt {
+        std::fs::write(dir.join("world.mt"), set_sqlite_backend(""))?;
+    }
+    Ok(archive.summary())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        let mut archive = ArchiveWriter::new(Vec::new()).unwrap();
+        archive
+            .add_file("world.mt", b"backend = leveldb\n")
+            .unwrap();
+        archive.add_file("players/sam", b"data").unwrap();
+        archive
+            .add_block(&v3s16::new(1, -2, 3), &[29, 1, 2])
+            .unwrap();
+        archive.add_block(&v3s16::new(0, 0, 0), &[29]).unwrap();
+        let (data, summary) = archive.finish().unwrap();
+        assert_eq!(
+            summary,
+            ArchiveSummary {
+                files: 2,
+                blocks: 2
+            }
+        );
+        data
+    }
+
+    fn read_all(data: &[u8]) -> Result<Vec<ArchiveEntry>> {
+        let mut archive = ArchiveReader::new(data)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.read_entry()? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        let entries = read_all(&data).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ArchiveEntry::File {
+                    name: "world.mt".to_string(),
+                    data: b"backend = leveldb\n".to_vec()
+                },
+                ArchiveEntry::File {
+                    name: "players/sam".to_string(),
+                    data: b"data".to_vec()
+                },
+                ArchiveEntry::Blocks(vec![
+                    (v3s16::new(1, -2, 3), vec![29, 1, 2]),
+                    (v3s16::new(0, 0, 0), vec![29]),
+                ]),
+            ]
+        );
+
+        assert!(read_all(&data[..data.len() - 1]).is_err());
+        // The first record's hash
+        let len = u32::from_be_bytes(data[11..15].try_into().unwrap()) as usize;
+        let mut corrupt = data.clone();
+        corrupt[15 + len] ^= 1;
+        assert!(read_all(&corrupt).is_err());
+    }
+
+    #[test]
+    fn paths_and_world_mt() {
+        let dir = Path::new("/worlds/w");
+        assert!(archive_path(dir, "players/sam").is_ok());
+        assert!(archive_path(dir, "../escape").is_err());
+        assert!(archive_path(dir, "/etc/passwd").is_err());
+        assert!(archive_path(dir, "").is_err());
+
+        assert_eq!(
+            set_sqlite_backend("gameid = minetest\nbackend = leveldb\nplayer_backend = sqlite3\n"),
+            "gameid = minetest\nbackend = sqlite3\nplayer_backend = sqlite3\n"
+        );
+        assert_eq!(
+            set_sqlite_backend("gameid = x"),
+            "gameid = x\nbackend = sqlite3\n"
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn export_import() {
+        use crate::world::database::MemoryMapDatabase;
+
+        let base = std::env::temp_dir().join(format!("mt-archive-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let from = base.join("from");
+        std::fs::create_dir_all(from.join("players")).unwrap();
+        std::fs::write(from.join("world.mt"), "backend = leveldb\n").unwrap();
+        std::fs::write(from.join("players/sam"), "data").unwrap();
+        std::fs::write(from.join("map.sqlite"), "not archived").unwrap();
+        let mut db = MemoryMapDatabase::new();
+        db.insert(&v3s16::new(5, 6, -7), vec![29, 9]);
+
+        let (data, summary) = export_world(&from, &db, Vec::new()).unwrap();
+        assert_eq!(
+            summary,
+            ArchiveSummary {
+                files: 2,
+                blocks: 1
+            }
+        );
+        let to = base.join("to");
+        assert_eq!(import_world(&data[..], &to).unwrap(), summary);
+        assert!(import_world(&data[..], &to).is_err());
+
+        assert_eq!(
+            std::fs::read_to_string(to.join("world.mt")).unwrap(),
+            "backend = sqlite3\n"
+        );
+        assert_eq!(std::fs::read(to.join("players/sam")).unwrap(), b"data");
+        let imported = crate::world::SqliteMapDatabase::open_world(&to).unwrap();
+        assert_eq!(
+            imported.get_block(&v3s16::new(5, 6, -7)).unwrap(),
+            Some(vec![29, 9])
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
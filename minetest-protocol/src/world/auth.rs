@@ -0,0 +1,369 @@
+//!
+//! Auth databases
+//!
+//! Accounts are kept in auth.sqlite (auth_backend = sqlite3, feature
+//! "sqlite"), or in auth.txt by older worlds (auth_backend = files):
+//!
+//! ```text
+//! auth.txt, one line per player:
+//! name:password:privilege,privilege...:last_login
+//!
+//! auth.sqlite:
+//! auth (id INTEGER PRIMARY KEY AUTOINCREMENT, name VARCHAR(32) UNIQUE,
+//!       password VARCHAR(512), last_login INTEGER)
+//! user_privileges (id INTEGER, privilege VARCHAR(32), PRIMARY KEY (id, privilege))
+//! ```
+//!
+//! Passwords are stored as the engine encodes them: "#1#salt#verifier"
+//! (base64) for SRP, or the legacy hash (see srp::legacy_password).
+//!
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::game::media::base64;
+use crate::game::media::unbase64;
+use crate::services::auth::Credentials;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthEntry {
+    pub name: String,
+    /// As stored (see credentials)
+    pub password: String,
+    pub privileges: BTreeSet<String>,
+    /// Unix time, or -1 for never
+    pub last_login: i64,
+}
+
+impl AuthEntry {
+    pub fn new(name: &str, credentials: &Credentials) -> Self {
+        let mut entry = Self {
+            name: name.to_string(),
+            password: String::new(),
+            privileges: BTreeSet::new(),
+            last_login: -1,
+        };
+        entry.set_credentials(credentials);
+        entry
+    }
+
+    pub fn credentials(&self) -> Credentials {
+        match decode_srp_verifier(&self.password) {
+            Some((salt, verifier)) => Credentials::Srp { salt, verifier },
+            None => Credentials::Legacy(self.password.clone()),
+        }
+    }
+
+    pub fn set_credentials(&mut self, credentials: &Credentials) {
+        self.password = match credentials {
+            Credentials::Srp { salt, verifier } => encode_srp_verifier(salt, verifier),
+            Credentials::Legacy(hash) => hash.clone(),
+        };
+    }
+
+    pub fn has_privilege(&self, privilege: &str) -> bool {
+        self.privileges.contains(privilege)
+    }
+}
+
+/// The engine's encoding of SRP credentials
+pub fn encode_srp_verifier(salt: &[u8], verifier: &[u8]) -> String {
+    format!("#1#{}#{}", base64(salt), base64(verifier))
+}
+
+/// The salt and verifier of "#1#salt#verifier". None for anything else
+/// (e.g. a legacy hash).
+pub fn decode_srp_verifier(password: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (salt, verifier) = password.strip_prefix("#1#")?.split_once('#')?;
+    Some((unbase64(salt)?, unbase64(verifier)?))
+}
+
+impl FromStr for AuthEntry {
+    type Err = anyhow::Error;
+
+    /// One line of auth.txt
+    fn from_str(line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.split(':').collect();
+        let (name, password, privileges, last_login) = match fields[..] {
+            [name, password, privileges] => (name, password, privileges, None),
+            [name, password, privileges, last_login] => {
+                (name, password, privileges, Some(last_login))
+            }
+            _ => bail!("Bad auth.txt line {:?}", line),
+        };
+        let last_login = match last_login {
+            Some(time) => time
+                .trim()
+                .parse()
+                .with_context(|| format!("Bad last login in {:?}", line))?,
+            None => -1,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            password: password.to_string(),
+            privileges: privileges
+                .split(',')
+                .map(str::trim)
+                .filter(|privilege| !privilege.is_empty())
+                .map(str::to_string)
+                .collect(),
+            last_login,
+        })
+    }
+}
+
+impl fmt::Display for AuthEntry {
+    /// One line of auth.txt, without the newline
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let privileges: Vec<&str> = self.privileges.iter().map(String::as_str).collect();
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.name,
+            self.password,
+            privileges.join(","),
+            self.last_login
+        )
+    }
+}
+
+/// Every account in an auth.txt. Blank lines are skipped.
+pub fn parse_auth_txt(text: &str) -> Result<Vec<AuthEntry>> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+pub fn write_auth_txt(entries: &[AuthEntry]) -> String {
+    entries.iter().map(|entry| format!("{}\n", entry)).collect()
+}
+
+/// The auth.txt in world directory `dir`
+pub fn read_auth_txt(dir: &Path) -> Result<Vec<AuthEntry>> {
+    let path = dir.join("auth.txt");
+    let text = std::fs::read_to_string(&path).with_context(|| format!("Reading {:?}", path))?;
+    parse_auth_txt(&text)
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::AuthDatabase;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::collections::BTreeSet;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::sync::MutexGuard;
+
+    use anyhow::bail;
+    use anyhow::Result;
+    use rusqlite::Connection;
+    use rusqlite::OptionalExtension;
+
+    use super::AuthEntry;
+    use crate::services::auth::AuthHandler;
+    use crate::services::auth::Credentials;
+
+    /// auth.sqlite. It is an AuthHandler too, so a server can log players
+    /// in with a world's accounts.
+    pub struct AuthDatabase {
+        conn: Mutex<Connection>,
+        default_privileges: BTreeSet<String>,
+    }
+
+    impl AuthDatabase {
+        /// Open for reading and writing, creating the database if needed
+        pub fn open(path: &Path) -> Result<Self> {
+            Self::from_connection(Connection::open(path)?)
+        }
+
+        /// The auth.sqlite in world directory `dir`
+        pub fn open_world(dir: &Path) -> Result<Self> {
+            Self::open(&dir.join("auth.sqlite"))
+        }
+
+        pub fn from_connection(conn: Connection) -> Result<Self> {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS auth (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name VARCHAR(32) UNIQUE,
+                    password VARCHAR(512),
+                    last_login INTEGER
+                );
+                CREATE TABLE IF NOT EXISTS user_privileges (
+                    id INTEGER,
+                    privilege VARCHAR(32),
+                    PRIMARY KEY (id, privilege)
+                );",
+            )?;
+            Ok(Self {
+                conn: Mutex::new(conn),
+                // The engine's default_privs
+                default_privileges: ["interact", "shout"].map(str::to_string).into(),
+            })
+        }
+
+        /// What new accounts get, when registered by a login
+        pub fn set_default_privileges<I: IntoIterator<Item = String>>(&mut self, privileges: I) {
+            self.default_privileges = privileges.into_iter().collect();
+        }
+
+        /// Every account's name
+        pub fn list(&self) -> Result<Vec<String>> {
+            let conn = self.conn();
+            let mut stmt = conn.prepare("SELECT name FROM auth ORDER BY name")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        }
+
+        pub fn load(&self, name: &str) -> Result<Option<AuthEntry>> {
+            let conn = self.conn();
+            let account = conn
+                .prepare_cached("SELECT id, password, last_login FROM auth WHERE name = ?1")?
+                .query_row([name], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                    ))
+                })
+                .optional()?;
+            let Some((id, password, last_login)) = account else {
+                return Ok(None);
+            };
+            let mut stmt =
+                conn.prepare_cached("SELECT privilege FROM user_privileges WHERE id = ?1")?;
+            let privileges = stmt
+                .query_map([id], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(Some(AuthEntry {
+                name: name.to_string(),
+                password: password.unwrap_or_default(),
+                privileges,
+                last_login: last_login.unwrap_or(-1),
+            }))
+        }
+
+        /// Adds the account, or replaces it (keeping its id)
+        pub fn save(&self, entry: &AuthEntry) -> Result<()> {
+            let mut conn = self.conn();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO auth (name, password, last_login) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE SET password = ?2, last_login = ?3",
+                rusqlite::params![entry.name, entry.password, entry.last_login],
+            )?;
+            let id: i64 = tx.query_row(
+                "SELECT id FROM auth WHERE name = ?1",
+                [&entry.name],
+                |row| row.get(0),
+            )?;
+            tx.execute("DELETE FROM user_privileges WHERE id = ?1", [id])?;
+            for privilege in &entry.privileges {
+                tx.execute(
+                    "INSERT INTO user_privileges (id, privilege) VALUES (?1, ?2)",
+                    rusqlite::params![id, privilege],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+
+        /// True if the account was there
+        pub fn remove(&self, name: &str) -> Result<bool> {
+            let mut conn = self.conn();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM user_privileges WHERE id IN (SELECT id FROM auth WHERE name = ?1)",
+                [name],
+            )?;
+            let removed = tx.execute("DELETE FROM auth WHERE name = ?1", [name])?;
+            tx.commit()?;
+            Ok(removed > 0)
+        }
+
+        fn conn(&self) -> MutexGuard<'_, Connection> {
+            self.conn.lock().unwrap()
+        }
+    }
+
+    impl AuthHandler for AuthDatabase {
+        fn credentials(&self, player_name: &str) -> Result<Option<Credentials>> {
+            Ok(self.load(player_name)?.map(|entry| entry.credentials()))
+        }
+
+        fn register(&self, player_name: &str, credentials: Credentials) -> Result<()> {
+            if self.load(player_name)?.is_some() {
+                bail!("Player {} already exists", player_name);
+            }
+            let mut entry = AuthEntry::new(player_name, &credentials);
+            entry.privileges = self.default_privileges.clone();
+            self.save(&entry)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_txt() {
+        let text = "sam:#1#c2FsdA==#dmVyaWZpZXI=:interact,shout:1700000000\n\
+                    old:Ta1AxXfmRbYXyTvuGPyjApJx9ZY=:interact\n";
+        let entries = parse_auth_txt(text).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].credentials(),
+            Credentials::Srp {
+                salt: b"salt".to_vec(),
+                verifier: b"verifier".to_vec(),
+            }
+        );
+        assert!(entries[0].has_privilege("shout"));
+        assert_eq!(entries[0].last_login, 1700000000);
+        assert_eq!(
+            entries[1].credentials(),
+            Credentials::Legacy("Ta1AxXfmRbYXyTvuGPyjApJx9ZY=".into())
+        );
+        assert_eq!(entries[1].last_login, -1);
+        assert_eq!(
+            write_auth_txt(&entries),
+            "sam:#1#c2FsdA==#dmVyaWZpZXI=:interact,shout:1700000000\n\
+             old:Ta1AxXfmRbYXyTvuGPyjApJx9ZY=:interact:-1\n"
+        );
+        assert!(parse_auth_txt("just_a_name\n").is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn auth_sqlite() {
+        use crate::services::auth::AuthHandler;
+        use rusqlite::Connection;
+
+        let db = AuthDatabase::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let credentials = Credentials::from_password("sam", "secret");
+        db.register("sam", credentials.clone()).unwrap();
+        assert!(db.register("sam", credentials.clone()).is_err());
+        assert_eq!(db.credentials("sam").unwrap(), Some(credentials));
+
+        let mut sam = db.load("sam").unwrap().unwrap();
+        assert_eq!(
+            sam.privileges,
+            ["interact", "shout"].map(str::to_string).into()
+        );
+        sam.privileges.insert("fly".into());
+        sam.last_login = 1700000000;
+        db.save(&sam).unwrap();
+        assert_eq!(db.load("sam").unwrap(), Some(sam));
+        assert_eq!(db.list().unwrap(), vec!["sam"]);
+        assert!(db.remove("sam").unwrap());
+        assert_eq!(db.credentials("sam").unwrap(), None);
+    }
+}
@@ -0,0 +1,143 @@
+//!
+//! Map backend selection
+//!
+//! A world's map is stored by whichever backend its world.mt names (sqlite3
+//! when it names none). open_world_map opens it with the matching
+//! MapDatabase, so tools work on any server's world:
+//!
+//! ```text
+//! backend = sqlite3       map.sqlite     SqliteMapDatabase    (feature "sqlite")
+//! backend = leveldb       map.db/        LevelDbMapDatabase   (feature "leveldb")
+//! backend = postgresql    pgsql_connection = ...
+//!                                        PostgresMapDatabase  (feature "postgres")
+//! ```
+//!
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use super::database::MapDatabase;
+use super::meta::WorldMt;
+
+/// Any backend's database
+pub type BoxedMapDatabase = Box<dyn MapDatabase + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapBackend {
+    Sqlite3,
+    LevelDb,
+    PostgreSql,
+    /// Nothing stored, for worlds that are thrown away
+    Dummy,
+}
+
+impl MapBackend {
+    /// The one named by world.mt
+    pub fn of_world(dir: &Path) -> Result<Self> {
+        WorldMt::read(dir)?.backend()
+    }
+
+    /// Whether this build can open it
+    pub fn is_supported(self) -> bool {
+        match self {
+            MapBackend::Sqlite3 => cfg!(feature = "sqlite"),
+            MapBackend::LevelDb => cfg!(feature = "leveldb"),
+            MapBackend::PostgreSql => cfg!(feature = "postgres"),
+            MapBackend::Dummy => false,
+        }
+    }
+}
+
+impl FromStr for MapBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Ok(match name {
+            "sqlite3" => MapBackend::Sqlite3,
+            "leveldb" => MapBackend::LevelDb,
+            "postgresql" => MapBackend::PostgreSql,
+            "dummy" => MapBackend::Dummy,
+            _ => bail!("Unknown map backend {:?}", name),
+        })
+    }
+}
+
+impl fmt::Display for MapBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MapBackend::Sqlite3 => "sqlite3",
+            MapBackend::LevelDb => "leveldb",
+            MapBackend::PostgreSql => "postgresql",
+            MapBackend::Dummy => "dummy",
+        })
+    }
+}
+
+/// Open the map of the world in `dir`, with the backend its world.mt names.
+/// Only sqlite3 can be opened read-only: the others are always writable.
+#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+pub fn open_world_map(dir: &Path, writable: bool) -> Result<BoxedMapDatabase> {
+    let backend = MapBackend::of_world(dir)?;
+    if !backend.is_supported() {
+        bail!(
+            "{:?} uses the {} map backend, which this build doesn't support",
+            dir,
+            backend
+        );
+    }
+    match backend {
+        #[cfg(feature = "sqlite")]
+        MapBackend::Sqlite3 => {
+            use super::sqlite::SqliteMapDatabase;
+
+            let path = dir.join("map.sqlite");
+            Ok(Box::new(if writable {
+                SqliteMapDatabase::open_read_write(&path)?
+            } else {
+                SqliteMapDatabase::open(&path)?
+            }))
+        }
+        #[cfg(feature = "leveldb")]
+        MapBackend::LevelDb => Ok(Box::new(super::leveldb::LevelDbMapDatabase::open_world(
+            dir,
+        )?)),
+        #[cfg(feature = "postgres")]
+        MapBackend::PostgreSql => Ok(Box::new(super::postgres::PostgresMapDatabase::open_world(
+            dir,
+        )?)),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        for backend in [
+            MapBackend::Sqlite3,
+            MapBackend::LevelDb,
+            MapBackend::PostgreSql,
+            MapBackend::Dummy,
+        ] {
+            assert_eq!(backend.to_string().parse::<MapBackend>().unwrap(), backend);
+        }
+        assert!("redis".parse::<MapBackend>().is_err());
+    }
+
+    #[test]
+    fn backend_of_world() {
+        let dir = std::env::temp_dir().join(format!("mt-backend-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(MapBackend::of_world(&dir).unwrap(), MapBackend::Sqlite3);
+        std::fs::write(dir.join("world.mt"), "backend = dummy\n").unwrap();
+        assert_eq!(MapBackend::of_world(&dir).unwrap(), MapBackend::Dummy);
+        assert!(open_world_map(&dir, false).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,852 @@
+//!
+//! Map blocks as stored on disk
+//!
+//! The disk format adds a timestamp, a name-id mapping, static objects and
+//! node timers to what is sent over the network. Node ids on disk are local
+//! to the block: the mapping gives each one's node name.
+//!
+//! Serialization versions 28 and 29 are read, and version 29 is written.
+//!
+//! ```text
+//! u8 version
+//! version 29, everything after the version zstd compressed:
+//!     u8 flags, u16 lighting_complete, u32 timestamp, name-id mapping,
+//!     u8 content_width, u8 params_width, nodes, node metadata,
+//!     static objects, node timers
+//! version 28:
+//!     u8 flags, u16 lighting_complete, u8 content_width, u8 params_width,
+//!     zlib(nodes), zlib(node metadata), static objects, u32 timestamp,
+//!     name-id mapping, node timers
+//! ```
+//!
+//! Static objects are objects (mostly LuaEntities) stored in the block
+//! while it isn't active:
+//!
+//! ```text
+//! u8 version (0), u16 count, then for each:
+//!     u8 type, 3 x s32 position (x1000), u16 length, data
+//! ```
+//!
+//! Node timers are the timers started with minetest.get_node_timer:
+//!
+//! ```text
+//! u8 length of a timer (10), u16 count, then for each:
+//!     u16 node index, s32 timeout (x1000), s32 elapsed (x1000)
+//! ```
+//!
+//! Blocks convert to and from the MapBlock sent to clients with
+//! to_map_block and from_map_block. Clients know nodes by content id, so
+//! those take the server's content ids (a NameIdMapping of every
+//! registered node, from its Nodedef):
+//!
+//! ```text
+//! let block = DiskMapBlock::decode(&db.get_block(&pos)?.unwrap())?;
+//! let blockdata = BlockdataSpec { pos, block: block.to_map_block(&content_ids)?, .. };
+//! ```
+//!
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::wire::deser::Deserialize;
+use crate::wire::deser::DeserializeError;
+use crate::wire::deser::DeserializeResult;
+use crate::wire::deser::Deserializer;
+use crate::wire::node::CONTENT_AIR;
+use crate::wire::node::CONTENT_IGNORE;
+use crate::wire::node::CONTENT_UNKNOWN;
+use crate::wire::ser::Serialize;
+use crate::wire::ser::SerializeError;
+use crate::wire::ser::SerializeResult;
+use crate::wire::ser::Serializer;
+use crate::wire::ser::VecSerializer;
+use crate::wire::types::s32;
+use crate::wire::types::v3f;
+use crate::wire::types::v3s16;
+use crate::wire::types::BinaryData16;
+use crate::wire::types::LongString;
+use crate::wire::types::MapBlock;
+use crate::wire::types::MapNode;
+use crate::wire::types::MapNodesBulk;
+use crate::wire::types::NodeMetadataList;
+use crate::wire::types::ProtocolContext;
+use crate::wire::types::NODECOUNT;
+use crate::wire::util::decompress_zlib;
+use crate::wire::util::zstd_compress;
+use crate::wire::util::zstd_decompress;
+
+/// Timestamp of a block that was never saved with one
+pub const BLOCK_TIMESTAMP_UNDEFINED: u32 = 0xffffffff;
+
+/// The length of one node timer on disk
+const NODE_TIMER_LEN: u8 = 10;
+
+/// Block-local node id to node name
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct NameIdMapping {
+    names: BTreeMap<u16, String>,
+}
+
+impl NameIdMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: u16) -> Option<&str> {
+        self.names.get(&id).map(|name| name.as_str())
+    }
+
+    pub fn insert(&mut self, id: u16, name: String) {
+        self.names.insert(id, name);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names.iter().map(|(id, name)| (*id, name.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn id_of(&self, name: &str) -> Option<u16> {
+        self.names
+            .iter()
+            .find(|(_, other)| *other == name)
+            .map(|(id, _)| *id)
+    }
+
+    /// The id of `name`, adding it after the highest id if it's missing
+    pub fn get_or_insert(&mut self, name: &str) -> Result<u16> {
+        if let Some(id) = self.id_of(name) {
+            return Ok(id);
+        }
+        let id = match self.names.last_key_value() {
+            Some((&last, _)) => match last.checked_add(1) {
+                Some(id) => id,
+                None => bail!("Name-id mapping is full"),
+            },
+            None => 0,
+        };
+        self.names.insert(id, name.to_string());
+        Ok(id)
+    }
+}
+
+impl Serialize for NameIdMapping {
+    type Input = Self;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        u8::serialize(&0, ser)?;
+        u16::serialize(&u16::try_from(value.names.len())?, ser)?;
+        for (id, name) in &value.names {
+            u16::serialize(id, ser)?;
+            String::serialize(name, ser)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deserialize for NameIdMapping {
+    type Output = Self;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
+        let ver = u8::deserialize(deser)?;
+        if ver != 0 {
+            bail!(DeserializeError::InvalidValue(format!(
+                "Invalid NameIdMapping version {}",
+                ver
+            )));
+        }
+        let count = u16::deserialize(deser)?;
+        let mut mapping = Self::new();
+        for _ in 0..count {
+            let id = u16::deserialize(deser)?;
+            let name = String::deserialize(deser)?;
+            mapping.insert(id, name);
+        }
+        Ok(mapping)
+    }
+}
+
+/// The engine's ACTIVEOBJECT_TYPE_LUAENTITY
+pub const STATIC_OBJECT_LUAENTITY: u8 = 7;
+
+/// An object stored in a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticObject {
+    /// STATIC_OBJECT_LUAENTITY, or another ActiveObjectType
+    pub type_id: u8,
+    /// In BS units (10 per node), kept to 3 decimals
+    pub pos: v3f,
+    /// Stored by the object, see lua_entity
+    pub data: Vec<u8>,
+}
+
+/// The start of a LuaEntity's data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LuaEntityData {
+    /// Entity name, e.g. "__builtin:item"
+    pub name: String,
+    /// What get_staticdata returned
+    pub staticdata: String,
+}
+
+impl StaticObject {
+    /// The entity name and staticdata of a LuaEntity. The hp, velocity and
+    /// rotation that follow are left in `data`.
+    pub fn lua_entity(&self) -> Option<LuaEntityData> {
+        if self.type_id != STATIC_OBJECT_LUAENTITY {
+            return None;
+        }
+        let context = ProtocolContext::latest_for_receive(true);
+        let mut deser = Deserializer::new(context, &self.data);
+        let version = u8::deserialize(&mut deser).ok()?;
+        if version != 1 {
+            return None;
+        }
+        Some(LuaEntityData {
+            name: String::deserialize(&mut deser).ok()?,
+            staticdata: LongString::deserialize(&mut deser).ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StaticObjectList {
+    pub objects: Vec<StaticObject>,
+}
+
+impl StaticObjectList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn f1000_to_s32(value: f32) -> s32 {
+    (value * 1000.0) as s32
+}
+
+impl Serialize for StaticObjectList {
+    type Input = Self;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        u8::serialize(&0, ser)?;
+        u16::serialize(&u16::try_from(value.objects.len())?, ser)?;
+        for object in &value.objects {
+            u8::serialize(&object.type_id, ser)?;
+            for coord in [object.pos.x, object.pos.y, object.pos.z] {
+                s32::serialize(&f1000_to_s32(coord), ser)?;
+            }
+            BinaryData16::serialize(&object.data, ser)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deserialize for StaticObjectList {
+    type Output = Self;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
+        let ver = u8::deserialize(deser)?;
+        if ver != 0 {
+            bail!(DeserializeError::InvalidValue(format!(
+                "Invalid StaticObjectList version {}",
+                ver
+            )));
+        }
+        let count = u16::deserialize(deser)?;
+        let mut objects = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let type_id = u8::deserialize(deser)?;
+            let mut pos = [0.0; 3];
+            for coord in &mut pos {
+                *coord = s32::deserialize(deser)? as f32 / 1000.0;
+            }
+            objects.push(StaticObject {
+                type_id,
+                pos: v3f::new(pos[0], pos[1], pos[2]),
+                data: BinaryData16::deserialize(deser)?,
+            });
+        }
+        Ok(Self { objects })
+    }
+}
+
+/// A timer running on a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeTimer {
+    /// Within the block, 0 to 15 on each axis
+    pub pos: v3s16,
+    /// Seconds, kept to 3 decimals
+    pub timeout: f32,
+    pub elapsed: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NodeTimerList {
+    pub timers: Vec<NodeTimer>,
+}
+
+impl NodeTimerList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, pos: &v3s16) -> Option<&NodeTimer> {
+        self.timers.iter().find(|timer| timer.pos == *pos)
+    }
+}
+
+impl Serialize for NodeTimerList {
+    type Input = Self;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        u8::serialize(&NODE_TIMER_LEN, ser)?;
+        u16::serialize(&u16::try_from(value.timers.len())?, ser)?;
+        for timer in &value.timers {
+            let p = &timer.pos;
+            if ![p.x, p.y, p.z].iter().all(|c| (0..16).contains(c)) {
+                bail!(SerializeError::InvalidValue(format!(
+                    "Node timer position ({},{},{}) is outside the block",
+                    p.x, p.y, p.z
+                )));
+            }
+            u16::serialize(&((p.z * 256 + p.y * 16 + p.x) as u16), ser)?;
+            s32::serialize(&f1000_to_s32(timer.timeout), ser)?;
+            s32::serialize(&f1000_to_s32(timer.elapsed), ser)?;
+        }
+        Ok(())
+    }
+}
+
+impl Deserialize for NodeTimerList {
+    type Output = Self;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
+        let mut result = Self::new();
+        // Blocks from before node timers end here
+        if deser.remaining() == 0 {
+            return Ok(result);
+        }
+        let len = u8::deserialize(deser)?;
+        if len != NODE_TIMER_LEN {
+            bail!(DeserializeError::InvalidValue(format!(
+                "Invalid node timer length {}",
+                len
+            )));
+        }
+        let count = u16::deserialize(deser)?;
+        for _ in 0..count {
+            let p = u16::deserialize(deser)?;
+            if p >= NODECOUNT {
+                bail!(DeserializeError::InvalidValue(format!(
+                    "Invalid node timer position {}",
+                    p
+                )));
+            }
+            let p = p as i16;
+            result.timers.push(NodeTimer {
+                pos: v3s16::new(p % 16, p / 16 % 16, p / 256),
+                timeout: s32::deserialize(deser)? as f32 / 1000.0,
+                elapsed: s32::deserialize(deser)? as f32 / 1000.0,
+            });
+        }
+        Ok(result)
+    }
+}
+
+/// A block decoded from a MapDatabase.
+///
+/// Decoding into an existing DiskMapBlock (decode_into) reuses its
+/// buffers, which matters when scanning whole worlds.
+#[derive(Debug, Clone)]
+pub struct DiskMapBlock {
+    pub version: u8,
+    pub is_underground: bool,
+    pub day_night_diff: bool,
+    pub generated: bool,
+    pub lighting_complete: Option<u16>,
+    /// Game time (seconds) the block was saved at
+    pub timestamp: u32,
+    pub name_id_mapping: NameIdMapping,
+    /// param0 of each node is an id in name_id_mapping
+    pub nodes: Box<[MapNode; NODECOUNT as usize]>,
+    pub node_metadata: NodeMetadataList,
+    pub static_objects: StaticObjectList,
+    pub node_timers: NodeTimerList,
+    scratch: Vec<u8>,
+}
+
+impl Default for DiskMapBlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiskMapBlock {
+    pub fn new() -> Self {
+        Self {
+            version: 29,
+            is_underground: false,
+            day_night_diff: false,
+            generated: true,
+            lighting_complete: None,
+            timestamp: BLOCK_TIMESTAMP_UNDEFINED,
+            name_id_mapping: NameIdMapping::new(),
+            nodes: Box::new([MapNode::default(); NODECOUNT as usize]),
+            node_metadata: NodeMetadataList {
+                metadata: Vec::new(),
+            },
+            static_objects: StaticObjectList::new(),
+            node_timers: NodeTimerList::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut block = Self::new();
+        block.decode_into(data)?;
+        Ok(block)
+    }
+
+    /// Decode `data`, replacing the contents of this block.
+    /// On error, the contents are unspecified.
+    pub fn decode_into(&mut self, data: &[u8]) -> Result<()> {
+        let mut context = ProtocolContext::latest_for_receive(true);
+        let Some(&version) = data.first() else {
+            bail!(DeserializeError::Eof);
+        };
+        context.ser_fmt = version;
+        self.version = version;
+        let data = &data[1..];
+        match version {
+            29 => {
+                let mut scratch = std::mem::take(&mut self.scratch);
+                scratch.clear();
+                let result = zstd_decompress(data, |chunk| {
+                    scratch.extend_from_slice(chunk);
+                    Ok(())
+                })
+                .and_then(|_| self.decode_v29(&mut Deserializer::new(context, &scratch)));
+                self.scratch = scratch;
+                result
+            }
+            28 => self.decode_v28(&mut Deserializer::new(context, data)),
+            _ => bail!("Unsupported map block version {}", version),
+        }
+    }
+
+    fn decode_v29(&mut self, deser: &mut Deserializer) -> Result<()> {
+        self.decode_flags(deser)?;
+        self.timestamp = u32::deserialize(deser)?;
+        self.name_id_mapping = NameIdMapping::deserialize(deser)?;
+        check_widths(deser)?;
+        MapNodesBulk::deserialize_into(deser, &mut self.nodes)?;
+        self.node_metadata = NodeMetadataList::deserialize(deser)?;
+        self.static_objects = StaticObjectList::deserialize(deser)?;
+        self.node_timers = NodeTimerList::deserialize(deser)?;
+        Ok(())
+    }
+
+    fn decode_v28(&mut self, deser: &mut Deserializer) -> Result<()> {
+        self.decode_flags(deser)?;
+        check_widths(deser)?;
+        let (consumed, nodes_raw) = decompress_zlib(deser.peek_all())?;
+        deser.take(consumed)?;
+        MapNodesBulk::deserialize_into(
+            &mut Deserializer::new(deser.context(), &nodes_raw),
+            &mut self.nodes,
+        )?;
+        let (consumed, metadata_raw) = decompress_zlib(deser.peek_all())?;
+        deser.take(consumed)?;
+        self.node_metadata =
+            NodeMetadataList::deserialize(&mut Deserializer::new(deser.context(), &metadata_raw))?;
+        self.static_objects = StaticObjectList::deserialize(deser)?;
+        self.timestamp = u32::deserialize(deser)?;
+        self.name_id_mapping = NameIdMapping::deserialize(deser)?;
+        self.node_timers = NodeTimerList::deserialize(deser)?;
+        Ok(())
+    }
+
+    fn decode_flags(&mut self, deser: &mut Deserializer) -> Result<()> {
+        let flags = u8::deserialize(deser)?;
+        self.is_underground = flags & 0x1 != 0;
+        self.day_night_diff = flags & 0x2 != 0;
+        self.generated = flags & 0x8 == 0;
+        self.lighting_complete = Some(u16::deserialize(deser)?);
+        Ok(())
+    }
+
+    /// The block in version 29, whatever version it was decoded from
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut context = ProtocolContext::latest_for_send(false);
+        context.ser_fmt = 29;
+        let mut ser = VecSerializer::new(context, 4 * NODECOUNT as usize);
+        let flags = (self.is_underground as u8)
+            | (self.day_night_diff as u8) << 1
+            | (!self.generated as u8) << 3;
+        u8::serialize(&flags, &mut ser)?;
+        u16::serialize(&self.lighting_complete.unwrap_or(0xffff), &mut ser)?;
+        u32::serialize(&self.timestamp, &mut ser)?;
+        NameIdMapping::serialize(&self.name_id_mapping, &mut ser)?;
+        u8::serialize(&2, &mut ser)?;
+        u8::serialize(&2, &mut ser)?;
+        MapNodesBulk::serialize(&MapNodesBulk { nodes: *self.nodes }, &mut ser)?;
+        NodeMetadataList::serialize(&self.node_metadata, &mut ser)?;
+        StaticObjectList::serialize(&self.static_objects, &mut ser)?;
+        NodeTimerList::serialize(&self.node_timers, &mut ser)?;
+        let raw = ser.take();
+
+        let mut data = vec![29];
+        zstd_compress(&raw, |chunk| {
+            data.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok(data)
+    }
+
+    /// Game time that passed since the block was saved, if it has a
+    /// timestamp. `game_time` is in seconds, like the timestamp.
+    pub fn unloaded_for(&self, game_time: u32) -> Option<u32> {
+        if self.timestamp == BLOCK_TIMESTAMP_UNDEFINED {
+            return None;
+        }
+        Some(game_time.saturating_sub(self.timestamp))
+    }
+
+    /// The name of the node with this block-local id
+    pub fn node_name(&self, node: &MapNode) -> Option<&str> {
+        self.name_id_mapping.get(node.param0)
+    }
+
+    /// The block as sent to clients, with the content ids in `content_ids`.
+    /// Nodes it doesn't know become CONTENT_UNKNOWN, and private metadata
+    /// is left out, like the engine does.
+    pub fn to_map_block(&self, content_ids: &NameIdMapping) -> Result<MapBlock> {
+        let mut ids = BTreeMap::new();
+        for (id, name) in self.name_id_mapping.iter() {
+            let content_id = content_ids
+                .id_of(name)
+                .or_else(|| builtin_content_id(name))
+                .unwrap_or(CONTENT_UNKNOWN);
+            ids.insert(id, content_id);
+        }
+        let mut nodes = MapNodesBulk { nodes: *self.nodes };
+        for node in nodes.nodes.iter_mut() {
+            let Some(&content_id) = ids.get(&node.param0) else {
+                bail!("Node id {} missing from the name-id mapping", node.param0);
+            };
+            node.param0 = content_id;
+        }
+        let mut node_metadata = self.node_metadata.clone();
+        for (_, metadata) in &mut node_metadata.metadata {
+            metadata.stringvars.retain(|var| !var.is_private);
+        }
+        Ok(MapBlock {
+            is_underground: self.is_underground,
+            day_night_diff: self.day_night_diff,
+            generated: self.generated,
+            lighting_complete: self.lighting_complete,
+            nodes,
+            node_metadata,
+        })
+    }
+
+    /// A block to store, from one sent to clients with the content ids in
+    /// `content_ids`. It has no timestamp, static objects or node timers.
+    pub fn from_map_block(block: &MapBlock, content_ids: &NameIdMapping) -> Result<Self> {
+        let mut disk = Self::new();
+        disk.is_underground = block.is_underground;
+        disk.day_night_diff = block.day_night_diff;
+        disk.generated = block.generated;
+        disk.lighting_complete = block.lighting_complete;
+        disk.node_metadata = block.node_metadata.clone();
+        let mut ids = BTreeMap::new();
+        for (node, disk_node) in block.nodes.nodes.iter().zip(disk.nodes.iter_mut()) {
+            let id = match ids.get(&node.param0) {
+                Some(&id) => id,
+                None => {
+                    let Some(name) = content_ids
+                        .get(node.param0)
+                        .or_else(|| builtin_content_name(node.param0))
+                    else {
+                        bail!("Unknown content id {}", node.param0);
+                    };
+                    let id = disk.name_id_mapping.get_or_insert(name)?;
+                    ids.insert(node.param0, id);
+                    id
+                }
+            };
+            *disk_node = MapNode {
+                param0: id,
+                ..*node
+            };
+        }
+        Ok(disk)
+    }
+}
+
+/// The nodes every server has, at fixed content ids
+const BUILTIN_CONTENT: [(u16, &str); 3] = [
+    (CONTENT_UNKNOWN, "unknown"),
+    (CONTENT_AIR, "air"),
+    (CONTENT_IGNORE, "ignore"),
+];
+
+fn builtin_content_id(name: &str) -> Option<u16> {
+    BUILTIN_CONTENT
+        .iter()
+        .find(|(_, builtin)| *builtin == name)
+        .map(|(id, _)| *id)
+}
+
+fn builtin_content_name(id: u16) -> Option<&'static str> {
+    BUILTIN_CONTENT
+        .iter()
+        .find(|(builtin, _)| *builtin == id)
+        .map(|(_, name)| *name)
+}
+
+fn check_widths(deser: &mut Deserializer) -> Result<()> {
+    let content_width = u8::deserialize(deser)?;
+    let params_width = u8::deserialize(deser)?;
+    if content_width != 2 || params_width != 2 {
+        bail!(DeserializeError::InvalidValue(
+            "Corrupted MapBlock: Invalid widths".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::wire::util::compress_zlib;
+    use crate::wire::util::ZLIB_DEFAULT_LEVEL;
+
+    fn ser<T: Serialize<Input = T>>(ser: &mut VecSerializer, value: &T) {
+        T::serialize(value, ser).unwrap();
+    }
+
+    fn body(names: &[&str], nodes: &MapNodesBulk, version: u8) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut context = ProtocolContext::latest_for_send(false);
+        context.ser_fmt = version;
+        let mut mapping = VecSerializer::new(context, 64);
+        ser(&mut mapping, &0u8);
+        ser(&mut mapping, &(names.len() as u16));
+        for (id, name) in names.iter().enumerate() {
+            ser(&mut mapping, &(id as u16));
+            ser(&mut mapping, &name.to_string());
+        }
+        let mut node_data = VecSerializer::new(context, 4 * NODECOUNT as usize);
+        ser(&mut node_data, nodes);
+        let mut metadata = VecSerializer::new(context, 1);
+        ser(
+            &mut metadata,
+            &NodeMetadataList {
+                metadata: Vec::new(),
+            },
+        );
+        (mapping.take(), node_data.take(), metadata.take())
+    }
+
+    /// A version 29 disk block: not underground, generated, with `nodes`
+    /// naming ids with `names`, saved at `timestamp`, one static object.
+    pub(crate) fn encode_v29(names: &[&str], nodes: &MapNodesBulk, timestamp: u32) -> Vec<u8> {
+        let (mapping, node_data, metadata) = body(names, nodes, 29);
+        let mut raw = vec![0u8, 0xff, 0xff];
+        raw.extend_from_slice(&timestamp.to_be_bytes());
+        raw.extend(mapping);
+        raw.extend([2, 2]);
+        raw.extend(node_data);
+        raw.extend(metadata);
+        // Static objects, node timers
+        raw.extend([
+            0, 0, 1, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, b'h', b'i',
+        ]);
+        raw.extend([10, 0, 0]);
+        let mut data = vec![29];
+        zstd_compress(&raw, |chunk| {
+            data.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        data
+    }
+
+    fn encode_v28(names: &[&str], nodes: &MapNodesBulk, timestamp: u32) -> Vec<u8> {
+        let (mapping, node_data, metadata) = body(names, nodes, 28);
+        let mut data = vec![28u8, 0x1, 0xff, 0xff, 2, 2];
+        data.extend(compress_zlib(&node_data, ZLIB_DEFAULT_LEVEL));
+        data.extend(compress_zlib(&metadata, ZLIB_DEFAULT_LEVEL));
+        data.extend([
+            0, 0, 1, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, b'h', b'i',
+        ]);
+        data.extend(timestamp.to_be_bytes());
+        data.extend(mapping);
+        data.extend([10, 0, 0]);
+        data
+    }
+
+    pub(crate) fn sample_nodes() -> MapNodesBulk {
+        let mut nodes = MapNodesBulk::empty();
+        for (i, node) in nodes.nodes.iter_mut().enumerate() {
+            node.param0 = (i % 3 == 0) as u16;
+            node.param2 = (i % 7) as u8;
+        }
+        nodes
+    }
+
+    #[test]
+    fn decode_versions() {
+        let names = ["air", "default:stone"];
+        let nodes = sample_nodes();
+        let mut block = DiskMapBlock::new();
+        for data in [
+            encode_v29(&names, &nodes, 1234),
+            encode_v28(&names, &nodes, 1234),
+        ] {
+            block.decode_into(&data).unwrap();
+            assert_eq!(block.timestamp, 1234);
+            assert_eq!(block.unloaded_for(1300), Some(66));
+            assert_eq!(block.lighting_complete, Some(0xffff));
+            assert!(block.generated);
+            assert_eq!(block.is_underground, block.version == 28);
+            assert_eq!(*block.nodes, nodes.nodes);
+            assert_eq!(block.node_name(&block.nodes[0]), Some("default:stone"));
+            assert_eq!(block.node_name(&block.nodes[1]), Some("air"));
+            assert_eq!(block.name_id_mapping.len(), 2);
+            let object = &block.static_objects.objects[0];
+            assert_eq!((object.type_id, &object.data[..]), (7, &b"hi"[..]));
+        }
+        assert_eq!(block.version, 28);
+        assert_eq!(DiskMapBlock::new().unloaded_for(1300), None);
+
+        let mut unknown = encode_v29(&names, &nodes, 0);
+        unknown[0] = 30;
+        assert!(DiskMapBlock::decode(&unknown).is_err());
+        assert!(DiskMapBlock::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let names = ["air", "default:stone"];
+        let nodes = sample_nodes();
+        for data in [
+            encode_v29(&names, &nodes, 1234),
+            encode_v28(&names, &nodes, 1234),
+        ] {
+            let mut block = DiskMapBlock::decode(&data).unwrap();
+            let id = block.name_id_mapping.get_or_insert("default:dirt").unwrap();
+            assert_eq!(id, 2);
+            assert_eq!(block.name_id_mapping.get_or_insert("air").unwrap(), 0);
+            block.nodes[5].param0 = id;
+            let decoded = DiskMapBlock::decode(&block.encode().unwrap()).unwrap();
+            assert_eq!(decoded.version, 29);
+            assert_eq!(decoded.is_underground, block.is_underground);
+            assert_eq!(decoded.timestamp, 1234);
+            assert_eq!(*decoded.nodes, *block.nodes);
+            assert_eq!(decoded.node_name(&decoded.nodes[5]), Some("default:dirt"));
+            assert_eq!(decoded.static_objects, block.static_objects);
+            assert!(decoded.node_timers.timers.is_empty());
+        }
+    }
+
+    #[test]
+    fn network_blocks() {
+        let names = ["air", "default:stone"];
+        let mut block = DiskMapBlock::decode(&encode_v29(&names, &sample_nodes(), 0)).unwrap();
+        block.nodes[7].param0 = block.name_id_mapping.get_or_insert("mod:gone").unwrap();
+        let mut content_ids = NameIdMapping::new();
+        content_ids.insert(0, "default:stone".to_string());
+
+        let map_block = block.to_map_block(&content_ids).unwrap();
+        assert_eq!(map_block.nodes.nodes[0].param0, 0);
+        assert_eq!(map_block.nodes.nodes[1].param0, CONTENT_AIR);
+        assert_eq!(map_block.nodes.nodes[7].param0, CONTENT_UNKNOWN);
+        assert_eq!(map_block.nodes.nodes[5].param2, 5);
+
+        let disk = DiskMapBlock::from_map_block(&map_block, &content_ids).unwrap();
+        assert_eq!(disk.node_name(&disk.nodes[0]), Some("default:stone"));
+        assert_eq!(disk.node_name(&disk.nodes[1]), Some("air"));
+        assert_eq!(disk.node_name(&disk.nodes[7]), Some("unknown"));
+        assert_eq!(disk.nodes[5].param2, 5);
+        assert_eq!(disk.name_id_mapping.len(), 3);
+
+        let mut bad = map_block.clone();
+        bad.nodes.nodes[0].param0 = 9;
+        assert!(DiskMapBlock::from_map_block(&bad, &content_ids).is_err());
+    }
+
+    #[test]
+    fn static_objects() {
+        let mut data = vec![1u8];
+        data.extend(b"\x00\x0e__builtin:item");
+        data.extend(b"\x00\x00\x00\x05stack");
+        data.extend([0, 1, 0, 0, 0, 0]);
+        let list = StaticObjectList {
+            objects: vec![StaticObject {
+                type_id: STATIC_OBJECT_LUAENTITY,
+                pos: v3f::new(12.5, -3.25, 0.5),
+                data,
+            }],
+        };
+        let mut out = VecSerializer::new(ProtocolContext::latest_for_send(false), 64);
+        ser(&mut out, &list);
+        let bytes = out.take();
+        assert_eq!(&bytes[..7], &[0, 0, 1, 7, 0, 0, 0x30]);
+        let context = ProtocolContext::latest_for_receive(true);
+        let decoded =
+            StaticObjectList::deserialize(&mut Deserializer::new(context, &bytes)).unwrap();
+        assert_eq!(decoded, list);
+        assert_eq!(
+            decoded.objects[0].lua_entity(),
+            Some(LuaEntityData {
+                name: "__builtin:item".to_string(),
+                staticdata: "stack".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn node_timers() {
+        let mut block = DiskMapBlock::new();
+        block.node_timers.timers.push(NodeTimer {
+            pos: v3s16::new(15, 2, 7),
+            timeout: 5.5,
+            elapsed: 1.25,
+        });
+        let mut out = VecSerializer::new(ProtocolContext::latest_for_send(false), 16);
+        ser(&mut out, &block.node_timers);
+        // Node index 7 * 256 + 2 * 16 + 15
+        assert_eq!(
+            out.take(),
+            [10, 0, 1, 0x07, 0x2f, 0, 0, 0x15, 0x7c, 0, 0, 0x04, 0xe2]
+        );
+
+        let decoded = DiskMapBlock::decode(&block.encode().unwrap()).unwrap();
+        assert_eq!(decoded.node_timers, block.node_timers);
+        let timer = decoded.node_timers.get(&v3s16::new(15, 2, 7)).unwrap();
+        assert_eq!(timer.timeout, 5.5);
+
+        let context = ProtocolContext::latest_for_receive(true);
+        let bad = [11, 0, 0];
+        assert!(NodeTimerList::deserialize(&mut Deserializer::new(context, &bad)).is_err());
+        // Would be negative as an i16
+        let bad = [10, 0, 1, 0x80, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(NodeTimerList::deserialize(&mut Deserializer::new(context, &bad)).is_err());
+
+        for pos in [
+            v3s16::new(16, 0, 0),
+            v3s16::new(0, -1, 0),
+            v3s16::new(0, 0, 200),
+        ] {
+            let mut block = DiskMapBlock::new();
+            block.node_timers.timers.push(NodeTimer {
+                pos,
+                timeout: 1.0,
+                elapsed: 0.0,
+            });
+            assert!(block.encode().is_err());
+        }
+    }
+}
@@ -0,0 +1,356 @@
+//!
+//! BlockCache
+//!
+//! An in-memory cache in front of a MapDatabase. It is a MapDatabase too,
+//! so a World or a server can use it in place of the database:
+//!
+//! ```text
+//! let mut cache = BlockCache::new(SqliteMapDatabase::open_read_write(path)?, 4096);
+//! let block = cache.get_block(&pos)?;   // from SQLite
+//! let block = cache.get_block(&pos)?;   // from memory
+//! cache.set_block(&pos, &data)?;        // in memory only, until flushed
+//! cache.flush()?;
+//! ```
+//!
+//! Blocks not stored are cached too, so asking again for a missing block
+//! doesn't reach the database.
+//!
+//! The least recently used blocks are evicted beyond `capacity`. Written
+//! (dirty) blocks are written back when evicted, which can only happen on
+//! a write: reads only evict clean blocks. Dirty blocks still in the cache
+//! when it is dropped are lost, so call `flush` (or `into_database`).
+//!
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use crate::wire::types::v3s16;
+
+use super::database::MapDatabase;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Blocks written (or deleted) in the database
+    pub write_backs: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// None if there is no block
+    data: Option<Vec<u8>>,
+    dirty: bool,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<v3s16, Entry>,
+    /// last_used -> position, oldest first
+    lru: BTreeMap<u64, v3s16>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn touch(&mut self, pos: &v3s16) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(pos) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.lru.insert(tick, pos.clone());
+        }
+    }
+
+    fn insert(&mut self, pos: &v3s16, data: Option<Vec<u8>>, dirty: bool) {
+        if let Some(old) = self.entries.remove(pos) {
+            self.lru.remove(&old.last_used);
+        }
+        self.tick += 1;
+        let entry = Entry {
+            data,
+            dirty,
+            last_used: self.tick,
+        };
+        self.lru.insert(self.tick, pos.clone());
+        self.entries.insert(pos.clone(), entry);
+    }
+
+    fn remove(&mut self, pos: &v3s16) -> Option<Entry> {
+        let entry = self.entries.remove(pos)?;
+        self.lru.remove(&entry.last_used);
+        Some(entry)
+    }
+
+    /// Evicts clean blocks, oldest first, down to `capacity`
+    fn evict_clean(&mut self, capacity: usize) {
+        if self.entries.len() <= capacity {
+            return;
+        }
+        let excess = self.entries.len() - capacity;
+        let clean: Vec<v3s16> = self
+            .lru
+            .values()
+            .filter(|pos| !self.entries[*pos].dirty)
+            .take(excess)
+            .cloned()
+            .collect();
+        for pos in clean {
+            self.remove(&pos);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+pub struct BlockCache<D: MapDatabase> {
+    db: D,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl<D: MapDatabase> BlockCache<D> {
+    /// Caches up to `capacity` blocks (more while they are dirty)
+    pub fn new(db: D, capacity: usize) -> Self {
+        Self {
+            db,
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Blocks (or known missing blocks) in the cache
+    pub fn len(&self) -> usize {
+        self.state().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state().stats
+    }
+
+    pub fn is_dirty(&self, pos: &v3s16) -> bool {
+        self.state()
+            .entries
+            .get(pos)
+            .is_some_and(|entry| entry.dirty)
+    }
+
+    /// Positions written but not yet flushed
+    pub fn dirty_blocks(&self) -> Vec<v3s16> {
+        let state = self.state();
+        state
+            .lru
+            .values()
+            .filter(|pos| state.entries[*pos].dirty)
+            .cloned()
+            .collect()
+    }
+
+    /// The database underneath. It doesn't have the dirty blocks.
+    pub fn database(&self) -> &D {
+        &self.db
+    }
+
+    /// Writes every dirty block to the database. Returns how many.
+    pub fn flush(&mut self) -> Result<usize> {
+        let dirty = self.dirty_blocks();
+        for pos in &dirty {
+            self.write_back(pos)?;
+        }
+        Ok(dirty.len())
+    }
+
+    /// Flushes, and returns the database
+    pub fn into_database(mut self) -> Result<D> {
+        self.flush()?;
+        Ok(self.db)
+    }
+
+    /// Drops every clean block from the cache, e.g. after the database was
+    /// changed by someone else
+    pub fn invalidate(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        let clean: Vec<v3s16> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.dirty)
+            .map(|(pos, _)| pos.clone())
+            .collect();
+        for pos in clean {
+            state.remove(&pos);
+        }
+    }
+
+    /// Writes back the block if it is dirty, and drops it from the cache.
+    /// Returns whether it was cached.
+    pub fn unload(&mut self, pos: &v3s16) -> Result<bool> {
+        self.write_back(pos)?;
+        Ok(self.state.get_mut().unwrap().remove(pos).is_some())
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap()
+    }
+
+    fn write_back(&mut self, pos: &v3s16) -> Result<()> {
+        let state = self.state.get_mut().unwrap();
+        let Some(entry) = state.entries.get_mut(pos) else {
+            return Ok(());
+        };
+        if !entry.dirty {
+            return Ok(());
+        }
+        match &entry.data {
+            Some(data) => self.db.set_block(pos, data)?,
+            None => self.db.delete_block(pos)?,
+        }
+        entry.dirty = false;
+        state.stats.write_backs += 1;
+        Ok(())
+    }
+
+    /// Makes room after a write, writing back the oldest dirty blocks if
+    /// evicting clean ones isn't enough
+    fn make_room(&mut self) -> Result<()> {
+        self.state.get_mut().unwrap().evict_clean(self.capacity);
+        loop {
+            let state = self.state.get_mut().unwrap();
+            if state.entries.len() <= self.capacity {
+                return Ok(());
+            }
+            let Some((_, oldest)) = state.lru.first_key_value() else {
+                return Ok(());
+            };
+            let oldest = oldest.clone();
+            self.write_back(&oldest)?;
+            let state = self.state.get_mut().unwrap();
+            state.remove(&oldest);
+            state.stats.evictions += 1;
+        }
+    }
+}
+
+impl<D: MapDatabase> MapDatabase for BlockCache<D> {
+    /// The database's blocks, with the unflushed writes applied
+    fn list_blocks(&self) -> Result<Vec<v3s16>> {
+        let mut blocks: BTreeSet<(i16, i16, i16)> = self
+            .db
+            .list_blocks()?
+            .into_iter()
+            .map(|pos| (pos.x, pos.y, pos.z))
+            .collect();
+        let state = self.state();
+        for (pos, entry) in state.entries.iter().filter(|(_, entry)| entry.dirty) {
+            let key = (pos.x, pos.y, pos.z);
+            if entry.data.is_some() {
+                blocks.insert(key);
+            } else {
+                blocks.remove(&key);
+            }
+        }
+        Ok(blocks
+            .into_iter()
+            .map(|(x, y, z)| v3s16::new(x, y, z))
+            .collect())
+    }
+
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>> {
+        {
+            let mut state = self.state();
+            if let Some(entry) = state.entries.get(pos) {
+                let data = entry.data.clone();
+                state.stats.hits += 1;
+                state.touch(pos);
+                return Ok(data);
+            }
+            state.stats.misses += 1;
+        }
+        // Not holding the lock while the database works
+        let data = self.db.get_block(pos)?;
+        let mut state = self.state();
+        // Another thread may have loaded it meanwhile
+        if !state.entries.contains_key(pos) {
+            state.insert(pos, data.clone(), false);
+            state.evict_clean(self.capacity);
+        }
+        Ok(data)
+    }
+
+    fn set_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()> {
+        self.state
+            .get_mut()
+            .unwrap()
+            .insert(pos, Some(data.to_vec()), true);
+        self.make_room()
+    }
+
+    fn delete_block(&mut self, pos: &v3s16) -> Result<()> {
+        self.state.get_mut().unwrap().insert(pos, None, true);
+        self.make_room()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::MemoryMapDatabase;
+
+    fn pos(x: i16) -> v3s16 {
+        v3s16::new(x, 0, 0)
+    }
+
+    #[test]
+    fn lru_and_write_back() {
+        let mut db = MemoryMapDatabase::new();
+        for x in 0..4 {
+            db.insert(&pos(x), vec![x as u8]);
+        }
+        let mut cache = BlockCache::new(db, 2);
+        assert_eq!(cache.get_block(&pos(0)).unwrap(), Some(vec![0]));
+        assert_eq!(cache.get_block(&pos(1)).unwrap(), Some(vec![1]));
+        assert_eq!(cache.get_block(&pos(0)).unwrap(), Some(vec![0]));
+        // Evicts 1, the least recently used
+        assert_eq!(cache.get_block(&pos(9)).unwrap(), None);
+        assert_eq!(cache.get_block(&pos(9)).unwrap(), None);
+        assert_eq!(cache.get_block(&pos(0)).unwrap(), Some(vec![0]));
+        assert_eq!(cache.get_block(&pos(1)).unwrap(), Some(vec![1]));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 4));
+        assert_eq!(stats.evictions, 2);
+
+        // Writes stay in memory
+        cache.set_block(&pos(5), &[5]).unwrap();
+        cache.delete_block(&pos(2)).unwrap();
+        assert_eq!(cache.database().len(), 4);
+        assert!(cache.is_dirty(&pos(5)));
+        assert_eq!(cache.get_block(&pos(2)).unwrap(), None);
+        let listed = cache.list_blocks().unwrap();
+        assert_eq!(listed, vec![pos(0), pos(1), pos(3), pos(5)]);
+
+        // A third dirty block forces the oldest one out
+        cache.set_block(&pos(6), &[6]).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.database().get_block(&pos(5)).unwrap(), Some(vec![5]));
+        assert_eq!(cache.dirty_blocks(), vec![pos(2), pos(6)]);
+
+        assert_eq!(cache.flush().unwrap(), 2);
+        assert!(cache.dirty_blocks().is_empty());
+        let db = cache.into_database().unwrap();
+        assert_eq!(db.get_block(&pos(2)).unwrap(), None);
+        assert_eq!(db.get_block(&pos(6)).unwrap(), Some(vec![6]));
+        assert_eq!(db.len(), 5);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::wire::types::v3s16;
+
+/// Where a world's map blocks are stored. Blocks are opaque blobs in the
+/// disk format, keyed by block position (node position / 16).
+pub trait MapDatabase {
+    /// Positions of every stored block, in no particular order
+    fn list_blocks(&self) -> Result<Vec<v3s16>>;
+
+    /// The stored block, or None if there isn't one
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>>;
+
+    /// Store a block, replacing any already there
+    fn set_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()>;
+
+    /// Remove a block. Removing one that isn't there is not an error.
+    fn delete_block(&mut self, pos: &v3s16) -> Result<()>;
+}
+
+impl<D: MapDatabase + ?Sized> MapDatabase for Box<D> {
+    fn list_blocks(&self) -> Result<Vec<v3s16>> {
+        (**self).list_blocks()
+    }
+
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>> {
+        (**self).get_block(pos)
+    }
+
+    fn set_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()> {
+        (**self).set_block(pos, data)
+    }
+
+    fn delete_block(&mut self, pos: &v3s16) -> Result<()> {
+        (**self).delete_block(pos)
+    }
+}
+
+/// The integer key the engine's databases use for a block position
+pub fn block_key(pos: &v3s16) -> i64 {
+    (pos.z as i64) * 0x1000000 + (pos.y as i64) * 0x1000 + (pos.x as i64)
+}
+
+/// Inverse of block_key
+pub fn block_pos(key: i64) -> v3s16 {
+    fn component(i: i64) -> i16 {
+        let value = i.rem_euclid(4096);
+        if value < 2048 {
+            value as i16
+        } else {
+            (value - 4096) as i16
+        }
+    }
+    let x = component(key);
+    let key = (key - x as i64) / 4096;
+    let y = component(key);
+    let key = (key - y as i64) / 4096;
+    let z = component(key);
+    v3s16::new(x, y, z)
+}
+
+/// A map held in memory, for tests and for worlds built on the fly
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMapDatabase {
+    blocks: BTreeMap<i64, Vec<u8>>,
+}
+
+impl MemoryMapDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, pos: &v3s16, data: Vec<u8>) {
+        self.blocks.insert(block_key(pos), data);
+    }
+
+    pub fn remove(&mut self, pos: &v3s16) -> Option<Vec<u8>> {
+        self.blocks.remove(&block_key(pos))
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+impl MapDatabase for MemoryMapDatabase {
+    fn list_blocks(&self) -> Result<Vec<v3s16>> {
+        Ok(self.blocks.keys().map(|key| block_pos(*key)).collect())
+    }
+
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>> {
+        Ok(self.blocks.get(&block_key(pos)).cloned())
+    }
+
+    fn set_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()> {
+        self.insert(pos, data.to_vec());
+        Ok(())
+    }
+
+    fn delete_block(&mut self, pos: &v3s16) -> Result<()> {
+        self.remove(pos);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_keys() {
+        for pos in [
+            v3s16::new(0, 0, 0),
+            v3s16::new(-1, -1, -1),
+            v3s16::new(2047, -2048, 5),
+            v3s16::new(-2048, 2047, -300),
+        ] {
+            assert_eq!(block_pos(block_key(&pos)), pos);
+        }
+        assert_eq!(block_key(&v3s16::new(1, 2, 3)), 0x3002001);
+        assert_eq!(block_key(&v3s16::new(-1, 0, 0)), -1);
+    }
+}
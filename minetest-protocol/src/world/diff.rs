@@ -0,0 +1,333 @@
+//!
+//! World diffs
+//!
+//! The blocks added, changed and removed between two snapshots of a map,
+//! which can be saved as a patch and applied to another copy. For
+//! incremental backups, keep a full archive and a patch per day. To roll
+//! a region back, diff the live map against an old snapshot within that
+//! region, and apply the result to the live map.
+//!
+//! Changes carry a hash of the block they replace, so a patch only applies
+//! to the map it was made against (unless forced).
+//!
+//! ```text
+//! "MTPATCH\0" u16 version, u32 length, zstd(changes), sha1(changes)
+//! changes: u32 count, count x (u8 kind, s16 x, y, z, ...)
+//!     kind 1 (added):   u32 length, data
+//!     kind 2 (changed): old sha1, u32 length, data
+//!     kind 3 (removed): old sha1
+//! ```
+//!
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::bail;
+use anyhow::Result;
+use rayon::prelude::*;
+use sha1_smol::Sha1;
+
+use crate::wire::types::v3s16;
+use crate::wire::types::MAP_BLOCKSIZE;
+use crate::wire::util::zstd_compress;
+use crate::wire::util::zstd_decompress;
+
+use super::database::block_key;
+use super::database::block_pos;
+use super::database::MapDatabase;
+
+pub const PATCH_MAGIC: &[u8; 8] = b"MTPATCH\0";
+pub const PATCH_VERSION: u16 = 1;
+
+const KIND_ADDED: u8 = 1;
+const KIND_CHANGED: u8 = 2;
+const KIND_REMOVED: u8 = 3;
+
+pub type BlockHash = [u8; 20];
+
+pub fn block_hash(data: &[u8]) -> BlockHash {
+    Sha1::from(data).digest().bytes()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockChange {
+    Added { data: Vec<u8> },
+    Changed { old: BlockHash, data: Vec<u8> },
+    Removed { old: BlockHash },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DiffCounts {
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WorldDiff {
+    /// In block_key order
+    pub changes: Vec<(v3s16, BlockChange)>,
+}
+
+impl WorldDiff {
+    /// What changed from `old` to `new`, for blocks in the box between
+    /// `area.0` and `area.1` (node coordinates, inclusive), or everywhere.
+    pub fn compute<A, B>(old: &A, new: &B, area: Option<(v3s16, v3s16)>) -> Result<Self>
+    where
+        A: MapDatabase + Sync + ?Sized,
+        B: MapDatabase + Sync + ?Sized,
+    {
+        let size = MAP_BLOCKSIZE as i16;
+        let area = area.map(|(a, b)| {
+            let lo = v3s16::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+            let hi = v3s16::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+            (
+                [lo.x, lo.y, lo.z].map(|v| v.div_euclid(size)),
+                [hi.x, hi.y, hi.z].map(|v| v.div_euclid(size)),
+            )
+        });
+        let inside = |pos: &v3s16| match &area {
+            Some((lo, hi)) => [pos.x, pos.y, pos.z]
+                .iter()
+                .enumerate()
+                .all(|(i, v)| (lo[i]..=hi[i]).contains(v)),
+            None => true,
+        };
+        let mut keys = BTreeSet::new();
+        for pos in old.list_blocks()?.into_iter().chain(new.list_blocks()?) {
+            if inside(&pos) {
+                keys.insert(block_key(&pos));
+            }
+        }
+        let keys: Vec<i64> = keys.into_iter().collect();
+        let changes: Vec<Option<(v3s16, BlockChange)>> = keys
+            .into_par_iter()
+            .map(|key| {
+                let pos = block_pos(key);
+                let change = match (old.get_block(&pos)?, new.get_block(&pos)?) {
+                    (None, Some(data)) => BlockChange::Added { data },
+                    (Some(before), Some(data)) if before != data => BlockChange::Changed {
+                        old: block_hash(&before),
+                        data,
+                    },
+                    (Some(before), None) => BlockChange::Removed {
+                        old: block_hash(&before),
+                    },
+                    _ => return Ok(None),
+                };
+                Ok(Some((pos, change)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            changes: changes.into_iter().flatten().collect(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn counts(&self) -> DiffCounts {
+        let mut counts = DiffCounts::default();
+        for (_, change) in &self.changes {
+            match change {
+                BlockChange::Added { .. } => counts.added += 1,
+                BlockChange::Changed { .. } => counts.changed += 1,
+                BlockChange::Removed { .. } => counts.removed += 1,
+            }
+        }
+        counts
+    }
+
+    /// Apply the changes to `db`. Unless `force`, every block is checked
+    /// first: each must be as it was in the old snapshot, or nothing is
+    /// written.
+    pub fn apply<D: MapDatabase + ?Sized>(&self, db: &mut D, force: bool) -> Result<()> {
+        if !force {
+            for (pos, change) in &self.changes {
+                let current = db.get_block(pos)?.map(|data| block_hash(&data));
+                let expected = match change {
+                    BlockChange::Added { .. } => None,
+                    BlockChange::Changed { old, .. } | BlockChange::Removed { old } => Some(*old),
+                };
+                if current != expected {
+                    bail!(
+                        "Block {:?} differs from the one the patch was made against",
+                        pos
+                    );
+                }
+            }
+        }
+        for (pos, change) in &self.changes {
+            match change {
+                BlockChange::Added { data } | BlockChange::Changed { data, .. } => {
+                    db.set_block(pos, data)?
+                }
+                BlockChange::Removed { .. } => db.delete_block(pos)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Save as a patch
+    pub fn write<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(self.changes.len() as u32).to_be_bytes());
+        for (pos, change) in &self.changes {
+            let (kind, old, data) = match change {
+                BlockChange::Added { data } => (KIND_ADDED, None, Some(data)),
+                BlockChange::Changed { old, data } => (KIND_CHANGED, Some(old), Some(data)),
+                BlockChange::Removed { old } => (KIND_REMOVED, Some(old), None),
+            };
+            payload.push(kind);
+            for v in [pos.x, pos.y, pos.z] {
+                payload.extend_from_slice(&v.to_be_bytes());
+            }
+            if let Some(old) = old {
+                payload.extend_from_slice(old);
+            }
+            if let Some(data) = data {
+                let Ok(len) = u32::try_from(data.len()) else {
+                    bail!("Block {:?} too large", pos);
+                };
+                payload.extend_from_slice(&len.to_be_bytes());
+                payload.extend_from_slice(data);
+            }
+        }
+        let mut compressed = Vec::new();
+        zstd_compress(&payload, |chunk| {
+            compressed.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        let Ok(len) = u32::try_from(compressed.len()) else {
+            bail!("Patch too large");
+        };
+        out.write_all(PATCH_MAGIC)?;
+        out.write_all(&PATCH_VERSION.to_be_bytes())?;
+        out.write_all(&len.to_be_bytes())?;
+        out.write_all(&compressed)?;
+        out.write_all(&block_hash(&payload))?;
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut input: R) -> Result<Self> {
+        let mut header = [0u8; 14];
+        input.read_exact(&mut header)?;
+        if &header[..8] != PATCH_MAGIC {
+            bail!("Not a world patch");
+        }
+        let version = u16::from_be_bytes([header[8], header[9]]);
+        if version != PATCH_VERSION {
+            bail!("Unsupported world patch version {}", version);
+        }
+        let len = u32::from_be_bytes([header[10], header[11], header[12], header[13]]);
+        let mut compressed = vec![0u8; len as usize];
+        input.read_exact(&mut compressed)?;
+        let mut hash = [0u8; 20];
+        input.read_exact(&mut hash)?;
+        let mut payload = Vec::new();
+        let decompressed = zstd_decompress(&compressed, |chunk| {
+            payload.extend_from_slice(chunk);
+            Ok(())
+        });
+        if decompressed.is_err() || block_hash(&payload) != hash {
+            bail!("World patch is corrupt");
+        }
+        parse_changes(&payload)
+    }
+}
+
+fn parse_changes(payload: &[u8]) -> Result<WorldDiff> {
+    fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+        if rest.len() < n {
+            bail!("World patch is corrupt");
+        }
+        let (head, tail) = rest.split_at(n);
+        *rest = tail;
+        Ok(head)
+    }
+    let mut rest = payload;
+    let count = u32::from_be_bytes(take(&mut rest, 4)?.try_into()?);
+    let mut changes = Vec::new();
+    for _ in 0..count {
+        let header = take(&mut rest, 7)?;
+        let v = |i: usize| i16::from_be_bytes([header[i], header[i + 1]]);
+        let pos = v3s16::new(v(1), v(3), v(5));
+        let kind = header[0];
+        let old = match kind {
+            KIND_CHANGED | KIND_REMOVED => Some(BlockHash::try_from(take(&mut rest, 20)?)?),
+            _ => None,
+        };
+        let data = match kind {
+            KIND_ADDED | KIND_CHANGED => {
+                let len = u32::from_be_bytes(take(&mut rest, 4)?.try_into()?);
+                Some(take(&mut rest, len as usize)?.to_vec())
+            }
+            _ => None,
+        };
+        let change = match (kind, old, data) {
+            (KIND_ADDED, None, Some(data)) => BlockChange::Added { data },
+            (KIND_CHANGED, Some(old), Some(data)) => BlockChange::Changed { old, data },
+            (KIND_REMOVED, Some(old), None) => BlockChange::Removed { old },
+            _ => bail!("Unknown world patch change kind {}", kind),
+        };
+        changes.push((pos, change));
+    }
+    Ok(WorldDiff { changes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::database::MemoryMapDatabase;
+
+    #[test]
+    fn diff_and_patch() {
+        let mut old = MemoryMapDatabase::new();
+        old.insert(&v3s16::new(0, 0, 0), vec![29, 1]);
+        old.insert(&v3s16::new(1, 0, 0), vec![29, 2]);
+        old.insert(&v3s16::new(5, 0, 0), vec![29, 3]);
+        let mut new = old.clone();
+        new.insert(&v3s16::new(1, 0, 0), vec![29, 20]);
+        new.remove(&v3s16::new(5, 0, 0));
+        new.insert(&v3s16::new(-1, 2, 0), vec![29, 4]);
+
+        let diff = WorldDiff::compute(&old, &new, None).unwrap();
+        assert_eq!(
+            diff.counts(),
+            DiffCounts {
+                added: 1,
+                changed: 1,
+                removed: 1,
+            }
+        );
+        let mut data = Vec::new();
+        diff.write(&mut data).unwrap();
+        let diff = WorldDiff::read(&data[..]).unwrap();
+
+        let mut patched = old.clone();
+        diff.apply(&mut patched, false).unwrap();
+        assert_eq!(
+            WorldDiff::compute(&patched, &new, None).unwrap(),
+            WorldDiff::default()
+        );
+        // Applied already: the blocks no longer match
+        assert!(diff.apply(&mut patched, false).is_err());
+        diff.apply(&mut patched, true).unwrap();
+
+        // Only blocks in x = 0..=31 (nodes)
+        let region = WorldDiff::compute(
+            &new,
+            &old,
+            Some((v3s16::new(0, 0, 0), v3s16::new(31, 100, 15))),
+        )
+        .unwrap();
+        assert_eq!(region.changes.len(), 1);
+        assert_eq!(region.changes[0].0, v3s16::new(1, 0, 0));
+
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert!(WorldDiff::read(&data[..]).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+//!
+//! Emerge queue
+//!
+//! Like the engine's emerge threads: blocks a client needs that don't exist
+//! yet are queued, and a pool of worker threads generates them, nearest to
+//! the requesting player first.
+//!
+//! A server that finds a block missing (e.g. when filling a MapSender)
+//! requests it here, and pushes it to the MapSender of every client that
+//! asked once it has emerged:
+//!
+//! ```text
+//! let emerge = EmergeQueue::new(generator, EmergeConfig::default());
+//! if !emerge.request(client, &pos, &player_block) {
+//!     // The queue is full, ask again later
+//! }
+//! ...
+//! let emerged = emerge.next_emerged().await;
+//! for client in emerged.clients { ... }
+//! ```
+//!
+//! A block is only generated once, however many clients ask for it. The
+//! queue is limited per client and in total, so one client running ahead
+//! can't starve the others.
+//!
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::wire::types::v3s16;
+
+/// Clients are known by address, like everywhere else in the server
+pub type ClientId = SocketAddr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmergeConfig {
+    pub workers: usize,
+    /// Blocks queued (not yet being generated) for one client
+    pub max_queued_per_client: usize,
+    /// Blocks queued for everyone
+    pub max_queued: usize,
+}
+
+impl Default for EmergeConfig {
+    /// The engine's defaults for emergequeue_limit_generate and
+    /// emergequeue_limit_total
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            max_queued_per_client: 128,
+            max_queued: 1024,
+        }
+    }
+}
+
+/// Makes new blocks. Called on the worker threads.
+pub trait Generator: Send + Sync + 'static {
+    type Block: Send + 'static;
+
+    fn generate(&self, pos: &v3s16) -> Result<Self::Block>;
+}
+
+impl<T, F> Generator for F
+where
+    T: Send + 'static,
+    F: Fn(&v3s16) -> Result<T> + Send + Sync + 'static,
+{
+    type Block = T;
+
+    fn generate(&self, pos: &v3s16) -> Result<T> {
+        self(pos)
+    }
+}
+
+/// A block that finished generating (or failed to)
+#[derive(Debug)]
+pub struct Emerged<T> {
+    pub pos: v3s16,
+    pub block: Result<T>,
+    /// Everyone who requested it
+    pub clients: Vec<ClientId>,
+}
+
+struct Request {
+    priority: u32,
+    seq: u64,
+    clients: Vec<ClientId>,
+}
+
+/// Which block to generate next. This is the queue without the threads.
+#[derive(Default)]
+pub struct EmergeScheduler {
+    max_queued_per_client: usize,
+    max_queued: usize,
+    queued: HashMap<v3s16, Request>,
+    /// (priority, seq, pos), next first
+    order: BTreeSet<(u32, u64, (i16, i16, i16))>,
+    queued_per_client: HashMap<ClientId, usize>,
+    /// Being generated, with the clients waiting for it
+    in_progress: HashMap<v3s16, Vec<ClientId>>,
+    seq: u64,
+}
+
+impl EmergeScheduler {
+    pub fn new(config: &EmergeConfig) -> Self {
+        Self {
+            max_queued_per_client: config.max_queued_per_client,
+            max_queued: config.max_queued,
+            ..Default::default()
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn queued_for(&self, client: &ClientId) -> usize {
+        self.queued_per_client.get(client).copied().unwrap_or(0)
+    }
+
+    pub fn in_progress(&self) -> usize {
+        self.in_progress.len()
+    }
+
+    /// Queues `pos` for `client`, whose player is in block `player_block`.
+    /// Returns false if a limit was reached and it wasn't queued.
+    pub fn request(&mut self, client: ClientId, pos: &v3s16, player_block: &v3s16) -> bool {
+        if let Some(waiting) = self.in_progress.get_mut(pos) {
+            if !waiting.contains(&client) {
+                waiting.push(client);
+            }
+            return true;
+        }
+        let priority = distance_sq(pos, player_block);
+        let key = (pos.x, pos.y, pos.z);
+        let client_full = self.queued_for(&client) >= self.max_queued_per_client;
+        if let Some(request) = self.queued.get_mut(pos) {
+            if !request.clients.contains(&client) {
+                if client_full {
+                    return false;
+                }
+                request.clients.push(client);
+                *self.queued_per_client.entry(client).or_default() += 1;
+            }
+            // The nearest player decides
+            if priority < request.priority {
+                self.order.remove(&(request.priority, request.seq, key));
+                request.priority = priority;
+                self.order.insert((priority, request.seq, key));
+            }
+            return true;
+        }
+        if self.queued.len() >= self.max_queued
+            || self.queued_for(&client) >= self.max_queued_per_client
+        {
+            return false;
+        }
+        self.seq += 1;
+        self.order.insert((priority, self.seq, key));
+        self.queued.insert(
+            pos.clone(),
+            Request {
+                priority,
+                seq: self.seq,
+                clients: vec![client],
+            },
+        );
+        *self.queued_per_client.entry(client).or_default() += 1;
+        true
+    }
+
+    /// Drops the client's requests, e.g. when it disconnects. Blocks
+    /// nobody else wants are no longer generated.
+    pub fn cancel_client(&mut self, client: &ClientId) {
+        self.queued_per_client.remove(client);
+        let mut abandoned = Vec::new();
+        for (pos, request) in self.queued.iter_mut() {
+            request.clients.retain(|c| c != client);
+            if request.clients.is_empty() {
+                abandoned.push(pos.clone());
+            }
+        }
+        for pos in abandoned {
+            let request = self.queued.remove(&pos).unwrap();
+            self.order
+                .remove(&(request.priority, request.seq, (pos.x, pos.y, pos.z)));
+        }
+        for waiting in self.in_progress.values_mut() {
+            waiting.retain(|c| c != client);
+        }
+    }
+
+    /// The next block to generate, now in progress
+    pub fn pop(&mut self) -> Option<v3s16> {
+        let (_, _, (x, y, z)) = self.order.pop_first()?;
+        let pos = v3s16::new(x, y, z);
+        let request = self.queued.remove(&pos).unwrap();
+        for client in &request.clients {
+            if let Some(count) = self.queued_per_client.get_mut(client) {
+                *count -= 1;
+                if *count == 0 {
+                    self.queued_per_client.remove(client);
+                }
+            }
+        }
+        self.in_progress.insert(pos.clone(), request.clients);
+        Some(pos)
+    }
+
+    /// Marks `pos` generated. Returns the clients waiting for it.
+    pub fn finish(&mut self, pos: &v3s16) -> Vec<ClientId> {
+        self.in_progress.remove(pos).unwrap_or_default()
+    }
+}
+
+fn distance_sq(a: &v3s16, b: &v3s16) -> u32 {
+    let d = |a: i16, b: i16| (a as i32 - b as i32).unsigned_abs();
+    let (x, y, z) = (d(a.x, b.x), d(a.y, b.y), d(a.z, b.z));
+    x * x + y * y + z * z
+}
+
+struct Shared {
+    scheduler: Mutex<(EmergeScheduler, bool)>,
+    wakeup: Condvar,
+}
+
+pub struct EmergeQueue<G: Generator> {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    emerged: UnboundedReceiver<Emerged<G::Block>>,
+}
+
+impl<G: Generator> EmergeQueue<G> {
+    pub fn new(generator: G, config: EmergeConfig) -> Self {
+        let shared = Arc::new(Shared {
+            scheduler: Mutex::new((EmergeScheduler::new(&config), false)),
+            wakeup: Condvar::new(),
+        });
+        let generator = Arc::new(generator);
+        let (tx, rx) = unbounded_channel();
+        let workers = (0..config.workers.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                let generator = generator.clone();
+                let tx = tx.clone();
+                std::thread::spawn(move || worker(&shared, &*generator, &tx))
+            })
+            .collect();
+        Self {
+            shared,
+            workers,
+            emerged: rx,
+        }
+    }
+
+    /// See EmergeScheduler::request
+    pub fn request(&self, client: ClientId, pos: &v3s16, player_block: &v3s16) -> bool {
+        let queued = self.lock().0.request(client, pos, player_block);
+        if queued {
+            self.shared.wakeup.notify_one();
+        }
+        queued
+    }
+
+    /// See EmergeScheduler::cancel_client
+    pub fn cancel_client(&self, client: &ClientId) {
+        self.lock().0.cancel_client(client);
+    }
+
+    pub fn queued(&self) -> usize {
+        self.lock().0.queued()
+    }
+
+    pub fn in_progress(&self) -> usize {
+        self.lock().0.in_progress()
+    }
+
+    /// Waits for the next block to finish
+    pub async fn next_emerged(&mut self) -> Emerged<G::Block> {
+        // The workers only stop when self is dropped
+        self.emerged.recv().await.unwrap()
+    }
+
+    /// The next block finished, if any
+    pub fn try_emerged(&mut self) -> Option<Emerged<G::Block>> {
+        self.emerged.try_recv().ok()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, (EmergeScheduler, bool)> {
+        self.shared.scheduler.lock().unwrap()
+    }
+}
+
+impl<G: Generator> Drop for EmergeQueue<G> {
+    /// Waits for the blocks being generated, and drops the rest
+    fn drop(&mut self) {
+        self.lock().1 = true;
+        self.shared.wakeup.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker<G: Generator>(shared: &Shared, generator: &G, tx: &UnboundedSender<Emerged<G::Block>>) {
+    loop {
+        let pos = {
+            let mut guard = shared.scheduler.lock().unwrap();
+            loop {
+                let (scheduler, shutdown) = &mut *guard;
+                if *shutdown {
+                    return;
+                }
+                if let Some(pos) = scheduler.pop() {
+                    break pos;
+                }
+                guard = shared.wakeup.wait(guard).unwrap();
+            }
+        };
+        let block = generator.generate(&pos);
+        let clients = shared.scheduler.lock().unwrap().0.finish(&pos);
+        let _ = tx.send(Emerged {
+            pos,
+            block,
+            clients,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(n: u16) -> ClientId {
+        SocketAddr::from(([127, 0, 0, 1], n))
+    }
+
+    fn pos(x: i16) -> v3s16 {
+        v3s16::new(x, 0, 0)
+    }
+
+    #[test]
+    fn nearest_first_within_limits() {
+        let mut scheduler = EmergeScheduler::new(&EmergeConfig {
+            workers: 1,
+            max_queued_per_client: 2,
+            max_queued: 3,
+        });
+        let (a, b) = (client(1), client(2));
+        let origin = pos(0);
+        assert!(scheduler.request(a, &pos(5), &origin));
+        assert!(scheduler.request(a, &pos(2), &origin));
+        assert!(!scheduler.request(a, &pos(1), &origin));
+        // Already queued: b shares it, and is nearer
+        assert!(scheduler.request(b, &pos(5), &pos(6)));
+        assert!(scheduler.request(b, &pos(-9), &origin));
+        assert!(!scheduler.request(client(3), &pos(7), &origin));
+        assert_eq!(scheduler.queued(), 3);
+
+        assert_eq!(scheduler.pop(), Some(pos(5)));
+        // While it is generated, asking again just waits for it
+        assert!(scheduler.request(client(3), &pos(5), &origin));
+        assert_eq!(scheduler.finish(&pos(5)), vec![a, b, client(3)]);
+
+        scheduler.cancel_client(&b);
+        assert_eq!(scheduler.pop(), Some(pos(2)));
+        assert_eq!(scheduler.pop(), None);
+        assert_eq!(scheduler.queued_for(&a), 0);
+    }
+
+    #[tokio::test]
+    async fn generates_on_workers() {
+        let generator = |pos: &v3s16| -> Result<i16> {
+            anyhow::ensure!(pos.x >= 0, "below the world");
+            Ok(pos.x * 10)
+        };
+        let mut queue = EmergeQueue::new(generator, EmergeConfig::default());
+        for x in -1..3 {
+            assert!(queue.request(client(1), &pos(x), &pos(0)));
+        }
+        let mut results = HashMap::new();
+        for _ in 0..4 {
+            let emerged = queue.next_emerged().await;
+            assert_eq!(emerged.clients, vec![client(1)]);
+            results.insert(emerged.pos.x, emerged.block.ok());
+        }
+        assert_eq!(results[&-1], None);
+        assert_eq!(results[&2], Some(20));
+        assert_eq!(queue.queued() + queue.in_progress(), 0);
+    }
+}
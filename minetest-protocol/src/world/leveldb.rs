@@ -0,0 +1,217 @@
+//!
+//! map.db (feature "leveldb")
+//!
+//! The engine's LevelDB backend: a directory of tables, keyed by the block
+//! key written out in decimal, as the engine does:
+//!
+//! ```text
+//! "-1"          (-1, 0, 0)
+//! "50339841"    (1, 2, 3)
+//! ```
+//!
+//! LevelDB doesn't allow two processes at once, so the world must not be in
+//! use by a server.
+//!
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+use rusty_leveldb::LdbIterator;
+use rusty_leveldb::Options;
+use rusty_leveldb::DB;
+
+use crate::wire::types::v3s16;
+
+use super::database::block_key;
+use super::database::block_pos;
+use super::database::MapDatabase;
+
+/// The engine writes snappy compressed tables
+const SNAPPY: u8 = 1;
+
+type Reply<T> = mpsc::Sender<Result<T>>;
+
+enum Request {
+    List(Reply<Vec<v3s16>>),
+    Get(i64, Reply<Option<Vec<u8>>>),
+    Set(i64, Vec<u8>, Reply<()>),
+    Delete(i64, Reply<()>),
+}
+
+/// A map.db directory.
+///
+/// rusty-leveldb's DB can't leave the thread that opened it, so it lives on
+/// a thread of its own, and requests are sent to it. Blocks are read one at
+/// a time, like SqliteMapDatabase.
+pub struct LevelDbMapDatabase {
+    requests: Option<mpsc::Sender<Request>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LevelDbMapDatabase {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::start(path.to_path_buf(), false)
+    }
+
+    /// Open, creating the database if it doesn't exist
+    pub fn create(path: &Path) -> Result<Self> {
+        Self::start(path.to_path_buf(), true)
+    }
+
+    /// Open the map.db in world directory `dir`
+    pub fn open_world(dir: &Path) -> Result<Self> {
+        Self::open(&dir.join("map.db"))
+    }
+
+    fn start(path: PathBuf, create: bool) -> Result<Self> {
+        let (opened_tx, opened_rx) = mpsc::channel();
+        let (requests, rx) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let options = Options {
+                create_if_missing: create,
+                compressor: SNAPPY,
+                ..Default::default()
+            };
+            match DB::open(&path, options) {
+                Ok(db) => {
+                    let _ = opened_tx.send(Ok(()));
+                    serve(db, rx);
+                }
+                Err(err) => {
+                    let _ = opened_tx.send(Err(anyhow!("Opening {:?}: {}", path, err)));
+                }
+            }
+        });
+        match opened_rx.recv() {
+            Ok(Ok(())) => Ok(Self {
+                requests: Some(requests),
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => Err(err),
+            Err(_) => bail!("LevelDB thread exited"),
+        }
+    }
+
+    fn request<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> Result<T> {
+        let (tx, rx) = mpsc::channel();
+        let sent = match &self.requests {
+            Some(requests) => requests.send(request(tx)).is_ok(),
+            None => false,
+        };
+        if !sent {
+            bail!("LevelDB thread exited");
+        }
+        match rx.recv() {
+            Ok(result) => result,
+            Err(_) => bail!("LevelDB thread exited"),
+        }
+    }
+}
+
+impl Drop for LevelDbMapDatabase {
+    /// Waits for the writes to reach the disk
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(mut db: DB, requests: mpsc::Receiver<Request>) {
+    for request in requests {
+        match request {
+            Request::List(reply) => {
+                let _ = reply.send(list(&mut db));
+            }
+            Request::Get(key, reply) => {
+                let data = db.get(key.to_string().as_bytes()).map(|data| data.to_vec());
+                let _ = reply.send(Ok(data));
+            }
+            Request::Set(key, data, reply) => {
+                let result = db.put(key.to_string().as_bytes(), &data);
+                let _ = reply.send(result.map_err(|err| anyhow!("LevelDB put: {}", err)));
+            }
+            Request::Delete(key, reply) => {
+                let result = db.delete(key.to_string().as_bytes());
+                let _ = reply.send(result.map_err(|err| anyhow!("LevelDB delete: {}", err)));
+            }
+        }
+    }
+    if let Err(err) = db.flush() {
+        eprintln!("LevelDB flush failed: {}", err);
+    }
+}
+
+fn list(db: &mut DB) -> Result<Vec<v3s16>> {
+    let mut iter = db
+        .new_iter()
+        .map_err(|err| anyhow!("LevelDB iterator: {}", err))?;
+    let mut positions = Vec::new();
+    while iter.advance() {
+        let Some((key, _)) = iter.current() else {
+            break;
+        };
+        let Some(key) = std::str::from_utf8(&key)
+            .ok()
+            .and_then(|key| key.parse::<i64>().ok())
+        else {
+            bail!("Not a block key: {:?}", String::from_utf8_lossy(&key));
+        };
+        positions.push(block_pos(key));
+    }
+    Ok(positions)
+}
+
+impl MapDatabase for LevelDbMapDatabase {
+    fn list_blocks(&self) -> Result<Vec<v3s16>> {
+        self.request(Request::List)
+    }
+
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>> {
+        self.request(|reply| Request::Get(block_key(pos), reply))
+    }
+
+    fn set_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()> {
+        self.request(|reply| Request::Set(block_key(pos), data.to_vec(), reply))
+    }
+
+    fn delete_block(&mut self, pos: &v3s16) -> Result<()> {
+        self.request(|reply| Request::Delete(block_key(pos), reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("mt-leveldb-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(LevelDbMapDatabase::open_world(&dir).is_err());
+        {
+            let mut db = LevelDbMapDatabase::create(&dir.join("map.db")).unwrap();
+            db.set_block(&v3s16::new(-1, 0, 0), &[29, 1]).unwrap();
+            db.set_block(&v3s16::new(1, 2, 3), &[29, 2]).unwrap();
+            db.set_block(&v3s16::new(0, 0, 0), &[29, 3]).unwrap();
+            db.delete_block(&v3s16::new(0, 0, 0)).unwrap();
+        }
+        let db = LevelDbMapDatabase::open_world(&dir).unwrap();
+        let mut positions = db.list_blocks().unwrap();
+        positions.sort_by_key(block_key);
+        assert_eq!(positions, vec![v3s16::new(-1, 0, 0), v3s16::new(1, 2, 3)]);
+        assert_eq!(
+            db.get_block(&v3s16::new(1, 2, 3)).unwrap(),
+            Some(vec![29, 2])
+        );
+        assert_eq!(db.get_block(&v3s16::new(0, 0, 0)).unwrap(), None);
+        drop(db);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
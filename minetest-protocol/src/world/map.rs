@@ -0,0 +1,253 @@
+//!
+//! World
+//!
+//! Node level access to a MapDatabase, across block boundaries.
+//!
+use std::sync::Arc;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::wire::types::v3s16;
+use crate::wire::types::MAP_BLOCKSIZE;
+
+use super::block::DiskMapBlock;
+use super::database::MapDatabase;
+
+/// What the engine calls nodes with an id missing from the name-id mapping
+pub const UNKNOWN_NODE_NAME: &str = "unknown";
+
+pub struct World<D: MapDatabase> {
+    db: D,
+}
+
+impl<D: MapDatabase> World<D> {
+    pub fn new(db: D) -> Self {
+        Self { db }
+    }
+
+    pub fn database(&self) -> &D {
+        &self.db
+    }
+
+    pub fn database_mut(&mut self) -> &mut D {
+        &mut self.db
+    }
+
+    pub fn into_database(self) -> D {
+        self.db
+    }
+
+    /// The block at block position `pos`, or None if it isn't stored
+    pub fn get_block(&self, pos: &v3s16) -> Result<Option<DiskMapBlock>> {
+        match self.db.get_block(pos)? {
+            Some(data) => Ok(Some(DiskMapBlock::decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `block` at block position `pos` (see DiskMapBlock::encode)
+    pub fn set_block(&mut self, pos: &v3s16, block: &DiskMapBlock) -> Result<()> {
+        self.db.set_block(pos, &block.encode()?)
+    }
+
+    /// Every node in the box between `min` and `max` (inclusive, in node
+    /// coordinates). Blocks are loaded one at a time, as the iterator
+    /// reaches them, so nodes come block by block (x fastest within each).
+    ///
+    /// Nodes in blocks that aren't stored are skipped. A block that can't be
+    /// loaded or decoded yields one error, and the iterator moves on.
+    pub fn nodes_in_area(&self, min: v3s16, max: v3s16) -> AreaNodes<'_, D> {
+        AreaNodes::new(&self.db, min, max)
+    }
+}
+
+/// A node from World::nodes_in_area
+#[derive(Debug, Clone, PartialEq)]
+pub struct AreaNode {
+    /// World (node) coordinates
+    pub pos: v3s16,
+    pub name: Arc<str>,
+    pub param1: u8,
+    pub param2: u8,
+}
+
+/// Block local bounds of the area, and where iteration is at
+struct BlockCursor {
+    base: [i32; 3],
+    lo: [i32; 3],
+    hi: [i32; 3],
+    at: [i32; 3],
+    /// Block-local id to name
+    names: Vec<Arc<str>>,
+}
+
+impl BlockCursor {
+    fn advance(&mut self, block: &DiskMapBlock, unknown: &Arc<str>) -> Option<AreaNode> {
+        if self.at[2] > self.hi[2] {
+            return None;
+        }
+        let [x, y, z] = self.at;
+        let node = &block.nodes[(z * 256 + y * 16 + x) as usize];
+        let name = self
+            .names
+            .get(node.param0 as usize)
+            .unwrap_or(unknown)
+            .clone();
+        let result = AreaNode {
+            pos: v3s16::new(
+                (self.base[0] + x) as i16,
+                (self.base[1] + y) as i16,
+                (self.base[2] + z) as i16,
+            ),
+            name,
+            param1: node.param1,
+            param2: node.param2,
+        };
+        // x fastest, then y, then z
+        self.at[0] += 1;
+        if self.at[0] > self.hi[0] {
+            self.at[0] = self.lo[0];
+            self.at[1] += 1;
+            if self.at[1] > self.hi[1] {
+                self.at[1] = self.lo[1];
+                self.at[2] += 1;
+            }
+        }
+        Some(result)
+    }
+}
+
+pub struct AreaNodes<'a, D: MapDatabase> {
+    db: &'a D,
+    min: [i32; 3],
+    max: [i32; 3],
+    /// Blocks left to visit, last first
+    pending: Vec<v3s16>,
+    cursor: Option<BlockCursor>,
+    block: DiskMapBlock,
+    unknown: Arc<str>,
+}
+
+impl<'a, D: MapDatabase> AreaNodes<'a, D> {
+    fn new(db: &'a D, a: v3s16, b: v3s16) -> Self {
+        let min = [a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)].map(i32::from);
+        let max = [a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)].map(i32::from);
+        let size = MAP_BLOCKSIZE as i32;
+        let bmin = min.map(|v| v.div_euclid(size));
+        let bmax = max.map(|v| v.div_euclid(size));
+        let mut pending = Vec::new();
+        for z in (bmin[2]..=bmax[2]).rev() {
+            for y in (bmin[1]..=bmax[1]).rev() {
+                for x in (bmin[0]..=bmax[0]).rev() {
+                    pending.push(v3s16::new(x as i16, y as i16, z as i16));
+                }
+            }
+        }
+        Self {
+            db,
+            min,
+            max,
+            pending,
+            cursor: None,
+            block: DiskMapBlock::new(),
+            unknown: Arc::from(UNKNOWN_NODE_NAME),
+        }
+    }
+
+    fn load(&mut self, pos: &v3s16) -> Result<Option<BlockCursor>> {
+        let Some(data) = self.db.get_block(pos)? else {
+            return Ok(None);
+        };
+        self.block.decode_into(&data)?;
+        let mut names: Vec<Arc<str>> = Vec::new();
+        for (id, name) in self.block.name_id_mapping.iter() {
+            let id = id as usize;
+            if names.len() <= id {
+                names.resize(id + 1, self.unknown.clone());
+            }
+            names[id] = Arc::from(name);
+        }
+        let size = MAP_BLOCKSIZE as i32;
+        let base = [pos.x, pos.y, pos.z].map(|v| v as i32 * size);
+        let lo = [0, 1, 2].map(|i| (self.min[i] - base[i]).max(0));
+        let hi = [0, 1, 2].map(|i| (self.max[i] - base[i]).min(size - 1));
+        Ok(Some(BlockCursor {
+            base,
+            lo,
+            hi,
+            at: lo,
+            names,
+        }))
+    }
+}
+
+impl<D: MapDatabase> Iterator for AreaNodes<'_, D> {
+    type Item = Result<AreaNode>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(cursor) = &mut self.cursor {
+                if let Some(node) = cursor.advance(&self.block, &self.unknown) {
+                    return Some(Ok(node));
+                }
+                self.cursor = None;
+            }
+            let pos = self.pending.pop()?;
+            match self.load(&pos) {
+                Ok(cursor) => self.cursor = cursor,
+                Err(err) => return Some(Err(err).with_context(|| format!("block {:?}", pos))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::block::tests::encode_v29;
+    use crate::world::block::tests::sample_nodes;
+    use crate::world::database::MemoryMapDatabase;
+
+    #[test]
+    fn nodes_in_area() {
+        let nodes = sample_nodes();
+        let mut db = MemoryMapDatabase::new();
+        db.insert(
+            &v3s16::new(0, 0, 0),
+            encode_v29(&["air", "default:stone"], &nodes, 0),
+        );
+        // Same nodes, ids swapped
+        db.insert(
+            &v3s16::new(1, 0, 0),
+            encode_v29(&["default:stone", "air"], &nodes, 0),
+        );
+        db.insert(&v3s16::new(-1, 0, 0), vec![29, 0]);
+        let world = World::new(db);
+
+        let area: Vec<AreaNode> = world
+            .nodes_in_area(v3s16::new(17, 1, 0), v3s16::new(14, 0, 0))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(area.len(), 8);
+        for node in &area {
+            let rel = |v: i16| v.rem_euclid(16) as usize;
+            let i = rel(node.pos.z) * 256 + rel(node.pos.y) * 16 + rel(node.pos.x);
+            let stone = (nodes.nodes[i].param0 == 1) == (node.pos.x < 16);
+            assert_eq!(&*node.name, if stone { "default:stone" } else { "air" });
+            assert_eq!(node.param2, nodes.nodes[i].param2);
+        }
+        assert_eq!(area[0].pos, v3s16::new(14, 0, 0));
+        // One block at a time
+        assert_eq!(area[2].pos, v3s16::new(14, 1, 0));
+        assert_eq!(area[4].pos, v3s16::new(16, 0, 0));
+
+        // Missing blocks are skipped, the corrupt one is an error
+        let results: Vec<_> = world
+            .nodes_in_area(v3s16::new(-20, 0, 0), v3s16::new(0, 0, 20))
+            .collect();
+        assert!(results[0].is_err());
+        assert_eq!(results.len(), 1 + 16);
+        assert!(results[1..].iter().all(|node| node.is_ok()));
+    }
+}
@@ -0,0 +1,358 @@
+//!
+//! world.mt and env_meta.txt
+//!
+//! Both are "key = value" lines. world.mt says which game the world is
+//! for, where its data is stored, and which mods are enabled:
+//!
+//! ```text
+//! gameid = minetest
+//! world_name = My World
+//! backend = sqlite3
+//! player_backend = sqlite3
+//! auth_backend = sqlite3
+//! creative_mode = false
+//! load_mod_mesecons = true
+//! ```
+//!
+//! env_meta.txt is the environment's state, ended by EnvArgsEnd:
+//!
+//! ```text
+//! game_time = 10412
+//! time_of_day = 6125
+//! last_clear_objects_time = 0
+//! lbm_introduction_times_version = 1
+//! lbm_introduction_times = default:convert_saplings~0;
+//! day_count = 2
+//! EnvArgsEnd
+//! ```
+//!
+//! WorldMt keeps lines it doesn't understand (and the order of the
+//! others), so editing one key leaves the rest of the file as it was.
+//!
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use super::backend::MapBackend;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    Entry {
+        key: String,
+        value: String,
+    },
+    /// Comments and blank lines
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WorldMt {
+    lines: Vec<Line>,
+}
+
+impl WorldMt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let lines = text
+            .lines()
+            .map(|line| match line.split_once('=') {
+                Some((key, value)) if !line.trim_start().starts_with('#') => Line::Entry {
+                    key: key.trim().to_string(),
+                    value: value.trim().to_string(),
+                },
+                _ => Line::Other(line.to_string()),
+            })
+            .collect();
+        Self { lines }
+    }
+
+    /// The world.mt in world directory `dir`. A missing world.mt is empty.
+    pub fn read(dir: &Path) -> Result<Self> {
+        let path = dir.join("world.mt");
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+        let text = std::fs::read_to_string(&path).with_context(|| format!("Reading {:?}", path))?;
+        Ok(Self::parse(&text))
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        std::fs::write(dir.join("world.mt"), self.to_string())?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+            .last()
+    }
+
+    /// Replaces the value where the key is, or adds it at the end
+    pub fn set(&mut self, key: &str, value: &str) {
+        for line in &mut self.lines {
+            if let Line::Entry { key: k, value: v } = line {
+                if k == key {
+                    *v = value.to_string();
+                    return;
+                }
+            }
+        }
+        self.lines.push(Line::Entry {
+            key: key.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let old = self.get(key).map(str::to_string);
+        self.lines
+            .retain(|line| !matches!(line, Line::Entry { key: k, .. } if k == key));
+        old
+    }
+
+    /// Keys and values, in file order
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Entry { key, value } => Some((key.as_str(), value.as_str())),
+            Line::Other(_) => None,
+        })
+    }
+
+    /// true/false, yes/no, on/off or 1/0, like the engine's is_yes
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)?.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Some(true),
+            "false" | "no" | "off" | "0" => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn gameid(&self) -> Option<&str> {
+        self.get("gameid")
+    }
+
+    pub fn world_name(&self) -> Option<&str> {
+        self.get("world_name")
+    }
+
+    /// The map backend, sqlite3 if none is named
+    pub fn backend(&self) -> Result<MapBackend> {
+        match self.get("backend") {
+            Some(backend) => backend.parse(),
+            None => Ok(MapBackend::Sqlite3),
+        }
+    }
+
+    /// "sqlite3" if none is named (likewise auth_backend and
+    /// mod_storage_backend)
+    pub fn player_backend(&self) -> &str {
+        self.get("player_backend").unwrap_or("sqlite3")
+    }
+
+    pub fn auth_backend(&self) -> &str {
+        self.get("auth_backend").unwrap_or("sqlite3")
+    }
+
+    pub fn mod_storage_backend(&self) -> &str {
+        self.get("mod_storage_backend").unwrap_or("sqlite3")
+    }
+
+    pub fn creative_mode(&self) -> bool {
+        self.get_bool("creative_mode").unwrap_or(false)
+    }
+
+    pub fn enable_damage(&self) -> bool {
+        self.get_bool("enable_damage").unwrap_or(true)
+    }
+
+    /// Mods with load_mod_<name> set. Newer engines write the mod's path
+    /// instead of true.
+    pub fn enabled_mods(&self) -> Vec<&str> {
+        self.entries()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix("load_mod_")?;
+                let enabled = !matches!(value, "false" | "no" | "off" | "0" | "");
+                enabled.then_some(name)
+            })
+            .collect()
+    }
+
+    pub fn set_mod_enabled(&mut self, name: &str, enabled: bool) {
+        self.set(
+            &format!("load_mod_{}", name),
+            if enabled { "true" } else { "false" },
+        );
+    }
+}
+
+impl fmt::Display for WorldMt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Entry { key, value } => writeln!(f, "{} = {}", key, value)?,
+                Line::Other(text) => writeln!(f, "{}", text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EnvMeta {
+    /// Seconds the world has run
+    pub game_time: u64,
+    /// 0 to 23999, 6000 is sunrise
+    pub time_of_day: u32,
+    /// game_time of the last /clearobjects
+    pub last_clear_objects_time: u64,
+    /// As the engine writes it: "name~time;" per LBM
+    pub lbm_introduction_times: String,
+    pub day_count: u32,
+    /// Keys this doesn't know, kept as they are
+    pub other: BTreeMap<String, String>,
+}
+
+impl EnvMeta {
+    /// The env_meta.txt in world directory `dir`
+    pub fn read(dir: &Path) -> Result<Self> {
+        let path = dir.join("env_meta.txt");
+        let text = std::fs::read_to_string(&path).with_context(|| format!("Reading {:?}", path))?;
+        text.parse()
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        std::fs::write(dir.join("env_meta.txt"), self.to_string())?;
+        Ok(())
+    }
+}
+
+impl FromStr for EnvMeta {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut meta = Self::default();
+        let mut ended = false;
+        for line in text.lines() {
+            let line = line.trim();
+            if line == "EnvArgsEnd" {
+                ended = true;
+                break;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("Bad {} {:?}", key, value))
+            };
+            match key {
+                "game_time" => meta.game_time = number()?,
+                "time_of_day" => meta.time_of_day = number()? as u32,
+                "last_clear_objects_time" => meta.last_clear_objects_time = number()?,
+                "lbm_introduction_times" => meta.lbm_introduction_times = value.to_string(),
+                "day_count" => meta.day_count = number()? as u32,
+                // Written again by Display
+                "lbm_introduction_times_version" => (),
+                _ => {
+                    meta.other.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+        if !ended {
+            bail!("env_meta.txt doesn't end with EnvArgsEnd");
+        }
+        Ok(meta)
+    }
+}
+
+impl fmt::Display for EnvMeta {
+    /// In the engine's order
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "game_time = {}", self.game_time)?;
+        writeln!(f, "time_of_day = {}", self.time_of_day)?;
+        writeln!(
+            f,
+            "last_clear_objects_time = {}",
+            self.last_clear_objects_time
+        )?;
+        writeln!(f, "lbm_introduction_times_version = 1")?;
+        writeln!(
+            f,
+            "lbm_introduction_times = {}",
+            self.lbm_introduction_times
+        )?;
+        writeln!(f, "day_count = {}", self.day_count)?;
+        for (key, value) in &self.other {
+            writeln!(f, "{} = {}", key, value)?;
+        }
+        writeln!(f, "EnvArgsEnd")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn world_mt() {
+        let text = "gameid = minetest\n\
+                    # backend = leveldb\n\
+                    backend = leveldb\n\
+                    creative_mode = true\n\
+                    load_mod_mesecons = true\n\
+                    load_mod_mobs = false\n\
+                    load_mod_areas = mods/areas\n";
+        let mut world_mt = WorldMt::parse(text);
+        assert_eq!(world_mt.to_string(), text);
+        assert_eq!(world_mt.gameid(), Some("minetest"));
+        assert_eq!(world_mt.backend().unwrap(), MapBackend::LevelDb);
+        assert_eq!(world_mt.auth_backend(), "sqlite3");
+        assert!(world_mt.creative_mode());
+        assert!(world_mt.enable_damage());
+        assert_eq!(world_mt.enabled_mods(), vec!["mesecons", "areas"]);
+
+        world_mt.set("backend", "sqlite3");
+        world_mt.set_mod_enabled("mobs", true);
+        world_mt.set("world_name", "Test");
+        assert_eq!(world_mt.remove("creative_mode").as_deref(), Some("true"));
+        assert_eq!(
+            world_mt.to_string(),
+            "gameid = minetest\n\
+             # backend = leveldb\n\
+             backend = sqlite3\n\
+             load_mod_mesecons = true\n\
+             load_mod_mobs = true\n\
+             load_mod_areas = mods/areas\n\
+             world_name = Test\n"
+        );
+    }
+
+    #[test]
+    fn env_meta() {
+        let text = "game_time = 10412\n\
+                    time_of_day = 6125\n\
+                    last_clear_objects_time = 0\n\
+                    lbm_introduction_times_version = 1\n\
+                    lbm_introduction_times = default:convert_saplings~0;\n\
+                    day_count = 2\n\
+                    EnvArgsEnd\n";
+        let meta: EnvMeta = text.parse().unwrap();
+        assert_eq!(meta.game_time, 10412);
+        assert_eq!(meta.time_of_day, 6125);
+        assert_eq!(meta.day_count, 2);
+        assert_eq!(meta.to_string(), text);
+        assert!("game_time = 1\n".parse::<EnvMeta>().is_err());
+        assert!("game_time = soon\nEnvArgsEnd\n".parse::<EnvMeta>().is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//!
+//! Worlds on disk
+//!
+//! Offline access to a world's map, for tools that scan, render or prune
+//! whole worlds. A map is a MapDatabase of blocks, each stored in the disk
+//! format (see DiskMapBlock), which differs from the network format.
+//!
+//! Scanning decodes blocks on all cores:
+//!
+//! ```text
+//! let generated = world::par_map_blocks(&db, |_pos, block| {
+//!     block.map(|block| block.generated).unwrap_or(false)
+//! })?
+//! .filter(|generated| *generated)
+//! .count();
+//! ```
+//!
+//! World reads nodes by position, across blocks:
+//!
+//! ```text
+//! let world = World::new(db);
+//! let diamonds = world
+//!     .nodes_in_area(v3s16::new(-100, -100, -100), v3s16::new(100, 0, 100))
+//!     .filter_map(|node| node.ok())
+//!     .filter(|node| &*node.name == "default:stone_with_diamond")
+//!     .count();
+//! ```
+//!
+//! Maps in any of the engine's backends are opened by open_world_map, from
+//! the backend named in world.mt (see world::backend).
+//!
+//! A BlockCache in front of the database keeps recently used blocks in
+//! memory, and holds writes until they are flushed. Blocks not generated
+//! yet are made by an EmergeQueue, on worker threads. BlockActivity unloads
+//! the blocks nobody has used for a while.
+//!
+//! A server saves its players, changed blocks and mod storage with
+//! Persistence, which replays its journal after a crash. A
+//! SnapshotMapDatabase lets a backup read the map as it was at one moment
+//! while the server keeps writing.
+//!
+//! The rest of a world's files have typed readers and writers too: world.mt
+//! and env_meta.txt (see meta), accounts in auth.sqlite or auth.txt (see
+//! auth), and players in players.sqlite or players/ (see players).
+//!
+//! Structures are placed from .mts schematics (see place_schematic), and
+//! regions exchanged with the WorldEdit mod as .we files.
+//!
+pub mod activity;
+pub mod archive;
+pub mod auth;
+pub mod backend;
+pub mod block;
+pub mod cache;
+pub mod database;
+pub mod diff;
+pub mod emerge;
+#[cfg(feature = "leveldb")]
+pub mod leveldb;
+pub mod map;
+pub mod meta;
+#[cfg(feature = "sqlite")]
+pub mod mod_storage;
+#[cfg(feature = "sqlite")]
+pub mod persist;
+pub mod players;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod scan;
+pub mod schematic;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+pub mod worldedit;
+
+pub use activity::BlockActivity;
+pub use archive::ArchiveReader;
+pub use archive::ArchiveWriter;
+#[cfg(feature = "sqlite")]
+pub use auth::AuthDatabase;
+pub use auth::AuthEntry;
+pub use backend::open_world_map;
+pub use backend::BoxedMapDatabase;
+pub use backend::MapBackend;
+pub use block::DiskMapBlock;
+pub use block::NameIdMapping;
+pub use block::NodeTimer;
+pub use block::NodeTimerList;
+pub use block::StaticObject;
+pub use block::StaticObjectList;
+pub use cache::BlockCache;
+pub use database::MapDatabase;
+pub use database::MemoryMapDatabase;
+pub use diff::WorldDiff;
+pub use emerge::EmergeQueue;
+#[cfg(feature = "leveldb")]
+pub use leveldb::LevelDbMapDatabase;
+pub use map::AreaNode;
+pub use map::World;
+pub use meta::EnvMeta;
+pub use meta::WorldMt;
+#[cfg(feature = "sqlite")]
+pub use mod_storage::ModStorage;
+#[cfg(feature = "sqlite")]
+pub use persist::Persistence;
+#[cfg(feature = "sqlite")]
+pub use players::PlayerDatabase;
+pub use players::PlayerFiles;
+pub use players::PlayerRecord;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresMapDatabase;
+pub use scan::par_blocks;
+pub use scan::par_map_blocks;
+pub use schematic::place_schematic;
+pub use schematic::Schematic;
+pub use snapshot::MapSnapshot;
+pub use snapshot::SnapshotMapDatabase;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteMapDatabase;
+pub use stats::WorldStats;
+pub use worldedit::WorldEditFile;
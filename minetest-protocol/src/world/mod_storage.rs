@@ -0,0 +1,134 @@
+//!
+//! Mod storage (feature "sqlite")
+//!
+//! mod_storage.sqlite holds what mods keep with core.get_mod_storage():
+//! string keys and values, per mod. Mods see changes made here the next
+//! time the server starts.
+//!
+//! ```text
+//! entries (modname TEXT NOT NULL, key BLOB NOT NULL, value BLOB NOT NULL,
+//!          PRIMARY KEY (modname, key))
+//! ```
+//!
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
+
+pub struct ModStorage {
+    conn: Connection,
+}
+
+impl ModStorage {
+    /// Open for reading and writing, creating the database if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(Self { conn })
+    }
+
+    /// The mod_storage.sqlite in world directory `dir`
+    pub fn open_world(dir: &Path) -> Result<Self> {
+        Self::open(&dir.join("mod_storage.sqlite"))
+    }
+
+    pub fn from_connection(conn: Connection) -> Result<Self> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS entries (
+                modname TEXT NOT NULL,
+                key BLOB NOT NULL,
+                value BLOB NOT NULL,
+                PRIMARY KEY (modname, key)
+            )",
+            [],
+        )?;
+        Ok(Self { conn })
+    }
+
+    /// Mods with anything stored, and how many keys each has
+    pub fn mods(&self) -> Result<Vec<(String, u64)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT modname, COUNT(*) FROM entries GROUP BY modname ORDER BY modname")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Every key and value of `modname`, by key
+    pub fn entries(&self, modname: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT key, value FROM entries WHERE modname = ?1 ORDER BY key")?;
+        let rows = stmt.query_map([modname], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn get(&self, modname: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT value FROM entries WHERE modname = ?1 AND key = ?2")?
+            .query_row(rusqlite::params![modname, key], |row| row.get(0))
+            .optional()?)
+    }
+
+    pub fn set(&self, modname: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.conn
+            .prepare_cached("REPLACE INTO entries (modname, key, value) VALUES (?1, ?2, ?3)")?
+            .execute(rusqlite::params![modname, key, value])?;
+        Ok(())
+    }
+
+    /// True if the key was there
+    pub fn remove(&self, modname: &str, key: &[u8]) -> Result<bool> {
+        let removed = self
+            .conn
+            .prepare_cached("DELETE FROM entries WHERE modname = ?1 AND key = ?2")?
+            .execute(rusqlite::params![modname, key])?;
+        Ok(removed > 0)
+    }
+
+    /// Remove everything `modname` stored. Returns how many keys it had.
+    pub fn clear(&self, modname: &str) -> Result<usize> {
+        Ok(self
+            .conn
+            .prepare_cached("DELETE FROM entries WHERE modname = ?1")?
+            .execute([modname])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mod_storage() {
+        let storage = ModStorage::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        storage.set("areas", b"count", b"2").unwrap();
+        storage.set("areas", b"1", b"{owner=sam}").unwrap();
+        storage.set("mail", b"sam", b"").unwrap();
+        storage.set("areas", b"count", b"3").unwrap();
+
+        assert_eq!(
+            storage.mods().unwrap(),
+            vec![("areas".to_string(), 2), ("mail".to_string(), 1)]
+        );
+        assert_eq!(storage.get("areas", b"count").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(storage.get("areas", b"missing").unwrap(), None);
+        assert_eq!(
+            storage.entries("areas").unwrap(),
+            vec![
+                (b"1".to_vec(), b"{owner=sam}".to_vec()),
+                (b"count".to_vec(), b"3".to_vec())
+            ]
+        );
+        assert!(storage.remove("mail", b"sam").unwrap());
+        assert!(!storage.remove("mail", b"sam").unwrap());
+        assert_eq!(storage.clear("areas").unwrap(), 2);
+        assert!(storage.mods().unwrap().is_empty());
+    }
+}
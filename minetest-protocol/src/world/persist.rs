@@ -0,0 +1,717 @@
+//!
+//! Saving server state (feature "sqlite")
+//!
+//! A server keeps players, map blocks and mod storage in memory while it
+//! runs. Persistence holds what changed, saves it every `save_interval`
+//! (the engine's server_map_save_interval), and once more at a clean
+//! shutdown:
+//!
+//! ```text
+//! let mut persistence = Persistence::open_world(dir, map, PersistConfig::default())?;
+//! let mut sam = persistence.load_player("sam")?.unwrap_or_else(|| PlayerRecord::new("sam"));
+//! ...
+//! sam.position = position;
+//! persistence.update_player(sam);
+//! persistence.map_mut().set_block(&pos, &data)?;
+//! persistence.set_mod_storage("areas", b"count", Some(b"3"));
+//! persistence.maybe_save(Instant::now())?;
+//! ...
+//! persistence.shutdown()?;
+//! ```
+//!
+//! A save touches several databases, so it is written to a journal
+//! (server.journal in the world directory) first, and the journal is only
+//! removed once every database has it. After a crash half way through a
+//! save, opening the world again replays the journal. A journal cut short
+//! (a crash while writing it) is dropped: none of it reached the databases.
+//!
+//! Journal format, big-endian:
+//!
+//! ```text
+//! magic             8 bytes    b"MTJOURNL"
+//! zero or more entries
+//!   kind              u8
+//!   length            u32        length of the payload
+//!   payload
+//! commit            kind 0, length 4, entry count u32
+//! ```
+//!
+//! ```text
+//! 1  Block         x i16, y i16, z i16, present u8, data
+//! 2  Player        name, x f32, y f32, z f32, pitch f32, yaw f32, hp u16, breath u16,
+//!                  inventory (as in TOCLIENT_INVENTORY), metadata u32, (key, value)...
+//! 3  RemovePlayer  name
+//! 4  ModStorage    modname, key, present u8, value
+//! ```
+//!
+//! Strings and byte strings are a u32 length and the bytes.
+//!
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::wire::types::v3f;
+use crate::wire::types::v3s16;
+
+use super::cache::BlockCache;
+use super::database::MapDatabase;
+use super::mod_storage::ModStorage;
+use super::players::deserialize_inventory;
+use super::players::serialize_inventory;
+use super::players::PlayerDatabase;
+use super::players::PlayerRecord;
+
+pub const JOURNAL_MAGIC: &[u8; 8] = b"MTJOURNL";
+
+const KIND_COMMIT: u8 = 0;
+const KIND_BLOCK: u8 = 1;
+const KIND_PLAYER: u8 = 2;
+const KIND_REMOVE_PLAYER: u8 = 3;
+const KIND_MOD_STORAGE: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PersistConfig {
+    pub save_interval: Duration,
+    /// Map blocks kept in memory (see BlockCache)
+    pub cached_blocks: usize,
+}
+
+impl Default for PersistConfig {
+    /// The engine's default server_map_save_interval
+    fn default() -> Self {
+        Self {
+            save_interval: Duration::from_millis(5300),
+            cached_blocks: 4096,
+        }
+    }
+}
+
+/// One change, as written to the journal
+#[derive(Debug, Clone, PartialEq)]
+pub enum JournalEntry {
+    /// None removes the block
+    Block(v3s16, Option<Vec<u8>>),
+    Player(PlayerRecord),
+    RemovePlayer(String),
+    ModStorage {
+        modname: String,
+        key: Vec<u8>,
+        /// None removes the key
+        value: Option<Vec<u8>>,
+    },
+}
+
+/// The crash recovery journal
+#[derive(Debug, Clone, PartialEq)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the journal with `entries`. They are on disk before the
+    /// commit is, so a journal with a commit is complete.
+    pub fn write(&self, entries: &[JournalEntry]) -> Result<()> {
+        let mut body = JOURNAL_MAGIC.to_vec();
+        for entry in entries {
+            let mut payload = Vec::new();
+            let kind = encode_entry(entry, &mut payload)?;
+            put_record(&mut body, kind, &payload);
+        }
+        let mut file = std::fs::File::create(&self.path)?;
+        file.write_all(&body)?;
+        file.sync_data()?;
+        let mut commit = Vec::new();
+        put_record(
+            &mut commit,
+            KIND_COMMIT,
+            &(entries.len() as u32).to_be_bytes(),
+        );
+        file.write_all(&commit)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// The entries of a complete journal. None if there is no journal, or
+    /// it was cut short.
+    pub fn read(&self) -> Result<Option<Vec<JournalEntry>>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if data.len() < JOURNAL_MAGIC.len() {
+            return Ok(None);
+        }
+        if !data.starts_with(JOURNAL_MAGIC) {
+            bail!("{:?} is not a journal", self.path);
+        }
+        let mut reader = Reader {
+            data: &data[JOURNAL_MAGIC.len()..],
+        };
+        let mut entries = Vec::new();
+        loop {
+            let Some((kind, payload)) = reader.record() else {
+                return Ok(None);
+            };
+            let mut payload = Reader { data: payload };
+            if kind == KIND_COMMIT {
+                if payload.u32() != Some(entries.len() as u32) {
+                    bail!("{:?}: commit doesn't match the entries", self.path);
+                }
+                return Ok(Some(entries));
+            }
+            let Some(entry) = decode_entry(kind, &mut payload) else {
+                bail!("{:?}: bad entry of kind {}", self.path, kind);
+            };
+            entries.push(entry);
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SaveSummary {
+    pub blocks: usize,
+    pub players: usize,
+    pub mod_storage: usize,
+}
+
+impl SaveSummary {
+    fn of(entries: &[JournalEntry]) -> Self {
+        let mut summary = Self::default();
+        for entry in entries {
+            match entry {
+                JournalEntry::Block(..) => summary.blocks += 1,
+                JournalEntry::Player(_) | JournalEntry::RemovePlayer(_) => summary.players += 1,
+                JournalEntry::ModStorage { .. } => summary.mod_storage += 1,
+            }
+        }
+        summary
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The state of a running server, and when to save it
+pub struct Persistence<D: MapDatabase> {
+    config: PersistConfig,
+    map: BlockCache<D>,
+    players: PlayerDatabase,
+    mod_storage: ModStorage,
+    journal: Journal,
+    /// None removes the player
+    dirty_players: BTreeMap<String, Option<PlayerRecord>>,
+    mod_storage_writes: BTreeMap<(String, Vec<u8>), Option<Vec<u8>>>,
+    last_save: Option<Instant>,
+    recovered: SaveSummary,
+}
+
+impl<D: MapDatabase> Persistence<D> {
+    /// Replays the journal, if a crash left one
+    pub fn new(
+        map: D,
+        players: PlayerDatabase,
+        mod_storage: ModStorage,
+        journal: Journal,
+        config: PersistConfig,
+    ) -> Result<Self> {
+        let mut persistence = Self {
+            map: BlockCache::new(map, config.cached_blocks),
+            config,
+            players,
+            mod_storage,
+            journal,
+            dirty_players: BTreeMap::new(),
+            mod_storage_writes: BTreeMap::new(),
+            last_save: None,
+            recovered: SaveSummary::default(),
+        };
+        match persistence.journal.read()? {
+            Some(entries) => {
+                persistence.apply(&entries)?;
+                persistence.recovered = SaveSummary::of(&entries);
+                persistence.journal.clear()?;
+            }
+            None => persistence.journal.clear()?,
+        }
+        Ok(persistence)
+    }
+
+    /// With the players.sqlite, mod_storage.sqlite and server.journal of
+    /// world directory `dir`, and its map `map`
+    pub fn open_world(dir: &Path, map: D, config: PersistConfig) -> Result<Self> {
+        Self::new(
+            map,
+            PlayerDatabase::open_world(dir)?,
+            ModStorage::open_world(dir)?,
+            Journal::new(&dir.join("server.journal")),
+            config,
+        )
+    }
+
+    pub fn config(&self) -> &PersistConfig {
+        &self.config
+    }
+
+    /// What the journal replayed when opening
+    pub fn recovered(&self) -> SaveSummary {
+        self.recovered
+    }
+
+    /// Changes to blocks are held until the next save
+    pub fn map(&self) -> &BlockCache<D> {
+        &self.map
+    }
+
+    pub fn map_mut(&mut self) -> &mut BlockCache<D> {
+        &mut self.map
+    }
+
+    /// The player as last updated, saved or not
+    pub fn load_player(&self, name: &str) -> Result<Option<PlayerRecord>> {
+        match self.dirty_players.get(name) {
+            Some(player) => Ok(player.clone()),
+            None => self.players.load(name),
+        }
+    }
+
+    pub fn update_player(&mut self, player: PlayerRecord) {
+        self.dirty_players.insert(player.name.clone(), Some(player));
+    }
+
+    pub fn remove_player(&mut self, name: &str) {
+        self.dirty_players.insert(name.to_string(), None);
+    }
+
+    pub fn get_mod_storage(&self, modname: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self
+            .mod_storage_writes
+            .get(&(modname.to_string(), key.to_vec()))
+        {
+            Some(value) => Ok(value.clone()),
+            None => self.mod_storage.get(modname, key),
+        }
+    }
+
+    /// None removes the key
+    pub fn set_mod_storage(&mut self, modname: &str, key: &[u8], value: Option<&[u8]>) {
+        self.mod_storage_writes.insert(
+            (modname.to_string(), key.to_vec()),
+            value.map(|value| value.to_vec()),
+        );
+    }
+
+    /// Whether there is anything to save
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty_players.is_empty()
+            || !self.mod_storage_writes.is_empty()
+            || !self.map.dirty_blocks().is_empty()
+    }
+
+    /// Saves if `save_interval` has passed since the last save (or since
+    /// the first call)
+    pub fn maybe_save(&mut self, now: Instant) -> Result<Option<SaveSummary>> {
+        let Some(last_save) = self.last_save else {
+            self.last_save = Some(now);
+            return Ok(None);
+        };
+        if now < last_save + self.config.save_interval {
+            return Ok(None);
+        }
+        self.last_save = Some(now);
+        self.save().map(Some)
+    }
+
+    /// Saves everything that changed. If a database fails, the changes are
+    /// kept (and stay in the journal), to be saved again.
+    pub fn save(&mut self) -> Result<SaveSummary> {
+        let mut entries = Vec::new();
+        for pos in self.map.dirty_blocks() {
+            let data = self.map.get_block(&pos)?;
+            entries.push(JournalEntry::Block(pos, data));
+        }
+        for (name, player) in &self.dirty_players {
+            entries.push(match player {
+                Some(player) => JournalEntry::Player(player.clone()),
+                None => JournalEntry::RemovePlayer(name.clone()),
+            });
+        }
+        for ((modname, key), value) in &self.mod_storage_writes {
+            entries.push(JournalEntry::ModStorage {
+                modname: modname.clone(),
+                key: key.clone(),
+                value: value.clone(),
+            });
+        }
+        if entries.is_empty() {
+            return Ok(SaveSummary::default());
+        }
+        self.journal.write(&entries)?;
+        self.apply(&entries)?;
+        self.journal.clear()?;
+        self.dirty_players.clear();
+        self.mod_storage_writes.clear();
+        Ok(SaveSummary::of(&entries))
+    }
+
+    /// Saves for the last time
+    pub fn shutdown(mut self) -> Result<SaveSummary> {
+        self.save()
+    }
+
+    fn apply(&mut self, entries: &[JournalEntry]) -> Result<()> {
+        for entry in entries {
+            match entry {
+                JournalEntry::Block(pos, Some(data)) => self.map.set_block(pos, data)?,
+                JournalEntry::Block(pos, None) => self.map.delete_block(pos)?,
+                JournalEntry::Player(player) => self.players.save(player)?,
+                JournalEntry::RemovePlayer(name) => {
+                    self.players.remove(name)?;
+                }
+                JournalEntry::ModStorage {
+                    modname,
+                    key,
+                    value: Some(value),
+                } => self.mod_storage.set(modname, key, value)?,
+                JournalEntry::ModStorage {
+                    modname,
+                    key,
+                    value: None,
+                } => {
+                    self.mod_storage.remove(modname, key)?;
+                }
+            }
+        }
+        self.map.flush()?;
+        Ok(())
+    }
+}
+
+fn put_record(out: &mut Vec<u8>, kind: u8, payload: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn put_optional(out: &mut Vec<u8>, bytes: &Option<Vec<u8>>) {
+    out.push(bytes.is_some() as u8);
+    put_bytes(out, bytes.as_deref().unwrap_or_default());
+}
+
+fn encode_entry(entry: &JournalEntry, out: &mut Vec<u8>) -> Result<u8> {
+    Ok(match entry {
+        JournalEntry::Block(pos, data) => {
+            for c in [pos.x, pos.y, pos.z] {
+                out.extend_from_slice(&c.to_be_bytes());
+            }
+            put_optional(out, data);
+            KIND_BLOCK
+        }
+        JournalEntry::Player(player) => {
+            put_bytes(out, player.name.as_bytes());
+            let position = &player.position;
+            for f in [position.x, position.y, position.z, player.pitch, player.yaw] {
+                out.extend_from_slice(&f.to_be_bytes());
+            }
+            out.extend_from_slice(&player.hp.to_be_bytes());
+            out.extend_from_slice(&player.breath.to_be_bytes());
+            put_bytes(out, &serialize_inventory(&player.inventory)?);
+            out.extend_from_slice(&(player.metadata.len() as u32).to_be_bytes());
+            for (key, value) in &player.metadata {
+                put_bytes(out, key.as_bytes());
+                put_bytes(out, value.as_bytes());
+            }
+            KIND_PLAYER
+        }
+        JournalEntry::RemovePlayer(name) => {
+            put_bytes(out, name.as_bytes());
+            KIND_REMOVE_PLAYER
+        }
+        JournalEntry::ModStorage {
+            modname,
+            key,
+            value,
+        } => {
+            put_bytes(out, modname.as_bytes());
+            put_bytes(out, key);
+            put_optional(out, value);
+            KIND_MOD_STORAGE
+        }
+    })
+}
+
+fn decode_entry(kind: u8, r: &mut Reader<'_>) -> Option<JournalEntry> {
+    Some(match kind {
+        KIND_BLOCK => {
+            let pos = v3s16::new(r.i16()?, r.i16()?, r.i16()?);
+            JournalEntry::Block(pos, r.optional()?)
+        }
+        KIND_PLAYER => {
+            let mut player = PlayerRecord::new(&r.string()?);
+            player.position = v3f::new(r.f32()?, r.f32()?, r.f32()?);
+            player.pitch = r.f32()?;
+            player.yaw = r.f32()?;
+            player.hp = r.u16()?;
+            player.breath = r.u16()?;
+            player.inventory = deserialize_inventory(r.bytes()?).ok()?;
+            for _ in 0..r.u32()? {
+                player.metadata.insert(r.string()?, r.string()?);
+            }
+            JournalEntry::Player(player)
+        }
+        KIND_REMOVE_PLAYER => JournalEntry::RemovePlayer(r.string()?),
+        KIND_MOD_STORAGE => JournalEntry::ModStorage {
+            modname: r.string()?,
+            key: r.bytes()?.to_vec(),
+            value: r.optional()?,
+        },
+        _ => return None,
+    })
+}
+
+/// None when the data runs out
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.array()?))
+    }
+
+    fn i16(&mut self) -> Option<i16> {
+        Some(i16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_be_bytes(self.array()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    fn optional(&mut self) -> Option<Option<Vec<u8>>> {
+        let present = self.take(1)?[0] != 0;
+        let bytes = self.bytes()?.to_vec();
+        Some(present.then_some(bytes))
+    }
+
+    fn record(&mut self) -> Option<(u8, &'a [u8])> {
+        let kind = self.take(1)?[0];
+        Some((kind, self.bytes()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::types::InventoryEntry;
+    use crate::wire::types::InventoryList;
+    use crate::wire::types::ItemStackUpdate;
+    use crate::world::players::item_string;
+    use crate::world::players::parse_item_string;
+    use crate::world::MemoryMapDatabase;
+    use rusqlite::Connection;
+
+    fn open(journal: &Journal) -> Persistence<MemoryMapDatabase> {
+        Persistence::new(
+            MemoryMapDatabase::new(),
+            PlayerDatabase::from_connection(Connection::open_in_memory().unwrap()).unwrap(),
+            ModStorage::from_connection(Connection::open_in_memory().unwrap()).unwrap(),
+            journal.clone(),
+            PersistConfig::default(),
+        )
+        .unwrap()
+    }
+
+    fn journal(name: &str) -> Journal {
+        let dir = std::env::temp_dir().join(format!("mt-persist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Journal::new(&dir.join(name))
+    }
+
+    fn changes() -> Vec<JournalEntry> {
+        let mut sam = PlayerRecord::new("sam");
+        sam.position = v3f::new(1.0, 2.5, -3.0);
+        sam.inventory
+            .entries
+            .push(InventoryEntry::Update(InventoryList {
+                name: "main".into(),
+                width: 8,
+                items: vec![
+                    parse_item_string("default:dirt 5").unwrap(),
+                    ItemStackUpdate::Empty,
+                ],
+            }));
+        sam.metadata.insert("home".into(), "(1,2,3)".into());
+        vec![
+            JournalEntry::Block(v3s16::new(1, -2, 3), Some(vec![29, 1])),
+            JournalEntry::Block(v3s16::new(0, 0, 0), None),
+            JournalEntry::Player(sam),
+            JournalEntry::RemovePlayer("alex".into()),
+            JournalEntry::ModStorage {
+                modname: "areas".into(),
+                key: b"count".to_vec(),
+                value: Some(b"3".to_vec()),
+            },
+        ]
+    }
+
+    #[test]
+    fn periodic_save() {
+        let journal = journal("periodic");
+        let mut persistence = open(&journal);
+        let start = Instant::now();
+        assert_eq!(persistence.maybe_save(start).unwrap(), None);
+
+        persistence.update_player(PlayerRecord::new("sam"));
+        persistence
+            .map_mut()
+            .set_block(&v3s16::new(0, 0, 0), &[29])
+            .unwrap();
+        persistence.set_mod_storage("areas", b"count", Some(b"1"));
+        assert!(persistence.is_dirty());
+        assert_eq!(
+            persistence
+                .maybe_save(start + Duration::from_secs(1))
+                .unwrap(),
+            None
+        );
+        let saved = persistence
+            .maybe_save(start + Duration::from_secs(6))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            saved,
+            SaveSummary {
+                blocks: 1,
+                players: 1,
+                mod_storage: 1,
+            }
+        );
+        assert!(!persistence.is_dirty());
+        assert!(!journal.exists());
+        assert_eq!(
+            persistence
+                .map()
+                .database()
+                .get_block(&v3s16::new(0, 0, 0))
+                .unwrap(),
+            Some(vec![29])
+        );
+        assert_eq!(
+            persistence.get_mod_storage("areas", b"count").unwrap(),
+            Some(b"1".to_vec())
+        );
+
+        persistence.remove_player("sam");
+        assert_eq!(persistence.load_player("sam").unwrap(), None);
+        assert_eq!(persistence.shutdown().unwrap().players, 1);
+    }
+
+    #[test]
+    fn crash_recovery() {
+        let journal = journal("crash");
+        journal.write(&changes()).unwrap();
+        assert_eq!(journal.read().unwrap(), Some(changes()));
+
+        let persistence = open(&journal);
+        assert_eq!(
+            persistence.recovered(),
+            SaveSummary {
+                blocks: 2,
+                players: 2,
+                mod_storage: 1,
+            }
+        );
+        assert!(!journal.exists());
+        let sam = persistence.load_player("sam").unwrap().unwrap();
+        assert_eq!(
+            item_string(&sam.list("main").unwrap().items[0]).unwrap(),
+            "default:dirt 5"
+        );
+        assert_eq!(
+            persistence
+                .map()
+                .database()
+                .get_block(&v3s16::new(1, -2, 3))
+                .unwrap(),
+            Some(vec![29, 1])
+        );
+        assert_eq!(
+            persistence.get_mod_storage("areas", b"count").unwrap(),
+            Some(b"3".to_vec())
+        );
+    }
+
+    #[test]
+    fn torn_journal() {
+        let journal = journal("torn");
+        journal.write(&changes()).unwrap();
+        let data = std::fs::read(journal.path()).unwrap();
+        std::fs::write(journal.path(), &data[..data.len() - 3]).unwrap();
+        assert_eq!(journal.read().unwrap(), None);
+
+        let persistence = open(&journal);
+        assert!(persistence.recovered().is_empty());
+        assert!(!journal.exists());
+        assert_eq!(persistence.load_player("sam").unwrap(), None);
+    }
+}
@@ -0,0 +1,707 @@
+//!
+//! Players
+//!
+//! Where each player is, their hp and breath, their inventory, and their
+//! metadata. The engine keeps them in players.sqlite (player_backend =
+//! sqlite3, feature "sqlite"), or in older worlds as one file per player
+//! in players/ (player_backend = files):
+//!
+//! ```text
+//! players.sqlite:
+//! player (name, pitch, yaw, posX, posY, posZ, hp, breath,
+//!         creation_date, modification_date)
+//! player_inventories (player, inv_id, inv_width, inv_name, inv_size)
+//! player_inventory_items (player, inv_id, slot_id, item)
+//! player_metadata (player, metadata, value)
+//!
+//! players/sam:
+//! name = sam
+//! pitch = 0
+//! yaw = 90
+//! position = (105,-200,32.5)
+//! hp = 14
+//! breath = 10
+//! version = 1
+//! extended_attributes = {"home":"(1,2,3)"}
+//! PlayerArgsEnd
+//! List main 32
+//! Width 0
+//! Item default:dirt 5
+//! ...
+//! EndInventoryList
+//! EndInventory
+//! ```
+//!
+//! Positions are in the engine's units (BS = 10 per node). The inventory
+//! is the same as in TOCLIENT_INVENTORY; players.sqlite has each item
+//! as an item string ("default:dirt 5", or "" for an empty slot).
+//!
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::wire::deser::Deserialize;
+use crate::wire::deser::Deserializer;
+use crate::wire::ser::Serialize;
+use crate::wire::ser::VecSerializer;
+use crate::wire::types::v3f;
+use crate::wire::types::Inventory;
+use crate::wire::types::InventoryEntry;
+use crate::wire::types::InventoryList;
+use crate::wire::types::ItemStack;
+use crate::wire::types::ItemStackUpdate;
+use crate::wire::types::ProtocolContext;
+use crate::wire::util::deserialize_json_string;
+use crate::wire::util::serialize_json_string;
+use crate::wire::util::skip_whitespace;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerRecord {
+    pub name: String,
+    pub position: v3f,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub hp: u16,
+    pub breath: u16,
+    /// Only Update entries are stored
+    pub inventory: Inventory,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl PlayerRecord {
+    /// At the origin, with full hp and breath and nothing else
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            position: v3f::new(0.0, 0.0, 0.0),
+            pitch: 0.0,
+            yaw: 0.0,
+            hp: 20,
+            breath: 10,
+            inventory: Inventory {
+                entries: Vec::new(),
+            },
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// The inventory's lists
+    pub fn lists(&self) -> impl Iterator<Item = &InventoryList> {
+        self.inventory
+            .entries
+            .iter()
+            .filter_map(|entry| match entry {
+                InventoryEntry::Update(list) => Some(list),
+                InventoryEntry::KeepList(_) => None,
+            })
+    }
+
+    pub fn list(&self, name: &str) -> Option<&InventoryList> {
+        self.lists().find(|list| list.name == name)
+    }
+}
+
+/// An item as players.sqlite stores it
+pub fn item_string(item: &ItemStackUpdate) -> Result<String> {
+    let ItemStackUpdate::Item(item) = item else {
+        return Ok(String::new());
+    };
+    let mut ser = VecSerializer::new(ProtocolContext::latest_for_send(false), 32);
+    ItemStack::serialize(item, &mut ser)?;
+    let line = String::from_utf8(ser.take())?;
+    Ok(line["Item ".len()..].trim_end_matches('\n').to_string())
+}
+
+pub fn parse_item_string(item: &str) -> Result<ItemStackUpdate> {
+    if item.trim().is_empty() {
+        return Ok(ItemStackUpdate::Empty);
+    }
+    let line = format!("Item {}\n", item);
+    let mut deser = Deserializer::new(ProtocolContext::latest_for_receive(false), line.as_bytes());
+    Ok(ItemStackUpdate::Item(ItemStack::deserialize(&mut deser)?))
+}
+
+pub(super) fn serialize_inventory(inventory: &Inventory) -> Result<Vec<u8>> {
+    let mut ser = VecSerializer::new(ProtocolContext::latest_for_send(false), 1024);
+    Inventory::serialize(inventory, &mut ser)?;
+    Ok(ser.take())
+}
+
+pub(super) fn deserialize_inventory(data: &[u8]) -> Result<Inventory> {
+    let mut deser = Deserializer::new(ProtocolContext::latest_for_receive(false), data);
+    Inventory::deserialize(&mut deser)
+}
+
+/// A player file, as in the players/ directory
+pub fn parse_player_file(data: &[u8]) -> Result<PlayerRecord> {
+    let mut player = PlayerRecord::new("");
+    let mut rest = data;
+    loop {
+        let Some(end) = rest.iter().position(|&ch| ch == b'\n') else {
+            bail!("Player file doesn't have PlayerArgsEnd");
+        };
+        let line = std::str::from_utf8(&rest[..end])?.trim();
+        rest = &rest[end + 1..];
+        if line == "PlayerArgsEnd" {
+            break;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let bad = || format!("Bad {} {:?}", key, value);
+        match key {
+            "name" => player.name = value.to_string(),
+            "pitch" => player.pitch = value.parse().with_context(bad)?,
+            "yaw" => player.yaw = value.parse().with_context(bad)?,
+            "position" => player.position = parse_v3f(value).with_context(bad)?,
+            "hp" => player.hp = value.parse().with_context(bad)?,
+            "breath" => player.breath = value.parse().with_context(bad)?,
+            "extended_attributes" => {
+                player.metadata = parse_json_object(value).with_context(bad)?
+            }
+            _ => (),
+        }
+    }
+    if player.name.is_empty() {
+        bail!("Player file without a name");
+    }
+    player.inventory = deserialize_inventory(rest)?;
+    Ok(player)
+}
+
+pub fn write_player_file(player: &PlayerRecord) -> Result<Vec<u8>> {
+    let position = &player.position;
+    let args = [
+        ("name", player.name.clone()),
+        ("pitch", player.pitch.to_string()),
+        ("yaw", player.yaw.to_string()),
+        (
+            "position",
+            format!("({},{},{})", position.x, position.y, position.z),
+        ),
+        ("hp", player.hp.to_string()),
+        ("breath", player.breath.to_string()),
+        ("version", "1".to_string()),
+    ];
+    let mut out = Vec::new();
+    for (key, value) in args {
+        out.extend(format!("{} = {}\n", key, value).into_bytes());
+    }
+    if !player.metadata.is_empty() {
+        out.extend_from_slice(b"extended_attributes = ");
+        write_json_object(&player.metadata, &mut out)?;
+        out.push(b'\n');
+    }
+    out.extend_from_slice(b"PlayerArgsEnd\n");
+    out.extend(serialize_inventory(&player.inventory)?);
+    Ok(out)
+}
+
+/// "(x,y,z)"
+fn parse_v3f(value: &str) -> Result<v3f> {
+    let Some(inner) = value.strip_prefix('(').and_then(|v| v.strip_suffix(')')) else {
+        bail!("Not in parentheses");
+    };
+    let c: Vec<f32> = inner
+        .split(',')
+        .map(|c| c.trim().parse())
+        .collect::<Result<_, _>>()?;
+    let [x, y, z] = c[..] else {
+        bail!("Not three coordinates");
+    };
+    Ok(v3f::new(x, y, z))
+}
+
+/// A JSON object of strings, which is all extended_attributes has
+fn parse_json_object(value: &str) -> Result<BTreeMap<String, String>> {
+    let mut map = BTreeMap::new();
+    let Some(mut rest) = value.as_bytes().strip_prefix(b"{") else {
+        bail!("Not a JSON object");
+    };
+    let string = |rest: &mut &[u8]| -> Result<String> {
+        *rest = skip_whitespace(rest);
+        if rest.first() != Some(&b'"') {
+            bail!("Expected a JSON string");
+        }
+        let (value, len) = deserialize_json_string(rest)?;
+        *rest = skip_whitespace(&rest[len..]);
+        Ok(String::from_utf8(value)?)
+    };
+    rest = skip_whitespace(rest);
+    if rest.first() == Some(&b'}') {
+        return Ok(map);
+    }
+    loop {
+        let key = string(&mut rest)?;
+        let Some(after) = rest.strip_prefix(b":") else {
+            bail!("Expected ':'");
+        };
+        rest = after;
+        let value = string(&mut rest)?;
+        map.insert(key, value);
+        match rest.split_first() {
+            Some((b',', after)) => rest = after,
+            Some((b'}', _)) => return Ok(map),
+            _ => bail!("Expected ',' or '}}'"),
+        }
+    }
+}
+
+fn write_json_object(map: &BTreeMap<String, String>, out: &mut Vec<u8>) -> Result<()> {
+    out.push(b'{');
+    for (i, (key, value)) in map.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        for s in [key, value] {
+            serialize_json_string(s.as_bytes(), |chunk| {
+                out.extend_from_slice(chunk);
+                Ok(())
+            })?;
+            out.push(b':');
+        }
+        out.pop();
+    }
+    out.push(b'}');
+    Ok(())
+}
+
+/// The players/ directory of a world with player_backend = files
+pub struct PlayerFiles {
+    dir: PathBuf,
+}
+
+impl PlayerFiles {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// The players/ in world directory `dir`
+    pub fn open_world(dir: &Path) -> Self {
+        Self::new(&dir.join("players"))
+    }
+
+    /// Every player, by name. Files that don't parse are skipped.
+    pub fn load_all(&self) -> Result<BTreeMap<String, PlayerRecord>> {
+        let mut players = BTreeMap::new();
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(players),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            match parse_player_file(&std::fs::read(&path)?) {
+                Ok(player) => {
+                    players.insert(player.name.clone(), player);
+                }
+                Err(err) => eprintln!("Skipping player file {:?}: {}", path, err),
+            }
+        }
+        Ok(players)
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        Ok(self.load_all()?.into_keys().collect())
+    }
+
+    /// The file named after the player. Fails for names that would point
+    /// outside players/.
+    fn player_path(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\', '.']) {
+            bail!("Not a player file name: {:?}", name);
+        }
+        Ok(self.dir.join(name))
+    }
+
+    /// The engine names the file after the player, but older worlds may
+    /// have it elsewhere, so the rest are looked at too
+    pub fn load(&self, name: &str) -> Result<Option<PlayerRecord>> {
+        let data = self
+            .player_path(name)
+            .and_then(|path| Ok(std::fs::read(path)?));
+        if let Ok(data) = data {
+            if let Ok(player) = parse_player_file(&data) {
+                if player.name == name {
+                    return Ok(Some(player));
+                }
+            }
+        }
+        Ok(self.load_all()?.remove(name))
+    }
+
+    pub fn save(&self, player: &PlayerRecord) -> Result<()> {
+        let path = self.player_path(&player.name)?;
+        std::fs::create_dir_all(&self.dir)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, write_player_file(player)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// True if the player was there
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        let path = self.player_path(name)?;
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::PlayerDatabase;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::path::Path;
+
+    use anyhow::Result;
+    use rusqlite::Connection;
+    use rusqlite::OptionalExtension;
+
+    use super::item_string;
+    use super::parse_item_string;
+    use super::PlayerRecord;
+    use crate::wire::types::v3f;
+    use crate::wire::types::InventoryEntry;
+    use crate::wire::types::InventoryList;
+
+    /// players.sqlite
+    pub struct PlayerDatabase {
+        conn: Connection,
+    }
+
+    impl PlayerDatabase {
+        /// Open for reading and writing, creating the database if needed
+        pub fn open(path: &Path) -> Result<Self> {
+            Self::from_connection(Connection::open(path)?)
+        }
+
+        /// The players.sqlite in world directory `dir`
+        pub fn open_world(dir: &Path) -> Result<Self> {
+            Self::open(&dir.join("players.sqlite"))
+        }
+
+        pub fn from_connection(conn: Connection) -> Result<Self> {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS player (
+                    name VARCHAR(50) NOT NULL,
+                    pitch NUMERIC(11, 4) NOT NULL,
+                    yaw NUMERIC(11, 4) NOT NULL,
+                    posX NUMERIC(11, 4) NOT NULL,
+                    posY NUMERIC(11, 4) NOT NULL,
+                    posZ NUMERIC(11, 4) NOT NULL,
+                    hp INT NOT NULL,
+                    breath INT NOT NULL,
+                    creation_date DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                    modification_date DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (name)
+                );
+                CREATE TABLE IF NOT EXISTS player_inventories (
+                    player VARCHAR(50) NOT NULL,
+                    inv_id INT NOT NULL,
+                    inv_width INT NOT NULL,
+                    inv_name TEXT NOT NULL DEFAULT '',
+                    inv_size INT NOT NULL,
+                    PRIMARY KEY (player, inv_id)
+                );
+                CREATE TABLE IF NOT EXISTS player_inventory_items (
+                    player VARCHAR(50) NOT NULL,
+                    inv_id INT NOT NULL,
+                    slot_id INT NOT NULL,
+                    item TEXT NOT NULL DEFAULT '',
+                    PRIMARY KEY (player, inv_id, slot_id)
+                );
+                CREATE TABLE IF NOT EXISTS player_metadata (
+                    player VARCHAR(50) NOT NULL,
+                    metadata VARCHAR(256) NOT NULL,
+                    value TEXT,
+                    PRIMARY KEY (player, metadata)
+                );",
+            )?;
+            Ok(Self { conn })
+        }
+
+        /// Every player's name
+        pub fn list(&self) -> Result<Vec<String>> {
+            let mut stmt = self.conn.prepare("SELECT name FROM player ORDER BY name")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        }
+
+        pub fn load(&self, name: &str) -> Result<Option<PlayerRecord>> {
+            let player = self
+                .conn
+                .prepare_cached(
+                    "SELECT pitch, yaw, posX, posY, posZ, hp, breath FROM player WHERE name = ?1",
+                )?
+                .query_row([name], |row| {
+                    let mut player = PlayerRecord::new(name);
+                    player.pitch = row.get::<_, f64>(0)? as f32;
+                    player.yaw = row.get::<_, f64>(1)? as f32;
+                    player.position = v3f::new(
+                        row.get::<_, f64>(2)? as f32,
+                        row.get::<_, f64>(3)? as f32,
+                        row.get::<_, f64>(4)? as f32,
+                    );
+                    player.hp = row.get(5)?;
+                    player.breath = row.get(6)?;
+                    Ok(player)
+                })
+                .optional()?;
+            let Some(mut player) = player else {
+                return Ok(None);
+            };
+
+            let mut stmt = self.conn.prepare_cached(
+                "SELECT inv_id, inv_width, inv_name, inv_size FROM player_inventories
+                 WHERE player = ?1 ORDER BY inv_id",
+            )?;
+            let lists = stmt.query_map([name], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, u32>(3)?,
+                ))
+            })?;
+            for list in lists {
+                let (id, width, list_name, size) = list?;
+                let mut items = vec![String::new(); size as usize];
+                let mut stmt = self.conn.prepare_cached(
+                    "SELECT slot_id, item FROM player_inventory_items
+                     WHERE player = ?1 AND inv_id = ?2",
+                )?;
+                let rows = stmt.query_map(rusqlite::params![name, id], |row| {
+                    Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+                })?;
+                for row in rows {
+                    let (slot, item) = row?;
+                    if let Some(slot) = items.get_mut(slot as usize) {
+                        *slot = item;
+                    }
+                }
+                let items = items
+                    .iter()
+                    .map(|item| parse_item_string(item))
+                    .collect::<Result<_>>()?;
+                player
+                    .inventory
+                    .entries
+                    .push(InventoryEntry::Update(InventoryList {
+                        name: list_name,
+                        width,
+                        items,
+                    }));
+            }
+
+            let mut stmt = self
+                .conn
+                .prepare_cached("SELECT metadata, value FROM player_metadata WHERE player = ?1")?;
+            let rows = stmt.query_map([name], |row| {
+                Ok((row.get(0)?, row.get::<_, Option<String>>(1)?))
+            })?;
+            for row in rows {
+                let (key, value) = row?;
+                player.metadata.insert(key, value.unwrap_or_default());
+            }
+            Ok(Some(player))
+        }
+
+        /// Store a player, replacing everything stored for them, in one
+        /// transaction
+        pub fn save(&mut self, player: &PlayerRecord) -> Result<()> {
+            let tx = self.conn.transaction()?;
+            let created: Option<String> = tx
+                .query_row(
+                    "SELECT creation_date FROM player WHERE name = ?1",
+                    [&player.name],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            remove_player(&tx, &player.name)?;
+            tx.execute(
+                "INSERT INTO player (name, pitch, yaw, posX, posY, posZ, hp, breath, creation_date)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, COALESCE(?9, CURRENT_TIMESTAMP))",
+                rusqlite::params![
+                    player.name,
+                    player.pitch as f64,
+                    player.yaw as f64,
+                    player.position.x as f64,
+                    player.position.y as f64,
+                    player.position.z as f64,
+                    player.hp,
+                    player.breath,
+                    created,
+                ],
+            )?;
+            for (id, list) in player.lists().enumerate() {
+                tx.execute(
+                    "INSERT INTO player_inventories (player, inv_id, inv_width, inv_name, inv_size)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    rusqlite::params![
+                        player.name,
+                        id as u32,
+                        list.width,
+                        list.name,
+                        list.items.len() as u32
+                    ],
+                )?;
+                for (slot, item) in list.items.iter().enumerate() {
+                    tx.execute(
+                        "INSERT INTO player_inventory_items (player, inv_id, slot_id, item)
+                         VALUES (?1, ?2, ?3, ?4)",
+                        rusqlite::params![player.name, id as u32, slot as u32, item_string(item)?],
+                    )?;
+                }
+            }
+            for (key, value) in &player.metadata {
+                tx.execute(
+                    "INSERT INTO player_metadata (player, metadata, value) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![player.name, key, value],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+
+        /// True if the player was there
+        pub fn remove(&mut self, name: &str) -> Result<bool> {
+            let tx = self.conn.transaction()?;
+            let removed = remove_player(&tx, name)?;
+            tx.commit()?;
+            Ok(removed)
+        }
+    }
+
+    fn remove_player(conn: &Connection, name: &str) -> Result<bool> {
+        let removed = conn.execute("DELETE FROM player WHERE name = ?1", [name])?;
+        for table in [
+            "player_inventories",
+            "player_inventory_items",
+            "player_metadata",
+        ] {
+            conn.execute(&format!("DELETE FROM {} WHERE player = ?1", table), [name])?;
+        }
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sam() -> PlayerRecord {
+        let mut sam = PlayerRecord::new("sam");
+        sam.position = v3f::new(105.0, -200.0, 32.5);
+        sam.yaw = 90.0;
+        sam.hp = 14;
+        sam.inventory
+            .entries
+            .push(InventoryEntry::Update(InventoryList {
+                name: "main".into(),
+                width: 0,
+                items: vec![
+                    parse_item_string("default:dirt 5").unwrap(),
+                    ItemStackUpdate::Empty,
+                    parse_item_string("\"mymod:odd name\" 1 300").unwrap(),
+                ],
+            }));
+        sam.metadata.insert("home".into(), "(1,2,3)".into());
+        sam
+    }
+
+    #[test]
+    fn item_strings() {
+        for item in [
+            "default:dirt 5",
+            "default:torch",
+            "\"mymod:odd name\" 1 300",
+            "",
+        ] {
+            assert_eq!(
+                item_string(&parse_item_string(item).unwrap()).unwrap(),
+                item
+            );
+        }
+        let ItemStackUpdate::Item(item) = parse_item_string("default:pick_steel 1 10818").unwrap()
+        else {
+            panic!("expected an item");
+        };
+        assert_eq!(item.wear, 10818);
+    }
+
+    #[test]
+    fn player_file() {
+        let text = "name = sam\n\
+                    pitch = 0\n\
+                    yaw = 90\n\
+                    position = (105,-200,32.5)\n\
+                    hp = 14\n\
+                    breath = 10\n\
+                    version = 1\n\
+                    extended_attributes = {\"home\":\"(1,2,3)\"}\n\
+                    PlayerArgsEnd\n\
+                    List main 3\n\
+                    Width 0\n\
+                    Item default:dirt 5\n\
+                    Empty\n\
+                    Item \"mymod:odd name\" 1 300\n\
+                    EndInventoryList\n\
+                    EndInventory\n";
+        let player = parse_player_file(text.as_bytes()).unwrap();
+        assert_eq!(player, sam());
+        assert_eq!(player.list("main").unwrap().items.len(), 3);
+        assert_eq!(write_player_file(&player).unwrap(), text.as_bytes());
+        assert!(parse_player_file(b"name = sam\n").is_err());
+        assert!(parse_player_file(b"position = (1,2)\nPlayerArgsEnd\nEndInventory\n").is_err());
+
+        let dir = std::env::temp_dir().join(format!("mt-players-{}", std::process::id()));
+        let files = PlayerFiles::open_world(&dir);
+        files.save(&player).unwrap();
+        files.save(&PlayerRecord::new("alex")).unwrap();
+        assert_eq!(files.list().unwrap(), vec!["alex", "sam"]);
+        assert_eq!(files.load("sam").unwrap(), Some(player));
+        assert!(files.remove("sam").unwrap());
+        assert_eq!(files.load("sam").unwrap(), None);
+        assert!(files.save(&PlayerRecord::new("../x")).is_err());
+        assert!(files.remove("../world.mt").is_err());
+        assert!(files.remove("..\\world.mt").is_err());
+        assert_eq!(files.load("../players/alex").unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn players_sqlite() {
+        use rusqlite::Connection;
+
+        let mut db =
+            PlayerDatabase::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let mut sam = sam();
+        db.save(&sam).unwrap();
+        db.save(&PlayerRecord::new("alex")).unwrap();
+        assert_eq!(db.load("sam").unwrap(), Some(sam.clone()));
+        assert_eq!(db.list().unwrap(), vec!["alex", "sam"]);
+
+        sam.inventory.entries.clear();
+        sam.hp = 20;
+        db.save(&sam).unwrap();
+        assert_eq!(db.load("sam").unwrap(), Some(sam));
+        assert!(db.remove("sam").unwrap());
+        assert!(!db.remove("sam").unwrap());
+        assert_eq!(db.load("sam").unwrap(), None);
+    }
+}
@@ -0,0 +1,111 @@
+//!
+//! PostgreSQL maps (feature "postgres")
+//!
+//! The engine's postgresql backend, with the connection string from
+//! world.mt's pgsql_connection:
+//!
+//! ```text
+//! pgsql_connection = host=127.0.0.1 port=5432 user=mt password=... dbname=world
+//!
+//! blocks (posX INT NOT NULL, posY INT NOT NULL, posZ INT NOT NULL, data BYTEA,
+//!         PRIMARY KEY (posX, posY, posZ))
+//! ```
+//!
+//! Only plain connections are made (no TLS). The client is blocking, so it
+//! can't be used from inside a tokio runtime (use spawn_blocking).
+//!
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use anyhow::Result;
+use postgres::Client;
+use postgres::NoTls;
+
+use crate::wire::types::v3s16;
+
+use super::database::MapDatabase;
+use super::meta::WorldMt;
+
+/// A map in a PostgreSQL database.
+///
+/// The connection is shared behind a lock, like SqliteMapDatabase's.
+pub struct PostgresMapDatabase {
+    client: Mutex<Client>,
+}
+
+impl PostgresMapDatabase {
+    /// `params` is a connection string, e.g. "host=localhost dbname=world".
+    /// The blocks table is created if it doesn't exist, as the engine does.
+    pub fn connect(params: &str) -> Result<Self> {
+        let mut client = Client::connect(params, NoTls)?;
+        client.batch_execute(
+            "CREATE TABLE IF NOT EXISTS blocks (
+                posX INT NOT NULL,
+                posY INT NOT NULL,
+                posZ INT NOT NULL,
+                data BYTEA,
+                PRIMARY KEY (posX, posY, posZ)
+            )",
+        )?;
+        Ok(Self {
+            client: Mutex::new(client),
+        })
+    }
+
+    /// Connect to the database named by world.mt in world directory `dir`
+    pub fn open_world(dir: &Path) -> Result<Self> {
+        let world_mt = WorldMt::read(dir)?;
+        let params = world_mt
+            .get("pgsql_connection")
+            .context("No pgsql_connection in world.mt")?;
+        Self::connect(params)
+    }
+}
+
+impl MapDatabase for PostgresMapDatabase {
+    fn list_blocks(&self) -> Result<Vec<v3s16>> {
+        let mut client = self.client.lock().unwrap();
+        let rows = client.query("SELECT posX, posY, posZ FROM blocks", &[])?;
+        let mut positions = Vec::with_capacity(rows.len());
+        for row in rows {
+            let (x, y, z): (i32, i32, i32) = (row.try_get(0)?, row.try_get(1)?, row.try_get(2)?);
+            positions.push(v3s16::new(x as i16, y as i16, z as i16));
+        }
+        Ok(positions)
+    }
+
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>> {
+        let mut client = self.client.lock().unwrap();
+        let (x, y, z) = (pos.x as i32, pos.y as i32, pos.z as i32);
+        let row = client.query_opt(
+            "SELECT data FROM blocks WHERE posX = $1 AND posY = $2 AND posZ = $3",
+            &[&x, &y, &z],
+        )?;
+        match row {
+            Some(row) => Ok(row.try_get::<_, Option<Vec<u8>>>(0)?),
+            None => Ok(None),
+        }
+    }
+
+    fn set_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()> {
+        let client = self.client.get_mut().unwrap();
+        let (x, y, z) = (pos.x as i32, pos.y as i32, pos.z as i32);
+        client.execute(
+            "INSERT INTO blocks (posX, posY, posZ, data) VALUES ($1, $2, $3, $4)
+             ON CONFLICT ON CONSTRAINT blocks_pkey DO UPDATE SET data = $4",
+            &[&x, &y, &z, &data],
+        )?;
+        Ok(())
+    }
+
+    fn delete_block(&mut self, pos: &v3s16) -> Result<()> {
+        let client = self.client.get_mut().unwrap();
+        let (x, y, z) = (pos.x as i32, pos.y as i32, pos.z as i32);
+        client.execute(
+            "DELETE FROM blocks WHERE posX = $1 AND posY = $2 AND posZ = $3",
+            &[&x, &y, &z],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,491 @@
+//!
+//! Schematics
+//!
+//! A box of nodes in the engine's .mts format, as saved by
+//! core.create_schematic, and placed into a World like
+//! core.place_schematic does:
+//!
+//! ```text
+//! let mut schematic = Schematic::read(File::open("house.mts")?)?;
+//! schematic.param2_types.insert("stairs:stair_wood".into(), ParamType2::Facedir);
+//! let placed = place_schematic(&mut world, &pos, &schematic, 1, false, &mut rng)?;
+//! ```
+//!
+//! ```text
+//! "MTSM" u16 version (4), v3s16 size, size.y x u8 slice probability,
+//! u16 name count, names (u16 length each), zlib(nodes)
+//! nodes: every param0 (u16), then every param1, then every param2,
+//!     x fastest, then y, then z
+//! ```
+//!
+//! param1 of a schematic node is the probability of placing it, from 0
+//! (never) to 127 (always), or'ed with FORCE_PLACE to replace whatever is
+//! there. Versions 1 to 3 are read too, and converted like the engine does.
+//!
+use std::collections::HashMap;
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::bail;
+use anyhow::Result;
+use rand::Rng;
+
+use crate::wire::deser::Deserialize;
+use crate::wire::deser::Deserializer;
+use crate::wire::param2::rotate_param2_y;
+use crate::wire::param2::ParamType2;
+use crate::wire::ser::Serialize;
+use crate::wire::ser::Serializer;
+use crate::wire::ser::VecSerializer;
+use crate::wire::types::v3s16;
+use crate::wire::types::MapNode;
+use crate::wire::types::ProtocolContext;
+use crate::wire::types::MAP_BLOCKSIZE;
+use crate::wire::util::compress_zlib;
+use crate::wire::util::decompress_zlib;
+use crate::wire::util::ZLIB_DEFAULT_LEVEL;
+
+use super::database::MapDatabase;
+use super::map::World;
+
+pub const MTSCHEM_SIGNATURE: &[u8; 4] = b"MTSM";
+pub const MTSCHEM_VERSION: u16 = 4;
+
+pub const PROB_NEVER: u8 = 0x00;
+pub const PROB_ALWAYS: u8 = 0x7f;
+pub const PROB_MASK: u8 = 0x7f;
+pub const FORCE_PLACE: u8 = 0x80;
+// Probabilities went up to 0xff before version 4
+const PROB_ALWAYS_OLD: u8 = 0xff;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schematic {
+    pub size: v3s16,
+    /// The probability of placing each y slice, bottom first
+    pub slice_probs: Vec<u8>,
+    /// Node names, by param0
+    pub names: Vec<String>,
+    /// x fastest, then y, then z. param1 is the probability of placing.
+    pub nodes: Vec<MapNode>,
+    /// How the named nodes use param2, which .mts files don't say. Nodes
+    /// not listed keep their param2 when the schematic is turned.
+    pub param2_types: HashMap<String, ParamType2>,
+}
+
+impl Schematic {
+    /// A schematic of air, always placed
+    pub fn new(size: v3s16) -> Self {
+        let count = [size.x, size.y, size.z]
+            .iter()
+            .map(|v| (*v).max(0) as usize)
+            .product();
+        Self {
+            slice_probs: vec![PROB_ALWAYS; size.y.max(0) as usize],
+            size,
+            names: vec!["air".to_string()],
+            nodes: vec![
+                MapNode {
+                    param0: 0,
+                    param1: PROB_ALWAYS,
+                    param2: 0,
+                };
+                count
+            ],
+            param2_types: HashMap::new(),
+        }
+    }
+
+    /// Index in `nodes` of the node at `(x, y, z)`, from the minimum corner
+    pub fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        let (sx, sy) = (self.size.x as usize, self.size.y as usize);
+        (z * sy + y) * sx + x
+    }
+
+    pub fn node_name(&self, node: &MapNode) -> Option<&str> {
+        self.names
+            .get(node.param0 as usize)
+            .map(|name| name.as_str())
+    }
+
+    /// Sets the node at `(x, y, z)`, always placed, adding `name` to the
+    /// names if it's new
+    pub fn set_node(&mut self, x: usize, y: usize, z: usize, name: &str, param2: u8) {
+        let param0 = match self.names.iter().position(|other| other == name) {
+            Some(param0) => param0,
+            None => {
+                self.names.push(name.to_string());
+                self.names.len() - 1
+            }
+        };
+        let index = self.index(x, y, z);
+        self.nodes[index] = MapNode {
+            param0: param0 as u16,
+            param1: PROB_ALWAYS,
+            param2,
+        };
+    }
+
+    fn dims(&self) -> Result<[usize; 3]> {
+        let size = [self.size.x, self.size.y, self.size.z];
+        if size.iter().any(|v| *v < 0) {
+            bail!("Invalid schematic size {:?}", self.size);
+        }
+        let dims = size.map(|v| v as usize);
+        if self.nodes.len() != dims.iter().product::<usize>() || self.slice_probs.len() != dims[1] {
+            bail!("Schematic data doesn't match its size {:?}", self.size);
+        }
+        Ok(dims)
+    }
+
+    pub fn read<R: Read>(mut input: R) -> Result<Self> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut deser = Deserializer::new(ProtocolContext::latest_for_receive(true), &data);
+        if deser.take(4)? != &MTSCHEM_SIGNATURE[..] {
+            bail!("Not a schematic");
+        }
+        let version = u16::deserialize(&mut deser)?;
+        if version == 0 || version > MTSCHEM_VERSION {
+            bail!("Unsupported schematic version {}", version);
+        }
+        let size = v3s16::deserialize(&mut deser)?;
+        if size.x < 0 || size.y < 0 || size.z < 0 {
+            bail!("Invalid schematic size {:?}", size);
+        }
+        let mut slice_probs = if version >= 3 {
+            deser.take(size.y as usize)?.to_vec()
+        } else {
+            vec![PROB_ALWAYS_OLD; size.y as usize]
+        };
+        let count = u16::deserialize(&mut deser)?;
+        let mut names = Vec::with_capacity(count as usize);
+        // Version 1 used ignore for nodes not to place
+        let mut ignore = None;
+        for id in 0..count {
+            let name = String::deserialize(&mut deser)?;
+            if name == "ignore" {
+                ignore = Some(id);
+                names.push("air".to_string());
+            } else {
+                names.push(name);
+            }
+        }
+
+        let (_, raw) = decompress_zlib(deser.peek_all())?;
+        let count = size.x as usize * size.y as usize * size.z as usize;
+        if raw.len() < 4 * count {
+            bail!("Schematic node data is truncated");
+        }
+        let mut nodes: Vec<MapNode> = (0..count)
+            .map(|i| MapNode {
+                param0: u16::from_be_bytes([raw[2 * i], raw[2 * i + 1]]),
+                param1: raw[2 * count + i],
+                param2: raw[3 * count + i],
+            })
+            .collect();
+        if version < 2 {
+            for node in &mut nodes {
+                if node.param1 == 0 {
+                    node.param1 = PROB_ALWAYS_OLD;
+                }
+                if Some(node.param0) == ignore {
+                    node.param1 = PROB_NEVER;
+                }
+            }
+        }
+        if version < 4 {
+            for prob in &mut slice_probs {
+                *prob >>= 1;
+            }
+            for node in &mut nodes {
+                node.param1 >>= 1;
+            }
+        }
+        Ok(Self {
+            size,
+            slice_probs,
+            names,
+            nodes,
+            param2_types: HashMap::new(),
+        })
+    }
+
+    /// Save in the current version
+    pub fn write<W: Write>(&self, mut out: W) -> Result<()> {
+        self.dims()?;
+        let mut ser = VecSerializer::new(ProtocolContext::latest_for_send(false), 64);
+        ser.write_bytes(MTSCHEM_SIGNATURE)?;
+        u16::serialize(&MTSCHEM_VERSION, &mut ser)?;
+        v3s16::serialize(&self.size, &mut ser)?;
+        ser.write_bytes(&self.slice_probs)?;
+        u16::serialize(&u16::try_from(self.names.len())?, &mut ser)?;
+        for name in &self.names {
+            String::serialize(name, &mut ser)?;
+        }
+        let mut raw = Vec::with_capacity(4 * self.nodes.len());
+        for node in &self.nodes {
+            raw.extend_from_slice(&node.param0.to_be_bytes());
+        }
+        raw.extend(self.nodes.iter().map(|node| node.param1));
+        raw.extend(self.nodes.iter().map(|node| node.param2));
+        out.write_all(&ser.take())?;
+        out.write_all(&compress_zlib(&raw, ZLIB_DEFAULT_LEVEL))?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Places `schematic` with its minimum corner at `pos`, turned `turns`
+/// quarter turns around +Y (see rotate_param2_y), like
+/// core.place_schematic. Slices and nodes are placed with their
+/// probabilities. Unless `force`, only air and ignore are replaced, except
+/// by nodes with FORCE_PLACE.
+///
+/// Placed nodes have param1 (light) 0, until the engine lights them.
+/// Blocks that aren't stored are left out: they can't be generated here.
+/// Returns the number of nodes placed.
+pub fn place_schematic<D: MapDatabase, R: Rng>(
+    world: &mut World<D>,
+    pos: &v3s16,
+    schematic: &Schematic,
+    turns: i32,
+    force: bool,
+    rng: &mut R,
+) -> Result<usize> {
+    let [sx, sy, sz] = schematic.dims()?.map(|v| v as i32);
+    let turns = turns.rem_euclid(4);
+    let size = if turns % 2 == 1 {
+        [sz, sy, sx]
+    } else {
+        [sx, sy, sz]
+    };
+    if size.contains(&0) {
+        return Ok(0);
+    }
+    let slices: Vec<bool> = schematic
+        .slice_probs
+        .iter()
+        .map(|prob| roll(rng, *prob))
+        .collect();
+    // Where a node of the turned schematic comes from
+    let source = |x: i32, y: i32, z: i32| {
+        let (x, z) = match turns {
+            0 => (x, z),
+            1 => (sx - 1 - z, x),
+            2 => (sx - 1 - x, sz - 1 - z),
+            _ => (z, sz - 1 - x),
+        };
+        schematic.index(x as usize, y as usize, z as usize)
+    };
+
+    let bs = MAP_BLOCKSIZE as i32;
+    let min = [pos.x, pos.y, pos.z].map(i32::from);
+    let max = [0, 1, 2].map(|i| min[i] + size[i] - 1);
+    let mut placed = 0;
+    for bz in min[2].div_euclid(bs)..=max[2].div_euclid(bs) {
+        for by in min[1].div_euclid(bs)..=max[1].div_euclid(bs) {
+            for bx in min[0].div_euclid(bs)..=max[0].div_euclid(bs) {
+                let block_pos = v3s16::new(bx as i16, by as i16, bz as i16);
+                let Some(mut block) = world.get_block(&block_pos)? else {
+                    continue;
+                };
+                let base = [bx, by, bz].map(|v| v * bs);
+                let lo = [0, 1, 2].map(|i| min[i].max(base[i]));
+                let hi = [0, 1, 2].map(|i| max[i].min(base[i] + bs - 1));
+                let mut changed = false;
+                for z in lo[2]..=hi[2] {
+                    for y in lo[1]..=hi[1] {
+                        if !slices[(y - min[1]) as usize] {
+                            continue;
+                        }
+                        for x in lo[0]..=hi[0] {
+                            let node = schematic.nodes[source(x - min[0], y - min[1], z - min[2])];
+                            let Some(name) = schematic.node_name(&node) else {
+                                continue;
+                            };
+                            let prob = node.param1 & PROB_MASK;
+                            if name == "ignore" || prob == PROB_NEVER {
+                                continue;
+                            }
+                            let i = ((z - base[2]) * bs * bs + (y - base[1]) * bs + (x - base[0]))
+                                as usize;
+                            if !force && node.param1 & FORCE_PLACE == 0 {
+                                match block.node_name(&block.nodes[i]) {
+                                    Some("air") | Some("ignore") => (),
+                                    _ => continue,
+                                }
+                            }
+                            if !roll(rng, prob) {
+                                continue;
+                            }
+                            let param2 = match schematic.param2_types.get(name) {
+                                Some(ptype) => rotate_param2_y(*ptype, node.param2, turns),
+                                None => node.param2,
+                            };
+                            block.nodes[i] = MapNode {
+                                param0: block.name_id_mapping.get_or_insert(name)?,
+                                param1: 0,
+                                param2,
+                            };
+                            changed = true;
+                            placed += 1;
+                        }
+                    }
+                }
+                if changed {
+                    world.set_block(&block_pos, &block)?;
+                }
+            }
+        }
+    }
+    Ok(placed)
+}
+
+/// True with probability `prob` (out of PROB_ALWAYS)
+fn roll<R: Rng>(rng: &mut R, prob: u8) -> bool {
+    prob >= PROB_ALWAYS || rng.gen_range(1..=PROB_ALWAYS) <= prob
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::world::block::DiskMapBlock;
+    use crate::world::database::MemoryMapDatabase;
+
+    /// An L: stone at (0, 0, 0) and (1, 0, 0), and a stair facing +Z at
+    /// (0, 0, 1). The corner (1, 0, 1) and the y = 1 slice are never placed.
+    fn stair_l() -> Schematic {
+        let mut schematic = Schematic::new(v3s16::new(2, 2, 2));
+        let corner = schematic.index(1, 0, 1);
+        schematic.nodes[corner].param1 = PROB_NEVER;
+        schematic.set_node(0, 0, 0, "default:stone", 0);
+        schematic.set_node(1, 0, 0, "default:stone", 0);
+        schematic.set_node(0, 0, 1, "stairs:stair", 0);
+        schematic.slice_probs[1] = PROB_NEVER;
+        schematic
+            .param2_types
+            .insert("stairs:stair".to_string(), ParamType2::Facedir);
+        schematic
+    }
+
+    fn world_of_air() -> World<MemoryMapDatabase> {
+        let mut world = World::new(MemoryMapDatabase::new());
+        let mut block = DiskMapBlock::new();
+        block.name_id_mapping.insert(0, "air".to_string());
+        block.name_id_mapping.insert(1, "default:dirt".to_string());
+        block.nodes[0].param0 = 1;
+        for pos in [v3s16::new(0, 0, 0), v3s16::new(-1, 0, 0)] {
+            world.set_block(&pos, &block).unwrap();
+        }
+        world
+    }
+
+    fn name_at(world: &World<MemoryMapDatabase>, x: i16, z: i16) -> (String, u8) {
+        let node = world
+            .nodes_in_area(v3s16::new(x, 0, z), v3s16::new(x, 0, z))
+            .next()
+            .unwrap()
+            .unwrap();
+        (node.name.to_string(), node.param2)
+    }
+
+    #[test]
+    fn read_write() {
+        let schematic = stair_l();
+        let mut data = Vec::new();
+        schematic.write(&mut data).unwrap();
+        assert_eq!(&data[..12], b"MTSM\x00\x04\x00\x02\x00\x02\x00\x02");
+        let mut read = Schematic::read(&data[..]).unwrap();
+        assert!(read.param2_types.is_empty());
+        read.param2_types = schematic.param2_types.clone();
+        assert_eq!(read, schematic);
+
+        // Version 3 probabilities went up to 0xff
+        data[5] = 3;
+        data[12] = 0xff;
+        let old = Schematic::read(&data[..]).unwrap();
+        assert_eq!(old.slice_probs, vec![PROB_ALWAYS, PROB_NEVER]);
+        assert_eq!(old.nodes[0].param1, PROB_ALWAYS >> 1);
+        assert!(Schematic::read(&b"MTSX"[..]).is_err());
+    }
+
+    #[test]
+    fn placement() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let schematic = stair_l();
+        let mut world = world_of_air();
+        let placed = place_schematic(
+            &mut world,
+            &v3s16::new(0, 0, 0),
+            &schematic,
+            0,
+            false,
+            &mut rng,
+        )
+        .unwrap();
+        // The dirt stays, and so does the air under the never placed slice
+        assert_eq!(placed, 2);
+        assert_eq!(name_at(&world, 0, 0).0, "default:dirt");
+        assert_eq!(name_at(&world, 1, 0).0, "default:stone");
+        assert_eq!(name_at(&world, 0, 1), ("stairs:stair".to_string(), 0));
+        assert_eq!(name_at(&world, 1, 1).0, "air");
+
+        // A quarter turn, across the block boundary at x = 0
+        let mut world = world_of_air();
+        let placed = place_schematic(
+            &mut world,
+            &v3s16::new(-1, 0, 0),
+            &schematic,
+            1,
+            true,
+            &mut rng,
+        )
+        .unwrap();
+        assert_eq!(placed, 3);
+        assert_eq!(name_at(&world, -1, 1).0, "default:stone");
+        assert_eq!(name_at(&world, -1, 0).0, "default:stone");
+        // The stair faces +X now
+        assert_eq!(name_at(&world, 0, 1), ("stairs:stair".to_string(), 1));
+        assert_eq!(name_at(&world, 0, 0).0, "default:dirt");
+
+        // Missing blocks are left out
+        let placed = place_schematic(
+            &mut world,
+            &v3s16::new(0, 16, 0),
+            &schematic,
+            0,
+            true,
+            &mut rng,
+        )
+        .unwrap();
+        assert_eq!(placed, 0);
+    }
+
+    #[test]
+    fn probabilities() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut schematic = Schematic::new(v3s16::new(16, 1, 16));
+        for x in 0..16 {
+            for z in 0..16 {
+                schematic.set_node(x, 0, z, "default:stone", 0);
+            }
+        }
+        for node in &mut schematic.nodes {
+            node.param1 = PROB_ALWAYS / 2;
+        }
+        let mut world = world_of_air();
+        let placed = place_schematic(
+            &mut world,
+            &v3s16::new(0, 0, 0),
+            &schematic,
+            0,
+            true,
+            &mut rng,
+        )
+        .unwrap();
+        assert!((64..192).contains(&placed), "{}", placed);
+    }
+}
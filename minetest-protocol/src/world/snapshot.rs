@@ -0,0 +1,266 @@
+//!
+//! Map snapshots
+//!
+//! A backup taken while the server runs must see the map as it was at one
+//! moment, not half old and half new. SnapshotMapDatabase sits in front of
+//! a MapDatabase (e.g. under a Persistence), and hands out snapshots that
+//! a backup can read on another thread while the server keeps writing:
+//!
+//! ```text
+//! let mut db = SnapshotMapDatabase::new(SqliteMapDatabase::open_read_write(path)?);
+//! let snapshot = db.snapshot();
+//! std::thread::spawn(move || export_world(dir, &snapshot, out));
+//! db.set_block(&pos, &data)?;   // the snapshot still has the old block
+//! ```
+//!
+//! Snapshots are copy-on-write: the first write to a block while a
+//! snapshot is alive keeps the block as it was, for that snapshot. Nothing
+//! is copied when a snapshot is taken. Snapshots read the database without
+//! holding up the bookkeeping, so a write only waits for the database read
+//! a snapshot is doing at the time (one block, or the list of blocks). The
+//! blocks kept are freed when the snapshot is dropped, so a snapshot
+//! shouldn't outlive its backup.
+//!
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::wire::types::v3s16;
+
+use super::database::MapDatabase;
+
+/// Blocks as they were when the snapshot was taken, for the blocks written
+/// since. None if there was no block.
+type Kept = HashMap<v3s16, Option<Vec<u8>>>;
+
+#[derive(Default)]
+struct Snapshots {
+    next_id: u64,
+    live: BTreeMap<u64, Kept>,
+}
+
+struct Shared<D> {
+    /// Locked before db, when both are
+    snapshots: Mutex<Snapshots>,
+    db: RwLock<D>,
+}
+
+impl<D> Shared<D> {
+    fn snapshots(&self) -> MutexGuard<'_, Snapshots> {
+        self.snapshots.lock().unwrap()
+    }
+}
+
+pub struct SnapshotMapDatabase<D: MapDatabase> {
+    shared: Arc<Shared<D>>,
+}
+
+impl<D: MapDatabase> SnapshotMapDatabase<D> {
+    pub fn new(db: D) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                snapshots: Mutex::new(Snapshots::default()),
+                db: RwLock::new(db),
+            }),
+        }
+    }
+
+    /// The map as it is now
+    pub fn snapshot(&self) -> MapSnapshot<D> {
+        let mut snapshots = self.shared.snapshots();
+        let id = snapshots.next_id;
+        snapshots.next_id += 1;
+        snapshots.live.insert(id, Kept::new());
+        MapSnapshot {
+            shared: self.shared.clone(),
+            id,
+        }
+    }
+
+    /// Snapshots not dropped yet
+    pub fn live_snapshots(&self) -> usize {
+        self.shared.snapshots().live.len()
+    }
+
+    /// Blocks kept for the live snapshots
+    pub fn kept_blocks(&self) -> usize {
+        self.shared
+            .snapshots()
+            .live
+            .values()
+            .map(|kept| kept.len())
+            .sum()
+    }
+
+    /// Runs `f` on the database underneath, e.g. to flush it
+    pub fn with_database<R>(&self, f: impl FnOnce(&D) -> R) -> R {
+        f(&self.shared.db.read().unwrap())
+    }
+
+    /// Keeps the block at `pos` for the snapshots that don't have it yet,
+    /// then writes it
+    fn write(&mut self, pos: &v3s16, write: impl FnOnce(&mut D) -> Result<()>) -> Result<()> {
+        let mut snapshots = self.shared.snapshots();
+        let mut db = self.shared.db.write().unwrap();
+        if snapshots.live.values().any(|kept| !kept.contains_key(pos)) {
+            let old = db.get_block(pos)?;
+            for kept in snapshots.live.values_mut() {
+                kept.entry(pos.clone()).or_insert_with(|| old.clone());
+            }
+        }
+        write(&mut db)
+    }
+}
+
+impl<D: MapDatabase> MapDatabase for SnapshotMapDatabase<D> {
+    fn list_blocks(&self) -> Result<Vec<v3s16>> {
+        self.shared.db.read().unwrap().list_blocks()
+    }
+
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>> {
+        self.shared.db.read().unwrap().get_block(pos)
+    }
+
+    fn set_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()> {
+        self.write(pos, |db| db.set_block(pos, data))
+    }
+
+    fn delete_block(&mut self, pos: &v3s16) -> Result<()> {
+        self.write(pos, |db| db.delete_block(pos))
+    }
+}
+
+/// The map as it was when the snapshot was taken. Read-only.
+pub struct MapSnapshot<D: MapDatabase> {
+    shared: Arc<Shared<D>>,
+    id: u64,
+}
+
+impl<D: MapDatabase> MapSnapshot<D> {
+    fn kept(&self, pos: &v3s16) -> Option<Option<Vec<u8>>> {
+        self.shared.snapshots().live[&self.id].get(pos).cloned()
+    }
+}
+
+impl<D: MapDatabase> MapDatabase for MapSnapshot<D> {
+    fn list_blocks(&self) -> Result<Vec<v3s16>> {
+        let mut blocks: HashSet<v3s16> = self
+            .shared
+            .db
+            .read()
+            .unwrap()
+            .list_blocks()?
+            .into_iter()
+            .collect();
+        // Every block written since the snapshot, before or after the
+        // listing, is kept by now
+        let snapshots = self.shared.snapshots();
+        for (pos, old) in &snapshots.live[&self.id] {
+            if old.is_some() {
+                blocks.insert(pos.clone());
+            } else {
+                blocks.remove(pos);
+            }
+        }
+        Ok(blocks.into_iter().collect())
+    }
+
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>> {
+        if let Some(old) = self.kept(pos) {
+            return Ok(old);
+        }
+        let current = self.shared.db.read().unwrap().get_block(pos)?;
+        // Written since the check, the old block is kept now
+        Ok(self.kept(pos).unwrap_or(current))
+    }
+
+    fn set_block(&mut self, _pos: &v3s16, _data: &[u8]) -> Result<()> {
+        bail!("Snapshots are read-only")
+    }
+
+    fn delete_block(&mut self, _pos: &v3s16) -> Result<()> {
+        bail!("Snapshots are read-only")
+    }
+}
+
+impl<D: MapDatabase> Drop for MapSnapshot<D> {
+    fn drop(&mut self) {
+        self.shared.snapshots().live.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::database::block_key;
+    use crate::world::MemoryMapDatabase;
+
+    fn pos(x: i16) -> v3s16 {
+        v3s16::new(x, 0, 0)
+    }
+
+    fn sorted(mut blocks: Vec<v3s16>) -> Vec<v3s16> {
+        blocks.sort_by_key(block_key);
+        blocks
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let mut memory = MemoryMapDatabase::new();
+        memory.insert(&pos(0), vec![0]);
+        memory.insert(&pos(1), vec![1]);
+        let mut db = SnapshotMapDatabase::new(memory);
+
+        let first = db.snapshot();
+        db.set_block(&pos(0), &[10]).unwrap();
+        db.delete_block(&pos(1)).unwrap();
+        db.set_block(&pos(2), &[2]).unwrap();
+        let second = db.snapshot();
+        db.set_block(&pos(0), &[20]).unwrap();
+        assert_eq!(db.kept_blocks(), 4);
+
+        assert_eq!(first.get_block(&pos(0)).unwrap(), Some(vec![0]));
+        assert_eq!(first.get_block(&pos(1)).unwrap(), Some(vec![1]));
+        assert_eq!(first.get_block(&pos(2)).unwrap(), None);
+        assert_eq!(sorted(first.list_blocks().unwrap()), vec![pos(0), pos(1)]);
+        assert_eq!(second.get_block(&pos(0)).unwrap(), Some(vec![10]));
+        assert_eq!(sorted(second.list_blocks().unwrap()), vec![pos(0), pos(2)]);
+        assert_eq!(db.get_block(&pos(0)).unwrap(), Some(vec![20]));
+
+        drop(first);
+        assert_eq!(db.live_snapshots(), 1);
+        assert_eq!(db.kept_blocks(), 1);
+        drop(second);
+        assert_eq!(db.kept_blocks(), 0);
+        db.set_block(&pos(3), &[3]).unwrap();
+        assert_eq!(db.kept_blocks(), 0);
+    }
+
+    #[test]
+    fn backup_while_writing() {
+        let mut memory = MemoryMapDatabase::new();
+        for x in 0..100 {
+            memory.insert(&pos(x), vec![0]);
+        }
+        let mut db = SnapshotMapDatabase::new(memory);
+        let snapshot = db.snapshot();
+        let backup = std::thread::spawn(move || {
+            let mut blocks = Vec::new();
+            for block in sorted(snapshot.list_blocks().unwrap()) {
+                blocks.push(snapshot.get_block(&block).unwrap().unwrap());
+            }
+            blocks
+        });
+        for x in 0..100 {
+            db.set_block(&pos(x), &[1]).unwrap();
+        }
+        assert_eq!(backup.join().unwrap(), vec![vec![0]; 100]);
+    }
+}
@@ -0,0 +1,208 @@
+//!
+//! map.sqlite (feature "sqlite")
+//!
+//! The engine's default map backend. Two schemas are in use:
+//!
+//! ```text
+//! blocks (pos INT PRIMARY KEY, data BLOB)            block_key(pos)
+//! blocks (x INTEGER, y INTEGER, z INTEGER, data BLOB NOT NULL,
+//!         PRIMARY KEY (x, z, y))                     newer engines
+//! ```
+//!
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::bail;
+use anyhow::Result;
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
+
+use crate::wire::types::v3s16;
+
+use super::database::block_key;
+use super::database::block_pos;
+use super::database::MapDatabase;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Schema {
+    /// One integer key per block
+    Pos,
+    /// Separate x, y, z columns
+    Xyz,
+}
+
+/// A map.sqlite. Opened read-only with open, read-write with open_read_write
+/// or create.
+///
+/// The connection is shared behind a lock, so a parallel scan reads blocks
+/// one at a time but decodes them on all threads.
+pub struct SqliteMapDatabase {
+    conn: Mutex<Connection>,
+    schema: Schema,
+}
+
+impl SqliteMapDatabase {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Self::from_connection(conn)
+    }
+
+    pub fn open_read_write(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        Self::from_connection(conn)
+    }
+
+    /// Open for writing, creating the database (with the engine's legacy
+    /// schema, which every version reads) if it doesn't exist
+    pub fn create(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS blocks (pos INT PRIMARY KEY, data BLOB)",
+            [],
+        )?;
+        Self::from_connection(conn)
+    }
+
+    /// Open the map.sqlite in world directory `dir`
+    pub fn open_world(dir: &Path) -> Result<Self> {
+        Self::open(&dir.join("map.sqlite"))
+    }
+
+    pub fn from_connection(conn: Connection) -> Result<Self> {
+        let columns: Vec<String> = {
+            let mut stmt = conn.prepare("PRAGMA table_info(blocks)")?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(1))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let has = |name: &str| columns.iter().any(|column| column == name);
+        let schema = if has("pos") {
+            Schema::Pos
+        } else if has("x") && has("y") && has("z") {
+            Schema::Xyz
+        } else {
+            bail!("Not a map database (no blocks table)");
+        };
+        Ok(Self {
+            conn: Mutex::new(conn),
+            schema,
+        })
+    }
+
+    /// Store blocks (replacing any already there), in one transaction
+    pub fn set_blocks(&self, blocks: &[(v3s16, Vec<u8>)]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = match self.schema {
+                Schema::Pos => {
+                    tx.prepare("INSERT OR REPLACE INTO blocks (pos, data) VALUES (?1, ?2)")?
+                }
+                Schema::Xyz => tx.prepare(
+                    "INSERT OR REPLACE INTO blocks (x, y, z, data) VALUES (?1, ?2, ?3, ?4)",
+                )?,
+            };
+            for (pos, data) in blocks {
+                match self.schema {
+                    Schema::Pos => stmt.execute(rusqlite::params![block_key(pos), data])?,
+                    Schema::Xyz => stmt.execute(rusqlite::params![pos.x, pos.y, pos.z, data])?,
+                };
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl MapDatabase for SqliteMapDatabase {
+    fn list_blocks(&self) -> Result<Vec<v3s16>> {
+        let conn = self.conn.lock().unwrap();
+        let positions = match self.schema {
+            Schema::Pos => {
+                let mut stmt = conn.prepare("SELECT pos FROM blocks")?;
+                let rows = stmt.query_map([], |row| Ok(block_pos(row.get(0)?)))?;
+                rows.collect::<rusqlite::Result<_>>()?
+            }
+            Schema::Xyz => {
+                let mut stmt = conn.prepare("SELECT x, y, z FROM blocks")?;
+                let rows = stmt.query_map([], |row| {
+                    Ok(v3s16::new(row.get(0)?, row.get(1)?, row.get(2)?))
+                })?;
+                rows.collect::<rusqlite::Result<_>>()?
+            }
+        };
+        Ok(positions)
+    }
+
+    fn get_block(&self, pos: &v3s16) -> Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock().unwrap();
+        let data = match self.schema {
+            Schema::Pos => conn
+                .prepare_cached("SELECT data FROM blocks WHERE pos = ?1")?
+                .query_row([block_key(pos)], |row| row.get(0))
+                .optional()?,
+            Schema::Xyz => conn
+                .prepare_cached("SELECT data FROM blocks WHERE x = ?1 AND y = ?2 AND z = ?3")?
+                .query_row([pos.x, pos.y, pos.z], |row| row.get(0))
+                .optional()?,
+        };
+        Ok(data)
+    }
+
+    fn set_block(&mut self, pos: &v3s16, data: &[u8]) -> Result<()> {
+        self.set_blocks(&[(pos.clone(), data.to_vec())])
+    }
+
+    fn delete_block(&mut self, pos: &v3s16) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        match self.schema {
+            Schema::Pos => conn
+                .prepare_cached("DELETE FROM blocks WHERE pos = ?1")?
+                .execute([block_key(pos)])?,
+            Schema::Xyz => conn
+                .prepare_cached("DELETE FROM blocks WHERE x = ?1 AND y = ?2 AND z = ?3")?
+                .execute([pos.x, pos.y, pos.z])?,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_schemas() {
+        let legacy = Connection::open_in_memory().unwrap();
+        legacy
+            .execute_batch(
+                "CREATE TABLE blocks (pos INT PRIMARY KEY, data BLOB);
+                 INSERT INTO blocks VALUES (-1, x'1d01');
+                 INSERT INTO blocks VALUES (50339841, x'1d02');",
+            )
+            .unwrap();
+        let xyz = Connection::open_in_memory().unwrap();
+        xyz.execute_batch(
+            "CREATE TABLE blocks (x INTEGER, y INTEGER, z INTEGER, data BLOB NOT NULL,
+                                  PRIMARY KEY (x, z, y));
+             INSERT INTO blocks VALUES (-1, 0, 0, x'1d01');
+             INSERT INTO blocks VALUES (1, 2, 3, x'1d02');",
+        )
+        .unwrap();
+        for conn in [legacy, xyz] {
+            let db = SqliteMapDatabase::from_connection(conn).unwrap();
+            db.set_blocks(&[(v3s16::new(-1, 0, 0), vec![29, 1])])
+                .unwrap();
+            let mut positions = db.list_blocks().unwrap();
+            positions.sort_by_key(block_key);
+            assert_eq!(positions, vec![v3s16::new(-1, 0, 0), v3s16::new(1, 2, 3)]);
+            assert_eq!(
+                db.get_block(&v3s16::new(1, 2, 3)).unwrap(),
+                Some(vec![29, 2])
+            );
+            assert_eq!(db.get_block(&v3s16::new(0, 0, 0)).unwrap(), None);
+        }
+        let empty = Connection::open_in_memory().unwrap();
+        assert!(SqliteMapDatabase::from_connection(empty).is_err());
+    }
+}
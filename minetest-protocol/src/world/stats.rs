@@ -0,0 +1,185 @@
+//!
+//! World statistics
+//!
+//! Node histograms, per-mod node counts, generation coverage and node
+//! metadata counts, gathered with a parallel scan.
+//!
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rayon::prelude::*;
+
+use crate::wire::types::v3s16;
+
+use super::block::DiskMapBlock;
+use super::database::MapDatabase;
+use super::map::UNKNOWN_NODE_NAME;
+use super::scan::par_map_blocks;
+
+/// The mod a node name belongs to: "default" for "default:stone".
+/// Engine nodes ("air", "ignore", "unknown") have no prefix, and give "".
+pub fn mod_name(name: &str) -> &str {
+    name.split_once(':').map_or("", |(prefix, _)| prefix)
+}
+
+/// Without allocating the name when it's already there
+fn add(counts: &mut BTreeMap<String, u64>, name: &str, count: u64) {
+    match counts.get_mut(name) {
+        Some(total) => *total += count,
+        None => {
+            counts.insert(name.to_string(), count);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorldStats {
+    pub blocks: u64,
+    /// Blocks the map generator has finished
+    pub generated: u64,
+    /// Blocks that couldn't be loaded or decoded
+    pub unreadable: u64,
+    /// The smallest box (in block positions) containing every block
+    pub extent: Option<(v3s16, v3s16)>,
+    /// Node name to count
+    pub nodes: BTreeMap<String, u64>,
+    /// Node name to the number of those nodes with metadata
+    pub metadata: BTreeMap<String, u64>,
+}
+
+impl WorldStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scan every block in `db`
+    pub fn scan<D: MapDatabase + Sync + ?Sized>(db: &D) -> Result<Self> {
+        Ok(par_map_blocks(db, |pos, block| {
+            let mut stats = Self::new();
+            match block {
+                Ok(block) => stats.add_block(pos, block),
+                Err(_) => stats.unreadable += 1,
+            }
+            stats
+        })?
+        .reduce(Self::new, Self::merged))
+    }
+
+    pub fn add_block(&mut self, pos: &v3s16, block: &DiskMapBlock) {
+        self.blocks += 1;
+        if block.generated {
+            self.generated += 1;
+        }
+        self.extend(pos, pos);
+
+        let mut counts: Vec<u64> = Vec::new();
+        for node in block.nodes.iter() {
+            let id = node.param0 as usize;
+            if counts.len() <= id {
+                counts.resize(id + 1, 0);
+            }
+            counts[id] += 1;
+        }
+        for (id, count) in counts.into_iter().enumerate() {
+            if count > 0 {
+                let name = block.name_id_mapping.get(id as u16);
+                add(&mut self.nodes, name.unwrap_or(UNKNOWN_NODE_NAME), count);
+            }
+        }
+        for (pos, _) in &block.node_metadata.metadata {
+            let name = block
+                .nodes
+                .get(pos.raw as usize)
+                .and_then(|node| block.node_name(node));
+            add(&mut self.metadata, name.unwrap_or(UNKNOWN_NODE_NAME), 1);
+        }
+    }
+
+    fn extend(&mut self, min: &v3s16, max: &v3s16) {
+        self.extent = Some(match self.extent.take() {
+            None => (min.clone(), max.clone()),
+            Some((lo, hi)) => (
+                v3s16::new(lo.x.min(min.x), lo.y.min(min.y), lo.z.min(min.z)),
+                v3s16::new(hi.x.max(max.x), hi.y.max(max.y), hi.z.max(max.z)),
+            ),
+        });
+    }
+
+    pub fn merge(&mut self, other: WorldStats) {
+        self.blocks += other.blocks;
+        self.generated += other.generated;
+        self.unreadable += other.unreadable;
+        if let Some((min, max)) = other.extent {
+            self.extend(&min, &max);
+        }
+        for (name, count) in other.nodes {
+            *self.nodes.entry(name).or_default() += count;
+        }
+        for (name, count) in other.metadata {
+            *self.metadata.entry(name).or_default() += count;
+        }
+    }
+
+    pub fn merged(mut self, other: WorldStats) -> Self {
+        self.merge(other);
+        self
+    }
+
+    /// Node counts per mod (see mod_name)
+    pub fn mods(&self) -> BTreeMap<&str, u64> {
+        let mut mods = BTreeMap::new();
+        for (name, count) in &self.nodes {
+            *mods.entry(mod_name(name)).or_default() += count;
+        }
+        mods
+    }
+
+    /// Nodes with metadata, of any kind
+    pub fn metadata_total(&self) -> u64 {
+        self.metadata.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::block::tests::encode_v29;
+    use crate::world::block::tests::sample_nodes;
+    use crate::world::database::MemoryMapDatabase;
+
+    #[test]
+    fn scan_stats() {
+        assert_eq!(mod_name("default:stone"), "default");
+        assert_eq!(mod_name("air"), "");
+
+        let nodes = sample_nodes();
+        let stone = nodes.nodes.iter().filter(|node| node.param0 == 1).count() as u64;
+        let mut db = MemoryMapDatabase::new();
+        db.insert(
+            &v3s16::new(-3, 0, 1),
+            encode_v29(&["air", "default:stone"], &nodes, 0),
+        );
+        db.insert(
+            &v3s16::new(2, -1, 0),
+            encode_v29(&["moreores:mithril", "default:dirt"], &nodes, 0),
+        );
+        db.insert(&v3s16::new(0, 5, 0), vec![29]);
+
+        let stats = WorldStats::scan(&db).unwrap();
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.generated, 2);
+        assert_eq!(stats.unreadable, 1);
+        assert_eq!(
+            stats.extent,
+            Some((v3s16::new(-3, -1, 0), v3s16::new(2, 0, 1)))
+        );
+        assert_eq!(stats.nodes["default:stone"], stone);
+        assert_eq!(stats.nodes["air"], 4096 - stone);
+        assert_eq!(stats.nodes["default:dirt"], stone);
+        let mods = stats.mods();
+        assert_eq!(mods["default"], 2 * stone);
+        assert_eq!(mods["moreores"], 4096 - stone);
+        assert_eq!(mods[""], 4096 - stone);
+        assert_eq!(stats.metadata_total(), 0);
+    }
+}
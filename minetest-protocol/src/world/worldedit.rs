@@ -0,0 +1,634 @@
+//!
+//! WorldEdit files
+//!
+//! Regions saved by the WorldEdit mod (//save, //load), as .we files. The
+//! current version 5 is a header and a serialized Lua table of the nodes
+//! that aren't air, at positions relative to the region's minimum corner:
+//!
+//! ```text
+//! 5:return {{["x"] = 0, ["y"] = 0, ["z"] = 0, ["name"] = "default:chest",
+//!     ["param2"] = 2, ["meta"] = {["fields"] = {["infotext"] = "Chest"},
+//!     ["inventory"] = {["main"] = {"default:apple 3", "", ...}}}}, ...}
+//! ```
+//!
+//! param1, param2 and meta are left out when empty. Version 4 is the same
+//! table without the header. Version 3 is a list, one node per line:
+//!
+//! ```text
+//! <x> <y> <z> <name> <param1> <param2>
+//! ```
+//!
+//! Versions 3 to 5 are read, and version 5 is written. To place a region,
+//! turn it into a Schematic (see place_schematic); node metadata stays in
+//! the WorldEditFile.
+//!
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::wire::types::v3s16;
+use crate::wire::types::MapNode;
+
+use super::schematic::Schematic;
+use super::schematic::FORCE_PLACE;
+use super::schematic::PROB_ALWAYS;
+use super::schematic::PROB_NEVER;
+
+pub const WORLDEDIT_VERSION: u32 = 5;
+
+/// Node metadata, as minetest.get_meta(pos):to_table() gives it
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WeMeta {
+    pub fields: BTreeMap<String, String>,
+    /// Item strings in each list, "" for an empty slot
+    pub inventory: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeNode {
+    /// Relative to the region
+    pub pos: v3s16,
+    pub name: String,
+    pub param1: u8,
+    pub param2: u8,
+    pub meta: Option<WeMeta>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WorldEditFile {
+    pub nodes: Vec<WeNode>,
+}
+
+impl WorldEditFile {
+    pub fn read<R: Read>(mut input: R) -> Result<Self> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let (version, content) = split_header(&data);
+        match version {
+            Some(3) => read_list(content),
+            Some(4) | Some(5) => read_table(content),
+            Some(version) => bail!("Unsupported WorldEdit version {}", version),
+            None if content.contains(&b'{') => read_table(content),
+            None => read_list(content),
+        }
+    }
+
+    /// Save in the current version
+    pub fn write<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut text = format!("{}:return {{", WORLDEDIT_VERSION).into_bytes();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if i > 0 {
+                text.extend_from_slice(b", ");
+            }
+            let mut fields = vec![
+                ("x", LuaValue::Number(node.pos.x as f64)),
+                ("y", LuaValue::Number(node.pos.y as f64)),
+                ("z", LuaValue::Number(node.pos.z as f64)),
+                ("name", LuaValue::String(node.name.clone().into_bytes())),
+            ];
+            if node.param1 != 0 {
+                fields.push(("param1", LuaValue::Number(node.param1 as f64)));
+            }
+            if node.param2 != 0 {
+                fields.push(("param2", LuaValue::Number(node.param2 as f64)));
+            }
+            if let Some(meta) = &node.meta {
+                fields.push(("meta", meta_to_lua(meta)));
+            }
+            LuaValue::record(fields).write(&mut text);
+        }
+        text.push(b'}');
+        out.write_all(&text)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// The box from the origin to the farthest node, with the nodes in it
+    /// forced into place and everything else not placed at all, like
+    /// //load does. param1 is dropped.
+    pub fn to_schematic(&self) -> Result<Schematic> {
+        let mut size = [0i32; 3];
+        for node in &self.nodes {
+            let pos = [node.pos.x, node.pos.y, node.pos.z];
+            if pos.iter().any(|v| *v < 0) {
+                bail!("WorldEdit node at negative position {:?}", node.pos);
+            }
+            for i in 0..3 {
+                size[i] = size[i].max(pos[i] as i32 + 1);
+            }
+        }
+        let size = size.map(|v| i16::try_from(v).unwrap_or(i16::MAX));
+        let mut schematic = Schematic::new(v3s16::new(size[0], size[1], size[2]));
+        for node in &mut schematic.nodes {
+            node.param1 = PROB_NEVER;
+        }
+        for node in &self.nodes {
+            let (x, y, z) = (
+                node.pos.x as usize,
+                node.pos.y as usize,
+                node.pos.z as usize,
+            );
+            schematic.set_node(x, y, z, &node.name, node.param2);
+            let index = schematic.index(x, y, z);
+            schematic.nodes[index].param1 = PROB_ALWAYS | FORCE_PLACE;
+        }
+        Ok(schematic)
+    }
+
+    /// The nodes of `schematic` that would be placed, except air, like
+    /// //save keeps them
+    pub fn from_schematic(schematic: &Schematic) -> Self {
+        let mut nodes = Vec::new();
+        let size = [schematic.size.x, schematic.size.y, schematic.size.z].map(|v| v.max(0));
+        for z in 0..size[2] {
+            for y in 0..size[1] {
+                for x in 0..size[0] {
+                    let index = schematic.index(x as usize, y as usize, z as usize);
+                    let Some(&MapNode {
+                        param0,
+                        param1,
+                        param2,
+                    }) = schematic.nodes.get(index)
+                    else {
+                        continue;
+                    };
+                    let name = schematic
+                        .names
+                        .get(param0 as usize)
+                        .map(|name| name.as_str());
+                    let Some(name) = name.filter(|name| *name != "air" && *name != "ignore") else {
+                        continue;
+                    };
+                    if param1 & !FORCE_PLACE == PROB_NEVER {
+                        continue;
+                    }
+                    nodes.push(WeNode {
+                        pos: v3s16::new(x, y, z),
+                        name: name.to_string(),
+                        param1: 0,
+                        param2,
+                        meta: None,
+                    });
+                }
+            }
+        }
+        Self { nodes }
+    }
+}
+
+/// The version in the header ("5:" or "5,extra,fields:"), and what follows
+fn split_header(data: &[u8]) -> (Option<u32>, &[u8]) {
+    let digits = data.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 || !matches!(data.get(digits), Some(b',') | Some(b':')) {
+        return (None, data);
+    }
+    let Some(end) = data.iter().position(|c| *c == b':') else {
+        return (None, data);
+    };
+    let version = std::str::from_utf8(&data[..digits])
+        .ok()
+        .and_then(|v| v.parse().ok());
+    (version, &data[end + 1..])
+}
+
+fn read_list(content: &[u8]) -> Result<WorldEditFile> {
+    let mut nodes = Vec::new();
+    for line in String::from_utf8_lossy(content).lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let [x, y, z, name, param1, param2] = words[..] else {
+            bail!("Invalid WorldEdit line: {}", line);
+        };
+        nodes.push(WeNode {
+            pos: v3s16::new(x.parse()?, y.parse()?, z.parse()?),
+            name: name.to_string(),
+            param1: param1.parse()?,
+            param2: param2.parse()?,
+            meta: None,
+        });
+    }
+    Ok(WorldEditFile { nodes })
+}
+
+fn read_table(content: &[u8]) -> Result<WorldEditFile> {
+    let mut parser = LuaParser {
+        input: content,
+        pos: 0,
+    };
+    if parser.peek_ident() == Some(b"return") {
+        parser.pos += b"return".len();
+    }
+    let table = parser.value()?;
+    parser.skip_space();
+    if parser.pos != content.len() {
+        bail!("Unexpected data after the WorldEdit table");
+    }
+    let LuaValue::Table(entries) = table else {
+        bail!("WorldEdit data is not a table");
+    };
+    let mut nodes = Vec::with_capacity(entries.len());
+    for (_, node) in &entries {
+        let coord = |key: &str| -> Result<i16> { node.get(key).as_int() };
+        let param = |key: &str| -> Result<u8> {
+            match node.get(key) {
+                LuaValue::Nil => Ok(0),
+                value => Ok(value.as_int()?),
+            }
+        };
+        let LuaValue::String(name) = node.get("name") else {
+            bail!("WorldEdit node without a name");
+        };
+        nodes.push(WeNode {
+            pos: v3s16::new(coord("x")?, coord("y")?, coord("z")?),
+            name: String::from_utf8_lossy(name).into_owned(),
+            param1: param("param1")?,
+            param2: param("param2")?,
+            meta: meta_from_lua(node.get("meta"))?,
+        });
+    }
+    Ok(WorldEditFile { nodes })
+}
+
+fn meta_from_lua(meta: &LuaValue) -> Result<Option<WeMeta>> {
+    if let LuaValue::Nil = meta {
+        return Ok(None);
+    }
+    let mut result = WeMeta::default();
+    for (key, value) in meta.get("fields").entries() {
+        result.fields.insert(key.as_string()?, value.as_string()?);
+    }
+    for (key, list) in meta.get("inventory").entries() {
+        let items = list
+            .entries()
+            .iter()
+            .map(|(_, item)| item.as_string())
+            .collect::<Result<_>>()?;
+        result.inventory.insert(key.as_string()?, items);
+    }
+    Ok(Some(result))
+}
+
+fn meta_to_lua(meta: &WeMeta) -> LuaValue {
+    let string = |s: &str| LuaValue::String(s.as_bytes().to_vec());
+    let fields = meta
+        .fields
+        .iter()
+        .map(|(key, value)| (string(key), string(value)))
+        .collect();
+    let inventory = meta
+        .inventory
+        .iter()
+        .map(|(key, items)| {
+            let items = items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (LuaValue::Number((i + 1) as f64), string(item)))
+                .collect();
+            (string(key), LuaValue::Table(items))
+        })
+        .collect();
+    LuaValue::record(vec![
+        ("fields", LuaValue::Table(fields)),
+        ("inventory", LuaValue::Table(inventory)),
+    ])
+}
+
+/// The values minetest.serialize writes
+#[derive(Debug, Clone, PartialEq)]
+enum LuaValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Vec<u8>),
+    /// In order. Positional values get keys 1, 2, ...
+    Table(Vec<(LuaValue, LuaValue)>),
+}
+
+const NIL: LuaValue = LuaValue::Nil;
+
+impl LuaValue {
+    fn record(fields: Vec<(&str, LuaValue)>) -> Self {
+        LuaValue::Table(
+            fields
+                .into_iter()
+                .map(|(key, value)| (LuaValue::String(key.as_bytes().to_vec()), value))
+                .collect(),
+        )
+    }
+
+    fn get(&self, key: &str) -> &LuaValue {
+        self.entries()
+            .iter()
+            .find(|(k, _)| matches!(k, LuaValue::String(k) if k == key.as_bytes()))
+            .map_or(&NIL, |(_, value)| value)
+    }
+
+    fn entries(&self) -> &[(LuaValue, LuaValue)] {
+        match self {
+            LuaValue::Table(entries) => entries,
+            _ => &[],
+        }
+    }
+
+    fn as_int<T: TryFrom<i64>>(&self) -> Result<T> {
+        if let LuaValue::Number(v) = self {
+            if v.fract() == 0.0 {
+                if let Ok(v) = T::try_from(*v as i64) {
+                    return Ok(v);
+                }
+            }
+        }
+        bail!("Expected an integer in WorldEdit data, got {:?}", self)
+    }
+
+    /// Strings, and numbers as Lua would turn them into strings
+    fn as_string(&self) -> Result<String> {
+        match self {
+            LuaValue::String(s) => Ok(String::from_utf8_lossy(s).into_owned()),
+            LuaValue::Number(v) => Ok(v.to_string()),
+            _ => bail!("Expected a string in WorldEdit data, got {:?}", self),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            LuaValue::Nil => out.extend_from_slice(b"nil"),
+            LuaValue::Bool(b) => out.extend_from_slice(if *b { b"true" } else { b"false" }),
+            LuaValue::Number(v) => out.extend_from_slice(v.to_string().as_bytes()),
+            LuaValue::String(s) => {
+                out.push(b'"');
+                for &c in s {
+                    match c {
+                        b'"' | b'\\' => out.extend_from_slice(&[b'\\', c]),
+                        b'\n' => out.extend_from_slice(b"\\n"),
+                        b'\r' => out.extend_from_slice(b"\\r"),
+                        0..=31 | 127 => out.extend_from_slice(format!("\\{:03}", c).as_bytes()),
+                        _ => out.push(c),
+                    }
+                }
+                out.push(b'"');
+            }
+            LuaValue::Table(entries) => {
+                out.push(b'{');
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        out.extend_from_slice(b", ");
+                    }
+                    out.push(b'[');
+                    key.write(out);
+                    out.extend_from_slice(b"] = ");
+                    value.write(out);
+                }
+                out.push(b'}');
+            }
+        }
+    }
+}
+
+/// Lua literals: tables, strings, numbers, booleans and nil
+struct LuaParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl LuaParser<'_> {
+    fn skip_space(&mut self) {
+        while self.pos < self.input.len() && self.input[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_space();
+        self.input.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.peek() != Some(c) {
+            bail!(
+                "Expected '{}' at offset {} of WorldEdit data",
+                c as char,
+                self.pos
+            );
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn peek_ident(&mut self) -> Option<&[u8]> {
+        self.skip_space();
+        let rest = &self.input[self.pos..];
+        let len = rest
+            .iter()
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+            .count();
+        if len == 0 || rest[0].is_ascii_digit() {
+            return None;
+        }
+        Some(&rest[..len])
+    }
+
+    fn value(&mut self) -> Result<LuaValue> {
+        match self.peek() {
+            Some(b'{') => self.table(),
+            Some(quote @ (b'"' | b'\'')) => self.string(quote),
+            Some(c) if c == b'-' || c == b'.' || c.is_ascii_digit() => self.number(),
+            _ => {
+                let value = match self.peek_ident() {
+                    Some(b"nil") => LuaValue::Nil,
+                    Some(b"true") => LuaValue::Bool(true),
+                    Some(b"false") => LuaValue::Bool(false),
+                    _ => bail!("Unexpected data at offset {} of WorldEdit data", self.pos),
+                };
+                self.pos += self.peek_ident().map_or(0, |ident| ident.len());
+                Ok(value)
+            }
+        }
+    }
+
+    fn table(&mut self) -> Result<LuaValue> {
+        self.expect(b'{')?;
+        let mut entries = Vec::new();
+        let mut index = 1;
+        while self.peek() != Some(b'}') {
+            let start = self.pos;
+            let key = if self.peek() == Some(b'[') {
+                self.pos += 1;
+                let key = self.value()?;
+                self.expect(b']')?;
+                self.expect(b'=')?;
+                Some(key)
+            } else if let Some(ident) = self.peek_ident().map(|ident| ident.to_vec()) {
+                self.pos += ident.len();
+                if self.peek() == Some(b'=') && self.input.get(self.pos + 1) != Some(&b'=') {
+                    self.pos += 1;
+                    Some(LuaValue::String(ident))
+                } else {
+                    self.pos = start;
+                    None
+                }
+            } else {
+                None
+            };
+            let value = self.value()?;
+            let key = key.unwrap_or_else(|| {
+                index += 1;
+                LuaValue::Number((index - 1) as f64)
+            });
+            entries.push((key, value));
+            match self.peek() {
+                Some(b',') | Some(b';') => self.pos += 1,
+                _ => break,
+            }
+        }
+        self.expect(b'}')?;
+        Ok(LuaValue::Table(entries))
+    }
+
+    fn string(&mut self, quote: u8) -> Result<LuaValue> {
+        self.pos += 1;
+        let mut s = Vec::new();
+        loop {
+            let Some(&c) = self.input.get(self.pos) else {
+                bail!("Unterminated string in WorldEdit data");
+            };
+            self.pos += 1;
+            if c == quote {
+                return Ok(LuaValue::String(s));
+            }
+            if c != b'\\' {
+                s.push(c);
+                continue;
+            }
+            let Some(&escaped) = self.input.get(self.pos) else {
+                bail!("Unterminated string in WorldEdit data");
+            };
+            self.pos += 1;
+            match escaped {
+                b'n' | b'\n' => s.push(b'\n'),
+                b'r' => s.push(b'\r'),
+                b't' => s.push(b'\t'),
+                b'a' => s.push(7),
+                b'b' => s.push(8),
+                b'f' => s.push(12),
+                b'v' => s.push(11),
+                b'0'..=b'9' => {
+                    // Up to 3 decimal digits
+                    let mut value = (escaped - b'0') as u32;
+                    for _ in 0..2 {
+                        match self.input.get(self.pos) {
+                            Some(d) if d.is_ascii_digit() => {
+                                value = value * 10 + (d - b'0') as u32;
+                                self.pos += 1;
+                            }
+                            _ => break,
+                        }
+                    }
+                    let Ok(byte) = u8::try_from(value) else {
+                        bail!("Invalid escape in WorldEdit data");
+                    };
+                    s.push(byte);
+                }
+                other => s.push(other),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<LuaValue> {
+        let start = self.pos;
+        while let Some(&c) = self.input.get(self.pos) {
+            let sign_ok = self.pos == start
+                || matches!(self.input[self.pos - 1], b'e' | b'E') && !self.is_hex(start);
+            if c.is_ascii_alphanumeric() || c == b'.' || (matches!(c, b'-' | b'+') && sign_ok) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let text = std::str::from_utf8(&self.input[start..self.pos])?;
+        let value = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => i64::from_str_radix(hex, 16).map(|v| v as f64).ok(),
+            None => text.parse().ok(),
+        };
+        match value {
+            Some(value) => Ok(LuaValue::Number(value)),
+            None => bail!("Invalid number {} in WorldEdit data", text),
+        }
+    }
+
+    fn is_hex(&self, start: usize) -> bool {
+        matches!(self.input.get(start..start + 2), Some(b"0x") | Some(b"0X"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As saved by WorldEdit, abridged
+    const V5: &str = r#"5:return {{["y"] = 0, ["x"] = 0, ["name"] = "default:stone", ["z"] = 0}, {["y"] = 1, ["x"] = 2, ["name"] = "default:chest", ["z"] = 0, ["param2"] = 3, ["param1"] = 15, ["meta"] = {["fields"] = {["infotext"] = "A \"chest\"\
+"}, ["inventory"] = {["main"] = {"default:apple 3", ""}}}}}"#;
+
+    #[test]
+    fn read_versions() {
+        let file = WorldEditFile::read(V5.as_bytes()).unwrap();
+        assert_eq!(file.nodes.len(), 2);
+        assert_eq!(file.nodes[0].name, "default:stone");
+        assert_eq!(file.nodes[0].meta, None);
+        let chest = &file.nodes[1];
+        assert_eq!(chest.pos, v3s16::new(2, 1, 0));
+        assert_eq!((chest.param1, chest.param2), (15, 3));
+        let meta = chest.meta.as_ref().unwrap();
+        assert_eq!(meta.fields["infotext"], "A \"chest\"\n");
+        assert_eq!(meta.inventory["main"], vec!["default:apple 3", ""]);
+
+        // No header, bare keys
+        let v4 = "return {{x=0,y=0,z=0,name='default:stone',param1=0,param2=0,meta={fields={},inventory={}}}, {x=2,y=1,z=0,name=\"default:chest\",param1=15,param2=3}}";
+        let file4 = WorldEditFile::read(v4.as_bytes()).unwrap();
+        assert_eq!(file4.nodes[1].name, "default:chest");
+        assert_eq!(file4.nodes[0].meta, Some(WeMeta::default()));
+
+        let v3 = "0 0 0 default:stone 0 0\n2 1 0 default:chest 15 3\n";
+        let file3 = WorldEditFile::read(v3.as_bytes()).unwrap();
+        assert_eq!(
+            file3.nodes[1],
+            WeNode {
+                meta: None,
+                ..chest.clone()
+            }
+        );
+
+        assert!(WorldEditFile::read(&b"6:return {}"[..]).is_err());
+        assert!(WorldEditFile::read(&b"5:return {{x=0"[..]).is_err());
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let file = WorldEditFile::read(V5.as_bytes()).unwrap();
+        let mut data = Vec::new();
+        file.write(&mut data).unwrap();
+        assert!(data.starts_with(b"5:return {{[\"x\"] = 0, "));
+        assert_eq!(WorldEditFile::read(&data[..]).unwrap(), file);
+    }
+
+    #[test]
+    fn schematics() {
+        let file = WorldEditFile::read(V5.as_bytes()).unwrap();
+        let schematic = file.to_schematic().unwrap();
+        assert_eq!(schematic.size, v3s16::new(3, 2, 1));
+        let chest = schematic.nodes[schematic.index(2, 1, 0)];
+        assert_eq!(schematic.node_name(&chest), Some("default:chest"));
+        assert_eq!(chest.param1, PROB_ALWAYS | FORCE_PLACE);
+        assert_eq!(schematic.nodes[schematic.index(1, 0, 0)].param1, PROB_NEVER);
+
+        let back = WorldEditFile::from_schematic(&schematic);
+        assert_eq!(back.nodes.len(), 2);
+        assert_eq!(back.nodes[1].pos, v3s16::new(2, 1, 0));
+        assert_eq!(back.nodes[1].param2, 3);
+    }
+}
@@ -0,0 +1,58 @@
+//!
+//! Compatibility report
+//!
+//! With --compat-report FILE, every session is tallied (see
+//! services::compat_report), and FILE is rewritten as JSON when it ends.
+//! An existing FILE is added to, so the report covers every run:
+//!
+//! ```text
+//! $ mtshark -l 40000 -t 127.0.0.1:30000 --compat-report compat.json
+//! $ jq '.versions[] | .unknown_commands' compat.json
+//! ```
+//!
+//! It only has counts by command and client version, so it can be shared
+//! as is.
+//!
+use anyhow::Context;
+use anyhow::Result;
+use minetest_protocol::services::compat_report::CompatReport;
+use minetest_protocol::services::compat_report::CompatTally;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub struct CompatReportFile {
+    path: PathBuf,
+    report: Mutex<CompatReport>,
+}
+
+impl CompatReportFile {
+    /// Adds to the report already at `path`, if there is one
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let report = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).with_context(|| format!("{:?}", path))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => CompatReport::new(),
+            Err(err) => return Err(err).with_context(|| format!("{:?}", path)),
+        };
+        Ok(Self {
+            path,
+            report: Mutex::new(report),
+        })
+    }
+
+    /// Adds a finished session, and writes the report out
+    pub fn add(&self, tally: CompatTally) {
+        let mut report = self.report.lock().unwrap();
+        report.add(tally);
+        if let Err(err) = self.write(&report) {
+            log!("Cannot write {:?}: {:?}", self.path, err);
+        }
+    }
+
+    /// Through a temporary file, so the report is never half written
+    fn write(&self, report: &CompatReport) -> Result<()> {
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_string_pretty(report)? + "\n")?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
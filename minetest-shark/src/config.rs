@@ -0,0 +1,255 @@
+//!
+//! Reloadable configuration
+//!
+//! Settings that can change while the proxy runs, without dropping active
+//! sessions. They start out from the command line, and a config file
+//! (--config) overrides them:
+//!
+//! ```text
+//! # mtshark.conf
+//! verbose = 1
+//! filter = Blockdata Media ActiveObjectMessages
+//! filter_expr = dir==C->S || cmd==TCChatMessage
+//! media_quota = 52428800
+//! target = 127.0.0.1:30001 127.0.0.1:30002
+//! stall_timeout = 10
+//! quarantine = /var/lib/mtshark/quarantine
+//! delivery = ActiveObjectMessages=unreliable Blockdata=1
+//! audit = 192.168.1.20
+//! ```
+//!
+//! ```text
+//! verbose       like -v, -vv, -vvv
+//! filter        commands left out of the output, by name
+//! filter_expr   only the commands that match are shown, like --filter
+//!               (see filter)
+//! media_quota   bytes of media sent to each client per minute, beyond
+//!               which media is dropped (0 for no limit)
+//! target        the upstream servers, in order of preference
+//! stall_timeout seconds a reliable packet may go unacked before the
+//!               session is dumped (0 to never check)
+//! quarantine    directory to save packets and commands that fail to parse
+//!               in (see peer::quarantine)
+//! delivery      channel and reliability to forward commands with, instead
+//!               of their defaults (see peer::delivery)
+//! audit         the clients whose traffic (both ways) is audited: all,
+//!               none, or their IP addresses (see wire::audit)
+//! ```
+//!
+//! The file is read again on SIGHUP, and whenever it changes with
+//! --watch-config. A file that doesn't parse is reported, and the previous
+//! configuration stays.
+//!
+use crate::filter::CommandFilter;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use minetest_protocol::peer::delivery::DeliveryOverrides;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::sync::watch;
+
+/// How often --watch-config looks at the file
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The window media_quota applies to
+pub const MEDIA_QUOTA_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProxyConfig {
+    pub verbosity: u8,
+    /// Names of the commands not shown
+    pub filter: BTreeSet<String>,
+    /// Only the commands that match are shown
+    pub filter_expr: Option<CommandFilter>,
+    /// Bytes of media per client per MEDIA_QUOTA_WINDOW
+    pub media_quota: Option<u64>,
+    pub targets: Vec<SocketAddr>,
+    /// See services::watchdog
+    pub stall_timeout: Option<Duration>,
+    pub quarantine: Option<PathBuf>,
+    pub delivery: DeliveryOverrides,
+    pub audit: AuditScope,
+}
+
+/// Which clients are audited
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum AuditScope {
+    #[default]
+    None,
+    All,
+    Clients(BTreeSet<IpAddr>),
+}
+
+impl AuditScope {
+    pub fn includes(&self, client: IpAddr) -> bool {
+        match self {
+            AuditScope::None => false,
+            AuditScope::All => true,
+            AuditScope::Clients(clients) => clients.contains(&client),
+        }
+    }
+}
+
+impl FromStr for AuditScope {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(match value {
+            "" | "none" => AuditScope::None,
+            "all" => AuditScope::All,
+            value => AuditScope::Clients(
+                value
+                    .split_whitespace()
+                    .map(|addr| addr.parse())
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+impl ProxyConfig {
+    /// `self` with the settings in the config file `text` applied
+    pub fn with_file(&self, text: &str) -> Result<Self> {
+        let mut config = self.clone();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            config
+                .set(line)
+                .with_context(|| format!("line {}", number + 1))?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, line: &str) -> Result<()> {
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Expected key = value");
+        };
+        let value = value.trim();
+        match key.trim() {
+            "verbose" => self.verbosity = value.parse()?,
+            "filter" => self.filter = value.split_whitespace().map(String::from).collect(),
+            "filter_expr" => {
+                self.filter_expr = match value {
+                    "" => None,
+                    value => Some(value.parse()?),
+                };
+            }
+            "media_quota" => {
+                let bytes: u64 = value.parse()?;
+                self.media_quota = (bytes > 0).then_some(bytes);
+            }
+            "target" => {
+                let targets: Vec<_> = value
+                    .split_whitespace()
+                    .map(|addr| addr.parse())
+                    .collect::<Result<_, _>>()?;
+                if targets.is_empty() {
+                    // Every new player would be refused
+                    bail!("target needs at least one address");
+                }
+                self.targets = targets;
+            }
+            "stall_timeout" => {
+                let secs: u64 = value.parse()?;
+                self.stall_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "quarantine" => self.quarantine = Some(value.into()),
+            "delivery" => self.delivery = value.parse()?,
+            "audit" => self.audit = value.parse()?,
+            key => bail!("Unknown setting {:?}", key),
+        }
+        Ok(())
+    }
+
+    /// `self` with the config file at `path` applied
+    pub fn load(&self, path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        self.with_file(&text).with_context(|| format!("{:?}", path))
+    }
+}
+
+/// Reloads the config file at `path` over `base` on SIGHUP, and when
+/// `watch_file`, whenever the file changes
+pub fn spawn_reloader(
+    base: ProxyConfig,
+    path: PathBuf,
+    watch_file: bool,
+    config: watch::Sender<ProxyConfig>,
+) {
+    tokio::spawn(async move {
+        if let Err(err) = reload_loop(base, path, watch_file, config).await {
+            log!("Config reloading stopped: {:?}", err);
+        }
+    });
+}
+
+async fn reload_loop(
+    base: ProxyConfig,
+    path: PathBuf,
+    watch_file: bool,
+    config: watch::Sender<ProxyConfig>,
+) -> Result<()> {
+    let mut hangup = Hangup::new()?;
+    let mut modified = modified_time(&path);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => (),
+            _ = tokio::time::sleep(WATCH_INTERVAL), if watch_file => {
+                let now = modified_time(&path);
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+            },
+        }
+        match base.load(&path) {
+            Ok(new) if new.targets.is_empty() => {
+                log!("Config {:?} not applied: no target", path);
+            }
+            Ok(new) => {
+                if config.send_if_modified(|old| std::mem::replace(old, new.clone()) != new) {
+                    log!("Config {:?} reloaded", path);
+                }
+            }
+            Err(err) => log!("Config not applied: {:#}", err),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// SIGHUP, where there is such a thing
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,400 @@
+//!
+//! Command filter expressions
+//!
+//! With --filter (or filter_expr in the config file), only the commands
+//! that match are shown:
+//!
+//! ```text
+//! $ mtshark -l 40000 -t 127.0.0.1:30000 -vv --filter "cmd==Blockdata || cmd==TSChatMessage"
+//! $ mtshark ... --filter "dir==S->C && channel==2 && !cmd==Hud*"
+//! $ mtshark ... --filter "size>10000"
+//! ```
+//!
+//! ```text
+//! cmd       the command name (case insensitive, Hud* for a prefix)
+//! id        the command id, e.g. 0x20
+//! dir       C->S or S->C
+//! channel   the channel it is forwarded on
+//! reliable  true or false
+//! size      serialized size in bytes
+//! ```
+//!
+//! Compared with == != < <= > >=, and combined with && || ! and
+//! parentheses. A value with spaces can be quoted.
+//!
+//! Only names and numbers are looked at, so a command that doesn't match
+//! costs nothing more (its contents are never formatted).
+//!
+use anyhow::bail;
+use anyhow::Result;
+use minetest_protocol::CommandDirection;
+use minetest_protocol::CommandRef;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Cmd,
+    Id,
+    Dir,
+    Channel,
+    Reliable,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Name(String),
+    Number(u64),
+    Dir(CommandDirection),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Op, Value),
+}
+
+/// A parsed --filter expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandFilter {
+    expr: Expr,
+    text: String,
+}
+
+impl CommandFilter {
+    /// Whether `command` is shown. `size` is its serialized size, if known
+    /// (see uses_size).
+    pub fn matches<Cmd: CommandRef>(&self, command: &Cmd, size: Option<usize>) -> bool {
+        self.expr.eval(command, size)
+    }
+
+    /// Whether the serialized size is needed
+    pub fn uses_size(&self) -> bool {
+        self.expr.uses(Field::Size)
+    }
+}
+
+impl std::fmt::Display for CommandFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl FromStr for CommandFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            bail!("Unexpected {}", token);
+        }
+        Ok(Self {
+            expr,
+            text: text.to_string(),
+        })
+    }
+}
+
+impl Expr {
+    fn eval<Cmd: CommandRef>(&self, command: &Cmd, size: Option<usize>) -> bool {
+        match self {
+            Expr::Or(a, b) => a.eval(command, size) || b.eval(command, size),
+            Expr::And(a, b) => a.eval(command, size) && b.eval(command, size),
+            Expr::Not(a) => !a.eval(command, size),
+            Expr::Compare(field, op, value) => compare(command, size, *field, *op, value),
+        }
+    }
+
+    fn uses(&self, field: Field) -> bool {
+        match self {
+            Expr::Or(a, b) | Expr::And(a, b) => a.uses(field) || b.uses(field),
+            Expr::Not(a) => a.uses(field),
+            Expr::Compare(f, _, _) => *f == field,
+        }
+    }
+}
+
+fn compare<Cmd: CommandRef>(
+    command: &Cmd,
+    size: Option<usize>,
+    field: Field,
+    op: Op,
+    value: &Value,
+) -> bool {
+    let number = match (field, value) {
+        (Field::Cmd, Value::Name(pattern)) => {
+            return op.equality(name_matches(command.command_name(), pattern));
+        }
+        (Field::Dir, Value::Dir(dir)) => return op.equality(command.direction() == *dir),
+        (Field::Reliable, Value::Bool(reliable)) => {
+            return op.equality(command.default_reliability() == *reliable);
+        }
+        (Field::Id, _) => {
+            let id = match (command.toclient_ref(), command.toserver_ref()) {
+                (Some(command), _) => command.command_id(),
+                (_, Some(command)) => command.command_id(),
+                _ => return false,
+            };
+            id as u64
+        }
+        (Field::Channel, _) => command.default_channel() as u64,
+        (Field::Size, _) => match size {
+            Some(size) => size as u64,
+            None => return false,
+        },
+        _ => return false,
+    };
+    let Value::Number(value) = value else {
+        return false;
+    };
+    match op {
+        Op::Eq => number == *value,
+        Op::Ne => number != *value,
+        Op::Lt => number < *value,
+        Op::Le => number <= *value,
+        Op::Gt => number > *value,
+        Op::Ge => number >= *value,
+    }
+}
+
+/// `pattern` is a name, or a prefix followed by *
+fn name_matches(name: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+        None => name.eq_ignore_ascii_case(pattern),
+    }
+}
+
+impl Field {
+    const ALL: [(&'static str, Field); 6] = [
+        ("cmd", Field::Cmd),
+        ("id", Field::Id),
+        ("dir", Field::Dir),
+        ("channel", Field::Channel),
+        ("reliable", Field::Reliable),
+        ("size", Field::Size),
+    ];
+
+    fn name(self) -> &'static str {
+        Field::ALL
+            .iter()
+            .find(|(_, field)| *field == self)
+            .map_or("", |(name, _)| name)
+    }
+}
+
+impl Op {
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+
+    /// For fields that are only compared with == and !=
+    fn equality(self, equal: bool) -> bool {
+        match self {
+            Op::Ne => !equal,
+            _ => equal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{:?}", word),
+            Token::Op(op) => write!(f, "{}", op.symbol()),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Not => write!(f, "!"),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('"', _) => {
+                let Some(len) = chars[i + 1..].iter().position(|&c| c == '"') else {
+                    bail!("Unterminated quote");
+                };
+                let word = chars[i + 1..i + 1 + len].iter().collect();
+                (Token::Word(word), len + 2)
+            }
+            _ => {
+                // The > in C->S belongs to the word
+                let mut end = i;
+                while end < chars.len()
+                    && (is_word_char(chars[end]) || (chars[end] == '>' && chars[end - 1] == '-'))
+                {
+                    end += 1;
+                }
+                if end == i {
+                    bail!("Unexpected {:?}", c);
+                }
+                (Token::Word(chars[i..end].iter().collect()), end - i)
+            }
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '*' | '-' | '.')
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek_is(&self, token: &Token) -> bool {
+        self.tokens.get(self.pos) == Some(token)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.peek_is(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.peek_is(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    Some(token) => bail!("Expected ), found {}", token),
+                    None => bail!("Missing )"),
+                }
+            }
+            Some(Token::Word(field)) => self.compare(&field),
+            Some(token) => bail!("Unexpected {}", token),
+            None => bail!("Unexpected end of filter"),
+        }
+    }
+
+    fn compare(&mut self, name: &str) -> Result<Expr> {
+        let Some(&(_, field)) = Field::ALL.iter().find(|(field, _)| *field == name) else {
+            bail!(
+                "Unknown field {:?} (cmd, id, dir, channel, reliable or size)",
+                name
+            );
+        };
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => bail!("Expected == != < <= > or >= after {}", name),
+        };
+        let Some(Token::Word(value)) = self.next() else {
+            bail!("Expected a value after {}{}", name, op.symbol());
+        };
+        let value = match field {
+            Field::Cmd => Value::Name(value),
+            Field::Dir => match value.to_ascii_uppercase().as_str() {
+                "C->S" | "TOSERVER" => Value::Dir(CommandDirection::ToServer),
+                "S->C" | "TOCLIENT" => Value::Dir(CommandDirection::ToClient),
+                _ => bail!("dir is C->S or S->C, not {:?}", value),
+            },
+            Field::Reliable => match value.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => bail!("reliable is true or false, not {:?}", value),
+            },
+            Field::Id | Field::Channel | Field::Size => Value::Number(parse_number(&value)?),
+        };
+        if !matches!(op, Op::Eq | Op::Ne) && !matches!(value, Value::Number(_)) {
+            bail!("{} can only be compared with == or !=", field.name());
+        }
+        Ok(Expr::Compare(field, op, value))
+    }
+}
+
+fn parse_number(value: &str) -> Result<u64> {
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    match number {
+        Ok(number) => Ok(number),
+        Err(_) => bail!("Expected a number, not {:?}", value),
+    }
+}
@@ -1,18 +1,53 @@
+#[macro_use]
+mod output;
+
+mod compat;
+mod config;
+mod filter;
 mod proxy;
+mod trace;
+mod upstream;
 
 use anyhow::bail;
+use anyhow::Context;
 use clap::ArgGroup;
 use clap::Parser;
-use minetest_protocol::audit_on;
+use clap::Subcommand;
+use compat::CompatReportFile;
+use config::AuditScope;
+use config::ProxyConfig;
+use filter::CommandFilter;
+use minetest_protocol::recording::NameRedaction;
+use minetest_protocol::recording::PcapTap;
+use minetest_protocol::recording::PcapngWriter;
+use minetest_protocol::recording::Redactor;
+use minetest_protocol::replay::replay;
+use minetest_protocol::replay::Capture;
+use minetest_protocol::replay::ReplayOptions;
+use minetest_protocol::services::transport::DatagramTap;
+use minetest_protocol::wire::audit::set_audit_policy;
+use minetest_protocol::wire::audit::AuditPolicy;
+use output::OutputFormat;
 use proxy::MinetestProxy;
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::watch;
 
 /// mtshark - Minetest proxy that gives detailed inspection of protocol
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 #[command(group(ArgGroup::new("source").required(true).args(["listen", "bind"])))]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Listen on port
     #[arg(group = "source", short, long)]
     listen: Option<u16>,
@@ -21,17 +56,117 @@ struct Args {
     #[arg(group = "source", short, long)]
     bind: Option<SocketAddr>,
 
-    /// Target server (address:port)
-    #[arg(short, long, required = true)]
-    target: SocketAddr,
+    /// Target server (address:port). Repeat for fallback servers, in
+    /// order of preference: players move to the next healthy one when
+    /// their server goes down.
+    #[arg(short, long, required_unless_present = "config")]
+    target: Vec<SocketAddr>,
 
     /// Verbosity level (up to -vvv)
     #[arg(short, long, default_value_t = 0, action = clap::ArgAction::Count)]
     verbose: u8,
 
-    /// Enable audit mode
+    /// Only show the commands that match, e.g.
+    /// "cmd==Blockdata || (dir==C->S && channel==0)". Fields are cmd, id,
+    /// dir, channel, reliable and size.
+    #[arg(short, long)]
+    filter: Option<CommandFilter>,
+
+    /// Enable audit mode, for every client
     #[arg(short, long, default_value_t = false)]
     audit: bool,
+
+    /// Enable audit mode for this client only (can be repeated)
+    #[arg(long, conflicts_with = "audit")]
+    audit_client: Vec<IpAddr>,
+
+    /// In audit mode, report mismatches and carry on instead of terminating
+    #[arg(long, default_value_t = false)]
+    audit_continue: bool,
+
+    /// Record each proxied connection into this directory
+    #[arg(short, long)]
+    record: Option<PathBuf>,
+
+    /// Write the datagrams of both legs, both ways, to this pcapng file
+    /// (for Wireshark). These are not redacted.
+    #[arg(long)]
+    pcap: Option<PathBuf>,
+
+    /// Redact player names, credentials, chat, and addresses
+    /// from the output and recordings (for sharing in bug reports)
+    #[arg(long, default_value_t = false)]
+    redact: bool,
+
+    /// Tag every line with the session's trace id, the leg (span) it
+    /// happened on, and a timestamp, and show time spent in the proxy
+    #[arg(long, default_value_t = false)]
+    trace: bool,
+
+    /// Dump a session when a reliable packet goes unacked this long,
+    /// in seconds (0 to never check)
+    #[arg(long, default_value_t = 10)]
+    stall_timeout: u64,
+
+    /// How commands are shown. jsonl writes every command as a line of
+    /// JSON (with contents at -vv), and everything else to stderr.
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Save packets and commands that fail to parse into this directory,
+    /// with what they were parsed as. These are not redacted.
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    /// Count the commands and parse failures of every session, by client
+    /// version, into this JSON file (added to if it exists). It has no
+    /// names, addresses or contents.
+    #[arg(long)]
+    compat_report: Option<PathBuf>,
+
+    /// Read settings from this file, which overrides the command line.
+    /// Send SIGHUP to reload it without dropping sessions.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Reload the config file whenever it changes
+    #[arg(long, default_value_t = false, requires = "config")]
+    watch_config: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send what the client sent in a captured session (a recording, a
+    /// pcapng file, or JSON lines from -o jsonl -vv) to a server again,
+    /// with the original timing
+    Replay {
+        /// Recording, pcapng or JSON lines file
+        capture: PathBuf,
+
+        /// Server to replay against (address:port)
+        #[arg(short, long)]
+        server: SocketAddr,
+
+        /// Faster (2 for twice as fast) or slower (0.5) than recorded
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// Send everything right away, ignoring the timing
+        #[arg(long, default_value_t = false, conflicts_with = "speed")]
+        fast: bool,
+
+        /// Log in as this player, instead of sending the recorded login
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Password to log in with
+        #[arg(short, long, default_value = "", requires = "name")]
+        password: String,
+
+        /// Seconds to wait for the server after the last command
+        #[arg(long, default_value_t = 2)]
+        linger: u64,
+    },
 }
 
 #[tokio::main]
@@ -45,16 +180,72 @@ async fn real_main() -> anyhow::Result<()> {
     std::env::set_var("RUST_BACKTRACE", "1");
 
     let args = Args::parse();
+    args.output.set();
+
+    if let Some(Command::Replay {
+        capture,
+        server,
+        speed,
+        fast,
+        name,
+        password,
+        linger,
+    }) = args.command
+    {
+        if speed.is_nan() || speed <= 0.0 {
+            bail!("--speed must be above 0");
+        }
+        let options = ReplayOptions {
+            speed: if fast { f64::INFINITY } else { speed },
+            login: name.map(|name| (name, password)),
+            linger: Duration::from_secs(linger),
+        };
+        return replay_capture(&capture, server, &options).await;
+    }
 
-    if args.audit {
-        audit_on();
-        println!("Auditing is ON.");
-        println!("Proxy will terminate if an invalid packet is received,");
-        println!("or if serialization/deserialization do not match exactly.");
+    let base = ProxyConfig {
+        verbosity: args.verbose,
+        targets: args.target.clone(),
+        stall_timeout: (args.stall_timeout > 0).then(|| Duration::from_secs(args.stall_timeout)),
+        quarantine: args.quarantine.clone(),
+        filter_expr: args.filter.clone(),
+        audit: if args.audit {
+            AuditScope::All
+        } else if !args.audit_client.is_empty() {
+            AuditScope::Clients(args.audit_client.iter().copied().collect())
+        } else {
+            AuditScope::None
+        },
+        ..Default::default()
+    };
+    let config = match &args.config {
+        Some(path) => base.load(path)?,
+        None => base.clone(),
+    };
+
+    // The config file can turn auditing on later, so this is always set
+    let terminate = !args.audit_continue;
+    set_audit_policy(AuditPolicy::Callback(Arc::new(move |mismatch| {
+        log!("{}", mismatch);
+        if terminate {
+            std::process::exit(1);
+        }
+    })));
+    if config.audit != AuditScope::None {
+        log!("Auditing is ON.");
+        if terminate {
+            log!("Proxy will terminate if an invalid packet is received,");
+            log!("or if serialization/deserialization do not match exactly.");
+        } else {
+            log!("Mismatches between serialization and deserialization are reported.");
+        }
+    }
+    if config.targets.is_empty() {
+        bail!("No target server, use --target or set target in the config file");
     }
 
     let bind_addr: SocketAddr = if let Some(listen_port) = args.listen {
-        if args.target.is_ipv4() {
+        if config.targets[0].is_ipv4() {
             format!("0.0.0.0:{}", listen_port).parse()?
         } else {
             format!("[::]:{}", listen_port).parse()?
@@ -65,8 +256,76 @@ async fn real_main() -> anyhow::Result<()> {
         bail!("One of --listen or --bind must be specified");
     };
 
-    let _proxy = MinetestProxy::new(bind_addr, args.target, args.verbose);
+    if let Some(dir) = &args.record {
+        std::fs::create_dir_all(dir)?;
+        log!("Recording connections to {:?}", dir);
+    }
+
+    let redactor = if args.redact {
+        log!("Redaction is ON.");
+        Some(Redactor::new(NameRedaction::Hash))
+    } else {
+        None
+    };
+
+    let (config_tx, config_rx) = watch::channel(config);
+    if let Some(path) = args.config {
+        config::spawn_reloader(base, path, args.watch_config, config_tx);
+    }
+
+    let pcap = match &args.pcap {
+        Some(path) => {
+            let writer = PcapngWriter::new(BufWriter::new(File::create(path)?))?;
+            log!("Capturing packets to {:?}", path);
+            let tap: Arc<dyn DatagramTap> = Arc::new(PcapTap::new(writer));
+            Some(tap)
+        }
+        None => None,
+    };
+
+    let compat_report = match args.compat_report {
+        Some(path) => {
+            log!("Compatibility report in {:?}", path);
+            Some(Arc::new(CompatReportFile::open(path)?))
+        }
+        None => None,
+    };
+
+    let _proxy = MinetestProxy::new(
+        bind_addr,
+        config_rx,
+        args.record,
+        redactor,
+        args.trace,
+        pcap,
+        compat_report,
+    );
     loop {
         tokio::time::sleep(Duration::from_secs(3600)).await;
     }
 }
+
+async fn replay_capture(
+    path: &Path,
+    server: SocketAddr,
+    options: &ReplayOptions,
+) -> anyhow::Result<()> {
+    let capture = Capture::load(&std::fs::read(path)?).with_context(|| format!("{:?}", path))?;
+    for skipped in &capture.skipped {
+        log!("Skipped {}", skipped);
+    }
+    let duration = capture.steps.last().map(|step| step.at).unwrap_or_default();
+    log!(
+        "Replaying {} commands ({:.1?} as recorded) to {}",
+        capture.steps.len(),
+        duration,
+        server
+    );
+    let stats = replay(server, &capture.steps, options).await?;
+    log!(
+        "Replayed {} commands, the server sent {}",
+        stats.sent,
+        stats.received
+    );
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//!
+//! Output format
+//!
+//! Commands are shown as text (see -v), or with --output jsonl, as one JSON
+//! object per line, for jq and the like:
+//!
+//! ```text
+//! $ mtshark -l 40000 -t 127.0.0.1:30000 --output jsonl | jq -c 'select(.size > 10000)'
+//! {"timestamp":1712345678.123456,"session":1,"dir":"S->C","peer":"127.0.0.1:30000","channel":2,"reliable":true,"size":14527,"name":"Blockdata"}
+//! ```
+//!
+//! ```text
+//! timestamp  when the proxy received it, in seconds since the UNIX epoch
+//! session    the proxied session, as in [1] in text output
+//! dir        C->S or S->C
+//! peer       the address it came from (redacted with --redact)
+//! channel    the channel and reliability it is forwarded with
+//! reliable
+//! size       serialized size, in the negotiated protocol version
+//! name       the command
+//! command    its contents, with -vv (-vvv for map and media)
+//! ```
+//!
+//! With --trace, the trace, span and time since the session started are
+//! there too.
+//!
+//! In jsonl mode stdout only has the JSON lines. Everything else (sessions
+//! starting and ending, errors) goes to stderr, through log!.
+//!
+use clap::ValueEnum;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable
+    #[default]
+    Text,
+    /// One JSON object per command
+    #[value(alias = "json")]
+    Jsonl,
+}
+
+static JSONL: AtomicBool = AtomicBool::new(false);
+
+impl OutputFormat {
+    /// The format for the rest of the run. Set once, before proxying.
+    pub fn set(self) {
+        JSONL.store(self == OutputFormat::Jsonl, Ordering::Relaxed);
+    }
+
+    pub fn get() -> Self {
+        if JSONL.load(Ordering::Relaxed) {
+            OutputFormat::Jsonl
+        } else {
+            OutputFormat::Text
+        }
+    }
+}
+
+/// println!, or eprintln! when stdout is for JSON lines
+macro_rules! log {
+    ($($arg:tt)*) => {
+        match $crate::output::OutputFormat::get() {
+            $crate::output::OutputFormat::Text => println!($($arg)*),
+            $crate::output::OutputFormat::Jsonl => eprintln!($($arg)*),
+        }
+    };
+}
@@ -17,25 +17,92 @@
 //!
 //! As an added bonus, enabling verbose mode will print out the stream of
 //! commands in both directions, in a human-readable format.
+//!
+//! With several targets, the proxy is a basic high-availability front end
+//! (see UpstreamPool): players are sent to the first healthy server, and
+//! asked to reconnect when theirs goes down.
+//!
+//! Changes to the ProxyConfig (see config) apply to every session right
+//! away, and to the upstream pool.
+//!
+//! When either leg stalls (see services::watchdog), the session is dumped:
+//! the state of both legs and the last commands proxied.
+//!
+//! With --compat-report, what each session sent and failed to parse is
+//! counted (see compat).
 use anyhow::Result;
 
+use crate::compat::CompatReportFile;
+use crate::config::ProxyConfig;
+use crate::config::MEDIA_QUOTA_WINDOW;
+use crate::output::OutputFormat;
+use crate::trace::Leg;
+use crate::trace::SessionTrace;
+use crate::upstream::Upstream;
+use crate::upstream::UpstreamPool;
+
 use minetest_protocol::peer::peer::PeerError;
+use minetest_protocol::peer::peer::UnreliableSplitPolicy;
+use minetest_protocol::peer::quarantine::ParseFailure;
+use minetest_protocol::recording::RecordingWriter;
+use minetest_protocol::recording::Redactor;
+use minetest_protocol::services::bandwidth::Quota;
+use minetest_protocol::services::compat_report::CompatTally;
+use minetest_protocol::services::transport::DatagramTap;
+use minetest_protocol::services::transport::TappedTransport;
+use minetest_protocol::services::watchdog::RecentCommands;
+use minetest_protocol::services::watchdog::StallDetector;
+use minetest_protocol::services::watchdog::DEFAULT_RECENT_COMMANDS;
+use minetest_protocol::wire::command::serialize_commandref;
+use minetest_protocol::wire::command::CommandProperties;
 use minetest_protocol::wire::command::ToClientCommand;
+use minetest_protocol::wire::compat::CompatMode;
+use minetest_protocol::wire::packet::LATEST_PROTOCOL_VERSION;
+use minetest_protocol::wire::packet::SER_FMT_HIGHEST_WRITE;
+use minetest_protocol::wire::ser::MockSerializer;
+use minetest_protocol::wire::types::AccessDeniedCode;
+use minetest_protocol::wire::types::ProtocolContext;
 use minetest_protocol::CommandDirection;
 use minetest_protocol::CommandRef;
 use minetest_protocol::MinetestClient;
 use minetest_protocol::MinetestConnection;
 use minetest_protocol::MinetestServer;
+use serde_json::Map;
+use serde_json::Value;
+use std::fs::File;
+use std::io::BufWriter;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
 
 pub struct MinetestProxy {}
 
 impl MinetestProxy {
-    pub fn new(bind_addr: SocketAddr, forwarding_addr: SocketAddr, verbosity: u8) -> Self {
+    pub fn new(
+        bind_addr: SocketAddr,
+        config: watch::Receiver<ProxyConfig>,
+        record_dir: Option<PathBuf>,
+        redactor: Option<Redactor>,
+        trace: bool,
+        pcap: Option<Arc<dyn DatagramTap>>,
+        compat_report: Option<Arc<CompatReportFile>>,
+    ) -> Self {
+        let targets = config.borrow().targets.clone();
         let runner = MinetestProxyRunner {
             bind_addr,
-            forwarding_addr,
-            verbosity,
+            pool: UpstreamPool::new(targets),
+            config,
+            record_dir,
+            redactor,
+            trace,
+            pcap,
+            compat_report,
         };
         tokio::spawn(async move { runner.run().await });
         MinetestProxy {}
@@ -44,111 +111,566 @@ impl MinetestProxy {
 
 struct MinetestProxyRunner {
     bind_addr: SocketAddr,
-    forwarding_addr: SocketAddr,
-    verbosity: u8,
+    pool: UpstreamPool,
+    config: watch::Receiver<ProxyConfig>,
+    record_dir: Option<PathBuf>,
+    redactor: Option<Redactor>,
+    trace: bool,
+    /// Sees the datagrams of every socket, with --pcap
+    pcap: Option<Arc<dyn DatagramTap>>,
+    /// Sessions are tallied into it, with --compat-report
+    compat_report: Option<Arc<CompatReportFile>>,
 }
 
 impl MinetestProxyRunner {
-    async fn run(self) {
-        let mut server = MinetestServer::new(self.bind_addr);
+    async fn listen(&self) -> Result<MinetestServer> {
+        let Some(tap) = &self.pcap else {
+            return Ok(MinetestServer::new(self.bind_addr));
+        };
+        let socket = UdpSocket::bind(self.bind_addr).await?;
+        Ok(MinetestServer::with_transport(TappedTransport::new(
+            socket,
+            tap.clone(),
+        ))?)
+    }
+
+    async fn connect(&self, upstream: SocketAddr) -> Result<MinetestClient> {
+        let Some(tap) = &self.pcap else {
+            return MinetestClient::connect(upstream).await;
+        };
+        let bind_addr: SocketAddr = if upstream.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        MinetestClient::connect_with_transport(TappedTransport::new(socket, tap.clone()), upstream)
+            .await
+    }
+
+    async fn run(mut self) {
+        let mut server = match self.listen().await {
+            Ok(server) => server,
+            Err(err) => {
+                log!("Cannot listen on {}: {:?}", self.bind_addr, err);
+                return;
+            }
+        };
         let mut next_id: u64 = 1;
         loop {
             tokio::select! {
                 conn = server.accept() => {
                     let id = next_id;
                     next_id += 1;
-                    println!("[P{}] New client connected from {:?}", id, conn.remote_addr());
-                    let client = MinetestClient::connect(self.forwarding_addr).await.expect("Connect failed");
-                    ProxyAdapterRunner::spawn(id, conn, client, self.verbosity);
+                    let trace = SessionTrace::new(id, self.trace);
+                    let remote = match &self.redactor {
+                        Some(redactor) => redactor.addr(&conn.remote_addr()),
+                        None => format!("{:?}", conn.remote_addr()),
+                    };
+                    if self.trace {
+                        log!("[P{}] New client connected from {} trace={}", id, remote, trace.trace_id());
+                    } else {
+                        log!("[P{}] New client connected from {}", id, remote);
+                    }
+                    let Some(upstream) = self.pool.pick() else {
+                        log!("[P{}] No upstream available", id);
+                        tokio::spawn(conn.close(reconnect_code()));
+                        continue;
+                    };
+                    let client = match self.connect(upstream).await {
+                        Ok(client) => client,
+                        Err(err) => {
+                            log!("[P{}] Connect to {} failed: {:?}", id, upstream, err);
+                            self.pool.mark_down(upstream);
+                            tokio::spawn(conn.close(reconnect_code()));
+                            continue;
+                        }
+                    };
+                    if self.config.borrow().verbosity > 0 {
+                        log!("[P{}] Forwarding to {}", id, upstream);
+                    }
+                    let recorder = self.open_recording(id);
+                    let session = Session { conn, client, pool: self.pool.clone(), upstream };
+                    ProxyAdapterRunner::spawn(trace, session, self.config.clone(), recorder, self.redactor.clone(), self.compat_report.clone());
+                },
+                Ok(()) = self.config.changed() => {
+                    let targets = self.config.borrow_and_update().targets.clone();
+                    self.pool.set_addrs(targets);
                 },
             }
         }
     }
+
+    fn open_recording(&self, id: u64) -> Option<Recorder> {
+        let dir = self.record_dir.as_ref()?;
+        let path = dir.join(format!("conn-{}.mtrec", id));
+        let result = File::create(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| {
+                RecordingWriter::new(
+                    BufWriter::new(file),
+                    LATEST_PROTOCOL_VERSION,
+                    SER_FMT_HIGHEST_WRITE,
+                )
+            });
+        match result {
+            Ok(writer) => {
+                log!("[P{}] Recording to {:?}", id, path);
+                Some(writer)
+            }
+            Err(err) => {
+                log!("[P{}] Cannot record to {:?}: {:?}", id, path, err);
+                None
+            }
+        }
+    }
+}
+
+type Recorder = RecordingWriter<BufWriter<File>>;
+
+/// How often sessions look for stalls
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What players are told when their server goes away. The engine's client
+/// offers to reconnect, which picks a healthy upstream.
+fn reconnect_code() -> AccessDeniedCode {
+    AccessDeniedCode::Shutdown("Server unavailable, please reconnect".to_string(), true)
+}
+
+/// A player's connection, and the upstream it is forwarded to
+pub struct Session {
+    pub conn: MinetestConnection,
+    pub client: MinetestClient,
+    pub pool: UpstreamPool,
+    pub upstream: SocketAddr,
+}
+
+/// Why a session ended without an error
+enum SessionEnd {
+    /// The player's upstream is down
+    UpstreamLost,
 }
 
 pub struct ProxyAdapterRunner {
-    id: u64,
+    trace: SessionTrace,
     conn: MinetestConnection,
     client: MinetestClient,
-    verbosity: u8,
+    pool: UpstreamPool,
+    upstream: SocketAddr,
+    health: watch::Receiver<Vec<Upstream>>,
+    config: watch::Receiver<ProxyConfig>,
+    recorder: Option<Recorder>,
+    redactor: Option<Redactor>,
+    /// For the client leg, then the server leg
+    stalls: Option<[StallDetector; 2]>,
+    /// This session's part of the compatibility report
+    compat: Option<(CompatTally, Arc<CompatReportFile>)>,
 }
 
 impl ProxyAdapterRunner {
-    pub fn spawn(id: u64, conn: MinetestConnection, client: MinetestClient, verbosity: u8) {
-        let runner = ProxyAdapterRunner {
-            id,
-            conn,
-            client,
-            verbosity,
+    pub fn spawn(
+        trace: SessionTrace,
+        session: Session,
+        config: watch::Receiver<ProxyConfig>,
+        recorder: Option<Recorder>,
+        redactor: Option<Redactor>,
+        compat_report: Option<Arc<CompatReportFile>>,
+    ) {
+        let mut runner = ProxyAdapterRunner {
+            trace,
+            conn: session.conn,
+            client: session.client,
+            health: session.pool.subscribe(),
+            pool: session.pool,
+            upstream: session.upstream,
+            config,
+            recorder,
+            redactor,
+            stalls: None,
+            compat: compat_report.map(|report| (CompatTally::new(), report)),
         };
+        // Names and sizes only, nothing that needs redacting
+        runner
+            .conn
+            .keep_recent(RecentCommands::new(DEFAULT_RECENT_COMMANDS));
+        runner
+            .client
+            .keep_recent(RecentCommands::new(DEFAULT_RECENT_COMMANDS));
+        // Forward commands with the reliability they came with
+        runner.conn.set_split_policy(UnreliableSplitPolicy::Allow);
+        runner.client.set_split_policy(UnreliableSplitPolicy::Allow);
+        // Forwarded as is: what the two ends understand is up to them
+        runner.conn.set_compat_mode(CompatMode::Off);
+        runner.client.set_compat_mode(CompatMode::Off);
+        runner.apply_config();
         tokio::spawn(async move { runner.run().await });
     }
 
     pub async fn run(mut self) {
-        match self.run_inner().await {
-            Ok(_) => (),
+        let result = self.run_inner().await;
+        if let Some((mut tally, report)) = self.compat.take() {
+            if let Err(err) = &result {
+                tally.failure(err);
+            }
+            report.add(tally);
+        }
+        match result {
+            Ok(SessionEnd::UpstreamLost) => {
+                log!(
+                    "{} Upstream {} lost, asking the client to reconnect",
+                    self.trace.tag(),
+                    self.upstream
+                );
+                let _ = self.conn.close(reconnect_code()).await;
+            }
             Err(err) => {
-                let show_err = if let Some(err) = err.downcast_ref::<PeerError>() {
-                    match err {
-                        PeerError::PeerSentDisconnect => false,
-                        _ => true,
-                    }
-                } else {
-                    true
-                };
+                self.maybe_quarantine(&err);
+                let show_err = !PeerError::find(&err).is_some_and(PeerError::is_peer_disconnect);
                 if show_err {
-                    println!("[{}] Disconnected: {:?}", self.id, err)
+                    log!(
+                        "{} Disconnected: {:?}\n{}",
+                        self.trace.tag(),
+                        err,
+                        self.post_mortem()
+                    )
                 } else {
-                    println!("[{}] Disconnected", self.id)
+                    log!("{} Disconnected", self.trace.tag())
                 }
             }
         }
     }
 
-    pub async fn run_inner(&mut self) -> Result<()> {
+    async fn run_inner(&mut self) -> Result<SessionEnd> {
+        let mut stall_check = tokio::time::interval(STALL_CHECK_INTERVAL);
         loop {
             tokio::select! {
                 t = self.conn.recv() => {
-                    let command = t?;
-                    self.maybe_show(&command);
+                    let mut command = t?;
+                    if let Some((tally, _)) = &mut self.compat {
+                        tally.command(&command);
+                    }
+                    // Keep the server from picking a version we can't parse
+                    if let Some(spec) = command.as_init_mut() {
+                        spec.max_net_proto_version =
+                            spec.max_net_proto_version.min(LATEST_PROTOCOL_VERSION);
+                    }
+                    let received = Instant::now();
+                    let size = self.wire_size(&command);
+                    let shown = self.shows(&command, size);
+                    if !shown && self.recorder.is_none() {
+                        // Filtered out, so not worth redacting
+                    } else if let Some(redactor) = &self.redactor {
+                        let mut redacted = command.clone();
+                        redactor.redact_toserver(&mut redacted);
+                        self.observe(&redacted, received, size, shown);
+                    } else {
+                        self.observe(&command, received, size, shown);
+                    }
+                    let name = command.command_name();
                     self.client.send(command).await?;
+                    self.maybe_show_forwarded(Leg::Client, name, received, shown);
                 },
                 t = self.client.recv() => {
-                    let command = t?;
-                    self.maybe_show(&command);
+                    let command = match t {
+                        Ok(command) => command,
+                        Err(err) => return self.upstream_failed(err),
+                    };
+                    if let Some((tally, _)) = &mut self.compat {
+                        tally.command(&command);
+                    }
+                    let received = Instant::now();
+                    let size = self.wire_size(&command);
+                    let shown = self.shows(&command, size);
+                    if !shown && self.recorder.is_none() {
+                        // Filtered out, so not worth redacting
+                    } else if let Some(redactor) = &self.redactor {
+                        let mut redacted = command.clone();
+                        redactor.redact_toclient(&mut redacted);
+                        self.observe(&redacted, received, size, shown);
+                    } else {
+                        self.observe(&command, received, size, shown);
+                    }
+                    let name = command.command_name();
                     self.conn.send(command).await?;
-                }
+                    self.maybe_show_forwarded(Leg::Server, name, received, shown);
+                },
+                _ = self.health.changed() => {
+                    if !self.pool.is_healthy(self.upstream) {
+                        return Ok(SessionEnd::UpstreamLost);
+                    }
+                },
+                Ok(()) = self.config.changed() => {
+                    self.config.borrow_and_update();
+                    self.apply_config();
+                },
+                _ = stall_check.tick() => self.check_stalls(),
             }
         }
     }
 
+    /// Replaces the media quota, the stall detectors, the delivery
+    /// overrides and auditing with configured ones
+    fn apply_config(&mut self) {
+        let (media_quota, stall_timeout, delivery, audit) = {
+            let config = self.config.borrow();
+            (
+                config.media_quota,
+                config.stall_timeout,
+                config.delivery.clone(),
+                config.audit.includes(self.conn.remote_addr().ip()),
+            )
+        };
+        self.conn.set_delivery_overrides(delivery.clone());
+        self.client.set_delivery_overrides(delivery);
+        // Fails only once disconnected, which recv reports
+        let _ = self.conn.set_audit(audit);
+        let _ = self.client.set_audit(audit);
+        let meter = self.conn.meter_mut();
+        meter.clear_quotas();
+        if let Some(max_bytes) = media_quota {
+            meter.add_quota(Quota::media_sent(max_bytes, MEDIA_QUOTA_WINDOW));
+        }
+        self.stalls = stall_timeout.map(|timeout| [0; 2].map(|_| StallDetector::new(timeout)));
+    }
+
+    /// Dumps the session if a leg stalled
+    fn check_stalls(&mut self) {
+        let Some(stalls) = &mut self.stalls else {
+            return;
+        };
+        let now = Instant::now();
+        let legs = [
+            (Leg::Client, self.conn.stats()),
+            (Leg::Server, self.client.stats()),
+        ];
+        let stalled: Vec<_> = stalls
+            .iter_mut()
+            .zip(legs)
+            .filter_map(|(detector, (leg, stats))| Some((leg, detector.check(&stats, now)?)))
+            .collect();
+        for (leg, stall) in stalled {
+            log!(
+                "{} STALL on the {} leg, {}\n{}",
+                self.trace.tag(),
+                leg.name(),
+                stall,
+                self.post_mortem()
+            );
+        }
+    }
+
+    /// Both legs' post_mortem, with the addresses redacted under --redact
+    fn post_mortem(&self) -> String {
+        let mut dump = self.conn.post_mortem() + &self.client.post_mortem();
+        if let Some(redactor) = &self.redactor {
+            for addr in [self.conn.remote_addr(), self.upstream] {
+                dump = dump.replace(&addr.to_string(), &redactor.addr(&addr));
+            }
+        }
+        dump
+    }
+
+    /// The server side of the session failed. Only a failed transport
+    /// marks the upstream down, until its probe answers again; anything
+    /// else (a bad packet, a timeout, a server that hung up on purpose)
+    /// ends this session alone.
+    fn upstream_failed(&mut self, err: anyhow::Error) -> Result<SessionEnd> {
+        if !PeerError::find(&err).is_some_and(PeerError::is_transport_failure) {
+            return Err(err);
+        }
+        if let Some((tally, _)) = &mut self.compat {
+            tally.failure(&err);
+        }
+        log!(
+            "{} Upstream error: {:?}\n{}",
+            self.trace.tag(),
+            err,
+            self.post_mortem()
+        );
+        self.pool.mark_down(self.upstream);
+        Ok(SessionEnd::UpstreamLost)
+    }
+
+    /// Saves what failed to parse, if that's what the error is about
+    fn maybe_quarantine(&self, err: &anyhow::Error) {
+        let Some(failure) = err.downcast_ref::<ParseFailure>() else {
+            return;
+        };
+        let Some(dir) = self.config.borrow().quarantine.clone() else {
+            return;
+        };
+        match failure.quarantine(&dir) {
+            Ok(path) => log!(
+                "{} Quarantined the {} in {:?}",
+                self.trace.tag(),
+                failure.unit.name(),
+                path
+            ),
+            Err(err) => log!("{} Quarantine failed: {:?}", self.trace.tag(), err),
+        }
+    }
+
+    /// Show and record a command. With --redact, this only ever sees the
+    /// redacted copy.
+    fn observe<Cmd: CommandRef>(
+        &mut self,
+        command: &Cmd,
+        received: Instant,
+        size: Option<usize>,
+        shown: bool,
+    ) {
+        if shown {
+            self.show(command, received, size);
+        }
+        self.maybe_record(command);
+    }
+
+    /// Whether the command gets past the filters. Only its name and
+    /// properties are looked at, so this is cheap.
+    fn shows<Cmd: CommandRef>(&self, command: &Cmd, size: Option<usize>) -> bool {
+        let config = self.config.borrow();
+        if config.filter.contains(command.command_name()) {
+            return false;
+        }
+        match &config.filter_expr {
+            Some(filter) => filter.matches(command, size),
+            None => true,
+        }
+    }
+
+    /// Append the command to the recording, if there is one.
+    /// Recording stops (but proxying continues) after the first error.
+    pub fn maybe_record<Cmd: CommandRef>(&mut self, command: &Cmd) {
+        let Some(recorder) = self.recorder.as_mut() else {
+            return;
+        };
+        let mut result = recorder.write_command(command);
+        if result.is_ok() {
+            // The server's Hello switches both sides to the negotiated protocol
+            if let Some(ToClientCommand::Hello(spec)) = command.toclient_ref() {
+                result = recorder.set_context(spec.proto_ver, spec.serialization_ver);
+            }
+        }
+        if result.is_ok() {
+            result = recorder.flush();
+        }
+        if let Err(err) = result {
+            log!("{} Recording stopped: {:?}", self.trace.tag(), err);
+            self.recorder = None;
+        }
+    }
+
     pub fn is_bulk_command<Cmd: CommandRef>(&self, command: &Cmd) -> bool {
         if let Some(cmd) = command.toclient_ref() {
-            match cmd {
-                ToClientCommand::Blockdata(_) => true,
-                ToClientCommand::Media(_) => true,
-                _ => false,
-            }
+            matches!(
+                cmd,
+                ToClientCommand::Blockdata(_) | ToClientCommand::Media(_)
+            )
         } else {
             false
         }
     }
 
-    pub fn maybe_show<Cmd: CommandRef>(&self, command: &Cmd) {
-        let dir = match command.direction() {
-            CommandDirection::ToClient => "S->C",
-            CommandDirection::ToServer => "C->S",
+    /// Shows a command that got past the filters (see shows)
+    fn show<Cmd: CommandRef>(&self, command: &Cmd, received: Instant, size: Option<usize>) {
+        // The leg the command arrived on
+        let (dir, leg) = match command.direction() {
+            CommandDirection::ToClient => ("S->C", Leg::Server),
+            CommandDirection::ToServer => ("C->S", Leg::Client),
         };
-        let prefix = format!("[{}] {} ", self.id, dir);
-        let mut verbosity = self.verbosity;
+        let mut verbosity = self.config.borrow().verbosity;
         if verbosity == 2 && self.is_bulk_command(command) {
             // Show the contents of smaller commands, but skip the huge ones
             verbosity = 1;
         }
+        if OutputFormat::get() == OutputFormat::Jsonl {
+            self.show_json(command, leg, dir, verbosity > 1, received, size);
+            return;
+        }
+        if verbosity == 0 {
+            return;
+        }
+        let prefix = format!("{} {} ", self.trace.leg_tag(leg, received), dir);
         match verbosity {
-            0 => (),
-            1 => println!("{} {}", prefix, command.command_name()),
-            2.. => println!("{} {:#?}", prefix, command),
+            1 => log!("{} {}", prefix, command.command_name()),
+            _ => log!("{} {:#?}", prefix, command),
+        }
+    }
+
+    /// One line of JSON (see output), with the command's contents if
+    /// `contents`
+    fn show_json<Cmd: CommandRef>(
+        &self,
+        command: &Cmd,
+        leg: Leg,
+        dir: &str,
+        contents: bool,
+        received: Instant,
+        size: Option<usize>,
+    ) {
+        let timestamp = (SystemTime::now() - received.elapsed())
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let peer = match leg {
+            Leg::Client => self.conn.remote_addr(),
+            Leg::Server => self.upstream,
+        };
+        let peer = match &self.redactor {
+            Some(redactor) => redactor.addr(&peer),
+            None => peer.to_string(),
+        };
+        let mut line = Map::new();
+        line.insert("timestamp".into(), timestamp.as_secs_f64().into());
+        line.extend(self.trace.leg_fields(leg, received));
+        line.insert("dir".into(), dir.into());
+        line.insert("peer".into(), peer.into());
+        let (channel, reliable) = self.config.borrow().delivery.resolve(command);
+        line.insert("channel".into(), channel.into());
+        line.insert("reliable".into(), reliable.into());
+        line.insert("size".into(), size.into());
+        line.insert("name".into(), command.command_name().into());
+        if contents {
+            let value = match (command.toclient_ref(), command.toserver_ref()) {
+                (Some(command), _) => serde_json::to_value(command),
+                (_, Some(command)) => serde_json::to_value(command),
+                _ => Ok(Value::Null),
+            };
+            match value {
+                Ok(value) => {
+                    line.insert("command".into(), value);
+                }
+                Err(err) => {
+                    line.insert("error".into(), err.to_string().into());
+                }
+            }
+        }
+        println!("{}", Value::Object(line));
+    }
+
+    /// Serialized size of a command for --output jsonl and filters on
+    /// size, in the protocol version of the session
+    fn wire_size<Cmd: CommandRef>(&self, command: &Cmd) -> Option<usize> {
+        let filter_size = self
+            .config
+            .borrow()
+            .filter_expr
+            .as_ref()
+            .is_some_and(|filter| filter.uses_size());
+        if OutputFormat::get() != OutputFormat::Jsonl && !filter_size {
+            return None;
+        }
+        let context = ProtocolContext {
+            dir: command.direction(),
+            ..self.conn.send_context()
+        };
+        let mut ser = MockSerializer::new(context);
+        serialize_commandref(command, &mut ser).ok()?;
+        Some(ser.len())
+    }
+
+    fn maybe_show_forwarded(&self, from: Leg, name: &str, received: Instant, shown: bool) {
+        if shown && self.config.borrow().verbosity > 0 {
+            self.trace.forwarded(from, name, received);
         }
     }
 }
@@ -0,0 +1,121 @@
+//!
+//! Session tracing
+//!
+//! A proxied session has two legs: the client's connection to the proxy,
+//! and the proxy's connection to the server. With --trace, both legs share
+//! one trace id, each leg is a span of it, and every logged line is tagged
+//! with them along with the time since the session started. Log lines from
+//! many interleaved sessions can then be separated with grep, and timed.
+//!
+//! Each forwarded command also shows how long it spent in the proxy,
+//! from being received on one leg to being queued on the other.
+//!
+use rand::Rng;
+use serde_json::Map;
+use serde_json::Value;
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leg {
+    /// Client <-> proxy
+    Client,
+    /// Proxy <-> server
+    Server,
+}
+
+impl Leg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Leg::Client => "client",
+            Leg::Server => "server",
+        }
+    }
+}
+
+pub struct SessionTrace {
+    id: u64,
+    enabled: bool,
+    trace_id: u64,
+    start: Instant,
+}
+
+impl SessionTrace {
+    pub fn new(id: u64, enabled: bool) -> Self {
+        Self {
+            id,
+            enabled,
+            trace_id: rand::thread_rng().gen(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:016x}", self.trace_id)
+    }
+
+    pub fn span_id(&self, leg: Leg) -> String {
+        format!("{}-{}", self.trace_id(), leg.name())
+    }
+
+    /// The tag for log lines about the session as a whole
+    pub fn tag(&self) -> String {
+        if self.enabled {
+            format!(
+                "[{} trace={} +{}]",
+                self.id,
+                self.trace_id(),
+                secs(self.start.elapsed())
+            )
+        } else {
+            format!("[{}]", self.id)
+        }
+    }
+
+    /// The tag for a command received on `leg`
+    pub fn leg_tag(&self, leg: Leg, received: Instant) -> String {
+        if self.enabled {
+            format!(
+                "[{} trace={} span={} +{}]",
+                self.id,
+                self.trace_id(),
+                self.span_id(leg),
+                secs(received.duration_since(self.start))
+            )
+        } else {
+            format!("[{}]", self.id)
+        }
+    }
+
+    /// The fields of leg_tag, for a JSON line
+    pub fn leg_fields(&self, leg: Leg, received: Instant) -> Map<String, Value> {
+        let mut fields = Map::new();
+        fields.insert("session".into(), self.id.into());
+        if self.enabled {
+            fields.insert("trace".into(), self.trace_id().into());
+            fields.insert("span".into(), self.span_id(leg).into());
+            fields.insert(
+                "at".into(),
+                received.duration_since(self.start).as_secs_f64().into(),
+            );
+        }
+        fields
+    }
+
+    /// Log that a command received on `from` at `received` has been
+    /// forwarded to the other leg.
+    pub fn forwarded(&self, from: Leg, name: &str, received: Instant) {
+        if self.enabled {
+            log!(
+                "{} forwarded {} in {:.3}ms",
+                self.leg_tag(from, received),
+                name,
+                received.elapsed().as_secs_f64() * 1000.0
+            );
+        }
+    }
+}
+
+fn secs(d: Duration) -> String {
+    format!("{:.6}s", d.as_secs_f64())
+}
@@ -0,0 +1,210 @@
+//!
+//! Upstream pool
+//!
+//! The target servers behind the proxy, in order of preference. The first
+//! is the primary, the rest are fallbacks:
+//!
+//! ```text
+//! mtshark -l 30000 -t primary:30001 -t standby:30001
+//! ```
+//!
+//! Each upstream is probed every HEALTH_INTERVAL. A probe sends an Init
+//! with an empty player name, which every server refuses right away: any
+//! reply at all means the server is up. After UNHEALTHY_AFTER failed probes
+//! in a row, the upstream is marked down. So is the upstream of a session
+//! whose socket to the server fails. Anything else going wrong in a session
+//! (a packet that doesn't parse, a timeout) only ends that session.
+//!
+//! New players go to the first healthy upstream. Players on an upstream
+//! that goes down get an AccessDenied Shutdown asking them to reconnect,
+//! which lands them on the next healthy one.
+//!
+//! The upstreams can be replaced while the proxy runs (see config).
+//!
+use minetest_protocol::wire::command::InitSpec;
+use minetest_protocol::wire::packet::LATEST_PROTOCOL_VERSION;
+use minetest_protocol::wire::packet::SER_FMT_HIGHEST_READ;
+use minetest_protocol::MinetestClient;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Time between probes of each upstream
+const HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a probe waits for the server's reply
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Failed probes in a row before an upstream is marked down
+const UNHEALTHY_AFTER: u32 = 2;
+
+/// Oldest protocol version offered in probes
+const PROBE_MIN_PROTOCOL_VERSION: u16 = 37;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Upstream {
+    addr: SocketAddr,
+    healthy: bool,
+    /// Told apart from the same address removed and added back, whose
+    /// old health check may still be running
+    id: u64,
+}
+
+#[derive(Clone)]
+pub struct UpstreamPool {
+    /// In order of preference
+    upstreams: Arc<watch::Sender<Vec<Upstream>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl UpstreamPool {
+    /// Starts probing `addrs`. Every upstream counts as healthy until a
+    /// probe says otherwise.
+    pub fn new(addrs: Vec<SocketAddr>) -> Self {
+        let (upstreams, _) = watch::channel(Vec::new());
+        let pool = Self {
+            upstreams: Arc::new(upstreams),
+            next_id: Arc::new(AtomicU64::new(0)),
+        };
+        pool.set_addrs(addrs);
+        pool
+    }
+
+    /// Replaces the upstreams. Addresses already in the pool keep their
+    /// health. Sessions on a removed upstream carry on, and it counts as
+    /// healthy for them.
+    pub fn set_addrs(&self, addrs: Vec<SocketAddr>) {
+        let mut added = Vec::new();
+        self.upstreams.send_modify(|upstreams| {
+            let old = std::mem::take(upstreams);
+            for addr in addrs {
+                if upstreams.iter().any(|upstream| upstream.addr == addr) {
+                    continue;
+                }
+                let upstream = match old.iter().find(|upstream| upstream.addr == addr) {
+                    Some(upstream) => *upstream,
+                    None => {
+                        let upstream = Upstream {
+                            addr,
+                            healthy: true,
+                            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                        };
+                        added.push(upstream);
+                        upstream
+                    }
+                };
+                upstreams.push(upstream);
+            }
+        });
+        for upstream in added {
+            let pool = self.clone();
+            tokio::spawn(async move { pool.check_health(upstream).await });
+        }
+    }
+
+    /// The first healthy upstream, if any
+    pub fn pick(&self) -> Option<SocketAddr> {
+        self.upstreams
+            .borrow()
+            .iter()
+            .find(|upstream| upstream.healthy)
+            .map(|upstream| upstream.addr)
+    }
+
+    pub fn is_healthy(&self, addr: SocketAddr) -> bool {
+        self.upstreams
+            .borrow()
+            .iter()
+            .all(|upstream| upstream.addr != addr || upstream.healthy)
+    }
+
+    /// Notified whenever the upstreams or their health change
+    pub fn subscribe(&self) -> watch::Receiver<Vec<Upstream>> {
+        self.upstreams.subscribe()
+    }
+
+    /// Marks the upstream down until its next successful probe
+    pub fn mark_down(&self, addr: SocketAddr) {
+        self.set_health(addr, None, false);
+    }
+
+    /// Sets the health of `addr`, if it is in the pool (as `id`, if given).
+    /// Returns false if it isn't.
+    fn set_health(&self, addr: SocketAddr, id: Option<u64>, healthy: bool) -> bool {
+        let mut found = false;
+        self.upstreams.send_if_modified(|upstreams| {
+            let Some(upstream) = upstreams
+                .iter_mut()
+                .find(|upstream| upstream.addr == addr && id.is_none_or(|id| id == upstream.id))
+            else {
+                return false;
+            };
+            found = true;
+            if upstream.healthy == healthy {
+                return false;
+            }
+            upstream.healthy = healthy;
+            let state = if healthy { "up" } else { "DOWN" };
+            log!("Upstream {} is {}", addr, state);
+            true
+        });
+        found
+    }
+
+    /// Probes the upstream until it is removed from the pool
+    async fn check_health(self, upstream: Upstream) {
+        let mut failures: u32 = 0;
+        loop {
+            let healthy = if probe(upstream.addr).await {
+                failures = 0;
+                Some(true)
+            } else {
+                failures += 1;
+                (failures >= UNHEALTHY_AFTER).then_some(false)
+            };
+            let present = match healthy {
+                Some(healthy) => self.set_health(upstream.addr, Some(upstream.id), healthy),
+                None => self.is_present(&upstream),
+            };
+            if !present {
+                return;
+            }
+            tokio::time::sleep(HEALTH_INTERVAL).await;
+        }
+    }
+
+    fn is_present(&self, upstream: &Upstream) -> bool {
+        self.upstreams
+            .borrow()
+            .iter()
+            .any(|other| other.addr == upstream.addr && other.id == upstream.id)
+    }
+}
+
+/// True if the server at `addr` answers an Init
+async fn probe(addr: SocketAddr) -> bool {
+    let attempt = async {
+        let mut client = MinetestClient::connect(addr).await?;
+        client
+            .send(
+                InitSpec {
+                    serialization_ver_max: SER_FMT_HIGHEST_READ,
+                    supp_compr_modes: 0,
+                    min_net_proto_version: PROBE_MIN_PROTOCOL_VERSION,
+                    max_net_proto_version: LATEST_PROTOCOL_VERSION,
+                    player_name: String::new(),
+                }
+                .into(),
+            )
+            .await?;
+        client.recv().await?;
+        anyhow::Ok(())
+    };
+    matches!(
+        tokio::time::timeout(PROBE_TIMEOUT, attempt).await,
+        Ok(Ok(()))
+    )
+}
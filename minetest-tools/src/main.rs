@@ -0,0 +1,110 @@
+mod player;
+mod world;
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use clap::Subcommand;
+use minetest_protocol::wire::types::v3s16;
+
+/// mttools - Minetest server administration
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Look into a world's map and files
+    #[command(subcommand)]
+    World(WorldCommand),
+
+    /// Players and their accounts
+    #[command(subcommand)]
+    Player(PlayerCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum WorldCommand {
+    /// world.mt, env_meta.txt, and how many blocks, players and accounts
+    /// there are
+    Info {
+        /// World directory
+        world: PathBuf,
+    },
+
+    /// Everything in one map block: its nodes, node metadata, static
+    /// objects and node timers
+    DumpBlock {
+        /// World directory
+        world: PathBuf,
+
+        /// Block position (x,y,z)
+        #[arg(value_parser = parse_pos, allow_hyphen_values = true)]
+        pos: v3s16,
+
+        /// The position is a node's, dump the block it's in
+        #[arg(long, default_value_t = false)]
+        node: bool,
+    },
+
+    /// How many of a node there are in the map, and in how many blocks
+    CountNodes {
+        /// World directory
+        world: PathBuf,
+
+        /// Node name, e.g. default:diamondblock
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PlayerCommand {
+    /// Every player, with where they are and their privileges
+    List {
+        /// World directory
+        world: PathBuf,
+    },
+
+    /// One player's position, inventory, metadata and privileges
+    Show {
+        /// World directory
+        world: PathBuf,
+
+        /// Player name
+        name: String,
+    },
+}
+
+/// x,y,z
+fn parse_pos(s: &str) -> Result<v3s16, String> {
+    let parts: Vec<&str> = s.split(',').map(|part| part.trim()).collect();
+    let [x, y, z] = parts[..] else {
+        return Err("expected x,y,z".to_string());
+    };
+    let parse = |v: &str| v.parse::<i16>().map_err(|err| err.to_string());
+    Ok(v3s16::new(parse(x)?, parse(y)?, parse(z)?))
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    match args.command {
+        Command::World(WorldCommand::Info { world }) => world::info(&world),
+        Command::World(WorldCommand::DumpBlock { world, pos, node }) => {
+            let pos = if node {
+                let block = |v: i16| v.div_euclid(16);
+                v3s16::new(block(pos.x), block(pos.y), block(pos.z))
+            } else {
+                pos
+            };
+            world::dump_block(&world, &pos)
+        }
+        Command::World(WorldCommand::CountNodes { world, name }) => {
+            world::count_nodes(&world, &name)
+        }
+        Command::Player(PlayerCommand::List { world }) => player::list(&world),
+        Command::Player(PlayerCommand::Show { world, name }) => player::show(&world, &name),
+    }
+}
@@ -0,0 +1,194 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::bail;
+use anyhow::Result;
+use minetest_protocol::wire::types::ItemStackUpdate;
+use minetest_protocol::world::auth::read_auth_txt;
+use minetest_protocol::world::players::item_string;
+use minetest_protocol::world::AuthDatabase;
+use minetest_protocol::world::AuthEntry;
+use minetest_protocol::world::PlayerDatabase;
+use minetest_protocol::world::PlayerFiles;
+use minetest_protocol::world::PlayerRecord;
+use minetest_protocol::world::WorldMt;
+
+/// Engine units per node
+const BS: f32 = 10.0;
+
+/// The players of a world, in the backend its world.mt names
+pub enum Players {
+    Sqlite(PlayerDatabase),
+    Files(PlayerFiles),
+}
+
+impl Players {
+    pub fn open(dir: &Path) -> Result<Self> {
+        match WorldMt::read(dir)?.player_backend() {
+            "sqlite3" => {
+                let path = dir.join("players.sqlite");
+                if !path.exists() {
+                    bail!("No players.sqlite in {:?}", dir);
+                }
+                Ok(Self::Sqlite(PlayerDatabase::open(&path)?))
+            }
+            "files" => Ok(Self::Files(PlayerFiles::open_world(dir))),
+            backend => bail!("Player backend {} is not supported", backend),
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        match self {
+            Self::Sqlite(db) => db.list(),
+            Self::Files(files) => files.list(),
+        }
+    }
+
+    pub fn load(&self, name: &str) -> Result<Option<PlayerRecord>> {
+        match self {
+            Self::Sqlite(db) => db.load(name),
+            Self::Files(files) => files.load(name),
+        }
+    }
+}
+
+/// The accounts of a world, in the backend its world.mt names
+pub enum Accounts {
+    Sqlite(AuthDatabase),
+    Files(Vec<AuthEntry>),
+}
+
+impl Accounts {
+    pub fn open(dir: &Path) -> Result<Self> {
+        match WorldMt::read(dir)?.auth_backend() {
+            "sqlite3" => {
+                let path = dir.join("auth.sqlite");
+                if !path.exists() {
+                    bail!("No auth.sqlite in {:?}", dir);
+                }
+                Ok(Self::Sqlite(AuthDatabase::open(&path)?))
+            }
+            "files" => Ok(Self::Files(read_auth_txt(dir)?)),
+            backend => bail!("Auth backend {} is not supported", backend),
+        }
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        match self {
+            Self::Sqlite(db) => Ok(db.list()?.len()),
+            Self::Files(entries) => Ok(entries.len()),
+        }
+    }
+
+    pub fn load(&self, name: &str) -> Result<Option<AuthEntry>> {
+        match self {
+            Self::Sqlite(db) => db.load(name),
+            Self::Files(entries) => Ok(entries.iter().find(|entry| entry.name == name).cloned()),
+        }
+    }
+}
+
+fn node_position(player: &PlayerRecord) -> String {
+    let p = &player.position;
+    format!("({:.0},{:.0},{:.0})", p.x / BS, p.y / BS, p.z / BS)
+}
+
+fn privileges(entry: &AuthEntry) -> String {
+    let privileges: Vec<&str> = entry.privileges.iter().map(String::as_str).collect();
+    privileges.join(",")
+}
+
+fn last_login(entry: &AuthEntry) -> String {
+    if entry.last_login < 0 {
+        return "never".to_string();
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0);
+    let days = (now - entry.last_login).max(0) / 86400;
+    match days {
+        0 => "today".to_string(),
+        1 => "yesterday".to_string(),
+        days => format!("{} days ago", days),
+    }
+}
+
+pub fn list(world: &Path) -> Result<()> {
+    let players = Players::open(world)?;
+    let accounts = Accounts::open(world).ok();
+    for name in players.list()? {
+        let Some(player) = players.load(&name)? else {
+            continue;
+        };
+        let entry = match &accounts {
+            Some(accounts) => accounts.load(&name)?,
+            None => None,
+        };
+        let (login, privileges) = match &entry {
+            Some(entry) => (last_login(entry), privileges(entry)),
+            None => ("no account".to_string(), String::new()),
+        };
+        println!(
+            "{:<20} {:<20} hp {:>2}  {:<12} {}",
+            name,
+            node_position(&player),
+            player.hp,
+            login,
+            privileges
+        );
+    }
+    Ok(())
+}
+
+pub fn show(world: &Path, name: &str) -> Result<()> {
+    let Some(player) = Players::open(world)?.load(name)? else {
+        bail!("No player {:?}", name);
+    };
+    println!("Player: {}", player.name);
+    println!(
+        "  position: {}, pitch {:.1}, yaw {:.1}",
+        node_position(&player),
+        player.pitch,
+        player.yaw
+    );
+    println!("  hp: {}, breath: {}", player.hp, player.breath);
+    if let Some(entry) = Accounts::open(world)
+        .ok()
+        .map(|a| a.load(name))
+        .transpose()?
+    {
+        match entry {
+            Some(entry) => {
+                println!("  last login: {}", last_login(&entry));
+                println!("  privileges: {}", privileges(&entry));
+            }
+            None => println!("  no account"),
+        }
+    }
+    for list in player.lists() {
+        let used = list
+            .items
+            .iter()
+            .filter(|item| matches!(item, ItemStackUpdate::Item(_)))
+            .count();
+        println!(
+            "Inventory {} ({} of {} slots):",
+            list.name,
+            used,
+            list.items.len()
+        );
+        for (slot, item) in list.items.iter().enumerate() {
+            if let ItemStackUpdate::Item(_) = item {
+                println!("  {:>3}  {}", slot, item_string(item)?);
+            }
+        }
+    }
+    if !player.metadata.is_empty() {
+        println!("Metadata:");
+        for (key, value) in &player.metadata {
+            println!("  {} = {}", key, value);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Result;
+use minetest_protocol::wire::types::v3s16;
+use minetest_protocol::wire::types::InventoryEntry;
+use minetest_protocol::world::open_world_map;
+use minetest_protocol::world::par_map_blocks;
+use minetest_protocol::world::DiskMapBlock;
+use minetest_protocol::world::EnvMeta;
+use minetest_protocol::world::MapDatabase;
+use minetest_protocol::world::WorldMt;
+use rayon::prelude::*;
+
+use crate::player::Accounts;
+use crate::player::Players;
+
+/// Nodes per block edge
+const BLOCK_SIZE: i16 = 16;
+
+/// Engine units per node
+const BS: f32 = 10.0;
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// A count, or why there isn't one
+fn count(count: Result<usize>) -> String {
+    match count {
+        Ok(count) => count.to_string(),
+        Err(err) => format!("unknown ({})", err),
+    }
+}
+
+pub fn info(world: &Path) -> Result<()> {
+    let world_mt = WorldMt::read(world)?;
+    println!("World: {}", world_mt.world_name().unwrap_or("(unnamed)"));
+    println!("  game: {}", world_mt.gameid().unwrap_or("(unknown)"));
+    println!("  creative mode: {}", yes_no(world_mt.creative_mode()));
+    println!("  damage: {}", yes_no(world_mt.enable_damage()));
+    println!(
+        "Backends: map {}, players {}, auth {}, mod storage {}",
+        world_mt.backend()?,
+        world_mt.player_backend(),
+        world_mt.auth_backend(),
+        world_mt.mod_storage_backend()
+    );
+    match EnvMeta::read(world) {
+        Ok(env) => {
+            let ticks = env.time_of_day;
+            println!(
+                "Time: day {}, {:02}:{:02}, {} s of game time",
+                env.day_count,
+                ticks / 1000,
+                ticks % 1000 * 60 / 1000,
+                env.game_time
+            );
+        }
+        Err(err) => println!("Time: unknown ({})", err),
+    }
+
+    let blocks = open_world_map(world, false).and_then(|db| Ok(db.list_blocks()?.len()));
+    println!("Blocks: {}", count(blocks));
+    let players = Players::open(world).and_then(|players| Ok(players.list()?.len()));
+    println!("Players: {}", count(players));
+    let accounts = Accounts::open(world).and_then(|accounts| accounts.count());
+    println!("Accounts: {}", count(accounts));
+
+    let mods = world_mt.enabled_mods();
+    println!("Mods enabled: {}", mods.len());
+    for name in mods {
+        println!("  {}", name);
+    }
+    Ok(())
+}
+
+pub fn dump_block(world: &Path, pos: &v3s16) -> Result<()> {
+    let db = open_world_map(world, false)?;
+    let Some(data) = db.get_block(pos)? else {
+        bail!("No block at ({},{},{})", pos.x, pos.y, pos.z);
+    };
+    let block = DiskMapBlock::decode(&data)?;
+    let origin = v3s16::new(pos.x * BLOCK_SIZE, pos.y * BLOCK_SIZE, pos.z * BLOCK_SIZE);
+
+    println!(
+        "Block ({},{},{}), nodes ({},{},{}) to ({},{},{})",
+        pos.x,
+        pos.y,
+        pos.z,
+        origin.x,
+        origin.y,
+        origin.z,
+        origin.x + BLOCK_SIZE - 1,
+        origin.y + BLOCK_SIZE - 1,
+        origin.z + BLOCK_SIZE - 1
+    );
+    println!("  version: {}, {} bytes", block.version, data.len());
+    println!("  timestamp: {}", block.timestamp);
+    println!(
+        "  generated: {}, underground: {}, day/night differs: {}",
+        yes_no(block.generated),
+        yes_no(block.is_underground),
+        yes_no(block.day_night_diff)
+    );
+    if let Some(lighting) = block.lighting_complete {
+        println!("  lighting complete: {:#06x}", lighting);
+    }
+
+    let mut counts: BTreeMap<u16, u64> = BTreeMap::new();
+    for node in block.nodes.iter() {
+        *counts.entry(node.param0).or_default() += 1;
+    }
+    println!("Nodes:");
+    for (id, count) in counts {
+        let name = block.name_id_mapping.get(id).unwrap_or("(unmapped)");
+        println!("  {:>5}  {:>4}  {}", count, id, name);
+    }
+
+    let node_pos = |rel: &v3s16| {
+        format!(
+            "({},{},{})",
+            origin.x + rel.x,
+            origin.y + rel.y,
+            origin.z + rel.z
+        )
+    };
+    if !block.node_metadata.metadata.is_empty() {
+        println!("Node metadata:");
+    }
+    for (rel, meta) in &block.node_metadata.metadata {
+        let node = block
+            .nodes
+            .get(rel.raw as usize)
+            .and_then(|node| block.node_name(node));
+        println!(
+            "  {} {}",
+            node_pos(&rel.to_xyz()),
+            node.unwrap_or("(unmapped)")
+        );
+        for var in &meta.stringvars {
+            let private = if var.is_private { " (private)" } else { "" };
+            println!(
+                "    {} = {:?}{}",
+                var.name,
+                String::from_utf8_lossy(&var.value),
+                private
+            );
+        }
+        for entry in &meta.inventory.entries {
+            let InventoryEntry::Update(list) = entry else {
+                continue;
+            };
+            println!("    inventory {}: {} slots", list.name, list.items.len());
+        }
+    }
+
+    if !block.static_objects.objects.is_empty() {
+        println!("Static objects:");
+    }
+    for object in &block.static_objects.objects {
+        let p = &object.pos;
+        let kind = match object.lua_entity() {
+            Some(entity) => entity.name,
+            None => format!("type {}", object.type_id),
+        };
+        println!(
+            "  ({:.1},{:.1},{:.1}) {}",
+            p.x / BS,
+            p.y / BS,
+            p.z / BS,
+            kind
+        );
+    }
+
+    if !block.node_timers.timers.is_empty() {
+        println!("Node timers:");
+    }
+    for timer in &block.node_timers.timers {
+        println!(
+            "  {} {:.1} of {:.1} s",
+            node_pos(&timer.pos),
+            timer.elapsed,
+            timer.timeout
+        );
+    }
+    Ok(())
+}
+
+/// Nodes named `name`, blocks with any, and unreadable blocks
+#[derive(Default)]
+struct NodeCount {
+    nodes: u64,
+    blocks: u64,
+    unreadable: u64,
+}
+
+impl NodeCount {
+    fn merged(self, other: Self) -> Self {
+        Self {
+            nodes: self.nodes + other.nodes,
+            blocks: self.blocks + other.blocks,
+            unreadable: self.unreadable + other.unreadable,
+        }
+    }
+}
+
+pub fn count_nodes(world: &Path, name: &str) -> Result<()> {
+    let db = open_world_map(world, false)?;
+    let total = par_map_blocks(&db, |_, block| {
+        let block = match block {
+            Ok(block) => block,
+            Err(_) => {
+                return NodeCount {
+                    unreadable: 1,
+                    ..Default::default()
+                }
+            }
+        };
+        let Some(id) = block.name_id_mapping.id_of(name) else {
+            return NodeCount::default();
+        };
+        let nodes = block.nodes.iter().filter(|node| node.param0 == id).count() as u64;
+        NodeCount {
+            nodes,
+            blocks: (nodes > 0) as u64,
+            unreadable: 0,
+        }
+    })?
+    .reduce(NodeCount::default, NodeCount::merged);
+
+    println!("{}: {} in {} blocks", name, total.nodes, total.blocks);
+    if total.unreadable > 0 {
+        println!("  {} blocks unreadable", total.unreadable);
+    }
+    Ok(())
+}
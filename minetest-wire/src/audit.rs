@@ -0,0 +1,411 @@
+//! Audit
+//!
+//! When auditing is enabled for a ProtocolContext (ProtocolContext::audit),
+//! every Command deserialized in it is immediately re-serialized, and the
+//! results compared byte-by-byte. A peer audits what it receives once
+//! Peer::set_audit is on, so one suspicious connection can be audited while
+//! the others aren't.
+//!
+//! Any difference is reported as an AuditMismatch, and handled by the
+//! AuditPolicy, which is the same for the whole process:
+//!
+//! ```text
+//! set_audit_policy(AuditPolicy::LogAndContinue);
+//! set_audit_policy(AuditPolicy::Callback(Arc::new(|mismatch| {
+//!     mismatches.lock().unwrap().push(mismatch.clone());
+//! })));
+//! conn.set_audit(true)?;
+//! ```
+//!
+//! This is useful during development, to verify that new ser/deser methods are correct.
+//!
+//! With the default policy (Panic) it should not be enabled normally, because
+//! a malformed packet from a broken/modified client will cause a crash.
+
+use core::ops::Range;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use super::command::serialize_commandref;
+use super::command::Command;
+use super::command::CommandRef;
+use super::command::ToClientCommand;
+use super::ser::VecSerializer;
+use super::types::ProtocolContext;
+use super::util::decompress_zlib;
+use super::util::hexdump;
+use super::util::zstd_decompress;
+
+static AUDIT_POLICY: RwLock<AuditPolicy> = RwLock::new(AuditPolicy::Panic);
+
+/// What to do with an AuditMismatch
+#[derive(Clone)]
+pub enum AuditPolicy {
+    /// Panic with the report
+    Panic,
+    /// Print the report to stderr, and carry on with the command as parsed
+    LogAndContinue,
+    /// Hand the report to a handler, and carry on with the command as parsed
+    Callback(Arc<dyn Fn(&AuditMismatch) + Send + Sync>),
+}
+
+impl fmt::Debug for AuditPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditPolicy::Panic => write!(f, "Panic"),
+            AuditPolicy::LogAndContinue => write!(f, "LogAndContinue"),
+            AuditPolicy::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+/// For the whole process
+pub fn set_audit_policy(policy: AuditPolicy) {
+    *AUDIT_POLICY.write().unwrap_or_else(|err| err.into_inner()) = policy;
+}
+
+pub fn audit_policy() -> AuditPolicy {
+    AUDIT_POLICY
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+/// A command that didn't re-serialize to the bytes it was parsed from
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditMismatch {
+    /// The part that was compared, e.g. "default" or "zlib decompressed body"
+    pub what: &'static str,
+    /// The command as parsed
+    pub command: Command,
+    /// The compared bytes (decompressed for the parts that are compressed)
+    pub original: Vec<u8>,
+    pub reserialized: Vec<u8>,
+    /// The byte ranges that differ. Past the end of the shorter one, the
+    /// range covers the rest of the longer one.
+    pub differences: Vec<Range<usize>>,
+    /// Why the comparison couldn't be made (e.g. re-serialization failed),
+    /// if it couldn't
+    pub error: Option<String>,
+}
+
+impl AuditMismatch {
+    fn new<Cmd: CommandRef>(what: &'static str, orig: &[u8], reser: &[u8], command: &Cmd) -> Self {
+        Self {
+            what,
+            command: to_command(command),
+            original: orig.to_vec(),
+            reserialized: reser.to_vec(),
+            differences: differences(orig, reser),
+            error: None,
+        }
+    }
+
+    fn failed<Cmd: CommandRef>(
+        what: &'static str,
+        orig: &[u8],
+        reser: &[u8],
+        command: &Cmd,
+        error: &anyhow::Error,
+    ) -> Self {
+        Self {
+            error: Some(format!("{:#}", error)),
+            ..Self::new(what, orig, reser, command)
+        }
+    }
+}
+
+impl fmt::Display for AuditMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(error) => writeln!(f, "AUDIT: {} failed: {}", self.what, error)?,
+            None => writeln!(
+                f,
+                "AUDIT: Mismatch between original and re-serialized ({})",
+                self.what
+            )?,
+        }
+        let ranges: Vec<String> = self
+            .differences
+            .iter()
+            .map(|range| format!("{}..{}", range.start, range.end))
+            .collect();
+        if !ranges.is_empty() {
+            writeln!(f, "AUDIT: DIFFERING BYTES = {}", ranges.join(", "))?;
+        }
+        let mark = self.differences.first().map(|range| range.start);
+        writeln!(f, "AUDIT: PARSED = {:?}", self.command)?;
+        writeln!(f, "AUDIT: ORIGINAL =")?;
+        write!(f, "{}", hexdump(&self.original, 0, mark))?;
+        writeln!(f, "AUDIT: RESERIALIZED =")?;
+        write!(f, "{}", hexdump(&self.reserialized, 0, mark))
+    }
+}
+
+impl std::error::Error for AuditMismatch {}
+
+fn to_command<Cmd: CommandRef>(command: &Cmd) -> Command {
+    match (command.toserver_ref(), command.toclient_ref()) {
+        (Some(command), _) => Command::ToServer(command.clone()),
+        (_, Some(command)) => Command::ToClient(command.clone()),
+        _ => unreachable!("A command goes one way or the other"),
+    }
+}
+
+/// The runs of bytes that differ between `a` and `b`
+fn differences(a: &[u8], b: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for i in 0..a.len().min(b.len()) {
+        if a[i] != b[i] {
+            match ranges.last_mut() {
+                Some(last) if last.end == i => last.end = i + 1,
+                _ => ranges.push(i..i + 1),
+            }
+        }
+    }
+    let (short, long) = (a.len().min(b.len()), a.len().max(b.len()));
+    if short < long {
+        match ranges.last_mut() {
+            Some(last) if last.end == short => last.end = long,
+            _ => ranges.push(short..long),
+        }
+    }
+    ranges
+}
+
+fn report(mismatch: AuditMismatch) {
+    match audit_policy() {
+        AuditPolicy::Panic => panic!("{}", mismatch),
+        AuditPolicy::LogAndContinue => eprint!("{}", mismatch),
+        AuditPolicy::Callback(handler) => handler(&mismatch),
+    }
+}
+
+pub fn audit_command<Cmd: CommandRef>(context: ProtocolContext, orig: &[u8], command: &Cmd) {
+    if !context.audit {
+        return;
+    }
+    let mut ser = VecSerializer::new(context, 2 * orig.len());
+    if let Err(err) = serialize_commandref(command, &mut ser) {
+        report(AuditMismatch::failed(
+            "Reserialization",
+            orig,
+            &[],
+            command,
+            &err,
+        ));
+        return;
+    }
+    let reser = ser.take();
+    let reser = reser.as_slice();
+    if let Err(err) = audit_command_inner(context, orig, reser, command) {
+        match err.downcast::<AuditMismatch>() {
+            Ok(mismatch) => report(mismatch),
+            Err(err) => report(AuditMismatch::failed(
+                "Comparison",
+                orig,
+                reser,
+                command,
+                &err,
+            )),
+        }
+    }
+}
+
+fn audit_command_inner<Cmd: CommandRef>(
+    context: ProtocolContext,
+    orig: &[u8],
+    reser: &[u8],
+    command: &Cmd,
+) -> Result<()> {
+    // Too short for the layouts below
+    if orig.len() < 14 || reser.len() < 14 {
+        return do_compare("default", reser, orig, command);
+    }
+    // zstd or zlib re-compression is not guaranteed to be the same,
+    // so handle these separately.
+    match command.toclient_ref() {
+        Some(ToClientCommand::Blockdata(_)) => {
+            if context.ser_fmt >= 29 {
+                // Layout in format 29 and above:
+                //
+                //   command type: u16
+                //   pos: v3s16, (6 bytes)
+                //   datastring: ZStdCompressed<MapBlock>,
+                //   network_specific_version: u8
+                do_compare(
+                    "BlockData prefix (ver>=29)",
+                    &reser[..8],
+                    &orig[..8],
+                    command,
+                )?;
+                do_compare(
+                    "BlockData suffix (ver>=29)",
+                    &reser[reser.len() - 1..reser.len()],
+                    &orig[orig.len() - 1..orig.len()],
+                    command,
+                )?;
+                let reser = zstd_decompress_to_vec(&reser[8..reser.len() - 1])?;
+                let orig = zstd_decompress_to_vec(&orig[8..orig.len() - 1])?;
+                do_compare("Blockdata contents (ver>=29)", &reser, &orig, command)?;
+            } else {
+                // Layout in ver 28:
+                //
+                //   command type: u16         (2 bytes)
+                //   pos: v3s16                (6 bytes)
+                //   flags: u8                 (1 byte)
+                //   lighting_complete: u16    (2 bytes)
+                //   content_width: u8         (1 byte)
+                //   param_width: u8           (1 byte)
+                //   nodes: ZLibCompressed     (var size)
+                //   metadata: ZLibCompressed  (var size)
+                //   network_specific_version  (1 byte)
+                do_compare(
+                    "BlockData prefix (ver==28)",
+                    &reser[..13],
+                    &orig[..13],
+                    command,
+                )?;
+                do_compare(
+                    "BlockData suffix (ver==28)",
+                    &reser[reser.len() - 1..],
+                    &orig[orig.len() - 1..],
+                    command,
+                )?;
+
+                let reser_contents = {
+                    let (consumed1, nodes_raw) = decompress_zlib(&reser[13..])?;
+                    let (consumed2, metadata_raw) = decompress_zlib(&reser[13 + consumed1..])?;
+                    if 13 + consumed1 + consumed2 + 1 != reser.len() {
+                        bail!("Reserialized command does not have the right size")
+                    }
+                    (nodes_raw, metadata_raw)
+                };
+                let orig_contents = {
+                    let (consumed1, nodes_raw) = decompress_zlib(&orig[13..])?;
+                    let (consumed2, metadata_raw) = decompress_zlib(&orig[13 + consumed1..])?;
+                    if 13 + consumed1 + consumed2 + 1 != orig.len() {
+                        bail!("Original command does not seem to have the right size")
+                    }
+                    (nodes_raw, metadata_raw)
+                };
+                do_compare(
+                    "Uncompressed nodes (ver 28)",
+                    &reser_contents.0,
+                    &orig_contents.0,
+                    command,
+                )?;
+                do_compare(
+                    "Uncompressed node metadata (ver 28)",
+                    &reser_contents.1,
+                    &orig_contents.1,
+                    command,
+                )?;
+            }
+        }
+        Some(ToClientCommand::NodemetaChanged(_))
+        | Some(ToClientCommand::Itemdef(_))
+        | Some(ToClientCommand::Nodedef(_)) => {
+            // These contain a single zlib-compressed value.
+            // The prefix is a u16 command type, followed by u32 zlib size.
+            let reser = zlib_decompress_to_vec(&reser[6..])?;
+            let orig = zlib_decompress_to_vec(&orig[6..])?;
+            do_compare("zlib decompressed body", &reser, &orig, command)?;
+        }
+        _ => {
+            do_compare("default", reser, orig, command)?;
+        }
+    };
+    Ok(())
+}
+
+fn do_compare<Cmd: CommandRef>(
+    what: &'static str,
+    reser: &[u8],
+    orig: &[u8],
+    command: &Cmd,
+) -> Result<()> {
+    if reser != orig {
+        bail!(AuditMismatch::new(what, orig, reser, command));
+    }
+    Ok(())
+}
+
+fn zlib_decompress_to_vec(compressed: &[u8]) -> Result<Vec<u8>> {
+    match miniz_oxide::inflate::decompress_to_vec_zlib(compressed) {
+        Ok(uncompressed) => Ok(uncompressed),
+        Err(err) => bail!("Decompression failed unexpectedly: {:?}", err),
+    }
+}
+
+fn zstd_decompress_to_vec(compressed: &[u8]) -> Result<Vec<u8>> {
+    let mut result = Vec::new();
+    zstd_decompress(compressed, |chunk| {
+        result.extend(chunk);
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::BreathSpec;
+    use crate::deser::Deserialize;
+    use crate::deser::Deserializer;
+    use std::sync::Mutex;
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(differences(&[1, 2, 3], &[1, 2, 3]), vec![]);
+        assert_eq!(differences(&[1, 2, 3, 4], &[1, 0, 0, 4]), vec![1..3]);
+        assert_eq!(differences(&[1, 2, 3], &[0, 2, 3, 4, 5]), vec![0..1, 3..5]);
+        assert_eq!(differences(&[1, 2, 3], &[1, 2, 0, 4]), vec![2..4]);
+    }
+
+    #[test]
+    fn mismatch_report() {
+        let context = ProtocolContext::latest_for_receive(true);
+        let command: ToClientCommand = BreathSpec { breath: 5 }.into();
+        let orig = [0x00, 0x4e, 0x00, 0x05];
+        assert!(audit_command_inner(context, &orig, &orig, &command).is_ok());
+
+        let reser = [0x00, 0x4e, 0x00, 0x06];
+        let err = audit_command_inner(context, &orig, &reser, &command).unwrap_err();
+        let mismatch = err.downcast::<AuditMismatch>().unwrap();
+        assert_eq!(mismatch.what, "default");
+        assert_eq!(mismatch.differences, vec![3..4]);
+        assert_eq!(mismatch.command, Command::ToClient(command));
+        let text = mismatch.to_string();
+        assert!(text.contains("AUDIT: DIFFERING BYTES = 3..4"));
+        assert!(text.contains("^^ offset 3"));
+    }
+
+    #[test]
+    fn per_context() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let handler = reported.clone();
+        set_audit_policy(AuditPolicy::Callback(Arc::new(move |mismatch| {
+            handler.lock().unwrap().push(mismatch.clone());
+        })));
+        // A trailing byte is left over by the parser, so it isn't re-serialized
+        let data = [0x00, 0x4e, 0x00, 0x05, 0xff];
+        let context = ProtocolContext::latest_for_receive(true);
+        ToClientCommand::deserialize(&mut Deserializer::new(context, &data)).unwrap();
+        assert!(reported.lock().unwrap().is_empty());
+
+        let audited = ProtocolContext {
+            audit: true,
+            ..context
+        };
+        let command = ToClientCommand::deserialize(&mut Deserializer::new(audited, &data)).unwrap();
+        let reported = reported.lock().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].command, Command::ToClient(command));
+        assert_eq!(reported[0].differences, vec![4..5]);
+    }
+}
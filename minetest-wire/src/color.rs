@@ -0,0 +1,409 @@
+//!
+//! Color strings
+//!
+//! Lua mods, formspecs, HUD definitions and texture modifiers describe
+//! colors as strings. These are the forms accepted by the engine's
+//! parseColorString:
+//!
+//! * "#RGB", "#RGBA", "#RRGGBB" and "#RRGGBBAA" (hex digits in any case)
+//! * CSS named colors, e.g. "red" or "darkslategray" (case insensitive)
+//! * A named color with an alpha suffix, e.g. "red#80" or "red#8"
+//!
+//! On the wire colors are SColor. ColorSpec keeps the form a color was
+//! written in, so configuration can be read and written back unchanged.
+//!
+use alloc::string::String;
+use alloc::string::ToString;
+use core::fmt;
+use core::str::FromStr;
+
+use super::types::SColor;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ColorError {
+    #[error("Invalid hex color string: {0:?}")]
+    InvalidHex(String),
+    #[error("Unknown color name: {0:?}")]
+    UnknownName(String),
+    #[error("Invalid alpha suffix: {0:?}")]
+    InvalidAlpha(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorSpec {
+    /// A hex color string. `digits` is the number of hex digits written (3, 4, 6 or 8).
+    Hex { color: SColor, digits: u8 },
+    /// A named color, with an optional alpha suffix. `alpha_digits` is 1 or 2.
+    Named {
+        name: String,
+        alpha: Option<u8>,
+        alpha_digits: u8,
+    },
+}
+
+impl ColorSpec {
+    pub fn parse(s: &str) -> Result<Self, ColorError> {
+        if let Some(hex) = s.strip_prefix('#') {
+            return parse_hex(hex)
+                .map(|color| ColorSpec::Hex {
+                    color,
+                    digits: hex.len() as u8,
+                })
+                .ok_or_else(|| ColorError::InvalidHex(s.to_string()));
+        }
+        let (name, alpha) = match s.split_once('#') {
+            Some((name, alpha)) => (name, Some(alpha)),
+            None => (s, None),
+        };
+        if named_color(name).is_none() {
+            return Err(ColorError::UnknownName(name.to_string()));
+        }
+        let (alpha, alpha_digits) = match alpha {
+            None => (None, 0),
+            Some(digits) => {
+                let value = match digits.len() {
+                    1 | 2 => parse_hex_digits(digits),
+                    _ => None,
+                }
+                .ok_or_else(|| ColorError::InvalidAlpha(digits.to_string()))?;
+                let value = if digits.len() == 1 {
+                    value * 0x11
+                } else {
+                    value
+                };
+                (Some(value), digits.len() as u8)
+            }
+        };
+        Ok(ColorSpec::Named {
+            name: name.to_string(),
+            alpha,
+            alpha_digits,
+        })
+    }
+
+    /// The canonical hex form, "#RRGGBB" or "#RRGGBBAA" when not opaque.
+    /// This is what the engine writes (encodeHexColorString).
+    pub fn hex(color: SColor) -> Self {
+        ColorSpec::Hex {
+            color,
+            digits: if color.a == 0xFF { 6 } else { 8 },
+        }
+    }
+
+    pub fn color(&self) -> SColor {
+        match self {
+            ColorSpec::Hex { color, .. } => *color,
+            ColorSpec::Named { name, alpha, .. } => {
+                let mut color = named_color(name).unwrap_or(SColor::new(0, 0, 0, 0xFF));
+                color.a = alpha.unwrap_or(0xFF);
+                color
+            }
+        }
+    }
+}
+
+impl From<SColor> for ColorSpec {
+    fn from(color: SColor) -> Self {
+        Self::hex(color)
+    }
+}
+
+impl From<ColorSpec> for SColor {
+    fn from(spec: ColorSpec) -> Self {
+        spec.color()
+    }
+}
+
+impl FromStr for ColorSpec {
+    type Err = ColorError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for ColorSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorSpec::Hex { color, digits } => {
+                let SColor { a, r, g, b } = *color;
+                match digits {
+                    3 => write!(f, "#{:x}{:x}{:x}", r >> 4, g >> 4, b >> 4),
+                    4 => write!(f, "#{:x}{:x}{:x}{:x}", r >> 4, g >> 4, b >> 4, a >> 4),
+                    6 => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+                    _ => write!(f, "#{:02x}{:02x}{:02x}{:02x}", r, g, b, a),
+                }
+            }
+            ColorSpec::Named {
+                name,
+                alpha,
+                alpha_digits,
+            } => match alpha {
+                None => f.write_str(name),
+                Some(a) if *alpha_digits == 1 => write!(f, "{}#{:x}", name, a >> 4),
+                Some(a) => write!(f, "{}#{:02x}", name, a),
+            },
+        }
+    }
+}
+
+impl FromStr for SColor {
+    type Err = ColorError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ColorSpec::parse(s)?.color())
+    }
+}
+
+impl SColor {
+    /// The engine's canonical colorstring for this color
+    pub fn to_colorstring(&self) -> String {
+        ColorSpec::hex(*self).to_string()
+    }
+}
+
+fn parse_hex_digits(digits: &str) -> Option<u8> {
+    if digits.bytes().all(|ch| ch.is_ascii_hexdigit()) {
+        u8::from_str_radix(digits, 16).ok()
+    } else {
+        None
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<SColor> {
+    let short = |i: usize| parse_hex_digits(hex.get(i..i + 1)?).map(|d| d * 0x11);
+    let long = |i: usize| parse_hex_digits(hex.get(i * 2..i * 2 + 2)?);
+    match hex.len() {
+        3 => Some(SColor::new(short(0)?, short(1)?, short(2)?, 0xFF)),
+        4 => Some(SColor::new(short(0)?, short(1)?, short(2)?, short(3)?)),
+        6 => Some(SColor::new(long(0)?, long(1)?, long(2)?, 0xFF)),
+        8 => Some(SColor::new(long(0)?, long(1)?, long(2)?, long(3)?)),
+        _ => None,
+    }
+}
+
+/// Look up a named color (opaque). Names are case insensitive.
+pub fn named_color(name: &str) -> Option<SColor> {
+    let name = name.to_ascii_lowercase();
+    let index = NAMED_COLORS
+        .binary_search_by(|(n, _)| (*n).cmp(name.as_str()))
+        .ok()?;
+    let rgb = NAMED_COLORS[index].1;
+    Some(SColor::new(
+        (rgb >> 16) as u8,
+        (rgb >> 8) as u8,
+        rgb as u8,
+        0xFF,
+    ))
+}
+
+/// The engine's named colors (the CSS color keywords), sorted by name.
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xf0f8ff),
+    ("antiquewhite", 0xfaebd7),
+    ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff),
+    ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4),
+    ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff),
+    ("blueviolet", 0x8a2be2),
+    ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887),
+    ("cadetblue", 0x5f9ea0),
+    ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50),
+    ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc),
+    ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b),
+    ("darkcyan", 0x008b8b),
+    ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9),
+    ("darkgreen", 0x006400),
+    ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b),
+    ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc),
+    ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a),
+    ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f),
+    ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3),
+    ("deeppink", 0xff1493),
+    ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969),
+    ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0),
+    ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff),
+    ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700),
+    ("goldenrod", 0xdaa520),
+    ("gray", 0x808080),
+    ("green", 0x008000),
+    ("greenyellow", 0xadff2f),
+    ("grey", 0x808080),
+    ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4),
+    ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082),
+    ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa),
+    ("lavenderblush", 0xfff0f5),
+    ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd),
+    ("lightblue", 0xadd8e6),
+    ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2),
+    ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3),
+    ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a),
+    ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899),
+    ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0),
+    ("lime", 0x00ff00),
+    ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6),
+    ("magenta", 0xff00ff),
+    ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd),
+    ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371),
+    ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc),
+    ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa),
+    ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5),
+    ("navajowhite", 0xffdead),
+    ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6),
+    ("olive", 0x808000),
+    ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500),
+    ("orangered", 0xff4500),
+    ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98),
+    ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5),
+    ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f),
+    ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6),
+    ("purple", 0x800080),
+    ("rebeccapurple", 0x663399),
+    ("red", 0xff0000),
+    ("rosybrown", 0xbc8f8f),
+    ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072),
+    ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57),
+    ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0),
+    ("skyblue", 0x87ceeb),
+    ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090),
+    ("slategrey", 0x708090),
+    ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4),
+    ("tan", 0xd2b48c),
+    ("teal", 0x008080),
+    ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347),
+    ("turquoise", 0x40e0d0),
+    ("violet", 0xee82ee),
+    ("wheat", 0xf5deb3),
+    ("white", 0xffffff),
+    ("whitesmoke", 0xf5f5f5),
+    ("yellow", 0xffff00),
+    ("yellowgreen", 0x9acd32),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deser::Deserialize;
+    use crate::deser::Deserializer;
+    use crate::types::ProtocolContext;
+
+    #[test]
+    fn parses_colorstrings() {
+        let orange = SColor::new(0xFF, 0xA5, 0x00, 0xFF);
+        assert_eq!("#FFA500".parse::<SColor>().unwrap(), orange);
+        assert_eq!("#ffa500ff".parse::<SColor>().unwrap(), orange);
+        assert_eq!("Orange".parse::<SColor>().unwrap(), orange);
+        assert_eq!(
+            "#f0a8".parse::<SColor>().unwrap(),
+            SColor::new(0xFF, 0x00, 0xAA, 0x88)
+        );
+        assert_eq!(
+            "red#80".parse::<SColor>().unwrap(),
+            SColor::new(0xFF, 0, 0, 0x80)
+        );
+        assert_eq!(
+            "red#8".parse::<SColor>().unwrap(),
+            SColor::new(0xFF, 0, 0, 0x88)
+        );
+
+        for s in [
+            "#fff",
+            "#fff8",
+            "#00ff00",
+            "#00ff0080",
+            "white",
+            "Red#8",
+            "red#80",
+        ] {
+            assert_eq!(ColorSpec::parse(s).unwrap().to_string(), s);
+        }
+        assert_eq!(orange.to_colorstring(), "#ffa500");
+        assert_eq!(SColor::new(1, 2, 3, 4).to_colorstring(), "#01020304");
+
+        assert!(ColorSpec::parse("#ffff0").is_err());
+        assert!(ColorSpec::parse("#gggggg").is_err());
+        assert!(ColorSpec::parse("reddish").is_err());
+        assert!(ColorSpec::parse("red#800").is_err());
+        assert!(ColorSpec::parse("").is_err());
+    }
+
+    #[test]
+    fn names_are_sorted() {
+        assert!(NAMED_COLORS.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn wire_order_is_argb() {
+        let context = ProtocolContext::latest_for_receive(false);
+        let mut deser = Deserializer::new(context, &[0x80, 0x11, 0x22, 0x33]);
+        let color = SColor::deserialize(&mut deser).unwrap();
+        assert_eq!(color.to_colorstring(), "#11223380");
+    }
+}
@@ -1,16 +1,25 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
 use super::audit::audit_command;
 use super::deser::Deserialize;
 use super::deser::DeserializeError;
+use super::deser::DeserializeField;
+use super::deser::DeserializeLocation;
 use super::deser::DeserializeResult;
 use super::deser::Deserializer;
+use super::ser::MockSerializer;
 use super::ser::Serialize;
 use super::ser::SerializeResult;
 use super::ser::Serializer;
 use super::types::*;
 use anyhow::bail;
+use core::ops::Deref;
+use minetest_protocol_derive::CommandAccessors;
 use minetest_protocol_derive::MinetestDeserialize;
 use minetest_protocol_derive::MinetestSerialize;
-use std::ops::Deref;
 
 #[macro_export]
 macro_rules! as_item {
@@ -68,12 +77,76 @@ macro_rules! default_deserializer {
 
 #[macro_export]
 macro_rules! implicit_from {
-    ($command_ty: ident, $name: ident, $spec_ty: ident) => {
+    ($command_ty: ident, $dir: ident, $name: ident, $spec_ty: ident) => {
         impl From<$spec_ty> for $command_ty {
             fn from(value: $spec_ty) -> Self {
                 $command_ty::$name(Box::new(value))
             }
         }
+
+        impl From<$spec_ty> for Command {
+            fn from(value: $spec_ty) -> Self {
+                Command::$dir($command_ty::$name(Box::new(value)))
+            }
+        }
+
+        /// Gives the command back if it is another one
+        impl TryFrom<$command_ty> for $spec_ty {
+            type Error = $command_ty;
+            fn try_from(command: $command_ty) -> Result<Self, $command_ty> {
+                match command {
+                    $command_ty::$name(spec) => Ok(*spec),
+                    command => Err(command),
+                }
+            }
+        }
+
+        /// Gives the command back if it is another one
+        impl TryFrom<Command> for $spec_ty {
+            type Error = Command;
+            fn try_from(command: Command) -> Result<Self, Command> {
+                match command {
+                    Command::$dir($command_ty::$name(spec)) => Ok(*spec),
+                    command => Err(command),
+                }
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! command_spec {
+    ($command_ty: ident, $dir: ident, $name: ident, $id: literal, $spec_ty: ident) => {
+        impl CommandSpec for $spec_ty {
+            type Command = $command_ty;
+            const NAME: &'static str = stringify!($name);
+            const ID: u16 = $id;
+
+            fn of(command: &$command_ty) -> Option<&Self> {
+                match command {
+                    $command_ty::$name(spec) => Some(spec),
+                    _ => None,
+                }
+            }
+
+            fn of_mut(command: &mut $command_ty) -> Option<&mut Self> {
+                match command {
+                    $command_ty::$name(spec) => Some(spec),
+                    _ => None,
+                }
+            }
+
+            fn from_command(command: $command_ty) -> Result<Self, $command_ty> {
+                Self::try_from(command)
+            }
+
+            fn in_command(command: &Command) -> Option<&Self> {
+                match command {
+                    Command::$dir(command) => Self::of(command),
+                    _ => None,
+                }
+            }
+        }
     };
 }
 
@@ -81,6 +154,7 @@ macro_rules! implicit_from {
 macro_rules! proto_struct {
     ($spec_ty: ident { }) => {
         #[derive(Debug, Clone, PartialEq, Default, MinetestSerialize, MinetestDeserialize)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
         pub struct $spec_ty;
     };
     ($spec_ty: ident {
@@ -88,6 +162,7 @@ macro_rules! proto_struct {
     }) => {
         $crate::as_item! {
             #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+            #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
             pub struct $spec_ty {
                $( $(#[$attr])? pub $fname: $ftype),+
             }
@@ -104,12 +179,46 @@ macro_rules! define_protocol {
              { $($fname: ident : $ftype: ty $([$attr:meta])? ),* } ),*
     }) => {
         $crate::as_item! {
-            #[derive(Debug, PartialEq, Clone)]
+            #[derive(Debug, PartialEq, Clone, CommandAccessors)]
+            #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+            #[non_exhaustive]
             pub enum $command_ty {
                 $($name(Box<$spec_ty>)),*,
             }
         }
 
+        $crate::as_item! {
+            impl $command_ty {
+                /// The name of every command, as command_name() has it
+                pub const COMMAND_NAMES: &'static [&'static str] = &[$(stringify!($name)),*];
+
+                /// The id it has on the wire
+                pub fn command_id(&self) -> u16 {
+                    match self {
+                        $($command_ty::$name(_) => $id),*,
+                    }
+                }
+
+                /// The contents, if this is the command of spec S
+                pub fn spec<S: CommandSpec<Command = Self>>(&self) -> Option<&S> {
+                    S::of(self)
+                }
+
+                pub fn spec_mut<S: CommandSpec<Command = Self>>(&mut self) -> Option<&mut S> {
+                    S::of_mut(self)
+                }
+
+                /// The contents, or the command back if it is another one
+                pub fn into_spec<S: CommandSpec<Command = Self>>(self) -> Result<S, Self> {
+                    S::from_command(self)
+                }
+
+                pub fn is<S: CommandSpec<Command = Self>>(&self) -> bool {
+                    S::of(self).is_some()
+                }
+            }
+        }
+
         $crate::as_item! {
             impl CommandProperties for $command_ty {
                 fn direction(&self) -> CommandDirection {
@@ -151,13 +260,23 @@ macro_rules! define_protocol {
             impl Deserialize for $command_ty {
                 type Output = Self;
                 fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
-                    let orig_buffer = deser.peek_all();
+                    // For chained input, peek_all() makes a copy, so only do it when needed.
+                    #[cfg(feature = "std")]
+                    let orig_buffer = if deser.context().audit { deser.peek_all() } else { &[] };
+                    let start = deser.clone();
                     let command_id = u16::deserialize(deser)?;
                     let dir = deser.direction();
                     let result = match (dir, command_id) {
-                        $( (CommandDirection::$dir, $id) => $command_ty::$name(Box::new(<$spec_ty as Deserialize>::deserialize(deser)?)) ),*,
+                        $( (CommandDirection::$dir, $id) => $command_ty::$name(Box::new(
+                            <$spec_ty as Deserialize>::deserialize(deser).map_err(|err| {
+                                let path = concat!(stringify!($command_ty), "::", stringify!($name));
+                                let location = DeserializeLocation::new(path, &err, &start, deser);
+                                err.context(location)
+                            })?
+                        )) ),*,
                         _ => bail!(DeserializeError::BadPacketId(dir, command_id)),
                     };
+                    #[cfg(feature = "std")]
                     audit_command(deser.context(), orig_buffer, &result);
                     Ok(result)
                 }
@@ -165,12 +284,13 @@ macro_rules! define_protocol {
         }
 
         $($crate::proto_struct!($spec_ty { $($fname: $ftype $([$attr])?),* });)*
-        $($crate::implicit_from!($command_ty, $name, $spec_ty);)*
+        $($crate::implicit_from!($command_ty, $dir, $name, $spec_ty);)*
+        $($crate::command_spec!($command_ty, $dir, $name, $id, $spec_ty);)*
 
     };
 }
 
-define_protocol!(41, 0x4f457403, ToClient, ToClientCommand => {
+define_protocol!(44, 0x4f457403, ToClient, ToClientCommand => {
     // CommandName, CommandType, Direction, Channel, Reliable
     Hello, 0x02, 0, true => HelloSpec {
         serialization_ver: u8,
@@ -233,7 +353,8 @@ define_protocol!(41, 0x4f457403, ToClient, ToClientCommand => {
     },
 
     MediaPush, 0x2C, 0, true => MediaPushSpec {
-        raw_hash: String,
+        // The raw 20 byte SHA1, not valid UTF-8
+        raw_hash: Vec<u8> [wrap(BinaryData16)],
         filename: String,
         cached: bool,
         token: u32
@@ -258,7 +379,7 @@ define_protocol!(41, 0x4f457403, ToClient, ToClientCommand => {
 
     Hp, 0x33, 0, true => HpSpec {
         hp: u16,
-        damage_effect: Option<bool>
+        damage_effect: Option<bool> [proto(since = 41)]
     },
 
     MovePlayer, 0x34, 0, true => MovePlayerSpec {
@@ -311,7 +432,8 @@ define_protocol!(41, 0x4f457403, ToClient, ToClientCommand => {
         spec_loop: bool,
         spec_fade: Option<f32>,
         spec_pitch: Option<f32>,
-        ephemeral: Option<bool>
+        ephemeral: Option<bool> [proto(since = 39)],
+        start_time: Option<f32> [proto(since = 43)]
     },
 
     StopSound, 0x40, 0, true => StopSoundSpec {
@@ -377,8 +499,8 @@ define_protocol!(41, 0x4f457403, ToClient, ToClientCommand => {
         world_pos: Option<v3f>,
         size: Option<v2s32>,
         z_index: Option<s16>,
-        text2: Option<String>,
-        style: Option<u32>
+        text2: Option<String> [proto(since = 39)],
+        style: Option<u32> [proto(since = 39)]
     },
 
     Hudrm, 0x4a, 1, true => HudrmSpec {
@@ -445,7 +567,7 @@ define_protocol!(41, 0x4f457403, ToClient, ToClientCommand => {
     },
 
     UpdatePlayerList, 0x56, 0, true => UpdatePlayerListSpec {
-        typ: u8,
+        typ: PlayerListModifier,
         players: Vec<String> [wrap(Array16<String>)]
     },
 
@@ -493,10 +615,15 @@ define_protocol!(41, 0x4f457403, ToClient, ToClientCommand => {
 
     SetLighting, 0x63, 0, true => SetLightingSpec {
         lighting: Lighting
+    },
+
+    // Since protocol 44
+    MovePlayerRel, 0x5d, 0, true => MovePlayerRelSpec {
+        added_pos: v3f
     }
 });
 
-define_protocol!(41, 0x4f457403, ToServer, ToServerCommand => {
+define_protocol!(44, 0x4f457403, ToServer, ToServerCommand => {
     /////////////////////////////////////////////////////////////////////////
     // ToServer
     Null, 0x00, 0, false => NullSpec {
@@ -598,7 +725,7 @@ define_protocol!(41, 0x4f457403, ToServer, ToServerCommand => {
         patch_ver: u8,
         reserved: u8,
         full_ver: String,
-        formspec_ver: Option<u16>
+        formspec_ver: Option<u16> [proto(since = 38)]
     },
 
     FirstSrp, 0x50, 1, true => FirstSrpSpec {
@@ -625,11 +752,47 @@ define_protocol!(41, 0x4f457403, ToServer, ToServerCommand => {
 });
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Command {
     ToServer(ToServerCommand),
     ToClient(ToClientCommand),
 }
 
+/// The contents of a command, e.g. HelloSpec for ToClientCommand::Hello.
+///
+/// The command enums are non_exhaustive, so that commands can be added
+/// without breaking other crates, and a match on them needs a wildcard arm.
+/// For a single command, the accessors are shorter:
+///
+/// ```text
+/// if let Some(hello) = command.spec::<HelloSpec>() {
+///     println!("protocol {}", hello.proto_ver);
+/// }
+/// let is_media = command.is::<MediaSpec>();
+/// let hello = command.as_hello();             // the same, by name
+/// let hello = HelloSpec::try_from(command)?;  // or Err(command)
+/// ```
+pub trait CommandSpec: Sized {
+    /// ToClientCommand or ToServerCommand
+    type Command;
+    /// As command_name() has it
+    const NAME: &'static str;
+    /// The id on the wire
+    const ID: u16;
+
+    fn of(command: &Self::Command) -> Option<&Self>;
+    fn of_mut(command: &mut Self::Command) -> Option<&mut Self>;
+    fn from_command(command: Self::Command) -> Result<Self, Self::Command>;
+    fn in_command(command: &Command) -> Option<&Self>;
+}
+
+impl Command {
+    /// The contents, if this is the command of spec S, either way
+    pub fn spec<S: CommandSpec>(&self) -> Option<&S> {
+        S::in_command(self)
+    }
+}
+
 pub trait CommandProperties {
     fn direction(&self) -> CommandDirection;
     fn default_channel(&self) -> u8;
@@ -640,7 +803,7 @@ pub trait CommandProperties {
 /// This only exists to make "audit_command" generic, but it
 /// wasn't as clean as I hoped.
 /// TODO(paradust): Factor this out.
-pub trait CommandRef: CommandProperties + std::fmt::Debug {
+pub trait CommandRef: CommandProperties + core::fmt::Debug {
     fn toserver_ref(&self) -> Option<&ToServerCommand>;
     fn toclient_ref(&self) -> Option<&ToClientCommand>;
 }
@@ -734,6 +897,33 @@ impl Serialize for Command {
     }
 }
 
+/// A command that can't be serialized, usually because of a field value
+/// out of range for the wire format (e.g. a String over 65535 bytes).
+/// The error names the field, outermost first.
+#[derive(thiserror::Error, Debug)]
+#[error("Cannot serialize {command}: {error:#}")]
+pub struct CommandSerializeError {
+    pub command: &'static str,
+    pub error: anyhow::Error,
+}
+
+impl Command {
+    /// Check that the command serializes in `context`, returning its size.
+    pub fn check_serialize(
+        &self,
+        context: ProtocolContext,
+    ) -> Result<usize, CommandSerializeError> {
+        let mut ser = MockSerializer::new(context);
+        match Command::serialize(self, &mut ser) {
+            Ok(()) => Ok(ser.len()),
+            Err(error) => Err(CommandSerializeError {
+                command: self.command_name(),
+                error,
+            }),
+        }
+    }
+}
+
 impl Deserialize for Command {
     type Output = Self;
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
@@ -743,3 +933,101 @@ impl Deserialize for Command {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn serialize_error_names_command_and_field() {
+        let command = Command::ToClient(
+            ShowFormspecSpec {
+                form_spec: String::new(),
+                form_name: "x".repeat(70000),
+            }
+            .into(),
+        );
+        let ctx = ProtocolContext::latest_for_send(false);
+        let err = command.check_serialize(ctx).unwrap_err();
+        assert_eq!(err.command, "ShowFormspec");
+        assert_eq!(
+            err.to_string(),
+            "Cannot serialize ShowFormspec: form_name: Invalid value: String too long (70000 bytes, max 65535)"
+        );
+    }
+
+    #[test]
+    fn spec_accessors() {
+        let mut command = ToClientCommand::Breath(Box::new(BreathSpec { breath: 5 }));
+        assert!(command.is::<BreathSpec>());
+        assert!(!command.is::<HpSpec>());
+        assert_eq!(command.command_id(), BreathSpec::ID);
+        command.spec_mut::<BreathSpec>().unwrap().breath = 6;
+        let wrapped = Command::ToClient(command.clone());
+        assert_eq!(wrapped.spec::<BreathSpec>().unwrap().breath, 6);
+        assert!(wrapped.spec::<TSChatMessageSpec>().is_none());
+        assert_eq!(command.into_spec::<BreathSpec>().ok().unwrap().breath, 6);
+    }
+
+    #[test]
+    fn conversions() {
+        let command: Command = TSChatMessageSpec {
+            message: "hi".into(),
+        }
+        .into();
+        assert_eq!(command.command_name(), "TSChatMessage");
+        let Command::ToServer(mut inner) = command.clone() else {
+            panic!("expected ToServer");
+        };
+        assert_eq!(inner.as_ts_chat_message().unwrap().message, "hi");
+        assert!(inner.as_init().is_none());
+        inner.as_ts_chat_message_mut().unwrap().message = "bye".into();
+
+        let err = BreathSpec::try_from(command.clone()).unwrap_err();
+        assert_eq!(err, command);
+        let spec = TSChatMessageSpec::try_from(inner).unwrap();
+        assert_eq!(spec.message, "bye");
+        assert!(TSChatMessageSpec::try_from(command).is_ok());
+        let hud: ToClientCommand = HudrmSpec { server_id: 1 }.into();
+        assert_eq!(hud.as_hudrm().unwrap().server_id, 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let chat = Command::ToServer(
+            TSChatMessageSpec {
+                message: "hi".to_string(),
+            }
+            .into(),
+        );
+        let json = serde_json::to_string(&chat).unwrap();
+        assert_eq!(json, r#"{"ToServer":{"TSChatMessage":{"message":"hi"}}}"#);
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), chat);
+
+        let mut nodes = MapNodesBulk::empty();
+        nodes.nodes[100].param0 = 7;
+        let block = Command::ToClient(
+            BlockdataSpec {
+                pos: v3s16::new(1, -2, 3),
+                block: MapBlock {
+                    is_underground: true,
+                    day_night_diff: false,
+                    generated: true,
+                    lighting_complete: Some(0xffff),
+                    nodes,
+                    node_metadata: NodeMetadataList {
+                        metadata: Vec::new(),
+                    },
+                },
+                network_specific_version: 2,
+            }
+            .into(),
+        );
+        let json = serde_json::to_string(&block).unwrap();
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), block);
+        let short = json.replacen(r#"{"param0":0,"param1":0,"param2":0},"#, "", 1);
+        assert!(serde_json::from_str::<Command>(&short).is_err());
+    }
+}
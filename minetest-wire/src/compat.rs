@@ -0,0 +1,251 @@
+//!
+//! Compatibility lint for outgoing commands
+//!
+//! Commands are always serialized in the latest format this crate knows.
+//! An older peer may not know a command at all, or may expect it in an
+//! older format. Depending on the command, the peer either ignores what it
+//! doesn't understand, or fails to parse it. Either way, nothing is
+//! reported back to the sender.
+//!
+//! Fields added in a later version (#[proto(since = N)]) are left out for
+//! older peers, so their values are silently lost.
+//!
+//! CompatLinter checks a command against the peer's negotiated protocol
+//! version, and lists what the peer won't understand or won't get.
+//! Optional fields are only reported when they are set.
+//!
+//! The versions come from the history in the engine's networkprotocol.h.
+//!
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::command::Command;
+use super::command::CommandProperties;
+use super::command::ToClientCommand;
+use super::command::ToServerCommand;
+
+/// Commands added, or whose format changed, after EARLIEST_PROTOCOL_VERSION
+const COMMAND_SINCE: &[(&str, u16)] = &[
+    // New format
+    ("SetSky", 39),
+    ("SetSun", 39),
+    ("SetMoon", 39),
+    ("SetStars", 39),
+    ("MinimapModes", 39),
+    // New format
+    ("MediaPush", 40),
+    ("HaveMedia", 40),
+    ("SetLighting", 41),
+    ("UpdateClientInfo", 41),
+    ("MovePlayerRel", 44),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompatIssueKind {
+    /// The peer doesn't know this command, or expects an older format
+    Command,
+    /// The peer doesn't know this (optional) field, so it isn't sent
+    Field(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatIssue {
+    pub command: &'static str,
+    pub kind: CompatIssueKind,
+    /// Protocol version that introduced the command or field
+    pub since: u16,
+    pub peer_version: u16,
+}
+
+impl fmt::Display for CompatIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            CompatIssueKind::Command => write!(f, "{}", self.command)?,
+            CompatIssueKind::Field(field) => write!(f, "{}.{}", self.command, field)?,
+        }
+        write!(
+            f,
+            " needs protocol {}, peer has {}",
+            self.since, self.peer_version
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Peer can't understand {}", join_issues(.0))]
+pub struct CompatError(pub Vec<CompatIssue>);
+
+fn join_issues(issues: &[CompatIssue]) -> String {
+    issues
+        .iter()
+        .map(|issue| issue.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompatMode {
+    /// Don't check
+    Off,
+    /// Send anyway, reporting the issues to the sender
+    #[default]
+    Warn,
+    /// Refuse to send (CompatError)
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompatLinter {
+    pub protocol_version: u16,
+    pub mode: CompatMode,
+}
+
+impl CompatLinter {
+    pub fn new(protocol_version: u16, mode: CompatMode) -> Self {
+        Self {
+            protocol_version,
+            mode,
+        }
+    }
+
+    /// Everything in `command` the peer won't understand
+    pub fn lint(&self, command: &Command) -> Vec<CompatIssue> {
+        let name = command.command_name();
+        let mut found: Vec<(CompatIssueKind, u16)> = Vec::new();
+        if let Some(&(_, since)) = COMMAND_SINCE.iter().find(|(n, _)| *n == name) {
+            found.push((CompatIssueKind::Command, since));
+        }
+        match command {
+            Command::ToClient(command) => toclient_fields(command, &mut found),
+            Command::ToServer(command) => toserver_fields(command, &mut found),
+        }
+        found
+            .into_iter()
+            .filter(|&(_, since)| self.protocol_version < since)
+            .map(|(kind, since)| CompatIssue {
+                command: name,
+                kind,
+                since,
+                peer_version: self.protocol_version,
+            })
+            .collect()
+    }
+
+    /// Apply the mode: Ok if the command should be sent, with the issues
+    /// to warn about (none when Off).
+    pub fn check(&self, command: &Command) -> Result<Vec<CompatIssue>, CompatError> {
+        if self.mode == CompatMode::Off {
+            return Ok(Vec::new());
+        }
+        let issues = self.lint(command);
+        match self.mode {
+            CompatMode::Deny if !issues.is_empty() => Err(CompatError(issues)),
+            _ => Ok(issues),
+        }
+    }
+}
+
+fn field(found: &mut Vec<(CompatIssueKind, u16)>, set: bool, name: &'static str, since: u16) {
+    if set {
+        found.push((CompatIssueKind::Field(name), since));
+    }
+}
+
+fn toclient_fields(command: &ToClientCommand, found: &mut Vec<(CompatIssueKind, u16)>) {
+    match command {
+        ToClientCommand::Hp(spec) => {
+            field(found, spec.damage_effect.is_some(), "damage_effect", 41);
+        }
+        ToClientCommand::PlaySound(spec) => {
+            field(found, spec.ephemeral.is_some(), "ephemeral", 39);
+            field(found, spec.start_time.is_some(), "start_time", 43);
+        }
+        ToClientCommand::Hudadd(spec) => {
+            field(found, spec.text2.is_some(), "text2", 39);
+            field(found, spec.style.is_some(), "style", 39);
+        }
+        ToClientCommand::SetSky(spec) => {
+            let tilt = spec.params.body_orbit_tilt.is_some();
+            field(found, tilt, "body_orbit_tilt", 41);
+        }
+        _ => (),
+    }
+}
+
+fn toserver_fields(command: &ToServerCommand, found: &mut Vec<(CompatIssueKind, u16)>) {
+    if let Some(spec) = command.as_client_ready() {
+        field(found, spec.formspec_ver.is_some(), "formspec_ver", 38);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::BreathSpec;
+    use crate::command::SetSkySpec;
+    use crate::types::FogTint;
+    use crate::types::SColor;
+    use crate::types::SkyType;
+    use crate::types::SkyboxParams;
+    use alloc::vec;
+
+    fn set_sky(body_orbit_tilt: Option<f32>) -> Command {
+        let white = SColor {
+            r: 255,
+            g: 255,
+            b: 255,
+            a: 255,
+        };
+        Command::ToClient(
+            SetSkySpec {
+                params: SkyboxParams {
+                    bgcolor: white,
+                    clouds: true,
+                    fog_tint: FogTint::Default,
+                    typ: SkyType::Plain,
+                    body_orbit_tilt,
+                },
+            }
+            .into(),
+        )
+    }
+
+    #[test]
+    fn body_orbit_tilt() {
+        let linter = CompatLinter::new(39, CompatMode::Deny);
+        assert!(linter.lint(&set_sky(None)).is_empty());
+        let issues = linter.lint(&set_sky(Some(10.0)));
+        assert_eq!(
+            issues,
+            vec![CompatIssue {
+                command: "SetSky",
+                kind: CompatIssueKind::Field("body_orbit_tilt"),
+                since: 41,
+                peer_version: 39,
+            }]
+        );
+        let err = linter.check(&set_sky(Some(10.0))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Peer can't understand SetSky.body_orbit_tilt needs protocol 41, peer has 39"
+        );
+        // The new set_sky format itself is too new for 38
+        let issues = CompatLinter::new(38, CompatMode::Warn).lint(&set_sky(Some(10.0)));
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].kind, CompatIssueKind::Command);
+
+        let warn = CompatLinter::new(39, CompatMode::Warn);
+        assert_eq!(warn.check(&set_sky(Some(10.0))).unwrap().len(), 1);
+        let off = CompatLinter::new(39, CompatMode::Off);
+        assert!(off.check(&set_sky(Some(10.0))).unwrap().is_empty());
+
+        let latest = CompatLinter::new(41, CompatMode::Deny);
+        assert!(latest.check(&set_sky(Some(10.0))).unwrap().is_empty());
+        let breath = Command::ToClient(BreathSpec { breath: 5 }.into());
+        assert!(CompatLinter::new(37, CompatMode::Deny)
+            .check(&breath)
+            .is_ok());
+    }
+}
@@ -0,0 +1,646 @@
+use super::types::CommandDirection;
+use super::types::ProtocolContext;
+use super::util::hexdump;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use anyhow::bail;
+use core::fmt;
+use core::num::ParseIntError;
+use core::str::Utf8Error;
+use typed_arena::Arena;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum DeserializeError {
+    #[error("Bad Packet Type {0:?} type={1}")]
+    BadPacketId(CommandDirection, u16),
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+    #[error("Invalid Protocol id: {0}")]
+    InvalidProtocolId(u32),
+    #[error("Invalid channel: {0}")]
+    InvalidChannel(u8),
+    #[error("Invalid Packet Kind: {0}")]
+    InvalidPacketKind(u8),
+    #[error("DecompressionFailed: {0}")]
+    DecompressionFailed(String),
+    #[error("OtherError: {0}")]
+    OtherError(String),
+    #[error("EOF during deserialization")]
+    Eof, // Data ended prematurely
+}
+
+impl DeserializeError {
+    /// The DeserializeError an error is about, if any
+    pub fn find(err: &anyhow::Error) -> Option<&DeserializeError> {
+        err.chain().find_map(|cause| cause.downcast_ref())
+    }
+
+    pub fn is_eof(&self) -> bool {
+        matches!(self, DeserializeError::Eof)
+    }
+
+    /// The direction and id of a command that isn't known
+    pub fn bad_packet_id(&self) -> Option<(CommandDirection, u16)> {
+        match self {
+            DeserializeError::BadPacketId(dir, id) => Some((*dir, *id)),
+            _ => None,
+        }
+    }
+}
+
+impl From<Utf8Error> for DeserializeError {
+    fn from(other: Utf8Error) -> DeserializeError {
+        DeserializeError::InvalidValue(format!("Utf8Error {:?}", other))
+    }
+}
+
+impl From<ParseIntError> for DeserializeError {
+    fn from(other: ParseIntError) -> DeserializeError {
+        DeserializeError::InvalidValue(format!("ParseIntError {:?}", other))
+    }
+}
+
+impl From<anyhow::Error> for DeserializeError {
+    fn from(value: anyhow::Error) -> Self {
+        DeserializeError::OtherError(format!("OtherError {:?}", value))
+    }
+}
+
+pub type DeserializeResult<R> = anyhow::Result<R>;
+
+/// Context on a deserialization error: the path to the field that failed,
+/// and where in the input it stopped (see Deserializer::input_offset).
+/// Derived Deserialize impls add each named field to it, on the way out.
+#[derive(Debug)]
+pub struct DeserializeField {
+    /// Innermost first
+    names: Vec<&'static str>,
+    pub offset: Option<usize>,
+}
+
+impl DeserializeField {
+    /// `err` from deserializing the field `name` with `deser`
+    pub fn wrap(mut err: anyhow::Error, name: &'static str, deser: &Deserializer) -> anyhow::Error {
+        match err.downcast_mut::<DeserializeField>() {
+            Some(field) => field.names.push(name),
+            None => {
+                err = err.context(DeserializeField {
+                    names: vec![name],
+                    offset: deser.input_offset(),
+                })
+            }
+        }
+        err
+    }
+
+    /// The field names, outermost first
+    pub fn path(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.names.iter().rev().copied()
+    }
+}
+
+impl fmt::Display for DeserializeField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, name) in self.path().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes kept on either side of where a command stopped parsing
+const LOCATION_CONTEXT: usize = 64;
+
+/// Where a command failed to deserialize: the path to the field (e.g.
+/// ToClientCommand::Hello.auth_mechs), the offset parsing stopped at, and
+/// a hex dump of the bytes around it. Added as context to the error by the
+/// command's Deserialize impl.
+#[derive(Debug)]
+pub struct DeserializeLocation {
+    pub path: String,
+    pub offset: usize,
+    /// Size of the command
+    pub len: usize,
+    dump: String,
+}
+
+impl DeserializeLocation {
+    /// For the error `err` from deserializing `command`, which started at
+    /// `start`, and had got to `stopped`
+    pub fn new(
+        command: &str,
+        err: &anyhow::Error,
+        start: &Deserializer,
+        stopped: &Deserializer,
+    ) -> Self {
+        let mut path = String::from(command);
+        let mut offset = stopped.offset() - start.offset();
+        if let Some(field) = err.downcast_ref::<DeserializeField>() {
+            path = format!("{}.{}", command, field);
+            // Where the field stopped says it better, if it is in the input
+            if let (Some(field_offset), Some(base)) = (field.offset, start.input_offset()) {
+                offset = field_offset.saturating_sub(base);
+            }
+        }
+        let mut start = start.clone();
+        let data = start.peek_all();
+        let offset = offset.min(data.len());
+        let from = offset.saturating_sub(LOCATION_CONTEXT);
+        let to = (offset + LOCATION_CONTEXT).min(data.len());
+        Self {
+            path,
+            offset,
+            len: data.len(),
+            dump: hexdump(&data[from..to], from, Some(offset)),
+        }
+    }
+
+    /// The DeserializeLocation of an error, if any
+    pub fn find(err: &anyhow::Error) -> Option<&DeserializeLocation> {
+        err.downcast_ref()
+    }
+
+    /// The bytes around where parsing stopped
+    pub fn hexdump(&self) -> &str {
+        &self.dump
+    }
+}
+
+impl fmt::Display for DeserializeLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cannot deserialize {} at offset {} of {}\n{}",
+            self.path, self.offset, self.len, self.dump
+        )
+    }
+}
+
+/// True if the error is DeserializeError::Eof
+pub fn is_eof(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<DeserializeError>(),
+        Some(DeserializeError::Eof)
+    )
+}
+
+/// Input made of several buffers, logically concatenated.
+///
+/// This lets a reassembled split command be parsed directly from its chunks.
+/// Reads which cross a chunk boundary are copied into a scratch arena owned
+/// by the ChainedBuffer, so the Deserializer can still hand out `&'a [u8]`.
+/// Reads inside a chunk (the vast majority) are not copied.
+pub struct ChainedBuffer {
+    chunks: Vec<Vec<u8>>,
+    scratch: Arena<u8>,
+}
+
+impl ChainedBuffer {
+    pub fn new(chunks: Vec<Vec<u8>>) -> Self {
+        Self {
+            chunks,
+            scratch: Arena::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|c| c.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.iter().all(|c| c.is_empty())
+    }
+
+    pub fn chunks(&self) -> &[Vec<u8>] {
+        &self.chunks
+    }
+
+    /// Concatenate the chunks into one buffer.
+    pub fn concat(&self) -> Vec<u8> {
+        self.chunks.concat()
+    }
+}
+
+#[derive(Clone)]
+pub struct Deserializer<'a> {
+    pub context: ProtocolContext,
+    pub data: &'a [u8], // Remaining data in the current chunk
+    // Chunks following 'data' (only for chained input)
+    rest: &'a [Vec<u8>],
+    rest_len: usize,
+    scratch: Option<&'a Arena<u8>>,
+    // Largest number of bytes a read was missing (see shortfall())
+    shortfall: usize,
+    // Where the input starts in the outermost input, if it is part of it
+    // (see input_offset())
+    base: Option<usize>,
+    len: usize,
+}
+
+impl<'a> Deserializer<'a> {
+    pub fn new(context: ProtocolContext, data: &'a [u8]) -> Self {
+        Self {
+            context,
+            data,
+            rest: &[],
+            rest_len: 0,
+            scratch: None,
+            shortfall: 0,
+            base: Some(0),
+            len: data.len(),
+        }
+    }
+
+    /// Deserialize from chained input, without concatenating it first.
+    pub fn new_chained(context: ProtocolContext, chain: &'a ChainedBuffer) -> Self {
+        let mut deser = Self {
+            context,
+            data: &[],
+            rest: &chain.chunks,
+            rest_len: chain.len(),
+            scratch: Some(&chain.scratch),
+            shortfall: 0,
+            base: Some(0),
+            len: chain.len(),
+        };
+        deser.next_chunk();
+        deser
+    }
+
+    /// A Deserializer for data decoded from this one's input (e.g.
+    /// decompressed). Its offsets are not in the input, so errors in it
+    /// are located where this one stopped.
+    pub fn decoded<'b>(&self, data: &'b [u8]) -> Deserializer<'b> {
+        Deserializer {
+            base: None,
+            ..Deserializer::new(self.context, data)
+        }
+    }
+
+    /// Bytes consumed so far
+    pub fn offset(&self) -> usize {
+        self.len - self.remaining()
+    }
+
+    /// Bytes consumed so far, counted from the start of the outermost
+    /// input (for a slice()), or None for decoded() data
+    pub fn input_offset(&self) -> Option<usize> {
+        self.base.map(|base| base + self.offset())
+    }
+
+    /// If the current chunk is exhausted, move to the next non-empty one.
+    fn next_chunk(&mut self) {
+        while self.data.is_empty() && !self.rest.is_empty() {
+            self.data = &self.rest[0];
+            self.rest = &self.rest[1..];
+            self.rest_len -= self.data.len();
+        }
+    }
+
+    /// Iterate over the remaining input, one contiguous piece at a time.
+    pub fn remaining_chunks(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let rest: &'a [Vec<u8>] = self.rest;
+        core::iter::once(self.data).chain(rest.iter().map(|c| c.as_slice()))
+    }
+
+    /// Copy 'count' bytes starting at the cursor into the scratch arena,
+    /// without advancing. The caller must have checked there is enough input.
+    fn gather(&self, count: usize) -> &'a [u8] {
+        // Only chained input can run past the current chunk.
+        let scratch = self.scratch.unwrap();
+        let bytes = self.remaining_chunks().flatten().copied().take(count);
+        scratch.alloc_extend(bytes)
+    }
+
+    /// Record that a read of 'count' bytes ran past the end of the input.
+    fn eof(&mut self, count: usize) -> DeserializeError {
+        self.expect_more(count - self.remaining());
+        DeserializeError::Eof
+    }
+
+    /// Note that at least 'count' more bytes of input are needed.
+    ///
+    /// Reads past the end of input do this automatically. Decoders which
+    /// discover truncation on their own (e.g. zstd) should call this before
+    /// failing with Eof, so that incremental parsing can ask for more data.
+    pub fn expect_more(&mut self, count: usize) {
+        self.shortfall = core::cmp::max(self.shortfall, count);
+    }
+
+    /// The number of additional bytes that a failed read needed,
+    /// or 0 if no read has run past the end of the input.
+    ///
+    /// Reads inside a slice() that run past the end of the slice are
+    /// not counted, since more input can't fix those.
+    pub fn shortfall(&self) -> usize {
+        self.shortfall
+    }
+
+    /// Advance the cursor by 'count' bytes.
+    pub fn skip(&mut self, mut count: usize) -> DeserializeResult<()> {
+        if count > self.remaining() {
+            bail!(self.eof(count))
+        }
+        while count > 0 {
+            let n = core::cmp::min(count, self.data.len());
+            self.data = &self.data[n..];
+            count -= n;
+            self.next_chunk();
+        }
+        Ok(())
+    }
+
+    /// Take a number of bytes, and return a sub-Deserializer which
+    /// only operates on those bytes
+    pub fn slice(&mut self, count: usize) -> DeserializeResult<Self> {
+        let base = self.input_offset();
+        Ok(Self {
+            base,
+            ..Self::new(self.context, self.take(count)?)
+        })
+    }
+
+    pub fn context(&self) -> ProtocolContext {
+        self.context
+    }
+
+    pub fn direction(&self) -> CommandDirection {
+        self.context.dir
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() + self.rest_len
+    }
+
+    /// Position of the first byte matching 'pred', from the current
+    /// position in the stream.
+    fn position<P: Fn(u8) -> bool>(&self, pred: P) -> Option<usize> {
+        let mut offset = 0;
+        for chunk in self.remaining_chunks() {
+            if let Some(pos) = chunk.iter().position(|&ch| pred(ch)) {
+                return Some(offset + pos);
+            }
+            offset += chunk.len();
+        }
+        None
+    }
+
+    /// Finds the first occurance of the byte 'b'
+    /// from the current position in the stream.
+    pub fn find(&mut self, b: u8) -> Option<usize> {
+        self.position(|ch| ch == b)
+    }
+
+    pub fn peek(&mut self, count: usize) -> DeserializeResult<&'a [u8]> {
+        if count <= self.data.len() {
+            Ok(&self.data[0..count])
+        } else if count > self.remaining() {
+            bail!(self.eof(count))
+        } else {
+            Ok(self.gather(count))
+        }
+    }
+
+    pub fn peek_all(&mut self) -> &'a [u8] {
+        if self.rest_len == 0 {
+            self.data
+        } else {
+            self.gather(self.remaining())
+        }
+    }
+
+    pub fn take(&mut self, count: usize) -> DeserializeResult<&'a [u8]> {
+        if count <= self.data.len() {
+            let ret;
+            (ret, self.data) = self.data.split_at(count);
+            self.next_chunk();
+            Ok(ret)
+        } else {
+            let ret = self.peek(count)?;
+            self.skip(count)?;
+            Ok(ret)
+        }
+    }
+
+    /// Take a number of bytes into a new Vec.
+    /// Unlike take(), this never copies twice for chained input.
+    pub fn take_vec(&mut self, count: usize) -> DeserializeResult<Vec<u8>> {
+        if count > self.remaining() {
+            bail!(self.eof(count))
+        }
+        let mut ret = Vec::with_capacity(count);
+        for chunk in self.remaining_chunks() {
+            let n = core::cmp::min(count - ret.len(), chunk.len());
+            ret.extend_from_slice(&chunk[..n]);
+            if ret.len() == count {
+                break;
+            }
+        }
+        self.skip(count)?;
+        Ok(ret)
+    }
+
+    pub fn take_n<const N: usize>(&mut self) -> DeserializeResult<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn take_all(&mut self) -> &'a [u8] {
+        let ret = self.peek_all();
+        self.data = &[];
+        self.rest = &[];
+        self.rest_len = 0;
+        ret
+    }
+
+    /// Peek the next line (including ending \n, if present)
+    /// If the stream is at end, this will be an empty slice.
+    pub fn peek_line(&mut self) -> DeserializeResult<&'a [u8]> {
+        let line_len = match self.find(b'\n') {
+            Some(pos) => pos + 1,
+            None => self.remaining(),
+        };
+        self.peek(line_len)
+    }
+
+    /// Take the next line (including ending \n, if present)
+    /// If the stream is at end, this will be an empty slice.
+    pub fn take_line(&mut self) -> DeserializeResult<&'a [u8]> {
+        let line_len = match self.find(b'\n') {
+            Some(pos) => pos + 1,
+            None => self.remaining(),
+        };
+        self.take(line_len)
+    }
+
+    /// Take bytes until whitespace or end of stream
+    /// If skip_whitespace is true, skips initial whitespace first.
+    /// If skip_whitespace is false, and the next byte is a space,
+    /// nothing is taken, and the returned will be empty.
+    pub fn take_word(&mut self, skip_whitespace: bool) -> &'a [u8] {
+        if skip_whitespace {
+            self.take_space();
+        }
+        match self.position(|ch| ch == b' ' || ch == b'\n') {
+            // Cannot fail, the position is within the remaining input
+            Some(end) => self.take(end).unwrap(),
+            None => self.take_all(),
+        }
+    }
+
+    /// Take whitespace from the current cursor.
+    /// Repositioning the cursor at the start of the next word (or end of stream)
+    pub fn take_space(&mut self) {
+        match self.position(|ch| ch != b' ' && ch != b'\n') {
+            Some(pos) => self.skip(pos).unwrap(),
+            None => {
+                self.take_all();
+            }
+        };
+    }
+}
+
+pub trait Deserialize: Sized {
+    /// Output should be Self, except for wrapper types.
+    type Output;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+    use crate::command::MediaSpec;
+    use crate::command::TCChatMessageSpec;
+    use crate::ser::Serialize;
+    use crate::ser::VecSerializer;
+    use crate::types::MediaFileData;
+    #[cfg(any(feature = "zstd", feature = "ruzstd"))]
+    use crate::types::ZStdCompressed;
+    use alloc::string::ToString;
+
+    fn context() -> ProtocolContext {
+        ProtocolContext::latest_for_receive(true)
+    }
+
+    fn to_chunks(data: &[u8], chunk_size: usize) -> ChainedBuffer {
+        ChainedBuffer::new(data.chunks(chunk_size).map(|c| c.to_vec()).collect())
+    }
+
+    #[test]
+    fn chained_commands() {
+        let commands = vec![
+            Command::ToClient(
+                MediaSpec {
+                    num_bunches: 1,
+                    bunch_index: 0,
+                    files: vec![MediaFileData {
+                        name: "default_stone.png".to_string(),
+                        data: (0..5000).map(|i| i as u8).collect(),
+                    }],
+                }
+                .into(),
+            ),
+            Command::ToClient(
+                TCChatMessageSpec {
+                    version: 1,
+                    message_type: 1,
+                    sender: "sam".to_string(),
+                    message: "Hello, world! ünïcödé".to_string(),
+                    timestamp: 12345,
+                }
+                .into(),
+            ),
+        ];
+        for command in commands {
+            let mut ser = VecSerializer::new(context(), 1024);
+            Command::serialize(&command, &mut ser).unwrap();
+            let data = ser.take();
+            for chunk_size in [1, 3, 7, 500, data.len()] {
+                let chain = to_chunks(&data, chunk_size);
+                let mut deser = Deserializer::new_chained(context(), &chain);
+                assert_eq!(deser.remaining(), data.len());
+                assert_eq!(Command::deserialize(&mut deser).unwrap(), command);
+                assert_eq!(deser.remaining(), 0);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "zstd", feature = "ruzstd"))]
+    fn chained_zstd() {
+        let text = "minetest ".repeat(1000);
+        let mut ser = VecSerializer::new(context(), 1024);
+        <ZStdCompressed<String> as Serialize>::serialize(&text, &mut ser).unwrap();
+        let mut data = ser.take();
+        data.extend_from_slice(b"tail");
+        for chunk_size in [1, 5, 64, data.len()] {
+            let chain = to_chunks(&data, chunk_size);
+            let mut deser = Deserializer::new_chained(context(), &chain);
+            assert_eq!(
+                ZStdCompressed::<String>::deserialize(&mut deser).unwrap(),
+                text
+            );
+            assert_eq!(deser.take_all(), b"tail");
+        }
+        // Truncated input must fail, not hang
+        let truncated = &data[..data.len() - 10];
+        let mut deser = Deserializer::new(context(), truncated);
+        assert!(ZStdCompressed::<String>::deserialize(&mut deser).is_err());
+    }
+
+    #[test]
+    fn chained_words() {
+        let chain = ChainedBuffer::new(vec![
+            b"Li".to_vec(),
+            Vec::new(),
+            b"st ma".to_vec(),
+            b"in 32\nWid".to_vec(),
+            b"th 0\n".to_vec(),
+        ]);
+        let mut deser = Deserializer::new_chained(context(), &chain);
+        assert_eq!(deser.take_word(true), b"List");
+        assert_eq!(deser.take_word(true), b"main");
+        assert_eq!(deser.take_line().unwrap(), b" 32\n");
+        assert_eq!(deser.peek_line().unwrap(), b"Width 0\n");
+        assert_eq!(deser.find(b'0'), Some(6));
+        deser.skip(6).unwrap();
+        assert_eq!(deser.take_all(), b"0\n");
+        assert!(deser.take(1).is_err());
+    }
+
+    #[test]
+    fn error_location() {
+        // A Hello cut off in auth_mechs
+        let data = [0x00, 0x02, 0x1d, 0x00, 0x00, 0x00, 0x2e, 0x00];
+        let context = context();
+        let err = Command::deserialize(&mut Deserializer::new(context, &data)).unwrap_err();
+        assert!(is_eof(&err));
+        assert!(DeserializeError::find(&err).unwrap().is_eof());
+        let location = DeserializeLocation::find(&err).unwrap();
+        assert_eq!(location.path, "ToClientCommand::Hello.auth_mechs");
+        assert_eq!(location.offset, 7);
+        assert_eq!(location.len, 8);
+        assert_eq!(
+            location.hexdump(),
+            "00000000  00 02 1d 00 00 00 2e 00                           |........|\n                               ^^ offset 7\n"
+        );
+        assert!(location.to_string().starts_with(
+            "Cannot deserialize ToClientCommand::Hello.auth_mechs at offset 7 of 8\n"
+        ));
+
+        // Shutdown, without its message
+        let data = [0x00, 0x0a, 11];
+        let err = Command::deserialize(&mut Deserializer::new(context, &data)).unwrap_err();
+        let location = DeserializeLocation::find(&err).unwrap();
+        assert!(location
+            .path
+            .starts_with("ToClientCommand::AccessDenied.code"));
+        assert_eq!(location.offset, 3);
+    }
+}
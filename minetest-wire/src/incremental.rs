@@ -0,0 +1,207 @@
+//!
+//! Incremental parsing
+//!
+//! Tools reading captures from a stream (pcap, stdin, a pipe) may receive
+//! data in arbitrary pieces. Instead of failing with Eof on a partial
+//! value, the parsers here report how many more bytes are needed, so the
+//! caller can read more and try again.
+//!
+//! Only values whose encoding is self-delimiting can be parsed this way.
+//! Some Minetest encodings instead run to the end of the available input:
+//! trailing `Option` fields, split packet bodies, and (therefore) whole
+//! Packets. Those must be framed externally, e.g. by datagram boundaries
+//! or a length prefix.
+//!
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use super::deser::is_eof;
+use super::deser::Deserialize;
+use super::deser::DeserializeResult;
+use super::deser::Deserializer;
+use super::types::ProtocolContext;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Parsed<T> {
+    /// A complete value, and the number of input bytes it used
+    Ready { value: T, consumed: usize },
+    /// The input ends in the middle of a value. At least this many more
+    /// bytes are needed before trying again (it may take more than that).
+    NeedMore(usize),
+}
+
+/// Parse one T from the start of 'data', which may be incomplete.
+///
+/// Errors other than running out of input are returned as errors.
+pub fn parse_partial<T: Deserialize>(
+    context: ProtocolContext,
+    data: &[u8],
+) -> DeserializeResult<Parsed<T::Output>> {
+    let mut deser = Deserializer::new(context, data);
+    match T::deserialize(&mut deser) {
+        Ok(value) => Ok(Parsed::Ready {
+            value,
+            consumed: data.len() - deser.remaining(),
+        }),
+        Err(err) if is_eof(&err) && deser.shortfall() > 0 => {
+            Ok(Parsed::NeedMore(deser.shortfall()))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Buffers stream input and parses a sequence of T from it.
+pub struct IncrementalParser<T: Deserialize> {
+    context: ProtocolContext,
+    buf: Vec<u8>,
+    needed: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Deserialize> IncrementalParser<T> {
+    pub fn new(context: ProtocolContext) -> Self {
+        Self {
+            context,
+            buf: Vec::new(),
+            needed: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn context(&self) -> ProtocolContext {
+        self.context
+    }
+
+    /// Change the context for values parsed from now on
+    /// (e.g. after protocol version negotiation).
+    pub fn set_context(&mut self, context: ProtocolContext) {
+        self.context = context;
+    }
+
+    /// Append newly received input.
+    pub fn push(&mut self, data: &[u8]) {
+        self.needed = self.needed.saturating_sub(data.len());
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Number of bytes received but not yet parsed.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Parse the next value.
+    ///
+    /// Returns NeedMore without re-parsing if the last attempt asked for
+    /// more bytes than have been pushed since. On error, the buffered input
+    /// is left as it was, and the caller will usually want to discard()
+    /// some of it to resynchronize.
+    pub fn poll(&mut self) -> DeserializeResult<Parsed<T::Output>> {
+        if self.needed > 0 {
+            return Ok(Parsed::NeedMore(self.needed));
+        }
+        if self.buf.is_empty() {
+            return Ok(Parsed::NeedMore(1));
+        }
+        let result = parse_partial::<T>(self.context, &self.buf)?;
+        match &result {
+            Parsed::Ready { consumed, .. } => {
+                self.buf.drain(..*consumed);
+            }
+            Parsed::NeedMore(count) => self.needed = *count,
+        }
+        Ok(result)
+    }
+
+    /// Drop up to 'count' bytes of buffered input.
+    pub fn discard(&mut self, count: usize) {
+        let count = core::cmp::min(count, self.buf.len());
+        self.buf.drain(..count);
+        self.needed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+    use crate::command::TSChatMessageSpec;
+    use crate::command::ToServerCommand;
+    use crate::ser::Serialize;
+    use crate::ser::VecSerializer;
+    use crate::types::Array16;
+    #[cfg(any(feature = "zstd", feature = "ruzstd"))]
+    use crate::types::ZStdCompressed;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec;
+
+    fn context() -> ProtocolContext {
+        ProtocolContext::latest_for_receive(false)
+    }
+
+    #[test]
+    fn reports_shortfall() {
+        let words: Vec<String> = vec!["alpha".into(), "beta".into()];
+        let mut ser = VecSerializer::new(context(), 64);
+        <Array16<String> as Serialize>::serialize(&words, &mut ser).unwrap();
+        let data = ser.take();
+        // count(2) len(2) "alpha" len(2) "beta"
+        assert_eq!(data.len(), 15);
+
+        let parse = |len: usize| parse_partial::<Array16<String>>(context(), &data[..len]).unwrap();
+        assert_eq!(parse(0), Parsed::NeedMore(2));
+        assert_eq!(parse(1), Parsed::NeedMore(1));
+        assert_eq!(parse(4), Parsed::NeedMore(5));
+        assert_eq!(parse(12), Parsed::NeedMore(3));
+        assert_eq!(
+            parse(15),
+            Parsed::Ready {
+                value: words.clone(),
+                consumed: 15
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "zstd", feature = "ruzstd"))]
+    fn truncated_zstd_needs_more() {
+        let text = "abc".repeat(500);
+        let mut ser = VecSerializer::new(context(), 64);
+        <ZStdCompressed<String> as Serialize>::serialize(&text, &mut ser).unwrap();
+        let data = ser.take();
+        let result = parse_partial::<ZStdCompressed<String>>(context(), &data[..data.len() - 1]);
+        assert!(matches!(result.unwrap(), Parsed::NeedMore(_)));
+    }
+
+    #[test]
+    fn stream_of_commands() {
+        let commands: Vec<Command> = (0..20)
+            .map(|i| {
+                Command::ToServer(
+                    TSChatMessageSpec {
+                        message: format!("message number {}", i),
+                    }
+                    .into(),
+                )
+            })
+            .collect();
+        let mut stream = Vec::new();
+        for command in commands.iter() {
+            let mut ser = VecSerializer::new(context(), 64);
+            Command::serialize(command, &mut ser).unwrap();
+            stream.extend(ser.take());
+        }
+
+        // Feed the stream in pieces which don't line up with commands
+        let mut parser = IncrementalParser::<ToServerCommand>::new(context());
+        let mut parsed = Vec::new();
+        for piece in stream.chunks(5) {
+            parser.push(piece);
+            while let Parsed::Ready { value, .. } = parser.poll().unwrap() {
+                parsed.push(Command::ToServer(value));
+            }
+        }
+        assert_eq!(parsed, commands);
+        assert_eq!(parser.buffered(), 0);
+    }
+}
@@ -0,0 +1,29 @@
+//!
+//! Serialization of Minetest packets and commands
+//!
+//! With default features off, this is `no_std` and only needs `alloc`.
+//! See the features in Cargo.toml for what that leaves out.
+//!
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod audit;
+pub mod color;
+pub mod command;
+pub mod compat;
+pub mod deser;
+#[cfg(all(test, feature = "std"))]
+mod golden;
+pub mod incremental;
+pub mod minimap;
+pub mod node;
+pub mod overhead;
+pub mod packet;
+pub mod param2;
+pub mod ser;
+pub mod texture;
+pub mod types;
+pub mod util;
+mod zstd;
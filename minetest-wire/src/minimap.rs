@@ -0,0 +1,192 @@
+//!
+//! Minimap modes
+//!
+//! The server chooses the modes the client can cycle through with
+//! TOCLIENT_MINIMAP_MODES, and the one selected initially. The selection
+//! afterwards is purely client side (see game::minimap in minetest-protocol).
+//!
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::command::MinimapModesSpec;
+use crate::command::ToClientCommand;
+use crate::texture::TextureSpec;
+use crate::types::MinimapMode;
+use crate::types::MinimapModeList;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinimapType {
+    Off,
+    Surface,
+    Radar,
+    Texture,
+}
+
+impl MinimapType {
+    pub fn from_u16(typ: u16) -> Option<Self> {
+        Some(match typ {
+            0 => MinimapType::Off,
+            1 => MinimapType::Surface,
+            2 => MinimapType::Radar,
+            3 => MinimapType::Texture,
+            _ => return None,
+        })
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        *self as u16
+    }
+}
+
+impl MinimapMode {
+    pub fn minimap_type(&self) -> Option<MinimapType> {
+        MinimapType::from_u16(self.typ)
+    }
+}
+
+impl MinimapModeList {
+    pub fn builder() -> MinimapModesBuilder {
+        MinimapModesBuilder::default()
+    }
+
+    /// The modes the client uses when the server sends none
+    pub fn engine_default() -> Self {
+        MinimapModesBuilder::default()
+            .off("")
+            .surface("", 256)
+            .surface("", 128)
+            .surface("", 64)
+            .radar("", 512)
+            .radar("", 256)
+            .radar("", 128)
+            .build()
+            .unwrap()
+    }
+
+    /// The initially selected mode
+    pub fn selected(&self) -> Option<&MinimapMode> {
+        self.vec.get(self.mode as usize)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !self.vec.is_empty() && self.mode as usize >= self.vec.len() {
+            bail!(
+                "Selected minimap mode {} out of range ({} modes)",
+                self.mode,
+                self.vec.len()
+            );
+        }
+        for (i, mode) in self.vec.iter().enumerate() {
+            let Some(typ) = mode.minimap_type() else {
+                bail!("Minimap mode {}: invalid type {}", i, mode.typ);
+            };
+            if typ != MinimapType::Off && mode.size == 0 {
+                bail!("Minimap mode {}: size must not be 0", i);
+            }
+            if typ == MinimapType::Texture {
+                if mode.texture.is_empty() {
+                    bail!("Minimap mode {}: texture mode without a texture", i);
+                }
+                if let Err(err) = TextureSpec::parse(&mode.texture) {
+                    bail!("Minimap mode {}: {}", i, err);
+                }
+                if mode.scale == 0 {
+                    bail!("Minimap mode {}: scale must not be 0", i);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MinimapModesBuilder {
+    modes: Vec<MinimapMode>,
+    selected: u16,
+}
+
+impl MinimapModesBuilder {
+    fn add(mut self, typ: MinimapType, label: &str, size: u16, texture: &str, scale: u16) -> Self {
+        self.modes.push(MinimapMode {
+            typ: typ.as_u16(),
+            label: label.to_string(),
+            size,
+            texture: texture.to_string(),
+            scale,
+        });
+        self
+    }
+
+    pub fn off(self, label: &str) -> Self {
+        self.add(MinimapType::Off, label, 0, "", 1)
+    }
+
+    /// `size` is the side of the area shown, in nodes
+    pub fn surface(self, label: &str, size: u16) -> Self {
+        self.add(MinimapType::Surface, label, size, "", 1)
+    }
+
+    pub fn radar(self, label: &str, size: u16) -> Self {
+        self.add(MinimapType::Radar, label, size, "", 1)
+    }
+
+    /// A map image. `scale` is texture pixels per node.
+    pub fn texture(self, label: &str, texture: &str, size: u16, scale: u16) -> Self {
+        self.add(MinimapType::Texture, label, size, texture, scale)
+    }
+
+    /// Select the mode the client starts in (default 0)
+    pub fn select(mut self, index: u16) -> Self {
+        self.selected = index;
+        self
+    }
+
+    pub fn build(self) -> Result<MinimapModeList> {
+        let list = MinimapModeList {
+            mode: self.selected,
+            vec: self.modes,
+        };
+        list.validate()?;
+        Ok(list)
+    }
+
+    pub fn build_command(self) -> Result<ToClientCommand> {
+        Ok(MinimapModesSpec {
+            modes: self.build()?,
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_validates() {
+        let list = MinimapModeList::builder()
+            .off("")
+            .texture("Map", "world_map.png^[resize:256x256", 256, 1)
+            .select(1)
+            .build()
+            .unwrap();
+        assert_eq!(list.selected().unwrap().label, "Map");
+        assert!(MinimapModeList::builder()
+            .off("")
+            .select(1)
+            .build()
+            .is_err());
+        assert!(MinimapModeList::builder().surface("", 0).build().is_err());
+        assert!(MinimapModeList::builder()
+            .texture("Map", "", 256, 1)
+            .build()
+            .is_err());
+        assert!(MinimapModeList::builder()
+            .texture("Map", "a.png^(", 256, 1)
+            .build()
+            .is_err());
+        MinimapModeList::engine_default().validate().unwrap();
+    }
+}
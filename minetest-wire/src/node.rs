@@ -0,0 +1,57 @@
+//!
+//! Content ids
+//!
+//! The engine reserves three content ids, whatever the game registers:
+//!
+//! ```text
+//! CONTENT_UNKNOWN  125  "unknown", a name with no definition
+//! CONTENT_AIR      126  "air"
+//! CONTENT_IGNORE   127  "ignore", not loaded (or outside the map)
+//! ```
+//!
+//! For what param2 means, see the param2 module.
+//!
+use super::types::MapNode;
+
+pub const CONTENT_UNKNOWN: u16 = 125;
+pub const CONTENT_AIR: u16 = 126;
+pub const CONTENT_IGNORE: u16 = 127;
+
+impl MapNode {
+    pub const AIR: MapNode = MapNode::new(CONTENT_AIR);
+    pub const IGNORE: MapNode = MapNode::new(CONTENT_IGNORE);
+
+    /// A node with param1 and param2 zero
+    pub const fn new(param0: u16) -> Self {
+        Self {
+            param0,
+            param1: 0,
+            param2: 0,
+        }
+    }
+
+    pub fn is_air(&self) -> bool {
+        self.param0 == CONTENT_AIR
+    }
+
+    pub fn is_ignore(&self) -> bool {
+        self.param0 == CONTENT_IGNORE
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.param0 == CONTENT_UNKNOWN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_ids() {
+        assert!(MapNode::AIR.is_air());
+        assert!(MapNode::IGNORE.is_ignore());
+        assert!(MapNode::new(CONTENT_UNKNOWN).is_unknown());
+        assert!(!MapNode::new(0).is_air());
+    }
+}
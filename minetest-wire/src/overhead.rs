@@ -0,0 +1,298 @@
+//!
+//! Protocol overhead analysis
+//!
+//! Reports how the bytes sent for a Command are divided between packet
+//! headers, the command header, and the payload itself, and how much the
+//! payload was shrunk by compression. Sizes are computed with
+//! MockSerializer exactly the way the peer would packetize the command.
+//!
+//! Minetest never places more than one command in a packet, so each
+//! command pays at least one packet header. Commands with a high
+//! `overhead_ratio` are the ones worth merging into fewer, larger
+//! commands where the protocol allows it (e.g. ActiveObjectMessages).
+//!
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use anyhow::Result;
+
+use super::command::Command;
+use super::command::CommandProperties;
+use super::command::CommandRef;
+use super::command::ToClientCommand;
+use super::packet::ser_fmt_for_protocol;
+use super::packet::InnerBody;
+use super::packet::OriginalBody;
+use super::packet::Packet;
+use super::packet::SplitBody;
+use super::packet::EARLIEST_PROTOCOL_VERSION;
+use super::packet::LATEST_PROTOCOL_VERSION;
+use super::packet::MAX_ORIGINAL_BODY_SIZE;
+use super::packet::MAX_SPLIT_BODY_SIZE;
+use super::ser::MockSerializer;
+use super::ser::Serialize;
+use super::types::AbsNodeMetadataList;
+use super::types::ItemdefList;
+use super::types::MapBlock;
+use super::types::NodeDefManager;
+use super::types::ProtocolContext;
+use super::types::TextFormatPolicy;
+use super::types::ZLibCompressed;
+use super::util::ZLIB_DEFAULT_LEVEL;
+
+/// Size of the command id that starts every command
+pub const COMMAND_HEADER_SIZE: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compression {
+    pub algorithm: &'static str,
+    pub uncompressed_bytes: usize,
+    pub compressed_bytes: usize,
+}
+
+impl Compression {
+    /// compressed / uncompressed
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_bytes == 0 {
+            1.0
+        } else {
+            self.compressed_bytes as f64 / self.uncompressed_bytes as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverheadReport {
+    pub protocol_version: u16,
+    pub ser_fmt: u8,
+    pub command_name: &'static str,
+    pub reliable: bool,
+    /// Number of packets the command is sent in (more than 1 if split)
+    pub packets: usize,
+    /// Packet, reliable and split headers, summed over all packets
+    pub packet_header_bytes: usize,
+    pub command_header_bytes: usize,
+    /// The serialized command body (after compression, if any)
+    pub payload_bytes: usize,
+    /// Set for commands which carry a compressed field
+    pub compression: Option<Compression>,
+}
+
+impl OverheadReport {
+    /// Total bytes on the wire
+    pub fn wire_bytes(&self) -> usize {
+        self.packet_header_bytes + self.command_header_bytes + self.payload_bytes
+    }
+
+    pub fn overhead_bytes(&self) -> usize {
+        self.packet_header_bytes + self.command_header_bytes
+    }
+
+    /// Fraction of the wire bytes which are not payload
+    pub fn overhead_ratio(&self) -> f64 {
+        self.overhead_bytes() as f64 / self.wire_bytes() as f64
+    }
+
+    /// How many more command bytes would fit without needing another packet
+    pub fn spare_bytes(&self) -> usize {
+        let command_bytes = self.command_header_bytes + self.payload_bytes;
+        if self.packets == 1 {
+            MAX_ORIGINAL_BODY_SIZE - command_bytes
+        } else {
+            self.packets * MAX_SPLIT_BODY_SIZE - command_bytes
+        }
+    }
+}
+
+impl fmt::Display for OverheadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "v{} {}: {} bytes in {} packet(s), payload {}, headers {} ({:.1}% overhead)",
+            self.protocol_version,
+            self.command_name,
+            self.wire_bytes(),
+            self.packets,
+            self.payload_bytes,
+            self.overhead_bytes(),
+            100.0 * self.overhead_ratio()
+        )?;
+        if let Some(c) = &self.compression {
+            write!(
+                f,
+                ", {} {} -> {} ({:.1}%)",
+                c.algorithm,
+                c.uncompressed_bytes,
+                c.compressed_bytes,
+                100.0 * c.ratio()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn mock_len<T: Serialize + ?Sized>(context: ProtocolContext, value: &T::Input) -> Result<usize> {
+    let mut ser = MockSerializer::new(context);
+    T::serialize(value, &mut ser)?;
+    Ok(ser.len())
+}
+
+/// Wire size of a packet carrying 'inner'
+fn packet_len(context: ProtocolContext, inner: InnerBody, reliable: bool) -> Result<usize> {
+    let body = if reliable {
+        inner.into_reliable(0)
+    } else {
+        inner.into_unreliable()
+    };
+    mock_len::<Packet>(context, &Packet::new(0, 0, body))
+}
+
+fn zlib<T: Serialize>(context: ProtocolContext, value: &T::Input) -> Result<Compression> {
+    Ok(Compression {
+        algorithm: "zlib",
+        uncompressed_bytes: mock_len::<T>(context, value)?,
+        // Minus the u32 size prefix
+        compressed_bytes: mock_len::<ZLibCompressed<T>>(context, value)? - 4,
+    })
+}
+
+fn compression(context: ProtocolContext, command: &Command) -> Result<Option<Compression>> {
+    let Some(command) = command.toclient_ref() else {
+        return Ok(None);
+    };
+    Ok(match command {
+        ToClientCommand::Blockdata(spec) => Some(Compression {
+            algorithm: if context.ser_fmt >= 29 {
+                "zstd"
+            } else {
+                "zlib"
+            },
+            uncompressed_bytes: spec.block.uncompressed_len(context)?,
+            compressed_bytes: mock_len::<MapBlock>(context, &spec.block)?,
+        }),
+        ToClientCommand::Nodedef(spec) => Some(zlib::<NodeDefManager>(context, &spec.node_def)?),
+        ToClientCommand::Itemdef(spec) => Some(zlib::<ItemdefList>(context, &spec.item_def)?),
+        ToClientCommand::NodemetaChanged(spec) => {
+            Some(zlib::<AbsNodeMetadataList>(context, &spec.list)?)
+        }
+        _ => None,
+    })
+}
+
+/// Analyze the overhead of sending 'command' using 'protocol_version'.
+pub fn analyze(command: &Command, protocol_version: u16) -> Result<OverheadReport> {
+    let context = ProtocolContext {
+        dir: command.direction(),
+        protocol_version,
+        ser_fmt: ser_fmt_for_protocol(protocol_version),
+        text_format: TextFormatPolicy::default(),
+        zlib_level: ZLIB_DEFAULT_LEVEL,
+        audit: false,
+    };
+    let reliable = command.default_reliability();
+    let command_bytes = mock_len::<Command>(context, command)?;
+
+    // Mirrors the packetization done by the peer's SplitSender
+    let (packets, packet_bytes) = if command_bytes <= MAX_ORIGINAL_BODY_SIZE {
+        let inner = InnerBody::Original(OriginalBody {
+            command: command.clone(),
+        });
+        (1, packet_len(context, inner, reliable)?)
+    } else {
+        let chunk = |len: usize| {
+            InnerBody::Split(SplitBody {
+                seqnum: 0,
+                chunk_count: 0,
+                chunk_num: 0,
+                chunk_data: vec![0; len],
+            })
+        };
+        let full = command_bytes / MAX_SPLIT_BODY_SIZE;
+        let last = command_bytes % MAX_SPLIT_BODY_SIZE;
+        let mut bytes = full * packet_len(context, chunk(MAX_SPLIT_BODY_SIZE), reliable)?;
+        let mut packets = full;
+        if last > 0 {
+            bytes += packet_len(context, chunk(last), reliable)?;
+            packets += 1;
+        }
+        (packets, bytes)
+    };
+
+    Ok(OverheadReport {
+        protocol_version,
+        ser_fmt: context.ser_fmt,
+        command_name: command.command_name(),
+        reliable,
+        packets,
+        packet_header_bytes: packet_bytes - command_bytes,
+        command_header_bytes: COMMAND_HEADER_SIZE,
+        payload_bytes: command_bytes - COMMAND_HEADER_SIZE,
+        compression: compression(context, command)?,
+    })
+}
+
+/// Analyze 'command' for every supported protocol version, oldest first.
+pub fn analyze_all_versions(command: &Command) -> Result<Vec<OverheadReport>> {
+    (EARLIEST_PROTOCOL_VERSION..=LATEST_PROTOCOL_VERSION)
+        .map(|version| analyze(command, version))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::MediaSpec;
+    use crate::command::TSChatMessageSpec;
+    use crate::packet::PACKET_HEADER_SIZE;
+    use crate::packet::RELIABLE_HEADER_SIZE;
+    use crate::packet::SPLIT_HEADER_SIZE;
+    use crate::types::MediaFileData;
+    use alloc::string::ToString;
+
+    #[test]
+    fn small_command() {
+        let command = Command::ToServer(
+            TSChatMessageSpec {
+                message: "hi".to_string(),
+            }
+            .into(),
+        );
+        let report = analyze(&command, LATEST_PROTOCOL_VERSION).unwrap();
+        assert_eq!(report.packets, 1);
+        // u16 length + 2 UTF-16 code units
+        assert_eq!(report.payload_bytes, 6);
+        // base header, reliable header, original packet type
+        assert_eq!(
+            report.packet_header_bytes,
+            PACKET_HEADER_SIZE + RELIABLE_HEADER_SIZE + 1
+        );
+        assert!(report.overhead_ratio() > 0.5);
+        assert!(report.compression.is_none());
+        assert_eq!(
+            analyze_all_versions(&command).unwrap().len(),
+            (LATEST_PROTOCOL_VERSION - EARLIEST_PROTOCOL_VERSION + 1) as usize
+        );
+    }
+
+    #[test]
+    fn split_command() {
+        let command = Command::ToClient(
+            MediaSpec {
+                num_bunches: 1,
+                bunch_index: 0,
+                files: vec![MediaFileData {
+                    name: "big.png".to_string(),
+                    data: vec![7; 10000],
+                }],
+            }
+            .into(),
+        );
+        let report = analyze(&command, LATEST_PROTOCOL_VERSION).unwrap();
+        let command_bytes = report.command_header_bytes + report.payload_bytes;
+        assert_eq!(report.packets, command_bytes.div_ceil(MAX_SPLIT_BODY_SIZE));
+        let per_packet = PACKET_HEADER_SIZE + RELIABLE_HEADER_SIZE + SPLIT_HEADER_SIZE;
+        assert_eq!(report.packet_header_bytes, report.packets * per_packet);
+        assert!(report.overhead_ratio() < 0.05);
+    }
+}
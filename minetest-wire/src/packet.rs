@@ -1,3 +1,6 @@
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
 use anyhow::bail;
 
 use super::command::Command;
@@ -11,7 +14,28 @@ use super::ser::Serializer;
 
 pub const PROTOCOL_ID: u32 = 0x4f457403;
 
-pub const LATEST_PROTOCOL_VERSION: u16 = 41;
+/// Newest protocol version understood.
+/// Changes since 41 (Minetest 5.6) that affect the wire format here:
+///
+/// 42 (5.7): TOCLIENT_SET_LIGHTING gains saturation and auto exposure
+/// 43 (5.8): TOCLIENT_PLAY_SOUND gains start_time
+/// 44 (5.9): TOCLIENT_MOVE_PLAYER_REL, and AO_CMD_SET_BONE_POSITION gains
+///           scale, interpolation times and absolute flags
+pub const LATEST_PROTOCOL_VERSION: u16 = 44;
+// Minetest 5.0
+pub const EARLIEST_PROTOCOL_VERSION: u16 = 37;
+
+/// The protocol version to use with a peer that supports
+/// `peer_min..=peer_max` (from TOSERVER_INIT): the highest version both
+/// sides support, as the engine's server picks it. None if there is none.
+pub fn negotiate_protocol_version(peer_min: u16, peer_max: u16) -> Option<u16> {
+    let version = peer_max.min(LATEST_PROTOCOL_VERSION);
+    if version < peer_min.max(EARLIEST_PROTOCOL_VERSION) {
+        None
+    } else {
+        Some(version)
+    }
+}
 
 // Serialization format of map data
 pub const SER_FMT_HIGHEST_READ: u8 = 29;
@@ -19,6 +43,16 @@ pub const SER_FMT_HIGHEST_WRITE: u8 = 29;
 pub const SER_FMT_LOWEST_READ: u8 = 28;
 pub const SER_FMT_LOWEST_WRITE: u8 = 29;
 
+/// The map serialization format used with a given protocol version.
+/// Format 29 (zstd) arrived together with protocol 40 (Minetest 5.5).
+pub fn ser_fmt_for_protocol(protocol_version: u16) -> u8 {
+    if protocol_version >= 40 {
+        29
+    } else {
+        28
+    }
+}
+
 pub const MAX_PACKET_SIZE: usize = 512;
 pub const SEQNUM_INITIAL: u16 = 65500;
 pub const PACKET_HEADER_SIZE: usize = 7;
@@ -247,7 +281,7 @@ impl InnerBody {
 
     pub fn into_reliable(self, seqnum: u16) -> PacketBody {
         PacketBody::Reliable(ReliableBody {
-            seqnum: seqnum,
+            seqnum,
             inner: self,
         })
     }
@@ -311,7 +345,7 @@ impl PacketBody {
     pub fn inner(&self) -> &InnerBody {
         match self {
             PacketBody::Reliable(body) => &body.inner,
-            PacketBody::Inner(inner) => &inner,
+            PacketBody::Inner(inner) => inner,
         }
     }
 
@@ -377,7 +411,7 @@ impl Packet {
 
     pub fn as_control(&self) -> Option<&ControlBody> {
         match self.inner() {
-            InnerBody::Control(control) => Some(&control),
+            InnerBody::Control(control) => Some(control),
             InnerBody::Original(_) => None,
             InnerBody::Split(_) => None,
         }
@@ -0,0 +1,571 @@
+//!
+//! param2 encodings and node rotation
+//!
+//! What param2 means depends on the node's ContentFeatures::param_type_2
+//! (see ParamType2):
+//!
+//! ```text
+//! facedir           0-23   direction of the top (facedir / 4), and a
+//!                          turn around it (facedir % 4)
+//! 4dir              0-3    the turns around +Y of facedir
+//! wallmounted       0-5    the wall the node is attached to (6 and 7 are
+//!                          the floor and ceiling, turned 90 degrees)
+//! degrotate         0-239  turn around +Y, in 1.5 degree steps
+//! color*                   the same, with a palette index in the high bits
+//! ```
+//!
+//! The helpers follow the engine: the Lua API for directions
+//! (core.facedir_to_dir, core.dir_to_wallmounted, ...), and
+//! MapNode::rotateAlongYAxis for rotating placed nodes, which map editing
+//! tools need when turning a structure. Color bits are never changed.
+//!
+//! Directions are in node coordinates, e.g. facedir 0 faces +Z. A quarter
+//! turn around +X takes +Y to +Z, around +Y takes +Z to +X (facedir 0 to
+//! 1), and around +Z takes +X to +Y.
+//!
+//! ```text
+//! // A schematic turned by 90 degrees
+//! let param2 = rotate_param2_y(ParamType2::ColorFacedir, node.param2, 1);
+//! ```
+//!
+use super::types::v3f;
+use super::types::v3s16;
+
+/// ContentFeatures::param_type_2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType2 {
+    None,
+    Full,
+    FlowingLiquid,
+    Facedir,
+    Wallmounted,
+    Leveled,
+    Degrotate,
+    Meshoptions,
+    Color,
+    ColorFacedir,
+    ColorWallmounted,
+    GlasslikeLiquidLevel,
+    ColorDegrotate,
+    FourDir,
+    ColorFourDir,
+}
+
+impl ParamType2 {
+    /// None for values this crate doesn't know
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => ParamType2::None,
+            1 => ParamType2::Full,
+            2 => ParamType2::FlowingLiquid,
+            3 => ParamType2::Facedir,
+            4 => ParamType2::Wallmounted,
+            5 => ParamType2::Leveled,
+            6 => ParamType2::Degrotate,
+            7 => ParamType2::Meshoptions,
+            8 => ParamType2::Color,
+            9 => ParamType2::ColorFacedir,
+            10 => ParamType2::ColorWallmounted,
+            11 => ParamType2::GlasslikeLiquidLevel,
+            12 => ParamType2::ColorDegrotate,
+            13 => ParamType2::FourDir,
+            14 => ParamType2::ColorFourDir,
+            _ => return None,
+        })
+    }
+
+    /// The bits of param2 holding the palette index, if it has one
+    fn color_bits(self) -> Option<(u8, u8)> {
+        // (shift, mask after shifting)
+        match self {
+            ParamType2::Color => Some((0, 0xff)),
+            ParamType2::ColorFacedir | ParamType2::ColorDegrotate => Some((5, 0x07)),
+            ParamType2::ColorWallmounted => Some((3, 0x1f)),
+            ParamType2::ColorFourDir => Some((2, 0x3f)),
+            _ => None,
+        }
+    }
+
+    /// Palette index in `param2`, for the colored types
+    pub fn color(self, param2: u8) -> Option<u8> {
+        let (shift, mask) = self.color_bits()?;
+        Some((param2 >> shift) & mask)
+    }
+
+    /// `param2` with its palette index replaced (truncated to the bits
+    /// available). Unchanged for types without color.
+    pub fn with_color(self, param2: u8, color: u8) -> u8 {
+        match self.color_bits() {
+            Some((shift, mask)) => (param2 & !(mask << shift)) | ((color & mask) << shift),
+            None => param2,
+        }
+    }
+
+    /// (palette index, the rest), e.g. the facedir of a colorfacedir node.
+    /// The palette index is 0 for types without color.
+    pub fn split(self, param2: u8) -> (u8, u8) {
+        match self.color_bits() {
+            Some((shift, mask)) => ((param2 >> shift) & mask, param2 & !(mask << shift)),
+            None => (0, param2),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+const FACEDIR_MASK: u8 = 0x1f;
+const FOURDIR_MASK: u8 = 0x03;
+const WALLMOUNTED_MASK: u8 = 0x07;
+const DEGROTATE_STEPS: i32 = 240;
+const COLOR_DEGROTATE_MASK: u8 = 0x1f;
+const COLOR_DEGROTATE_STEPS: i32 = 24;
+
+type Dir = (i16, i16, i16);
+
+// As in builtin/game/item.lua
+const FACEDIR_DIRS: [Dir; 6] = [
+    (0, 0, 1),
+    (1, 0, 0),
+    (0, 0, -1),
+    (-1, 0, 0),
+    (0, -1, 0),
+    (0, 1, 0),
+];
+const FACEDIR_TO_DIR: [u8; 24] = [
+    0, 1, 2, 3, //
+    4, 1, 5, 3, //
+    5, 1, 4, 3, //
+    0, 4, 2, 5, //
+    0, 5, 2, 4, //
+    0, 3, 2, 1, //
+];
+// facedir / 4 is the direction the node's top points to
+const FACEDIR_AXIS_DIRS: [Dir; 6] = [
+    (0, 1, 0),
+    (0, 0, 1),
+    (0, 0, -1),
+    (1, 0, 0),
+    (-1, 0, 0),
+    (0, -1, 0),
+];
+const WALLMOUNTED_DIRS: [Dir; 8] = [
+    (0, 1, 0),
+    (0, -1, 0),
+    (1, 0, 0),
+    (-1, 0, 0),
+    (0, 0, 1),
+    (0, 0, -1),
+    // 0 and 1, rotated by 90 degrees
+    (0, 1, 0),
+    (0, -1, 0),
+];
+
+fn to_v3s16((x, y, z): Dir) -> v3s16 {
+    v3s16::new(x, y, z)
+}
+
+fn to_v3f((x, y, z): Dir) -> v3f {
+    v3f::new(x as f32, y as f32, z as f32)
+}
+
+/// Quarter turns around `axis`
+fn turn(axis: Axis, (x, y, z): Dir, quarter_turns: i32) -> Dir {
+    let mut v = (x, y, z);
+    for _ in 0..quarter_turns.rem_euclid(4) {
+        v = match axis {
+            // +Y becomes +Z
+            Axis::X => (v.0, -v.2, v.1),
+            // +Z becomes +X
+            Axis::Y => (v.2, v.1, -v.0),
+            // +X becomes +Y
+            Axis::Z => (-v.1, v.0, v.2),
+        };
+    }
+    v
+}
+
+/// A facedir as a rotation, by where it takes +Y (the top) and +Z (the
+/// front). The 24 facedirs are the 24 rotations of a cube.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rotation {
+    top: Dir,
+    front: Dir,
+}
+
+impl Rotation {
+    fn of_facedir(facedir: u8) -> Self {
+        let facedir = (facedir & FACEDIR_MASK) as usize % FACEDIR_TO_DIR.len();
+        Self {
+            top: FACEDIR_AXIS_DIRS[facedir / 4],
+            front: FACEDIR_DIRS[FACEDIR_TO_DIR[facedir] as usize],
+        }
+    }
+
+    fn to_facedir(self) -> u8 {
+        (0..FACEDIR_TO_DIR.len() as u8)
+            .find(|&facedir| Rotation::of_facedir(facedir) == self)
+            .expect("top and front are perpendicular")
+    }
+
+    fn apply(self, (x, y, z): Dir) -> Dir {
+        let (t, f) = (self.top, self.front);
+        // right = top x front
+        let r = (
+            t.1 * f.2 - t.2 * f.1,
+            t.2 * f.0 - t.0 * f.2,
+            t.0 * f.1 - t.1 * f.0,
+        );
+        (
+            x * r.0 + y * t.0 + z * f.0,
+            x * r.1 + y * t.1 + z * f.1,
+            x * r.2 + y * t.2 + z * f.2,
+        )
+    }
+}
+
+/// The direction a facedir (or colorfacedir) node faces
+pub fn facedir_to_dir(param2: u8) -> v3s16 {
+    to_v3s16(Rotation::of_facedir(param2).front)
+}
+
+/// The direction the top of a facedir (or colorfacedir) node points to
+pub fn facedir_to_top(param2: u8) -> v3s16 {
+    to_v3s16(Rotation::of_facedir(param2).top)
+}
+
+/// The facedir closest to `dir`. Only the horizontal directions (0-3)
+/// unless `is_6d`.
+pub fn dir_to_facedir(dir: &v3f, is_6d: bool) -> u8 {
+    let (x, y, z) = (libm::fabsf(dir.x), libm::fabsf(dir.y), libm::fabsf(dir.z));
+    if is_6d && y > x && y > z {
+        // Facing down (from above) or up
+        return match (dir.y < 0.0, x > z) {
+            (true, true) if dir.x < 0.0 => 19,
+            (true, true) => 13,
+            (true, false) if dir.z < 0.0 => 10,
+            (true, false) => 4,
+            (false, true) if dir.x < 0.0 => 15,
+            (false, true) => 17,
+            (false, false) if dir.z < 0.0 => 6,
+            (false, false) => 8,
+        };
+    }
+    if x > z {
+        if dir.x < 0.0 {
+            3
+        } else {
+            1
+        }
+    } else if dir.z < 0.0 {
+        2
+    } else {
+        0
+    }
+}
+
+/// Rotates a facedir (or colorfacedir) node by quarter turns around `axis`
+pub fn rotate_facedir(param2: u8, axis: Axis, quarter_turns: i32) -> u8 {
+    let rotation = Rotation::of_facedir(param2);
+    let rotated = Rotation {
+        top: turn(axis, rotation.top, quarter_turns),
+        front: turn(axis, rotation.front, quarter_turns),
+    };
+    (param2 & !FACEDIR_MASK) | rotated.to_facedir()
+}
+
+/// Rotates a facedir (or colorfacedir) node by quarter turns around +Y,
+/// like the engine's MapNode::rotateAlongYAxis
+pub fn rotate_facedir_y(param2: u8, quarter_turns: i32) -> u8 {
+    rotate_facedir(param2, Axis::Y, quarter_turns)
+}
+
+/// The facedir of a node with facedir `inner`, in something (a schematic,
+/// another node) turned by facedir `outer`. Color bits are `inner`'s.
+pub fn compose_facedir(outer: u8, inner: u8) -> u8 {
+    let (outer, rotation) = (Rotation::of_facedir(outer), Rotation::of_facedir(inner));
+    let composed = Rotation {
+        top: outer.apply(rotation.top),
+        front: outer.apply(rotation.front),
+    };
+    (inner & !FACEDIR_MASK) | composed.to_facedir()
+}
+
+/// The facedir that undoes `param2`: composed with it, gives facedir 0.
+/// Color bits are kept.
+pub fn invert_facedir(param2: u8) -> u8 {
+    let inverse = (0..FACEDIR_TO_DIR.len() as u8)
+        .find(|&facedir| compose_facedir(facedir, param2) & FACEDIR_MASK == 0)
+        .expect("every rotation has an inverse");
+    (param2 & !FACEDIR_MASK) | inverse
+}
+
+/// The direction a 4dir (or color4dir) node faces
+pub fn fourdir_to_dir(param2: u8) -> v3s16 {
+    to_v3s16(FACEDIR_DIRS[(param2 & FOURDIR_MASK) as usize])
+}
+
+/// The 4dir closest to `dir`, ignoring its vertical part
+pub fn dir_to_fourdir(dir: &v3f) -> u8 {
+    dir_to_facedir(dir, false)
+}
+
+/// Rotates a 4dir (or color4dir) node by quarter turns around +Y
+pub fn rotate_fourdir_y(param2: u8, quarter_turns: i32) -> u8 {
+    let fourdir = ((param2 & FOURDIR_MASK) as i32 + quarter_turns).rem_euclid(4);
+    (param2 & !FOURDIR_MASK) | fourdir as u8
+}
+
+/// The direction of the wall (or floor, ceiling) a wallmounted (or
+/// colorwallmounted) node is attached to
+pub fn wallmounted_to_dir(param2: u8) -> v3s16 {
+    to_v3s16(WALLMOUNTED_DIRS[(param2 & WALLMOUNTED_MASK) as usize])
+}
+
+/// The wallmounted value for a node attached towards `dir`
+pub fn dir_to_wallmounted(dir: &v3f) -> u8 {
+    let (x, y, z) = (libm::fabsf(dir.x), libm::fabsf(dir.y), libm::fabsf(dir.z));
+    if y > x.max(z) {
+        if dir.y < 0.0 {
+            1
+        } else {
+            0
+        }
+    } else if x > z {
+        if dir.x < 0.0 {
+            3
+        } else {
+            2
+        }
+    } else if dir.z < 0.0 {
+        5
+    } else {
+        4
+    }
+}
+
+/// Rotates a wallmounted (or colorwallmounted) node by quarter turns
+/// around `axis`. A node whose wall doesn't move (e.g. on the floor, turned
+/// around +Y) is left as it is, like in the engine.
+pub fn rotate_wallmounted(param2: u8, axis: Axis, quarter_turns: i32) -> u8 {
+    let wallmounted = param2 & WALLMOUNTED_MASK;
+    let dir = WALLMOUNTED_DIRS[wallmounted as usize];
+    let rotated = turn(axis, dir, quarter_turns);
+    if rotated == dir {
+        return param2;
+    }
+    (param2 & !WALLMOUNTED_MASK) | dir_to_wallmounted(&to_v3f(rotated))
+}
+
+/// Rotates a wallmounted (or colorwallmounted) node by quarter turns
+/// around +Y
+pub fn rotate_wallmounted_y(param2: u8, quarter_turns: i32) -> u8 {
+    rotate_wallmounted(param2, Axis::Y, quarter_turns)
+}
+
+/// The turn around +Y of a degrotate (or colordegrotate) node, in degrees
+pub fn degrotate_degrees(param_type_2: ParamType2, param2: u8) -> Option<f32> {
+    match param_type_2 {
+        ParamType2::Degrotate => Some((param2 as i32 % DEGROTATE_STEPS) as f32 * 1.5),
+        ParamType2::ColorDegrotate => {
+            let steps = (param2 & COLOR_DEGROTATE_MASK) as i32 % COLOR_DEGROTATE_STEPS;
+            Some(steps as f32 * 15.0)
+        }
+        _ => None,
+    }
+}
+
+/// Rotates a placed node by quarter turns around +Y, whatever its
+/// param_type_2, like the engine's MapNode::rotateAlongYAxis. Types without
+/// a rotation are returned unchanged.
+pub fn rotate_param2_y(param_type_2: ParamType2, param2: u8, quarter_turns: i32) -> u8 {
+    match param_type_2 {
+        ParamType2::Facedir | ParamType2::ColorFacedir => rotate_facedir_y(param2, quarter_turns),
+        ParamType2::FourDir | ParamType2::ColorFourDir => rotate_fourdir_y(param2, quarter_turns),
+        ParamType2::Wallmounted | ParamType2::ColorWallmounted => {
+            rotate_wallmounted_y(param2, quarter_turns)
+        }
+        ParamType2::Degrotate => {
+            let steps = param2 as i32 + DEGROTATE_STEPS / 4 * quarter_turns;
+            steps.rem_euclid(DEGROTATE_STEPS) as u8
+        }
+        ParamType2::ColorDegrotate => {
+            let steps =
+                (param2 & COLOR_DEGROTATE_MASK) as i32 + COLOR_DEGROTATE_STEPS / 4 * quarter_turns;
+            (param2 & !COLOR_DEGROTATE_MASK) | steps.rem_euclid(COLOR_DEGROTATE_STEPS) as u8
+        }
+        _ => param2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(x: f32, y: f32, z: f32) -> v3f {
+        v3f::new(x, y, z)
+    }
+
+    fn dir_v3f(v: v3s16) -> v3f {
+        v3f::new(v.x as f32, v.y as f32, v.z as f32)
+    }
+
+    // From the engine's mapnode.cpp: the facedir after 0, 90, 180 and 270
+    // degrees around +Y
+    const ENGINE_ROTATE_FACEDIR: [[u8; 4]; 24] = [
+        [0, 1, 2, 3],
+        [1, 2, 3, 0],
+        [2, 3, 0, 1],
+        [3, 0, 1, 2],
+        [4, 13, 10, 19],
+        [5, 14, 11, 16],
+        [6, 15, 8, 17],
+        [7, 12, 9, 18],
+        [8, 17, 6, 15],
+        [9, 18, 7, 12],
+        [10, 19, 4, 13],
+        [11, 16, 5, 14],
+        [12, 9, 18, 7],
+        [13, 10, 19, 4],
+        [14, 11, 16, 5],
+        [15, 8, 17, 6],
+        [16, 5, 14, 11],
+        [17, 6, 15, 8],
+        [18, 7, 12, 9],
+        [19, 4, 13, 10],
+        [20, 23, 22, 21],
+        [21, 20, 23, 22],
+        [22, 21, 20, 23],
+        [23, 22, 21, 20],
+    ];
+
+    const ENGINE_ROTATE_WALLMOUNTED: [[u8; 4]; 6] = [
+        [0, 0, 0, 0],
+        [1, 1, 1, 1],
+        [2, 5, 3, 4],
+        [3, 4, 2, 5],
+        [4, 2, 5, 3],
+        [5, 3, 4, 2],
+    ];
+
+    #[test]
+    fn engine_tables() {
+        for (facedir, rotated) in ENGINE_ROTATE_FACEDIR.iter().enumerate() {
+            for (turns, &expected) in rotated.iter().enumerate() {
+                let facedir = facedir as u8;
+                assert_eq!(rotate_facedir_y(facedir, turns as i32), expected);
+                // Colors are kept
+                assert_eq!(
+                    rotate_param2_y(ParamType2::ColorFacedir, 0xe0 | facedir, turns as i32),
+                    0xe0 | expected
+                );
+            }
+        }
+        for (wallmounted, rotated) in ENGINE_ROTATE_WALLMOUNTED.iter().enumerate() {
+            for (turns, &expected) in rotated.iter().enumerate() {
+                let wallmounted = wallmounted as u8;
+                assert_eq!(rotate_wallmounted_y(wallmounted, turns as i32), expected);
+            }
+        }
+        assert_eq!(rotate_wallmounted_y(6, 1), 6);
+        assert_eq!(rotate_wallmounted_y(0xf8 | 2, -1), 0xf8 | 4);
+    }
+
+    #[test]
+    fn directions() {
+        assert_eq!(facedir_to_dir(0), v3s16::new(0, 0, 1));
+        assert_eq!(facedir_to_dir(4), v3s16::new(0, -1, 0));
+        assert_eq!(facedir_to_dir(22), v3s16::new(0, 0, -1));
+        assert_eq!(facedir_to_top(22), v3s16::new(0, -1, 0));
+        // Color bits are ignored
+        assert_eq!(facedir_to_dir(0xe0 | 3), v3s16::new(-1, 0, 0));
+        assert_eq!(dir_to_facedir(&dir(0.2, 0.9, -0.5), false), 2);
+        assert_eq!(dir_to_facedir(&dir(-3.0, 0.0, 1.0), false), 3);
+        for f in 0..4 {
+            assert_eq!(dir_to_facedir(&dir_v3f(facedir_to_dir(f)), true), f);
+        }
+        // Looking up or down, the node faces that way
+        for d in [
+            dir(0.0, 1.0, 0.1),
+            dir(0.1, -1.0, 0.0),
+            dir(0.0, -1.0, -0.2),
+        ] {
+            let facedir = dir_to_facedir(&d, true);
+            assert_eq!(facedir_to_dir(facedir), v3s16::new(0, d.y as i16, 0));
+        }
+
+        assert_eq!(fourdir_to_dir(2), v3s16::new(0, 0, -1));
+        assert_eq!(dir_to_fourdir(&dir(1.0, 5.0, 0.5)), 1);
+        assert_eq!(rotate_fourdir_y(0x04 | 3, 1), 0x04);
+        assert_eq!(rotate_fourdir_y(0, -1), 3);
+
+        assert_eq!(wallmounted_to_dir(1), v3s16::new(0, -1, 0));
+        assert_eq!(wallmounted_to_dir(0x08 | 5), v3s16::new(0, 0, -1));
+        for w in 0..6 {
+            assert_eq!(dir_to_wallmounted(&dir_v3f(wallmounted_to_dir(w))), w);
+        }
+    }
+
+    #[test]
+    fn composition() {
+        for f in 0..24u8 {
+            // Turning is composing with the turned facedir 0
+            for turns in 0..4 {
+                for axis in [Axis::X, Axis::Y, Axis::Z] {
+                    let turned = rotate_facedir(0, axis, turns);
+                    assert_eq!(compose_facedir(turned, f), rotate_facedir(f, axis, turns));
+                }
+            }
+            assert_eq!(compose_facedir(0, f), f);
+            assert_eq!(compose_facedir(f, 0), f);
+            assert_eq!(compose_facedir(invert_facedir(f), f), 0);
+            assert_eq!(compose_facedir(f, invert_facedir(f)), 0);
+            assert_eq!(rotate_facedir(f, Axis::X, 4), f);
+        }
+        // Tipped over towards +Z: the top points +Z, the front down
+        assert_eq!(rotate_facedir(0, Axis::X, 1), 4);
+        assert_eq!(
+            facedir_to_top(rotate_facedir(0, Axis::Z, 1)),
+            v3s16::new(-1, 0, 0)
+        );
+        assert_eq!(invert_facedir(0x40 | 1), 0x40 | 3);
+
+        // A torch on the floor, tipped onto a wall
+        assert_eq!(rotate_wallmounted(1, Axis::X, 1), 5);
+        assert_eq!(rotate_wallmounted(7, Axis::Z, 2), 0);
+    }
+
+    #[test]
+    fn colors_and_degrotate() {
+        assert_eq!(ParamType2::from_u8(9), Some(ParamType2::ColorFacedir));
+        assert_eq!(ParamType2::from_u8(200), None);
+        assert_eq!(ParamType2::Color.color(200), Some(200));
+        assert_eq!(ParamType2::ColorFacedir.color(0xa3), Some(5));
+        assert_eq!(ParamType2::ColorWallmounted.color(0xfd), Some(31));
+        assert_eq!(ParamType2::ColorFourDir.color(0x07), Some(1));
+        assert_eq!(ParamType2::Facedir.color(0xff), None);
+        assert_eq!(ParamType2::ColorFacedir.with_color(0xa3, 1), 0x23);
+        assert_eq!(ParamType2::ColorWallmounted.with_color(0x05, 0xff), 0xfd);
+        assert_eq!(ParamType2::Facedir.with_color(0x05, 3), 0x05);
+        assert_eq!(ParamType2::ColorFacedir.split(0xb7), (5, 0x17));
+        assert_eq!(ParamType2::Facedir.split(0xb7), (0, 0xb7));
+
+        assert_eq!(degrotate_degrees(ParamType2::Degrotate, 30), Some(45.0));
+        assert_eq!(
+            degrotate_degrees(ParamType2::ColorDegrotate, 0x20 | 6),
+            Some(90.0)
+        );
+        assert_eq!(degrotate_degrees(ParamType2::Facedir, 6), None);
+        assert_eq!(rotate_param2_y(ParamType2::Degrotate, 200, 1), 20);
+        assert_eq!(rotate_param2_y(ParamType2::Degrotate, 10, -1), 190);
+        assert_eq!(
+            rotate_param2_y(ParamType2::ColorDegrotate, 0xe0 | 20, 1),
+            0xe0 | 2
+        );
+        assert_eq!(rotate_param2_y(ParamType2::Leveled, 42, 1), 42);
+    }
+}
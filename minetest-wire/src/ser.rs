@@ -1,6 +1,10 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
 use anyhow::bail;
 use anyhow::Result;
-use std::num::TryFromIntError;
+use core::num::TryFromIntError;
 
 use super::types::CommandDirection;
 use super::types::ProtocolContext;
@@ -64,7 +68,7 @@ impl<'a> SliceSerializer<'a> {
         Self {
             context,
             offset: 0,
-            data: data,
+            data,
             overflow: false,
         }
     }
@@ -229,6 +233,10 @@ impl MockSerializer {
     pub fn len(&self) -> usize {
         self.count
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
 }
 
 impl Serializer for MockSerializer {
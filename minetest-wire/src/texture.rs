@@ -0,0 +1,815 @@
+//!
+//! Texture strings
+//!
+//! Textures are sent as plain strings in a small modifier language, e.g.
+//!
+//! ```text
+//! default_dirt.png^(default_grass_side.png^[mask:overlay.png)^[colorize:#fff:128
+//! ```
+//!
+//! Parts are joined with '^' (each part is drawn over the result so far),
+//! and parentheses group parts. A part starting with '[' is a modifier,
+//! which either generates an image or alters the result so far.
+//!
+//! TextureSpec parses and builds these strings. Parsing only fails on
+//! malformed input; modifiers recognized by name are checked for the right
+//! arguments, and unrecognized ones are kept as `TextureModifier::Other`.
+//! `lint` reports problems that would make a client render a black square
+//! (unknown modifiers, missing file extensions, zero sizes, ...).
+//!
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use super::color::ColorSpec;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum TextureError {
+    #[error("Unbalanced parentheses in texture string")]
+    UnbalancedParens,
+    #[error("Empty part in texture string")]
+    EmptyPart,
+    #[error("Dangling escape at end of texture string")]
+    DanglingEscape,
+    #[error("Invalid arguments for [{0}: {1:?}")]
+    InvalidArguments(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TextureSpec {
+    pub parts: Vec<TexturePart>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TexturePart {
+    File(String),
+    Group(TextureSpec),
+    Modifier(TextureModifier),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextureModifier {
+    /// [crack:<n>:<p>, [cracko:<n>:<p> or [crack:<t>:<n>:<p>
+    Crack {
+        overlay: bool,
+        tiles: Option<u32>,
+        frames: u32,
+        progress: u32,
+    },
+    /// [combine:<w>x<h>:<x1>,<y1>=<file1>:...
+    Combine {
+        width: u32,
+        height: u32,
+        layers: Vec<(i32, i32, TextureSpec)>,
+    },
+    Brighten,
+    NoAlpha,
+    /// [makealpha:<r>,<g>,<b>
+    MakeAlpha(u8, u8, u8),
+    /// [transform<t>, where t is a sequence like "FXR90" or a number 0-7
+    Transform(String),
+    /// [inventorycube{<top>{<left>{<right>
+    InventoryCube {
+        top: TextureSpec,
+        left: TextureSpec,
+        right: TextureSpec,
+    },
+    /// [lowpart:<percent>:<file>
+    LowPart {
+        percent: u32,
+        texture: TextureSpec,
+    },
+    /// [verticalframe:<frames>:<frame>
+    VerticalFrame {
+        frames: u32,
+        frame: u32,
+    },
+    /// [mask:<file>
+    Mask(TextureSpec),
+    /// [sheet:<w>x<h>:<x>,<y>
+    Sheet {
+        width: u32,
+        height: u32,
+        x: u32,
+        y: u32,
+    },
+    /// [colorize:<color>[:<ratio>], where ratio is 0-255 or "alpha"
+    Colorize {
+        color: String,
+        ratio: Option<String>,
+    },
+    /// [multiply:<color>
+    Multiply(String),
+    /// [screen:<color>
+    Screen(String),
+    /// [opacity:<r>
+    Opacity(u8),
+    /// [invert:<mode>, where mode is a combination of "rgba"
+    Invert(String),
+    /// [resize:<w>x<h>
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    /// [png:<base64>
+    Png(String),
+    /// [fill:<w>x<h>[:<x>,<y>]:<color>
+    Fill {
+        width: u32,
+        height: u32,
+        position: Option<(i32, i32)>,
+        color: String,
+    },
+    /// [hsl:<hue>:<saturation>:<lightness> (colorize = false)
+    /// [colorizehsl:<hue>:<saturation>:<lightness> (colorize = true)
+    Hsl {
+        colorize: bool,
+        hue: i32,
+        saturation: i32,
+        lightness: i32,
+    },
+    /// [contrast:<contrast>:<brightness>
+    Contrast {
+        contrast: i32,
+        brightness: i32,
+    },
+    /// A modifier not known to this crate, without the leading '['
+    Other(String),
+}
+
+impl TextureSpec {
+    pub fn parse(s: &str) -> Result<Self, TextureError> {
+        let mut parts = Vec::new();
+        if s.is_empty() {
+            return Ok(Self { parts });
+        }
+        for part in split_top(s, '^')? {
+            parts.push(parse_part(part)?);
+        }
+        Ok(Self { parts })
+    }
+
+    pub fn file(name: &str) -> Self {
+        Self {
+            parts: vec![TexturePart::File(name.to_string())],
+        }
+    }
+
+    pub fn modifier(modifier: TextureModifier) -> Self {
+        Self {
+            parts: vec![TexturePart::Modifier(modifier)],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// Draw `other` over this texture. A multi-part `other` is grouped,
+    /// so that its modifiers only apply to itself.
+    pub fn overlay(mut self, other: TextureSpec) -> Self {
+        match other.parts.len() {
+            0 => (),
+            1 => self.parts.extend(other.parts),
+            _ => self.parts.push(TexturePart::Group(other)),
+        }
+        self
+    }
+
+    /// Apply a modifier to this texture
+    pub fn with(mut self, modifier: TextureModifier) -> Self {
+        self.parts.push(TexturePart::Modifier(modifier));
+        self
+    }
+
+    /// Every file name referenced, including those nested in modifiers.
+    pub fn files(&self) -> Vec<&str> {
+        let mut out = Vec::new();
+        self.collect_files(&mut out);
+        out
+    }
+
+    fn collect_files<'a>(&'a self, out: &mut Vec<&'a str>) {
+        for part in &self.parts {
+            match part {
+                TexturePart::File(name) => out.push(name),
+                TexturePart::Group(spec) => spec.collect_files(out),
+                TexturePart::Modifier(modifier) => {
+                    for spec in modifier.nested() {
+                        spec.collect_files(out);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Problems that parse cleanly, but will not render as intended.
+    pub fn lint(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        self.lint_into(&mut out);
+        out
+    }
+
+    fn lint_into(&self, out: &mut Vec<String>) {
+        for (i, part) in self.parts.iter().enumerate() {
+            match part {
+                TexturePart::File(name) => {
+                    if !has_image_extension(name) {
+                        out.push(format!("{:?} is not an image file", name));
+                    }
+                }
+                TexturePart::Group(spec) => {
+                    if spec.is_empty() {
+                        out.push("Empty group".to_string());
+                    }
+                    spec.lint_into(out);
+                }
+                TexturePart::Modifier(modifier) => {
+                    if i == 0 && !modifier.generates_image() {
+                        out.push(format!("[{} has no image to modify", modifier.name()));
+                    }
+                    modifier.lint_into(out);
+                    for spec in modifier.nested() {
+                        spec.lint_into(out);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl TextureModifier {
+    pub fn name(&self) -> &str {
+        use TextureModifier::*;
+        match self {
+            Crack { overlay: false, .. } => "crack",
+            Crack { overlay: true, .. } => "cracko",
+            Combine { .. } => "combine",
+            Brighten => "brighten",
+            NoAlpha => "noalpha",
+            MakeAlpha(..) => "makealpha",
+            Transform(_) => "transform",
+            InventoryCube { .. } => "inventorycube",
+            LowPart { .. } => "lowpart",
+            VerticalFrame { .. } => "verticalframe",
+            Mask(_) => "mask",
+            Sheet { .. } => "sheet",
+            Colorize { .. } => "colorize",
+            Multiply(_) => "multiply",
+            Screen(_) => "screen",
+            Opacity(_) => "opacity",
+            Invert(_) => "invert",
+            Resize { .. } => "resize",
+            Png(_) => "png",
+            Fill { .. } => "fill",
+            Hsl {
+                colorize: false, ..
+            } => "hsl",
+            Hsl { colorize: true, .. } => "colorizehsl",
+            Contrast { .. } => "contrast",
+            Other(body) => body.split([':', '{']).next().unwrap_or(body),
+        }
+    }
+
+    /// True for modifiers that create an image, rather than alter one.
+    pub fn generates_image(&self) -> bool {
+        use TextureModifier::*;
+        matches!(
+            self,
+            Combine { .. } | InventoryCube { .. } | Png(_) | Fill { .. } | Other(_)
+        )
+    }
+
+    /// Textures nested inside this modifier
+    pub fn nested(&self) -> Vec<&TextureSpec> {
+        use TextureModifier::*;
+        match self {
+            Combine { layers, .. } => layers.iter().map(|(_, _, spec)| spec).collect(),
+            InventoryCube { top, left, right } => vec![top, left, right],
+            LowPart { texture, .. } => vec![texture],
+            Mask(spec) => vec![spec],
+            _ => Vec::new(),
+        }
+    }
+
+    fn lint_into(&self, out: &mut Vec<String>) {
+        use TextureModifier::*;
+        let name = self.name();
+        let problem = match self {
+            Combine { width, height, .. }
+            | Resize { width, height }
+            | Sheet { width, height, .. }
+            | Fill { width, height, .. }
+                if *width == 0 || *height == 0 =>
+            {
+                format!("[{} has zero size", name)
+            }
+            VerticalFrame { frames, frame } if *frames == 0 || frame >= frames => {
+                format!("[verticalframe:{}:{} is out of range", frames, frame)
+            }
+            LowPart { percent, .. } if *percent > 100 => {
+                format!("[lowpart percent {} is over 100", percent)
+            }
+            Transform(t) if !is_valid_transform(t) => format!("Invalid transform {:?}", t),
+            Invert(mode) if mode.is_empty() || !mode.chars().all(|ch| "rgba".contains(ch)) => {
+                format!("Invalid invert mode {:?}", mode)
+            }
+            Colorize { ratio: Some(r), .. } if r != "alpha" && r.parse::<u8>().is_err() => {
+                format!("Invalid colorize ratio {:?}", r)
+            }
+            Colorize { color, .. } | Multiply(color) | Screen(color) | Fill { color, .. }
+                if ColorSpec::parse(color).is_err() =>
+            {
+                format!("Invalid color {:?} in [{}", color, name)
+            }
+            Other(_) => format!("Unknown modifier [{}", name),
+            _ => return,
+        };
+        out.push(problem);
+    }
+}
+
+fn has_image_extension(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    [".png", ".jpg", ".jpeg", ".bmp", ".tga"]
+        .iter()
+        .any(|ext| lower.ends_with(ext) && lower.len() > ext.len())
+}
+
+fn is_valid_transform(t: &str) -> bool {
+    if let Ok(n) = t.parse::<u8>() {
+        return n < 8;
+    }
+    let mut rest = t.to_ascii_uppercase();
+    if rest.is_empty() {
+        return false;
+    }
+    while !rest.is_empty() {
+        let op = ["R270", "R180", "R90", "FX", "FY", "I"]
+            .iter()
+            .find(|op| rest.starts_with(*op));
+        match op {
+            Some(op) => rest.drain(..op.len()),
+            None => return false,
+        };
+    }
+    true
+}
+
+/// Split on `sep`, outside of parentheses and escapes.
+/// The pieces are returned raw (escapes are not removed).
+fn split_top(s: &str, sep: char) -> Result<Vec<&str>, TextureError> {
+    let mut pieces = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut chars = s.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next().ok_or(TextureError::DanglingEscape)?;
+            }
+            '(' => depth += 1,
+            ')' => depth = depth.checked_sub(1).ok_or(TextureError::UnbalancedParens)?,
+            ch if ch == sep && depth == 0 => {
+                pieces.push(&s[start..i]);
+                start = i + ch.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    if depth != 0 {
+        return Err(TextureError::UnbalancedParens);
+    }
+    pieces.push(&s[start..]);
+    Ok(pieces)
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            if let Some(next) = chars.next() {
+                out.push(next);
+            }
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        if ch == '\\' || special.contains(&ch) {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+fn parse_part(part: &str) -> Result<TexturePart, TextureError> {
+    if part.is_empty() {
+        return Err(TextureError::EmptyPart);
+    }
+    if let Some(inner) = part.strip_prefix('(') {
+        // split_top guarantees balance, so the group runs to the end
+        let inner = inner
+            .strip_suffix(')')
+            .ok_or(TextureError::UnbalancedParens)?;
+        return Ok(TexturePart::Group(TextureSpec::parse(inner)?));
+    }
+    if let Some(body) = part.strip_prefix('[') {
+        return Ok(TexturePart::Modifier(parse_modifier(body)?));
+    }
+    Ok(TexturePart::File(unescape(part)))
+}
+
+fn parse_modifier(body: &str) -> Result<TextureModifier, TextureError> {
+    use TextureModifier::*;
+    if let Some(t) = body.strip_prefix("transform") {
+        return Ok(Transform(t.to_string()));
+    }
+    if let Some(rest) = body.strip_prefix("inventorycube{") {
+        let faces: Vec<&str> = rest.split('{').collect();
+        if faces.len() != 3 {
+            return Err(invalid("inventorycube", rest));
+        }
+        let face = |s: &str| TextureSpec::parse(&s.replace('&', "^"));
+        return Ok(InventoryCube {
+            top: face(faces[0])?,
+            left: face(faces[1])?,
+            right: face(faces[2])?,
+        });
+    }
+    let (name, args) = match body.split_once(':') {
+        Some((name, args)) => (name, Some(args)),
+        None => (body, None),
+    };
+    let err = || invalid(name, args.unwrap_or(""));
+    let list = |sep| -> Result<Vec<&str>, TextureError> {
+        match args {
+            Some(args) => split_top(args, sep),
+            None => Ok(Vec::new()),
+        }
+    };
+    Ok(match name {
+        "brighten" if args.is_none() => Brighten,
+        "noalpha" if args.is_none() => NoAlpha,
+        "crack" | "cracko" => {
+            let nums = list(':')?
+                .into_iter()
+                .map(|n| n.parse::<u32>().map_err(|_| err()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let overlay = name == "cracko";
+            match nums[..] {
+                [frames, progress] => Crack {
+                    overlay,
+                    tiles: None,
+                    frames,
+                    progress,
+                },
+                [tiles, frames, progress] => Crack {
+                    overlay,
+                    tiles: Some(tiles),
+                    frames,
+                    progress,
+                },
+                _ => return Err(err()),
+            }
+        }
+        "combine" => {
+            let items = list(':')?;
+            let (width, height) = items.first().and_then(|s| size(s)).ok_or_else(err)?;
+            let mut layers = Vec::new();
+            for item in &items[1..] {
+                let (pos, file) = item.split_once('=').ok_or_else(err)?;
+                let (x, y) = position(pos).ok_or_else(err)?;
+                layers.push((x, y, TextureSpec::parse(&unescape(file))?));
+            }
+            Combine {
+                width,
+                height,
+                layers,
+            }
+        }
+        "makealpha" => {
+            let rgb = list(',')?
+                .into_iter()
+                .map(|n| n.parse::<u8>().map_err(|_| err()))
+                .collect::<Result<Vec<_>, _>>()?;
+            match rgb[..] {
+                [r, g, b] => MakeAlpha(r, g, b),
+                _ => return Err(err()),
+            }
+        }
+        "lowpart" => {
+            let (percent, file) = args.and_then(|a| a.split_once(':')).ok_or_else(err)?;
+            LowPart {
+                percent: percent.parse().map_err(|_| err())?,
+                texture: TextureSpec::parse(&unescape(file))?,
+            }
+        }
+        "verticalframe" => match list(':')?[..] {
+            [frames, frame] => VerticalFrame {
+                frames: frames.parse().map_err(|_| err())?,
+                frame: frame.parse().map_err(|_| err())?,
+            },
+            _ => return Err(err()),
+        },
+        "mask" => Mask(TextureSpec::parse(&unescape(args.ok_or_else(err)?))?),
+        "sheet" => match list(':')?[..] {
+            [dims, pos] => {
+                let (width, height) = size(dims).ok_or_else(err)?;
+                let (x, y) = position(pos).ok_or_else(err)?;
+                let x = u32::try_from(x).map_err(|_| err())?;
+                let y = u32::try_from(y).map_err(|_| err())?;
+                Sheet {
+                    width,
+                    height,
+                    x,
+                    y,
+                }
+            }
+            _ => return Err(err()),
+        },
+        "colorize" => match list(':')?[..] {
+            [color] if !color.is_empty() => Colorize {
+                color: color.to_string(),
+                ratio: None,
+            },
+            [color, ratio] if !color.is_empty() => Colorize {
+                color: color.to_string(),
+                ratio: Some(ratio.to_string()),
+            },
+            _ => return Err(err()),
+        },
+        "multiply" | "screen" => {
+            let color = args.filter(|a| !a.is_empty()).ok_or_else(err)?.to_string();
+            if name == "multiply" {
+                Multiply(color)
+            } else {
+                Screen(color)
+            }
+        }
+        "opacity" => Opacity(args.ok_or_else(err)?.parse().map_err(|_| err())?),
+        "invert" => Invert(args.ok_or_else(err)?.to_string()),
+        "resize" => {
+            let (width, height) = args.and_then(size).ok_or_else(err)?;
+            Resize { width, height }
+        }
+        "png" => Png(args.ok_or_else(err)?.to_string()),
+        "fill" => {
+            let items = list(':')?;
+            let (dims, pos, color) = match items[..] {
+                [dims, color] => (dims, None, color),
+                [dims, pos, color] => (dims, Some(position(pos).ok_or_else(err)?), color),
+                _ => return Err(err()),
+            };
+            let (width, height) = size(dims).ok_or_else(err)?;
+            Fill {
+                width,
+                height,
+                position: pos,
+                color: color.to_string(),
+            }
+        }
+        "hsl" | "colorizehsl" => {
+            let nums = list(':')?
+                .into_iter()
+                .map(|n| n.parse::<i32>().map_err(|_| err()))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Saturation and lightness are optional
+            let (hue, saturation, lightness) = match nums[..] {
+                [h] => (h, 0, 0),
+                [h, s] => (h, s, 0),
+                [h, s, l] => (h, s, l),
+                _ => return Err(err()),
+            };
+            Hsl {
+                colorize: name == "colorizehsl",
+                hue,
+                saturation,
+                lightness,
+            }
+        }
+        "contrast" => match list(':')?[..] {
+            [contrast, brightness] => Contrast {
+                contrast: contrast.parse().map_err(|_| err())?,
+                brightness: brightness.parse().map_err(|_| err())?,
+            },
+            [contrast] => Contrast {
+                contrast: contrast.parse().map_err(|_| err())?,
+                brightness: 0,
+            },
+            _ => return Err(err()),
+        },
+        _ => Other(body.to_string()),
+    })
+}
+
+fn invalid(name: &str, args: &str) -> TextureError {
+    TextureError::InvalidArguments(name.to_string(), args.to_string())
+}
+
+/// Parses "<w>x<h>"
+fn size(s: &str) -> Option<(u32, u32)> {
+    let (w, h) = s.split_once('x')?;
+    Some((w.parse().ok()?, h.parse().ok()?))
+}
+
+/// Parses "<x>,<y>"
+fn position(s: &str) -> Option<(i32, i32)> {
+    let (x, y) = s.split_once(',')?;
+    Some((x.parse().ok()?, y.parse().ok()?))
+}
+
+impl FromStr for TextureSpec {
+    type Err = TextureError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for TextureSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, part) in self.parts.iter().enumerate() {
+            if i > 0 {
+                f.write_str("^")?;
+            }
+            match part {
+                TexturePart::File(name) => f.write_str(&escape(name, &['^', '(', ')']))?,
+                TexturePart::Group(spec) => write!(f, "({})", spec)?,
+                TexturePart::Modifier(modifier) => write!(f, "[{}", modifier)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TextureModifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use TextureModifier::*;
+        let name = self.name();
+        match self {
+            Crack {
+                tiles,
+                frames,
+                progress,
+                ..
+            } => match tiles {
+                Some(tiles) => write!(f, "{}:{}:{}:{}", name, tiles, frames, progress),
+                None => write!(f, "{}:{}:{}", name, frames, progress),
+            },
+            Combine {
+                width,
+                height,
+                layers,
+            } => {
+                write!(f, "combine:{}x{}", width, height)?;
+                for (x, y, spec) in layers {
+                    let spec = escape(&spec.to_string(), &['^', ':']);
+                    write!(f, ":{},{}={}", x, y, spec)?;
+                }
+                Ok(())
+            }
+            Brighten | NoAlpha => f.write_str(name),
+            MakeAlpha(r, g, b) => write!(f, "makealpha:{},{},{}", r, g, b),
+            Transform(t) => write!(f, "transform{}", t),
+            InventoryCube { top, left, right } => {
+                let face = |spec: &TextureSpec| spec.to_string().replace('^', "&");
+                write!(
+                    f,
+                    "inventorycube{{{}{{{}{{{}",
+                    face(top),
+                    face(left),
+                    face(right)
+                )
+            }
+            LowPart { percent, texture } => {
+                write!(
+                    f,
+                    "lowpart:{}:{}",
+                    percent,
+                    escape(&texture.to_string(), &['^'])
+                )
+            }
+            VerticalFrame { frames, frame } => write!(f, "verticalframe:{}:{}", frames, frame),
+            Mask(spec) => write!(f, "mask:{}", escape(&spec.to_string(), &['^'])),
+            Sheet {
+                width,
+                height,
+                x,
+                y,
+            } => write!(f, "sheet:{}x{}:{},{}", width, height, x, y),
+            Colorize { color, ratio } => match ratio {
+                Some(ratio) => write!(f, "colorize:{}:{}", color, ratio),
+                None => write!(f, "colorize:{}", color),
+            },
+            Multiply(color) | Screen(color) => write!(f, "{}:{}", name, color),
+            Opacity(r) => write!(f, "opacity:{}", r),
+            Invert(mode) => write!(f, "invert:{}", mode),
+            Resize { width, height } => write!(f, "resize:{}x{}", width, height),
+            Png(data) => write!(f, "png:{}", data),
+            Fill {
+                width,
+                height,
+                position,
+                color,
+            } => match position {
+                Some((x, y)) => write!(f, "fill:{}x{}:{},{}:{}", width, height, x, y, color),
+                None => write!(f, "fill:{}x{}:{}", width, height, color),
+            },
+            Hsl {
+                hue,
+                saturation,
+                lightness,
+                ..
+            } => write!(f, "{}:{}:{}:{}", name, hue, saturation, lightness),
+            Contrast {
+                contrast,
+                brightness,
+            } => write!(f, "contrast:{}:{}", contrast, brightness),
+            Other(body) => f.write_str(body),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(s: &str) -> TextureSpec {
+        let spec = TextureSpec::parse(s).unwrap();
+        assert_eq!(spec.to_string(), s);
+        spec
+    }
+
+    #[test]
+    fn parses_common_textures() {
+        let spec = roundtrip(
+            "default_dirt.png^(default_grass_side.png^[mask:overlay.png)^[colorize:#fff:128",
+        );
+        assert_eq!(spec.parts.len(), 3);
+        assert_eq!(
+            spec.files(),
+            vec!["default_dirt.png", "default_grass_side.png", "overlay.png"]
+        );
+        assert!(spec.lint().is_empty());
+
+        let spec = roundtrip(
+            "[combine:16x32:0,0=default_cobble.png:0,16=wool_white.png\\^[colorize\\:red",
+        );
+        match &spec.parts[0] {
+            TexturePart::Modifier(TextureModifier::Combine { layers, .. }) => {
+                assert_eq!(layers[1].2.to_string(), "wool_white.png^[colorize:red");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        roundtrip("[inventorycube{grass.png{dirt.png&grass_side.png{dirt.png&grass_side.png");
+        roundtrip("tnt_side.png^[crack:1:4:2^[transformFXR90^[verticalframe:4:1");
+        roundtrip("[fill:16x16:#00000080^heart.png^[opacity:128^[resize:32x32");
+        assert!(roundtrip("").is_empty());
+    }
+
+    #[test]
+    fn builder() {
+        let spec = TextureSpec::file("default_stone.png")
+            .overlay(
+                TextureSpec::file("ore.png").with(TextureModifier::Multiply("#ff0000".to_string())),
+            )
+            .with(TextureModifier::Brighten);
+        assert_eq!(
+            spec.to_string(),
+            "default_stone.png^(ore.png^[multiply:#ff0000)^[brighten"
+        );
+        assert_eq!(TextureSpec::parse(&spec.to_string()).unwrap(), spec);
+    }
+
+    #[test]
+    fn rejects_and_lints() {
+        assert_eq!(
+            TextureSpec::parse("a.png^(b.png"),
+            Err(TextureError::UnbalancedParens)
+        );
+        assert_eq!(
+            TextureSpec::parse("a.png^^b.png"),
+            Err(TextureError::EmptyPart)
+        );
+        assert!(TextureSpec::parse("a.png^[resize:16").is_err());
+        assert!(TextureSpec::parse("a.png^[colorize").is_err());
+
+        let lints = TextureSpec::parse(
+            "[brighten^a^[verticalframe:4:4^[frobnicate:1^[transformQ^[multiply:bluish",
+        )
+        .unwrap()
+        .lint();
+        assert_eq!(lints.len(), 6, "{:?}", lints);
+    }
+}
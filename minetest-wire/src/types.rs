@@ -6,22 +6,41 @@
 //! serialized/deserialized, so be careful modifying anything below.
 //! Their serialized representation must stay the same.
 //!
+//! A field added in a later protocol version is marked
+//! #[proto(since = N)]. It is skipped for older peers, and deserializes
+//! as its Default (or #[proto(since = N, default = "expr")]).
+//!
+//! Enums are written as a tag followed by the variant's fields. The tag is
+//! the variant's index, its discriminant, or #[proto(tag = N)], and a u8
+//! unless the enum says otherwise (#[tag(u16)]). An enum marked
+//! #[proto(version = N)] starts with that version byte, before the tag.
+//!
 //! NOTE: The derive macros currently do not work on structs with generic parameters.
 //!
 //! TODO(paradust): Having an assert!-like macro that generates Serialize/Deserialize
 //! errors instead of aborts may be helpful for cleaning this up.
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
 use anyhow::bail;
 use minetest_protocol_derive::MinetestDeserialize;
 use minetest_protocol_derive::MinetestSerialize;
 
 use crate::itos;
 
+use super::deser::is_eof;
 use super::deser::Deserialize;
 use super::deser::DeserializeError;
+use super::deser::DeserializeField;
 use super::deser::DeserializeResult;
 use super::deser::Deserializer;
 use super::packet::LATEST_PROTOCOL_VERSION;
 use super::packet::SER_FMT_HIGHEST_READ;
+use super::ser::MockSerializer;
 use super::ser::Serialize;
 use super::ser::SerializeError;
 use super::ser::SerializeResult;
@@ -36,12 +55,13 @@ use super::util::skip_whitespace;
 use super::util::split_by_whitespace;
 use super::util::stoi;
 use super::util::zstd_compress;
-use super::util::zstd_decompress;
-use std::marker::PhantomData;
-use std::ops::Deref;
-use std::ops::DerefMut;
-use std::ops::Div;
-use std::ops::Mul;
+use super::util::zstd_decompress_chunks;
+use super::util::ZLIB_DEFAULT_LEVEL;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::ops::Div;
+use core::ops::Mul;
 
 #[allow(non_camel_case_types)]
 pub type s8 = i8;
@@ -52,9 +72,13 @@ pub type s16 = i16;
 #[allow(non_camel_case_types)]
 pub type s32 = i32;
 
+#[allow(non_camel_case_types)]
+pub type s64 = i64;
+
 pub type CommandId = u8;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CommandDirection {
     ToClient,
     ToServer,
@@ -83,10 +107,19 @@ impl CommandDirection {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProtocolContext {
     pub dir: CommandDirection,
     pub protocol_version: u16,
     pub ser_fmt: u8,
+    pub text_format: TextFormatPolicy,
+    /// zlib level for ZLibCompressed values (Nodedef, Itemdef, ...) and
+    /// map blocks before serialization version 29. 0 (none) to 9 (best).
+    pub zlib_level: u8,
+    /// Re-serialize every command parsed in this context, and compare
+    /// (see audit). Does nothing without the std feature.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub audit: bool,
 }
 
 impl ProtocolContext {
@@ -95,6 +128,9 @@ impl ProtocolContext {
             dir: CommandDirection::for_receive(remote_is_server),
             protocol_version: LATEST_PROTOCOL_VERSION,
             ser_fmt: SER_FMT_HIGHEST_READ,
+            text_format: TextFormatPolicy::default(),
+            zlib_level: ZLIB_DEFAULT_LEVEL,
+            audit: false,
         }
     }
 
@@ -103,6 +139,99 @@ impl ProtocolContext {
             dir: CommandDirection::for_send(remote_is_server),
             protocol_version: LATEST_PROTOCOL_VERSION,
             ser_fmt: SER_FMT_HIGHEST_READ,
+            text_format: TextFormatPolicy::default(),
+            zlib_level: ZLIB_DEFAULT_LEVEL,
+            audit: false,
+        }
+    }
+}
+
+/// How the line-based text formats (Inventory, ItemStack, InventoryAction,
+/// InventoryLocation) are parsed.
+///
+/// Emitting is always strict: the serializers write exactly what Minetest
+/// writes, quirks included. Parsing is lenient by default, and accepts
+/// everything Minetest's own parser accepts (legacy "End"/"end" markers,
+/// blank or unknown lines, mismatched list sizes). Lenient input does not
+/// necessarily serialize back to the same bytes. Code that must stay
+/// byte-transparent, such as a proxy, can use `reserialize` to find out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TextFormatPolicy {
+    #[default]
+    Lenient,
+    /// Only accept input in the exact form Minetest emits it.
+    Strict,
+}
+
+/// Result of `TextFormatPolicy::reserialize`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Reserialized<T> {
+    /// Serializing the value reproduces the input exactly
+    Exact(T),
+    /// The input was accepted, but serializes differently
+    Rewritten { value: T, emitted: Vec<u8> },
+}
+
+impl<T> Reserialized<T> {
+    pub fn is_exact(&self) -> bool {
+        matches!(self, Reserialized::Exact(_))
+    }
+
+    pub fn into_value(self) -> T {
+        match self {
+            Reserialized::Exact(value) => value,
+            Reserialized::Rewritten { value, .. } => value,
+        }
+    }
+}
+
+impl TextFormatPolicy {
+    pub fn is_strict(&self) -> bool {
+        *self == TextFormatPolicy::Strict
+    }
+
+    /// Fails in strict mode, for input that Minetest would never emit.
+    fn reject(&self, what: &str) -> DeserializeResult<()> {
+        if self.is_strict() {
+            bail!(DeserializeError::InvalidValue(format!(
+                "Non-canonical text format: {}",
+                what
+            )))
+        }
+        Ok(())
+    }
+
+    /// Parse `data` as a T under this policy, then serialize it again and
+    /// compare against the input. All of `data` must be consumed.
+    pub fn reserialize<T>(
+        &self,
+        context: ProtocolContext,
+        data: &[u8],
+    ) -> anyhow::Result<Reserialized<T::Output>>
+    where
+        T: Deserialize + Serialize<Input = <T as Deserialize>::Output>,
+    {
+        let context = ProtocolContext {
+            text_format: *self,
+            ..context
+        };
+        let mut deser = Deserializer::new(context, data);
+        let value = T::deserialize(&mut deser)?;
+        if deser.remaining() > 0 {
+            bail!(DeserializeError::InvalidValue(format!(
+                "{} trailing bytes",
+                deser.remaining()
+            )));
+        }
+        let mut ser = VecSerializer::new(context, data.len());
+        T::serialize(&value, &mut ser)?;
+        let emitted = ser.take();
+        if emitted == data {
+            Ok(Reserialized::Exact(value))
+        } else {
+            Ok(Reserialized::Rewritten { value, emitted })
         }
     }
 }
@@ -114,10 +243,10 @@ impl ProtocolContext {
 #[derive(Clone, PartialEq)]
 pub struct ByteString(pub Vec<u8>);
 
-impl std::fmt::Debug for ByteString {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for ByteString {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         // Format it as an escaped string
-        std::fmt::Debug::fmt(&self.escape_ascii(), f)
+        core::fmt::Debug::fmt(&self.escape_ascii(), f)
     }
 }
 
@@ -153,6 +282,58 @@ impl DerefMut for ByteString {
     }
 }
 
+/// A string in human readable formats (JSON) when it is valid UTF-8, and
+/// bytes otherwise. Either is accepted back.
+#[cfg(feature = "serde")]
+impl serde::Serialize for ByteString {
+    fn serialize<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match core::str::from_utf8(&self.0) {
+            Ok(text) if ser.is_human_readable() => ser.serialize_str(text),
+            _ => ser.serialize_bytes(&self.0),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ByteString {
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ByteString;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.write_str("a string or bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<ByteString, E> {
+                Ok(ByteString(text.as_bytes().to_vec()))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<ByteString, E> {
+                Ok(ByteString(bytes.to_vec()))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<ByteString, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(ByteString(bytes))
+            }
+        }
+
+        if de.is_human_readable() {
+            de.deserialize_any(Visitor)
+        } else {
+            de.deserialize_byte_buf(Visitor)
+        }
+    }
+}
+
 impl From<Vec<u8>> for ByteString {
     fn from(value: Vec<u8>) -> Self {
         Self(value)
@@ -299,12 +480,46 @@ impl Deserialize for f32 {
     }
 }
 
+impl Serialize for i64 {
+    type Input = Self;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        ser.write_bytes(&value.to_be_bytes()[..])
+    }
+}
+
+impl Deserialize for i64 {
+    type Output = Self;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
+        Ok(i64::from_be_bytes(deser.take_n::<8>()?))
+    }
+}
+
+impl Serialize for f64 {
+    type Input = Self;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        ser.write_bytes(&value.to_be_bytes()[..])
+    }
+}
+
+impl Deserialize for f64 {
+    type Output = Self;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
+        Ok(f64::from_be_bytes(deser.take_n::<8>()?))
+    }
+}
+
 /// str implements Serialize but not Deserialize
 impl Serialize for str {
     type Input = Self;
 
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        u16::serialize(&u16::try_from(value.len())?, ser)?;
+        let Ok(len) = u16::try_from(value.len()) else {
+            bail!(SerializeError::InvalidValue(format!(
+                "String too long ({} bytes, max 65535)",
+                value.len()
+            )));
+        };
+        u16::serialize(&len, ser)?;
         ser.write_bytes(value.as_bytes())
     }
 }
@@ -312,7 +527,7 @@ impl Serialize for str {
 impl Serialize for String {
     type Input = Self;
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        <str as Serialize>::serialize(&value, ser)
+        <str as Serialize>::serialize(value, ser)
     }
 }
 
@@ -320,7 +535,7 @@ impl Deserialize for String {
     type Output = Self;
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
         let num_bytes = u16::deserialize(deser)? as usize;
-        match std::str::from_utf8(deser.take(num_bytes)?) {
+        match core::str::from_utf8(deser.take(num_bytes)?) {
             Ok(s) => Ok(s.to_string()),
             Err(u) => bail!(DeserializeError::InvalidValue(u.to_string())),
         }
@@ -328,13 +543,14 @@ impl Deserialize for String {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LongString(PhantomData<String>);
 
 impl Serialize for LongString {
     type Input = String;
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
         u32::serialize(&u32::try_from(value.len())?, ser)?;
-        ser.write_bytes(&value.as_bytes())
+        ser.write_bytes(value.as_bytes())
     }
 }
 
@@ -342,7 +558,7 @@ impl Deserialize for LongString {
     type Output = String;
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
         let num_bytes = u32::deserialize(deser)? as usize;
-        match std::str::from_utf8(deser.take(num_bytes)?) {
+        match core::str::from_utf8(deser.take(num_bytes)?) {
             Ok(s) => Ok(s.to_string()),
             Err(u) => bail!(DeserializeError::InvalidValue(u.to_string())),
         }
@@ -351,6 +567,7 @@ impl Deserialize for LongString {
 
 /// Corresponds to std::wstring in C++ land
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WString(PhantomData<String>);
 
 impl Serialize for WString {
@@ -358,7 +575,13 @@ impl Serialize for WString {
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
         let enc: Vec<u16> = value.encode_utf16().collect();
 
-        u16::serialize(&u16::try_from(enc.len())?, ser)?;
+        let Ok(len) = u16::try_from(enc.len()) else {
+            bail!(SerializeError::InvalidValue(format!(
+                "Wide string too long ({} code units, max 65535)",
+                enc.len()
+            )));
+        };
+        u16::serialize(&len, ser)?;
         // TODO: This could be made more efficient.
         let mut buf: Vec<u8> = vec![0; 2 * enc.len()];
         let mut index: usize = 0;
@@ -389,6 +612,7 @@ impl Deserialize for WString {
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct v2f {
     pub x: f32,
     pub y: f32,
@@ -402,6 +626,7 @@ impl v2f {
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct v3f {
     pub x: f32,
     pub y: f32,
@@ -415,9 +640,9 @@ impl v3f {
 
     pub fn as_v3s32(&self) -> v3s32 {
         v3s32 {
-            x: self.x.round() as i32,
-            y: self.y.round() as i32,
-            z: self.z.round() as i32,
+            x: libm::roundf(self.x) as i32,
+            y: libm::roundf(self.y) as i32,
+            z: libm::roundf(self.z) as i32,
         }
     }
 }
@@ -444,8 +669,56 @@ impl Div<f32> for v3f {
     }
 }
 
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct v2f64 {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl v2f64 {
+    pub fn new(x: f64, y: f64) -> Self {
+        Self { x, y }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct v3f64 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl v3f64 {
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn as_v3f(&self) -> v3f {
+        v3f {
+            x: self.x as f32,
+            y: self.y as f32,
+            z: self.z as f32,
+        }
+    }
+}
+
+impl From<v3f> for v3f64 {
+    fn from(value: v3f) -> Self {
+        Self {
+            x: value.x as f64,
+            y: value.y as f64,
+            z: value.z as f64,
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct v2u32 {
     pub x: u32,
     pub y: u32,
@@ -459,6 +732,7 @@ impl v2u32 {
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct v2s16 {
     pub x: s16,
     pub y: s16,
@@ -471,7 +745,8 @@ impl v2s16 {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct v3s16 {
     pub x: s16,
     pub y: s16,
@@ -486,6 +761,7 @@ impl v3s16 {
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct v2s32 {
     pub x: s32,
     pub y: s32,
@@ -499,6 +775,7 @@ impl v2s32 {
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct v3s32 {
     pub x: s32,
     pub y: s32,
@@ -515,12 +792,14 @@ impl v3s32 {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+// Sent as ARGB8 (the big-endian encoding of the engine's 0xAARRGGBB)
+#[derive(Debug, Clone, Copy, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SColor {
+    pub a: u8,
     pub r: u8,
     pub g: u8,
     pub b: u8,
-    pub a: u8,
 }
 
 impl SColor {
@@ -531,6 +810,7 @@ impl SColor {
 
 // Wrapped in a String (really a BinaryData16) with a 16-bit length
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Wrapped16<T> {
     phantom: PhantomData<T>,
 }
@@ -557,6 +837,7 @@ impl<T: Deserialize> Deserialize for Wrapped16<T> {
 
 // Wrapped in a String (really a BinaryData16) with a 16-bit length
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Wrapped32<T> {
     phantom: PhantomData<T>,
 }
@@ -582,6 +863,7 @@ impl<T: Deserialize> Deserialize for Wrapped32<T> {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinaryData16;
 
 impl Serialize for BinaryData16 {
@@ -597,12 +879,13 @@ impl Deserialize for BinaryData16 {
     type Output = Vec<u8>;
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
         let num_bytes = u16::deserialize(deser)? as usize;
-        Ok(Vec::from(deser.take(num_bytes)?))
+        deser.take_vec(num_bytes)
     }
 }
 
 /// Binary data preceded by a U32 size
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinaryData32;
 
 impl Serialize for BinaryData32 {
@@ -618,11 +901,12 @@ impl Deserialize for BinaryData32 {
     type Output = Vec<u8>;
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
         let num_bytes = u32::deserialize(deser)? as usize;
-        Ok(Vec::from(deser.take(num_bytes)?))
+        deser.take_vec(num_bytes)
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FixedArray<const COUNT: usize, T>
 where
     T: Serialize<Input = T>,
@@ -689,9 +973,28 @@ impl<T: Deserialize> Deserialize for Option<T> {
     }
 }
 
+/// Boxed the same as unboxed, for large enum variants
+impl<T: Serialize> Serialize for Box<T>
+where
+    <T as Serialize>::Input: Sized,
+{
+    type Input = Box<T::Input>;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        <T as Serialize>::serialize(value, ser)
+    }
+}
+
+impl<T: Deserialize> Deserialize for Box<T> {
+    type Output = Box<T::Output>;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
+        Ok(Box::new(<T as Deserialize>::deserialize(deser)?))
+    }
+}
+
 // An Optional value controlled by a u16 size parameter.
 // Unlike Option, this can appear anywhere in the message.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Option16<T> {
     None,
     Some(T),
@@ -730,7 +1033,86 @@ impl<T: Deserialize> Deserialize for Option16<T> {
     }
 }
 
+// An Optional value controlled by a u32 size parameter.
+// Same as Option16, for values that may exceed 64KiB.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Option32<T> {
+    None,
+    Some(T),
+}
+
+impl<T: Serialize> Serialize for Option32<T>
+where
+    <T as Serialize>::Input: Sized,
+{
+    type Input = Option32<T::Input>;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        match value {
+            Option32::None => u32::serialize(&0u32, ser),
+            Option32::Some(value) => {
+                let mut buf = VecSerializer::new(ser.context(), 64);
+                <T as Serialize>::serialize(value, &mut buf)?;
+                let buf = buf.take();
+                let num_bytes = u32::try_from(buf.len())?;
+                u32::serialize(&num_bytes, ser)?;
+                ser.write_bytes(&buf)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: Deserialize> Deserialize for Option32<T> {
+    type Output = Option32<T::Output>;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
+        match u32::deserialize(deser)? {
+            0 => Ok(Option32::None),
+            num_bytes => {
+                let mut buf = deser.slice(num_bytes as usize)?;
+                Ok(Option32::Some(<T as Deserialize>::deserialize(&mut buf)?))
+            }
+        }
+    }
+}
+
+/// An Optional value preceded by a bool (u8) presence flag.
+/// Unlike Option, this can appear anywhere in the message.
+///
+/// Use as a wrapper: `field: Option<T> [wrap(BoolOption<T>)]`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BoolOption<T>(PhantomData<T>);
+
+impl<T: Serialize> Serialize for BoolOption<T>
+where
+    <T as Serialize>::Input: Sized,
+{
+    type Input = Option<T::Input>;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        match value {
+            None => bool::serialize(&false, ser),
+            Some(value) => {
+                bool::serialize(&true, ser)?;
+                <T as Serialize>::serialize(value, ser)
+            }
+        }
+    }
+}
+
+impl<T: Deserialize> Deserialize for BoolOption<T> {
+    type Output = Option<T::Output>;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
+        if bool::deserialize(deser)? {
+            Ok(Some(<T as Deserialize>::deserialize(deser)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AddedObject {
     pub id: u16,
     pub typ: u8,
@@ -740,6 +1122,7 @@ pub struct AddedObject {
 
 /// This corresponds to GenericCAO::Initialize in minetest
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GenericInitData {
     pub version: u8,
     pub name: String,
@@ -753,123 +1136,52 @@ pub struct GenericInitData {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ActiveObjectMessage {
     pub id: u16,
     #[wrap(Wrapped16<ActiveObjectCommand>)]
     pub data: ActiveObjectCommand,
 }
 
-// TODO(paradust): Handle this in derive macros
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ActiveObjectCommand {
-    SetProperties(AOCSetProperties),
+    #[proto(tag = 0)]
+    SetProperties(Box<AOCSetProperties>),
+    #[proto(tag = 1)]
     UpdatePosition(AOCUpdatePosition),
+    #[proto(tag = 2)]
     SetTextureMod(AOCSetTextureMod),
+    #[proto(tag = 3)]
     SetSprite(AOCSetSprite),
+    #[proto(tag = 9)]
     SetPhysicsOverride(AOCSetPhysicsOverride),
+    #[proto(tag = 6)]
     SetAnimation(AOCSetAnimation),
+    #[proto(tag = 12)]
     SetAnimationSpeed(AOCSetAnimationSpeed),
+    #[proto(tag = 7)]
     SetBonePosition(AOCSetBonePosition),
+    #[proto(tag = 8)]
     AttachTo(AOCAttachTo),
+    #[proto(tag = 4)]
     Punched(AOCPunched),
+    #[proto(tag = 5)]
     UpdateArmorGroups(AOCUpdateArmorGroups),
+    #[proto(tag = 11)]
     SpawnInfant(AOCSpawnInfant),
+    #[proto(tag = 10)]
     Obsolete1(AOCObsolete1),
 }
 
-const AO_CMD_SET_PROPERTIES: u8 = 0;
-const AO_CMD_UPDATE_POSITION: u8 = 1;
-const AO_CMD_SET_TEXTURE_MOD: u8 = 2;
-const AO_CMD_SET_SPRITE: u8 = 3;
-const AO_CMD_PUNCHED: u8 = 4;
-const AO_CMD_UPDATE_ARMOR_GROUPS: u8 = 5;
-const AO_CMD_SET_ANIMATION: u8 = 6;
-const AO_CMD_SET_BONE_POSITION: u8 = 7;
-const AO_CMD_ATTACH_TO: u8 = 8;
-const AO_CMD_SET_PHYSICS_OVERRIDE: u8 = 9;
-const AO_CMD_OBSOLETE1: u8 = 10;
-const AO_CMD_SPAWN_INFANT: u8 = 11;
-const AO_CMD_SET_ANIMATION_SPEED: u8 = 12;
-
-impl ActiveObjectCommand {
-    fn get_command_prefix(&self) -> u8 {
-        match self {
-            ActiveObjectCommand::SetProperties(_) => AO_CMD_SET_PROPERTIES,
-            ActiveObjectCommand::UpdatePosition(_) => AO_CMD_UPDATE_POSITION,
-            ActiveObjectCommand::SetTextureMod(_) => AO_CMD_SET_TEXTURE_MOD,
-            ActiveObjectCommand::SetSprite(_) => AO_CMD_SET_SPRITE,
-            ActiveObjectCommand::SetPhysicsOverride(_) => AO_CMD_SET_PHYSICS_OVERRIDE,
-            ActiveObjectCommand::SetAnimation(_) => AO_CMD_SET_ANIMATION,
-            ActiveObjectCommand::SetAnimationSpeed(_) => AO_CMD_SET_ANIMATION_SPEED,
-            ActiveObjectCommand::SetBonePosition(_) => AO_CMD_SET_BONE_POSITION,
-            ActiveObjectCommand::AttachTo(_) => AO_CMD_ATTACH_TO,
-            ActiveObjectCommand::Punched(_) => AO_CMD_PUNCHED,
-            ActiveObjectCommand::UpdateArmorGroups(_) => AO_CMD_UPDATE_ARMOR_GROUPS,
-            ActiveObjectCommand::SpawnInfant(_) => AO_CMD_SPAWN_INFANT,
-            ActiveObjectCommand::Obsolete1(_) => AO_CMD_OBSOLETE1,
-        }
-    }
-}
-
-impl Serialize for ActiveObjectCommand {
-    type Input = Self;
-    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        u8::serialize(&value.get_command_prefix(), ser)?;
-        match value {
-            ActiveObjectCommand::SetProperties(v) => AOCSetProperties::serialize(v, ser)?,
-            ActiveObjectCommand::UpdatePosition(v) => AOCUpdatePosition::serialize(v, ser)?,
-            ActiveObjectCommand::SetTextureMod(v) => AOCSetTextureMod::serialize(v, ser)?,
-            ActiveObjectCommand::SetSprite(v) => AOCSetSprite::serialize(v, ser)?,
-            ActiveObjectCommand::SetPhysicsOverride(v) => AOCSetPhysicsOverride::serialize(v, ser)?,
-            ActiveObjectCommand::SetAnimation(v) => AOCSetAnimation::serialize(v, ser)?,
-            ActiveObjectCommand::SetAnimationSpeed(v) => AOCSetAnimationSpeed::serialize(v, ser)?,
-            ActiveObjectCommand::SetBonePosition(v) => AOCSetBonePosition::serialize(v, ser)?,
-            ActiveObjectCommand::AttachTo(v) => AOCAttachTo::serialize(v, ser)?,
-            ActiveObjectCommand::Punched(v) => AOCPunched::serialize(v, ser)?,
-            ActiveObjectCommand::UpdateArmorGroups(v) => AOCUpdateArmorGroups::serialize(v, ser)?,
-            ActiveObjectCommand::SpawnInfant(v) => AOCSpawnInfant::serialize(v, ser)?,
-            ActiveObjectCommand::Obsolete1(v) => AOCObsolete1::serialize(v, ser)?,
-        }
-        Ok(())
-    }
-}
-
-impl Deserialize for ActiveObjectCommand {
-    type Output = Self;
-    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
-        use ActiveObjectCommand::*;
-        let cmd = u8::deserialize(deser)?;
-        Ok(match cmd {
-            AO_CMD_SET_PROPERTIES => SetProperties(AOCSetProperties::deserialize(deser)?),
-            AO_CMD_UPDATE_POSITION => UpdatePosition(AOCUpdatePosition::deserialize(deser)?),
-            AO_CMD_SET_TEXTURE_MOD => SetTextureMod(AOCSetTextureMod::deserialize(deser)?),
-            AO_CMD_SET_SPRITE => SetSprite(AOCSetSprite::deserialize(deser)?),
-            AO_CMD_PUNCHED => Punched(AOCPunched::deserialize(deser)?),
-            AO_CMD_UPDATE_ARMOR_GROUPS => {
-                UpdateArmorGroups(AOCUpdateArmorGroups::deserialize(deser)?)
-            }
-            AO_CMD_SET_ANIMATION => SetAnimation(AOCSetAnimation::deserialize(deser)?),
-            AO_CMD_SET_BONE_POSITION => SetBonePosition(AOCSetBonePosition::deserialize(deser)?),
-            AO_CMD_ATTACH_TO => AttachTo(AOCAttachTo::deserialize(deser)?),
-            AO_CMD_SET_PHYSICS_OVERRIDE => {
-                SetPhysicsOverride(AOCSetPhysicsOverride::deserialize(deser)?)
-            }
-            AO_CMD_OBSOLETE1 => Obsolete1(AOCObsolete1::deserialize(deser)?),
-            AO_CMD_SPAWN_INFANT => SpawnInfant(AOCSpawnInfant::deserialize(deser)?),
-            AO_CMD_SET_ANIMATION_SPEED => {
-                SetAnimationSpeed(AOCSetAnimationSpeed::deserialize(deser)?)
-            }
-            _ => bail!("ActiveObjectCommand: Invalid cmd={}", cmd),
-        })
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCSetProperties {
     pub newprops: ObjectProperties,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ObjectProperties {
     pub version: u8, // must be 4
     pub hp_max: u16,
@@ -913,6 +1225,7 @@ pub struct ObjectProperties {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCUpdatePosition {
     pub position: v3f,
     pub velocity: v3f,
@@ -924,11 +1237,13 @@ pub struct AOCUpdatePosition {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCSetTextureMod {
     pub modifier: String,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCSetSprite {
     pub base_pos: v2s16,
     pub anum_num_frames: u16,
@@ -937,6 +1252,7 @@ pub struct AOCSetSprite {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCSetPhysicsOverride {
     pub override_speed: f32,
     pub override_jump: f32,
@@ -947,6 +1263,7 @@ pub struct AOCSetPhysicsOverride {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCSetAnimation {
     pub range: v2f, // this is always casted to v2s32 by minetest for some reason
     pub speed: f32,
@@ -955,18 +1272,30 @@ pub struct AOCSetAnimation {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCSetAnimationSpeed {
     pub speed: f32,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCSetBonePosition {
     pub bone: String,
     pub position: v3f,
     pub rotation: v3f,
+    // Bone overrides, since protocol 44 (5.9). Older clients ignore them.
+    pub scale: Option<v3f>,
+    /// Seconds to interpolate over
+    pub position_interp_timer: Option<f32>,
+    pub rotation_interp_timer: Option<f32>,
+    pub scale_interp_timer: Option<f32>,
+    /// Bits 0, 1, 2: position, rotation, scale replace the animation's
+    /// instead of adding to it
+    pub absolute: Option<u8>,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCAttachTo {
     pub parent_id: s16,
     pub bone: String,
@@ -976,29 +1305,34 @@ pub struct AOCAttachTo {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCPunched {
     pub hp: u16,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCUpdateArmorGroups {
     // name -> rating
-    #[wrap(Array16<Pair<String, s16>>)]
-    pub ratings: Vec<(String, s16)>,
+    #[wrap(LenMap<u16, String, s16>)]
+    pub ratings: OrderedMap<String, s16>,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCSpawnInfant {
     pub child_id: u16,
     pub typ: u8,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AOCObsolete1 {}
 
 /// An array of items with no specified length.
 /// The length is determined by buffer end.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Array0<T>(PhantomData<T>);
 
 impl<T: Serialize> Serialize for Array0<T>
@@ -1027,6 +1361,7 @@ impl<T: Deserialize> Deserialize for Array0<T> {
 
 /// An array of items with a u8 length prefix
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Array8<T>(PhantomData<T>);
 
 impl<T: Serialize> Serialize for Array8<T>
@@ -1057,6 +1392,7 @@ impl<T: Deserialize> Deserialize for Array8<T> {
 
 /// An array of items with a u16 length prefix
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Array16<T>(PhantomData<T>);
 
 impl<T: Serialize> Serialize for Array16<T>
@@ -1087,6 +1423,7 @@ impl<T: Deserialize> Deserialize for Array16<T> {
 
 /// An array of items with a u32 length prefix
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Array32<T>(PhantomData<T>);
 
 impl<T: Serialize> Serialize for Array32<T>
@@ -1122,6 +1459,7 @@ impl<T: Deserialize> Deserialize for Array32<T> {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MediaFileData {
     pub name: String,
     #[wrap(BinaryData32)]
@@ -1129,12 +1467,14 @@ pub struct MediaFileData {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MediaAnnouncement {
     pub name: String,
     pub sha1_base64: String,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SkyColor {
     pub day_sky: SColor,
     pub day_horizon: SColor,
@@ -1146,6 +1486,7 @@ pub struct SkyColor {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SunParams {
     pub visible: bool,
     pub texture: String,
@@ -1156,6 +1497,7 @@ pub struct SunParams {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MoonParams {
     pub visible: bool,
     pub texture: String,
@@ -1163,6 +1505,7 @@ pub struct MoonParams {
     pub scale: f32,
 }
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StarParams {
     pub visible: bool,
     pub count: u32,
@@ -1172,6 +1515,7 @@ pub struct StarParams {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MinimapMode {
     pub typ: u16,
     pub label: String,
@@ -1181,6 +1525,7 @@ pub struct MinimapMode {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PlayerPos {
     pub position: v3f,     // serialized as v3s32, *100.0f
     pub speed: v3f,        // serialzied as v3s32, *100.0f
@@ -1196,9 +1541,9 @@ impl Serialize for PlayerPos {
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
         let s_position = (value.position * 100f32).as_v3s32();
         let s_speed = (value.speed * 100f32).as_v3s32();
-        let s_pitch = (value.pitch * 100f32).round() as s32;
-        let s_yaw = (value.yaw * 100f32).round() as s32;
-        let s_fov = (value.fov * 80f32).round() as u8;
+        let s_pitch = libm::roundf(value.pitch * 100f32) as s32;
+        let s_yaw = libm::roundf(value.yaw * 100f32) as s32;
+        let s_fov = libm::roundf(value.fov * 80f32) as u8;
 
         v3s32::serialize(&s_position, ser)?;
         v3s32::serialize(&s_speed, ser)?;
@@ -1226,14 +1571,15 @@ impl Deserialize for PlayerPos {
             speed: s_speed.as_v3f() / 100f32,
             pitch: (s_pitch as f32) / 100f32,
             yaw: (s_yaw as f32) / 100f32,
-            keys_pressed: keys_pressed,
+            keys_pressed,
             fov: (s_fov as f32) / 80f32,
-            wanted_range: wanted_range,
+            wanted_range,
         })
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pair<T1, T2>(PhantomData<(T1, T2)>);
 
 impl<T1: Serialize, T2: Serialize> Serialize for Pair<T1, T2>
@@ -1259,7 +1605,285 @@ impl<T1: Deserialize, T2: Deserialize> Deserialize for Pair<T1, T2> {
     }
 }
 
+/// An order-preserving map, backed by a Vec of (key, value) entries.
+///
+/// Minetest sends many maps (groups, armor groups, damage groups, ...) as
+/// length-prefixed lists of pairs. This keeps the entries in wire order
+/// (so they re-serialize identically), while allowing lookup by key.
+/// If the same key appears more than once, lookups return the last
+/// entry, matching how Minetest fills its std::map's.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OrderedMap<K, V> {
+    entries: Vec<(K, V)>,
+}
+
+impl<K, V> Default for OrderedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<K: PartialEq, V> OrderedMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn position<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: core::borrow::Borrow<Q>,
+        Q: PartialEq + ?Sized,
+    {
+        self.entries.iter().rposition(|(k, _)| k.borrow() == key)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: core::borrow::Borrow<Q>,
+        Q: PartialEq + ?Sized,
+    {
+        self.position(key).map(|i| &self.entries[i].1)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: core::borrow::Borrow<Q>,
+        Q: PartialEq + ?Sized,
+    {
+        self.position(key).map(|i| &mut self.entries[i].1)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: core::borrow::Borrow<Q>,
+        Q: PartialEq + ?Sized,
+    {
+        self.position(key).is_some()
+    }
+
+    /// Set the value for a key. An existing entry keeps its position,
+    /// otherwise the entry is appended. Returns the previous value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.position(&key) {
+            Some(i) => Some(core::mem::replace(&mut self.entries[i].1, value)),
+            None => {
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// Append an entry even if the key is already present.
+    pub fn push(&mut self, key: K, value: V) {
+        self.entries.push((key, value));
+    }
+
+    /// Remove all entries for a key. Returns the value lookups would have returned.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: core::borrow::Borrow<Q>,
+        Q: PartialEq + ?Sized,
+    {
+        let mut removed = None;
+        let mut i = 0;
+        while i < self.entries.len() {
+            if self.entries[i].0.borrow() == key {
+                removed = Some(self.entries.remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+        removed
+    }
+
+    /// True if some key appears more than once
+    pub fn has_duplicates(&self) -> bool {
+        self.entries
+            .iter()
+            .enumerate()
+            .any(|(i, (k, _))| self.entries[..i].iter().any(|(k2, _)| k2 == k))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|(_, v)| v)
+    }
+
+    pub fn entries(&self) -> &[(K, V)] {
+        &self.entries
+    }
+
+    pub fn into_entries(self) -> Vec<(K, V)> {
+        self.entries
+    }
+}
+
+impl<K, V> From<Vec<(K, V)>> for OrderedMap<K, V> {
+    /// Keeps all entries, including duplicate keys.
+    fn from(entries: Vec<(K, V)>) -> Self {
+        Self { entries }
+    }
+}
+
+impl<K: PartialEq, V> FromIterator<(K, V)> for OrderedMap<K, V> {
+    /// Later entries replace earlier ones with the same key.
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (k, v) in iter {
+            map.insert(k, v);
+        }
+        map
+    }
+}
+
+/// Integer types used as length prefixes
+pub trait LenType {
+    fn write_len<S: Serializer>(len: usize, ser: &mut S) -> SerializeResult;
+    fn read_len(deser: &mut Deserializer) -> DeserializeResult<usize>;
+}
+
+macro_rules! impl_len_type {
+    ($($t: ty),*) => {
+        $(impl LenType for $t {
+            fn write_len<S: Serializer>(len: usize, ser: &mut S) -> SerializeResult {
+                <$t>::serialize(&<$t>::try_from(len)?, ser)
+            }
+
+            fn read_len(deser: &mut Deserializer) -> DeserializeResult<usize> {
+                Ok(<$t>::deserialize(deser)? as usize)
+            }
+        })*
+    };
+}
+
+impl_len_type!(u8, u16, u32);
+
+/// What LenMap does when the same key is received more than once
+pub trait DuplicatePolicy {
+    fn add<K: PartialEq, V>(map: &mut OrderedMap<K, V>, key: K, value: V) -> DeserializeResult<()>;
+}
+
+/// Keep every entry, so the map re-serializes exactly as received.
+/// Lookups return the last value.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeepDuplicates;
+
+/// Keep only the last value for a key (at the position of the first).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LastWins;
+
+/// Keep only the first value for a key.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirstWins;
+
+/// Fail deserialization on a duplicate key.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RejectDuplicates;
+
+impl DuplicatePolicy for KeepDuplicates {
+    fn add<K: PartialEq, V>(map: &mut OrderedMap<K, V>, key: K, value: V) -> DeserializeResult<()> {
+        map.push(key, value);
+        Ok(())
+    }
+}
+
+impl DuplicatePolicy for LastWins {
+    fn add<K: PartialEq, V>(map: &mut OrderedMap<K, V>, key: K, value: V) -> DeserializeResult<()> {
+        map.insert(key, value);
+        Ok(())
+    }
+}
+
+impl DuplicatePolicy for FirstWins {
+    fn add<K: PartialEq, V>(map: &mut OrderedMap<K, V>, key: K, value: V) -> DeserializeResult<()> {
+        if !map.contains_key(&key) {
+            map.push(key, value);
+        }
+        Ok(())
+    }
+}
+
+impl DuplicatePolicy for RejectDuplicates {
+    fn add<K: PartialEq, V>(map: &mut OrderedMap<K, V>, key: K, value: V) -> DeserializeResult<()> {
+        if map.contains_key(&key) {
+            bail!(DeserializeError::InvalidValue(
+                "Duplicate key in map".to_string()
+            ));
+        }
+        map.push(key, value);
+        Ok(())
+    }
+}
+
+/// A map sent as a length-prefixed list of (key, value) pairs.
+///
+/// L is the length type (u8, u16 or u32), and D the DuplicatePolicy.
+/// For example, `LenMap<u16, String, s16>` is the same encoding
+/// as `Array16<Pair<String, s16>>`.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LenMap<L, K, V, D = KeepDuplicates>(PhantomData<(L, K, V, D)>);
+
+impl<L: LenType, K: Serialize, V: Serialize, D> Serialize for LenMap<L, K, V, D>
+where
+    <K as Serialize>::Input: Sized,
+    <V as Serialize>::Input: Sized,
+{
+    type Input = OrderedMap<K::Input, V::Input>;
+    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
+        L::write_len(value.entries.len(), ser)?;
+        for (k, v) in value.entries.iter() {
+            <K as Serialize>::serialize(k, ser)?;
+            <V as Serialize>::serialize(v, ser)?;
+        }
+        Ok(())
+    }
+}
+
+impl<L: LenType, K: Deserialize, V: Deserialize, D: DuplicatePolicy> Deserialize
+    for LenMap<L, K, V, D>
+where
+    <K as Deserialize>::Output: PartialEq,
+{
+    type Output = OrderedMap<K::Output, V::Output>;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
+        let length = L::read_len(deser)?;
+        let mut map = OrderedMap {
+            entries: Vec::with_capacity(core::cmp::min(length, 1024)),
+        };
+        for _ in 0..length {
+            let k = <K as Deserialize>::deserialize(deser)?;
+            let v = <V as Deserialize>::deserialize(deser)?;
+            D::add(&mut map, k, v)?;
+        }
+        Ok(map)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum AccessDeniedCode {
     WrongPassword,
     UnexpectedData,
@@ -1293,19 +1917,19 @@ impl Serialize for AccessDeniedCode {
             ServerFail => u8::serialize(&9, ser),
             CustomString(msg) => {
                 u8::serialize(&10, ser)?;
-                String::serialize(&msg, ser)?;
+                String::serialize(msg, ser)?;
                 Ok(())
             }
             Shutdown(msg, reconnect) => {
                 u8::serialize(&11, ser)?;
-                String::serialize(&msg, ser)?;
-                bool::serialize(&reconnect, ser)?;
+                String::serialize(msg, ser)?;
+                bool::serialize(reconnect, ser)?;
                 Ok(())
             }
             Crash(msg, reconnect) => {
                 u8::serialize(&12, ser)?;
-                String::serialize(&msg, ser)?;
-                bool::serialize(&reconnect, ser)?;
+                String::serialize(msg, ser)?;
+                bool::serialize(reconnect, ser)?;
                 Ok(())
             }
         }
@@ -1343,7 +1967,46 @@ impl Deserialize for AccessDeniedCode {
 }
 
 impl AccessDeniedCode {
-    pub fn to_str<'a>(&'a self) -> &'a str {
+    /// The numeric code on the wire
+    pub fn code(&self) -> u8 {
+        use AccessDeniedCode::*;
+        match self {
+            WrongPassword => 0,
+            UnexpectedData => 1,
+            Singleplayer => 2,
+            WrongVersion => 3,
+            WrongCharsInName => 4,
+            WrongName => 5,
+            TooManyUsers => 6,
+            EmptyPassword => 7,
+            AlreadyConnected => 8,
+            ServerFail => 9,
+            CustomString(_) => 10,
+            Shutdown(..) => 11,
+            Crash(..) => 12,
+        }
+    }
+
+    /// The server's own message, for the codes that have one (it may be
+    /// blank)
+    pub fn custom_message(&self) -> Option<&str> {
+        use AccessDeniedCode::*;
+        match self {
+            CustomString(msg) | Shutdown(msg, _) | Crash(msg, _) => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// Whether the client is asked to reconnect (Shutdown and Crash only)
+    pub fn should_reconnect(&self) -> bool {
+        use AccessDeniedCode::*;
+        match self {
+            Shutdown(_, reconnect) | Crash(_, reconnect) => *reconnect,
+            _ => false,
+        }
+    }
+
+    pub fn to_str(&self) -> &str {
         use AccessDeniedCode::*;
         match self {
             WrongPassword => "Invalid password",
@@ -1363,7 +2026,8 @@ impl AccessDeniedCode {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HudStat {
     Pos(v2f),
     Name(String),
@@ -1381,169 +2045,144 @@ pub enum HudStat {
     Style(u32),
 }
 
-impl Serialize for HudStat {
-    type Input = Self;
-    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        use HudStat::*;
-        match value {
-            Pos(v) => {
-                u8::serialize(&0, ser)?;
-                v2f::serialize(v, ser)?;
-            }
-            Name(v) => {
-                u8::serialize(&1, ser)?;
-                String::serialize(v, ser)?;
-            }
-            Scale(v) => {
-                u8::serialize(&2, ser)?;
-                v2f::serialize(v, ser)?;
-            }
-            Text(v) => {
-                u8::serialize(&3, ser)?;
-                String::serialize(v, ser)?;
-            }
-            Number(v) => {
-                u8::serialize(&4, ser)?;
-                u32::serialize(v, ser)?;
-            }
-            Item(v) => {
-                u8::serialize(&5, ser)?;
-                u32::serialize(v, ser)?;
-            }
-            Dir(v) => {
-                u8::serialize(&6, ser)?;
-                u32::serialize(v, ser)?;
-            }
-            Align(v) => {
-                u8::serialize(&7, ser)?;
-                v2f::serialize(v, ser)?;
-            }
-            Offset(v) => {
-                u8::serialize(&8, ser)?;
-                v2f::serialize(v, ser)?;
-            }
-            WorldPos(v) => {
-                u8::serialize(&9, ser)?;
-                v3f::serialize(v, ser)?;
-            }
-            Size(v) => {
-                u8::serialize(&10, ser)?;
-                v2s32::serialize(v, ser)?;
-            }
-            ZIndex(v) => {
-                u8::serialize(&11, ser)?;
-                u32::serialize(v, ser)?;
-            }
-            Text2(v) => {
-                u8::serialize(&12, ser)?;
-                String::serialize(v, ser)?;
-            }
-            Style(v) => {
-                u8::serialize(&13, ser)?;
-                u32::serialize(v, ser)?;
-            }
-        }
-        Ok(())
-    }
-}
-
-impl Deserialize for HudStat {
-    type Output = Self;
-    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
-        use HudStat::*;
-        let stat = u8::deserialize(deser)?;
-        match stat {
-            0 => Ok(Pos(v2f::deserialize(deser)?)),
-            1 => Ok(Name(String::deserialize(deser)?)),
-            2 => Ok(Scale(v2f::deserialize(deser)?)),
-            3 => Ok(Text(String::deserialize(deser)?)),
-            4 => Ok(Number(u32::deserialize(deser)?)),
-            5 => Ok(Item(u32::deserialize(deser)?)),
-            6 => Ok(Dir(u32::deserialize(deser)?)),
-            7 => Ok(Align(v2f::deserialize(deser)?)),
-            8 => Ok(Offset(v2f::deserialize(deser)?)),
-            9 => Ok(WorldPos(v3f::deserialize(deser)?)),
-            10 => Ok(Size(v2s32::deserialize(deser)?)),
-            11 => Ok(ZIndex(u32::deserialize(deser)?)),
-            12 => Ok(Text2(String::deserialize(deser)?)),
-            13 => Ok(Style(u32::deserialize(deser)?)),
-            _ => bail!(DeserializeError::InvalidValue(String::from(
-                "HudStat invalid stat",
-            ))),
-        }
-    }
-}
-
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SkyboxParams {
     pub bgcolor: SColor,
     pub clouds: bool,
-    pub fog_sun_tint: SColor,
-    pub fog_moon_tint: SColor,
-    pub fog_tint_type: String,
-    pub data: SkyboxData,
+    pub fog_tint: FogTint,
+    pub typ: SkyType,
     pub body_orbit_tilt: Option<f32>,
 }
 
+/// The sky type, with the fields only sent for that type
 #[derive(Debug, Clone, PartialEq)]
-pub enum SkyboxData {
-    None,                  // If skybox_type == "plain"
-    Textures(Vec<String>), // If skybox_type == "skybox"
-    Color(SkyColor),       // If skybox_type == "regular"
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SkyType {
+    /// "regular": the default sky, in these colors
+    Regular(SkyColor),
+    /// "skybox": six textures
+    Skybox(Vec<String>),
+    /// "plain": bgcolor only
+    Plain,
+}
+
+impl SkyType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkyType::Regular(_) => "regular",
+            SkyType::Skybox(_) => "skybox",
+            SkyType::Plain => "plain",
+        }
+    }
+}
+
+/// The tint of fog towards the sun and moon at sunrise and sunset
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FogTint {
+    /// "default": the client's own tint
+    Default,
+    /// "custom"
+    Custom { sun_tint: SColor, moon_tint: SColor },
+}
+
+impl FogTint {
+    /// The colors sent along with Default. The client ignores them.
+    pub const DEFAULT_SUN_TINT: SColor = SColor {
+        a: 255,
+        r: 244,
+        g: 125,
+        b: 29,
+    };
+    pub const DEFAULT_MOON_TINT: SColor = SColor {
+        a: 255,
+        r: 128,
+        g: 153,
+        b: 204,
+    };
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FogTint::Default => "default",
+            FogTint::Custom { .. } => "custom",
+        }
+    }
+
+    /// (sun_tint, moon_tint), as sent
+    pub fn colors(&self) -> (SColor, SColor) {
+        match *self {
+            FogTint::Default => (Self::DEFAULT_SUN_TINT, Self::DEFAULT_MOON_TINT),
+            FogTint::Custom {
+                sun_tint,
+                moon_tint,
+            } => (sun_tint, moon_tint),
+        }
+    }
 }
 
 impl Serialize for SkyboxParams {
     type Input = Self;
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
         SColor::serialize(&value.bgcolor, ser)?;
-        let skybox_type = match &value.data {
-            SkyboxData::None => "plain",
-            SkyboxData::Textures(..) => "skybox",
-            SkyboxData::Color(..) => "regular",
-        };
-        str::serialize(skybox_type, ser)?;
+        str::serialize(value.typ.name(), ser)?;
         bool::serialize(&value.clouds, ser)?;
-        SColor::serialize(&value.fog_sun_tint, ser)?;
-        SColor::serialize(&value.fog_moon_tint, ser)?;
-        String::serialize(&value.fog_tint_type, ser)?;
-        match &value.data {
-            SkyboxData::None => (),
-            SkyboxData::Textures(v) => <Array16<String> as Serialize>::serialize(v, ser)?,
-            SkyboxData::Color(v) => SkyColor::serialize(v, ser)?,
-        }
-        <Option<f32> as Serialize>::serialize(&value.body_orbit_tilt, ser)?;
+        let (sun_tint, moon_tint) = value.fog_tint.colors();
+        SColor::serialize(&sun_tint, ser)?;
+        SColor::serialize(&moon_tint, ser)?;
+        str::serialize(value.fog_tint.name(), ser)?;
+        match &value.typ {
+            SkyType::Regular(v) => SkyColor::serialize(v, ser)?,
+            SkyType::Skybox(v) => <Array16<String> as Serialize>::serialize(v, ser)?,
+            SkyType::Plain => (),
+        }
+        if ser.context().protocol_version >= 41 {
+            <Option<f32> as Serialize>::serialize(&value.body_orbit_tilt, ser)?;
+        }
         Ok(())
     }
 }
 
 impl Deserialize for SkyboxParams {
     type Output = Self;
+    /// Tint colors sent with "default" are dropped (the client ignores
+    /// them), so they come back as the engine defaults when reserialized.
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
         let bgcolor = SColor::deserialize(deser)?;
         let typ = String::deserialize(deser)?;
+        let clouds = bool::deserialize(deser)?;
+        let sun_tint = SColor::deserialize(deser)?;
+        let moon_tint = SColor::deserialize(deser)?;
+        let fog_tint = match String::deserialize(deser)?.as_str() {
+            "custom" => FogTint::Custom {
+                sun_tint,
+                moon_tint,
+            },
+            "default" => FogTint::Default,
+            other => bail!("Invalid fog tint type: {:?}", other),
+        };
+        let typ = match typ.as_str() {
+            "regular" => SkyType::Regular(SkyColor::deserialize(deser)?),
+            "skybox" => SkyType::Skybox(<Array16<String> as Deserialize>::deserialize(deser)?),
+            "plain" => SkyType::Plain,
+            _ => bail!("Invalid skybox type: {:?}", typ),
+        };
         Ok(SkyboxParams {
-            bgcolor: bgcolor,
-            clouds: bool::deserialize(deser)?,
-            fog_sun_tint: SColor::deserialize(deser)?,
-            fog_moon_tint: SColor::deserialize(deser)?,
-            fog_tint_type: String::deserialize(deser)?,
-            data: {
-                if typ == "skybox" {
-                    SkyboxData::Textures(<Array16<String> as Deserialize>::deserialize(deser)?)
-                } else if typ == "regular" {
-                    SkyboxData::Color(SkyColor::deserialize(deser)?)
-                } else if typ == "plain" {
-                    SkyboxData::None
-                } else {
-                    bail!("Invalid skybox type: {:?}", typ)
-                }
+            bgcolor,
+            clouds,
+            fog_tint,
+            typ,
+            body_orbit_tilt: if deser.context().protocol_version >= 41 {
+                <Option<f32> as Deserialize>::deserialize(deser)?
+            } else {
+                None
             },
-            body_orbit_tilt: <Option<f32> as Deserialize>::deserialize(deser)?,
         })
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MinimapModeList {
     pub mode: u16,
     pub vec: Vec<MinimapMode>,
@@ -1572,14 +2211,12 @@ impl Deserialize for MinimapModeList {
         for _ in 0..count {
             vec.push(MinimapMode::deserialize(deser)?);
         }
-        Ok(MinimapModeList {
-            mode: mode,
-            vec: vec,
-        })
+        Ok(MinimapModeList { mode, vec })
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AuthMechsBitset {
     pub legacy_password: bool,
     pub srp: bool,
@@ -1616,6 +2253,7 @@ impl Deserialize for AuthMechsBitset {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ZLibCompressed<T>(PhantomData<T>);
 
 impl<T: Serialize> Serialize for ZLibCompressed<T> {
@@ -1625,9 +2263,9 @@ impl<T: Serialize> Serialize for ZLibCompressed<T> {
 
         // Serialize 'value' to a temporary buffer, and then compress
         let mut tmp = VecSerializer::new(ser.context(), 1024);
-        <T as Serialize>::serialize(&value, &mut tmp)?;
+        <T as Serialize>::serialize(value, &mut tmp)?;
         let tmp = tmp.take();
-        let tmp = miniz_oxide::deflate::compress_to_vec_zlib(&tmp, 6);
+        let tmp = compress_zlib(&tmp, ser.context().zlib_level);
 
         // Write the size as a u32, followed by the data
         u32::serialize(&u32::try_from(tmp.len())?, ser)?;
@@ -1642,9 +2280,9 @@ impl<T: Deserialize> Deserialize for ZLibCompressed<T> {
         let num_bytes = u32::deserialize(deser)? as usize;
         let data = deser.take(num_bytes)?;
         // TODO(paradust): DANGEROUS. There is no decompression size bound.
-        match miniz_oxide::inflate::decompress_to_vec_zlib(&data) {
+        match miniz_oxide::inflate::decompress_to_vec_zlib(data) {
             Ok(decompressed) => {
-                let mut tmp = Deserializer::new(deser.context(), &decompressed);
+                let mut tmp = deser.decoded(&decompressed);
                 Ok(<T as Deserialize>::deserialize(&mut tmp)?)
             }
             Err(err) => bail!(DeserializeError::DecompressionFailed(err.to_string())),
@@ -1653,6 +2291,7 @@ impl<T: Deserialize> Deserialize for ZLibCompressed<T> {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ZStdCompressed<T>(PhantomData<T>);
 
 impl<T: Serialize> Serialize for ZStdCompressed<T> {
@@ -1678,21 +2317,26 @@ impl<T: Deserialize> Deserialize for ZStdCompressed<T> {
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
         // Decompress to a temporary buffer
         let mut tmp: Vec<u8> = Vec::with_capacity(65536);
-        match zstd_decompress(deser.peek_all(), |chunk| {
+        match zstd_decompress_chunks(deser.remaining_chunks(), |chunk| {
             tmp.extend_from_slice(chunk);
             Ok(())
         }) {
             Ok(consumed) => {
-                deser.take(consumed)?;
-                let mut tmp_deser = Deserializer::new(deser.context(), &tmp);
+                deser.skip(consumed)?;
+                let mut tmp_deser = deser.decoded(&tmp);
                 Ok(<T as Deserialize>::deserialize(&mut tmp_deser)?)
             }
+            Err(err) if is_eof(&err) => {
+                deser.expect_more(1);
+                Err(err)
+            }
             Err(err) => bail!(DeserializeError::DecompressionFailed(err.to_string())),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ItemdefList {
     pub itemdef_manager_version: u8,
     #[wrap(Array16<Wrapped16<ItemDef>>)]
@@ -1702,6 +2346,7 @@ pub struct ItemdefList {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ItemType {
     None,
     Node,
@@ -1710,6 +2355,7 @@ pub enum ItemType {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ToolGroupCap {
     pub uses: s16,
     pub maxlevel: s16,
@@ -1719,20 +2365,22 @@ pub struct ToolGroupCap {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ToolCapabilities {
     pub version: u8,
     pub full_punch_interval: f32,
     pub max_drop_level: s16,
-    // (name, tool group cap)
-    #[wrap(Array32<Pair<String, ToolGroupCap>>)]
-    pub group_caps: Vec<(String, ToolGroupCap)>,
-    // (name, rating)
-    #[wrap(Array32<Pair<String, s16>>)]
-    pub damage_groups: Vec<(String, s16)>,
+    // name -> tool group cap
+    #[wrap(LenMap<u32, String, ToolGroupCap>)]
+    pub group_caps: OrderedMap<String, ToolGroupCap>,
+    // name -> rating
+    #[wrap(LenMap<u32, String, s16>)]
+    pub damage_groups: OrderedMap<String, s16>,
     pub punch_attack_uses: Option<u16>,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SimpleSoundSpec {
     pub name: String,
     pub gain: f32,
@@ -1741,6 +2389,7 @@ pub struct SimpleSoundSpec {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ItemDef {
     pub version: u8,
     pub item_type: ItemType,
@@ -1753,8 +2402,8 @@ pub struct ItemDef {
     pub usable: bool,
     pub liquids_pointable: bool,
     pub tool_capabilities: Option16<ToolCapabilities>,
-    #[wrap(Array16<Pair<String, s16>>)]
-    pub groups: Vec<(String, s16)>,
+    #[wrap(LenMap<u16, String, s16>)]
+    pub groups: OrderedMap<String, s16>,
     pub node_placement_prediction: String,
     pub sound_place: SimpleSoundSpec,
     pub sound_place_failed: SimpleSoundSpec,
@@ -1770,12 +2419,14 @@ pub struct ItemDef {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ItemAlias {
     pub name: String,
     pub convert_to: String,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TileDef {
     pub name: String,
     pub animation: TileAnimationParams,
@@ -1883,6 +2534,7 @@ impl Deserialize for TileDef {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TileAnimationParams {
     None,
     VerticalFrames {
@@ -1918,18 +2570,18 @@ impl Serialize for TileAnimationParams {
                 aspect_h,
                 length,
             } => {
-                u16::serialize(&aspect_w, ser)?;
-                u16::serialize(&aspect_h, ser)?;
-                f32::serialize(&length, ser)?;
+                u16::serialize(aspect_w, ser)?;
+                u16::serialize(aspect_h, ser)?;
+                f32::serialize(length, ser)?;
             }
             TileAnimationParams::Sheet2D {
                 frames_w,
                 frames_h,
                 frame_length,
             } => {
-                u8::serialize(&frames_w, ser)?;
-                u8::serialize(&frames_h, ser)?;
-                f32::serialize(&frame_length, ser)?;
+                u8::serialize(frames_w, ser)?;
+                u8::serialize(frames_h, ser)?;
+                f32::serialize(frame_length, ser)?;
             }
         };
         Ok(())
@@ -1961,6 +2613,7 @@ impl Deserialize for TileAnimationParams {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AlignStyle {
     Node,
     World,
@@ -1968,6 +2621,7 @@ pub enum AlignStyle {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DrawType {
     Normal,
     AirLike,
@@ -1990,11 +2644,12 @@ pub enum DrawType {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ContentFeatures {
     pub version: u8,
     pub name: String,
-    #[wrap(Array16<Pair<String, s16>>)]
-    pub groups: Vec<(String, s16)>,
+    #[wrap(LenMap<u16, String, s16>)]
+    pub groups: OrderedMap<String, s16>,
     pub param_type: u8,
     pub param_type_2: u8,
     pub drawtype: DrawType,
@@ -2053,84 +2708,42 @@ pub struct ContentFeatures {
     pub liquid_move_physics: Option<bool>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+// Unused version number, always 6
+#[proto(version = 6)]
 pub enum NodeBox {
     Regular,
     Fixed(NodeBoxFixed),
     Wallmounted(NodeBoxWallmounted),
     Leveled(NodeBoxLeveled),
-    Connected(NodeBoxConnected),
-}
-
-impl Serialize for NodeBox {
-    type Input = Self;
-    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        // Unused version number, always 6
-        u8::serialize(&6, ser)?;
-
-        let typ = match value {
-            NodeBox::Regular => 0,
-            NodeBox::Fixed(_) => 1,
-            NodeBox::Wallmounted(_) => 2,
-            NodeBox::Leveled(_) => 3,
-            NodeBox::Connected(_) => 4,
-        };
-        u8::serialize(&typ, ser)?;
-        match value {
-            NodeBox::Regular => Ok(()),
-            NodeBox::Fixed(v) => NodeBoxFixed::serialize(v, ser),
-            NodeBox::Wallmounted(v) => NodeBoxWallmounted::serialize(v, ser),
-            NodeBox::Leveled(v) => NodeBoxLeveled::serialize(v, ser),
-            NodeBox::Connected(v) => NodeBoxConnected::serialize(v, ser),
-        }
-    }
-}
-
-impl Deserialize for NodeBox {
-    type Output = Self;
-    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
-        let ver = u8::deserialize(deser)?;
-        if ver != 6 {
-            bail!(DeserializeError::InvalidValue(
-                "Invalid NodeBox ver".to_string(),
-            ));
-        }
-        let typ = u8::deserialize(deser)?;
-        match typ {
-            0 => Ok(NodeBox::Regular),
-            1 => Ok(NodeBox::Fixed(NodeBoxFixed::deserialize(deser)?)),
-            2 => Ok(NodeBox::Wallmounted(NodeBoxWallmounted::deserialize(
-                deser,
-            )?)),
-            3 => Ok(NodeBox::Leveled(NodeBoxLeveled::deserialize(deser)?)),
-            4 => Ok(NodeBox::Connected(NodeBoxConnected::deserialize(deser)?)),
-            _ => bail!(DeserializeError::InvalidValue(
-                "Invalid NodeBox type".to_string(),
-            )),
-        }
-    }
+    Connected(Box<NodeBoxConnected>),
 }
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct aabb3f {
     pub min_edge: v3f,
     pub max_edge: v3f,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeBoxLeveled {
     #[wrap(Array16<aabb3f>)]
     pub fixed: Vec<aabb3f>,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeBoxFixed {
     #[wrap(Array16<aabb3f>)]
     pub fixed: Vec<aabb3f>,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeBoxWallmounted {
     pub wall_top: aabb3f,
     pub wall_bottom: aabb3f,
@@ -2138,6 +2751,7 @@ pub struct NodeBoxWallmounted {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeBoxConnected {
     #[wrap(Array16<aabb3f>)]
     pub fixed: Vec<aabb3f>,
@@ -2172,6 +2786,7 @@ pub struct NodeBoxConnected {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AlphaMode {
     Blend,
     Clip,
@@ -2180,6 +2795,7 @@ pub enum AlphaMode {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeDefManager {
     pub content_features: Vec<(u16, ContentFeatures)>,
 }
@@ -2236,13 +2852,14 @@ impl Deserialize for NodeDefManager {
     }
 }
 
-// A "block" is 16x16x16 "nodes"
-const MAP_BLOCKSIZE: u16 = 16;
+/// A "block" is 16x16x16 "nodes"
+pub const MAP_BLOCKSIZE: u16 = 16;
 
-// Number of nodes in a block
-const NODECOUNT: u16 = MAP_BLOCKSIZE * MAP_BLOCKSIZE * MAP_BLOCKSIZE;
+/// Number of nodes in a block
+pub const NODECOUNT: u16 = MAP_BLOCKSIZE * MAP_BLOCKSIZE * MAP_BLOCKSIZE;
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MapBlock {
     pub is_underground: bool,
     pub day_night_diff: bool,
@@ -2252,6 +2869,24 @@ pub struct MapBlock {
     pub node_metadata: NodeMetadataList, // m_node_metadata.serialize(os, version, disk);
 }
 
+impl MapBlock {
+    /// Size of the block data before compression is applied
+    /// (for whichever parts the ser_fmt compresses).
+    pub fn uncompressed_len(&self, context: ProtocolContext) -> anyhow::Result<usize> {
+        let header = MapBlockHeader {
+            is_underground: self.is_underground,
+            day_night_diff: self.day_night_diff,
+            generated: self.generated,
+            lighting_complete: self.lighting_complete,
+        };
+        let mut ser = MockSerializer::new(context);
+        MapBlockHeader::serialize(&header, &mut ser)?;
+        MapNodesBulk::serialize(&self.nodes, &mut ser)?;
+        NodeMetadataList::serialize(&self.node_metadata, &mut ser)?;
+        Ok(ser.len())
+    }
+}
+
 impl Serialize for MapBlock {
     /// MapBlock is a bit of a nightmare, because the compression algorithm
     /// and where the compression is applied (to the whole struct, or to
@@ -2279,7 +2914,7 @@ impl Serialize for MapBlock {
             // Serialize and compress using zlib
             let mut inner = VecSerializer::new(ser.context(), 32768);
             MapNodesBulk::serialize(&value.nodes, &mut inner)?;
-            let compressed = compress_zlib(&inner.take());
+            let compressed = compress_zlib(&inner.take(), ser.context().zlib_level);
             ser.write_bytes(&compressed)?;
         }
         if ver >= 29 {
@@ -2288,7 +2923,7 @@ impl Serialize for MapBlock {
             // Serialize and compress using zlib
             let mut inner = VecSerializer::new(ser.context(), 32768);
             NodeMetadataList::serialize(&value.node_metadata, &mut inner)?;
-            let compressed = compress_zlib(&inner.take());
+            let compressed = compress_zlib(&inner.take(), ser.context().zlib_level);
             ser.write_bytes(&compressed)?;
         }
         if ver >= 29 {
@@ -2366,7 +3001,7 @@ impl Deserialize for MapBlockHeader {
             is_underground: (flags & 0x1) != 0,
             day_night_diff: (flags & 0x2) != 0,
             generated: (flags & 0x8) == 0,
-            lighting_complete: lighting_complete,
+            lighting_complete,
         })
     }
 }
@@ -2374,54 +3009,163 @@ impl Deserialize for MapBlockHeader {
 impl Deserialize for MapBlock {
     type Output = Self;
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
-        let ver = deser.context().ser_fmt;
-        if ver < 28 {
-            bail!("Unsupported ser fmt");
+        let mut nodes = MapNodesBulk::empty();
+        let (header, node_metadata) =
+            deserialize_map_block(deser, &mut Vec::new(), &mut nodes.nodes)?;
+        Ok(Self {
+            is_underground: header.is_underground,
+            day_night_diff: header.day_night_diff,
+            generated: header.generated,
+            lighting_complete: header.lighting_complete,
+            nodes,
+            node_metadata,
+        })
+    }
+}
+
+/// Parse a MapBlock, putting the nodes in `nodes`. For ser_fmt >= 29,
+/// `scratch` holds the decompressed block (its allocation is reused).
+fn deserialize_map_block(
+    deser: &mut Deserializer,
+    scratch: &mut Vec<u8>,
+    nodes: &mut [MapNode; NODECOUNT as usize],
+) -> DeserializeResult<(MapBlockHeader, NodeMetadataList)> {
+    let ver = deser.context().ser_fmt;
+    if ver < 28 {
+        bail!("Unsupported ser fmt");
+    }
+    if ver >= 29 {
+        scratch.clear();
+        // Decompress to a temporary buffer
+        let bytes_taken = zstd_decompress_chunks(deser.remaining_chunks(), |chunk| {
+            scratch.extend_from_slice(chunk);
+            Ok(())
+        })
+        .inspect_err(|err| {
+            if is_eof(err) {
+                deser.expect_more(1);
+            }
+        })?;
+        deser.skip(bytes_taken)?;
+        let deser = &mut deser.decoded(scratch);
+        let header = MapBlockHeader::deserialize(deser)?;
+        MapNodesBulk::deserialize_into(deser, nodes)?;
+        let node_metadata = NodeMetadataList::deserialize(deser)?;
+        Ok((header, node_metadata))
+    } else {
+        let header = MapBlockHeader::deserialize(deser)?;
+        let (consumed, nodes_raw) = decompress_zlib(deser.peek_all())?;
+        deser.take(consumed)?;
+        {
+            let mut tmp = deser.decoded(&nodes_raw);
+            MapNodesBulk::deserialize_into(&mut tmp, nodes)?;
         }
-        // TODO(paradust): I can't make the borrow checker happy with sharing
-        // code here, so for now the code has two different paths.
-        if ver >= 29 {
-            let mut tmp: Vec<u8> = Vec::new();
-            // Decompress to a temporary buffer
-            let bytes_taken = zstd_decompress(deser.peek_all(), |chunk| {
-                tmp.extend_from_slice(chunk);
-                Ok(())
-            })?;
-            deser.take(bytes_taken)?;
-            let deser = &mut Deserializer::new(deser.context(), &tmp);
-            let header = MapBlockHeader::deserialize(deser)?;
-            let nodes = MapNodesBulk::deserialize(deser)?;
-            let node_metadata = NodeMetadataList::deserialize(deser)?;
-            Ok(Self {
-                is_underground: header.is_underground,
-                day_night_diff: header.day_night_diff,
-                generated: header.generated,
-                lighting_complete: header.lighting_complete,
-                nodes,
-                node_metadata,
-            })
-        } else {
-            let header = MapBlockHeader::deserialize(deser)?;
-            let (consumed, nodes_raw) = decompress_zlib(deser.peek_all())?;
-            deser.take(consumed)?;
-            let nodes = {
-                let mut tmp = Deserializer::new(deser.context(), &nodes_raw);
-                MapNodesBulk::deserialize(&mut tmp)?
-            };
-            let (consumed, metadata_raw) = decompress_zlib(deser.peek_all())?;
-            deser.take(consumed)?;
-            let node_metadata = {
-                let mut tmp = Deserializer::new(deser.context(), &metadata_raw);
-                NodeMetadataList::deserialize(&mut tmp)?
-            };
-            Ok(Self {
-                is_underground: header.is_underground,
-                day_night_diff: header.day_night_diff,
-                generated: header.generated,
-                lighting_complete: header.lighting_complete,
-                nodes,
-                node_metadata,
-            })
+        let (consumed, metadata_raw) = decompress_zlib(deser.peek_all())?;
+        deser.take(consumed)?;
+        let node_metadata = {
+            let mut tmp = deser.decoded(&metadata_raw);
+            NodeMetadataList::deserialize(&mut tmp)?
+        };
+        Ok((header, node_metadata))
+    }
+}
+
+/// A reusable MapBlock, for parsing many blocks (scanning a world, or
+/// proxying map traffic) without allocating for each one.
+///
+/// The nodes live on the heap, and the decompression buffer is kept
+/// between blocks. Node metadata is still allocated per block, but most
+/// blocks have none.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MapBlockBuf {
+    pub is_underground: bool,
+    pub day_night_diff: bool,
+    pub generated: bool,
+    pub lighting_complete: Option<u16>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_nodes"))]
+    pub nodes: Box<[MapNode; NODECOUNT as usize]>,
+    pub node_metadata: NodeMetadataList,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    scratch: Vec<u8>,
+}
+
+impl Default for MapBlockBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MapBlockBuf {
+    pub fn new() -> Self {
+        // Built on the heap, a [MapNode; 4096] is 16 KB
+        let nodes: Box<[MapNode]> = vec![MapNode::default(); NODECOUNT as usize].into();
+        Self {
+            is_underground: false,
+            day_night_diff: false,
+            generated: true,
+            lighting_complete: None,
+            nodes: match nodes.try_into() {
+                Ok(nodes) => nodes,
+                Err(_) => unreachable!(),
+            },
+            node_metadata: NodeMetadataList {
+                metadata: Vec::new(),
+            },
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Parse a MapBlock (as in MapBlock::deserialize) into this buffer,
+    /// replacing its contents. On error, the contents are unspecified.
+    pub fn deserialize_from(&mut self, deser: &mut Deserializer) -> DeserializeResult<()> {
+        let (header, node_metadata) =
+            deserialize_map_block(deser, &mut self.scratch, &mut self.nodes)?;
+        self.is_underground = header.is_underground;
+        self.day_night_diff = header.day_night_diff;
+        self.generated = header.generated;
+        self.lighting_complete = header.lighting_complete;
+        self.node_metadata = node_metadata;
+        Ok(())
+    }
+
+    pub fn to_map_block(&self) -> MapBlock {
+        MapBlock {
+            is_underground: self.is_underground,
+            day_night_diff: self.day_night_diff,
+            generated: self.generated,
+            lighting_complete: self.lighting_complete,
+            nodes: MapNodesBulk { nodes: *self.nodes },
+            node_metadata: self.node_metadata.clone(),
+        }
+    }
+}
+
+/// Hands out MapBlockBufs, and takes them back for reuse, e.g. to share
+/// buffers between the tasks of a world scan.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MapBlockBufPool {
+    free: Vec<MapBlockBuf>,
+    /// Buffers kept for reuse at most. Extra ones returned are dropped.
+    pub max_free: usize,
+}
+
+impl MapBlockBufPool {
+    pub fn new(max_free: usize) -> Self {
+        Self {
+            free: Vec::new(),
+            max_free,
+        }
+    }
+
+    pub fn get(&mut self) -> MapBlockBuf {
+        self.free.pop().unwrap_or_default()
+    }
+
+    pub fn put(&mut self, buf: MapBlockBuf) {
+        if self.free.len() < self.max_free {
+            self.free.push(buf);
         }
     }
 }
@@ -2429,17 +3173,49 @@ impl Deserialize for MapBlock {
 /// This has a special serialization, presumably to make it compress better.
 /// Each param is stored in a separate array.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MapNodesBulk {
+    #[cfg_attr(feature = "serde", serde(with = "serde_nodes"))]
     pub nodes: [MapNode; NODECOUNT as usize],
 }
 
+/// A block's nodes, as a sequence. serde only does arrays of up to 32.
+#[cfg(feature = "serde")]
+mod serde_nodes {
+    use super::MapNode;
+    use super::NODECOUNT;
+    use alloc::vec::Vec;
+    use core::borrow::Borrow;
+    use serde::de::Error;
+
+    type Nodes = [MapNode; NODECOUNT as usize];
+
+    pub fn serialize<A: Borrow<Nodes>, S: serde::Serializer>(
+        nodes: &A,
+        ser: S,
+    ) -> Result<S::Ok, S::Error> {
+        ser.collect_seq(nodes.borrow())
+    }
+
+    pub fn deserialize<'de, A: From<Nodes>, D: serde::Deserializer<'de>>(
+        de: D,
+    ) -> Result<A, D::Error> {
+        let nodes: Vec<MapNode> = serde::Deserialize::deserialize(de)?;
+        let len = nodes.len();
+        let nodes: Nodes = nodes
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"a node for each position of the block"))?;
+        Ok(A::from(nodes))
+    }
+}
+
 impl Serialize for MapNodesBulk {
     type Input = Self;
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
         let nodecount = NODECOUNT as usize;
         // Write all param0 first
-        ser.write(2 * nodecount as usize, |buf| {
-            assert!(buf.len() == 2 * nodecount as usize);
+        ser.write(2 * nodecount, |buf| {
+            assert!(buf.len() == 2 * nodecount);
             for i in 0..nodecount {
                 let v = value.nodes[i].param0.to_be_bytes();
                 buf[2 * i] = v[0];
@@ -2449,48 +3225,62 @@ impl Serialize for MapNodesBulk {
         // Write all param1
         ser.write(nodecount, |buf| {
             assert!(buf.len() == nodecount);
-            for i in 0..nodecount {
-                buf[i] = value.nodes[i].param1;
+            for (byte, node) in buf.iter_mut().zip(value.nodes.iter()) {
+                *byte = node.param1;
             }
         })?;
         // Write all param2
         ser.write(nodecount, |buf| {
             assert!(buf.len() == nodecount);
-            for i in 0..nodecount {
-                buf[i] = value.nodes[i].param2;
+            for (byte, node) in buf.iter_mut().zip(value.nodes.iter()) {
+                *byte = node.param2;
             }
         })?;
         Ok(())
     }
 }
 
-impl Deserialize for MapNodesBulk {
-    type Output = Self;
-    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
+impl MapNodesBulk {
+    /// All nodes zero
+    pub fn empty() -> Self {
+        Self {
+            nodes: [MapNode::default(); NODECOUNT as usize],
+        }
+    }
+
+    /// Deserialize into a caller-provided array, without allocating
+    pub fn deserialize_into(
+        deser: &mut Deserializer,
+        nodes: &mut [MapNode; NODECOUNT as usize],
+    ) -> DeserializeResult<()> {
         let nodecount = NODECOUNT as usize;
         let data = deser.take(4 * nodecount)?;
-        let mut nodes: Vec<MapNode> = Vec::with_capacity(nodecount);
-        let param1_offset = 2 * nodecount;
-        let param2_offset = 3 * nodecount;
-        for i in 0..nodecount {
-            nodes.push(MapNode {
-                param0: u16::from_be_bytes(data[2 * i..2 * i + 2].try_into().unwrap()),
-                param1: data[param1_offset + i],
-                param2: data[param2_offset + i],
-            })
+        let (param0, rest) = data.split_at(2 * nodecount);
+        let (param1, param2) = rest.split_at(nodecount);
+        for (i, node) in nodes.iter_mut().enumerate() {
+            *node = MapNode {
+                param0: u16::from_be_bytes([param0[2 * i], param0[2 * i + 1]]),
+                param1: param1[i],
+                param2: param2[i],
+            };
         }
-        Ok(Self {
-            nodes: match nodes.try_into() {
-                Ok(value) => value,
-                Err(_) => bail!("Bug in MapNodesBulk"),
-            },
-        })
+        Ok(())
+    }
+}
+
+impl Deserialize for MapNodesBulk {
+    type Output = Self;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
+        let mut value = Self::empty();
+        Self::deserialize_into(deser, &mut value.nodes)?;
+        Ok(value)
     }
 }
 
 /// The default serialization is used for single nodes.
 /// But for transferring entire blocks, MapNodeBulk is used instead.
-#[derive(Debug, Clone, Copy, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MapNode {
     pub param0: u16,
     pub param1: u8,
@@ -2498,6 +3288,7 @@ pub struct MapNode {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeMetadataList {
     pub metadata: Vec<(BlockPos, NodeMetadata)>,
 }
@@ -2505,7 +3296,7 @@ pub struct NodeMetadataList {
 impl Serialize for NodeMetadataList {
     type Input = Self;
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        if value.metadata.len() == 0 {
+        if value.metadata.is_empty() {
             u8::serialize(&0, ser)?; // version 0 indicates no data
             return Ok(());
         }
@@ -2520,9 +3311,9 @@ impl Deserialize for NodeMetadataList {
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
         let ver = u8::deserialize(deser)?;
         if ver == 0 {
-            return Ok(Self {
+            Ok(Self {
                 metadata: Vec::new(),
-            });
+            })
         } else if ver == 2 {
             Ok(Self {
                 metadata: <Array16<Pair<BlockPos, NodeMetadata>> as Deserialize>::deserialize(
@@ -2538,6 +3329,7 @@ impl Deserialize for NodeMetadataList {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AbsNodeMetadataList {
     pub metadata: Vec<(AbsBlockPos, NodeMetadata)>,
 }
@@ -2545,7 +3337,7 @@ pub struct AbsNodeMetadataList {
 impl Serialize for AbsNodeMetadataList {
     type Input = Self;
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        if value.metadata.len() == 0 {
+        if value.metadata.is_empty() {
             u8::serialize(&0, ser)?; // version 0 indicates no data
             return Ok(());
         }
@@ -2560,9 +3352,9 @@ impl Deserialize for AbsNodeMetadataList {
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
         let ver = u8::deserialize(deser)?;
         if ver == 0 {
-            return Ok(Self {
+            Ok(Self {
                 metadata: Vec::new(),
-            });
+            })
         } else if ver == 2 {
             Ok(Self {
                 metadata: <Array16<Pair<AbsBlockPos, NodeMetadata>> as Deserialize>::deserialize(
@@ -2577,14 +3369,33 @@ impl Deserialize for AbsNodeMetadataList {
     }
 }
 
+/// Despite the name, the absolute position of a node
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AbsBlockPos {
     pos: v3s16,
 }
 
+impl AbsBlockPos {
+    pub fn new(pos: v3s16) -> Self {
+        Self { pos }
+    }
+
+    pub fn pos(&self) -> &v3s16 {
+        &self.pos
+    }
+
+    /// The block the node is in
+    pub fn block(&self) -> v3s16 {
+        let block = |v: s16| v.div_euclid(MAP_BLOCKSIZE as s16);
+        v3s16::new(block(self.pos.x), block(self.pos.y), block(self.pos.z))
+    }
+}
+
 /// BlockPos addresses a node within a block
 /// It is equivalent to (16*z + y)*16 + x, where x,y,z are from 0 to 15.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BlockPos {
     pub raw: u16,
 }
@@ -2635,6 +3446,7 @@ impl Deserialize for BlockPos {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeMetadata {
     #[wrap(Array32<StringVar>)]
     pub stringvars: Vec<StringVar>,
@@ -2642,6 +3454,7 @@ pub struct NodeMetadata {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StringVar {
     pub name: String,
     #[wrap(BinaryData32)]
@@ -2650,11 +3463,13 @@ pub struct StringVar {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Inventory {
     pub entries: Vec<InventoryEntry>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InventoryEntry {
     // Inventory lists to keep
     KeepList(String),
@@ -2696,14 +3511,19 @@ impl Deserialize for Inventory {
         };
         while deser.remaining() > 0 {
             // Peek the line, but don't take it yet.
+            let policy = deser.context().text_format;
             let line = deser.peek_line()?;
             let words = split_by_whitespace(line);
-            if words.len() == 0 {
+            if words.is_empty() {
+                policy.reject("blank line in Inventory")?;
                 deser.take_line()?;
                 continue;
             }
             let name = words[0];
             if name == b"EndInventory" || name == b"End" {
+                if name == b"End" {
+                    policy.reject("legacy End marker")?;
+                }
                 // Take the line
                 deser.take_line()?;
                 return Ok(result);
@@ -2718,7 +3538,7 @@ impl Deserialize for Inventory {
                         "KeepList missing name".to_string(),
                     ));
                 }
-                match std::str::from_utf8(&words[1]) {
+                match core::str::from_utf8(words[1]) {
                     Ok(s) => result.entries.push(InventoryEntry::KeepList(s.to_string())),
                     Err(_) => {
                         bail!(DeserializeError::InvalidValue(
@@ -2730,6 +3550,7 @@ impl Deserialize for Inventory {
                 deser.take_line()?;
             } else {
                 // Anything else is supposed to be ignored. Gross.
+                policy.reject("unknown line in Inventory")?;
                 deser.take_line()?;
             }
         }
@@ -2739,6 +3560,7 @@ impl Deserialize for Inventory {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InventoryList {
     pub name: String,
     pub width: u32,
@@ -2746,6 +3568,7 @@ pub struct InventoryList {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ItemStackUpdate {
     Empty,
     Keep, // this seems to not be used yet
@@ -2793,8 +3616,9 @@ impl Deserialize for InventoryList {
                 "Broken List tag".to_string(),
             ));
         }
-        let list_name = std::str::from_utf8(words[1])?;
-        let _count: u32 = stoi(words[2])?;
+        let policy = deser.context().text_format;
+        let list_name = core::str::from_utf8(words[1])?;
+        let count: u32 = stoi(words[2])?;
         let mut result = Self {
             name: list_name.to_string(),
             width: 0,
@@ -2804,12 +3628,19 @@ impl Deserialize for InventoryList {
             // Peek the line, but don't take it yet.
             let line = deser.peek_line()?;
             let words = split_by_whitespace(line);
-            if words.len() == 0 {
+            if words.is_empty() {
+                policy.reject("blank line in InventoryList")?;
                 deser.take_line()?;
                 continue;
             }
             let name = words[0];
             if name == b"EndInventoryList" || name == b"end" {
+                if name == b"end" {
+                    policy.reject("legacy end marker")?;
+                }
+                if result.items.len() != count as usize {
+                    policy.reject("InventoryList size mismatch")?;
+                }
                 deser.take_line()?;
                 return Ok(result);
             } else if name == b"Width" {
@@ -2833,6 +3664,7 @@ impl Deserialize for InventoryList {
                 deser.take_line()?;
             } else {
                 // Ignore unrecognized lines
+                policy.reject("unknown line in InventoryList")?;
                 deser.take_line()?;
             }
         }
@@ -2842,6 +3674,7 @@ impl Deserialize for InventoryList {
 
 // Custom deserialization, part of Inventory
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ItemStack {
     pub name: String,
     pub count: u16,
@@ -2854,9 +3687,7 @@ impl Serialize for ItemStack {
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
         // Item <name_json> [count] [wear] [metadata]
         ser.write_bytes(b"Item ")?;
-        serialize_json_string_if_needed(&value.name.as_bytes(), |chunk| {
-            Ok(ser.write_bytes(chunk)?)
-        })?;
+        serialize_json_string_if_needed(value.name.as_bytes(), |chunk| ser.write_bytes(chunk))?;
 
         let mut parts = 1;
         if !value.metadata.string_vars.is_empty() {
@@ -2901,7 +3732,7 @@ impl Deserialize for ItemStack {
         let line = skip_whitespace(&line[skip..]);
 
         let mut result = Self {
-            name: std::str::from_utf8(&name)?.to_string(),
+            name: core::str::from_utf8(&name)?.to_string(),
             count: 1,
             wear: 0,
             metadata: ItemStackMetadata {
@@ -2913,8 +3744,8 @@ impl Deserialize for ItemStack {
             if let Some((word, line)) = next_word(line) {
                 result.wear = stoi(word)?;
                 let line = skip_whitespace(line);
-                if line.len() > 0 {
-                    let mut tmp_deser = Deserializer::new(deser.context(), line);
+                if !line.is_empty() {
+                    let mut tmp_deser = deser.decoded(line);
                     result.metadata = ItemStackMetadata::deserialize(&mut tmp_deser)?;
                 }
             }
@@ -2925,6 +3756,7 @@ impl Deserialize for ItemStack {
 
 // Custom deserialization as json blob
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ItemStackMetadata {
     pub string_vars: Vec<(ByteString, ByteString)>,
 }
@@ -2960,7 +3792,7 @@ impl Deserialize for ItemStackMetadata {
             string_vars: Vec::new(),
         };
         let raw = &raw[..]; // easier to work with slice
-        if raw.len() == 0 {
+        if raw.is_empty() {
             return Ok(result);
         }
         if raw[0] != DESERIALIZE_START[0] {
@@ -2970,14 +3802,14 @@ impl Deserialize for ItemStackMetadata {
         }
         let mut raw = &raw[1..];
         // This is odd, but matches the behavior of ItemStackMetadata::deSerialize
-        while raw.len() != 0 {
+        while !raw.is_empty() {
             let kv_delim_pos = raw
                 .iter()
                 .position(|ch| *ch == DESERIALIZE_KV_DELIM[0])
                 .unwrap_or(raw.len());
             let name = &raw[..kv_delim_pos];
             raw = &raw[kv_delim_pos..];
-            if raw.len() > 0 {
+            if !raw.is_empty() {
                 raw = &raw[1..];
             }
             let pair_delim_pos = raw
@@ -2986,7 +3818,7 @@ impl Deserialize for ItemStackMetadata {
                 .unwrap_or(raw.len());
             let var = &raw[..pair_delim_pos];
             raw = &raw[pair_delim_pos..];
-            if raw.len() > 0 {
+            if !raw.is_empty() {
                 raw = &raw[1..];
             }
             result.string_vars.push((name.into(), var.into()));
@@ -2998,6 +3830,7 @@ impl Deserialize for ItemStackMetadata {
 /// This is the way ADD_PARTICLESPAWNER is serialized.
 /// It seems to be an older version of ParticleParameters
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AddParticleSpawnerLegacy {
     pub amount: u16,
     pub time: f32,
@@ -3028,6 +3861,7 @@ pub struct AddParticleSpawnerLegacy {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AddParticleSpawnerExtra {
     pub pos_start_bias: f32,
     pub vel_start_bias: f32,
@@ -3052,7 +3886,8 @@ pub struct AddParticleSpawnerExtra {
     pub texpool: Vec<ServerParticleTexture>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Attractor {
     None,
     Point(PointAttractor),
@@ -3060,41 +3895,8 @@ pub enum Attractor {
     Plane(PlaneAttractor),
 }
 
-impl Serialize for Attractor {
-    type Input = Self;
-    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        let kind: u8 = match value {
-            Attractor::None => 0,
-            Attractor::Point(_) => 1,
-            Attractor::Line(_) => 2,
-            Attractor::Plane(_) => 3,
-        };
-        u8::serialize(&kind, ser)?;
-        match value {
-            Attractor::None => (),
-            Attractor::Point(v) => PointAttractor::serialize(v, ser)?,
-            Attractor::Line(v) => LineAttractor::serialize(v, ser)?,
-            Attractor::Plane(v) => PlaneAttractor::serialize(v, ser)?,
-        }
-        Ok(())
-    }
-}
-
-impl Deserialize for Attractor {
-    type Output = Self;
-    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
-        let kind = u8::deserialize(deser)?;
-        Ok(match kind {
-            0 => Attractor::None,
-            1 => Attractor::Point(PointAttractor::deserialize(deser)?),
-            2 => Attractor::Line(LineAttractor::deserialize(deser)?),
-            3 => Attractor::Plane(PlaneAttractor::deserialize(deser)?),
-            _ => bail!("Invalid AttractorKind: {}", kind),
-        })
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PointAttractor {
     pub attract: TweenedParameter<RangedParameter<f32>>,
     pub origin: TweenedParameter<v3f>,
@@ -3103,6 +3905,7 @@ pub struct PointAttractor {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LineAttractor {
     pub attract: TweenedParameter<RangedParameter<f32>>,
     pub origin: TweenedParameter<v3f>,
@@ -3113,6 +3916,7 @@ pub struct LineAttractor {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PlaneAttractor {
     pub attract: TweenedParameter<RangedParameter<f32>>,
     pub origin: TweenedParameter<v3f>,
@@ -3126,6 +3930,7 @@ pub struct PlaneAttractor {
 /// ServerParticleTexture, so it doesn't implement the  methods
 /// on its own.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BlendMode {
     Alpha,
     Add,
@@ -3155,6 +3960,7 @@ impl BlendMode {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ServerParticleTextureNewPropsOnly {
     pub blend_mode: BlendMode,
     pub alpha: TweenedParameter<f32>,
@@ -3197,6 +4003,7 @@ impl Deserialize for ServerParticleTextureNewPropsOnly {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ServerParticleTexture {
     pub blend_mode: BlendMode,
     pub alpha: TweenedParameter<f32>,
@@ -3242,6 +4049,7 @@ impl Deserialize for ServerParticleTexture {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TweenStyle {
     Fwd,
     Rev,
@@ -3250,6 +4058,7 @@ pub enum TweenStyle {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TweenedParameter<T: Serialize + Deserialize>
 where
     T: Serialize<Input = T>,
@@ -3265,6 +4074,7 @@ where
 /// This is the send format used by SendSpawnParticle
 /// See ParticleParameters::serialize
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParticleParameters {
     pub pos: v3f,
     pub vel: v3f,
@@ -3289,6 +4099,7 @@ pub struct ParticleParameters {
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RangedParameter<T: Serialize + Deserialize>
 where
     T: Serialize<Input = T>,
@@ -3300,6 +4111,7 @@ where
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RangedParameterLegacy<T: Serialize + Deserialize>
 where
     T: Serialize<Input = T>,
@@ -3310,13 +4122,18 @@ where
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Lighting {
     pub shadow_intensity: f32,
+    /// Sent since protocol 42. Older peers get the defaults.
+    #[proto(since = 42, default = "1.0")]
     pub saturation: f32,
+    #[proto(since = 42)]
     pub exposure: AutoExposure,
 }
 
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AutoExposure {
     pub luminance_min: f32,
     pub luminance_max: f32,
@@ -3326,57 +4143,61 @@ pub struct AutoExposure {
     pub center_weight_power: f32,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Default for AutoExposure {
+    /// The engine's defaults
+    fn default() -> Self {
+        Self {
+            luminance_min: -3.0,
+            luminance_max: -3.0,
+            exposure_correction: 0.0,
+            speed_dark_bright: 1000.0,
+            speed_bright_dark: 1000.0,
+            center_weight_power: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[tag(u16)]
 pub enum HudSetParam {
-    SetHotBarItemCount(s32),
+    /// The count is wrapped in a String16
+    #[proto(tag = 1)]
+    SetHotBarItemCount(#[wrap(HotBarItemCount)] s32),
+    #[proto(tag = 2)]
     SetHotBarImage(String),
+    #[proto(tag = 3)]
     SetHotBarSelectedImage(String),
 }
 
-impl Serialize for HudSetParam {
-    type Input = Self;
+// An s32 in a String16, which must hold exactly its 4 bytes
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HotBarItemCount;
+
+impl Serialize for HotBarItemCount {
+    type Input = s32;
     fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        use HudSetParam::*;
-        let param: u16 = match value {
-            SetHotBarItemCount(_) => 1,
-            SetHotBarImage(_) => 2,
-            SetHotBarSelectedImage(_) => 3,
-        };
-        u16::serialize(&param, ser)?;
-        match value {
-            SetHotBarItemCount(v) => {
-                // The value is wrapped in a a String16
-                u16::serialize(&4, ser)?;
-                s32::serialize(v, ser)?;
-            }
-            SetHotBarImage(v) => String::serialize(v, ser)?,
-            SetHotBarSelectedImage(v) => String::serialize(v, ser)?,
-        };
-        Ok(())
+        Wrapped16::<s32>::serialize(value, ser)
     }
 }
 
-impl Deserialize for HudSetParam {
-    type Output = Self;
-    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
-        use HudSetParam::*;
-        let param = u16::deserialize(deser)?;
-        Ok(match param {
-            1 => {
-                let size = u16::deserialize(deser)?;
-                if size != 4 {
-                    bail!("Invalid size in SetHotBarItemCount: {}", size);
-                }
-                SetHotBarItemCount(s32::deserialize(deser)?)
-            }
-            2 => SetHotBarImage(String::deserialize(deser)?),
-            3 => SetHotBarSelectedImage(String::deserialize(deser)?),
-            _ => bail!("Invalid HudSetParam param: {}", param),
-        })
+impl Deserialize for HotBarItemCount {
+    type Output = s32;
+    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self::Output> {
+        let size = u16::deserialize(deser)?;
+        if size != 4 {
+            bail!(DeserializeError::InvalidValue(format!(
+                "Invalid size in SetHotBarItemCount: {}",
+                size
+            )));
+        }
+        s32::deserialize(deser)
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HudFlags {
     pub hotbar_visible: bool,
     pub healthbar_visible: bool,
@@ -3392,7 +4213,7 @@ pub struct HudFlags {
 impl HudFlags {
     pub fn to_u32(&self) -> u32 {
         let mut flags: u32 = 0;
-        flags |= (self.hotbar_visible as u32) << 0;
+        flags |= self.hotbar_visible as u32;
         flags |= (self.healthbar_visible as u32) << 1;
         flags |= (self.crosshair_visible as u32) << 2;
         flags |= (self.wielditem_visible as u32) << 3;
@@ -3438,7 +4259,16 @@ impl Deserialize for HudFlags {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlayerListModifier {
+    Init,
+    Add,
+    Remove,
+}
+
 #[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InteractAction {
     StartDigging,
     StopDigging,
@@ -3448,7 +4278,9 @@ pub enum InteractAction {
     Activate,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, MinetestSerialize, MinetestDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[proto(version = 0)]
 pub enum PointedThing {
     Nothing,
     Node {
@@ -3460,59 +4292,8 @@ pub enum PointedThing {
     },
 }
 
-impl Serialize for PointedThing {
-    type Input = Self;
-    fn serialize<S: Serializer>(value: &Self::Input, ser: &mut S) -> SerializeResult {
-        // version, always 0
-        u8::serialize(&0, ser)?;
-
-        let typ: u8 = match value {
-            PointedThing::Nothing => 0,
-            PointedThing::Node { .. } => 1,
-            PointedThing::Object { .. } => 2,
-        };
-        u8::serialize(&typ, ser)?;
-
-        match value {
-            PointedThing::Nothing => (),
-            PointedThing::Node {
-                under_surface,
-                above_surface,
-            } => {
-                v3s16::serialize(under_surface, ser)?;
-                v3s16::serialize(above_surface, ser)?;
-            }
-            PointedThing::Object { object_id } => {
-                u16::serialize(object_id, ser)?;
-            }
-        }
-        Ok(())
-    }
-}
-
-impl Deserialize for PointedThing {
-    type Output = Self;
-    fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
-        let ver = u8::deserialize(deser)?;
-        if ver != 0 {
-            bail!("Invalid PointedThing version: {}", ver);
-        }
-        let typ = u8::deserialize(deser)?;
-        Ok(match typ {
-            0 => PointedThing::Nothing,
-            1 => PointedThing::Node {
-                under_surface: v3s16::deserialize(deser)?,
-                above_surface: v3s16::deserialize(deser)?,
-            },
-            2 => PointedThing::Object {
-                object_id: u16::deserialize(deser)?,
-            },
-            _ => bail!("Invalid PointedThing type: {}", typ),
-        })
-    }
-}
-
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InventoryAction {
     Move {
         count: u16,
@@ -3605,10 +4386,10 @@ impl Deserialize for InventoryAction {
             Ok(InventoryAction::Move {
                 count: stoi(deser.take_word(true))?,
                 from_inv: InventoryLocation::deserialize(deser)?,
-                from_list: std::str::from_utf8(deser.take_word(true))?.to_owned(),
+                from_list: core::str::from_utf8(deser.take_word(true))?.to_owned(),
                 from_i: stoi(deser.take_word(true))?,
                 to_inv: InventoryLocation::deserialize(deser)?,
-                to_list: std::str::from_utf8(deser.take_word(true))?.to_owned(),
+                to_list: core::str::from_utf8(deser.take_word(true))?.to_owned(),
                 to_i: if word == b"Move" {
                     Some(stoi(deser.take_word(true))?)
                 } else {
@@ -3619,14 +4400,24 @@ impl Deserialize for InventoryAction {
             Ok(InventoryAction::Drop {
                 count: stoi(deser.take_word(true))?,
                 from_inv: InventoryLocation::deserialize(deser)?,
-                from_list: std::str::from_utf8(deser.take_word(true))?.to_owned(),
+                from_list: core::str::from_utf8(deser.take_word(true))?.to_owned(),
                 from_i: stoi(deser.take_word(true))?,
             })
         } else if word == b"Craft" {
-            Ok(InventoryAction::Craft {
+            let action = InventoryAction::Craft {
                 count: stoi(deser.take_word(true))?,
                 craft_inv: InventoryLocation::deserialize(deser)?,
-            })
+            };
+            // Take the extra space Minetest writes, so it isn't left over
+            if deser.remaining() > 0 && deser.peek(1)? == b" " {
+                deser.take(1)?;
+            } else {
+                deser
+                    .context()
+                    .text_format
+                    .reject("Craft without trailing space")?;
+            }
+            Ok(action)
         } else {
             bail!("Invalid InventoryAction kind");
         }
@@ -3634,6 +4425,7 @@ impl Deserialize for InventoryAction {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InventoryLocation {
     Undefined,
     CurrentPlayer,
@@ -3669,13 +4461,13 @@ impl Deserialize for InventoryLocation {
     fn deserialize(deser: &mut Deserializer) -> DeserializeResult<Self> {
         let word = deser.take_word(true);
         if word == b"undefined" {
-            return Ok(InventoryLocation::Undefined);
+            Ok(InventoryLocation::Undefined)
         } else if word == b"current_player" {
-            return Ok(InventoryLocation::CurrentPlayer);
+            Ok(InventoryLocation::CurrentPlayer)
         } else if word.starts_with(b"player:") {
-            return Ok(InventoryLocation::Player {
-                name: std::str::from_utf8(&word[7..])?.to_string(),
-            });
+            Ok(InventoryLocation::Player {
+                name: core::str::from_utf8(&word[7..])?.to_string(),
+            })
         } else if word.starts_with(b"nodemeta:") {
             let coords: Vec<&[u8]> = word[9..].split(|&ch| ch == b',').collect();
             if coords.len() != 3 {
@@ -3686,13 +4478,428 @@ impl Deserialize for InventoryLocation {
                 xyz[i] = stoi(n)?;
             }
             let pos = v3s16::new(xyz[0], xyz[1], xyz[2]);
-            return Ok(InventoryLocation::NodeMeta { pos });
+            Ok(InventoryLocation::NodeMeta { pos })
         } else if word.starts_with(b"detached:") {
-            return Ok(InventoryLocation::Detached {
-                name: std::str::from_utf8(&word[9..])?.to_string(),
-            });
+            Ok(InventoryLocation::Detached {
+                name: core::str::from_utf8(&word[9..])?.to_string(),
+            })
         } else {
             bail!("Unknown InventoryLocation: {:?}", word)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> ProtocolContext {
+        ProtocolContext::latest_for_send(false)
+    }
+
+    fn ser<T: Serialize>(value: &T::Input) -> Vec<u8> {
+        let mut ser = VecSerializer::new(context(), 64);
+        T::serialize(value, &mut ser).unwrap();
+        ser.take()
+    }
+
+    fn deser<T: Deserialize>(data: &[u8]) -> T::Output {
+        let mut deser = Deserializer::new(context(), data);
+        let value = T::deserialize(&mut deser).unwrap();
+        assert_eq!(deser.remaining(), 0);
+        value
+    }
+
+    #[test]
+    fn option_wrappers() {
+        let value = Option32::Some("abc".to_string());
+        let data = ser::<Option32<String>>(&value);
+        assert_eq!(data, b"\x00\x00\x00\x05\x00\x03abc");
+        assert_eq!(deser::<Option32<String>>(&data), value);
+        assert_eq!(ser::<Option32<String>>(&Option32::None), b"\0\0\0\0");
+
+        let value = Some(0x1234u16);
+        let data = ser::<BoolOption<u16>>(&value);
+        assert_eq!(data, b"\x01\x12\x34");
+        assert_eq!(deser::<BoolOption<u16>>(&data), value);
+        assert_eq!(ser::<BoolOption<u16>>(&None), b"\x00");
+        assert_eq!(deser::<BoolOption<u16>>(b"\x00"), None);
+    }
+
+    #[test]
+    fn fields_since_version() {
+        let lighting = Lighting {
+            shadow_intensity: 0.5,
+            saturation: 0.25,
+            exposure: AutoExposure {
+                luminance_min: -2.0,
+                ..Default::default()
+            },
+        };
+        let at = |protocol_version| ProtocolContext {
+            protocol_version,
+            ..context()
+        };
+        let mut old_ser = VecSerializer::new(at(41), 64);
+        Lighting::serialize(&lighting, &mut old_ser).unwrap();
+        let data = old_ser.take();
+        assert_eq!(data.len(), 4);
+        let old = Lighting::deserialize(&mut Deserializer::new(at(41), &data)).unwrap();
+        assert_eq!(old.shadow_intensity, 0.5);
+        assert_eq!(old.saturation, 1.0);
+        assert_eq!(old.exposure, AutoExposure::default());
+
+        let data = ser::<Lighting>(&lighting);
+        assert_eq!(data.len(), 32);
+        assert_eq!(deser::<Lighting>(&data), lighting);
+    }
+
+    #[test]
+    fn derived_enums() {
+        let stat = HudStat::WorldPos(v3f::new(1.0, 2.0, 3.0));
+        let data = ser::<HudStat>(&stat);
+        assert_eq!(data[0], 9);
+        assert_eq!(data.len(), 13);
+        assert_eq!(deser::<HudStat>(&data), stat);
+
+        let punched = ActiveObjectCommand::Punched(AOCPunched { hp: 7 });
+        assert_eq!(ser::<ActiveObjectCommand>(&punched), b"\x04\x00\x07");
+        assert_eq!(deser::<ActiveObjectCommand>(b"\x04\x00\x07"), punched);
+        let mut bad = Deserializer::new(context(), b"\x20");
+        let err = ActiveObjectCommand::deserialize(&mut bad).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid ActiveObjectCommand tag: 32");
+
+        // Bone overrides are optional, for servers before 5.9
+        let bone = AOCSetBonePosition {
+            bone: "Head".to_string(),
+            position: v3f::new(0.0, 6.3, 0.0),
+            rotation: v3f::new(0.0, 90.0, 0.0),
+            scale: Some(v3f::new(1.0, 2.0, 1.0)),
+            position_interp_timer: Some(0.0),
+            rotation_interp_timer: Some(0.5),
+            scale_interp_timer: Some(0.0),
+            absolute: Some(0b010),
+        };
+        let set_bone = ActiveObjectCommand::SetBonePosition(bone.clone());
+        let data = ser::<ActiveObjectCommand>(&set_bone);
+        assert_eq!(data.len(), 1 + 6 + 24 + 12 + 12 + 1);
+        assert_eq!(deser::<ActiveObjectCommand>(&data), set_bone);
+        let old = deser::<ActiveObjectCommand>(&data[..31]);
+        let ActiveObjectCommand::SetBonePosition(old) = old else {
+            panic!("Not SetBonePosition");
+        };
+        assert_eq!(
+            (old.rotation, old.scale, old.absolute),
+            (bone.rotation, None, None)
+        );
+
+        let node_box = NodeBox::Leveled(NodeBoxLeveled { fixed: Vec::new() });
+        let data = ser::<NodeBox>(&node_box);
+        assert_eq!(data, b"\x06\x03\x00\x00");
+        assert_eq!(deser::<NodeBox>(&data), node_box);
+        let mut bad = Deserializer::new(context(), b"\x05\x00");
+        let err = NodeBox::deserialize(&mut bad).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid NodeBox version: 5");
+
+        let pointed = PointedThing::Object { object_id: 0x102 };
+        let data = ser::<PointedThing>(&pointed);
+        assert_eq!(data, b"\x00\x02\x01\x02");
+        assert_eq!(deser::<PointedThing>(&data), pointed);
+        assert_eq!(deser::<PointedThing>(b"\x00\x00"), PointedThing::Nothing);
+
+        let param = HudSetParam::SetHotBarItemCount(8);
+        let data = ser::<HudSetParam>(&param);
+        assert_eq!(data, b"\x00\x01\x00\x04\x00\x00\x00\x08");
+        assert_eq!(deser::<HudSetParam>(&data), param);
+        for bad in [
+            &b"\x00\x01\x00\x05\x00\x00\x00\x08\x00"[..],
+            &b"\x00\x01\x00\x02\x00\x08"[..],
+        ] {
+            let mut deser = Deserializer::new(context(), bad);
+            let err = HudSetParam::deserialize(&mut deser).unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("Invalid size in SetHotBarItemCount"));
+        }
+
+        #[derive(Debug, PartialEq, MinetestSerialize, MinetestDeserialize)]
+        #[tag(u16)]
+        enum Mode {
+            Off = 3,
+            On = 0x100,
+        }
+        assert_eq!(ser::<Mode>(&Mode::On), b"\x01\x00");
+        assert_eq!(deser::<Mode>(b"\x00\x03"), Mode::Off);
+
+        #[derive(Debug, PartialEq, MinetestSerialize, MinetestDeserialize)]
+        enum Shape {
+            Empty,
+            #[proto(tag = 5)]
+            Box {
+                size: u8,
+                #[proto(since = 99)]
+                rounded: Option<bool>,
+            },
+        }
+        assert_eq!(ser::<Shape>(&Shape::Empty), b"\x00");
+        let shape = Shape::Box {
+            size: 3,
+            rounded: Some(true),
+        };
+        let data = ser::<Shape>(&shape);
+        assert_eq!(data, b"\x05\x03");
+        assert_eq!(
+            deser::<Shape>(&data),
+            Shape::Box {
+                size: 3,
+                rounded: None
+            }
+        );
+    }
+
+    #[test]
+    fn len_map() {
+        // cracky=3, choppy=1, cracky=2
+        let data = b"\x00\x03\x00\x06cracky\x00\x03\x00\x06choppy\x00\x01\x00\x06cracky\x00\x02";
+
+        let map = deser::<LenMap<u16, String, s16>>(data);
+        assert_eq!(map.len(), 3);
+        assert!(map.has_duplicates());
+        assert_eq!(map.get("cracky"), Some(&2));
+        assert_eq!(map.get("choppy"), Some(&1));
+        assert_eq!(map.get("snappy"), None);
+        // Same encoding as the Array16<Pair<..>> it replaces, duplicates included
+        assert_eq!(ser::<LenMap<u16, String, s16>>(&map), data);
+        assert_eq!(
+            ser::<Array16<Pair<String, s16>>>(&map.clone().into_entries()),
+            data
+        );
+
+        let last = deser::<LenMap<u16, String, s16, LastWins>>(data);
+        assert_eq!(
+            last.entries(),
+            &[("cracky".to_string(), 2), ("choppy".to_string(), 1)]
+        );
+        let first = deser::<LenMap<u16, String, s16, FirstWins>>(data);
+        assert_eq!(first.get("cracky"), Some(&3));
+
+        let mut deser = Deserializer::new(context(), data);
+        assert!(LenMap::<u16, String, s16, RejectDuplicates>::deserialize(&mut deser).is_err());
+    }
+
+    fn exact<T>(data: &[u8]) -> T::Output
+    where
+        T: Deserialize + Serialize<Input = <T as Deserialize>::Output>,
+        T::Output: core::fmt::Debug,
+    {
+        match TextFormatPolicy::Strict.reserialize::<T>(context(), data) {
+            Ok(Reserialized::Exact(value)) => value,
+            other => panic!("not byte-exact: {:?}", other),
+        }
+    }
+
+    // Captured from a Minetest 5.7 server: TOCLIENT_INVENTORY after respawn.
+    fn golden_inventory() -> Vec<u8> {
+        let mut data = b"List main 32\nWidth 0\n\
+            Item default:pick_steel 1 10818\n\
+            Item default:torch 99\n\
+            Item default:dirt 42\n"
+            .to_vec();
+        data.extend(b"Empty\n".repeat(29));
+        data.extend(b"EndInventoryList\nList craft 9\nWidth 3\n");
+        data.extend(b"Empty\n".repeat(9));
+        data.extend(
+            b"EndInventoryList\nKeepList craftpreview\nKeepList craftresult\nEndInventory\n",
+        );
+        data
+    }
+
+    #[test]
+    fn inventory_golden() {
+        let inv = exact::<Inventory>(&golden_inventory());
+        assert_eq!(inv.entries.len(), 4);
+        let InventoryEntry::Update(main) = &inv.entries[0] else {
+            panic!("expected main list")
+        };
+        assert_eq!(main.items.len(), 32);
+        assert_eq!(
+            main.items[0],
+            ItemStackUpdate::Item(ItemStack {
+                name: "default:pick_steel".to_string(),
+                count: 1,
+                wear: 10818,
+                metadata: ItemStackMetadata {
+                    string_vars: Vec::new()
+                },
+            })
+        );
+        assert_eq!(
+            inv.entries[3],
+            InventoryEntry::KeepList("craftresult".to_string())
+        );
+    }
+
+    #[test]
+    fn itemstack_golden() {
+        let line = b"Item default:book_written 1 0 \"\\u0001title\\u0002Notes\\u0003text\\u0002hello world\\u0003\"\n";
+        let item = exact::<ItemStack>(line);
+        assert_eq!(item.count, 1);
+        assert_eq!(item.metadata.string_vars.len(), 2);
+        assert_eq!(item.metadata.string_vars[1].1.as_bytes(), b"hello world");
+
+        // Names with spaces or quotes are quoted, others are bare
+        let item = exact::<ItemStack>(b"Item \"mymod:odd name\" 3\n");
+        assert_eq!(item.name, "mymod:odd name");
+        exact::<ItemStack>(b"Item default:sword_diamond 1 65534\n");
+    }
+
+    #[test]
+    fn inventory_action_golden() {
+        let action =
+            exact::<InventoryAction>(b"Move 1 current_player main 0 nodemeta:-12,7,300 src 0");
+        assert_eq!(
+            action,
+            InventoryAction::Move {
+                count: 1,
+                from_inv: InventoryLocation::CurrentPlayer,
+                from_list: "main".to_string(),
+                from_i: 0,
+                to_inv: InventoryLocation::NodeMeta {
+                    pos: v3s16::new(-12, 7, 300)
+                },
+                to_list: "src".to_string(),
+                to_i: Some(0),
+            }
+        );
+        exact::<InventoryAction>(
+            b"MoveSomewhere 5 current_player main 3 detached:creative_trash main",
+        );
+        exact::<InventoryAction>(b"Drop 10 player:singleplayer main 2");
+        // Minetest writes a trailing space after a Craft action
+        exact::<InventoryAction>(b"Craft 1 current_player ");
+        exact::<InventoryLocation>(b"undefined");
+    }
+
+    #[test]
+    fn lenient_parse_strict_emit() {
+        // Legacy markers, a blank line, an unknown line, and a wrong list size
+        let data = b"List main 2\nWidth 0\n\nItem default:dirt\nSomething new\nend\nEnd\n";
+        for bad in [
+            &data[..],
+            b"List main 1\nWidth 0\nEmpty\nend\nEndInventory\n",
+        ] {
+            assert!(TextFormatPolicy::Strict
+                .reserialize::<Inventory>(context(), bad)
+                .is_err());
+        }
+
+        let result = TextFormatPolicy::Lenient
+            .reserialize::<Inventory>(context(), data)
+            .unwrap();
+        assert!(!result.is_exact());
+        let Reserialized::Rewritten { value, emitted } = result else {
+            unreachable!()
+        };
+        assert_eq!(
+            emitted,
+            b"List main 1\nWidth 0\nItem default:dirt\nEndInventoryList\nEndInventory\n"
+        );
+        // Canonical output is stable
+        let again = exact::<Inventory>(&emitted);
+        assert_eq!(again, value);
+
+        // The policy is taken from the context
+        let strict = ProtocolContext {
+            text_format: TextFormatPolicy::Strict,
+            ..context()
+        };
+        let mut strict_deser = Deserializer::new(strict, data);
+        assert!(Inventory::deserialize(&mut strict_deser).is_err());
+        assert_eq!(deser::<Inventory>(data), value);
+    }
+
+    #[test]
+    fn skybox_params() {
+        let white = SColor::new(255, 255, 255, 255);
+        let mut params = SkyboxParams {
+            bgcolor: white,
+            clouds: true,
+            fog_tint: FogTint::Custom {
+                sun_tint: white,
+                moon_tint: white,
+            },
+            typ: SkyType::Skybox(vec!["a.png".to_string(); 6]),
+            body_orbit_tilt: Some(10.0),
+        };
+        let data = ser::<SkyboxParams>(&params);
+        assert_eq!(&data[4..12], b"\0\x06skybox");
+        assert_eq!(deser::<SkyboxParams>(&data), params);
+
+        // The colors sent with "default" are the engine's, and ignored
+        params.fog_tint = FogTint::Default;
+        params.typ = SkyType::Plain;
+        let data = ser::<SkyboxParams>(&params);
+        let colors = [FogTint::DEFAULT_SUN_TINT, FogTint::DEFAULT_MOON_TINT];
+        assert_eq!(&data[4..16], b"\0\x05plain\x01\xff\xf4\x7d\x1d");
+        assert_eq!(deser::<SkyboxParams>(&data), params);
+        let mut rewritten = data.clone();
+        rewritten[12..16].copy_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(deser::<SkyboxParams>(&rewritten), params);
+        assert_eq!(FogTint::Default.colors(), (colors[0], colors[1]));
+    }
+
+    #[test]
+    #[cfg(any(feature = "zstd", feature = "ruzstd"))]
+    fn map_block_buf() {
+        let mut nodes = MapNodesBulk::empty();
+        for (i, node) in nodes.nodes.iter_mut().enumerate() {
+            *node = MapNode {
+                param0: (i * 7) as u16,
+                param1: (i % 16) as u8,
+                param2: (i / 16) as u8,
+            };
+        }
+        let block = MapBlock {
+            is_underground: true,
+            day_night_diff: false,
+            generated: true,
+            lighting_complete: Some(0xfffe),
+            nodes,
+            node_metadata: NodeMetadataList {
+                metadata: Vec::new(),
+            },
+        };
+        let data = ser::<MapBlock>(&block);
+        assert_eq!(deser::<MapBlock>(&data), block);
+
+        let mut pool = MapBlockBufPool::new(1);
+        let mut buf = pool.get();
+        for _ in 0..2 {
+            let mut deser = Deserializer::new(context(), &data);
+            buf.deserialize_from(&mut deser).unwrap();
+            assert_eq!(deser.remaining(), 0);
+            assert_eq!(buf.to_map_block(), block);
+        }
+        pool.put(buf);
+        assert_eq!(pool.get().to_map_block(), block);
+
+        let bulk = ser::<MapNodesBulk>(&block.nodes);
+        let mut into = [MapNode::default(); NODECOUNT as usize];
+        MapNodesBulk::deserialize_into(&mut Deserializer::new(context(), &bulk), &mut into)
+            .unwrap();
+        assert_eq!(into, block.nodes.nodes);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn byte_string_serde() {
+        let text = ByteString::from(&b"plain"[..]);
+        let binary = ByteString::from(&b"\xff\x00"[..]);
+        assert_eq!(serde_json::to_string(&text).unwrap(), r#""plain""#);
+        assert_eq!(serde_json::to_string(&binary).unwrap(), "[255,0]");
+        for value in [text, binary] {
+            let json = serde_json::to_string(&value).unwrap();
+            assert_eq!(serde_json::from_str::<ByteString>(&json).unwrap(), value);
+        }
+    }
+}
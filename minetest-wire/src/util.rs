@@ -2,14 +2,17 @@
 //! The crazy exotic serialization methods Minetest uses
 //!
 
-use std::str::FromStr;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str::FromStr;
 
 use anyhow::bail;
 use anyhow::Result;
 use miniz_oxide::inflate::core::inflate_flags;
 use miniz_oxide::inflate::core::DecompressorOxide;
-use zstd_safe::InBuffer;
-use zstd_safe::OutBuffer;
 
 /// Convert an integer type into it's string represention as &[u8]
 ///
@@ -29,14 +32,15 @@ macro_rules! itos {
 /// `return Err()` implicitly.
 ///
 /// Use return type-inference to specify the integer type, e.g:
-
-///     let val: u16 = stoi(&s);
 ///
+/// ```text
+/// let val: u16 = stoi(&s)?;
+/// ```
 pub fn stoi<T: FromStr>(b: &[u8]) -> anyhow::Result<T>
 where
-    <T as FromStr>::Err: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+    <T as FromStr>::Err: core::error::Error + core::marker::Sync + core::marker::Send + 'static,
 {
-    let s = std::str::from_utf8(b)?;
+    let s = core::str::from_utf8(b)?;
     let n = s.parse::<T>()?;
     Ok(n)
 }
@@ -44,7 +48,7 @@ where
 #[macro_export]
 macro_rules! stoi {
     ($b: expr, $typ: ty) => {{
-        let result: anyhow::Result<$typ> = match std::str::from_utf8($b) {
+        let result: anyhow::Result<$typ> = match core::str::from_utf8($b) {
             Ok(v) => match v.parse::<$typ>() {
                 Ok(v) => Ok(v),
                 Err(e) => Err(anyhow::Error::from(e)),
@@ -58,45 +62,11 @@ macro_rules! stoi {
 
 ///
 /// Streaming Zstd compress
-pub fn zstd_compress<F>(input: &[u8], mut write: F) -> anyhow::Result<()>
+pub fn zstd_compress<F>(input: &[u8], write: F) -> anyhow::Result<()>
 where
     F: FnMut(&[u8]) -> anyhow::Result<()>,
 {
-    let mut ctx = zstd_safe::CCtx::create();
-    const BUFSIZE: usize = 16384;
-    let mut buf = [0u8; BUFSIZE];
-    let mut input_buffer = InBuffer {
-        src: &input,
-        pos: 0,
-    };
-    while input_buffer.pos < input.len() {
-        let mut output_buffer = OutBuffer::around(&mut buf);
-        match ctx.compress_stream(&mut output_buffer, &mut input_buffer) {
-            Ok(_) => {
-                let written = output_buffer.as_slice();
-                if written.len() > 0 {
-                    write(&written)?;
-                }
-            }
-            Err(e) => bail!("zstd_compress: {}", zstd_safe::get_error_name(e)),
-        }
-    }
-    loop {
-        let mut output_buffer = OutBuffer::around(&mut buf);
-        match ctx.end_stream(&mut output_buffer) {
-            Ok(code) => {
-                let chunk = output_buffer.as_slice();
-                if chunk.len() != 0 {
-                    write(&chunk)?;
-                }
-                if code == 0 {
-                    break;
-                }
-            }
-            Err(ec) => bail!("zstd_compress end: {}", zstd_safe::get_error_name(ec)),
-        }
-    }
-    Ok(())
+    crate::zstd::compress(input, write)
 }
 
 /// Streaming Zstd decompress
@@ -104,34 +74,24 @@ where
 /// The input is allowed to contain more data than Zstd will consume.
 /// Returns the actual number of bytes consumed from the input.
 ///
-pub fn zstd_decompress<F>(input: &[u8], mut write: F) -> anyhow::Result<usize>
+pub fn zstd_decompress<F>(input: &[u8], write: F) -> anyhow::Result<usize>
 where
     F: FnMut(&[u8]) -> anyhow::Result<()>,
 {
-    let mut ctx = zstd_safe::DCtx::create();
-    const BUFSIZE: usize = 16384;
-    let mut buf = [0u8; BUFSIZE];
+    zstd_decompress_chunks(core::iter::once(input), write)
+}
 
-    let mut input_buffer = InBuffer {
-        src: &input,
-        pos: 0,
-    };
-    loop {
-        let mut output_buffer = OutBuffer::around(&mut buf);
-        match ctx.decompress_stream(&mut output_buffer, &mut input_buffer) {
-            Ok(code) => {
-                let out = output_buffer.as_slice();
-                if out.len() != 0 {
-                    write(&out)?;
-                }
-                if code == 0 {
-                    break;
-                }
-            }
-            Err(ec) => bail!("zstd_compress: {}", zstd_safe::get_error_name(ec)),
-        };
-    }
-    Ok(input_buffer.pos())
+/// Streaming Zstd decompress, with the input split over several buffers
+/// (see Deserializer::remaining_chunks).
+///
+/// Returns the total number of bytes consumed from the input.
+/// If the input ends before the end of the zstd frame, this fails with Eof.
+pub fn zstd_decompress_chunks<'c, I, F>(inputs: I, write: F) -> anyhow::Result<usize>
+where
+    I: IntoIterator<Item = &'c [u8]>,
+    F: FnMut(&[u8]) -> anyhow::Result<()>,
+{
+    crate::zstd::decompress_chunks(inputs, write)
 }
 
 /// serializeJsonStringIfNeeded
@@ -139,12 +99,12 @@ pub fn serialize_json_string_if_needed<W>(input: &[u8], mut write: W) -> anyhow:
 where
     W: FnMut(&[u8]) -> anyhow::Result<()>,
 {
-    if input.len() == 0
+    if input.is_empty()
         || input
             .iter()
             .any(|&ch| ch <= 0x1f || ch >= 0x7f || ch == b' ' || ch == b'\"')
     {
-        serialize_json_string(&input, write)
+        serialize_json_string(input, write)
     } else {
         write(input)
     }
@@ -165,7 +125,7 @@ where
             b'\r' => write(b"\\r")?,
             b'\t' => write(b"\\t")?,
             ch => {
-                if ch >= 32 && ch <= 126 {
+                if (32..=126).contains(&ch) {
                     write(&[ch])?
                 } else {
                     // \u00XX style escaping
@@ -179,17 +139,62 @@ where
     Ok(())
 }
 
+/// Hex dump, 16 bytes a line, with offsets from `start` (where `data` is in
+/// a larger buffer). The byte at offset `mark`, if any, is pointed at:
+///
+/// ```text
+/// 00000000  00 02 1d 00 00 00 2e 00  |........|
+///                             ^^ offset 6
+/// ```
+pub fn hexdump(data: &[u8], start: usize, mark: Option<usize>) -> String {
+    let mut out = String::new();
+    for (row, line) in data.chunks(16).enumerate() {
+        let offset = start + row * 16;
+        let _ = write!(out, "{:08x} ", offset);
+        for (i, byte) in line.iter().enumerate() {
+            let gap = if i == 8 { "  " } else { " " };
+            let _ = write!(out, "{}{:02x}", gap, byte);
+        }
+        for i in line.len()..16 {
+            out.push_str(if i == 8 { "    " } else { "   " });
+        }
+        out.push_str("  |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+        if let Some(mark) = mark {
+            // At the end of the data, point just after the last byte
+            let last_row = offset + line.len() == start + data.len();
+            if mark >= offset && (mark < offset + 16 || (last_row && mark == offset + 16)) {
+                let column = mark - offset;
+                let indent = 9 + column * 3 + if column >= 8 { 2 } else { 1 };
+                out.extend(core::iter::repeat_n(' ', indent));
+                let _ = writeln!(out, "^^ offset {}", mark);
+            }
+        }
+    }
+    if data.is_empty() {
+        out.push_str("(no data)\n");
+    }
+    out
+}
+
 pub fn to_hex(index: u8) -> u8 {
     const HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
     HEX_CHARS[index as usize]
 }
 
 pub fn from_hex(hex_digit: u8) -> anyhow::Result<u8> {
-    if hex_digit >= b'0' && hex_digit <= b'9' {
+    if hex_digit.is_ascii_digit() {
         Ok(hex_digit - b'0')
-    } else if hex_digit >= b'a' && hex_digit <= b'f' {
+    } else if (b'a'..=b'f').contains(&hex_digit) {
         Ok(10 + (hex_digit - b'a'))
-    } else if hex_digit >= b'A' && hex_digit <= b'F' {
+    } else if (b'A'..=b'F').contains(&hex_digit) {
         Ok(10 + (hex_digit - b'A'))
     } else {
         bail!("Invalid hex digit: {}", hex_digit);
@@ -199,7 +204,7 @@ pub fn from_hex(hex_digit: u8) -> anyhow::Result<u8> {
 // deSerializeJsonStringIfNeeded
 // Returns number of bytes consumed by the "json" string, so that parsing can continue after.
 pub fn deserialize_json_string_if_needed(input: &[u8]) -> Result<(Vec<u8>, usize), anyhow::Error> {
-    if input.len() > 0 {
+    if !input.is_empty() {
         if input[0] == b'"' {
             return deserialize_json_string(input);
         }
@@ -280,7 +285,7 @@ pub fn deserialize_json_string(input: &[u8]) -> Result<(Vec<u8>, usize), anyhow:
 /// This is needed to handle the crazy inventory parsing.
 pub fn split_by_whitespace(line: &[u8]) -> Vec<&[u8]> {
     line.split(|ch| *ch == b' ' || *ch == b'\n')
-        .filter(|v| v.len() > 0)
+        .filter(|v| !v.is_empty())
         .collect()
 }
 
@@ -300,7 +305,7 @@ pub fn next_word(line: &[u8]) -> Option<(&[u8], &[u8])> {
     match line.iter().position(|ch| *ch == b' ' || *ch == b'\n') {
         Some(endpos) => Some((&line[..endpos], &line[endpos..])),
         None => {
-            if line.len() == 0 {
+            if line.is_empty() {
                 None
             } else {
                 Some((line, &line[line.len()..]))
@@ -309,8 +314,27 @@ pub fn next_word(line: &[u8]) -> Option<(&[u8], &[u8])> {
     }
 }
 
-pub fn compress_zlib(uncompressed: &[u8]) -> Vec<u8> {
-    miniz_oxide::deflate::compress_to_vec_zlib(uncompressed, 6)
+/// The level Minetest uses
+pub const ZLIB_DEFAULT_LEVEL: u8 = 6;
+
+/// `level` is 0 (none) to 9 (best), higher levels are treated as 9.
+/// Uses flate2 with the "flate2" feature (see "zlib-ng" and "zlib-rs"),
+/// otherwise miniz_oxide.
+pub fn compress_zlib(uncompressed: &[u8], level: u8) -> Vec<u8> {
+    let level = level.min(9);
+    #[cfg(feature = "flate2")]
+    {
+        use std::io::Write;
+        let mut encoder = flate2::write::ZlibEncoder::new(
+            Vec::with_capacity(uncompressed.len() / 2),
+            flate2::Compression::new(level as u32),
+        );
+        // Writing to a Vec can't fail
+        encoder.write_all(uncompressed).unwrap();
+        encoder.finish().unwrap()
+    }
+    #[cfg(not(feature = "flate2"))]
+    miniz_oxide::deflate::compress_to_vec_zlib(uncompressed, level)
 }
 
 /// This method must detect the end of the stream.
@@ -361,9 +385,9 @@ pub fn decompress_zlib(input: &[u8]) -> Result<(usize, Vec<u8>)> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use std::ops::Range;
+    use core::ops::Range;
 
     use super::*;
     use rand::thread_rng;
@@ -381,7 +405,7 @@ mod tests {
 
     fn serialize_to_vec(input: &[u8]) -> Vec<u8> {
         let mut out = Vec::new();
-        assert!(serialize_json_string_if_needed(&input, |chunk| {
+        assert!(serialize_json_string_if_needed(input, |chunk| {
             out.extend(chunk);
             Ok(())
         })
@@ -406,7 +430,7 @@ mod tests {
                 println!("serialized_plus_junk = {:?}", serialized_plus_junk);
                 println!("result = {:?}", result);
                 println!("consumed = {}", consumed);
-                assert!(false);
+                panic!("round trip mismatch");
             }
             assert_eq!(input, result);
             assert_eq!(consumed, serialized.len());
@@ -428,4 +452,21 @@ mod tests {
             assert_eq!(v, i);
         }
     }
+
+    #[test]
+    fn zlib_levels() {
+        let input: Vec<u8> = (0..20000u32).map(|i| (i % 13 + i / 1000) as u8).collect();
+        let stored = compress_zlib(&input, 0);
+        let best = compress_zlib(&input, 9);
+        assert!(stored.len() > input.len());
+        assert!(best.len() < stored.len() / 4);
+        assert_eq!(compress_zlib(&input, 200), best);
+        for compressed in [stored, best] {
+            let mut with_trailing = compressed.clone();
+            with_trailing.extend_from_slice(b"trailing");
+            let (consumed, output) = decompress_zlib(&with_trailing).unwrap();
+            assert_eq!(consumed, compressed.len());
+            assert_eq!(output, input);
+        }
+    }
 }
@@ -0,0 +1,297 @@
+//!
+//! Zstd backends
+//!
+//! Selected at compile time:
+//!
+//! ```text
+//! feature "zstd"     The C library, through zstd-safe (default)
+//! feature "ruzstd"   Pure Rust (ruzstd), for wasm, no_std and cross-compiling
+//! ```
+//!
+//! With both, the C library is used. The output of the two encoders is not
+//! byte-identical, but each decodes the other's.
+//!
+//! Use util::zstd_compress and util::zstd_decompress_chunks rather than
+//! this module directly.
+//!
+
+#[cfg(feature = "zstd")]
+pub(crate) use self::c::compress;
+#[cfg(feature = "zstd")]
+pub(crate) use self::c::decompress_chunks;
+
+#[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+pub(crate) use self::rust::compress;
+#[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+pub(crate) use self::rust::decompress_chunks;
+
+#[cfg(not(any(feature = "zstd", feature = "ruzstd")))]
+pub(crate) use self::none::compress;
+#[cfg(not(any(feature = "zstd", feature = "ruzstd")))]
+pub(crate) use self::none::decompress_chunks;
+
+#[cfg(any(feature = "zstd", feature = "ruzstd"))]
+const BUFSIZE: usize = 16384;
+
+#[cfg(feature = "zstd")]
+pub(crate) mod c {
+    use anyhow::bail;
+    use zstd_safe::InBuffer;
+    use zstd_safe::OutBuffer;
+
+    use super::BUFSIZE;
+    use crate::deser::DeserializeError;
+
+    pub(crate) fn compress<F>(input: &[u8], mut write: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&[u8]) -> anyhow::Result<()>,
+    {
+        let mut ctx = zstd_safe::CCtx::create();
+        let mut buf = [0u8; BUFSIZE];
+        let mut input_buffer = InBuffer { src: input, pos: 0 };
+        while input_buffer.pos < input.len() {
+            let mut output_buffer = OutBuffer::around(&mut buf);
+            match ctx.compress_stream(&mut output_buffer, &mut input_buffer) {
+                Ok(_) => {
+                    let written = output_buffer.as_slice();
+                    if !written.is_empty() {
+                        write(written)?;
+                    }
+                }
+                Err(e) => bail!("zstd_compress: {}", zstd_safe::get_error_name(e)),
+            }
+        }
+        loop {
+            let mut output_buffer = OutBuffer::around(&mut buf);
+            match ctx.end_stream(&mut output_buffer) {
+                Ok(code) => {
+                    let chunk = output_buffer.as_slice();
+                    if !chunk.is_empty() {
+                        write(chunk)?;
+                    }
+                    if code == 0 {
+                        break;
+                    }
+                }
+                Err(ec) => bail!("zstd_compress end: {}", zstd_safe::get_error_name(ec)),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decompress_chunks<'c, I, F>(inputs: I, mut write: F) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item = &'c [u8]>,
+        F: FnMut(&[u8]) -> anyhow::Result<()>,
+    {
+        let mut ctx = zstd_safe::DCtx::create();
+        let mut buf = [0u8; BUFSIZE];
+
+        let mut consumed = 0;
+        for input in inputs {
+            let mut input_buffer = InBuffer { src: input, pos: 0 };
+            loop {
+                let mut output_buffer = OutBuffer::around(&mut buf);
+                match ctx.decompress_stream(&mut output_buffer, &mut input_buffer) {
+                    Ok(code) => {
+                        let out = output_buffer.as_slice();
+                        let out_full = out.len() == BUFSIZE;
+                        if !out.is_empty() {
+                            write(out)?;
+                        }
+                        if code == 0 {
+                            return Ok(consumed + input_buffer.pos());
+                        }
+                        // When the output isn't full, zstd has flushed everything
+                        // it can, and needs more input to make progress.
+                        if input_buffer.pos() == input.len() && !out_full {
+                            break;
+                        }
+                    }
+                    Err(ec) => bail!("zstd_decompress: {}", zstd_safe::get_error_name(ec)),
+                };
+            }
+            consumed += input.len();
+        }
+        bail!(DeserializeError::Eof)
+    }
+}
+
+// With both backends, this is only used by the interop tests
+#[cfg(all(feature = "ruzstd", any(test, not(feature = "zstd"))))]
+pub(crate) mod rust {
+    use anyhow::bail;
+    use ruzstd::decoding::BlockDecodingStrategy;
+    use ruzstd::decoding::FrameDecoder;
+    use ruzstd::encoding::compress_to_vec;
+    use ruzstd::encoding::CompressionLevel;
+
+    use super::BUFSIZE;
+    use crate::deser::DeserializeError;
+
+    /// The only level ruzstd implements besides Uncompressed.
+    /// Roughly zstd level 1.
+    pub(crate) fn compress<F>(input: &[u8], mut write: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&[u8]) -> anyhow::Result<()>,
+    {
+        write(&compress_to_vec(input, CompressionLevel::Fastest))
+    }
+
+    /// Reads the chunks in order, counting what the decoder consumes.
+    /// The decoder only reads what belongs to the frame.
+    struct ChunkReader<'c, I: Iterator<Item = &'c [u8]>> {
+        chunks: I,
+        current: &'c [u8],
+        consumed: usize,
+        exhausted: bool,
+    }
+
+    impl<'c, I: Iterator<Item = &'c [u8]>> ruzstd::io::Read for ChunkReader<'c, I> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ruzstd::io::Error> {
+            while self.current.is_empty() {
+                match self.chunks.next() {
+                    Some(chunk) => self.current = chunk,
+                    None => {
+                        self.exhausted = true;
+                        return Ok(0);
+                    }
+                }
+            }
+            let n = core::cmp::min(buf.len(), self.current.len());
+            buf[..n].copy_from_slice(&self.current[..n]);
+            self.current = &self.current[n..];
+            self.consumed += n;
+            Ok(n)
+        }
+    }
+
+    pub(crate) fn decompress_chunks<'c, I, F>(inputs: I, mut write: F) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item = &'c [u8]>,
+        F: FnMut(&[u8]) -> anyhow::Result<()>,
+    {
+        let mut source = ChunkReader {
+            chunks: inputs.into_iter(),
+            current: &[],
+            consumed: 0,
+            exhausted: false,
+        };
+        let mut decoder = FrameDecoder::new();
+        let mut result = decoder.reset(&mut source).map(|_| ());
+        while result.is_ok() && !decoder.is_finished() {
+            result = decoder
+                .decode_blocks(&mut source, BlockDecodingStrategy::UptoBytes(BUFSIZE))
+                .map(|_| ());
+            if let Some(out) = decoder.collect() {
+                if !out.is_empty() {
+                    write(&out)?;
+                }
+            }
+        }
+        if let Err(err) = result {
+            if source.exhausted {
+                bail!(DeserializeError::Eof);
+            }
+            bail!("zstd_decompress: {}", err);
+        }
+        if let Some(out) = decoder.collect() {
+            if !out.is_empty() {
+                write(&out)?;
+            }
+        }
+        Ok(source.consumed)
+    }
+}
+
+#[cfg(not(any(feature = "zstd", feature = "ruzstd")))]
+mod none {
+    use anyhow::bail;
+
+    const UNAVAILABLE: &str = "zstd requires the \"zstd\" or \"ruzstd\" feature";
+
+    pub(crate) fn compress<F>(_input: &[u8], _write: F) -> anyhow::Result<()>
+    where
+        F: FnMut(&[u8]) -> anyhow::Result<()>,
+    {
+        bail!(UNAVAILABLE)
+    }
+
+    pub(crate) fn decompress_chunks<'c, I, F>(_inputs: I, _write: F) -> anyhow::Result<usize>
+    where
+        I: IntoIterator<Item = &'c [u8]>,
+        F: FnMut(&[u8]) -> anyhow::Result<()>,
+    {
+        bail!(UNAVAILABLE)
+    }
+}
+
+#[cfg(all(test, feature = "zstd", feature = "ruzstd"))]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::deser::DeserializeError;
+
+    fn sample() -> Vec<u8> {
+        // Compressible, but not trivially, and longer than BUFSIZE
+        (0..100_000u32)
+            .flat_map(|i| ((i / 7) % 251).to_le_bytes())
+            .collect()
+    }
+
+    fn collect<F>(decompress: F, data: &[u8]) -> (Vec<u8>, usize)
+    where
+        F: Fn(&[u8], &mut dyn FnMut(&[u8]) -> anyhow::Result<()>) -> anyhow::Result<usize>,
+    {
+        let mut out = Vec::new();
+        let consumed = decompress(data, &mut |chunk| {
+            out.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        (out, consumed)
+    }
+
+    #[test]
+    fn interop_with_c_zstd() {
+        let input = sample();
+        let mut from_c = Vec::new();
+        c::compress(&input, |chunk| {
+            from_c.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        let mut from_rust = Vec::new();
+        rust::compress(&input, |chunk| {
+            from_rust.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+
+        // Trailing data after the frame isn't consumed
+        let mut trailing = from_c.clone();
+        trailing.extend_from_slice(b"trailing");
+        let rust_decode = |data: &[u8], write: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>| {
+            rust::decompress_chunks(data.chunks(1000), write)
+        };
+        let c_decode = |data: &[u8], write: &mut dyn FnMut(&[u8]) -> anyhow::Result<()>| {
+            c::decompress_chunks(data.chunks(1000), write)
+        };
+        assert_eq!(
+            collect(rust_decode, &trailing),
+            (input.clone(), from_c.len())
+        );
+        assert_eq!(
+            collect(c_decode, &from_rust),
+            (input.clone(), from_rust.len())
+        );
+        assert_eq!(collect(rust_decode, &from_rust), (input, from_rust.len()));
+
+        let err = rust::decompress_chunks([&from_c[..from_c.len() - 1]], |_| Ok(())).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DeserializeError>(),
+            Some(DeserializeError::Eof)
+        ));
+    }
+}
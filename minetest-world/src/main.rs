@@ -0,0 +1,253 @@
+mod stats;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::Parser;
+use clap::Subcommand;
+use minetest_protocol::wire::types::v3s16;
+use minetest_protocol::world::archive::export_world;
+use minetest_protocol::world::archive::import_world;
+use minetest_protocol::world::open_world_map;
+use minetest_protocol::world::BoxedMapDatabase;
+use minetest_protocol::world::ModStorage;
+use minetest_protocol::world::SqliteMapDatabase;
+use minetest_protocol::world::WorldDiff;
+
+/// mtworld - Minetest world inspection
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Node histograms, per-mod node counts, generation coverage
+    /// and node metadata counts
+    Stats {
+        /// World directory, or its map.sqlite
+        world: PathBuf,
+
+        /// Show only the most common node names (0 for all)
+        #[arg(short, long, default_value_t = 0)]
+        top: usize,
+    },
+
+    /// Pack a world (map, world.mt, auth, players...) into one archive,
+    /// with checksums. Stop the server first.
+    Export {
+        /// World directory
+        world: PathBuf,
+
+        /// Archive to write
+        archive: PathBuf,
+    },
+
+    /// Unpack an archive into a new world, with an sqlite3 map
+    /// (whatever backend it was exported from)
+    Import {
+        /// Archive to read
+        archive: PathBuf,
+
+        /// World directory to create
+        world: PathBuf,
+    },
+
+    /// Save the blocks that changed from one copy of a map to another
+    /// as a patch
+    Diff {
+        /// The older world (or map.sqlite)
+        old: PathBuf,
+
+        /// The newer world (or map.sqlite)
+        new: PathBuf,
+
+        /// Patch to write
+        patch: PathBuf,
+
+        /// Only blocks in the box between this node position (x,y,z)...
+        #[arg(long, value_parser = parse_pos, requires = "to", allow_hyphen_values = true)]
+        from: Option<v3s16>,
+
+        /// ...and this one
+        #[arg(long, value_parser = parse_pos, requires = "from", allow_hyphen_values = true)]
+        to: Option<v3s16>,
+    },
+
+    /// Apply a patch made with diff
+    Patch {
+        /// World (or map.sqlite) to change
+        world: PathBuf,
+
+        /// Patch to apply
+        patch: PathBuf,
+
+        /// Apply even where the world doesn't match the patch's old blocks
+        #[arg(long, default_value_t = false)]
+        force: bool,
+    },
+
+    /// Show or edit mod storage (mod_storage.sqlite): the mods with
+    /// anything stored, one mod's keys, or one key. Stop the server
+    /// before editing.
+    Storage {
+        /// World directory
+        world: PathBuf,
+
+        /// Mod name
+        modname: Option<String>,
+
+        /// Key
+        key: Option<String>,
+
+        /// Set the key to this value
+        #[arg(long, requires = "key", conflicts_with = "delete")]
+        set: Option<String>,
+
+        /// Delete the key (or with no key, everything the mod stored)
+        #[arg(long, requires = "modname", default_value_t = false)]
+        delete: bool,
+    },
+}
+
+/// x,y,z
+fn parse_pos(s: &str) -> Result<v3s16, String> {
+    let parts: Vec<&str> = s.split(',').map(|part| part.trim()).collect();
+    let [x, y, z] = parts[..] else {
+        return Err("expected x,y,z".to_string());
+    };
+    let parse = |v: &str| v.parse::<i16>().map_err(|err| err.to_string());
+    Ok(v3s16::new(parse(x)?, parse(y)?, parse(z)?))
+}
+
+/// The map of a world directory, in the backend its world.mt names (or a
+/// map.sqlite itself)
+fn open_map(path: &Path, writable: bool) -> anyhow::Result<BoxedMapDatabase> {
+    if path.is_dir() {
+        open_world_map(path, writable)
+    } else if writable {
+        Ok(Box::new(SqliteMapDatabase::open_read_write(path)?))
+    } else {
+        Ok(Box::new(SqliteMapDatabase::open(path)?))
+    }
+}
+
+fn storage(
+    world: &Path,
+    modname: Option<String>,
+    key: Option<String>,
+    set: Option<String>,
+    delete: bool,
+) -> anyhow::Result<()> {
+    let path = world.join("mod_storage.sqlite");
+    let editing = set.is_some() || delete;
+    if !path.exists() && !editing {
+        bail!("No mod_storage.sqlite in {:?}", world);
+    }
+    let storage = if editing {
+        ModStorage::open(&path)?
+    } else {
+        ModStorage::open_read_only(&path)?
+    };
+    match (modname, key) {
+        (None, _) => {
+            for (modname, count) in storage.mods()? {
+                println!("{:>8}  {}", count, modname);
+            }
+        }
+        (Some(modname), None) if delete => {
+            println!("Removed {} keys", storage.clear(&modname)?);
+        }
+        (Some(modname), None) => {
+            for (key, value) in storage.entries(&modname)? {
+                println!(
+                    "{} = {}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+        }
+        (Some(modname), Some(key)) => {
+            if let Some(value) = set {
+                storage.set(&modname, key.as_bytes(), value.as_bytes())?;
+            } else if delete {
+                if !storage.remove(&modname, key.as_bytes())? {
+                    bail!("{} has no key {:?}", modname, key);
+                }
+            } else {
+                match storage.get(&modname, key.as_bytes())? {
+                    Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+                    None => bail!("{} has no key {:?}", modname, key),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    match args.command {
+        Command::Stats { world, top } => stats::run(&open_map(&world, false)?, top),
+        Command::Export { world, archive } => {
+            let db = open_map(&world, false)?;
+            let out = BufWriter::new(File::create(&archive)?);
+            let (_, summary) = export_world(&world, &db, out)?;
+            println!(
+                "Exported {} files and {} blocks to {:?}",
+                summary.files, summary.blocks, archive
+            );
+            Ok(())
+        }
+        Command::Import { archive, world } => {
+            let input = BufReader::new(File::open(&archive)?);
+            let summary = import_world(input, &world)?;
+            println!(
+                "Imported {} files and {} blocks into {:?}",
+                summary.files, summary.blocks, world
+            );
+            Ok(())
+        }
+        Command::Diff {
+            old,
+            new,
+            patch,
+            from,
+            to,
+        } => {
+            let area = from.zip(to);
+            let diff = WorldDiff::compute(&open_map(&old, false)?, &open_map(&new, false)?, area)?;
+            diff.write(BufWriter::new(File::create(&patch)?))?;
+            let counts = diff.counts();
+            println!(
+                "{} blocks added, {} changed, {} removed",
+                counts.added, counts.changed, counts.removed
+            );
+            Ok(())
+        }
+        Command::Patch {
+            world,
+            patch,
+            force,
+        } => {
+            let diff = WorldDiff::read(BufReader::new(File::open(&patch)?))?;
+            let mut db = open_map(&world, true)?;
+            diff.apply(&mut db, force)?;
+            println!("Applied {} block changes", diff.changes.len());
+            Ok(())
+        }
+        Command::Storage {
+            world,
+            modname,
+            key,
+            set,
+            delete,
+        } => storage(&world, modname, key, set, delete),
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+
+use minetest_protocol::world::MapDatabase;
+use minetest_protocol::world::WorldStats;
+
+/// Largest first
+fn by_count<K: AsRef<str>>(counts: &BTreeMap<K, u64>) -> Vec<(&str, u64)> {
+    let mut sorted: Vec<(&str, u64)> = counts
+        .iter()
+        .map(|(name, count)| (name.as_ref(), *count))
+        .collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    sorted
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / total as f64
+    }
+}
+
+pub fn run<D: MapDatabase + Sync + ?Sized>(db: &D, top: usize) -> anyhow::Result<()> {
+    let stats = WorldStats::scan(db)?;
+    let total_nodes: u64 = stats.nodes.values().sum();
+
+    println!("Blocks: {}", stats.blocks);
+    println!(
+        "  generated: {} ({:.1}%)",
+        stats.generated,
+        percent(stats.generated, stats.blocks)
+    );
+    if stats.unreadable > 0 {
+        println!("  unreadable: {}", stats.unreadable);
+    }
+    if let Some((min, max)) = &stats.extent {
+        println!(
+            "  extent: ({},{},{}) to ({},{},{})",
+            min.x, min.y, min.z, max.x, max.y, max.z
+        );
+    }
+    println!("Nodes with metadata: {}", stats.metadata_total());
+    for (name, count) in by_count(&stats.metadata) {
+        println!("  {:>12}  {}", count, name);
+    }
+
+    println!("Nodes by mod:");
+    for (name, count) in by_count(&stats.mods()) {
+        let name = if name.is_empty() { "(engine)" } else { name };
+        println!(
+            "  {:>12}  {:>5.1}%  {}",
+            count,
+            percent(count, total_nodes),
+            name
+        );
+    }
+
+    println!("Nodes:");
+    let nodes = by_count(&stats.nodes);
+    let shown = if top == 0 { nodes.len() } else { top };
+    for (name, count) in nodes.iter().take(shown) {
+        println!(
+            "  {:>12}  {:>5.1}%  {}",
+            count,
+            percent(*count, total_nodes),
+            name
+        );
+    }
+    if nodes.len() > shown {
+        println!("  ... {} more", nodes.len() - shown);
+    }
+    Ok(())
+}